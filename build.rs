@@ -2,11 +2,27 @@
 extern crate bindgen;
 
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 fn main() {
     println!("cargo:rerun-if-changed=external.h");
 
+    // for version::build_info().  Unknown when not built from a git checkout
+    if Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        let out = Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output();
+        match out {
+            Ok(out) if out.status.success() => {
+                let rev = String::from_utf8_lossy(&out.stdout);
+                println!("cargo:rustc-env=SANDBOX_GIT_REV={}", rev.trim());
+            }
+            _ => (),
+        }
+    }
+
     let bindings = bindgen::Builder::default()
         .header("external.h")
         .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()))
@@ -14,6 +30,7 @@ fn main() {
         .allowlist_type("cap_user_header_t")
         .allowlist_type("cap_user_data_t")
         .allowlist_type("ifreq")
+        .allowlist_type("mount_attr")
         .allowlist_function("capset")
         .allowlist_function("capget")
         .allowlist_function("ioctl")
@@ -22,18 +39,30 @@ fn main() {
         .allowlist_var("CAP_SYS_ADMIN")
         .allowlist_var("CAP_SETUID")
         .allowlist_var("CAP_SETGID")
+        .allowlist_var("CAP_NET_RAW")
         .allowlist_var("SIOCGIFFLAGS")
         .allowlist_var("SIOCSIFFLAGS")
         .allowlist_var("SIOCGIFADDR")
         .allowlist_var("SIOCSIFADDR")
+        .allowlist_var("SIOCGIFNETMASK")
+        .allowlist_var("SIOCSIFNETMASK")
         .allowlist_var("SIOCGIFINDEX")
+        .allowlist_var("SIOCGIFMTU")
         .allowlist_var("SIOCSIFMTU")
+        .allowlist_var("SIOCGIFHWADDR")
+        .allowlist_var("SIOCSIFHWADDR")
         .allowlist_var("SIOCBRADDBR")
         .allowlist_var("SIOCBRADDIF")
+        .allowlist_var("TIOCSTI")
+        .allowlist_var("TIOCLINUX")
         .allowlist_var("REAL_TUNSETIFF")
         .allowlist_var("IFF_UP")
         .allowlist_var("IFF_TAP")
         .allowlist_var("IFF_NO_PI")
+        .allowlist_var("MOUNT_ATTR_RDONLY")
+        .allowlist_var("MOUNT_ATTR_NOSUID")
+        .allowlist_var("MOUNT_ATTR_NODEV")
+        .allowlist_var("MOUNT_ATTR_NOEXEC")
         .generate()
         .expect("Unable to generate bindings");
 
@@ -1,6 +1,7 @@
 #include <linux/capability.h>
 #include <linux/if_tun.h>
 #include <linux/if_bridge.h>
+#include <linux/mount.h>
 #include <linux/sockios.h>
 #include <sys/ioctl.h>
 #include <net/if.h>
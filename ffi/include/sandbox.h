@@ -0,0 +1,79 @@
+/* C ABI of the sandbox crate.  Link with -lsandbox_ffi
+ *
+ * Functions which may fail return 0, or -1 and set *err (when not NULL)
+ * to an error, which the caller frees with sandbox_error_free().
+ *
+ *   sandbox_hooks *hooks = sandbox_hooks_new();
+ *   const char *argv[] = {"ip", "link", NULL};
+ *   sandbox_error *err = NULL;
+ *   int code;
+ *   sandbox_hooks_namespaces(hooks, CLONE_NEWUSER | CLONE_NEWNET);
+ *   sandbox_hooks_map_caller(hooks);
+ *   if (sandbox_hooks_exec(hooks, argv, &err) || sandbox_run(hooks, &code, &err)) {
+ *       fprintf(stderr, "%s\n", sandbox_error_message(err));
+ *       sandbox_error_free(err);
+ *   }
+ *   sandbox_hooks_free(hooks);
+ */
+#ifndef SANDBOX_H
+#define SANDBOX_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct sandbox_hooks sandbox_hooks;
+typedef struct sandbox_error sandbox_error;
+
+/* Stages of sandbox_hooks_on() */
+#define SANDBOX_AT_START 0
+#define SANDBOX_UNSHARE 1
+#define SANDBOX_SET_ID_MAP 2
+#define SANDBOX_READY 3
+#define SANDBOX_SETUP_PRIV 4
+#define SANDBOX_SETUP 5
+
+/* Returns 0, or an errno */
+typedef int (*sandbox_stage_fn)(void *user);
+
+/* eg. "1.0.0" */
+const char *sandbox_version(void);
+
+sandbox_hooks *sandbox_hooks_new(void);
+void sandbox_hooks_free(sandbox_hooks *hooks);
+
+/* CLONE_NEW* namespaces to create */
+void sandbox_hooks_namespaces(sandbox_hooks *hooks, int flags);
+/* Kill the container if the caller exits first.  Default 1. */
+void sandbox_hooks_watchdog(sandbox_hooks *hooks, int enable);
+/* Scratch directory of the container */
+int sandbox_hooks_tempdir(sandbox_hooks *hooks, const char *dir, sandbox_error **err);
+/* Call func(user) at stage.  Replaces any previous callback of that stage.
+ * user must remain valid until sandbox_run() returns.
+ */
+int sandbox_hooks_on(sandbox_hooks *hooks, int stage, sandbox_stage_fn func, void *user,
+                     sandbox_error **err);
+/* Map the calling user and group, in a new user namespace.
+ * Replaces any SANDBOX_SET_ID_MAP callback.
+ */
+void sandbox_hooks_map_caller(sandbox_hooks *hooks);
+/* Execute argv, a NULL terminated array, with argv[0] found through $PATH.
+ * Replaces any SANDBOX_SETUP callback.
+ */
+int sandbox_hooks_exec(sandbox_hooks *hooks, const char *const *argv, sandbox_error **err);
+
+/* Run a container, and wait for process 1 to exit.  Its exit code is stored in *code.
+ * Capabilities of the calling thread are dropped.
+ */
+int sandbox_run(const sandbox_hooks *hooks, int *code, sandbox_error **err);
+
+const char *sandbox_error_message(const sandbox_error *err);
+/* The errno of a failed syscall, or 0 */
+int sandbox_error_errno(const sandbox_error *err);
+void sandbox_error_free(sandbox_error *err);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SANDBOX_H */
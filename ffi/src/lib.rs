@@ -0,0 +1,328 @@
+//! C ABI of the sandbox crate.  cf. `include/sandbox.h`
+//!
+//! A `sandbox_hooks` wraps a `HooksBuilder`.  Stages are C callbacks, which return
+//! 0 or an `errno`.  Functions which may fail return 0, or -1 and set `*err`
+//! (when not NULL) to a `sandbox_error`, which the caller frees.
+//! No Rust panic crosses the ABI.  A panic is reported as an error.
+
+#![allow(non_camel_case_types)]
+
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use sandbox::container::{self, IdMap};
+use sandbox::{runc, util, HooksBuilder, StageCtx};
+
+/// Stages of `sandbox_hooks_on()`.  cf. `ContainerHooks`
+pub const SANDBOX_AT_START: c_int = 0;
+pub const SANDBOX_UNSHARE: c_int = 1;
+pub const SANDBOX_SET_ID_MAP: c_int = 2;
+pub const SANDBOX_READY: c_int = 3;
+pub const SANDBOX_SETUP_PRIV: c_int = 4;
+pub const SANDBOX_SETUP: c_int = 5;
+
+/// Returns 0, or an `errno`
+pub type sandbox_stage_fn = extern "C" fn(user: *mut c_void) -> c_int;
+
+pub struct sandbox_hooks {
+    builder: HooksBuilder,
+}
+
+pub struct sandbox_error {
+    message: CString,
+    errno: c_int,
+}
+
+/// The first `errno` of `err`, or its sources.  0 if none.
+fn errno_of(err: &(dyn Error + 'static)) -> c_int {
+    let mut cur = Some(err);
+    while let Some(err) = cur {
+        if let Some(code) = err
+            .downcast_ref::<io::Error>()
+            .and_then(|e| e.raw_os_error())
+        {
+            return code;
+        }
+        cur = err.source();
+    }
+    0
+}
+
+impl sandbox_error {
+    fn new(err: &(dyn Error + 'static)) -> Box<sandbox_error> {
+        let message = err.to_string().replace('\0', " ");
+        Box::new(sandbox_error {
+            message: CString::new(message).unwrap(),
+            errno: errno_of(err),
+        })
+    }
+}
+
+/// Call `f`, and report any error, or panic, through `err`
+fn guard<F>(err: *mut *mut sandbox_error, f: F) -> c_int
+where
+    F: FnOnce() -> container::Result<()>,
+{
+    let ret = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(ret) => ret,
+        Err(_) => Err("panic".into()),
+    };
+    match ret {
+        Ok(()) => 0,
+        Err(e) => {
+            if !err.is_null() {
+                unsafe { *err = Box::into_raw(sandbox_error::new(&*e)) };
+            }
+            -1
+        }
+    }
+}
+
+unsafe fn string(s: *const c_char) -> container::Result<String> {
+    if s.is_null() {
+        return Err("NULL string".into());
+    }
+    Ok(CStr::from_ptr(s).to_str()?.to_string())
+}
+
+/// A callback, and its argument.  Only called from the thread, or child processes,
+/// of `sandbox_run()`.
+#[derive(Clone, Copy)]
+struct Callback {
+    func: sandbox_stage_fn,
+    user: *mut c_void,
+}
+
+impl Callback {
+    fn call(&self, _ctx: &StageCtx) -> container::Result<()> {
+        match (self.func)(self.user) {
+            0 => Ok(()),
+            code => Err(io::Error::from_raw_os_error(code).into()),
+        }
+    }
+}
+
+/// Library version.  eg. "1.0.0"
+#[no_mangle]
+pub extern "C" fn sandbox_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub extern "C" fn sandbox_hooks_new() -> *mut sandbox_hooks {
+    Box::into_raw(Box::new(sandbox_hooks {
+        builder: HooksBuilder::new(),
+    }))
+}
+
+/// # Safety
+/// `hooks` from `sandbox_hooks_new()`, or NULL
+#[no_mangle]
+pub unsafe extern "C" fn sandbox_hooks_free(hooks: *mut sandbox_hooks) {
+    if !hooks.is_null() {
+        drop(Box::from_raw(hooks));
+    }
+}
+
+/// `CLONE_NEW*` namespaces to create
+///
+/// # Safety
+/// `hooks` from `sandbox_hooks_new()`
+#[no_mangle]
+pub unsafe extern "C" fn sandbox_hooks_namespaces(hooks: *mut sandbox_hooks, flags: c_int) {
+    (*hooks).builder.namespaces(flags);
+}
+
+/// Kill the container if the caller exits first.  Default true.
+///
+/// # Safety
+/// `hooks` from `sandbox_hooks_new()`
+#[no_mangle]
+pub unsafe extern "C" fn sandbox_hooks_watchdog(hooks: *mut sandbox_hooks, enable: c_int) {
+    (*hooks).builder.watchdog(enable != 0);
+}
+
+/// Scratch directory of the container
+///
+/// # Safety
+/// `hooks` from `sandbox_hooks_new()`.  `dir` a C string.
+#[no_mangle]
+pub unsafe extern "C" fn sandbox_hooks_tempdir(
+    hooks: *mut sandbox_hooks,
+    dir: *const c_char,
+    err: *mut *mut sandbox_error,
+) -> c_int {
+    guard(err, || {
+        (*hooks).builder.tempdir(string(dir)?);
+        Ok(())
+    })
+}
+
+/// Call `func(user)` at `stage`.  Replaces any previous callback of that stage.
+///
+/// # Safety
+/// `hooks` from `sandbox_hooks_new()`.  `user` valid until `sandbox_run()` returns.
+#[no_mangle]
+pub unsafe extern "C" fn sandbox_hooks_on(
+    hooks: *mut sandbox_hooks,
+    stage: c_int,
+    func: sandbox_stage_fn,
+    user: *mut c_void,
+    err: *mut *mut sandbox_error,
+) -> c_int {
+    let cb = Callback { func, user };
+    let call = move |ctx: &StageCtx| cb.call(ctx);
+    guard(err, || {
+        let builder = &mut (*hooks).builder;
+        match stage {
+            SANDBOX_AT_START => builder.on_at_start(call),
+            SANDBOX_UNSHARE => builder.on_unshare(call),
+            SANDBOX_SET_ID_MAP => builder.on_set_id_map(call),
+            SANDBOX_READY => builder.on_ready(call),
+            SANDBOX_SETUP_PRIV => builder.on_setup_priv(call),
+            SANDBOX_SETUP => builder.on_setup(call),
+            _ => return Err(format!("Unknown stage {}", stage).into()),
+        };
+        Ok(())
+    })
+}
+
+/// Map the calling user and group, in a new user namespace.
+/// Replaces any `SANDBOX_SET_ID_MAP` callback.
+///
+/// # Safety
+/// `hooks` from `sandbox_hooks_new()`
+#[no_mangle]
+pub unsafe extern "C" fn sandbox_hooks_map_caller(hooks: *mut sandbox_hooks) {
+    (*hooks).builder.on_set_id_map(|ctx| {
+        if let Some(pid) = ctx.child() {
+            let (uid, gid) = (util::getuid(), util::getgid());
+            IdMap::new_uid(pid.id()).add(uid, uid, 1).write()?;
+            IdMap::new_gid(pid.id()).add(gid, gid, 1).write()?;
+        }
+        Ok(())
+    });
+}
+
+/// Execute `argv`, a NULL terminated array, with `argv[0]` found through `$PATH`.
+/// Replaces any `SANDBOX_SETUP` callback.
+///
+/// # Safety
+/// `hooks` from `sandbox_hooks_new()`.  `argv` a NULL terminated array of C strings.
+#[no_mangle]
+pub unsafe extern "C" fn sandbox_hooks_exec(
+    hooks: *mut sandbox_hooks,
+    argv: *const *const c_char,
+    err: *mut *mut sandbox_error,
+) -> c_int {
+    guard(err, || {
+        if argv.is_null() {
+            return Err("NULL argv".into());
+        }
+        let mut args = vec![];
+        let mut arg = argv;
+        while !(*arg).is_null() {
+            args.push(string(*arg)?);
+            arg = arg.add(1);
+        }
+        if args.is_empty() {
+            return Err("Empty argv".into());
+        }
+        (*hooks).builder.on_setup(move |_ctx| {
+            util::Exec::new(&args[0])?.args(&args)?.exec()?;
+            Ok(())
+        });
+        Ok(())
+    })
+}
+
+/// Run a container, and wait for process 1 to exit.  Its exit code is stored in `*code`.
+/// Capabilities of the calling thread are dropped.  cf. `runc()`
+///
+/// # Safety
+/// `hooks` from `sandbox_hooks_new()`.  `code` valid, or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn sandbox_run(
+    hooks: *const sandbox_hooks,
+    code: *mut c_int,
+    err: *mut *mut sandbox_error,
+) -> c_int {
+    guard(err, || {
+        let ret = runc(&(*hooks).builder)?;
+        if !code.is_null() {
+            *code = ret;
+        }
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `err` from a failed call
+#[no_mangle]
+pub unsafe extern "C" fn sandbox_error_message(err: *const sandbox_error) -> *const c_char {
+    (*err).message.as_ptr()
+}
+
+/// The `errno` of a failed syscall, or 0
+///
+/// # Safety
+/// `err` from a failed call
+#[no_mangle]
+pub unsafe extern "C" fn sandbox_error_errno(err: *const sandbox_error) -> c_int {
+    (*err).errno
+}
+
+/// # Safety
+/// `err` from a failed call, or NULL
+#[no_mangle]
+pub unsafe extern "C" fn sandbox_error_free(err: *mut sandbox_error) {
+    if !err.is_null() {
+        drop(Box::from_raw(err));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    extern "C" fn fail(_user: *mut c_void) -> c_int {
+        libc::EACCES
+    }
+
+    #[test]
+    fn errors() {
+        let hooks = sandbox_hooks_new();
+        let mut err = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                sandbox_hooks_on(hooks, 42, fail, ptr::null_mut(), &mut err),
+                -1
+            );
+            let msg = CStr::from_ptr(sandbox_error_message(err));
+            assert_eq!(msg.to_str().unwrap(), "Unknown stage 42");
+            assert_eq!(sandbox_error_errno(err), 0);
+            sandbox_error_free(err);
+
+            // no namespaces, so runs anywhere
+            let mut err = ptr::null_mut();
+            let rc = sandbox_hooks_on(hooks, SANDBOX_SETUP, fail, ptr::null_mut(), &mut err);
+            assert_eq!(rc, 0);
+            let mut code = -1;
+            assert_eq!(sandbox_run(hooks, &mut code, &mut err), 0);
+            assert_ne!(code, 0);
+
+            let argv = [ptr::null()];
+            assert_eq!(
+                sandbox_hooks_exec(hooks, argv.as_ptr(), ptr::null_mut()),
+                -1
+            );
+            sandbox_hooks_free(hooks);
+        }
+        let ver = unsafe { CStr::from_ptr(sandbox_version()) };
+        assert_eq!(ver.to_str().unwrap(), env!("CARGO_PKG_VERSION"));
+    }
+}
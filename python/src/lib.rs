@@ -0,0 +1,140 @@
+//! Python bindings.  Built as the `sandbox` module with maturin.
+//!
+//! ```python
+//! import sandbox
+//! box = sandbox.Sandbox(profile="build.toml", rw=["/srv/out"])
+//! res = box.run(["make"])
+//! print(res.returncode, res.elapsed, res.max_rss, res.startup["total"])
+//! ```
+//!
+//! The sandbox is that of `sandbox::test::Isolated`.  No network access, a private `/tmp`,
+//! and all else read-only except the working directory.  A profile adds `net`,
+//! `rw`, `ro`, and `toolchains`.
+
+// in the expansion of #[pymethods] with pyo3 0.22
+#![allow(clippy::useless_conversion)]
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+
+use pyo3::exceptions::{PyOSError, PyRuntimeError};
+use pyo3::prelude::*;
+
+use sandbox::profile::Profile;
+use sandbox::test::{Isolated, Report};
+
+/// Python exception from a Rust error.  `OSError` when a syscall failed.
+fn py_err(err: &(dyn Error + 'static)) -> PyErr {
+    let mut cur = Some(err);
+    while let Some(e) = cur {
+        if let Some(code) = e.downcast_ref::<io::Error>().and_then(|e| e.raw_os_error()) {
+            return PyOSError::new_err((code, err.to_string()));
+        }
+        cur = e.source();
+    }
+    PyRuntimeError::new_err(err.to_string())
+}
+
+/// Outcome of `Sandbox.run()`.  Times in seconds.
+#[pyclass(name = "Result", module = "sandbox", get_all)]
+struct PyReport {
+    returncode: i32,
+    elapsed: f64,
+    user_time: f64,
+    system_time: f64,
+    /// KiB
+    max_rss: u64,
+    /// Startup phases.  eg. "mounts", "total"
+    startup: HashMap<String, f64>,
+}
+
+impl From<Report> for PyReport {
+    fn from(report: Report) -> Self {
+        PyReport {
+            returncode: report.code,
+            elapsed: report.elapsed.as_secs_f64(),
+            user_time: report.usage.user.as_secs_f64(),
+            system_time: report.usage.system.as_secs_f64(),
+            max_rss: report.usage.max_rss,
+            startup: report
+                .startup
+                .iter()
+                .map(|(phase, dur)| (phase.name().to_string(), dur.as_secs_f64()))
+                .collect(),
+        }
+    }
+}
+
+#[pymethods]
+impl PyReport {
+    fn __repr__(&self) -> String {
+        format!(
+            "sandbox.Result(returncode={}, elapsed={:.3})",
+            self.returncode, self.elapsed
+        )
+    }
+}
+
+#[pyclass(name = "Sandbox", module = "sandbox")]
+struct PySandbox {
+    config: Isolated,
+}
+
+#[pymethods]
+impl PySandbox {
+    /// Keyword arguments take precedence over the profile
+    #[new]
+    #[pyo3(signature = (profile=None, net=None, rw=vec![], ro=vec![]))]
+    fn new(
+        profile: Option<PathBuf>,
+        net: Option<bool>,
+        rw: Vec<PathBuf>,
+        ro: Vec<PathBuf>,
+    ) -> PyResult<Self> {
+        let mut config = Isolated::new();
+        if let Some(file) = profile {
+            let profile = Profile::load(&file).map_err(|e| py_err(&e))?;
+            config.net(profile.net.unwrap_or(false));
+            for dir in profile.rw {
+                config.rw(dir);
+            }
+            let toolchains = profile.toolchains.map(|t| t.resolve()).unwrap_or_default();
+            for dir in profile.ro.into_iter().chain(toolchains) {
+                config.ro(dir);
+            }
+        }
+        if let Some(net) = net {
+            config.net(net);
+        }
+        for dir in rw {
+            config.rw(dir);
+        }
+        for dir in ro {
+            config.ro(dir);
+        }
+        Ok(PySandbox { config })
+    }
+
+    /// Run `cmd`, a list, and wait for it to exit.  With the GIL released.
+    fn run(&self, py: Python<'_>, cmd: Vec<String>) -> PyResult<PyReport> {
+        let config = self.config.clone();
+        // runc() drops the capabilities of the calling thread.  Not those of the interpreter.
+        let ret = py.allow_threads(|| {
+            std::thread::spawn(move || config.command(&cmd).map_err(|e| py_err(&*e)))
+                .join()
+                .unwrap_or_else(|_| Err(PyRuntimeError::new_err("Sandbox.run() panicked")))
+        });
+        ret.map(PyReport::from)
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "sandbox")]
+fn sandbox_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PySandbox>()?;
+    m.add_class::<PyReport>()?;
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    Ok(())
+}
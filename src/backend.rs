@@ -0,0 +1,229 @@
+//! Delegate the mechanics of a sandbox to another tool.  For isolate --backend.
+//!
+//! Where namespaces can not be created directly, eg. hardened kernels which disallow
+//! unprivileged user namespaces, but allow a SUID `bwrap` (bubblewrap).
+//! The policy is still decided here.  Only the mounts, and namespaces, are delegated.
+//!
+//! Or, where no namespace may be created at all, path restrictions through Landlock.
+//! cf. `landlock`
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+use super::err::{Error, Result};
+use super::util;
+
+/// Executable of the `Bwrap` backend, looked up through `$PATH`
+pub const BWRAP: &str = "bwrap";
+
+/// How a sandbox is created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Namespaces created by this crate.  cf. `runc()`
+    #[default]
+    Native,
+    /// Through `bwrap`
+    Bwrap,
+    /// No namespaces.  Path restrictions through Landlock.  cf. `landlock::Ruleset`
+    Landlock,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Backend> {
+        match s {
+            "native" => Ok(Backend::Native),
+            "bwrap" => Ok(Backend::Bwrap),
+            "landlock" => Ok(Backend::Landlock),
+            _ => Err(Error::os(
+                format!(
+                    "Expected \"native\", \"bwrap\", or \"landlock\", not {:?}",
+                    s
+                ),
+                std::io::ErrorKind::InvalidInput.into(),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Native => write!(f, "native"),
+            Backend::Bwrap => write!(f, "bwrap"),
+            Backend::Landlock => write!(f, "landlock"),
+        }
+    }
+}
+
+/// Exit code of a child process.  Or 128 + the signal which killed it.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(sig)) => 128 + sig,
+        (None, None) => 1,
+    }
+}
+
+/// Find an executable `name` in `$PATH`
+pub fn which(name: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|file| match fs::metadata(file) {
+            Ok(meta) => meta.is_file() && meta.permissions().mode() & 0o111 != 0,
+            Err(_) => false,
+        })
+}
+
+/// Arguments of a `bwrap` invocation.  Applied by `bwrap` in order, so later mounts
+/// cover earlier ones.
+#[derive(Debug, Clone, Default)]
+pub struct Bwrap {
+    args: Vec<String>,
+}
+
+fn path_arg<P: AsRef<Path>>(path: P) -> String {
+    path.as_ref().to_string_lossy().into_owned()
+}
+
+impl Bwrap {
+    /// New PID, IPC, and cgroup namespaces.  Which end with the caller.
+    pub fn new() -> Bwrap {
+        let mut ret = Bwrap::default();
+        ret.arg("--unshare-pid")
+            .arg("--unshare-ipc")
+            .arg("--unshare-cgroup-try")
+            .arg("--die-with-parent");
+        ret
+    }
+
+    fn arg<S: Into<String>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn unshare_net(&mut self) -> &mut Self {
+        self.arg("--unshare-net")
+    }
+
+    pub fn bind<A: AsRef<Path>, B: AsRef<Path>>(&mut self, src: A, dest: B) -> &mut Self {
+        self.arg("--bind").arg(path_arg(src)).arg(path_arg(dest))
+    }
+
+    pub fn ro_bind<A: AsRef<Path>, B: AsRef<Path>>(&mut self, src: A, dest: B) -> &mut Self {
+        self.arg("--ro-bind").arg(path_arg(src)).arg(path_arg(dest))
+    }
+
+    /// A new `/dev`, with only the usual devices, and an empty `/dev/shm`
+    pub fn dev<P: AsRef<Path>>(&mut self, dest: P) -> &mut Self {
+        self.arg("--dev").arg(path_arg(dest))
+    }
+
+    /// `/proc` of the new PID namespace
+    pub fn proc<P: AsRef<Path>>(&mut self, dest: P) -> &mut Self {
+        self.arg("--proc").arg(path_arg(dest))
+    }
+
+    pub fn tmpfs<P: AsRef<Path>>(&mut self, dest: P) -> &mut Self {
+        self.arg("--tmpfs").arg(path_arg(dest))
+    }
+
+    /// Hide `path`, as `util::mask_path()`.  Does nothing if `path` does not exist.
+    pub fn mask<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        let path = path.as_ref();
+        if path.is_dir() {
+            self.tmpfs(path).remount_ro(path)
+        } else if path.exists() {
+            self.ro_bind("/dev/null", path)
+        } else {
+            self
+        }
+    }
+
+    pub fn remount_ro<P: AsRef<Path>>(&mut self, dest: P) -> &mut Self {
+        self.arg("--remount-ro").arg(path_arg(dest))
+    }
+
+    pub fn setenv(&mut self, name: &str, value: &str) -> &mut Self {
+        self.arg("--setenv").arg(name).arg(value)
+    }
+
+    pub fn chdir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.arg("--chdir").arg(path_arg(dir))
+    }
+
+    /// Arguments given so far
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// Prepare to run `cmd`.  As the calling user, when SUID.
+    pub fn command<S: AsRef<str>>(&self, cmd: &[S]) -> Command {
+        let mut ret = Command::new(BWRAP);
+        ret.args(&self.args)
+            .arg("--")
+            .args(cmd.iter().map(|a| a.as_ref()));
+        if util::geteuid() != util::getuid() {
+            ret.uid(util::getuid()).gid(util::getgid());
+        }
+        ret
+    }
+
+    /// Run `cmd`, and wait for it to exit.  Returns the exit code.
+    /// Or 128 + the signal which killed `bwrap`.
+    pub fn run<S: AsRef<str>>(&self, cmd: &[S]) -> Result<i32> {
+        let status = self
+            .command(cmd)
+            .status()
+            .map_err(|e| Error::file("exec", BWRAP, e))?;
+        Ok(exit_code(status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!("bwrap".parse::<Backend>().unwrap(), Backend::Bwrap);
+        assert_eq!("native".parse::<Backend>().unwrap(), Backend::Native);
+        assert!("docker".parse::<Backend>().is_err());
+        assert_eq!(Backend::Bwrap.to_string(), "bwrap");
+        assert_eq!("landlock".parse::<Backend>().unwrap(), Backend::Landlock);
+    }
+
+    #[test]
+    fn args() {
+        let mut bwrap = Bwrap::new();
+        bwrap
+            .ro_bind("/", "/")
+            .unshare_net()
+            .mask("/nonexistent")
+            .mask("/dev/null")
+            .setenv("A", "b c");
+        assert_eq!(
+            &bwrap.args()[4..],
+            [
+                "--ro-bind",
+                "/",
+                "/",
+                "--unshare-net",
+                "--ro-bind",
+                "/dev/null",
+                "/dev/null",
+                "--setenv",
+                "A",
+                "b c"
+            ]
+        );
+        assert!(which("sh").is_some());
+        assert!(which("no-such-executable").is_none());
+    }
+}
@@ -0,0 +1,282 @@
+//! Keep the original contents of files modified by a sandboxed command.
+//!
+//! Writable binds are watched with fanotify `FAN_OPEN_PERM`.  The opening process
+//! is blocked until the first opened version of each regular file has been copied
+//! to the backup directory.  Once the command exits, copies of files which are
+//! unchanged are discarded.
+//!
+//! Permission events need `CAP_SYS_ADMIN` in the initial user namespace.
+//! Files removed, renamed over, or truncated, without first being opened are not caught.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{debug, warn};
+
+use super::err::{Error, Result};
+use super::snapshot;
+use super::{id, util};
+
+/// Watch for opens of files on the mounts at `dirs`, as seen by the calling process.
+pub fn watch<P: AsRef<Path>>(dirs: &[P]) -> Result<OwnedFd> {
+    let fan = unsafe {
+        let fd = libc::fanotify_init(
+            libc::FAN_CLASS_CONTENT | libc::FAN_CLOEXEC,
+            (libc::O_RDONLY | libc::O_LARGEFILE | libc::O_CLOEXEC) as _,
+        );
+        if fd < 0 {
+            return Err(Error::last_os_error("fanotify_init"));
+        }
+        OwnedFd::from_raw_fd(fd)
+    };
+    for dir in dirs {
+        let dir = dir.as_ref();
+        debug!("fanotify watch {}", dir.display());
+        let cdir = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+        let ret = unsafe {
+            libc::fanotify_mark(
+                fan.as_raw_fd(),
+                libc::FAN_MARK_ADD | libc::FAN_MARK_MOUNT,
+                libc::FAN_OPEN_PERM,
+                libc::AT_FDCWD,
+                cdir.as_ptr(),
+            )
+        };
+        if ret < 0 {
+            return Err(Error::last_file_error("fanotify_mark", dir));
+        }
+    }
+    Ok(fan)
+}
+
+/// Identifies one version of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stamp {
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl From<&fs::Metadata> for Stamp {
+    fn from(meta: &fs::Metadata) -> Stamp {
+        Stamp {
+            ino: meta.ino(),
+            size: meta.size(),
+            mtime: (meta.mtime(), meta.mtime_nsec()),
+            ctime: (meta.ctime(), meta.ctime_nsec()),
+        }
+    }
+}
+
+/// Copies of files under some directories, made before they are first opened.
+#[derive(Debug)]
+pub struct Backup {
+    /// Copy of `/some/file` is `<dir>/some/file`
+    dir: PathBuf,
+    sources: Vec<PathBuf>,
+    saved: Arc<Mutex<HashMap<PathBuf, Stamp>>>,
+}
+
+impl Backup {
+    /// Backup files under `sources` to a new sub-directory of `base`.
+    /// Which is created with the first copy.
+    pub fn new<B: AsRef<Path>>(base: B, sources: Vec<PathBuf>) -> Backup {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let dir = base
+            .as_ref()
+            .join(format!("{}-{}", now.as_secs(), id::current()));
+        Backup {
+            dir,
+            sources,
+            saved: Default::default(),
+        }
+    }
+
+    /// Where copies are kept
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Copy `file`, opened from `path`, unless already copied.
+    fn save(&self, file: &File, path: &Path) -> Result<()> {
+        if !path.is_absolute() || !self.sources.iter().any(|s| path.starts_with(s)) {
+            return Ok(());
+        }
+        let meta = file.metadata().map_err(|e| Error::file("stat", path, e))?;
+        if !meta.is_file() {
+            return Ok(());
+        }
+        let mut saved = self.saved.lock().unwrap();
+        if saved.contains_key(path) {
+            return Ok(());
+        }
+
+        let copy = self.dir.join(path.strip_prefix("/").unwrap());
+        util::mkdirs(copy.parent().unwrap())?;
+        let mut output = File::create(&copy).map_err(|e| Error::file("create", &copy, e))?;
+        if snapshot::reflink(file, &output).is_err() {
+            // eg. different filesystem.  This descriptor is used only here, from the start.
+            io::copy(&mut &*file, &mut output).map_err(|e| Error::file("copy", &copy, e))?;
+        }
+        output
+            .set_permissions(meta.permissions())
+            .map_err(|e| Error::file("chmod", &copy, e))?;
+        snapshot::set_times(&output, &meta)?;
+        debug!("Backup {}", path.display());
+        saved.insert(path.to_path_buf(), Stamp::from(&meta));
+        Ok(())
+    }
+
+    /// Handle events from `watch()` in a background thread.  Runs until the process exits.
+    pub fn spawn(&self, fan: OwnedFd) -> Result<thread::JoinHandle<()>> {
+        let backup = Backup {
+            dir: self.dir.clone(),
+            sources: self.sources.clone(),
+            saved: self.saved.clone(),
+        };
+        Ok(thread::spawn(move || {
+            let mut fan = File::from(fan);
+            // u64 for event alignment
+            let mut buf = vec![0u64; 512];
+            loop {
+                let n = match fan.read(bytes_mut(&mut buf)) {
+                    Ok(n) => n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        warn!("Backup stops : {}", err);
+                        return;
+                    }
+                };
+                if let Err(err) = backup.handle(&mut fan, &bytes_mut(&mut buf)[..n]) {
+                    warn!("Backup stops : {}", err);
+                    return;
+                }
+            }
+        }))
+    }
+
+    /// Handle a batch of events.  Every permission event is allowed, even if the copy fails.
+    fn handle(&self, fan: &mut File, mut events: &[u8]) -> Result<()> {
+        let hlen = mem::size_of::<libc::fanotify_event_metadata>();
+        while events.len() >= hlen {
+            let event: libc::fanotify_event_metadata =
+                unsafe { std::ptr::read_unaligned(events.as_ptr() as *const _) };
+            let elen = event.event_len as usize;
+            if event.vers != libc::FANOTIFY_METADATA_VERSION || elen < hlen || elen > events.len() {
+                let err = io::Error::new(io::ErrorKind::InvalidData, "unexpected event");
+                return Err(Error::os("fanotify read", err));
+            }
+            events = &events[elen..];
+            if event.fd == libc::FAN_NOFD {
+                warn!("Backup events lost");
+                continue;
+            }
+            let file = unsafe { File::from_raw_fd(event.fd) };
+
+            let path = fs::read_link(format!("/proc/self/fd/{}", event.fd));
+            match path {
+                Ok(path) => {
+                    if let Err(err) = self.save(&file, &path) {
+                        warn!("Unable to backup {} : {}", path.display(), err);
+                    }
+                }
+                Err(err) => warn!("Unable to backup PID {} open : {}", event.pid, err),
+            }
+
+            if event.mask & libc::FAN_OPEN_PERM != 0 {
+                let response = libc::fanotify_response {
+                    fd: event.fd,
+                    response: libc::FAN_ALLOW,
+                };
+                let response = unsafe {
+                    std::slice::from_raw_parts(
+                        &response as *const _ as *const u8,
+                        mem::size_of::<libc::fanotify_response>(),
+                    )
+                };
+                fan.write_all(response)
+                    .map_err(|e| Error::os("fanotify response", e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Discard copies of files which have not changed.
+    /// Returns the files which were changed or removed, and have a copy.
+    pub fn finish(&self) -> Result<Vec<PathBuf>> {
+        let saved = self.saved.lock().unwrap();
+        let mut changed = vec![];
+        for (path, stamp) in saved.iter() {
+            let current = fs::symlink_metadata(path).ok();
+            if current.as_ref().map(Stamp::from).as_ref() != Some(stamp) {
+                changed.push(path.clone());
+                continue;
+            }
+            let copy = self.dir.join(path.strip_prefix("/").unwrap());
+            fs::remove_file(&copy).map_err(|e| Error::file("remove", &copy, e))?;
+            // any now empty parents
+            for dir in copy.ancestors().skip(1) {
+                if !dir.starts_with(&self.dir) || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+        changed.sort();
+        Ok(changed)
+    }
+}
+
+fn bytes_mut(buf: &mut [u64]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, buf.len() * 8) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn prune() {
+        let tdir = TempDir::new().unwrap();
+        let src = util::mkdirs(tdir.path().join("src")).unwrap();
+        for name in ["same", "changed", "removed"] {
+            fs::write(src.join(name), name).unwrap();
+        }
+        let backup = Backup::new(tdir.path().join("backup"), vec![src.clone()]);
+        assert!(backup.dir().starts_with(tdir.path().join("backup")));
+
+        for name in ["same", "changed", "removed"] {
+            let path = src.join(name);
+            backup.save(&File::open(&path).unwrap(), &path).unwrap();
+        }
+        // outside of sources
+        let other = tdir.path().join("other");
+        fs::write(&other, "").unwrap();
+        backup.save(&File::open(&other).unwrap(), &other).unwrap();
+
+        // the first version is kept
+        fs::write(src.join("changed"), "new contents").unwrap();
+        let path = src.join("changed");
+        backup.save(&File::open(&path).unwrap(), &path).unwrap();
+        fs::remove_file(src.join("removed")).unwrap();
+
+        let changed = backup.finish().unwrap();
+        assert_eq!(changed, vec![src.join("changed"), src.join("removed")]);
+        let copy = |name: &str| backup.dir().join(src.join(name).strip_prefix("/").unwrap());
+        assert_eq!(fs::read_to_string(copy("changed")).unwrap(), "changed");
+        assert_eq!(fs::read_to_string(copy("removed")).unwrap(), "removed");
+        assert!(!copy("same").exists());
+        assert!(!backup.dir().join(other.strip_prefix("/").unwrap()).exists());
+    }
+}
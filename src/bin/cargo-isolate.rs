@@ -0,0 +1,271 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::{env, process};
+
+use log::{debug, warn};
+
+use sandbox::config::{Document, Table, Value};
+use sandbox::msg::{self, Msg};
+use sandbox::{ui, util, Error};
+
+/// Keys of [package.metadata.isolate] and [workspace.metadata.isolate]
+const METADATA_KEYS: &[&str] = &["net", "registry", "rw", "ro", "profile"];
+
+fn usage() {
+    eprint!("{}", msg::text(Msg::CargoIsolateUsage));
+}
+
+/// Options, from the command line and Cargo.toml
+#[derive(Debug, Default)]
+struct Options {
+    net: Option<bool>,
+    registry_rw: Option<bool>,
+    profile: Option<PathBuf>,
+    /// (writable, directory) in order of increasing precedence
+    dirs: Vec<(bool, PathBuf)>,
+}
+
+impl Options {
+    /// Add defaults from the isolate table of Cargo metadata
+    fn merge_metadata(&mut self, isolate: &Table, root: &Path) {
+        for key in isolate.keys() {
+            if !METADATA_KEYS.contains(&key.as_str()) {
+                warn!("Ignore unknown key metadata.isolate.{key}");
+            }
+        }
+        let item = |key: &str| isolate.get(key).map(|i| &i.value);
+        if let Some(net) = item("net").and_then(Value::as_bool) {
+            self.net = self.net.or(Some(net));
+        }
+        match item("registry").and_then(Value::as_str) {
+            Some("ro") => self.registry_rw = self.registry_rw.or(Some(false)),
+            Some("rw") => self.registry_rw = self.registry_rw.or(Some(true)),
+            Some(other) => warn!("Ignore metadata.isolate.registry = {other:?}"),
+            None => (),
+        }
+        if let Some(file) = item("profile").and_then(Value::as_str) {
+            self.profile = self.profile.take().or_else(|| Some(root.join(file)));
+        }
+        // before any from the command line
+        let mut dirs = vec![];
+        for (key, writable) in [("rw", true), ("ro", false)] {
+            let list = item(key).and_then(Value::as_array).unwrap_or_default();
+            dirs.extend(
+                list.iter()
+                    .filter_map(Value::as_str)
+                    .map(|d| (writable, root.join(d))),
+            );
+        }
+        self.dirs.splice(0..0, dirs);
+    }
+}
+
+/// Relevant parts of "cargo metadata"
+#[derive(Debug)]
+struct Metadata {
+    workspace_root: PathBuf,
+    target_directory: PathBuf,
+    /// [workspace.metadata.isolate], then [package.metadata.isolate] of the root package
+    isolate: Vec<Table>,
+}
+
+fn cargo() -> String {
+    // set by cargo when running a subcommand
+    env::var("CARGO").unwrap_or_else(|_| "cargo".to_string())
+}
+
+fn metadata() -> Result<Metadata, Error> {
+    let out = Command::new(cargo())
+        .args(["metadata", "--no-deps", "--format-version", "1"])
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !out.status.success() {
+        ui::fatal(msg::text(Msg::MetadataFailed));
+    }
+    let doc = Document::parse_json(&String::from_utf8_lossy(&out.stdout), "cargo metadata")?;
+    let root = &doc.root;
+    let path = |key: &str| -> Result<PathBuf, Error> {
+        match root.get(key).and_then(|i| i.value.as_str()) {
+            Some(path) => Ok(path.into()),
+            None => Err(format!("cargo metadata without {key}").into()),
+        }
+    };
+    let workspace_root = path("workspace_root")?;
+    let isolate_of = |table: Option<&Table>| -> Option<Table> {
+        let metadata = table?.get("metadata")?.value.as_table()?;
+        metadata.get("isolate")?.value.as_table().cloned()
+    };
+
+    let mut isolate: Vec<Table> = isolate_of(Some(root)).into_iter().collect();
+    let manifest = workspace_root.join("Cargo.toml");
+    let packages = root.get("packages").and_then(|i| i.value.as_array());
+    let package = packages.unwrap_or_default().iter().find(|p| {
+        let path = p.as_table().and_then(|t| t.get("manifest_path"));
+        matches!(path.and_then(|i| i.value.as_str()), Some(p) if Path::new(p) == manifest)
+    });
+    isolate.extend(isolate_of(package.and_then(Value::as_table)));
+
+    Ok(Metadata {
+        target_directory: path("target_directory")?,
+        workspace_root,
+        isolate,
+    })
+}
+
+/// The workspace will be read-only.  Create any missing Cargo.lock beforehand.
+/// Which only resolves dependencies, without running any build scripts.
+fn generate_lockfile(root: &Path, net: bool) -> Result<(), Error> {
+    if root.join("Cargo.lock").exists() {
+        return Ok(());
+    }
+    let mut cmd = Command::new(cargo());
+    cmd.arg("generate-lockfile").current_dir(root);
+    if !net {
+        cmd.arg("--offline");
+    }
+    debug!("Generate lockfile {:?}", cmd);
+    if !cmd.status()?.success() {
+        ui::fatal(msg::tr(
+            Msg::LockfileFailed,
+            &[("file", &root.join("Cargo.lock").display())],
+        ));
+    }
+    Ok(())
+}
+
+/// `isolate` beside this executable, or else from $PATH
+fn isolate_exe() -> String {
+    let exe = env::current_exe().ok();
+    let sibling = exe.as_ref().and_then(|e| Some(e.parent()?.join("isolate")));
+    match sibling.filter(|e| e.is_file()) {
+        Some(exe) => exe.to_string_lossy().into_owned(),
+        None => "isolate".to_string(),
+    }
+}
+
+fn run() -> Result<(), Error> {
+    sandbox::logging::setup().unwrap();
+
+    let mut iargs = env::args().skip(1).peekable();
+    // "cargo isolate ..." runs "cargo-isolate isolate ..."
+    if iargs.peek().map(String::as_str) == Some("isolate") {
+        iargs.next();
+    }
+
+    let mut opts = Options::default();
+    while let Some(arg) = iargs.peek() {
+        if !arg.starts_with('-') {
+            break;
+        }
+        let arg = iargs.next().unwrap();
+        let mut value = || match iargs.next() {
+            Some(value) => value,
+            None => {
+                usage();
+                ui::fatal(msg::tr(Msg::ExpectsArgument, &[("arg", &arg)]));
+            }
+        };
+
+        if arg == "-N" || arg == "--net" {
+            opts.net = Some(true);
+        } else if arg == "--registry" {
+            opts.registry_rw = match value().as_str() {
+                "ro" => Some(false),
+                "rw" => Some(true),
+                other => {
+                    usage();
+                    ui::fatal(msg::tr(
+                        Msg::RegistryValue,
+                        &[("value", &format!("{other:?}"))],
+                    ));
+                }
+            };
+        } else if arg == "-p" || arg == "--profile" {
+            opts.profile = Some(value().into());
+        } else if arg == "-W" || arg == "--rw" {
+            opts.dirs.push((true, value().into()));
+        } else if arg == "-O" || arg == "--ro" {
+            opts.dirs.push((false, value().into()));
+        } else if let Some(change) = ui::verbosity_arg(&arg) {
+            ui::set_verbosity(ui::verbosity() + change);
+        } else if arg == "--" {
+            break;
+        } else if arg == "-h" || arg == "--help" {
+            usage();
+            return Ok(());
+        } else {
+            usage();
+            ui::fatal(msg::tr(Msg::UnknownArgument, &[("arg", &arg)]));
+        }
+    }
+
+    let cmd: Vec<String> = iargs.collect();
+    if cmd.is_empty() {
+        usage();
+        process::exit(1);
+    }
+
+    let meta = metadata()?;
+    for isolate in meta.isolate.iter().rev() {
+        opts.merge_metadata(isolate, &meta.workspace_root);
+    }
+    let net = opts.net.unwrap_or(false);
+    let registry_rw = opts.registry_rw.unwrap_or(net);
+    generate_lockfile(&meta.workspace_root, net)?;
+
+    let exe = isolate_exe();
+    let mut args = vec![exe.clone(), "--name".to_string(), "cargo".to_string()];
+    if let Some(profile) = &opts.profile {
+        args.extend([
+            "--profile".to_string(),
+            profile.to_string_lossy().into_owned(),
+        ]);
+    }
+    if net {
+        args.push("--net".to_string());
+    }
+    match ui::verbosity() {
+        0 => (),
+        v if v < 0 => args.push("-q".to_string()),
+        v => args.push(format!("-{}", "v".repeat(v as usize))),
+    }
+    // no writes to $PWD, or anywhere else in the workspace
+    args.extend(["-c".to_string(), "-O".to_string()]);
+    args.push(meta.workspace_root.to_string_lossy().into_owned());
+
+    // isolate ignores directories which do not exist
+    let mut writable = vec![meta.target_directory.clone()];
+    if registry_rw {
+        let home = match env::var_os("CARGO_HOME") {
+            Some(home) => PathBuf::from(home),
+            None => PathBuf::from(env::var("HOME")?).join(".cargo"),
+        };
+        writable.extend([home.join("registry"), home.join("git")]);
+    }
+    for dir in &writable {
+        if !dir.exists() {
+            util::mkdirs(dir)?;
+        }
+        args.extend(["-W".to_string(), dir.to_string_lossy().into_owned()]);
+    }
+    for (rw, dir) in &opts.dirs {
+        let flag = if *rw { "-W" } else { "-O" };
+        args.extend([flag.to_string(), dir.to_string_lossy().into_owned()]);
+    }
+
+    args.push(cargo());
+    args.extend(cmd);
+
+    let mut exec = util::Exec::new(&exe)?;
+    exec.args(&args)?;
+    if !net {
+        exec.env("CARGO_NET_OFFLINE", "true")?;
+    }
+    debug!("EXEC {:?}", args);
+    exec.exec()?;
+    Ok(())
+}
+
+fn main() {
+    ui::main(run)
+}
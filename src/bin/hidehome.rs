@@ -1,21 +1,27 @@
+use std::os::unix::io::RawFd;
 use std::path::Path;
 use std::{env, process};
 
 use libc;
 use log::debug;
 
-use sandbox::container::{ContainerHooks, IdMap, Proc};
-use sandbox::util;
+use sandbox::container::{ContainerHooks, IdMap, StageCtx};
+use sandbox::fs::Mounts;
+use sandbox::msg::{self, Msg};
+use sandbox::toolchain::{self, Toolchains};
 use sandbox::{runc, Error};
+use sandbox::{ui, util};
 
 /// Container which executes a command with most of /home hidden
 struct HideHome {
     isuser: bool,
     args: Vec<String>,
+    /// Inherited from our caller
+    keepfds: Vec<RawFd>,
 }
 
 impl HideHome {
-    pub fn new<I>(args: I) -> Result<HideHome, Error>
+    pub fn new<I>(args: I, keepfds: Vec<RawFd>) -> Result<HideHome, Error>
     where
         I: IntoIterator,
         I::Item: Into<String>,
@@ -23,24 +29,23 @@ impl HideHome {
         Ok(HideHome {
             isuser: !util::Cap::current()?.effective(util::CAP_SYS_ADMIN),
             args: args.into_iter().map(|e| e.into()).collect(),
+            keepfds,
         })
     }
 }
 
 impl ContainerHooks for HideHome {
-    fn unshare(&self) -> Result<(), Error> {
-        debug!("child unshare()");
+    fn namespaces(&self) -> libc::c_int {
         let mut flags = libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWCGROUP;
         if self.isuser {
             flags |= libc::CLONE_NEWUSER;
         }
-        util::unshare(flags)?;
-        Ok(())
+        flags
     }
 
-    fn set_id_map(&self, pid: &Proc) -> Result<(), Error> {
+    fn set_id_map(&self, ctx: &StageCtx) -> Result<(), Error> {
         // Setup 1-1 mapping
-        if self.isuser {
+        if let (true, Some(pid)) = (self.isuser, ctx.child()) {
             debug!("Setup 1-1 UID mapping");
             let uid = util::getuid();
             let gid = util::getgid();
@@ -50,7 +55,7 @@ impl ContainerHooks for HideHome {
         Ok(())
     }
 
-    fn setup_priv(&self) -> Result<(), Error> {
+    fn setup_priv(&self, _ctx: &StageCtx) -> Result<(), Error> {
         let tmp = Path::new("/tmp");
 
         // Taking notion of /home from caller's environment.
@@ -58,8 +63,7 @@ impl ContainerHooks for HideHome {
         // and never grant more visibility or permission.
         let home = Path::new(&env::var("HOME")?).canonicalize()?;
         if !home.is_absolute() {
-            eprintln!("$HOME must be an absolute path");
-            process::exit(1);
+            ui::fatal(msg::text(Msg::HomeNotAbsolute));
         }
 
         // The root of the tree we will hide.
@@ -73,8 +77,7 @@ impl ContainerHooks for HideHome {
         let cwd = env::current_dir()?.canonicalize()?;
 
         if cwd.starts_with(tmp) {
-            eprintln!("Can't run under /tmp");
-            process::exit(1);
+            ui::fatal(msg::text(Msg::UnderTmp));
         }
 
         let noopt = libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_NOSUID | libc::MS_RELATIME;
@@ -109,6 +112,15 @@ impl ContainerHooks for HideHome {
             util::mkdirs(&home)?;
         }
 
+        // keep toolchain roots which would be hidden.  eg. /home/linuxbrew/.linuxbrew
+        let mut mounts = Mounts::current()?;
+        for dir in Toolchains::Detect.resolve() {
+            if let Ok(rel) = dir.strip_prefix(root) {
+                let target = util::mkdirs(tmp.join(rel))?;
+                toolchain::bind_ro(&mut mounts, &dir, &target)?;
+            }
+        }
+
         // hide real /home
         util::mount(&tmp, &root, "", libc::MS_MOVE)?;
 
@@ -123,9 +135,10 @@ impl ContainerHooks for HideHome {
         Ok(())
     }
 
-    fn setup(&self) -> Result<(), Error> {
+    fn setup(&self, _ctx: &StageCtx) -> Result<(), Error> {
         debug!("EXEC {:?}", &self.args[0..]);
 
+        util::cloexec_extra_fds(&self.keepfds)?;
         util::Exec::new(&self.args[0])?
             .args(&self.args[0..])?
             .exec()?;
@@ -134,14 +147,20 @@ impl ContainerHooks for HideHome {
     }
 }
 
-fn main() -> Result<(), Error> {
+fn run() -> Result<(), Error> {
+    let keepfds = util::open_fds()?;
     sandbox::logging::setup().unwrap();
 
     let rawargs = env::args().collect::<Vec<String>>();
-    if rawargs.len() <= 1 {
-        eprintln!("Usage: {} <cmd> [args ...]", rawargs[0]);
+    let args = ui::take_verbosity(&rawargs[1..]);
+    if args.is_empty() {
+        eprintln!("{}", msg::tr(Msg::CmdUsage, &[("execname", &rawargs[0])]));
         process::exit(1);
     }
 
-    process::exit(runc(&HideHome::new(&rawargs[1..])?)?);
+    process::exit(runc(&HideHome::new(args, keepfds)?)?);
+}
+
+fn main() {
+    ui::main(run)
 }
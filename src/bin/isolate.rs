@@ -1,38 +1,731 @@
+use std::cell::RefCell;
 use std::collections::HashSet;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{env, process};
 
 use log;
 
-use sandbox::container::{ContainerHooks, IdMap, Proc};
-use sandbox::fs::Mounts;
+use sandbox::backend::{self, Backend, Bwrap};
+use sandbox::backup::{self, Backup};
+use sandbox::container::{self, ContainerHooks, ContainerInfo, IdMap, StageCtx};
+use sandbox::coredump::CorePolicy;
+use sandbox::crash::{self, CrashTrace};
+use sandbox::dhcp;
+use sandbox::entropy;
+use sandbox::envpolicy::Preset;
+use sandbox::fs::{self, MountInfo, Mounts};
+use sandbox::gc;
+use sandbox::hook::{HookCmd, Stage};
+use sandbox::id;
+use sandbox::info::{self, SandboxInfo};
+use sandbox::landlock::{self, Ruleset};
+use sandbox::limits::{self, Admit, Limits};
+use sandbox::msg::{self, Msg};
+use sandbox::net::{self, PortForward};
+use sandbox::notify::{self, NotifyProxy};
 use sandbox::path;
-use sandbox::tempdir::TempDir;
-use sandbox::{net, util};
-use sandbox::{runc, Error};
+use sandbox::policy::{Hardening, Level};
+use sandbox::portal::{self, Documents};
+use sandbox::procfs::ProcFs;
+use sandbox::profile::Profile;
+use sandbox::project::{self, AllowList};
+use sandbox::registry::{Entry, Registry};
+use sandbox::retry::Restart;
+use sandbox::scratch::Scratch;
+use sandbox::seccomp::{self, Filter};
+use sandbox::secret::{self, Secret, SECRETS_DIR};
+use sandbox::site::SitePolicy;
+use sandbox::snapshot::{self, Snapshot};
+use sandbox::stats::Phase;
+use sandbox::stdio::{LimitAction, OutputProxy};
+use sandbox::systemd::Scope;
+use sandbox::tempdir::{self, Location, TempDir};
+use sandbox::term::{self, Color, Pty};
+use sandbox::toolchain::{self, Toolchains};
+use sandbox::util::{Daemon, OnExit};
+use sandbox::wayland::{self, SecurityContext};
+use sandbox::{nat, ui, util};
+use sandbox::{runc_cancel, CancelToken, Error};
 
 const NOOPT: libc::c_ulong = libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_NOSUID | libc::MS_RELATIME;
 const TMPOPT: libc::c_ulong = libc::MS_NODEV | libc::MS_NOSUID | libc::MS_RELATIME;
 
+/// Where the command finds the relayed `$NOTIFY_SOCKET`
+const NOTIFY_SOCKET: &str = "/tmp/.sd-notify";
+
+/// Where the command finds the Wayland security context socket of --gui
+const WAYLAND_SOCKET: &str = "/tmp/.wayland-0";
+
+/// Name of the sandbox end of the veth pair of --net-bridge
+const BRIDGE_IFNAME: &str = "eth0";
+
+/// How long --dhcp waits for a lease
+const DHCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default prompt prefix of --shell
+const PROMPT_PREFIX: &str = "(sandbox) ";
+
+/// Default value of `$SANDBOX_NAME`
+const DEFAULT_NAME: &str = "isolate";
+
+/// Quote for `sh`
+fn sh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Prompt variables to prepend `prefix`.
+///
+/// `$PS1` is usually replaced by rc files, so also prefix again before each
+/// prompt through `$PROMPT_COMMAND` (bash).
+fn prompt_env(prefix: &str) -> Vec<(&'static str, String)> {
+    let ps1 = env::var("PS1").unwrap_or_else(|_| "$ ".to_string());
+    let quoted = sh_quote(prefix);
+    let mut cmd = format!("PS1={0}\"${{PS1#{0}}}\"", quoted);
+    if let Ok(prev) = env::var("PROMPT_COMMAND") {
+        if !prev.is_empty() {
+            cmd = format!("{};{}", cmd, prev);
+        }
+    }
+    vec![
+        ("PS1", format!("{}{}", prefix, ps1)),
+        ("PROMPT_COMMAND", cmd),
+    ]
+}
+
+/// Writable with --backend landlock.  Those which exist.
+const LANDLOCK_DEVICES: &[&str] = &[
+    "/dev/null",
+    "/dev/zero",
+    "/dev/full",
+    "/dev/tty",
+    "/dev/ptmx",
+    "/dev/pts",
+    "/dev/shm",
+];
+
+/// Where the temporary directory may be mounted, when in memory.  The first not
+/// covering a bound directory.
+const MEMORY_MOUNTS: &[&str] = &["/tmp", "/var/tmp", "/mnt"];
+
+/// Not available inside, when mounts can be removed
+const BLACKLIST_FSTYPES: &[&str] = &["cgroup", "cgroup2", "debugfs"];
+
+/// A sandbox started with --detach
+struct Detached {
+    registry: Registry,
+    id: String,
+    /// Closed once the container has started, to release the caller
+    report: RefCell<Option<File>>,
+}
+
+/// --snapshot-before, or --rollback-on-failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SnapshotMode {
+    Keep,
+    Rollback,
+}
+
 #[derive(Debug)]
 enum MountType {
     ReadOnly,
     Writable,
+    /// Read-only, nosuid, and nodev
+    Toolchain,
+}
+
+/// Run by root, a user namespace where `uid` and `gid` are the first of host `uids` and `gids`
+#[derive(Debug, Clone, Copy)]
+struct RootMap {
+    uid: u32,
+    gid: u32,
+    uids: util::IdRange,
+    gids: util::IdRange,
 }
 
 struct Isolate<'a> {
     isuser: bool,
+    /// Run by root.  The user namespace entered after setup.
+    rootmap: Option<RootMap>,
+    /// --run-as.  Switched to after setup, within any user namespace.
+    runas: Option<util::Account>,
     allownet: bool,
+    /// Keep CAP_NET_RAW, in the new network namespace
+    netraw: bool,
+    /// --net-ipv6.  Otherwise IPv6 is disabled on interfaces created for the sandbox.
+    netipv6: bool,
+    /// --net-bridge.  Existing host bridge
+    netbridge: Option<String>,
+    /// --dhcp.  Configure `BRIDGE_IFNAME` from a DHCP server on the bridge
+    dhcp: bool,
+    /// --net-nat.  Forward TCP, and DNS, from the parent.  cf. `nat`
+    netnat: bool,
+    /// --publish.  Host ports forwarded into the network namespace
+    publish: Vec<PortForward>,
+    /// Processes forwarding `publish`, once started
+    forwarders: RefCell<Vec<util::Proc>>,
     args: Vec<String>,
+    shell: bool,
+    /// --exec-stdin.  Run instead of looking up `args[0]`
+    payload: Option<Vec<u8>>,
+    /// `$SANDBOX_NAME`
+    name: String,
+    /// Prepended to `$PS1`
+    prompt: Option<String>,
+    /// Also set `$VIRTUAL_ENV`, as older versions did
+    virtualenv: bool,
     tdir: &'a Path,
+    /// Where a tmpfs holding `tdir` is mounted, inside the mount namespace.
+    /// When no host directory is usable.  cf. `tempdir::probe()`
+    memory: Option<PathBuf>,
     mounts: Vec<(MountType, PathBuf)>,
     cwd: PathBuf,
-    bridge: std::cell::Cell<Option<net::Bridge>>,
+    pidfile: Option<PathBuf>,
+    notifyfd: Option<RawFd>,
+    /// Inherited from our caller, and passed on to the command.  eg. a make jobserver
+    keepfds: Vec<RawFd>,
+    sdnotify: bool,
+    notifyproxy: Option<NotifyProxy>,
+    /// --gui
+    wayland: Option<SecurityContext>,
+    /// --document, or --pick-documents
+    documents: Option<Documents>,
+    /// Granted documents, as seen by the command
+    docpaths: Vec<PathBuf>,
+    secrets: Vec<Secret>,
+    /// --env-preset.  Otherwise the environment is inherited
+    envpreset: Option<Preset>,
+    /// Masked by site policy, after user binds
+    sitemask: Vec<PathBuf>,
+    scope: Option<Scope>,
+    profile: Profile,
+    timereport: bool,
+    /// Container process 1, once started
+    procfs: RefCell<Option<ProcFs>>,
+    output: Option<OutputProxy>,
+    /// --pty
+    pty: Option<Pty>,
+    termout: term::Output,
+    color: Color,
+    /// Adjusted for `termout`.  `None` to remove
+    termenv: Vec<(&'static str, Option<String>)>,
+    cores: Option<CorePolicy>,
+    crashtrace: Option<CrashTrace>,
+    onexit: OnExit,
+    snapshot: Option<SnapshotMode>,
+    /// --scratch.  Paths in the sandbox
+    scratch: Vec<PathBuf>,
+    /// Once created, just before running
+    scratchdirs: Option<Scratch>,
+    backup: Option<Backup>,
+    detached: Option<Detached>,
+    maskuffd: bool,
+    /// --entropy-seed
+    entropy: Option<u64>,
+    hardening: Option<Hardening>,
+    filter: Filter,
+    info: SandboxInfo,
+}
+
+impl<'a> Isolate<'a> {
+    /// Host uid and gid of the command
+    fn command_ids(&self) -> (u32, u32) {
+        let (uid, gid) = match &self.runas {
+            Some(account) => (account.uid, account.gid),
+            None => (util::getuid(), util::getgid()),
+        };
+        match &self.rootmap {
+            Some(map) => (
+                map.uids.start + (uid - map.uid),
+                map.gids.start + (gid - map.gid),
+            ),
+            None => (uid, gid),
+        }
+    }
+
+    /// Variables added to the environment of the command
+    fn env_vars(&self) -> Vec<(&'static str, String)> {
+        let mut ret = vec![
+            (info::ENV_MARKER, "1".to_string()),
+            ("SANDBOX_NAME", self.name.clone()),
+            (id::ENV_ID, id::current()),
+        ];
+        if let Some(account) = &self.runas {
+            ret.push(("HOME", account.home.display().to_string()));
+            ret.push(("USER", account.name.clone()));
+            ret.push(("LOGNAME", account.name.clone()));
+        }
+        if self.virtualenv {
+            ret.push(("VIRTUAL_ENV", "isolated".to_string()));
+        }
+        match (&self.prompt, self.shell) {
+            (Some(prefix), _) => ret.extend(prompt_env(prefix)),
+            (None, true) => ret.extend(prompt_env(PROMPT_PREFIX)),
+            (None, false) => (),
+        }
+        if self.shell {
+            ret.push(("SANDBOX_SHELL", "1".to_string()));
+        }
+        if self.notifyproxy.is_some() {
+            ret.push(("NOTIFY_SOCKET", NOTIFY_SOCKET.to_string()));
+        }
+        if self.wayland.is_some() {
+            ret.push(("WAYLAND_DISPLAY", WAYLAND_SOCKET.to_string()));
+        }
+        if self.documents.is_some() {
+            let paths: Vec<String> = self
+                .docpaths
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect();
+            ret.push(("SANDBOX_DOCUMENTS", paths.join("\n")));
+        }
+        for (name, value) in &self.termenv {
+            if let Some(value) = value {
+                ret.push((name, value.clone()));
+            }
+        }
+        ret
+    }
+
+    /// Variables removed from the environment of the command
+    fn env_removed(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.termenv
+            .iter()
+            .filter(|(_, value)| value.is_none())
+            .map(|(name, _)| *name)
+    }
+
+    /// Summary for `info::INFO_FILE`
+    fn summary(&self) -> SandboxInfo {
+        let dirs = |want: fn(&MountType) -> bool| {
+            self.mounts
+                .iter()
+                .filter(|(mtype, _)| want(mtype))
+                .map(|(_, dir)| dir.clone())
+                .collect()
+        };
+        SandboxInfo {
+            tool: "isolate".into(),
+            id: id::current(),
+            namespaces: container::namespace_names(self.namespaces())
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            network: self.allownet,
+            writable: dirs(|t| matches!(t, MountType::Writable)),
+            readonly: dirs(|t| matches!(t, MountType::ReadOnly | MountType::Toolchain)),
+            hardening: self.hardening.as_ref().map(|h| h.level.to_string()),
+            seccomp: self
+                .filter
+                .syscalls()
+                .into_iter()
+                .map(|nr| match seccomp::syscall_name(nr) {
+                    Some(name) => name.to_string(),
+                    None => nr.to_string(),
+                })
+                .collect(),
+            no_new_privs: matches!(&self.hardening, Some(h) if h.no_new_privs),
+        }
+    }
+
+    /// Write `info::INFO_FILE` under the new root.
+    fn write_info(&self, tdir: &Path, new_root: &Path) -> Result<(), Error> {
+        let host = Path::new(info::INFO_FILE).parent().unwrap();
+        if !path!(new_root, host.parent().unwrap().strip_prefix("/")?).is_dir() {
+            log::debug!("Not creating {}", host.display());
+            return Ok(());
+        }
+        let dir = mkdir_shadowed(tdir, new_root, host)?;
+        util::mount_with_data("none", &dir, "tmpfs", NOOPT, "mode=0755,size=64k")?;
+        util::write_new_file(
+            path!(new_root, info::INFO_FILE.strip_prefix("/").unwrap()),
+            self.info.to_json(),
+        )?;
+        util::mount("", &dir, "", NOOPT | libc::MS_REMOUNT | libc::MS_RDONLY)?;
+        Ok(())
+    }
+
+    /// Arguments of --backend bwrap.  As setup_priv(), with the options which
+    /// `bwrap` can express.
+    fn bwrap(&self) -> Result<Bwrap, Error> {
+        let mut bwrap = Bwrap::new();
+        if !self.allownet {
+            bwrap.unshare_net();
+        }
+        bwrap
+            .ro_bind("/", "/")
+            .dev("/dev")
+            .proc("/proc")
+            .tmpfs("/tmp")
+            .tmpfs("/var/tmp");
+
+        let host = Path::new(info::INFO_FILE).parent().unwrap();
+        if host.is_dir() {
+            let source = path!(self.tdir, "info.json");
+            util::write_file(&source, self.info.to_json())?;
+            bwrap
+                .tmpfs(host)
+                .ro_bind(&source, info::INFO_FILE)
+                .remount_ro(host);
+        }
+
+        for (mtype, dir) in &self.mounts {
+            match mtype {
+                MountType::Writable => bwrap.bind(dir, dir),
+                MountType::ReadOnly | MountType::Toolchain => bwrap.ro_bind(dir, dir),
+            };
+        }
+        if let Some(scratch) = &self.scratchdirs {
+            for (path, host) in scratch.dirs() {
+                bwrap.bind(host, path);
+            }
+        }
+        for path in &self.sitemask {
+            bwrap.mask(path);
+        }
+
+        for (name, value) in self.env_vars() {
+            bwrap.setenv(name, &value);
+        }
+        bwrap.chdir(&self.cwd);
+        Ok(bwrap)
+    }
+
+    /// Rules of --backend landlock.  As bwrap(), but without namespaces.  So the host
+    /// `/tmp`, `/proc`, and network, remain visible.  Writes are confined to the writable
+    /// mounts, a private `$TMPDIR`, and a few devices.
+    fn landlock(&self) -> Result<Ruleset, Error> {
+        let mut rules = Ruleset::new();
+        rules.ro("/");
+        for (mtype, dir) in &self.mounts {
+            if matches!(mtype, MountType::Writable) {
+                rules.rw(dir);
+            }
+        }
+        rules.rw(path!(self.tdir, "tmp"));
+        for dev in LANDLOCK_DEVICES {
+            rules.rw(dev);
+        }
+        if !self.allownet {
+            rules.deny_tcp();
+        }
+        Ok(rules)
+    }
+
+    /// Run with --backend landlock
+    fn run_landlock(&self) -> Result<i32, Error> {
+        let tmp = util::mkdir(path!(self.tdir, "tmp"))?;
+        util::chown(&tmp, util::getuid(), util::getgid())?;
+        let prepared = self.landlock()?.create()?;
+        let mut cmd = prepared.command(&self.args);
+        cmd.envs(self.env_vars())
+            .env("TMPDIR", &tmp)
+            .current_dir(&self.cwd);
+        let status = cmd.status()?;
+        Ok(backend::exit_code(status))
+    }
+
+    /// Describe the effective sandbox policy.
+    fn explain(&self) -> Result<String, Error> {
+        let mut out = String::new();
+        let yes = |b: bool| if b { "yes" } else { "no" };
+
+        writeln!(out, "Command: {:?}", self.args)?;
+        if let Some(payload) = &self.payload {
+            writeln!(
+                out,
+                "  executable from stdin, {} bytes, in memory",
+                payload.len()
+            )?;
+        }
+        writeln!(
+            out,
+            "Namespaces: {}",
+            container::namespace_names(self.namespaces()).join(" ")
+        )?;
+        if self.isuser {
+            writeln!(
+                out,
+                "  user namespace maps uid {} gid {} to itself",
+                util::getuid(),
+                util::getgid()
+            )?;
+        }
+        if let Some(map) = &self.rootmap {
+            writeln!(
+                out,
+                "  then a user namespace maps uid {} gid {} to uid {} gid {} of the host",
+                map.uid, map.gid, map.uids.start, map.gids.start
+            )?;
+        }
+        if let Some(account) = &self.runas {
+            let (uid, gid) = self.command_ids();
+            writeln!(
+                out,
+                "  command runs as {} (uid {} gid {} of the host), without supplementary groups",
+                account.name, uid, gid
+            )?;
+        }
+
+        writeln!(out, "Mounts:")?;
+        writeln!(out, "  / recursive bind of host /")?;
+        let mounts = Mounts::current()?;
+        let mut visible = mounts.visible_under("/");
+        visible.retain(|mp| {
+            !fs::SPECIAL_MOUNTS
+                .iter()
+                .any(|(dir, _)| mp.mount_point.starts_with(dir))
+        });
+        if !self.isuser {
+            let removed: Vec<PathBuf> = visible
+                .iter()
+                .filter(|mp| BLACKLIST_FSTYPES.contains(&mp.fstype.as_str()))
+                .map(|mp| mp.mount_point.clone())
+                .collect();
+            for dir in &removed {
+                writeln!(out, "  {} unmounted", dir.display())?;
+            }
+            visible.retain(|mp| !removed.iter().any(|r| mp.mount_point.starts_with(r)));
+        }
+        let readonly = |mp: &MountInfo| mp.has_option(libc::MS_RDONLY);
+        for (mp, recursive) in fs::plan_recursive(&visible, fs::want_readonly, readonly) {
+            writeln!(
+                out,
+                "  {} read-only{}",
+                mp.mount_point.display(),
+                if recursive { ", recursive" } else { "" }
+            )?;
+        }
+        for (dir, fstype) in fs::SPECIAL_MOUNTS {
+            writeln!(out, "  {} new {}", dir, fstype)?;
+        }
+        writeln!(out, "  {} policy summary", info::INFO_FILE)?;
+        if self.maskuffd {
+            writeln!(out, "  /dev/userfaultfd masked")?;
+        }
+        if let Some(hardening) = &self.hardening {
+            for path in &hardening.masked_paths {
+                writeln!(out, "  {} masked", path)?;
+            }
+        }
+        if self.notifyproxy.is_some() {
+            writeln!(out, "  {} notify proxy socket", NOTIFY_SOCKET)?;
+        }
+        if self.wayland.is_some() {
+            if let Ok(host) = wayland::display_path() {
+                writeln!(out, "  {} masked", host.display())?;
+            }
+            writeln!(out, "  {} Wayland security context socket", WAYLAND_SOCKET)?;
+        }
+        if let Some(docs) = &self.documents {
+            writeln!(
+                out,
+                "  {} document portal, granted documents only",
+                docs.mount_point().display()
+            )?;
+            for path in &self.docpaths {
+                writeln!(out, "    {}", path.display())?;
+            }
+        }
+        for secret in &self.secrets {
+            writeln!(out, "  {}/{} secret", SECRETS_DIR, secret.name())?;
+        }
+        for (mtype, dir) in &self.mounts {
+            let mode = match mtype {
+                MountType::ReadOnly => "read-only",
+                MountType::Writable => "writable",
+                MountType::Toolchain => "read-only toolchain",
+            };
+            writeln!(out, "  {} bind {}", dir.display(), mode)?;
+        }
+        for path in &self.scratch {
+            writeln!(out, "  {} scratch, empty and writable", path.display())?;
+        }
+        for path in &self.sitemask {
+            writeln!(out, "  {} masked by site policy", path.display())?;
+        }
+
+        match (&self.netbridge, self.allownet, self.netraw) {
+            (Some(bridge), _, raw) => writeln!(
+                out,
+                "Network: host bridge {}, through a veth pair as {}{}",
+                bridge,
+                BRIDGE_IFNAME,
+                if raw { ", with raw sockets" } else { "" }
+            )?,
+            (None, false, raw) if self.netnat => writeln!(
+                out,
+                "Network: user-mode NAT, as {} on {}.  TCP, and DNS, only{}",
+                nat::ADDRESS,
+                nat::IFNAME,
+                if raw { ", with raw sockets" } else { "" }
+            )?,
+            (None, true, _) => writeln!(out, "Network: host")?,
+            (None, false, false) => writeln!(out, "Network: none.  loopback only")?,
+            (None, false, true) => {
+                writeln!(out, "Network: none.  loopback only, with raw sockets")?
+            }
+        }
+
+        if !self.allownet {
+            let state = if self.netipv6 { "enabled" } else { "disabled" };
+            writeln!(out, "  IPv6 {} on created interfaces", state)?;
+        }
+        if self.dhcp {
+            writeln!(out, "  {} address from DHCP.  not renewed", BRIDGE_IFNAME)?;
+        }
+        for fwd in &self.publish {
+            writeln!(
+                out,
+                "  host {} forwarded to port {}",
+                fwd.host()?,
+                fwd.guest()
+            )?;
+        }
+
+        match self.envpreset {
+            Some(preset) => writeln!(
+                out,
+                "Environment: preset {}.  only {}",
+                preset,
+                preset.allowed().join(" ")
+            )?,
+            None => writeln!(out, "Environment: inherited")?,
+        }
+        writeln!(
+            out,
+            "  terminal {}, color {}{}",
+            match self.termout {
+                term::Output::Inherited => "inherited",
+                term::Output::Pty => "new pty",
+                term::Output::Captured => "output captured",
+            },
+            self.color,
+            if self.termout.colored(self.color) {
+                ""
+            } else {
+                " (stripped)"
+            }
+        )?;
+        for (name, value) in self.env_vars() {
+            writeln!(out, "  {}={}", name, value)?;
+        }
+        if self.notifyproxy.is_none() && self.sdnotify {
+            writeln!(out, "  NOTIFY_SOCKET unset")?;
+        }
+        for name in self.env_removed() {
+            writeln!(out, "  {} unset", name)?;
+        }
+
+        writeln!(
+            out,
+            "On exit: {}",
+            match self.onexit {
+                OnExit::Kill => "kill remaining processes",
+                OnExit::Wait => "wait for remaining processes",
+            }
+        )?;
+
+        match self.snapshot {
+            Some(SnapshotMode::Keep) => writeln!(out, "Snapshot: of $PWD, kept")?,
+            Some(SnapshotMode::Rollback) => {
+                writeln!(out, "Snapshot: of $PWD, restored if the command fails")?
+            }
+            None => (),
+        }
+
+        if let Some(backup) = &self.backup {
+            writeln!(
+                out,
+                "Backup: originals of modified files to {}",
+                backup.dir().display()
+            )?;
+        }
+
+        if let Some(trace) = &self.crashtrace {
+            writeln!(out, "Crash traces: to {}", trace.dir.display())?;
+            writeln!(out, "  debugger: {}", trace.debugger)?;
+        }
+
+        let kept: Vec<String> = self
+            .keep_caps()
+            .iter()
+            .map(|cap| match *cap {
+                util::CAP_NET_RAW => "CAP_NET_RAW".to_string(),
+                cap => cap.to_string(),
+            })
+            .collect();
+        if kept.is_empty() {
+            writeln!(out, "Capabilities: none.  all cleared before exec")?;
+        } else {
+            writeln!(
+                out,
+                "Capabilities: {}.  others cleared before exec",
+                kept.join(", ")
+            )?;
+        }
+        writeln!(
+            out,
+            "  no_new_privs: {}",
+            yes(matches!(&self.hardening, Some(h) if h.no_new_privs))
+        )?;
+        writeln!(
+            out,
+            "  new session keyring: {}",
+            yes(matches!(&self.hardening, Some(h) if h.new_keyring))
+        )?;
+        writeln!(
+            out,
+            "  isolate processes dumpable: {}",
+            yes(!matches!(&self.hardening, Some(h) if h.non_dumpable))
+        )?;
+
+        match &self.hardening {
+            Some(hardening) => writeln!(out, "Seccomp: hardening level {}", hardening.level)?,
+            None => writeln!(out, "Seccomp:")?,
+        }
+        if self.filter.is_empty() {
+            writeln!(out, "  none")?;
+        } else {
+            for line in self.filter.to_string().lines() {
+                writeln!(out, "  {}", line)?;
+            }
+            let abis: Vec<_> = self.filter.abis().iter().map(|a| a.to_string()).collect();
+            writeln!(out, "  for ABIs: {}", abis.join(", "))?;
+            writeln!(out, "  other ABIs -> ENOSYS")?;
+        }
+        Ok(out)
+    }
+
+    /// Make one mount read-only, without `mount_setattr()`
+    fn remount_ro(&self, mp: &MountInfo) -> Result<(), Error> {
+        match util::mount(
+            "",
+            &mp.mount_point,
+            "",
+            mp.options | libc::MS_REMOUNT | libc::MS_RDONLY | libc::MS_BIND,
+        ) {
+            // this mount point may not be accessible to a non-privlaged user.  eg. under /root
+            Err(err) if self.isuser && err.is_io_error(std::io::ErrorKind::PermissionDenied) => {
+                Ok(())
+            }
+            other => other,
+        }?;
+        Ok(())
+    }
 }
 
 impl<'a> ContainerHooks for Isolate<'a> {
-    fn unshare(&self) -> Result<(), Error> {
-        log::debug!("child unshare()");
+    fn namespaces(&self) -> libc::c_int {
         let mut flags =
             libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWCGROUP | libc::CLONE_NEWIPC;
         if !self.allownet {
@@ -41,29 +734,163 @@ impl<'a> ContainerHooks for Isolate<'a> {
         if self.isuser {
             flags |= libc::CLONE_NEWUSER;
         }
-        util::unshare(flags)?;
+        flags
+    }
+
+    fn scratch_dir(&self) -> Option<&Path> {
+        Some(self.tdir)
+    }
+
+    fn watchdog(&self) -> bool {
+        // outlives the caller
+        self.detached.is_none()
+    }
+
+    fn at_start(&self, _ctx: &StageCtx) -> Result<(), Error> {
+        if let Some(scope) = &self.scope {
+            // authenticate to the bus as the calling user
+            let euid = util::geteuid();
+            util::seteuid(util::getuid())?;
+            let ret = scope.start(&[std::process::id()]);
+            util::seteuid(euid)?;
+            ret?;
+        }
         Ok(())
     }
 
-    fn set_id_map(&self, pid: &Proc) -> Result<(), Error> {
+    fn set_id_map(&self, ctx: &StageCtx) -> Result<(), Error> {
         log::debug!("Setup ID mapping");
         // Setup 1-1 mapping
-        if self.isuser {
+        if let (true, Some(pid)) = (self.isuser, ctx.child()) {
             log::debug!("Setup 1-1 UID mapping");
             let uid = util::getuid();
             let gid = util::getgid();
             IdMap::new_uid(pid.id()).add(uid, uid, 1).write()?;
             IdMap::new_gid(pid.id()).add(gid, gid, 1).write()?;
         }
+        if let (Some(bridge), Some(pid)) = (&self.netbridge, ctx.child()) {
+            attach_bridge(bridge, pid.id())?;
+        }
+        if let Some(pid) = ctx.child() {
+            for fwd in &self.publish {
+                let proc = fwd.spawn(pid.id(), self.isuser)?;
+                self.forwarders.borrow_mut().push(proc);
+            }
+        }
+        Ok(())
+    }
+
+    fn started(&self, ctx: &StageCtx, info: &ContainerInfo) -> Result<(), Error> {
+        for (ns, path) in info.namespaces() {
+            log::debug!("Container {ns} namespace {}", path.display());
+        }
+        *self.procfs.borrow_mut() = Some(info.procfs());
+        if matches!(&self.hardening, Some(h) if h.non_dumpable) {
+            // after set_id_map(), which may need to write our /proc/<pid>/ as the user
+            util::set_dumpable(false)?;
+        }
+        if let Some(pidfile) = &self.pidfile {
+            log::debug!("Write PID {} to {}", info.pid(), pidfile.display());
+            std::fs::write(pidfile, format!("{}\n", info.pid()))?;
+        }
+        if let Some(proxy) = &self.notifyproxy {
+            proxy.spawn()?;
+        }
+        if let Some(output) = &self.output {
+            output.start()?;
+        }
+        if let Some(pty) = &self.pty {
+            pty.start()?;
+        }
+        if self.netnat {
+            // sent by setup_priv()
+            match nat::Sockets::recv(ctx.channel().unwrap())? {
+                Some(socks) => socks.spawn(nat::host_nameservers())?,
+                None => return Err("Container did not start NAT".into()),
+            }
+        }
+        if let Some(backup) = &self.backup {
+            // sent by setup_priv()
+            match util::recv_fd(ctx.channel().unwrap())? {
+                Some(fan) => {
+                    backup.spawn(fan)?;
+                }
+                None => return Err("Container did not start backup".into()),
+            }
+        }
+        if let Some(detached) = &self.detached {
+            let mut entry = Entry::new(detached.id.clone(), info.pid());
+            entry.name = self.name.clone();
+            entry.args = self.args.clone();
+            entry.cwd = self.cwd.clone();
+            entry.log = detached.registry.log_file(&detached.id);
+            detached.registry.add(&entry)?;
+            if let Some(mut report) = detached.report.borrow_mut().take() {
+                std::io::Write::write_all(&mut report, b"started\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn ready(&self, ctx: &StageCtx) -> Result<(), Error> {
+        if self.timereport {
+            eprint!("isolate startup\n{}", ctx.stats());
+            if let Some(procfs) = &*self.procfs.borrow() {
+                report_process(procfs);
+            }
+        }
+        if let Some(fd) = self.notifyfd {
+            notify::notify_fd(fd, "READY\n")?;
+        }
+        if self.sdnotify && !notify::sd_notify("READY=1")? {
+            log::warn!("--sd-notify without $NOTIFY_SOCKET");
+        }
         Ok(())
     }
 
-    fn setup_priv(&self) -> Result<(), Error> {
+    fn hook_cmds(&self, stage: Stage) -> &[HookCmd] {
+        self.profile.hooks(stage)
+    }
+
+    fn keep_caps(&self) -> &[u32] {
+        if self.netraw {
+            &[util::CAP_NET_RAW]
+        } else {
+            &[]
+        }
+    }
+
+    fn setup_priv(&self, ctx: &StageCtx) -> Result<(), Error> {
         log::debug!("Privlaged setup");
 
+        let mut resolv = None;
         if !self.allownet {
             net::configure_lo()?;
-            self.bridge.set(Some(net::dummy_bridge()?));
+            // loopback keeps ::1
+            if !self.netipv6 {
+                net::disable_ipv6("default", ctx.namespaces())?;
+            }
+            if self.netbridge.is_some() {
+                // moved in by set_id_map()
+                if !self.netipv6 {
+                    net::disable_ipv6(BRIDGE_IFNAME, ctx.namespaces())?;
+                }
+                net::set_up(BRIDGE_IFNAME)?;
+                if self.dhcp {
+                    let lease = dhcp::discover(BRIDGE_IFNAME, DHCP_TIMEOUT)?;
+                    log::info!("DHCP address {}/{}", lease.address, lease.prefix);
+                    lease.apply(BRIDGE_IFNAME)?;
+                    resolv = lease.resolv_conf();
+                }
+            } else if self.netnat {
+                nat::setup()?.send(ctx.channel().unwrap())?;
+                resolv = Some(nat::resolv_conf());
+            } else {
+                ctx.keep(net::dummy_bridge()?);
+            }
+            if let Err(err) = net::allow_ping(ctx.namespaces()) {
+                log::warn!("Unable to allow ping : {err}");
+            }
         }
 
         // begin by isolating our new mount ns
@@ -72,7 +899,12 @@ impl<'a> ContainerHooks for Isolate<'a> {
         // make /proc for our new PID namespace available early
         util::mount("proc", "/proc", "proc", NOOPT)?;
 
-        let new_root = util::mkdir(path!(self.tdir, "root"))?;
+        if let Some(mount_point) = &self.memory {
+            tempdir::mount_memory(mount_point)?;
+        }
+        let tdir = ctx.scratch_dir().expect("scratch dir");
+        let new_root = util::mkdir(path!(tdir, "root"))?;
+        ctx.set_new_root(&new_root);
         let new_tmp = path!(&new_root, "tmp");
         let new_proc = path!(&new_root, "proc");
         let new_devshm = path!(&new_root, "dev", "shm");
@@ -90,39 +922,55 @@ impl<'a> ContainerHooks for Isolate<'a> {
 
         log::debug!("Fixup non-root mounts");
 
-        for mp in Mounts::current()?.into_iter() {
-            if !mp.mount_point.starts_with(&new_root) {
-                continue;
-            }
-            log::debug!("Visit: {}", &mp);
+        let mut mounts = Mounts::current()?;
+        let mut visible = mounts.visible_under(&new_root);
 
-            // black-list some fs-types
-            if !self.isuser && ["cgroup", "cgroup2", "debugfs"].contains(&mp.fstype.as_str()) {
-                log::debug!("Unmount: {}", mp.mount_point.display());
-                util::umount_lazy(&mp.mount_point)?;
+        // black-list some fs-types
+        if !self.isuser {
+            let mut removed: Vec<PathBuf> = vec![];
+            for mp in &visible {
+                if BLACKLIST_FSTYPES.contains(&mp.fstype.as_str())
+                    && !removed.iter().any(|r| mp.mount_point.starts_with(r))
+                {
+                    log::debug!("Unmount: {}", mp.mount_point.display());
+                    util::umount_lazy(&mp.mount_point)?;
+                    removed.push(mp.mount_point.clone());
+                }
             }
+            // also detaches anything below
+            visible.retain(|mp| !removed.iter().any(|r| mp.mount_point.starts_with(r)));
+        }
 
-            if mp.has_option(libc::MS_RDONLY) {
-                continue;
-            }
+        // try to remount phyisical and various tmpfs-like as read-only
+        let want = fs::want_readonly;
+        let readonly = |mp: &MountInfo| mp.has_option(libc::MS_RDONLY);
+        let mut setattr = true;
 
-            // try to remount phyisical and various tmpfs-like as read-only
-            if mp.source.starts_with("/dev/") || ["tmpfs", "ramfs"].contains(&mp.fstype.as_str()) {
-                log::debug!("Make RO: {}", mp.mount_point.display());
-                match util::mount(
-                    "",
-                    &mp.mount_point,
-                    "",
-                    mp.options | libc::MS_REMOUNT | libc::MS_RDONLY | libc::MS_BIND,
-                ) {
-                    // this mount point may not be accessible to a non-privlaged user.  eg. under /root
-                    Err(err)
-                        if self.isuser && err.is_io_error(std::io::ErrorKind::PermissionDenied) =>
-                    {
-                        Ok(())
+        for (mp, recursive) in fs::plan_recursive(&visible, want, readonly) {
+            log::debug!(
+                "Make RO: {} recursive={}",
+                mp.mount_point.display(),
+                recursive
+            );
+            if setattr {
+                match util::mount_setattr(&mp.mount_point, recursive, util::MOUNT_ATTR_RDONLY, 0) {
+                    Ok(()) => continue,
+                    Err(err) if err.is_io_error(std::io::ErrorKind::Unsupported) => {
+                        log::debug!("No mount_setattr(), fall back to remount");
+                        setattr = false;
                     }
-                    other => other,
-                }?;
+                    // some mount below may not be accessible.  fall back to one at a time
+                    Err(err)
+                        if recursive && err.is_io_error(std::io::ErrorKind::PermissionDenied) => {}
+                    Err(err) => Err(err)?,
+                }
+            }
+            let subtree = visible.iter().filter(|c| {
+                c.mount_point == mp.mount_point
+                    || (recursive && c.mount_point.starts_with(&mp.mount_point) && want(c))
+            });
+            for mp in subtree {
+                self.remount_ro(mp)?;
             }
         }
 
@@ -133,6 +981,76 @@ impl<'a> ContainerHooks for Isolate<'a> {
         util::mount("none", &new_devshm, "tmpfs", NOOPT)?;
         util::mount("none", path!(&new_root, "var", "tmp"), "tmpfs", TMPOPT)?;
 
+        let uffd = path!(&new_root, "dev", "userfaultfd");
+        if self.maskuffd && uffd.exists() {
+            // bypasses vm.unprivileged_userfaultfd
+            util::mount("/dev/null", &uffd, "", libc::MS_BIND)?;
+        }
+
+        if let Some(seed) = self.entropy {
+            entropy::prepare(seed, tdir, &new_root)?;
+        }
+
+        self.write_info(tdir, &new_root)?;
+
+        if let Some(cores) = &self.cores {
+            cores.prepare(&new_root)?;
+        }
+
+        if let Some(hardening) = &self.hardening {
+            hardening.mask(&new_root)?;
+            if hardening.new_keyring {
+                util::join_session_keyring()?;
+            }
+            if hardening.non_dumpable {
+                // inherited by helpers.  eg. --on-exit wait.  Not by the command.
+                util::set_dumpable(false)?;
+            }
+        }
+
+        if let Some(proxy) = &self.notifyproxy {
+            let target = path!(&new_root, NOTIFY_SOCKET.strip_prefix("/").unwrap());
+            util::write_file(&target, "")?;
+            util::mount(proxy.path(), &target, "", libc::MS_BIND)?;
+        }
+
+        if let Some(context) = &self.wayland {
+            // so that clients can not bypass the security context
+            if let Ok(host) = wayland::display_path() {
+                util::mask_path(path!(&new_root, host.strip_prefix("/")?))?;
+            }
+            let target = path!(&new_root, WAYLAND_SOCKET.strip_prefix("/").unwrap());
+            util::write_file(&target, "")?;
+            util::mount(context.path(), &target, "", libc::MS_BIND)?;
+        }
+
+        if let Some(docs) = &self.documents {
+            // hide documents granted to other applications
+            let view = docs.open_app_view()?;
+            let target = path!(&new_root, docs.mount_point().strip_prefix("/")?);
+            let source = format!("/proc/self/fd/{}", view.as_raw_fd());
+            util::mount(source, &target, "", libc::MS_BIND)?;
+        }
+
+        if !self.secrets.is_empty() {
+            let target = mkdir_shadowed(tdir, &new_root, Path::new(SECRETS_DIR))?;
+            let (uid, gid) = self.command_ids();
+            secret::install(&self.secrets, &target, uid, gid)?;
+        }
+
+        if let Some(text) = resolv {
+            // often a symlink.  eg. into /run/systemd/resolve/
+            match Path::new("/etc/resolv.conf").canonicalize() {
+                Ok(host) => {
+                    let source = path!(tdir, "resolv.conf");
+                    util::write_file(&source, text)?;
+                    let target = path!(&new_root, host.strip_prefix("/")?);
+                    util::mount(&source, &target, "", libc::MS_BIND)?;
+                }
+                Err(err) => log::warn!("DHCP name servers not used: /etc/resolv.conf {err}"),
+            }
+        }
+
         // user binds
         for (mtype, dir) in &self.mounts {
             let tdir = path!(&new_root, dir.strip_prefix("/")?);
@@ -146,7 +1064,7 @@ impl<'a> ContainerHooks for Isolate<'a> {
 
                     // now do a re-mount as RO.
                     // must look up mount info each time.
-                    let opts = Mounts::current()?.lookup(&tdir)?.options;
+                    let opts = mounts.refresh(&tdir)?.options;
 
                     util::mount(
                         "",
@@ -166,10 +1084,34 @@ impl<'a> ContainerHooks for Isolate<'a> {
 
                     util::mount(&dir, tdir, "", libc::MS_BIND)?;
                 }
+                MountType::Toolchain => {
+                    if tdir.exists() {
+                        toolchain::bind_ro(&mut mounts, dir, &tdir)?;
+                    } else {
+                        log::warn!("Toolchain {} not visible", dir.display());
+                    }
+                }
+            }
+        }
+
+        // after user binds, so that a scratch directory replaces any
+        if let Some(scratch) = &self.scratchdirs {
+            for (path, host) in scratch.dirs() {
+                let target = path!(&new_root, path.strip_prefix("/")?);
+                if !target.is_dir() {
+                    util::mkdirs(&target)?;
+                }
+                util::mount(host, &target, "", libc::MS_BIND)?;
             }
         }
 
+        // after user binds, which must not expose them
+        for path in &self.sitemask {
+            util::mask_path(path!(&new_root, path.strip_prefix("/")?))?;
+        }
+
         log::debug!("Switch to new root");
+        let start = Instant::now();
 
         util::mkdir(path!(&new_tmp, "oldroot"))?;
 
@@ -183,58 +1125,447 @@ impl<'a> ContainerHooks for Isolate<'a> {
         util::umount_lazy("/tmp/oldroot")?;
         util::rmdir("/tmp/oldroot")?;
 
+        ctx.record(Phase::Pivot, start.elapsed());
         log::debug!("Switched to new root");
 
+        if self.backup.is_some() {
+            // the new bind mounts, so that only access from the container is seen
+            let writable: Vec<&PathBuf> = self
+                .mounts
+                .iter()
+                .filter(|(mtype, _)| matches!(mtype, MountType::Writable))
+                .map(|(_, dir)| dir)
+                .collect();
+            let fan = backup::watch(&writable)?;
+            util::send_fd(ctx.channel().unwrap(), &fan)?;
+        }
+
+        if let Some(map) = self.rootmap {
+            // last, as host root no longer owns anything afterwards
+            container::enter_user_ns(map.uid, map.gid, map.uids, map.gids)?;
+        }
+        if let Some(account) = &self.runas {
+            // already, if the user namespace maps nothing else
+            if (util::getuid(), util::getgid()) != (account.uid, account.gid) {
+                log::debug!("Run as {} ({}:{})", account.name, account.uid, account.gid);
+                util::setgroups(&[])?;
+                util::setgid(account.gid)?;
+                util::setuid(account.uid)?;
+            }
+        }
+
         Ok(())
     }
 
-    fn setup(&self) -> Result<(), Error> {
-        env::set_current_dir(&self.cwd)?;
+    fn seccomp(&self) -> Option<&Filter> {
+        if self.filter.is_empty() {
+            None
+        } else {
+            Some(&self.filter)
+        }
+    }
+
+    fn setup(&self, ctx: &StageCtx) -> Result<(), Error> {
+        if let Err(err) = env::set_current_dir(&self.cwd) {
+            let switched = self.rootmap.is_some() || self.runas.is_some();
+            if switched && err.kind() == std::io::ErrorKind::PermissionDenied {
+                log::error!(
+                    "{} is not accessible to uid {} of the host",
+                    self.cwd.display(),
+                    self.command_ids().0
+                );
+            }
+            return Err(err.into());
+        }
+
+        if let Some(pty) = &self.pty {
+            pty.attach()?;
+        }
+        if let Some(output) = &self.output {
+            output.redirect()?;
+        }
+        if let Some(cores) = &self.cores {
+            cores.apply()?;
+        }
+
+        if matches!(&self.hardening, Some(h) if h.no_new_privs) {
+            util::set_no_new_privs()?;
+        }
 
         log::debug!("EXEC {:?}", &self.args[0..]);
-        env::set_var("VIRTUAL_ENV", "isolated");
+        for (name, value) in self.env_vars() {
+            env::set_var(name, value);
+        }
+        if self.notifyproxy.is_none() && self.sdnotify {
+            // readiness is reported by the sandbox
+            env::remove_var("NOTIFY_SOCKET");
+        }
+        for name in self.env_removed() {
+            env::remove_var(name);
+        }
+        for name in secret::leaked_vars(&self.secrets, env::vars_os()) {
+            log::info!("Remove ${} holding a secret", name.to_string_lossy());
+            env::remove_var(name);
+        }
 
-        util::Exec::new(&self.args[0])?
-            .args(&self.args[0..])?
-            .exec()?;
+        let exec = || {
+            util::cloexec_extra_fds(&self.keepfds)?;
+            let mut cmd = match &self.payload {
+                Some(bytes) => util::Exec::from_memfd(bytes)?,
+                None => util::Exec::new(&self.args[0])?,
+            };
+            if let Some(preset) = self.envpreset {
+                cmd.env_policy(preset);
+                for (name, value) in self.env_vars() {
+                    cmd.env(name, value.as_str())?;
+                }
+            }
+            cmd.args(&self.args[0..])?.exec()
+        };
+        // closed by exec() of the command.  Not held by process 1, or a crash supervisor.
+        let ready = ctx.take_ready();
+        let exec = move || {
+            let ret = exec();
+            if let (Err(_), Some(mut ready)) = (&ret, ready) {
+                let _ = std::io::Write::write_all(&mut ready, b"X");
+            }
+            ret
+        };
+        let run = || match &self.crashtrace {
+            Some(trace) => process::exit(trace.supervise(exec)?),
+            None => exec(),
+        };
+        if self.onexit == OnExit::Wait {
+            // remain as process 1
+            process::exit(util::init_shim(run, self.onexit)?);
+        }
+        run()?;
 
         Ok(())
     }
 }
 
+/// Directory `dir` under `new_root`, created if missing without changing the host.
+/// eg. under `/run`, which is shared with the host, and read-only.
+fn mkdir_shadowed(tdir: &Path, new_root: &Path, dir: &Path) -> Result<PathBuf, Error> {
+    let target = path!(new_root, dir.strip_prefix("/")?);
+    if !target.is_dir() {
+        let parent = target.parent().unwrap();
+        // left empty by each shadow_dir()
+        let scratch = path!(tdir, "shadow");
+        if !scratch.is_dir() {
+            util::mkdir(&scratch)?;
+        }
+        fs::shadow_dir(parent, &scratch)?;
+        util::mkdir(&target)?;
+        util::mount(
+            "",
+            parent,
+            "",
+            libc::MS_REMOUNT | libc::MS_RDONLY | libc::MS_NODEV | libc::MS_NOSUID,
+        )?;
+    }
+    Ok(target)
+}
+
+/// Connect the network namespace of `pid` to the host `bridge` through a new veth pair.
+/// The sandbox end is named `BRIDGE_IFNAME`.
+fn attach_bridge(bridge: &str, pid: libc::pid_t) -> Result<(), Error> {
+    // on the host, so named by the run rather than racing with others for an unused name
+    let id = id::current();
+    let mut veth = net::Veth::create(format!("vsb{id}"), format!("vsbp{id}"))?;
+    let ret = net::IfConfig::new()
+        .and_then(|conf| conf.bridge_add(bridge, veth.name()))
+        .and_then(|_| net::set_up(veth.name()))
+        .and_then(|_| veth.move_peer_to_pid(pid, Some(BRIDGE_IFNAME)));
+    if let Err(err) = ret {
+        if let Err(err) = veth.delete() {
+            log::warn!("Unable to remove veth : {err}");
+        }
+        return Err(err.into());
+    }
+    log::debug!("Attached {} to {}", veth.name(), bridge);
+    Ok(())
+}
+
+/// Listen on the host ports of --publish, as the calling user.  So that a SUID
+/// isolate does not bind privileged ports.
+fn bind_forwards(publish: &[(SocketAddr, u16)]) -> Result<Vec<PortForward>, Error> {
+    let euid = util::geteuid();
+    util::seteuid(util::getuid())?;
+    let ret = publish
+        .iter()
+        .map(|(host, guest)| PortForward::bind(*host, *guest))
+        .collect::<Result<Vec<_>, _>>();
+    util::seteuid(euid)?;
+    Ok(ret?)
+}
+
+/// Directories named by a profile
+fn profile_mounts(profile: &Profile, file: &str) -> Result<Vec<(MountType, PathBuf)>, Error> {
+    let dirs = profile
+        .rw
+        .iter()
+        .map(|d| (MountType::Writable, d))
+        .chain(profile.ro.iter().map(|d| (MountType::ReadOnly, d)));
+    let mut ret = vec![];
+    for (mtype, dir) in dirs {
+        if dir.is_dir() {
+            ret.push((mtype, dir.canonicalize()?));
+        } else {
+            log::warn!("Ignore non-existant directory: {file} {}", dir.display());
+        }
+    }
+    Ok(ret)
+}
+
+/// Resource usage of container process 1, for --time-report
+fn report_process(procfs: &ProcFs) {
+    let rss = procfs.status_field("VmRSS");
+    let (fds, mounts) = (procfs.fds(), procfs.mounts());
+    match (rss, fds, mounts) {
+        (Ok(rss), Ok(fds), Ok(mounts)) => eprint!(
+            "container process {}\n{:>8} {}\n{:>8} {}\n{:>8} {}\n",
+            procfs.pid(),
+            "rss",
+            rss.unwrap_or_default(),
+            "fds",
+            fds.len(),
+            "mounts",
+            mounts.len()
+        ),
+        (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => {
+            log::debug!("Unable to inspect container : {err}")
+        }
+    }
+}
+
+/// Exit when option `arg` is missing its argument
+fn expects(arg: &str) -> ! {
+    ui::fatal(msg::tr(Msg::ExpectsArgument, &[("arg", &arg)]))
+}
+
 fn usage() {
     let execname = env::args().next().unwrap();
     eprint!(
-        "Usage: {execname} [-h] [-N|--net] [-W|--rw <dir>] [-O|--ro <dir>] <cmd> [args ...]
+        "{}",
+        msg::tr(
+            Msg::IsolateUsage,
+            &[
+                ("execname", &execname),
+                ("debugger", &crash::DEFAULT_DEBUGGER),
+                ("prompt", &PROMPT_PREFIX),
+                ("name", &DEFAULT_NAME),
+            ]
+        )
+    );
+}
 
-Execute command in an isolated environment.  By default only $PWD
-will be writable, with no network access allowed.
+/// Run as the calling user, when SUID
+fn as_caller<T, F: FnOnce() -> Result<T, Error>>(f: F) -> Result<T, Error> {
+    let euid = util::geteuid();
+    util::seteuid(util::getuid())?;
+    let ret = f();
+    util::seteuid(euid)?;
+    ret
+}
 
-Options:
-    -h             - Show this message
-    -N --net       - Allow network access
-    -c --no-pwd    - Deny writes to $PWD  (shorthand for \"-O .\")
-    -W --rw <dir>  - Allow writes to part of the directory tree
-    -O --ro <dir>  - Deny writes to part of the directory tree
+/// Reflink $PWD into the state directory
+fn take_snapshot(cwd: &Path) -> Result<Snapshot, Error> {
+    let snap = as_caller(|| Ok(Snapshot::create(cwd, snapshot::state_dir()?)?))?;
+    log::debug!("Snapshot of {} in {}", cwd.display(), snap.path().display());
+    Ok(snap)
+}
+
+/// Continue in a detached process, with output to a log file.
+/// The calling process waits until the container has started, prints the ID, then exits.
+fn detach_now() -> Result<Option<Detached>, Error> {
+    let registry = Registry::new()?;
+    let rx = match util::daemonize()? {
+        Daemon::Caller(rx) => rx,
+        Daemon::Detached(mut report) => {
+            let id = id::current();
+            let log = registry.log_file(&id);
+            // when SUID, create as the calling user
+            let euid = util::geteuid();
+            util::seteuid(util::getuid())?;
+            let file = registry
+                .create()
+                .map_err(Error::from)
+                .and_then(|_| File::create(&log).map_err(Error::from));
+            util::seteuid(euid)?;
+            util::redirect_output(file?)?;
+            std::io::Write::write_all(&mut report, format!("{id}\n").as_bytes())?;
+            return Ok(Some(Detached {
+                registry,
+                id,
+                report: RefCell::new(Some(report)),
+            }));
+        }
+    };
 
-eg. prevent a build from accidentally changing files outside of the build directory.
-  $ isolate make
+    // not until EOF, as processes of the container also hold the write end
+    let mut lines = BufReader::new(rx).lines().map_while(|l| l.ok());
+    match (lines.next(), lines.next().as_deref()) {
+        (Some(id), Some("started")) => {
+            println!("{id}");
+            process::exit(0);
+        }
+        (Some(id), _) => ui::fatal(msg::tr(
+            Msg::DetachFailedLog,
+            &[("log", &registry.log_file(&id).display())],
+        )),
+        (None, _) => ui::fatal(msg::text(Msg::DetachFailed)),
+    }
+}
 
-"
-    );
+/// Run the sandbox once
+fn attempt(cont: &Isolate, cancel: &CancelToken, backend: Backend) -> Result<i32, Error> {
+    let ret = match backend {
+        Backend::Native => runc_cancel(cont, cancel),
+        Backend::Bwrap => cont.bwrap()?.run(&cont.args).map_err(Into::into),
+        Backend::Landlock => cont.run_landlock(),
+    };
+    if let Some(output) = &cont.output {
+        output.finish();
+    }
+    if let Some(pty) = &cont.pty {
+        pty.finish();
+    }
+    ret
 }
 
-fn main() -> Result<(), Error> {
+/// --restart.  Each run in a child process, as runc() drops privileges,
+/// which the next run needs.  Until success, the limit, or a signal.
+fn supervise(
+    cont: &Isolate,
+    cancel: &CancelToken,
+    backend: Backend,
+    policy: Restart,
+) -> Result<i32, Error> {
+    let stop = Arc::new(AtomicBool::new(false));
+    for sig in [libc::SIGINT, libc::SIGTERM, libc::SIGQUIT] {
+        signal_hook::flag::register(sig, stop.clone())?;
+    }
+    let mut restarts = 0;
+    let mut consecutive = 0;
+    loop {
+        if restarts > 0 {
+            // left by the last run.  Mounts were private to it.
+            for name in ["root", "shadow"] {
+                let dir = path!(cont.tdir, name);
+                if dir.exists() {
+                    std::fs::remove_dir_all(&dir)?;
+                }
+            }
+        }
+        let start = Instant::now();
+        let mut child = util::fork::<_, Error>(|| match attempt(cont, cancel, backend) {
+            Ok(code) => process::exit(code),
+            Err(err) => {
+                ui::error(err);
+                process::exit(1)
+            }
+        })?;
+        if let Some(detached) = &cont.detached {
+            // only the first run reports to the caller
+            drop(detached.report.borrow_mut().take());
+        }
+        let code = child.park()?;
+        if stop.load(Ordering::SeqCst) || !policy.again(restarts, code) {
+            return Ok(code);
+        }
+        if start.elapsed() >= Restart::MAX_DELAY {
+            consecutive = 0;
+        }
+        let delay = Restart::delay(consecutive);
+        restarts += 1;
+        consecutive += 1;
+        ui::warning(msg::tr(
+            Msg::Restarting,
+            &[
+                ("code", &code),
+                ("count", &restarts),
+                ("delay", &delay.as_secs()),
+            ],
+        ));
+        let until = Instant::now() + delay;
+        while Instant::now() < until {
+            if stop.load(Ordering::SeqCst) {
+                return Ok(code);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+fn run() -> Result<(), Error> {
+    // before opening any of our own
+    let mut keepfds = util::open_fds()?;
     sandbox::logging::setup().unwrap();
 
     let cwd = env::current_dir()?.canonicalize()?;
     if !cwd.is_absolute() {
-        eprintln!("curdir is not absolute?!?");
+        ui::error(msg::text(Msg::CurdirNotAbsolute));
         process::exit(2);
     }
 
     let mut iargs = env::args().skip(1).peekable();
     let mut allownet = false;
+    let mut netraw = false;
+    let mut netbridge = None;
+    let mut usedhcp = false;
+    let mut keeptmp = false;
+    let mut netipv6 = false;
+    let mut netnat = false;
+    let mut publish = vec![];
+    let mut zerofootprint = false;
+    let mut pidfile = None;
+    let mut notifyfd = None;
+    let mut sdnotify = false;
+    let mut notifyproxy = false;
+    let mut scope = false;
+    let mut slice = None;
+    let mut timereport = false;
+    let mut outputlimit = None;
+    let mut cores = None;
+    let mut crashdir = None;
+    let mut detach = false;
+    let mut debugger = None;
+    let mut onexit = OnExit::Kill;
+    let mut snapmode = None;
+    let mut backupdir = None;
+    let mut scratch = vec![];
+    let mut scratchrm = false;
+    let mut limitaction = LimitAction::Truncate;
+    let mut nouffd = false;
+    let mut nouring = false;
+    let mut entropyseed = None;
+    let mut hardening = None;
+    let mut explainharden = false;
+    let mut explain = false;
+    let mut shell = false;
+    let mut execstdin = false;
+    let mut gui = false;
+    let mut docfiles = vec![];
+    let mut pickdocs = false;
+    let mut secrets = vec![];
+    let mut name = None;
+    let mut prompt = None;
+    let mut virtualenv = None;
+    let mut toolchains = None;
+    let mut profile = Profile::default();
+    let mut haveprofile = false;
+    let mut netset = false;
+    let mut noproject = false;
+    let mut backend = Backend::Native;
+    let mut envpreset = None;
+    let mut usepty = false;
+    let mut asroot = false;
+    let mut runas = None;
+    let mut color = Color::Auto;
+    let mut restart = Restart::Never;
+    let mut version = false;
     let mut mounts = vec![];
 
     // order first, so the any subsequent -O ./whatever take precedence
@@ -246,8 +1577,152 @@ fn main() -> Result<(), Error> {
         }
         let arg = iargs.next().unwrap();
 
-        if arg == "-n" || arg == "-N" || arg == "--net" {
+        if arg == "-p" || arg == "--profile" {
+            let file = iargs.next().unwrap_or_else(|| expects(&arg));
+            profile = as_caller(|| Ok(Profile::load(&file)?))?;
+            haveprofile = true;
+            name = profile.name.clone().or(name);
+            prompt = profile.prompt.clone().or(prompt);
+            virtualenv = profile.virtualenv.or(virtualenv);
+            toolchains = profile.toolchains.clone().or(toolchains);
+            if let Some(net) = profile.net {
+                allownet = net;
+                netset = true;
+            }
+            mounts.extend(profile_mounts(&profile, &file)?);
+        } else if arg == "--net-raw" {
+            netraw = true;
+        } else if arg == "--net-bridge" {
+            netbridge = Some(iargs.next().unwrap_or_else(|| expects(&arg)));
+        } else if arg == "--net-ipv6" {
+            netipv6 = true;
+        } else if arg == "--net-nat" {
+            netnat = true;
+        } else if arg == "--publish" {
+            let spec = iargs.next().unwrap_or_else(|| expects(&arg));
+            publish.push(PortForward::parse_spec(&spec)?);
+        } else if arg == "--dhcp" {
+            usedhcp = true;
+        } else if arg == "--no-project" {
+            noproject = true;
+        } else if arg == "--restart" {
+            restart = iargs.next().unwrap_or_else(|| expects(&arg)).parse()?;
+        } else if arg == "--env-preset" {
+            envpreset = Some(iargs.next().unwrap_or_else(|| expects(&arg)).parse()?);
+        } else if arg == "--pty" {
+            usepty = true;
+        } else if arg == "--as-root" {
+            asroot = true;
+        } else if arg == "--run-as" {
+            runas = Some(iargs.next().unwrap_or_else(|| expects(&arg)));
+        } else if arg == "--color" {
+            color = iargs.next().unwrap_or_else(|| expects(&arg)).parse()?;
+        } else if arg == "--backend" {
+            backend = iargs.next().unwrap_or_else(|| expects(&arg)).parse()?;
+        } else if arg == "-n" || arg == "-N" || arg == "--net" {
             allownet = true;
+            netset = true;
+        } else if arg == "-K" || arg == "--keep-tmp" {
+            keeptmp = true;
+        } else if arg == "--zero-footprint" {
+            zerofootprint = true;
+        } else if arg == "-P" || arg == "--pid-file" {
+            let file: PathBuf = iargs.next().unwrap_or_else(|| expects(&arg)).into();
+            pidfile = Some(cwd.join(file));
+        } else if arg == "--detach" {
+            detach = true;
+        } else if arg == "--notify-fd" {
+            let fd: RawFd = iargs.next().unwrap_or_else(|| expects(&arg)).parse()?;
+            // not to be inherited by the sandboxed command
+            util::set_cloexec(fd, true)?;
+            keepfds.retain(|&keep| keep != fd);
+            notifyfd = Some(fd);
+        } else if arg == "--sd-notify" {
+            sdnotify = true;
+        } else if arg == "--notify-proxy" {
+            notifyproxy = true;
+        } else if arg == "--scope" {
+            scope = true;
+        } else if arg == "--slice" {
+            scope = true;
+            slice = Some(iargs.next().unwrap_or_else(|| expects(&arg)));
+        } else if arg == "--time-report" {
+            timereport = true;
+        } else if arg == "--output-limit" {
+            let limit: u64 = iargs.next().unwrap_or_else(|| expects(&arg)).parse()?;
+            outputlimit = Some(limit);
+        } else if arg == "--on-output-limit" {
+            limitaction = iargs.next().unwrap_or_else(|| expects(&arg)).parse()?;
+        } else if arg == "--cores" {
+            let policy: CorePolicy = iargs.next().unwrap_or_else(|| expects(&arg)).parse()?;
+            cores = Some(match policy {
+                CorePolicy::Dir(dir) if dir.is_dir() => CorePolicy::Dir(dir.canonicalize()?),
+                CorePolicy::Dir(dir) => {
+                    ui::fatal(msg::tr(Msg::CoresDirMissing, &[("dir", &dir.display())]))
+                }
+                other => other,
+            });
+        } else if arg == "--crash-trace" {
+            let dir = PathBuf::from(iargs.next().unwrap_or_else(|| expects(&arg)));
+            if !dir.is_dir() {
+                ui::fatal(msg::tr(
+                    Msg::CrashTraceDirMissing,
+                    &[("dir", &dir.display())],
+                ));
+            }
+            crashdir = Some(dir.canonicalize()?);
+        } else if arg == "--crash-debugger" {
+            debugger = Some(iargs.next().unwrap_or_else(|| expects(&arg)));
+        } else if arg == "--on-exit" {
+            onexit = iargs.next().unwrap_or_else(|| expects(&arg)).parse()?;
+        } else if arg == "--snapshot-before" {
+            snapmode = snapmode.or(Some(SnapshotMode::Keep));
+        } else if arg == "--rollback-on-failure" {
+            snapmode = Some(SnapshotMode::Rollback);
+        } else if arg == "--scratch" {
+            scratch.push(PathBuf::from(iargs.next().unwrap_or_else(|| expects(&arg))));
+        } else if arg == "--scratch-rm" {
+            scratchrm = true;
+        } else if arg == "--backup-dir" {
+            let dir = PathBuf::from(iargs.next().unwrap_or_else(|| expects(&arg)));
+            as_caller(|| Ok(util::mkdirs(&dir)?))?;
+            backupdir = Some(dir.canonicalize()?);
+        } else if arg == "--harden" {
+            let level: Level = iargs.next().unwrap_or_else(|| expects(&arg)).parse()?;
+            hardening = Some(Hardening::new(level));
+        } else if arg == "--explain-hardening" {
+            explainharden = true;
+        } else if arg == "--name" {
+            name = Some(iargs.next().unwrap_or_else(|| expects(&arg)));
+        } else if arg == "--prompt" {
+            prompt = Some(iargs.next().unwrap_or_else(|| expects(&arg)));
+        } else if arg == "--toolchains" {
+            toolchains = Some(Toolchains::Detect);
+        } else if arg == "--virtualenv-compat" {
+            virtualenv = Some(true);
+        } else if arg == "--shell" {
+            shell = true;
+        } else if arg == "--exec-stdin" {
+            execstdin = true;
+        } else if arg == "--gui" {
+            gui = true;
+        } else if arg == "--document" {
+            let file = PathBuf::from(iargs.next().unwrap_or_else(|| expects(&arg)));
+            docfiles.push(cwd.join(file));
+        } else if arg == "--pick-documents" {
+            pickdocs = true;
+        } else if arg == "--secret" {
+            let spec = iargs.next().unwrap_or_else(|| expects(&arg));
+            secrets.push(as_caller(|| Ok(Secret::load(&spec)?))?);
+        } else if arg == "--explain" {
+            explain = true;
+        } else if arg == "--no-userfaultfd" {
+            nouffd = true;
+        } else if arg == "--no-io-uring" {
+            nouring = true;
+        } else if arg == "--entropy-seed" {
+            let seed: u64 = iargs.next().unwrap_or_else(|| expects(&arg)).parse()?;
+            entropyseed = Some(seed);
         } else if arg == "-c" || arg == "--no-pwd" {
             mounts.push((MountType::ReadOnly, cwd.clone()));
         } else if arg == "-W" || arg == "--rw" || arg == "-O" || arg == "--ro" {
@@ -257,27 +1732,77 @@ fn main() -> Result<(), Error> {
                 MountType::Writable
             };
 
-            let dir: PathBuf = iargs
-                .next()
-                .expect(&format!("{arg} expects argument"))
-                .into();
+            let dir: PathBuf = iargs.next().unwrap_or_else(|| expects(&arg)).into();
             if dir.is_dir() {
                 mounts.push((mtype, dir.canonicalize()?));
             } else {
                 log::warn!("Ignore non-existant directory: {arg} {}", dir.display());
             }
+        } else if let Some(change) = ui::verbosity_arg(&arg) {
+            ui::set_verbosity(ui::verbosity() + change);
+        } else if arg == "--version" {
+            version = true;
         } else if arg == "-h" {
             usage();
             return Ok(());
         } else {
             usage();
-            eprintln!("Unknown argument: {arg}");
-            process::exit(1);
+            ui::fatal(msg::tr(Msg::UnknownArgument, &[("arg", &arg)]));
+        }
+    }
+
+    if version {
+        ui::version("isolate", ui::verbosity() > 0);
+        return Ok(());
+    }
+
+    // project profile has the lowest precedence
+    let project = if noproject {
+        None
+    } else {
+        // .sandbox.toml may be a symlink, to anywhere
+        as_caller(|| Ok(project::discover(&cwd)))?
+    };
+    if let Some(file) = project {
+        let allowed = as_caller(|| match AllowList::new()?.read_allowed(&file)? {
+            Some(text) => Ok(Some(Profile::parse(&text, &file)?)),
+            None => Ok(None),
+        })?;
+        match allowed {
+            Some(proj) => {
+                log::debug!("Using {}", file.display());
+                if !netset {
+                    if let Some(net) = proj.net {
+                        allownet = net;
+                    }
+                }
+                name = name.or_else(|| proj.name.clone());
+                prompt = prompt.or_else(|| proj.prompt.clone());
+                virtualenv = virtualenv.or(proj.virtualenv);
+                toolchains = toolchains.or_else(|| proj.toolchains.clone());
+                let pmounts = profile_mounts(&proj, &file.to_string_lossy())?;
+                // after $PWD, before anything from the command line
+                mounts.splice(1..1, pmounts);
+                if !haveprofile {
+                    profile = proj;
+                }
+            }
+            None => log::warn!(
+                "Ignoring {}.  Not allowed.  To use, run: sandbox allow {}",
+                file.display(),
+                file.display()
+            ),
         }
     }
 
+    if let Some(toolchains) = toolchains {
+        // lowest precedence
+        let dirs = toolchains.resolve();
+        mounts.splice(0..0, dirs.into_iter().map(|d| (MountType::Toolchain, d)));
+    }
+
     // remove duplicates in favor of last
-    let mounts = {
+    let mut mounts = {
         let mut mseen = HashSet::new();
         let mut munique = vec![];
         for (mtype, dir) in mounts.into_iter() {
@@ -290,27 +1815,658 @@ fn main() -> Result<(), Error> {
         munique
     };
 
-    let rawargs = iargs.collect::<Vec<String>>();
+    // site policy is applied last, so that it can not be relaxed
+    let site = SitePolicy::system()?;
+    if site.deny_net && (allownet || netnat) {
+        log::warn!("Network access denied by site policy");
+        allownet = false;
+        netnat = false;
+    }
+    if site.deny_ipv6 && netipv6 {
+        log::warn!("IPv6 denied by site policy");
+        netipv6 = false;
+    }
+    if let Some(level) = site.harden {
+        match &hardening {
+            Some(hardening) if hardening.level >= level => (),
+            _ => hardening = Some(Hardening::new(level)),
+        }
+    }
+    for dir in &site.readonly {
+        let exposed = mounts.iter().any(|(mtype, d)| {
+            matches!(mtype, MountType::Writable) && (d.starts_with(dir) || dir.starts_with(d))
+        });
+        if exposed && dir.exists() {
+            mounts.push((MountType::ReadOnly, dir.clone()));
+        }
+    }
+
+    if explainharden {
+        let hardening = hardening.unwrap_or_else(|| Hardening::new(Level::Default));
+        print!("{hardening}");
+        return Ok(());
+    }
+
+    let mut rawargs = iargs.collect::<Vec<String>>();
+
+    if shell {
+        if !rawargs.is_empty() {
+            usage();
+            ui::fatal(msg::text(Msg::ShellWithCommand));
+        }
+        rawargs.push(env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string()));
+    }
+
+    let payload = if execstdin {
+        if shell {
+            ui::fatal(msg::text(Msg::ExecStdinWithShell));
+        }
+        let mut payload = vec![];
+        std::io::stdin().read_to_end(&mut payload)?;
+        if payload.is_empty() {
+            ui::fatal(msg::text(Msg::NoPayload));
+        }
+        if rawargs.is_empty() {
+            rawargs.push("payload".to_string());
+        }
+        Some(payload)
+    } else {
+        None
+    };
 
-    if rawargs.len() == 0 {
+    if rawargs.is_empty() && !explain {
         usage();
         process::exit(1);
     }
 
-    let tdir = TempDir::new()?;
-    util::chown(tdir.path(), util::getuid(), util::getgid())?;
+    if netraw && allownet {
+        // never raw sockets on the host network
+        ui::fatal(msg::text(Msg::NetRawWithNet));
+    }
+    if let Some(bridge) = &netbridge {
+        if allownet {
+            ui::fatal(msg::text(Msg::NetBridgeWithNet));
+        }
+        if !net::interfaces()?.iter().any(|iface| &iface.name == bridge) {
+            ui::fatal(msg::tr(Msg::NoSuchBridge, &[("name", bridge)]));
+        }
+    } else if usedhcp {
+        ui::fatal(msg::text(Msg::DhcpWithoutBridge));
+    } else if netipv6 {
+        ui::fatal(msg::text(Msg::Ipv6WithoutBridge));
+    }
+    if netnat {
+        let conflict = [(allownet, "--net"), (netbridge.is_some(), "--net-bridge")];
+        if let Some((_, opt)) = conflict.iter().find(|(given, _)| *given) {
+            ui::fatal(msg::tr(Msg::NetNatWith, &[("option", opt)]));
+        }
+    }
+    if !publish.is_empty() && allownet {
+        // nothing to forward into
+        ui::fatal(msg::text(Msg::PublishWithNet));
+    }
+    if usepty {
+        let conflict = [
+            (outputlimit.is_some(), "--output-limit"),
+            (detach, "--detach"),
+        ];
+        if let Some((_, opt)) = conflict.iter().find(|(given, _)| *given) {
+            ui::fatal(msg::tr(Msg::PtyWith, &[("option", opt)]));
+        }
+    }
+
+    if backend != Backend::Native {
+        let native = [
+            (netraw, "--net-raw"),
+            (netbridge.is_some(), "--net-bridge"),
+            (netnat, "--net-nat"),
+            (!publish.is_empty(), "--publish"),
+            (pidfile.is_some(), "--pid-file"),
+            (detach, "--detach"),
+            (notifyfd.is_some(), "--notify-fd"),
+            (sdnotify, "--sd-notify"),
+            (notifyproxy, "--notify-proxy"),
+            (scope, "--scope"),
+            (timereport, "--time-report"),
+            (outputlimit.is_some(), "--output-limit"),
+            (cores.is_some(), "--cores"),
+            (crashdir.is_some(), "--crash-trace"),
+            (onexit != OnExit::Kill, "--on-exit"),
+            (backupdir.is_some(), "--backup-dir"),
+            (hardening.is_some(), "--harden"),
+            (nouffd, "--no-userfaultfd"),
+            (nouring, "--no-io-uring"),
+            (entropyseed.is_some(), "--entropy-seed"),
+            (execstdin, "--exec-stdin"),
+            (gui, "--gui"),
+            (pickdocs || !docfiles.is_empty(), "--document"),
+            (!secrets.is_empty(), "--secret"),
+            (envpreset.is_some(), "--env-preset"),
+            (usepty, "--pty"),
+            (runas.is_some(), "--run-as"),
+        ];
+        if let Some((_, opt)) = native.iter().find(|(given, _)| *given) {
+            ui::fatal(msg::tr(
+                Msg::BackendUnsupported,
+                &[("option", opt), ("backend", &backend)],
+            ));
+        }
+        if backend == Backend::Landlock {
+            if !scratch.is_empty() {
+                ui::fatal(msg::tr(
+                    Msg::BackendUnsupported,
+                    &[("option", &"--scratch"), ("backend", &backend)],
+                ));
+            }
+            if !site.mask.is_empty() {
+                ui::fatal(msg::text(Msg::LandlockSiteMask));
+            }
+            match landlock::abi() {
+                Ok(abi) if abi < 4 && !allownet => {
+                    ui::fatal(msg::tr(Msg::LandlockNoNetwork, &[("abi", &abi)]))
+                }
+                Ok(abi) => log::debug!("Landlock ABI {}", abi),
+                Err(err) => ui::fatal(msg::tr(Msg::LandlockUnavailable, &[("err", &err)])),
+            }
+        }
+        if backend == Backend::Bwrap && backend::which(backend::BWRAP).is_none() {
+            ui::fatal(msg::tr(Msg::BackendMissing, &[("cmd", &backend::BWRAP)]));
+        }
+    }
+
+    if zerofootprint {
+        let host = [
+            (keeptmp, "--keep-tmp"),
+            (pidfile.is_some(), "--pid-file"),
+            (detach, "--detach"),
+            (scope, "--scope"),
+            (notifyproxy, "--notify-proxy"),
+            (crashdir.is_some(), "--crash-trace"),
+            (matches!(cores, Some(CorePolicy::Dir(_))), "--cores dir:"),
+            (snapmode.is_some(), "--snapshot-before"),
+            (backupdir.is_some(), "--backup-dir"),
+            (!scratch.is_empty(), "--scratch"),
+            (gui, "--gui"),
+            (pickdocs || !docfiles.is_empty(), "--document"),
+            (backend != Backend::Native, "--backend"),
+        ];
+        if let Some((_, opt)) = host.iter().find(|(given, _)| *given) {
+            ui::fatal(msg::tr(Msg::FootprintUnsupported, &[("option", opt)]));
+        }
+    }
+
+    if restart != Restart::Never && backupdir.is_some() {
+        // copies are collected by the process which started the watch
+        ui::fatal(msg::tr(
+            Msg::RestartUnsupported,
+            &[("option", &"--backup-dir")],
+        ));
+    }
+
+    // visible to every process through /proc/<pid>/cmdline
+    for secret in &secrets {
+        if rawargs.iter().any(|arg| secret.appears_in(arg.as_bytes())) {
+            ui::fatal(msg::tr(Msg::SecretInArgs, &[("name", &secret.name())]));
+        }
+    }
+
+    // when SUID, apply any limits set by the administrator.  Held until exit.
+    let _slot = if util::geteuid() == 0 && util::getuid() != 0 && !rawargs.is_empty() {
+        match Limits::system()? {
+            Some(limits) if !limits.is_empty() => {
+                if zerofootprint {
+                    // admission is counted in limits::STATE_DIR
+                    ui::fatal(msg::text(Msg::FootprintLimits));
+                }
+                match limits.admit(limits::STATE_DIR, util::getuid())? {
+                    Admit::Allowed(slot) => Some(slot),
+                    Admit::TooMany(max) => {
+                        ui::fatal(msg::tr(Msg::TooManySandboxes, &[("max", &max)]))
+                    }
+                    Admit::TooFrequent(max, period) => ui::fatal(msg::tr(
+                        Msg::TooFrequentSandboxes,
+                        &[("max", &max), ("period", &period)],
+                    )),
+                }
+            }
+            _ => None,
+        }
+    } else {
+        None
+    };
+
+    if snapmode.is_some() {
+        let writable = mounts.iter().find(|(_, d)| d == &cwd);
+        if !matches!(writable, Some((MountType::Writable, _))) {
+            ui::fatal(msg::text(Msg::SnapshotNotWritable));
+        }
+    }
+    for path in &scratch {
+        if !path.is_absolute() {
+            ui::fatal(msg::tr(
+                Msg::ScratchNotAbsolute,
+                &[("path", &path.display())],
+            ));
+        }
+        // over an existing directory, or created in a tmpfs of the sandbox
+        if !path.is_dir() && !path.starts_with("/tmp") && !path.starts_with("/var/tmp") {
+            ui::fatal(msg::tr(Msg::ScratchMissing, &[("path", &path.display())]));
+        }
+    }
+    // before detaching, so that any error is seen
+    let snapshot = match snapmode {
+        Some(_) if !rawargs.is_empty() => Some(take_snapshot(&cwd)?),
+        _ => None,
+    };
+    if let (Some(SnapshotMode::Keep), Some(snap)) = (snapmode, &snapshot) {
+        ui::info(msg::tr(
+            Msg::SnapshotKept,
+            &[("dir", &cwd.display()), ("path", &snap.path().display())],
+        ));
+    }
+
+    let termout = if usepty {
+        term::Output::Pty
+    } else if outputlimit.is_some() || detach {
+        term::Output::Captured
+    } else {
+        term::Output::Inherited
+    };
+    // before output is redirected by --detach
+    let termenv = termout.env(
+        color,
+        term::window_size(std::io::stdout()).or_else(|| term::window_size(std::io::stderr())),
+        env::var("TERM").ok().as_deref(),
+    );
+
+    let detached = if detach && !rawargs.is_empty() {
+        if shell {
+            ui::fatal(msg::text(Msg::DetachWithShell));
+        }
+        detach_now()?
+    } else {
+        None
+    };
+
+    // leftovers of runs which were killed.  Only those of the calling user.
+    if !zerofootprint {
+        match as_caller(|| Ok(gc::Collector::new().quick()?)) {
+            Ok((items, _live)) => {
+                for item in items {
+                    log::debug!("Removed stale {}", item);
+                }
+            }
+            Err(err) => log::debug!("gc : {}", err),
+        }
+    }
+
+    let location = if zerofootprint {
+        Location::Memory
+    } else {
+        tempdir::probe()
+    };
+    let (tdir, memory) = match location {
+        Location::Dir(dir) => {
+            let mut tdir = TempDir::new_in(dir)?;
+            if keeptmp {
+                tdir.keep();
+            }
+            util::chown(tdir.path(), util::getuid(), util::getgid())?;
+            (Some(tdir), None)
+        }
+        Location::Memory => {
+            // nothing on the host, so nothing to share with it
+            let host = [
+                (keeptmp, "--keep-tmp"),
+                (notifyproxy, "--notify-proxy"),
+                (gui, "--gui"),
+                (backend != Backend::Native, "--backend"),
+            ];
+            if let Some((_, opt)) = host.iter().find(|(given, _)| *given) {
+                ui::fatal(msg::tr(Msg::TempDirInMemory, &[("option", opt)]));
+            }
+            // not covering anything to be bound into the sandbox
+            let mount_point = MEMORY_MOUNTS
+                .iter()
+                .map(Path::new)
+                .find(|mp| mp.is_dir() && !mounts.iter().any(|(_, dir)| dir.starts_with(mp)))
+                .unwrap_or_else(|| ui::fatal(msg::text(Msg::NoTempDir)));
+            log::debug!("No usable host directory.  Temp dir in memory");
+            (None, Some(mount_point.to_path_buf()))
+        }
+    };
+    let tdir_path = match (&tdir, &memory) {
+        (Some(tdir), _) => tdir.path().to_path_buf(),
+        (None, Some(mount_point)) => tempdir::memory_path(mount_point),
+        (None, None) => unreachable!(),
+    };
+
+    let notifyproxy = if notifyproxy {
+        let proxy = NotifyProxy::bind(path!(&tdir_path, "notify"))?;
+        if let Some(proxy) = &proxy {
+            util::chown(proxy.path(), util::getuid(), util::getgid())?;
+        } else {
+            log::warn!("--notify-proxy without $NOTIFY_SOCKET");
+        }
+        proxy
+    } else {
+        None
+    };
+
+    let name = name.unwrap_or_else(|| DEFAULT_NAME.to_string());
+    let wayland = if gui && !rawargs.is_empty() {
+        if env::var_os("WAYLAND_DISPLAY").is_none() {
+            ui::fatal(msg::text(Msg::GuiNoWayland));
+        }
+        let path = path!(&tdir_path, "wayland");
+        let instance = id::current();
+        Some(as_caller(|| {
+            Ok(SecurityContext::new(path, &name, &instance)?)
+        })?)
+    } else {
+        None
+    };
 
-    let cont = Isolate {
-        isuser: !util::Cap::current()?.effective(util::CAP_SYS_ADMIN),
+    let (documents, docpaths) = if (pickdocs || !docfiles.is_empty()) && !rawargs.is_empty() {
+        as_caller(|| {
+            let mut docs = Documents::connect(portal::app_id(&name))?;
+            if pickdocs {
+                let title = msg::tr(Msg::PickDocumentsTitle, &[("name", &name)]);
+                let picked = docs.pick(&title)?;
+                if picked.is_empty() {
+                    ui::fatal(msg::text(Msg::NoDocuments));
+                }
+                docfiles.extend(picked);
+            }
+            let mut paths = vec![];
+            for file in &docfiles {
+                paths.push(docs.add(file)?);
+            }
+            Ok((Some(docs), paths))
+        })?
+    } else {
+        (None, vec![])
+    };
+
+    let scope = if scope {
+        let mut unit = Scope::new(format!("isolate-{}.scope", id::current()));
+        unit.description(format!("isolate {}", rawargs.join(" ")))
+            .user(util::getuid() != 0);
+        if let Some(slice) = slice {
+            unit.slice(slice);
+        }
+        Some(unit)
+    } else {
+        None
+    };
+
+    // these sysctls are not per namespace.  Only add filter rules when the host allows.
+    let mut filter = Filter::new();
+    if nouffd && !seccomp::host_blocks_userfaultfd() {
+        seccomp::deny_userfaultfd(&mut filter);
+    }
+    if nouring && !seccomp::host_blocks_io_uring() {
+        seccomp::deny_io_uring(&mut filter);
+    }
+    if entropyseed.is_some() {
+        log::warn!("--entropy-seed makes randomness in the sandbox predictable.  For testing only");
+        entropy::deny_getrandom(&mut filter);
+    }
+    if let Some(hardening) = &mut hardening {
+        if crashdir.is_some() && !hardening.ptrace_requests.is_empty() {
+            // the crash supervisor, and debugger, attach
+            log::warn!("--crash-trace allows ptrace() attach");
+            hardening.ptrace_requests.clear();
+        }
+        filter.extend(&hardening.filter());
+    }
+    if !filter.is_empty() && !seccomp::SUPPORTED {
+        let given = [
+            (nouffd, "--no-userfaultfd"),
+            (nouring, "--no-io-uring"),
+            (entropyseed.is_some(), "--entropy-seed"),
+            (hardening.is_some(), "--harden"),
+        ];
+        let opt = given
+            .iter()
+            .find(|(given, _)| *given)
+            .map_or("--harden", |(_, opt)| opt);
+        ui::fatal(msg::tr(
+            Msg::SeccompUnsupported,
+            &[("option", &opt), ("arch", &seccomp::Arch::NATIVE)],
+        ));
+    }
+
+    let crashtrace = match crashdir {
+        Some(dir) => {
+            if filter.syscalls().contains(&libc::SYS_ptrace) {
+                ui::fatal(msg::text(Msg::CrashTraceNoPtrace));
+            }
+            // the innermost mount decides
+            let writable = mounts
+                .iter()
+                .filter(|(_, d)| dir.starts_with(d))
+                .max_by_key(|(_, d)| d.as_os_str().len());
+            if !matches!(writable, Some((MountType::Writable, _))) {
+                log::warn!("--crash-trace {} is not writable", dir.display());
+            }
+            let trace = CrashTrace::new(dir);
+            Some(match debugger {
+                Some(cmd) => trace.debugger(cmd),
+                None => trace,
+            })
+        }
+        None => None,
+    };
+
+    let cancel = CancelToken::new();
+    let output = match outputlimit {
+        Some(limit) => {
+            let mut proxy = OutputProxy::new(limit, limitaction, Some(cancel.clone()))?;
+            proxy.strip_color(!termout.colored(color));
+            Some(proxy)
+        }
+        None => None,
+    };
+    let pty = if usepty { Some(Pty::open()?) } else { None };
+
+    let isuser = !util::Cap::current()?.effective(util::CAP_SYS_ADMIN);
+    let runas = runas.map(|name| {
+        if util::getuid() != 0 {
+            ui::fatal(msg::text(Msg::RunAsNeedsRoot));
+        }
+        util::account(&name)
+            .unwrap_or_else(|| ui::fatal(msg::tr(Msg::NoSuchAccount, &[("name", &name)])))
+    });
+    let rootmap = if !isuser && util::getuid() == 0 && !asroot && backend == Backend::Native {
+        let uids = util::subids("/etc/subuid", "root", 0);
+        let gids = util::subids("/etc/subgid", "root", 0);
+        Some(match (uids, gids, &runas) {
+            (Some(uids), Some(gids), _) => RootMap {
+                uid: 0,
+                gid: 0,
+                uids,
+                gids,
+            },
+            // only the IDs of the command are mapped
+            (_, _, Some(account)) => RootMap {
+                uid: account.uid,
+                gid: account.gid,
+                uids: util::NOBODY,
+                gids: util::NOBODY,
+            },
+            _ => RootMap {
+                uid: 0,
+                gid: 0,
+                uids: util::NOBODY,
+                gids: util::NOBODY,
+            },
+        })
+    } else {
+        None
+    };
+    if let (Some(map), Some(account)) = (&rootmap, &runas) {
+        if account.uid - map.uid >= map.uids.count || account.gid - map.gid >= map.gids.count {
+            ui::fatal(msg::tr(Msg::RunAsNotMapped, &[("name", &account.name)]));
+        }
+    }
+    if netraw && rootmap.is_some() {
+        // CAP_NET_RAW of a nested user namespace does not apply to the network namespace
+        ui::fatal(msg::text(Msg::NetRawAsRoot));
+    }
+    if netraw && runas.is_some() {
+        // capabilities are lost with uid 0
+        ui::fatal(msg::text(Msg::RunAsWithNetRaw));
+    }
+
+    let mut cont = Isolate {
+        isuser,
+        rootmap,
+        runas,
         allownet,
+        netraw,
+        netipv6,
+        netnat,
+        publish: bind_forwards(&publish)?,
+        forwarders: RefCell::new(vec![]),
+        netbridge,
+        dhcp: usedhcp,
         args: rawargs,
-        tdir: tdir.path(),
+        shell,
+        payload,
+        name,
+        prompt,
+        virtualenv: virtualenv.unwrap_or(false),
+        tdir: &tdir_path,
+        memory,
         mounts,
         cwd: env::current_dir()?,
-        bridge: std::cell::Cell::new(None),
+        pidfile,
+        notifyfd,
+        keepfds,
+        sdnotify,
+        notifyproxy,
+        wayland,
+        documents,
+        docpaths,
+        secrets,
+        envpreset,
+        sitemask: site.mask,
+        scope,
+        profile,
+        timereport,
+        procfs: RefCell::new(None),
+        output,
+        pty,
+        termout,
+        color,
+        termenv,
+        cores,
+        crashtrace,
+        onexit,
+        snapshot: snapmode,
+        scratch,
+        scratchdirs: None,
+        backup: None,
+        detached,
+        maskuffd: nouffd,
+        entropy: entropyseed,
+        hardening,
+        filter,
+        info: Default::default(),
     };
+    cont.info = cont.summary();
+    if cont.netbridge.is_some() && cont.isuser {
+        // veth created in the host network namespace
+        ui::fatal(msg::text(Msg::NetBridgeNeedsRoot));
+    }
+    if let Some(dir) = backupdir {
+        if cont.isuser {
+            // fanotify permission events
+            ui::fatal(msg::text(Msg::BackupNeedsRoot));
+        }
+        let writable = cont.mounts.iter().filter_map(|(mtype, dir)| match mtype {
+            MountType::Writable => Some(dir.clone()),
+            _ => None,
+        });
+        cont.backup = Some(Backup::new(dir, writable.collect()));
+    }
+
+    if explain {
+        let text = cont.explain()?;
+        if cont.args.is_empty() {
+            print!("{text}");
+            return Ok(());
+        }
+        eprint!("{text}");
+    }
+
+    if !cont.scratch.is_empty() {
+        let paths = &cont.scratch;
+        cont.scratchdirs = Some(as_caller(|| {
+            Ok(Scratch::create(snapshot::state_dir()?, paths)?)
+        })?);
+    }
 
-    let ret = runc(&cont);
+    let ret = match restart {
+        Restart::Never => attempt(&cont, &cancel, backend),
+        policy => supervise(&cont, &cancel, backend, policy),
+    };
+    if let Some(pidfile) = &cont.pidfile {
+        if let Err(err) = std::fs::remove_file(pidfile) {
+            log::debug!("Unable to remove {} : {err}", pidfile.display());
+        }
+    }
+    if let Some(detached) = &cont.detached {
+        if let Err(err) = detached.registry.remove(&detached.id) {
+            log::warn!("{err}");
+        }
+    }
+    if let Some(backup) = &cont.backup {
+        let changed = as_caller(|| Ok(backup.finish()?))?;
+        if !changed.is_empty() {
+            ui::info(msg::tr(
+                Msg::BackupSaved,
+                &[("count", &changed.len()), ("dir", &backup.dir().display())],
+            ));
+        }
+    }
+    if let Some(scratch) = cont.scratchdirs.take() {
+        if scratchrm {
+            as_caller(|| Ok(scratch.remove()?))?;
+        } else {
+            for (path, host) in scratch.dirs() {
+                ui::info(msg::tr(
+                    Msg::ScratchKept,
+                    &[("path", &path.display()), ("host", &host.display())],
+                ));
+            }
+        }
+    }
     drop(tdir);
+    if let (Some(SnapshotMode::Rollback), Some(snap)) = (snapmode, snapshot) {
+        if matches!(ret, Ok(0)) {
+            as_caller(|| Ok(snap.remove()?))?;
+        } else {
+            match as_caller(|| Ok(snap.restore()?)) {
+                Ok(()) => {
+                    ui::warning(msg::tr(Msg::RolledBack, &[("dir", &cwd.display())]));
+                    as_caller(|| Ok(snap.remove()?))?;
+                }
+                Err(err) => ui::error(msg::tr(
+                    Msg::RollbackFailed,
+                    &[
+                        ("dir", &cwd.display()),
+                        ("path", &snap.path().display()),
+                        ("err", &err),
+                    ],
+                )),
+            }
+        }
+    }
     process::exit(ret?);
 }
+
+fn main() {
+    ui::main(run)
+}
@@ -1,24 +1,26 @@
+use std::os::unix::io::RawFd;
 use std::{env, process};
 
 use libc;
 use log::debug;
 
-use sandbox::container::ContainerHooks;
-use sandbox::{net, util};
+use sandbox::container::{ContainerHooks, StageCtx};
+use sandbox::msg::{self, Msg};
+use sandbox::{net, ui, util};
 use sandbox::{runc, Error};
 
 struct NoNet {
     args: Vec<String>,
+    /// Inherited from our caller
+    keepfds: Vec<RawFd>,
 }
 
 impl ContainerHooks for NoNet {
-    fn unshare(&self) -> Result<(), Error> {
-        debug!("child unshare()");
-        util::unshare(libc::CLONE_NEWNET)?;
-        Ok(())
+    fn namespaces(&self) -> libc::c_int {
+        libc::CLONE_NEWNET
     }
 
-    fn setup_priv(&self) -> Result<(), Error> {
+    fn setup_priv(&self, _ctx: &StageCtx) -> Result<(), Error> {
         // setup loopback only
 
         net::configure_lo()?;
@@ -26,9 +28,10 @@ impl ContainerHooks for NoNet {
         Ok(())
     }
 
-    fn setup(&self) -> Result<(), Error> {
+    fn setup(&self, _ctx: &StageCtx) -> Result<(), Error> {
         debug!("EXEC {:?}", &self.args[0..]);
 
+        util::cloexec_extra_fds(&self.keepfds)?;
         util::Exec::new(&self.args[0])?
             .args(&self.args[0..])?
             .exec()?;
@@ -37,16 +40,23 @@ impl ContainerHooks for NoNet {
     }
 }
 
-fn main() -> Result<(), Error> {
+fn run() -> Result<(), Error> {
+    let keepfds = util::open_fds()?;
     sandbox::logging::setup().unwrap();
 
     let rawargs = env::args().collect::<Vec<String>>();
-    if rawargs.len() <= 1 {
-        eprintln!("Usage: {} <cmd> [args ...]", rawargs[0]);
+    let args = ui::take_verbosity(&rawargs[1..]);
+    if args.is_empty() {
+        eprintln!("{}", msg::tr(Msg::CmdUsage, &[("execname", &rawargs[0])]));
         process::exit(1);
     }
 
     process::exit(runc(&NoNet {
-        args: rawargs[1..].to_vec(),
+        args: args.to_vec(),
+        keepfds,
     })?);
 }
+
+fn main() {
+    ui::main(run)
+}
@@ -0,0 +1,360 @@
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{env, process};
+
+use sandbox::config::Document;
+use sandbox::fs::Mounts;
+use sandbox::gc::Collector;
+use sandbox::msg::{self, Msg};
+use sandbox::preview;
+use sandbox::procfs::ProcFs;
+use sandbox::profile::Profile;
+use sandbox::project::{self, AllowList};
+use sandbox::registry::{Entry, Registry};
+use sandbox::site::SitePolicy;
+use sandbox::test::Isolated;
+use sandbox::{container, forkserver, id, info, ui, util, Error};
+
+/// Default of stop -t
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn usage() {
+    let execname = env::args().next().unwrap();
+    eprint!(
+        "{}",
+        msg::tr(
+            Msg::SandboxUsage,
+            &[
+                ("execname", &execname),
+                ("file", &project::PROJECT_FILE),
+                ("timeout", &STOP_TIMEOUT.as_secs()),
+            ]
+        )
+    );
+}
+
+/// Returns the number of files with problems
+fn lint(files: &[&str]) -> usize {
+    let mut bad = 0;
+    for file in files {
+        let doc = match Document::load(file) {
+            Ok(doc) => doc,
+            Err(err) => {
+                ui::error(err);
+                bad += 1;
+                continue;
+            }
+        };
+        let warnings = Profile::lint(&doc);
+        for warning in &warnings {
+            ui::warning(format!("{file}:{warning}"));
+        }
+        if let Err(err) = Profile::from_document(&doc) {
+            ui::error(err);
+            bad += 1;
+        } else if !warnings.is_empty() {
+            bad += 1;
+        }
+    }
+    bad
+}
+
+fn preview(file: &str) -> Result<(), Error> {
+    let profile = Profile::load(file)?;
+    let mounts = Mounts::current()?;
+    let cwd = env::current_dir()?;
+    let site = SitePolicy::system()?;
+    for entry in preview::preview(&profile, &mounts.visible_under("/"), &cwd, &site) {
+        match &entry.warning {
+            Some(warning) => println!(
+                "{:<10} {}  ! {}",
+                entry.access,
+                entry.path.display(),
+                warning
+            ),
+            None => println!("{:<10} {}", entry.access, entry.path.display()),
+        }
+    }
+    Ok(())
+}
+
+/// The named project file, or the one found from $PWD
+fn project_file(file: Option<&str>) -> Result<PathBuf, Error> {
+    if let Some(file) = file {
+        return Ok(file.into());
+    }
+    match project::discover(env::current_dir()?) {
+        Some(file) => Ok(file),
+        None => ui::fatal(msg::tr(
+            Msg::NoProjectFile,
+            &[("file", &project::PROJECT_FILE)],
+        )),
+    }
+}
+
+/// The entry named by `key`, or exit
+fn find(registry: &Registry, key: &str) -> Result<Entry, Error> {
+    match registry.find(key)? {
+        Some(entry) => Ok(entry),
+        None => ui::fatal(msg::tr(Msg::NoDetached, &[("key", &key)])),
+    }
+}
+
+fn list(registry: &Registry) -> Result<(), Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    println!(
+        "{:<8} {:<8} {:<16} {:>8} COMMAND",
+        "ID", "PID", "NAME", "AGE"
+    );
+    for entry in registry.list()? {
+        let age = if entry.alive() {
+            format!("{}s", now.saturating_sub(entry.started))
+        } else {
+            "exited".to_string()
+        };
+        println!(
+            "{:<8} {:<8} {:<16} {:>8} {}",
+            entry.id,
+            entry.pid,
+            entry.name,
+            age,
+            entry.args.join(" ")
+        );
+    }
+    Ok(())
+}
+
+/// Wait until `entry` is no longer alive.  Returns false on timeout
+fn wait_gone(entry: &Entry, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while entry.alive() {
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    true
+}
+
+fn stop(registry: &Registry, entry: &Entry, timeout: Duration) -> Result<(), Error> {
+    if entry.supervised() {
+        // relayed to the sandboxed processes.  Process 1 ignores it, unless handled
+        log::debug!("SIGTERM supervisor {}", entry.supervisor);
+        unsafe { libc::kill(entry.supervisor, libc::SIGTERM) };
+    }
+    if !wait_gone(entry, timeout) {
+        // takes all others in the PID namespace with it
+        log::debug!("SIGKILL container {}", entry.pid);
+        unsafe { libc::kill(entry.pid, libc::SIGKILL) };
+        if !wait_gone(entry, STOP_TIMEOUT) {
+            ui::fatal(msg::tr(Msg::DidNotStop, &[("id", &entry.id)]));
+        }
+    }
+    // normally removed by the supervisor
+    registry.remove(&entry.id)?;
+    Ok(())
+}
+
+/// Fields of /proc/<pid>/status shown by inspect
+const INSPECT_STATUS: &[&str] = &[
+    "Name",
+    "State",
+    "Uid",
+    "Gid",
+    "Threads",
+    "VmRSS",
+    "CapEff",
+    "NoNewPrivs",
+    "Seccomp",
+];
+
+fn inspect(entry: &Entry) -> Result<(), Error> {
+    if !entry.alive() {
+        ui::fatal(msg::tr(Msg::HasExited, &[("id", &entry.id)]));
+    }
+    let procfs = ProcFs::new(entry.pid);
+    println!("ID {}  PID {}  NAME {}", entry.id, entry.pid, entry.name);
+    println!("status:");
+    for (key, value) in procfs.status()? {
+        if INSPECT_STATUS.contains(&key.as_str()) {
+            let value: Vec<&str> = value.split_whitespace().collect();
+            println!("  {:<12} {}", key, value.join(" "));
+        }
+    }
+    println!("fds:");
+    for (fd, target) in procfs.fds()? {
+        println!("  {:>4} {}", fd, target.display());
+    }
+    println!("mounts:");
+    for mount in procfs.mounts()? {
+        println!(
+            "  {} {} {}",
+            mount.mount_point.display(),
+            mount.fstype,
+            mount.source
+        );
+    }
+    Ok(())
+}
+
+/// Returns the exit code of `cmd`
+fn exec(entry: &Entry, cmd: &[&str]) -> Result<i32, Error> {
+    if !entry.alive() {
+        ui::fatal(msg::tr(Msg::HasExited, &[("id", &entry.id)]));
+    }
+    let status = std::fs::read_to_string(format!("/proc/{}/status", entry.pid))?;
+    if status
+        .lines()
+        .any(|l| l.starts_with("Seccomp:") && l.trim_end() != "Seccomp:\t0")
+    {
+        log::warn!("The seccomp filter of sandbox {} is not applied", entry.id);
+    }
+
+    container::join(entry.pid)?;
+    // the first child is in the new PID namespace
+    let mut child = util::fork(|| -> Result<(), Error> {
+        if let Err(err) = env::set_current_dir(&entry.cwd) {
+            log::warn!("Unable to enter {} : {err}", entry.cwd.display());
+        }
+        util::Cap::current()?.clear().update()?;
+        env::set_var(info::ENV_MARKER, "1");
+        env::set_var("SANDBOX_NAME", &entry.name);
+        util::Exec::new(cmd[0])?.args(cmd)?.exec()?;
+        Ok(())
+    })?;
+    Ok(child.park()?)
+}
+
+/// Prepare a sandbox once, then serve requests at `socket` until killed.
+/// Returns the exit code of the server.
+fn server(profile: Option<&str>, socket: Option<&str>) -> Result<i32, Error> {
+    let mut config = Isolated::new();
+    if let Some(file) = profile {
+        let profile = Profile::load(file)?;
+        config.net(profile.net.unwrap_or(false));
+        for dir in profile.rw {
+            config.rw(dir);
+        }
+        let toolchains = profile.toolchains.map(|t| t.resolve()).unwrap_or_default();
+        for dir in profile.ro.into_iter().chain(toolchains) {
+            config.ro(dir);
+        }
+    }
+    let socket = match socket {
+        Some(socket) => PathBuf::from(socket),
+        None => env::temp_dir().join(format!("sandbox-server-{}.sock", id::current())),
+    };
+    // not again when run inside the sandbox
+    if env::var_os(forkserver::ENV_LISTEN_FD).is_none() {
+        ui::info(msg::tr(
+            Msg::ServerListening,
+            &[("socket", &socket.display())],
+        ));
+    }
+    config.serve(&socket)
+}
+
+/// Remove leftovers of runs which ended without cleaning up
+fn gc(dry_run: bool) -> Result<(), Error> {
+    let mut collector = Collector::new();
+    collector.dry_run(dry_run);
+    let msg = if dry_run {
+        Msg::GcWouldRemove
+    } else {
+        Msg::GcRemoved
+    };
+    for item in collector.all()? {
+        ui::info(msg::tr(msg, &[("item", &item)]));
+    }
+    Ok(())
+}
+
+fn run() -> Result<(), Error> {
+    sandbox::logging::setup().unwrap();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = ui::take_verbosity(&args)
+        .iter()
+        .map(String::as_str)
+        .collect();
+
+    match args.as_slice() {
+        ["profile", "lint", files @ ..] if !files.is_empty() => {
+            process::exit(if lint(files) == 0 { 0 } else { 1 });
+        }
+        ["profile", "preview", file] => preview(file),
+        ["allow"] | ["allow", _] => {
+            let file = project_file(args.get(1).copied())?;
+            // check before allowing
+            if lint(&[&file.to_string_lossy()]) != 0 {
+                process::exit(1);
+            }
+            AllowList::new()?.allow(&file)?;
+            ui::info(msg::tr(Msg::Allowed, &[("file", &file.display())]));
+            Ok(())
+        }
+        ["deny"] | ["deny", _] => {
+            let file = project_file(args.get(1).copied())?;
+            if !AllowList::new()?.deny(&file)? {
+                ui::warning(msg::tr(Msg::NotAllowed, &[("file", &file.display())]));
+            }
+            Ok(())
+        }
+        ["list"] => list(&Registry::new()?),
+        ["stop", "-t", secs, key] => {
+            let registry = Registry::new()?;
+            let entry = find(&registry, key)?;
+            stop(&registry, &entry, Duration::from_secs(secs.parse()?))
+        }
+        ["stop", key] => {
+            let registry = Registry::new()?;
+            let entry = find(&registry, key)?;
+            stop(&registry, &entry, STOP_TIMEOUT)
+        }
+        ["inspect", key] => inspect(&find(&Registry::new()?, key)?),
+        ["exec", key, cmd @ ..] if !cmd.is_empty() => {
+            let entry = find(&Registry::new()?, key)?;
+            process::exit(exec(&entry, cmd)?);
+        }
+        ["server", opts @ ..] => {
+            let (mut profile, mut socket) = (None, None);
+            let mut opts = opts.iter();
+            while let Some(opt) = opts.next() {
+                match (*opt, opts.next()) {
+                    ("--profile", Some(file)) => profile = Some(*file),
+                    ("--socket", Some(path)) => socket = Some(*path),
+                    _ => {
+                        usage();
+                        process::exit(1);
+                    }
+                }
+            }
+            process::exit(server(profile, socket)?);
+        }
+        ["request", socket, cmd @ ..] if !cmd.is_empty() => {
+            process::exit(forkserver::request(socket, cmd)?);
+        }
+        ["gc"] => gc(false),
+        ["gc", "-n"] => gc(true),
+        ["--version"] | ["--version", "--verbose"] => {
+            ui::version("sandbox", args.len() > 1 || ui::verbosity() > 0);
+            Ok(())
+        }
+        ["-h"] | ["--help"] => {
+            usage();
+            Ok(())
+        }
+        _ => {
+            usage();
+            process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    ui::main(run)
+}
@@ -0,0 +1,270 @@
+//! Define container hooks with closures.
+//!
+//! eg. run a command without network access
+//!
+//! ```no_run
+//! use sandbox::{runc, util, HooksBuilder};
+//!
+//! let mut hooks = HooksBuilder::new();
+//! hooks
+//!     .namespaces(libc::CLONE_NEWNET)
+//!     .on_setup(|_ctx| Ok(util::Exec::new("ip")?.args(["ip", "link"])?.exec()?));
+//! let code = runc(&hooks).unwrap();
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use super::cgroup::Resources;
+use super::container::{ContainerHooks, ContainerInfo, Result, StageCtx};
+use super::hook::{HookCmd, Stage};
+use super::retry::RetryPolicy;
+use super::seccomp::Filter;
+
+type Hook = Box<dyn Fn(&StageCtx) -> Result<()>>;
+type StartedHook = Box<dyn Fn(&StageCtx, &ContainerInfo) -> Result<()>>;
+
+/// Container hooks defined by closures.  Stages without a closure do nothing.
+#[derive(Default)]
+pub struct HooksBuilder {
+    namespaces: libc::c_int,
+    tempdir: Option<PathBuf>,
+    nowatchdog: bool,
+    retry: Option<RetryPolicy>,
+    cgroup: Option<Resources>,
+    seccomp: Option<Filter>,
+    at_start: Option<Hook>,
+    unshare: Option<Hook>,
+    set_id_map: Option<Hook>,
+    started: Option<StartedHook>,
+    ready: Option<Hook>,
+    setup_priv: Option<Hook>,
+    setup: Option<Hook>,
+    prestart: Vec<HookCmd>,
+    poststop: Vec<HookCmd>,
+}
+
+fn call(hook: &Option<Hook>, ctx: &StageCtx) -> Result<()> {
+    match hook {
+        Some(hook) => hook(ctx),
+        None => Ok(()),
+    }
+}
+
+impl HooksBuilder {
+    pub fn new() -> HooksBuilder {
+        Default::default()
+    }
+
+    /// `CLONE_NEW*` namespaces to create.  cf. `ContainerHooks::namespaces()`
+    pub fn namespaces(&mut self, flags: libc::c_int) -> &mut Self {
+        self.namespaces = flags;
+        self
+    }
+
+    /// Scratch directory made available through `StageCtx::scratch_dir()`
+    pub fn tempdir<P: Into<PathBuf>>(&mut self, dir: P) -> &mut Self {
+        self.tempdir = Some(dir.into());
+        self
+    }
+
+    /// Kill the container if the caller exits first.  Default true.
+    /// cf. `ContainerHooks::watchdog()`
+    pub fn watchdog(&mut self, enable: bool) -> &mut Self {
+        self.nowatchdog = !enable;
+        self
+    }
+
+    /// Retries of transiently failing setup operations.  cf. `ContainerHooks::retry_policy()`
+    pub fn retry(&mut self, policy: RetryPolicy) -> &mut Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Limits applied through a transient cgroup.  cf. `ContainerHooks::cgroup()`
+    pub fn cgroup(&mut self, res: Resources) -> &mut Self {
+        self.cgroup = Some(res);
+        self
+    }
+
+    /// Syscall filter of the container.  cf. `ContainerHooks::seccomp()`
+    pub fn seccomp(&mut self, filter: Filter) -> &mut Self {
+        self.seccomp = Some(filter);
+        self
+    }
+
+    /// cf. `ContainerHooks::at_start()`
+    pub fn on_at_start<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&StageCtx) -> Result<()> + 'static,
+    {
+        self.at_start = Some(Box::new(f));
+        self
+    }
+
+    /// cf. `ContainerHooks::unshare()`
+    pub fn on_unshare<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&StageCtx) -> Result<()> + 'static,
+    {
+        self.unshare = Some(Box::new(f));
+        self
+    }
+
+    /// cf. `ContainerHooks::set_id_map()`
+    pub fn on_set_id_map<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&StageCtx) -> Result<()> + 'static,
+    {
+        self.set_id_map = Some(Box::new(f));
+        self
+    }
+
+    /// cf. `ContainerHooks::started()`
+    pub fn on_started<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&StageCtx, &ContainerInfo) -> Result<()> + 'static,
+    {
+        self.started = Some(Box::new(f));
+        self
+    }
+
+    /// cf. `ContainerHooks::ready()`
+    pub fn on_ready<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&StageCtx) -> Result<()> + 'static,
+    {
+        self.ready = Some(Box::new(f));
+        self
+    }
+
+    /// cf. `ContainerHooks::setup_priv()`
+    pub fn on_setup_priv<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&StageCtx) -> Result<()> + 'static,
+    {
+        self.setup_priv = Some(Box::new(f));
+        self
+    }
+
+    /// cf. `ContainerHooks::setup()`
+    pub fn on_setup<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&StageCtx) -> Result<()> + 'static,
+    {
+        self.setup = Some(Box::new(f));
+        self
+    }
+
+    /// Append an external command.  cf. `ContainerHooks::hook_cmds()`
+    pub fn hook_cmd(&mut self, stage: Stage, cmd: HookCmd) -> &mut Self {
+        match stage {
+            Stage::Prestart => self.prestart.push(cmd),
+            Stage::Poststop => self.poststop.push(cmd),
+        }
+        self
+    }
+}
+
+impl ContainerHooks for HooksBuilder {
+    fn namespaces(&self) -> libc::c_int {
+        self.namespaces
+    }
+    fn scratch_dir(&self) -> Option<&Path> {
+        self.tempdir.as_deref()
+    }
+    fn watchdog(&self) -> bool {
+        !self.nowatchdog
+    }
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        self.retry
+    }
+    fn cgroup(&self) -> Option<&Resources> {
+        self.cgroup.as_ref()
+    }
+    fn at_start(&self, ctx: &StageCtx) -> Result<()> {
+        call(&self.at_start, ctx)
+    }
+    fn unshare(&self, ctx: &StageCtx) -> Result<()> {
+        call(&self.unshare, ctx)
+    }
+    fn set_id_map(&self, ctx: &StageCtx) -> Result<()> {
+        call(&self.set_id_map, ctx)
+    }
+    fn started(&self, ctx: &StageCtx, info: &ContainerInfo) -> Result<()> {
+        match &self.started {
+            Some(hook) => hook(ctx, info),
+            None => Ok(()),
+        }
+    }
+    fn hook_cmds(&self, stage: Stage) -> &[HookCmd] {
+        match stage {
+            Stage::Prestart => &self.prestart,
+            Stage::Poststop => &self.poststop,
+        }
+    }
+    fn ready(&self, ctx: &StageCtx) -> Result<()> {
+        call(&self.ready, ctx)
+    }
+    fn setup_priv(&self, ctx: &StageCtx) -> Result<()> {
+        call(&self.setup_priv, ctx)
+    }
+    fn seccomp(&self) -> Option<&Filter> {
+        self.seccomp.as_ref()
+    }
+    fn setup(&self, ctx: &StageCtx) -> Result<()> {
+        call(&self.setup, ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container::runc;
+    use crate::util;
+    use std::cell::RefCell;
+    use std::io::{Read, Write};
+    use std::rc::Rc;
+
+    #[test]
+    fn closures() {
+        let (mut me, dut) = util::socketpair().expect("socketpair");
+        let dut = Rc::new(RefCell::new(dut));
+        let at = move |pos: &'static str| {
+            let dut = dut.clone();
+            move |_ctx: &StageCtx| -> Result<()> {
+                dut.borrow_mut().write_all(pos.as_bytes())?;
+                Ok(())
+            }
+        };
+
+        let mut hooks = HooksBuilder::new();
+        hooks
+            .tempdir("/nonexistent")
+            .retry(RetryPolicy::none())
+            .on_at_start(at("A"))
+            .on_unshare(at("B"))
+            .on_setup_priv(|ctx| {
+                assert_eq!(ctx.scratch_dir(), Some(Path::new("/nonexistent")));
+                assert_eq!(RetryPolicy::current(), RetryPolicy::none());
+                ctx.set_new_root("/new");
+                ctx.keep(42u32);
+                Ok(())
+            })
+            .on_setup({
+                let at = at("E");
+                move |ctx| {
+                    assert_eq!(ctx.new_root(), Some(PathBuf::from("/new")));
+                    assert_eq!(ctx.kept(), 1);
+                    at(ctx)
+                }
+            })
+            .on_ready(at("F"));
+        runc(&hooks).expect("runc");
+        drop(hooks);
+        drop(at);
+
+        let mut result = String::new();
+        me.read_to_string(&mut result).expect("Read results");
+        assert_eq!(result, "ABEF");
+    }
+}
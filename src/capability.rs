@@ -4,7 +4,7 @@ use std::fmt;
 use super::ext;
 use libc;
 
-pub use super::ext::CAP_SYS_ADMIN;
+pub use super::ext::{CAP_NET_RAW, CAP_SYS_ADMIN};
 
 use super::err::{Error, Result};
 
@@ -21,6 +21,24 @@ fn empty_data() -> ext::__user_cap_data_struct {
     ext::__user_cap_data_struct::default()
 }
 
+/// Test bit number `cap` of a mask.  false if out of range.
+fn get_bit(mask: &[u32; DATA_SIZE], cap: u32) -> bool {
+    match mask.get((cap / 32) as usize) {
+        Some(word) => 0 != word & (1 << (cap % 32)),
+        None => false,
+    }
+}
+
+/// Set or clear bit number `cap` of a mask.  Panics if out of range.
+fn set_bit(mask: &mut [u32; DATA_SIZE], cap: u32, v: bool) {
+    let word = &mut mask[(cap / 32) as usize];
+    if v {
+        *word |= 1 << (cap % 32);
+    } else {
+        *word &= !(1 << (cap % 32));
+    }
+}
+
 impl Cap {
     /// Fetch the current capabilities of this process
     pub fn current() -> Result<Self> {
@@ -107,14 +125,58 @@ impl Cap {
         self.clear_effective().clear_permitted().clear_inheritable()
     }
 
-    /// Test a bit in the effective mask
+    /// Test a bit in the effective mask.  eg. `CAP_SYS_ADMIN`
     pub fn effective(&self, cap: u32) -> bool {
-        let word = cap / 32;
-        let bit = cap % 32;
-        0 != (self.effective[word as usize] & bit)
+        get_bit(&self.effective, cap)
+    }
+
+    /// Test a bit in the permitted mask
+    pub fn permitted(&self, cap: u32) -> bool {
+        get_bit(&self.permitted, cap)
+    }
+
+    /// Test a bit in the inheritable mask
+    pub fn inheritable(&self, cap: u32) -> bool {
+        get_bit(&self.inheritable, cap)
+    }
+
+    /// Set or clear a bit in the effective mask
+    pub fn set_effective(&mut self, cap: u32, v: bool) -> &mut Self {
+        set_bit(&mut self.effective, cap, v);
+        self
+    }
+
+    /// Set or clear a bit in the permitted mask
+    pub fn set_permitted(&mut self, cap: u32, v: bool) -> &mut Self {
+        set_bit(&mut self.permitted, cap, v);
+        self
+    }
+
+    /// Set or clear a bit in the inheritable mask
+    pub fn set_inheritable(&mut self, cap: u32, v: bool) -> &mut Self {
+        set_bit(&mut self.inheritable, cap, v);
+        self
     }
 }
 
+/// Add `cap` to the ambient set of the calling process, which is kept through `exec()`
+/// of a program without file capabilities.  Must already be permitted and inheritable.
+pub fn raise_ambient(cap: u32) -> Result<()> {
+    let ret = unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong,
+            cap as libc::c_ulong,
+            0,
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error(format!("raise ambient cap {}", cap)));
+    }
+    Ok(())
+}
+
 fn fmt_arr(arr: &[u32], f: &mut fmt::Formatter<'_>) -> fmt::Result {
     for n in (0..arr.len()).rev() {
         write!(f, "{:08x}", arr[n])?;
@@ -147,4 +209,30 @@ mod tests {
     fn apply_current() {
         Cap::current().unwrap().update().unwrap();
     }
+
+    #[test]
+    fn bits() {
+        let mut cap = Cap::default();
+        // bit 5, but not 21
+        cap.effective[0] = 0x20;
+        assert!(!cap.effective(CAP_SYS_ADMIN));
+        assert!(cap.effective(5));
+
+        cap.clear().set_effective(CAP_SYS_ADMIN, true);
+        assert_eq!(cap.effective, [1 << 21, 0]);
+        assert!(cap.effective(CAP_SYS_ADMIN));
+        assert!(!cap.permitted(CAP_SYS_ADMIN));
+
+        // in the second word
+        const CAP_SYSLOG: u32 = 34;
+        cap.set_permitted(CAP_SYSLOG, true)
+            .set_inheritable(CAP_SYSLOG, true);
+        assert_eq!(cap.permitted, [0, 1 << (CAP_SYSLOG - 32)]);
+        assert!(cap.permitted(CAP_SYSLOG) && cap.inheritable(CAP_SYSLOG));
+        assert!(!cap.effective(CAP_SYSLOG));
+
+        cap.set_effective(CAP_SYS_ADMIN, false);
+        assert_eq!(cap.effective, [0, 0]);
+        assert!(!cap.effective(1000));
+    }
 }
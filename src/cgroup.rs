@@ -0,0 +1,345 @@
+//! Resource limits through a transient cgroup (v2) per container.
+//!
+//! The cgroup is created as a sibling of that of the calling process, as a cgroup
+//! with member processes may not enable controllers for its children.
+//! eg. `/user.slice/user-1000.slice/user@1000.service/app.slice/sandbox-1a2b3c4d-0`
+//! under a systemd user session, which delegates `app.slice` to the user.
+//!
+//! Only container process 1, and its descendants, are placed in the cgroup.
+//! cf. `ContainerHooks::cgroup()`
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use super::err::{Error, Result};
+use super::proc::{fork, Proc};
+use super::{id, util};
+
+/// Mount points of the unified hierarchy.  Pure v2, then hybrid.
+const MOUNTS: &[&str] = &["/sys/fs/cgroup", "/sys/fs/cgroup/unified"];
+
+/// Default `cpu.max` period.  µs
+pub const CPU_PERIOD: u64 = 100_000;
+
+/// Wait for the last member to exit, before removal fails
+const REMOVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+static SERIAL: AtomicUsize = AtomicUsize::new(0);
+
+/// `cpu.max`.  At most `quota` µs of CPU time in each `period` µs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuMax {
+    pub quota: u64,
+    pub period: u64,
+}
+
+impl CpuMax {
+    /// Limit to a number of CPUs.  eg. `1.5`
+    pub fn cpus(cpus: f64) -> CpuMax {
+        CpuMax {
+            quota: ((cpus * CPU_PERIOD as f64) as u64).max(1000),
+            period: CPU_PERIOD,
+        }
+    }
+}
+
+impl fmt::Display for CpuMax {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.quota, self.period)
+    }
+}
+
+/// Limits applied to a cgroup.  `None` leaves a limit unset.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Resources {
+    /// `memory.max`.  bytes
+    pub memory_max: Option<u64>,
+    /// `pids.max`
+    pub pids_max: Option<u64>,
+    /// `cpu.max`
+    pub cpu_max: Option<CpuMax>,
+}
+
+impl Resources {
+    pub fn is_empty(&self) -> bool {
+        self.memory_max.is_none() && self.pids_max.is_none() && self.cpu_max.is_none()
+    }
+
+    /// Controllers needed.  eg. `["memory"]`
+    pub fn controllers(&self) -> Vec<&'static str> {
+        let mut ret = vec![];
+        if self.cpu_max.is_some() {
+            ret.push("cpu");
+        }
+        if self.memory_max.is_some() {
+            ret.push("memory");
+        }
+        if self.pids_max.is_some() {
+            ret.push("pids");
+        }
+        ret
+    }
+
+    /// Interface files, and their contents
+    fn files(&self) -> Vec<(&'static str, String)> {
+        let mut ret = vec![];
+        if let Some(max) = self.memory_max {
+            ret.push(("memory.max", max.to_string()));
+        }
+        if let Some(max) = self.pids_max {
+            ret.push(("pids.max", max.to_string()));
+        }
+        if let Some(max) = self.cpu_max {
+            ret.push(("cpu.max", max.to_string()));
+        }
+        ret
+    }
+}
+
+/// Mount point of the unified hierarchy
+pub fn mount_point() -> Result<PathBuf> {
+    MOUNTS
+        .iter()
+        .map(PathBuf::from)
+        .find(|dir| dir.join("cgroup.controllers").exists())
+        .ok_or_else(|| {
+            Error::os(
+                "No cgroup2 mount",
+                io::Error::from_raw_os_error(libc::ENOENT),
+            )
+        })
+}
+
+/// cgroup of process `pid`, relative to the mount point.  eg. `/user.slice`
+fn path_of(pid: &str) -> Result<PathBuf> {
+    let name = format!("/proc/{}/cgroup", pid);
+    let text = fs::read_to_string(&name).map_err(|e| Error::file("read", &name, e))?;
+    text.lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(PathBuf::from)
+        .ok_or_else(|| {
+            Error::file(
+                "parse",
+                &name,
+                io::Error::new(io::ErrorKind::InvalidData, "No cgroup2 entry"),
+            )
+        })
+}
+
+/// Where `Cgroup::create()` places new cgroups.  The parent of the cgroup of the caller.
+pub fn sibling_dir() -> Result<PathBuf> {
+    let mine = path_of("self")?;
+    let rel = mine.parent().unwrap_or(&mine).strip_prefix("/").unwrap();
+    Ok(mount_point()?.join(rel))
+}
+
+/// A transient cgroup.  Removed when dropped.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    /// cf. `Cgroup::remove_from_helper()`
+    helper: Option<(File, Proc)>,
+}
+
+impl Cgroup {
+    /// Create a new cgroup next to that of the calling process, with `res` applied.
+    pub fn create(res: &Resources) -> Result<Cgroup> {
+        let parent = sibling_dir()?;
+        let name = format!(
+            "sandbox-{}-{}",
+            id::current(),
+            SERIAL.fetch_add(1, Ordering::Relaxed)
+        );
+        Self::create_in(parent, &name, res)
+    }
+
+    /// Create cgroup `name` in directory `parent`, with `res` applied
+    pub fn create_in<P: AsRef<Path>>(parent: P, name: &str, res: &Resources) -> Result<Cgroup> {
+        let parent = parent.as_ref();
+        enable(parent, &res.controllers())?;
+        let ret = Cgroup {
+            path: util::mkdir(parent.join(name))?,
+            helper: None,
+        };
+        debug!("Created cgroup {}", ret.path.display());
+        for (file, value) in res.files() {
+            util::overwrite_file(ret.path.join(file), value)?;
+        }
+        Ok(ret)
+    }
+
+    /// Directory in the cgroup2 filesystem
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move process `pid` into this cgroup.  Later children follow.
+    pub fn attach(&self, pid: libc::pid_t) -> Result<()> {
+        debug!("Attach {} to {}", pid, self.path.display());
+        util::overwrite_file(self.path.join("cgroup.procs"), pid.to_string())
+    }
+
+    /// Member processes
+    pub fn procs(&self) -> Result<Vec<libc::pid_t>> {
+        let name = self.path.join("cgroup.procs");
+        let text = fs::read_to_string(&name).map_err(|e| Error::file("read", &name, e))?;
+        Ok(text.lines().filter_map(|l| l.parse().ok()).collect())
+    }
+
+    /// Remove from a helper process, which keeps the privilege of the caller.
+    /// eg. when the caller will drop privilege, as `runc()` does.
+    /// The helper acts once this `Cgroup` is dropped, or should the caller exit first.
+    pub fn remove_from_helper(&mut self) -> Result<()> {
+        if self.helper.is_some() {
+            return Ok(());
+        }
+        let (mut rx, tx) = util::pipe()?;
+        let tx_fd = tx.as_raw_fd();
+        let helper = fork(|| -> Result<()> {
+            unsafe {
+                libc::close(tx_fd);
+                // outlive a Ctrl+C of the caller
+                libc::signal(libc::SIGINT, libc::SIG_IGN);
+                libc::signal(libc::SIGQUIT, libc::SIG_IGN);
+            }
+            let mut buf = vec![];
+            let _ = rx.read_to_end(&mut buf);
+            self.destroy()
+        })?;
+        debug!("cgroup helper {}", helper.id());
+        self.helper = Some((tx, helper));
+        Ok(())
+    }
+
+    /// Kill any remaining members, and remove
+    pub fn remove(self) -> Result<()> {
+        if self.helper.is_some() {
+            // by the helper, when dropped
+            return Ok(());
+        }
+        let ret = self.destroy();
+        std::mem::forget(self);
+        ret
+    }
+
+    fn destroy(&self) -> Result<()> {
+        let kill = self.path.join("cgroup.kill");
+        // Linux >= 5.14
+        if util::overwrite_file(&kill, "1").is_err() {
+            for pid in self.procs()? {
+                unsafe { libc::kill(pid, libc::SIGKILL) };
+            }
+        }
+        // members are removed once reaped
+        let start = Instant::now();
+        loop {
+            match fs::remove_dir(&self.path) {
+                Ok(()) => break,
+                Err(err)
+                    if err.raw_os_error() == Some(libc::EBUSY)
+                        && start.elapsed() < REMOVE_TIMEOUT =>
+                {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                Err(err) => return Err(Error::file("rmdir", &self.path, err)),
+            }
+        }
+        debug!("Removed cgroup {}", self.path.display());
+        Ok(())
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Some((tx, mut helper)) = self.helper.take() {
+            // helper removes once all copies are closed
+            drop(tx);
+            if let Err(err) = helper.park() {
+                warn!("{}", err);
+            }
+        } else if let Err(err) = self.destroy() {
+            warn!("{}", err);
+        }
+    }
+}
+
+/// Enable `controllers` for the children of cgroup `dir`.  Those already enabled are skipped.
+fn enable(dir: &Path, controllers: &[&str]) -> Result<()> {
+    let name = dir.join("cgroup.subtree_control");
+    let text = fs::read_to_string(&name).map_err(|e| Error::file("read", &name, e))?;
+    let enabled: Vec<_> = text.split_whitespace().collect();
+    let missing: Vec<_> = controllers
+        .iter()
+        .filter(|c| !enabled.contains(c))
+        .map(|c| format!("+{}", c))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    debug!("Enable {:?} in {}", missing, dir.display());
+    util::overwrite_file(&name, missing.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resources() {
+        let res = Resources {
+            memory_max: Some(1 << 20),
+            cpu_max: Some(CpuMax::cpus(0.5)),
+            ..Default::default()
+        };
+        assert!(!res.is_empty());
+        assert!(Resources::default().is_empty());
+        assert_eq!(res.controllers(), ["cpu", "memory"]);
+        assert_eq!(
+            res.files(),
+            [
+                ("memory.max", "1048576".to_string()),
+                ("cpu.max", "50000 100000".to_string())
+            ]
+        );
+        assert_eq!(CpuMax::cpus(0.0).quota, 1000);
+    }
+
+    #[test]
+    fn lifecycle() {
+        // needs a writable cgroup2 hierarchy
+        let cg = match Cgroup::create(&Resources::default()) {
+            Ok(cg) => cg,
+            Err(err) => {
+                eprintln!("Skip : {}", err);
+                return;
+            }
+        };
+        let path = cg.path().to_path_buf();
+        assert!(path.is_dir());
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        cg.attach(child.id() as libc::pid_t).unwrap();
+        assert_eq!(cg.procs().unwrap(), [child.id() as libc::pid_t]);
+        // killed, but must still be reaped
+        let reaper = thread::spawn(move || child.wait());
+        cg.remove().unwrap();
+        assert!(!path.exists());
+        reaper.join().unwrap().unwrap();
+
+        let mut cg = Cgroup::create(&Resources::default()).unwrap();
+        cg.remove_from_helper().unwrap();
+        let path = cg.path().to_path_buf();
+        drop(cg);
+        assert!(!path.exists());
+    }
+}
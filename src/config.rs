@@ -0,0 +1,860 @@
+//! Configuration file parsing.
+//!
+//! Handles the sub-set of TOML needed for profiles.
+//! Tables, arrays of tables, strings, integers, booleans, arrays, and inline tables.
+//! No floats or date/times.
+//!
+//! Also the same sub-set of JSON.  No floats.  `null` values, and object keys
+//! with a `null` value, are omitted.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::err::{Error, Result};
+use super::id;
+
+/// Location in a configuration file.  1-based.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Pos {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+/// A value, and where it was defined
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub pos: Pos,
+    pub value: Value,
+}
+
+pub type Table = BTreeMap<String, Item>;
+
+impl Value {
+    /// Name of the value type for diagnostics
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::Int(_) => "integer",
+            Value::Bool(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Table(_) => "table",
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&Table> {
+        match self {
+            Value::Table(t) => Some(t),
+            _ => None,
+        }
+    }
+
+    /// Serialize as compact JSON
+    pub fn to_json(&self) -> String {
+        match self {
+            Value::Str(s) => json_string(s),
+            Value::Int(i) => i.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Array(arr) => {
+                let elems: Vec<String> = arr.iter().map(|v| v.to_json()).collect();
+                format!("[{}]", elems.join(","))
+            }
+            Value::Table(table) => {
+                let elems: Vec<String> = table
+                    .iter()
+                    .map(|(k, item)| format!("{}:{}", json_string(k), item.value.to_json()))
+                    .collect();
+                format!("{{{}}}", elems.join(","))
+            }
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\t' => ret.push_str("\\t"),
+            '\r' => ret.push_str("\\r"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+/// A parsed configuration file
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub name: PathBuf,
+    pub root: Table,
+}
+
+impl Document {
+    /// Read and parse a file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Document> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| Error::file("read", path, e))?;
+        Self::parse(&text, path)
+    }
+
+    /// Parse text.  `name` is used in error messages
+    pub fn parse<P: AsRef<Path>>(text: &str, name: P) -> Result<Document> {
+        let name = name.as_ref();
+        let root = Parser::new(text)
+            .document()
+            .map_err(|(pos, msg)| Error::parse(format!("{} at {}", msg, pos), name))?;
+        Ok(Document {
+            name: name.to_path_buf(),
+            root,
+        })
+    }
+
+    /// Read and parse a JSON file
+    pub fn load_json<P: AsRef<Path>>(path: P) -> Result<Document> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| Error::file("read", path, e))?;
+        Self::parse_json(&text, path)
+    }
+
+    /// Parse JSON text, which must be an object.  `name` is used in error messages
+    pub fn parse_json<P: AsRef<Path>>(text: &str, name: P) -> Result<Document> {
+        let name = name.as_ref();
+        let root = Parser::new(text)
+            .json_document()
+            .map_err(|(pos, msg)| Error::parse(format!("{} at {}", msg, pos), name))?;
+        Ok(Document {
+            name: name.to_path_buf(),
+            root,
+        })
+    }
+
+    /// Error for a problem with a specific item
+    pub fn error<S: AsRef<str>>(&self, pos: Pos, msg: S) -> Error {
+        Error::parse(format!("{} at {}", msg.as_ref(), pos), &self.name)
+    }
+}
+
+/// Variables which may be expanded in configuration values.
+/// Upper case from the environment, lower case of the run.
+pub const INTERPOLATE_VARS: &[&str] = &[
+    "HOME",
+    "PWD",
+    "XDG_RUNTIME_DIR",
+    "run_id",
+    "date",
+    "profile",
+];
+
+/// Value of an interpolation variable from the environment of this process, or of this run.
+/// `profile`, the name of the file, is known only to the caller.
+pub fn lookup_env(name: &str) -> Option<String> {
+    match name {
+        "PWD" => {
+            let cwd = std::env::current_dir().ok()?;
+            cwd.to_str().map(String::from)
+        }
+        // also $SANDBOX_ID
+        "run_id" => Some(id::current()),
+        "date" => Some(utc_date(SystemTime::now())),
+        "profile" => None,
+        _ => std::env::var(name).ok(),
+    }
+}
+
+/// `YYYY-MM-DD`, in UTC
+pub fn utc_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    // days since 0000-03-01, so that leap days end each year.  cf. "civil_from_days"
+    let days = secs / 86400 + 719468;
+    let era = days / 146097;
+    let doe = days % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Expand `${NAME}` when `NAME` is one of `INTERPOLATE_VARS`.  `$$` is a literal `$`.
+///
+/// Expanded values are inserted verbatim, and are never expanded again.
+/// Any other use of `$`, and unset variables, are errors.
+pub fn interpolate<F>(text: &str, lookup: F) -> std::result::Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut ret = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(idx) = rest.find('$') {
+        ret.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        if let Some(tail) = rest.strip_prefix('$') {
+            ret.push('$');
+            rest = tail;
+            continue;
+        }
+        let (name, tail) = rest
+            .strip_prefix('{')
+            .and_then(|r| r.split_once('}'))
+            .ok_or_else(|| format!("expected \"${{NAME}}\" or \"$$\" in {:?}", text))?;
+        if !INTERPOLATE_VARS.contains(&name) {
+            return Err(format!(
+                "${{{}}} may not be expanded.  Allowed: {}",
+                name,
+                INTERPOLATE_VARS.join(", ")
+            ));
+        }
+        let value = lookup(name).ok_or_else(|| format!("${{{}}} is not set", name))?;
+        ret.push_str(&value);
+        rest = tail;
+    }
+    ret.push_str(rest);
+    Ok(ret)
+}
+
+type PResult<T> = std::result::Result<T, (Pos, String)>;
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    pos: Pos,
+}
+
+fn is_bare(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Parser {
+            chars: text.chars().peekable(),
+            pos: Pos { line: 1, col: 1 },
+        }
+    }
+
+    fn err<T, S: Into<String>>(&self, msg: S) -> PResult<T> {
+        Err((self.pos, msg.into()))
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.pos.line += 1;
+            self.pos.col = 1;
+        } else if c.is_some() {
+            self.pos.col += 1;
+        }
+        c
+    }
+
+    fn expect(&mut self, want: char) -> PResult<()> {
+        match self.peek() {
+            Some(c) if c == want => {
+                self.next();
+                Ok(())
+            }
+            Some(c) => self.err(format!("expected {:?} found {:?}", want, c)),
+            None => self.err(format!("expected {:?} found end of file", want)),
+        }
+    }
+
+    /// Skip spaces and tabs on the current line
+    fn skip_ws(&mut self) {
+        while let Some(' ' | '\t') = self.peek() {
+            self.next();
+        }
+    }
+
+    /// Skip whitespace, newlines, and comments
+    fn skip_all(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\r' | '\n') => {
+                    self.next();
+                }
+                Some('#') => self.skip_comment(),
+                _ => break,
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '\n' {
+                break;
+            }
+            self.next();
+        }
+    }
+
+    /// Expect only whitespace or a comment before the next line
+    fn end_of_line(&mut self) -> PResult<()> {
+        self.skip_ws();
+        match self.peek() {
+            Some('#') => {
+                self.skip_comment();
+                Ok(())
+            }
+            Some('\r' | '\n') | None => Ok(()),
+            Some(c) => self.err(format!("unexpected {:?} after value", c)),
+        }
+    }
+
+    fn document(&mut self) -> PResult<Table> {
+        let mut root = Table::new();
+        // path to the current table
+        let mut current: Vec<String> = vec![];
+
+        loop {
+            self.skip_all();
+            let pos = self.pos;
+            match self.peek() {
+                None => break,
+                Some('[') => {
+                    self.next();
+                    let isarray = self.peek() == Some('[');
+                    if isarray {
+                        self.next();
+                    }
+                    self.skip_ws();
+                    let path = self.key_path()?;
+                    self.skip_ws();
+                    self.expect(']')?;
+                    if isarray {
+                        self.expect(']')?;
+                    }
+                    self.end_of_line()?;
+
+                    if isarray {
+                        let (last, parents) = path.split_last().unwrap();
+                        let parent = descend(&mut root, parents, pos)?;
+                        let entry = parent.entry(last.clone()).or_insert_with(|| Item {
+                            pos,
+                            value: Value::Array(vec![]),
+                        });
+                        match &mut entry.value {
+                            Value::Array(arr) => arr.push(Value::Table(Table::new())),
+                            other => {
+                                return Err((
+                                    pos,
+                                    format!("{:?} already defined as {}", last, other.type_name()),
+                                ))
+                            }
+                        }
+                    } else {
+                        descend(&mut root, &path, pos)?;
+                    }
+                    current = path;
+                }
+                Some(_) => {
+                    let path = self.key_path()?;
+                    self.skip_ws();
+                    self.expect('=')?;
+                    self.skip_ws();
+                    let vpos = self.pos;
+                    let value = self.value()?;
+                    self.end_of_line()?;
+
+                    let table = descend(&mut root, &current, pos)?;
+                    insert(table, &path, Item { pos: vpos, value })?;
+                }
+            }
+        }
+        Ok(root)
+    }
+
+    /// Parse dotted key.  eg. `a.b."c d"`
+    fn key_path(&mut self) -> PResult<Vec<String>> {
+        let mut path = vec![self.key()?];
+        loop {
+            self.skip_ws();
+            if self.peek() != Some('.') {
+                break;
+            }
+            self.next();
+            self.skip_ws();
+            path.push(self.key()?);
+        }
+        Ok(path)
+    }
+
+    fn key(&mut self) -> PResult<String> {
+        match self.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            Some(c) if is_bare(c) => {
+                let mut key = String::new();
+                while let Some(c) = self.peek().filter(|c| is_bare(*c)) {
+                    key.push(c);
+                    self.next();
+                }
+                Ok(key)
+            }
+            Some(c) => self.err(format!("expected key found {:?}", c)),
+            None => self.err("expected key found end of file"),
+        }
+    }
+
+    fn value(&mut self) -> PResult<Value> {
+        match self.peek() {
+            Some('"') => Ok(Value::Str(self.basic_string()?)),
+            Some('\'') => Ok(Value::Str(self.literal_string()?)),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some(c) if c == '-' || c == '+' || c.is_ascii_digit() => self.integer(),
+            Some(c) if c.is_ascii_alphabetic() => {
+                let pos = self.pos;
+                let mut word = String::new();
+                while let Some(c) = self.peek().filter(|c| c.is_ascii_alphabetic()) {
+                    word.push(c);
+                    self.next();
+                }
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => Err((pos, format!("unknown value {:?}", word))),
+                }
+            }
+            Some(c) => self.err(format!("expected value found {:?}", c)),
+            None => self.err("expected value found end of file"),
+        }
+    }
+
+    fn integer(&mut self) -> PResult<Value> {
+        let pos = self.pos;
+        let mut text = String::new();
+        while let Some(c) = self
+            .peek()
+            .filter(|c| c.is_ascii_alphanumeric() || "+-_".contains(*c))
+        {
+            if c != '_' {
+                text.push(c);
+            }
+            self.next();
+        }
+        match text.parse::<i64>() {
+            Ok(i) => Ok(Value::Int(i)),
+            Err(_) => Err((pos, format!("invalid integer {:?}", text))),
+        }
+    }
+
+    fn basic_string(&mut self) -> PResult<String> {
+        self.expect('"')?;
+        let mut ret = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return self.err("unterminated string"),
+                Some('"') => return Ok(ret),
+                Some('\\') => match self.next() {
+                    Some('"') => ret.push('"'),
+                    Some('\\') => ret.push('\\'),
+                    Some('n') => ret.push('\n'),
+                    Some('t') => ret.push('\t'),
+                    Some('r') => ret.push('\r'),
+                    Some('b') => ret.push('\u{8}'),
+                    Some('f') => ret.push('\u{c}'),
+                    Some('u') => {
+                        let mut hex = String::new();
+                        for _ in 0..4 {
+                            hex.extend(self.next());
+                        }
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(c) => ret.push(c),
+                            None => return self.err(format!("invalid escape \\u{}", hex)),
+                        }
+                    }
+                    Some(c) => return self.err(format!("invalid escape \\{}", c)),
+                    None => return self.err("unterminated string"),
+                },
+                Some(c) => ret.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> PResult<String> {
+        self.expect('\'')?;
+        let mut ret = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return self.err("unterminated string"),
+                Some('\'') => return Ok(ret),
+                Some(c) => ret.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> PResult<Value> {
+        self.expect('[')?;
+        let mut ret = vec![];
+        loop {
+            self.skip_all();
+            if self.peek() == Some(']') {
+                self.next();
+                return Ok(Value::Array(ret));
+            }
+            ret.push(self.value()?);
+            self.skip_all();
+            match self.peek() {
+                Some(',') => {
+                    self.next();
+                }
+                Some(']') => (),
+                _ => return self.err("expected ',' or ']' in array"),
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> PResult<Value> {
+        self.expect('{')?;
+        let mut ret = Table::new();
+        self.skip_ws();
+        if self.peek() == Some('}') {
+            self.next();
+            return Ok(Value::Table(ret));
+        }
+        loop {
+            self.skip_ws();
+            let path = self.key_path()?;
+            self.skip_ws();
+            self.expect('=')?;
+            self.skip_ws();
+            let pos = self.pos;
+            let value = self.value()?;
+            insert(&mut ret, &path, Item { pos, value })?;
+            self.skip_ws();
+            match self.next() {
+                Some(',') => (),
+                Some('}') => return Ok(Value::Table(ret)),
+                _ => return self.err("expected ',' or '}' in inline table"),
+            }
+        }
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn json_ws(&mut self) {
+        while let Some(' ' | '\t' | '\r' | '\n') = self.peek() {
+            self.next();
+        }
+    }
+
+    fn json_document(&mut self) -> PResult<Table> {
+        self.json_ws();
+        let ret = match self.json_value()? {
+            Some(Value::Table(table)) => table,
+            other => {
+                let found = other.as_ref().map_or("null", Value::type_name);
+                return Err((
+                    Pos { line: 1, col: 1 },
+                    format!("expected object found {}", found),
+                ));
+            }
+        };
+        self.json_ws();
+        match self.peek() {
+            None => Ok(ret),
+            Some(c) => self.err(format!("unexpected {:?} after value", c)),
+        }
+    }
+
+    /// `None` for `null`
+    fn json_value(&mut self) -> PResult<Option<Value>> {
+        let value = match self.peek() {
+            Some('"') => Value::Str(self.basic_string()?),
+            Some('[') => {
+                self.next();
+                let mut ret = vec![];
+                self.json_ws();
+                if self.peek() == Some(']') {
+                    self.next();
+                    return Ok(Some(Value::Array(ret)));
+                }
+                loop {
+                    self.json_ws();
+                    ret.extend(self.json_value()?);
+                    self.json_ws();
+                    match self.next() {
+                        Some(',') => (),
+                        Some(']') => return Ok(Some(Value::Array(ret))),
+                        _ => return self.err("expected ',' or ']' in array"),
+                    }
+                }
+            }
+            Some('{') => {
+                self.next();
+                let mut ret = Table::new();
+                self.json_ws();
+                if self.peek() == Some('}') {
+                    self.next();
+                    return Ok(Some(Value::Table(ret)));
+                }
+                loop {
+                    self.json_ws();
+                    let key = self.basic_string()?;
+                    self.json_ws();
+                    self.expect(':')?;
+                    self.json_ws();
+                    let pos = self.pos;
+                    if let Some(value) = self.json_value()? {
+                        insert(&mut ret, &[key], Item { pos, value })?;
+                    }
+                    self.json_ws();
+                    match self.next() {
+                        Some(',') => (),
+                        Some('}') => return Ok(Some(Value::Table(ret))),
+                        _ => return self.err("expected ',' or '}' in object"),
+                    }
+                }
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => self.integer()?,
+            Some('n') => {
+                let pos = self.pos;
+                for want in "null".chars() {
+                    if self.next() != Some(want) {
+                        return Err((pos, "unknown value".to_string()));
+                    }
+                }
+                return Ok(None);
+            }
+            _ => self.value()?,
+        };
+        Ok(Some(value))
+    }
+}
+
+/// Find or create the table at `path`.  The last element of an array of tables is used.
+fn descend<'t>(mut table: &'t mut Table, path: &[String], pos: Pos) -> PResult<&'t mut Table> {
+    for key in path {
+        let item = table.entry(key.clone()).or_insert_with(|| Item {
+            pos,
+            value: Value::Table(Table::new()),
+        });
+        table = match &mut item.value {
+            Value::Table(t) => t,
+            Value::Array(arr) => match arr.last_mut() {
+                Some(Value::Table(t)) => t,
+                _ => return Err((pos, format!("{:?} is not a table", key))),
+            },
+            other => {
+                return Err((
+                    pos,
+                    format!("{:?} already defined as {}", key, other.type_name()),
+                ))
+            }
+        };
+    }
+    Ok(table)
+}
+
+fn insert(table: &mut Table, path: &[String], item: Item) -> PResult<()> {
+    let (last, parents) = path.split_last().unwrap();
+    let table = descend(table, parents, item.pos)?;
+    if let Some(prev) = table.get(last) {
+        return Err((
+            item.pos,
+            format!("duplicate key {:?}, previously at {}", last, prev.pos),
+        ));
+    }
+    table.insert(last.clone(), item);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Table {
+        Document::parse(text, "test").unwrap().root
+    }
+
+    #[test]
+    fn scalars() {
+        let root = parse(
+            r#"
+# comment
+a = "x\ty\u0041"  # trailing
+b = 'C:\path'
+c = -1_000
+d = true
+"e f" = false
+"#,
+        );
+        assert_eq!(root["a"].value.as_str(), Some("x\tyA"));
+        assert_eq!(root["b"].value.as_str(), Some("C:\\path"));
+        assert_eq!(root["c"].value.as_int(), Some(-1000));
+        assert_eq!(root["d"].value.as_bool(), Some(true));
+        assert_eq!(root["e f"].value.as_bool(), Some(false));
+        assert_eq!(root["a"].pos, Pos { line: 3, col: 5 });
+    }
+
+    #[test]
+    fn tables() {
+        let root = parse(
+            r#"
+top = 1
+[one.two]
+x = [ "a",
+  "b", # comment
+]
+y = { k = 1, j.l = "v" }
+[[arr]]
+n = 1
+[[arr]]
+n = 2
+"#,
+        );
+        let two = root["one"].value.as_table().unwrap()["two"]
+            .value
+            .as_table()
+            .unwrap();
+        assert_eq!(
+            two["x"].value,
+            Value::Array(vec![Value::Str("a".into()), Value::Str("b".into())])
+        );
+        let y = two["y"].value.as_table().unwrap();
+        assert_eq!(y["k"].value.as_int(), Some(1));
+        assert_eq!(
+            y["j"].value.as_table().unwrap()["l"].value.as_str(),
+            Some("v")
+        );
+
+        let arr = root["arr"].value.as_array().unwrap();
+        assert_eq!(arr.len(), 2);
+        assert_eq!(arr[1].as_table().unwrap()["n"].value.as_int(), Some(2));
+    }
+
+    #[test]
+    fn errors() {
+        for (text, pos) in [
+            ("a = ", "1:5"),
+            ("a = 1\na = 2", "2:5"),
+            ("a = \"x", "1:7"),
+            ("a = 1 b", "1:7"),
+            ("a = nope", "1:5"),
+            ("[a]\nb=1\n[a.b]", "3:1"),
+        ] {
+            let err = Document::parse(text, "test").unwrap_err().to_string();
+            assert!(err.contains(pos), "{:?} -> {}", text, err);
+        }
+    }
+
+    #[test]
+    fn json() {
+        let text = r#"{"a": "x\"y\u0041", "b": [1, -2, null, true],
+            "c": {"d": [], "e": {}}, "n": null}"#;
+        let doc = Document::parse_json(text, "test").unwrap();
+        let root = &doc.root;
+        assert_eq!(root["a"].value.as_str(), Some("x\"yA"));
+        assert_eq!(
+            root["b"].value,
+            Value::Array(vec![Value::Int(1), Value::Int(-2), Value::Bool(true)])
+        );
+        assert_eq!(root["c"].pos, Pos { line: 2, col: 18 });
+
+        let out = Value::Table(root.clone()).to_json();
+        assert_eq!(out, r#"{"a":"x\"yA","b":[1,-2,true],"c":{"d":[],"e":{}}}"#);
+        let again = Document::parse_json(&out, "test").unwrap();
+        assert_eq!(Value::Table(again.root).to_json(), out);
+
+        for (text, pos) in [
+            ("[]", "1:1"),
+            ("null", "1:1"),
+            ("{\"a\": 1,}", "1:9"),
+            ("{\"a\": nul}", "1:7"),
+            ("{} x", "1:4"),
+        ] {
+            let err = Document::parse_json(text, "test").unwrap_err().to_string();
+            assert!(err.contains(pos), "{:?} -> {}", text, err);
+        }
+    }
+
+    #[test]
+    fn interpolation() {
+        let lookup = |name: &str| match name {
+            "HOME" => Some("/home/${PWD}".to_string()),
+            "PWD" => Some("/work/$$".to_string()),
+            _ => None,
+        };
+        let expand = |text| interpolate(text, lookup);
+
+        assert_eq!(expand("/plain").unwrap(), "/plain");
+        assert_eq!(expand("${PWD}/src").unwrap(), "/work/$$/src");
+        assert_eq!(expand("a$$b$${HOME}").unwrap(), "a$b${HOME}");
+        // values are not expanded again
+        assert_eq!(expand("${HOME}").unwrap(), "/home/${PWD}");
+
+        for bad in ["$HOME", "${HOME", "${PATH}", "${XDG_RUNTIME_DIR}", "x$"] {
+            expand(bad).unwrap_err();
+        }
+
+        assert_eq!(lookup_env("run_id"), Some(id::current()));
+        assert_eq!(lookup_env("profile"), None);
+    }
+
+    #[test]
+    fn dates() {
+        let at = |secs| utc_date(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01-01");
+        assert_eq!(at(951782400), "2000-02-29");
+        assert_eq!(at(1709251199), "2024-02-29");
+        assert_eq!(at(1735689600), "2025-01-01");
+    }
+}
@@ -1,22 +1,40 @@
 //! Linux container (aka. namespace) management.
 //!
 //! Handles the double `fork()` needed to place a process into newly created namespaces.
+use std::any::Any;
+use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::error;
+use std::fs::File;
 use std::io::{self, Read, Write};
-use std::net;
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::process::{exit, Command};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use log::debug;
+use log::{debug, warn};
 
 use libc;
 
+use super::cgroup::{Cgroup, Resources};
+use super::fs::Mounts;
+use super::hook::{self, HookCmd, Stage};
 use super::proc::fork;
-use super::{err, ext, util};
+use super::procfs::ProcFs;
+use super::retry::{self, RetryPolicy};
+use super::seccomp::Filter;
+use super::stats::{Phase, SharedStats, Stats};
+use super::{err, ext, logging, util};
 
 pub use super::proc::Proc;
 
+pub mod oci;
+
 pub type Error = Box<dyn error::Error + 'static>;
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -27,45 +45,458 @@ pub type Result<T> = std::result::Result<T, Error>;
 /// ```text
 /// runc() \  # in parent process
 ///        |- ContainerHooks::at_start()
+///        |- Cgroup::create(ContainerHooks::cgroup())
 ///        |- fork() # create child process
+///        |  |- unshare(ContainerHooks::namespaces())
 ///        |  \- ContainerHooks::unshare()
 ///        |-- | - ContainerHooks::set_id_map()
 ///        |   |-- fork() # create grandchild process
 ///        |   |   \- ContainerHooks::setup_priv()
+///        |   |    |- Install ContainerHooks::seccomp()
 ///        |   |    |- Drop privilege
+///        |-- | -- | - Cgroup::attach()
+///        |-- | -- | - ContainerHooks::started()
+///        |-- | -- | - Stage::Prestart hook commands
 ///        |   |    |- ContainerHooks::setup()
 ///        |   |    \- execvpe()
+///        |-- | - ContainerHooks::ready() # after execvpe()
 ///        |   \- waitpid() # child waits for grandchild
-///        \- waitpid() # parent waits for child
+///        |- waitpid() # parent waits for child
+///        \- Stage::Poststop hook commands
 /// ```
+///
+/// Each method receives the `StageCtx` of the calling process.
 #[allow(unused_variables)]
 pub trait ContainerHooks {
+    /// `CLONE_NEW*` flags of namespaces to be created before `ContainerHooks::unshare()`.
+    /// Default none, leaving `ContainerHooks::unshare()` to make the call.
+    fn namespaces(&self) -> libc::c_int {
+        0
+    }
+    /// Scratch directory made available through `StageCtx::scratch_dir()`
+    fn scratch_dir(&self) -> Option<&Path> {
+        None
+    }
+    /// Kill the container if the process which called `runc()` exits first.
+    /// eg. when killed by `SIGKILL`.  Default true.
+    fn watchdog(&self) -> bool {
+        true
+    }
+    /// Retries of transiently failing setup operations.  cf. `retry::set_default()`
+    /// Default `None` leaves the process wide policy unchanged.
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        None
+    }
+    /// Limits applied to container process 1, and its descendants, through a transient
+    /// cgroup.  Removed when `runc()` returns.  cf. `cgroup::Cgroup::create()`
+    fn cgroup(&self) -> Option<&Resources> {
+        None
+    }
     /// Called in parent process before child is forked
-    fn at_start(&self) -> Result<()> {
+    fn at_start(&self, ctx: &StageCtx) -> Result<()> {
         Ok(())
     }
     /// Called from child process when time to unshare()
-    fn unshare(&self) -> Result<()> {
+    fn unshare(&self, ctx: &StageCtx) -> Result<()> {
+        Ok(())
+    }
+    /// Called from parent when time to set child uid/gid_map.  cf. `StageCtx::child()`
+    fn set_id_map(&self, ctx: &StageCtx) -> Result<()> {
         Ok(())
     }
-    /// Called from parent when time to set child uid/gid_map.
-    fn set_id_map(&self, pid: &Proc) -> Result<()> {
+    /// Called from parent, with privilege dropped, once the grandchild
+    /// (container process 1) has been created.
+    fn started(&self, ctx: &StageCtx, info: &ContainerInfo) -> Result<()> {
+        Ok(())
+    }
+    /// External commands run from parent, with privilege dropped.
+    /// The container PID is passed as `$SANDBOX_PID`.
+    fn hook_cmds(&self, stage: Stage) -> &[HookCmd] {
+        &[]
+    }
+    /// Called from parent, with privilege dropped, once setup is complete
+    /// and the container command has been executed.
+    fn ready(&self, ctx: &StageCtx) -> Result<()> {
         Ok(())
     }
     /// Called from grandchild with full privilege (all capabilities)
-    fn setup_priv(&self) -> Result<()> {
+    fn setup_priv(&self, ctx: &StageCtx) -> Result<()> {
         Ok(())
     }
-    /// Called from grandchild with final privilege (no capabilities)
-    fn setup(&self) -> Result<()> {
+    /// Syscall filter installed in the grandchild after `ContainerHooks::setup_priv()`,
+    /// and before `ContainerHooks::setup()`.  Inherited by the container command.
+    fn seccomp(&self) -> Option<&Filter> {
+        None
+    }
+    /// Capabilities kept by the grandchild after `ContainerHooks::setup_priv()`,
+    /// and passed on to the container command as ambient capabilities.
+    /// eg. `CAP_NET_RAW` in a new network namespace.  Default none.
+    fn keep_caps(&self) -> &[u32] {
+        &[]
+    }
+
+    /// Called from grandchild with final privilege (no capabilities, except `keep_caps()`)
+    fn setup(&self, ctx: &StageCtx) -> Result<()> {
         Ok(())
     }
 }
 
+/// State of one process of a container, passed to `ContainerHooks` methods.
+///
+/// Created by `runc()` in the parent and copied by each `fork()`.
+/// So changes made from the child and grandchild are not visible in the parent.
+pub struct StageCtx {
+    namespaces: libc::c_int,
+    watchdog: bool,
+    scratch: Option<PathBuf>,
+    child: Option<Proc>,
+    chan: Option<UnixStream>,
+    new_root: RefCell<Option<PathBuf>>,
+    resources: RefCell<Vec<Box<dyn Any>>>,
+    /// Write end of the readiness pipe.  Only in the grandchild.
+    ready: RefCell<Option<File>>,
+    cancel: CancelToken,
+    start: Instant,
+    stats: SharedStats,
+}
+
+impl StageCtx {
+    fn new<H: ContainerHooks>(hooks: &H, cancel: &CancelToken) -> Result<StageCtx> {
+        Ok(StageCtx {
+            namespaces: hooks.namespaces(),
+            watchdog: hooks.watchdog(),
+            scratch: hooks.scratch_dir().map(Path::to_path_buf),
+            child: None,
+            chan: None,
+            cancel: cancel.clone(),
+            new_root: RefCell::new(None),
+            resources: RefCell::new(vec![]),
+            ready: RefCell::new(None),
+            start: Instant::now(),
+            stats: SharedStats::new()?,
+        })
+    }
+
+    /// `CLONE_NEW*` flags from `ContainerHooks::namespaces()`
+    pub fn namespaces(&self) -> libc::c_int {
+        self.namespaces
+    }
+
+    /// From `ContainerHooks::scratch_dir()`
+    pub fn scratch_dir(&self) -> Option<&Path> {
+        self.scratch.as_deref()
+    }
+
+    /// The child process.  Only in the parent, once forked.
+    pub fn child(&self) -> Option<&Proc> {
+        self.child.as_ref()
+    }
+
+    /// Socket connecting the parent with the child and grandchild.
+    /// Free for use by hooks.  Not inherited by the container command.
+    pub fn channel(&self) -> Option<&UnixStream> {
+        self.chan.as_ref()
+    }
+
+    /// Where the new root file system is being prepared, if set by an earlier hook
+    pub fn new_root(&self) -> Option<PathBuf> {
+        self.new_root.borrow().clone()
+    }
+
+    pub fn set_new_root<P: Into<PathBuf>>(&self, root: P) {
+        *self.new_root.borrow_mut() = Some(root.into());
+    }
+
+    /// Mount points as currently seen by the calling process
+    pub fn mounts(&self) -> Result<Mounts> {
+        Ok(Mounts::current()?)
+    }
+
+    /// Hold a resource until this process exits or exec()s.
+    /// eg. a `net::Bridge` which would otherwise be torn down when dropped.
+    pub fn keep<R: Any>(&self, res: R) {
+        self.resources.borrow_mut().push(Box::new(res));
+    }
+
+    /// In `setup()`, the write end of the pipe whose closing, on `exec()`, reports that the
+    /// command has started.  For hooks which `exec()` the command in a child process, and
+    /// remain.  eg. as process 1.  Close it once forked, and write a byte from the child
+    /// if its `exec()` fails.
+    pub fn take_ready(&self) -> Option<File> {
+        self.ready.borrow_mut().take()
+    }
+
+    /// Number of resources being held
+    pub fn kept(&self) -> usize {
+        self.resources.borrow().len()
+    }
+
+    /// Record the duration of a startup phase.  Visible from all processes.
+    pub fn record(&self, phase: Phase, dur: Duration) {
+        self.stats.record(phase, dur);
+    }
+
+    /// Startup phase durations recorded so far
+    pub fn stats(&self) -> Stats {
+        self.stats.get()
+    }
+
+    /// Has this run been cancelled?  Only meaningful in the parent.
+    pub fn cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+}
+
+/// Abort a call to `runc_cancel()` from another thread.
+///
+/// Before `fork()` the run returns early.  Afterwards, the child and container
+/// process 1 are killed, poststop hooks are run, and resources released.
+/// In both cases `runc_cancel()` returns `Error::Cancelled`.
+/// Or `Error::Interrupted` when cancelled by a signal during setup.  cf. `runc()`
+///
+/// A token should only be used for one run.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<CancelInner>);
+
+#[derive(Debug, Default)]
+struct CancelInner {
+    cancelled: AtomicBool,
+    /// Signal which interrupted setup.  Zero if none.
+    signal: AtomicI32,
+    /// child and container process 1.  Zero when not running.
+    pids: [AtomicI32; 2],
+    /// Container process 1 once started, or 0 if the run ended first.  cf. `spawn()`
+    started: Mutex<Option<libc::pid_t>>,
+    started_cv: Condvar,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        Default::default()
+    }
+
+    /// Request cancellation.  Only sets a flag and calls `kill()`, so is safe to call
+    /// from a signal handler.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.kill();
+    }
+
+    /// Request cancellation once `timeout` has elapsed.  Acts as a deadline for the whole run.
+    pub fn cancel_after(&self, timeout: Duration) {
+        let token = self.clone();
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            token.cancel();
+        });
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// The signal which interrupted setup, if any
+    pub fn interrupted(&self) -> Option<libc::c_int> {
+        match self.0.signal.load(Ordering::SeqCst) {
+            0 => None,
+            sig => Some(sig),
+        }
+    }
+
+    /// Cancel on receipt of `sig`.  From a signal handler.
+    fn interrupt(&self, sig: libc::c_int) {
+        self.0.signal.store(sig, Ordering::SeqCst);
+        self.cancel();
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Box::new(err::Error::Cancelled))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Kill `pid` if cancelled, now or later.
+    fn watch(&self, slot: usize, pid: libc::pid_t) {
+        self.0.pids[slot].store(pid, Ordering::SeqCst);
+        if self.is_cancelled() {
+            self.kill();
+        }
+        if slot == 1 {
+            self.set_started(pid);
+        }
+    }
+
+    fn set_started(&self, pid: libc::pid_t) {
+        let mut started = self.0.started.lock().unwrap();
+        if started.is_none() {
+            *started = Some(pid);
+            self.0.started_cv.notify_all();
+        }
+    }
+
+    /// Wait until container process 1 is started.  0 if the run ended first.
+    fn wait_started(&self) -> libc::pid_t {
+        let mut started = self.0.started.lock().unwrap();
+        loop {
+            match *started {
+                Some(pid) => return pid,
+                None => started = self.0.started_cv.wait(started).unwrap(),
+            }
+        }
+    }
+
+    /// Send `sig` to container process 1, while running
+    fn signal(&self, sig: libc::c_int) -> io::Result<()> {
+        let pid = self.0.pids[1].load(Ordering::SeqCst);
+        if pid <= 0 {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        if unsafe { libc::kill(pid, sig) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn unwatch(&self) {
+        for pid in &self.0.pids {
+            pid.store(0, Ordering::SeqCst);
+        }
+    }
+
+    fn kill(&self) {
+        // container first, so that the child can not respawn it
+        for pid in self.0.pids.iter().rev() {
+            let pid = pid.load(Ordering::SeqCst);
+            if pid > 0 {
+                unsafe {
+                    libc::kill(pid, libc::SIGKILL);
+                }
+            }
+        }
+    }
+}
+
+/// Cancels a run on `SIGINT`, `SIGTERM`, or `SIGQUIT` while in setup.  Removed when dropped,
+/// before `Proc::park()` takes over relaying them to the container.
+struct SetupSignals(Vec<signal_hook::SigId>);
+
+impl SetupSignals {
+    fn install(cancel: &CancelToken) -> Result<SetupSignals> {
+        let caller = unsafe { libc::getpid() };
+        let mut guard = SetupSignals(vec![]);
+        for sig in [libc::SIGINT, libc::SIGTERM, libc::SIGQUIT] {
+            let token = cancel.clone();
+            // inherited by the child and grandchild, which the caller kills
+            let action = move || {
+                if unsafe { libc::getpid() } == caller {
+                    token.interrupt(sig);
+                }
+            };
+            let id = unsafe { signal_hook::low_level::register(sig, action) }
+                .map_err(|e| err::Error::os("Install signal handler", e))?;
+            guard.0.push(id);
+        }
+        Ok(guard)
+    }
+}
+
+impl Drop for SetupSignals {
+    fn drop(&mut self) {
+        for id in self.0.drain(..) {
+            signal_hook::low_level::unregister(id);
+        }
+    }
+}
+
+/// Namespaces listed under `/proc/<pid>/ns/`
+pub const NAMESPACES: &[&str] = &["cgroup", "ipc", "mnt", "net", "pid", "user", "uts"];
+
+const NS_FLAGS: &[(libc::c_int, &str)] = &[
+    (libc::CLONE_NEWCGROUP, "cgroup"),
+    (libc::CLONE_NEWIPC, "ipc"),
+    (libc::CLONE_NEWNS, "mnt"),
+    (libc::CLONE_NEWNET, "net"),
+    (libc::CLONE_NEWPID, "pid"),
+    (libc::CLONE_NEWUSER, "user"),
+    (libc::CLONE_NEWUTS, "uts"),
+];
+
+/// Names of the namespaces in a set of `CLONE_NEW*` flags.  eg. `["mnt", "net"]`
+pub fn namespace_names(flags: libc::c_int) -> Vec<&'static str> {
+    NS_FLAGS
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Identifies container process 1 as seen from the host
+#[derive(Debug, Clone)]
+pub struct ContainerInfo {
+    pid: libc::pid_t,
+}
+
+impl ContainerInfo {
+    /// Host PID of container process 1
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// Path of a namespace handle.  eg. `ns_path("net")` -> `/proc/<pid>/ns/net`
+    pub fn ns_path(&self, ns: &str) -> PathBuf {
+        PathBuf::from(format!("/proc/{}/ns/{}", self.pid, ns))
+    }
+
+    /// Handles for all namespaces of container process 1
+    pub fn namespaces(&self) -> Vec<(&'static str, PathBuf)> {
+        NAMESPACES
+            .iter()
+            .map(|ns| (*ns, self.ns_path(ns)))
+            .collect()
+    }
+
+    /// Inspect container process 1 from the host
+    pub fn procfs(&self) -> ProcFs {
+        ProcFs::new(self.pid)
+    }
+}
+
+/// Join the namespaces of process `pid`.  eg. of container process 1.
+/// The calling process must be single threaded.
+///
+/// The user namespace is joined first, then the others.
+/// The current PID namespace is not changed, only that of children created afterwards.
+/// Joining the mount namespace changes the root and working directories.
+pub fn join(pid: libc::pid_t) -> Result<()> {
+    let mut joins = vec![];
+    // open all before /proc changes
+    for (flag, ns) in NS_FLAGS {
+        let path = format!("/proc/{}/ns/{}", pid, ns);
+        let target = std::fs::metadata(&path).map_err(|e| err::Error::file("stat", &path, e))?;
+        let current = std::fs::metadata(format!("/proc/self/ns/{}", ns))?;
+        if (target.dev(), target.ino()) == (current.dev(), current.ino()) {
+            // joining the current user namespace fails
+            continue;
+        }
+        let file = File::open(&path).map_err(|e| err::Error::file("open", &path, e))?;
+        joins.push((*flag, *ns, file));
+    }
+    joins.sort_by_key(|(flag, _, _)| *flag != libc::CLONE_NEWUSER);
+    for (flag, ns, file) in joins {
+        debug!("Join {} namespace of {}", ns, pid);
+        util::setns(&file, flag)?;
+    }
+    Ok(())
+}
+
 fn handle_parent<H: ContainerHooks>(
     hooks: &H,
-    mut pid: Proc,
-    mut tochild: net::TcpStream,
+    mut ctx: StageCtx,
+    mut tochild: UnixStream,
+    cgroup: Option<Cgroup>,
+    signals: SetupSignals,
 ) -> Result<i32> {
     // wait for child to unshare()
     let mut msg = vec![0; 1];
@@ -79,7 +510,9 @@ fn handle_parent<H: ContainerHooks>(
     })?;
 
     if (msg[0] as char) == '.' {
-        hooks.set_id_map(&pid)?;
+        let start = Instant::now();
+        hooks.set_id_map(&ctx)?;
+        ctx.record(Phase::IdMap, start.elapsed());
         //.annotate("HOOK set_id_map")?;
         // notify child to proceed
         tochild.write_all(".".as_bytes())?;
@@ -87,19 +520,253 @@ fn handle_parent<H: ContainerHooks>(
         debug!("Child sent err msg {:?}", msg);
     }
 
-    debug!("Parent park");
+    // child reports the host PID of the grandchild.  EOF if child failed
+    let mut msg = [0; 4];
+    let info = match tochild.read_exact(&mut msg) {
+        Ok(()) => Some(ContainerInfo {
+            pid: libc::pid_t::from_ne_bytes(msg),
+        }),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            debug!("Child did not report container PID");
+            None
+        }
+        Err(err) => Err(err)?,
+    };
+    // before the grandchild proceeds past prestart, so the command runs with limits
+    if let (Some(cgroup), Some(info)) = (&cgroup, &info) {
+        cgroup.attach(info.pid)?;
+    }
+
     // drop SUID-ness
     util::setegid(util::getgid())?;
     util::seteuid(util::getuid())?;
     util::Cap::current()?.clear().update()?;
+
+    if let Some(info) = &info {
+        debug!("Container PID {}", info.pid);
+        ctx.cancel.watch(1, info.pid);
+        hooks.started(&ctx, info)?;
+        if ctx.cancel.is_cancelled() {
+            debug!("Cancelled before prestart");
+        } else if let Err(err) = hook::run_all(
+            hooks.hook_cmds(Stage::Prestart),
+            Stage::Prestart,
+            &[("SANDBOX_PID", info.pid.to_string())],
+        ) {
+            // grandchild exits before setup()
+            if let Err(err) = tochild.write_all(b"X") {
+                debug!("Child gone before prestart failed : {}", err);
+            }
+            drop(tochild);
+            drop(signals);
+            let code = ctx.child.as_mut().expect("child").park();
+            ctx.cancel.unwatch();
+            // undo what any earlier prestart hook did
+            run_poststop(hooks, info, code.unwrap_or(-1));
+            return Err(err.into());
+        }
+
+        // allow grandchild to proceed with setup()
+        let start = Instant::now();
+        if let Err(err) = tochild.write_all(b".") {
+            debug!("Child gone before prestart complete : {}", err);
+        }
+
+        // wait for grandchild to exec()
+        let mut msg = [0; 1];
+        match tochild.read(&mut msg) {
+            Ok(1) if msg[0] == b'R' => {
+                ctx.record(Phase::Exec, start.elapsed());
+                ctx.record(Phase::Total, ctx.start.elapsed());
+                debug!("Container ready {}", ctx.stats().to_json());
+                hooks.ready(&ctx)?;
+            }
+            Ok(_) => debug!("Container not ready {:?}", msg),
+            // eg. reset when cancelled
+            Err(err) => debug!("Container not ready : {}", err),
+        }
+    }
+    drop(tochild);
+    drop(signals);
+    if let Some(sig) = ctx.cancel.interrupted() {
+        debug!("Interrupted during setup by signal {}", sig);
+    }
+
+    debug!("Parent park");
     // wait for child to exit
-    Ok(pid.park()?)
+    let code = ctx.child.as_mut().expect("child").park();
+    // reaped, so PIDs may be reused
+    ctx.cancel.unwatch();
+    let code = code?;
+
+    if let Some(info) = &info {
+        run_poststop(hooks, info, code);
+    }
+    ctx.cancel.check()?;
+    Ok(code)
+}
+
+/// Failures of poststop hooks are only logged
+fn run_poststop<H: ContainerHooks>(hooks: &H, info: &ContainerInfo, code: i32) {
+    if let Err(err) = hook::run_all(
+        hooks.hook_cmds(Stage::Poststop),
+        Stage::Poststop,
+        &[
+            ("SANDBOX_PID", info.pid.to_string()),
+            ("SANDBOX_EXIT", code.to_string()),
+        ],
+    ) {
+        warn!("{}", err);
+    }
+}
+
+/// As `runc()`, without blocking.  `hooks` are called from a new thread.
+/// Returns once container process 1 is started, or the run has failed.
+/// Privilege of the calling process is dropped, as by `runc()`.
+///
+/// ```no_run
+/// # use sandbox::{spawn, ContainerHooks};
+/// struct Service;
+/// impl ContainerHooks for Service {}
+///
+/// let mut handle = spawn(Service).unwrap();
+/// println!("Running as {}", handle.pid());
+/// if handle.try_wait().unwrap().is_none() {
+///     handle.signal(libc::SIGTERM).unwrap();
+/// }
+/// // or handle.kill()
+/// let code = handle.wait().unwrap();
+/// ```
+pub fn spawn<H: ContainerHooks + Send + 'static>(hooks: H) -> Result<SandboxHandle> {
+    let cancel = CancelToken::new();
+    let token = cancel.clone();
+    let thread = thread::Builder::new()
+        .name("sandbox".to_string())
+        .spawn(move || {
+            // Error is not Send
+            let ret = runc_cancel(&hooks, &token).map_err(|e| e.to_string());
+            token.set_started(0);
+            ret
+        })?;
+    let ret = SandboxHandle {
+        pid: cancel.wait_started(),
+        cancel,
+        thread: Some(thread),
+        code: None,
+    };
+    if ret.pid == 0 {
+        // never started.  Report why.
+        ret.wait()?;
+        return Err(Box::new(err::Error::os(
+            "Container not started",
+            io::Error::from_raw_os_error(libc::ECHILD),
+        )));
+    }
+    Ok(ret)
+}
+
+/// A container started by `spawn()`.  Killed, and waited for, when dropped.
+#[derive(Debug)]
+pub struct SandboxHandle {
+    pid: libc::pid_t,
+    cancel: CancelToken,
+    thread: Option<thread::JoinHandle<std::result::Result<i32, String>>>,
+    code: Option<i32>,
+}
+
+impl SandboxHandle {
+    /// Host PID of container process 1
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// The exit code, if the container has exited.  As returned by `runc()`.
+    pub fn try_wait(&mut self) -> Result<Option<i32>> {
+        match &self.thread {
+            Some(thread) if !thread.is_finished() => Ok(None),
+            _ => self.join().map(Some),
+        }
+    }
+
+    /// Wait for the container to exit.  Returns the exit code, as `runc()`.
+    pub fn wait(mut self) -> Result<i32> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<i32> {
+        if let Some(thread) = self.thread.take() {
+            let ret = thread
+                .join()
+                .map_err(|_| err::Error::os("sandbox thread panic", io::ErrorKind::Other.into()))?;
+            self.code = Some(ret?);
+        }
+        self.code
+            .ok_or_else(|| Box::new(io::Error::from_raw_os_error(libc::ECHILD)) as Error)
+    }
+
+    /// Send `sig` to container process 1.  `ESRCH` once it has been reaped.
+    pub fn signal(&self, sig: libc::c_int) -> Result<()> {
+        Ok(self.cancel.signal(sig)?)
+    }
+
+    /// Kill container process 1, and so the container.  Returns without waiting.
+    pub fn kill(&self) -> Result<()> {
+        self.signal(libc::SIGKILL)
+    }
+}
+
+impl Drop for SandboxHandle {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.cancel.cancel();
+            let _ = self.join();
+        }
+    }
 }
 
-fn handle_child<H: ContainerHooks>(hooks: &H, toparent: RawFd) -> Result<()> {
-    let mut toparent = unsafe { net::TcpStream::from_raw_fd(toparent) };
-    hooks
-        .unshare()
+/// A pidfd for process `parent`, which becomes readable when it exits.
+/// `None` if not supported (before Linux 5.3), or if `parent` is already gone.
+///
+/// Backs up `PR_SET_PDEATHSIG`, which is cleared by a change of effective UID,
+/// and follows the parent thread rather than the parent process.
+fn watch_parent(parent: libc::pid_t) -> Result<Option<OwnedFd>> {
+    let pidfd = match util::pidfd_open(parent) {
+        Ok(fd) => fd,
+        Err(err) if err.errno() == Some(err::Errno::ENOSYS) => {
+            debug!("No parent watchdog : {}", err);
+            return Ok(None);
+        }
+        Err(err) if err.errno() == Some(err::Errno::ESRCH) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    // PID may have been reused
+    if unsafe { libc::getppid() } != parent {
+        return Ok(None);
+    }
+    Ok(Some(pidfd))
+}
+
+fn handle_child<H: ContainerHooks>(
+    hooks: &H,
+    ctx: &StageCtx,
+    toparent: RawFd,
+    parent: libc::pid_t,
+) -> Result<()> {
+    let mut toparent = unsafe { UnixStream::from_raw_fd(toparent) };
+    if ctx.watchdog {
+        util::set_pdeathsig(libc::SIGKILL)?;
+        if unsafe { libc::getppid() } != parent {
+            // already gone
+            exit(1);
+        }
+    }
+    let start = Instant::now();
+    let ret = if ctx.namespaces != 0 {
+        util::unshare(ctx.namespaces).map_err(Error::from)
+    } else {
+        Ok(())
+    };
+    ret.and_then(|_| hooks.unshare(ctx))
         //.annotate("HOOK unshare()")
         .or_else(|err| {
             if let Some(_err) = err
@@ -120,13 +787,15 @@ fn handle_child<H: ContainerHooks>(hooks: &H, toparent: RawFd) -> Result<()> {
             Err(err)
         })?;
 
+    ctx.record(Phase::Unshare, start.elapsed());
+
     // ask parent to setup uid/gid maps
     toparent.write_all(".".as_bytes())?;
 
     // wait for parent
     let mut msg = vec![0; 1];
+    // kept to report grandchild PID.  Not into the container, as SOCK_CLOEXEC
     toparent.read_exact(&mut msg)?;
-    drop(toparent);
     debug!("child continue");
     debug!(
         "Child Perms uid {},{} gid {},{}",
@@ -137,19 +806,64 @@ fn handle_child<H: ContainerHooks>(hooks: &H, toparent: RawFd) -> Result<()> {
     );
     debug!("Cap {}", util::Cap::current()?);
 
-    let mut pid = fork(|| handle_grandchild(hooks))?;
+    // grandchild will pass back the read end of a pipe which is closed on exec()
+    let (mut tograndchild, tograndparent) = UnixStream::pair()?;
+
+    let mut pid = fork(|| handle_grandchild(hooks, ctx, tograndparent))?;
 
     debug!("Forked Grandchild {}", pid);
+    // still in the parent PID namespace, so this is the host PID
+    toparent.write_all(&pid.id().to_ne_bytes())?;
+
+    let ready = if let Some(fd) = util::recv_fd(&tograndchild)? {
+        // relay go-ahead from parent after prestart hooks
+        let mut msg = [0; 1];
+        if toparent.read(&mut msg)? == 1 {
+            tograndchild.write_all(&msg)?;
+        }
+        drop(tograndchild);
+
+        // blocks until exec() closes the write end, or error is reported
+        let mut msg = vec![];
+        File::from(fd).read_to_end(&mut msg)?;
+        msg.is_empty()
+    } else {
+        drop(tograndchild);
+        false
+    };
+    toparent.write_all(if ready { b"R" } else { b"X" })?;
+    drop(toparent);
+
     debug!("Child park");
     // drop SUID-ness
     util::setegid(util::getgid())?;
     util::seteuid(util::getuid())?;
     util::Cap::current()?.clear().update()?;
+    let watch = if ctx.watchdog {
+        let watch = watch_parent(parent)?;
+        if watch.is_some() {
+            // the pidfd is watched instead, so that process 1 is killed first
+            util::set_pdeathsig(0)?;
+        } else if unsafe { libc::getppid() } != parent {
+            warn!("Parent {} exited.  Kill container", parent);
+            pid.kill()?;
+        } else {
+            // cleared by change of effective UID
+            util::set_pdeathsig(libc::SIGKILL)?;
+        }
+        watch
+    } else {
+        None
+    };
     // wait for child to exit
-    exit(pid.park()?);
+    exit(pid.park_watch(watch.as_ref().map(|fd| fd.as_fd()))?);
 }
 
-fn handle_grandchild<H: ContainerHooks>(hooks: &H) -> Result<()> {
+fn handle_grandchild<H: ContainerHooks>(
+    hooks: &H,
+    ctx: &StageCtx,
+    mut tograndparent: UnixStream,
+) -> Result<()> {
     debug!("Grandchild");
 
     debug!(
@@ -177,11 +891,43 @@ fn handle_grandchild<H: ContainerHooks>(hooks: &H) -> Result<()> {
     );
     debug!("Cap {}", util::Cap::current()?);
 
-    hooks.setup_priv()?;
+    // own session and process group, so that signals can reach all sandboxed processes.
+    // Except when interactive, as the terminal would then be lost.
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+        util::setsid()?;
+    }
 
-    // drop all capabilities, effective, permitted, and inheritable
-    util::Cap::current()?.clear().update()?;
-    debug!("Drop caps");
+    let start = Instant::now();
+    hooks.setup_priv(ctx)?;
+    ctx.record(Phase::Mounts, start.elapsed());
+
+    if let Some(filter) = hooks.seccomp() {
+        // without CAP_SYS_ADMIN.  eg. no user namespace, and not root
+        if !util::Cap::current()?.effective(ext::CAP_SYS_ADMIN) {
+            util::set_no_new_privs()?;
+        }
+        filter.install()?;
+    }
+
+    // drop all capabilities, effective, permitted, and inheritable.  Except any kept.
+    let mut caps = util::Cap::current()?;
+    caps.clear();
+    for cap in hooks.keep_caps() {
+        caps.set_effective(*cap, true)
+            .set_permitted(*cap, true)
+            .set_inheritable(*cap, true);
+    }
+    caps.update()?;
+    for cap in hooks.keep_caps() {
+        util::raise_ambient(*cap)?;
+    }
+    debug!("Drop caps, keeping {:?}", hooks.keep_caps());
+    if ctx.watchdog {
+        // after the last change of effective UID.  Process 1 takes all others with it.
+        // If the child is already gone, reading from it will fail below.
+        // Cleared if exec() gains privilege.  eg. as root
+        util::set_pdeathsig(libc::SIGKILL)?;
+    }
     debug!(
         "Final Perms uid {},{} gid {},{}",
         util::getuid(),
@@ -191,27 +937,153 @@ fn handle_grandchild<H: ContainerHooks>(hooks: &H) -> Result<()> {
     );
     debug!("Cap {}", util::Cap::current()?);
 
-    hooks.setup()?;
+    // created after setup_priv() so that any helper processes do not hold the write end
+    let (rx, tx) = util::pipe()?;
+    util::send_fd(&tograndparent, &rx)?;
+    drop(rx);
+
+    // wait for prestart hooks
+    let mut msg = [0; 1];
+    if tograndparent.read(&mut msg)? != 1 {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Parent gone before prestart complete",
+        )));
+    } else if msg[0] != b'.' {
+        return Err("Prestart hook failed".into());
+    }
+    drop(tograndparent);
+    *ctx.ready.borrow_mut() = Some(tx);
+
+    if let Err(err) = hooks.setup(ctx) {
+        // exec() failed
+        if let Some(mut tx) = ctx.take_ready() {
+            let _ = tx.write_all(b"X");
+        }
+        return Err(err);
+    }
     Ok(())
 }
 
 /// Launch container with given hooks.  Blocks until container process 1 exits.
 /// Returns with container process 1 exit code.
-/// May be interrupted by `SIGINT`.
+///
+/// `SIGINT`, `SIGTERM`, or `SIGQUIT` before container process 1 exec()s cancel the run,
+/// which returns `Error::Interrupted` once partial resources are released.
+/// Afterwards, they are relayed to container process 1.
 pub fn runc<H: ContainerHooks>(hooks: &H) -> Result<i32> {
+    runc_cancel(hooks, &CancelToken::new())
+}
+
+/// As `runc()`, which may also be aborted through a `CancelToken`.
+///
+/// On error, recent log records are printed.  cf. `logging::dump_recent()`
+/// Except when interrupted.
+pub fn runc_cancel<H: ContainerHooks>(hooks: &H, cancel: &CancelToken) -> Result<i32> {
+    match (run_container(hooks, cancel), cancel.interrupted()) {
+        (Err(_), Some(sig)) => Err(Box::new(err::Error::Interrupted(sig))),
+        (Err(err), None) => {
+            logging::dump_recent();
+            Err(err)
+        }
+        (ret, _) => ret,
+    }
+}
+
+fn run_container<H: ContainerHooks>(hooks: &H, cancel: &CancelToken) -> Result<i32> {
     // communications between parent and child to coordinate SetIdMap()
 
-    hooks.at_start()?;
+    if let Some(policy) = hooks.retry_policy() {
+        retry::set_default(policy);
+    }
+    let mut ctx = StageCtx::new(hooks, cancel)?;
+    let signals = SetupSignals::install(cancel)?;
+    cancel.check()?;
+    hooks.at_start(&ctx)?;
     //.annotate("HOOK at_start()")?;
+    cancel.check()?;
+    let cgroup = match hooks.cgroup() {
+        Some(res) => {
+            let mut cgroup = Cgroup::create(res)?;
+            // privilege is dropped before removal
+            cgroup.remove_from_helper()?;
+            Some(cgroup)
+        }
+        None => None,
+    };
 
     let (parent, child) = util::socketpair()?;
     let child_fd = child.as_raw_fd();
 
-    let pid = fork(|| handle_child(hooks, child_fd))?;
+    // hook channel.  closed on exec()
+    let (pchan, cchan) = UnixStream::pair()?;
+    ctx.chan = Some(cchan);
+
+    let caller = unsafe { libc::getpid() };
+    let start = Instant::now();
+    let parent_fd = parent.as_raw_fd();
+    let pid = fork(|| {
+        // otherwise the child would never see EOF should the parent exit.  eg. when detached
+        unsafe { libc::close(parent_fd) };
+        handle_child(hooks, &ctx, child_fd, caller)
+    })?;
+    ctx.record(Phase::Fork, start.elapsed());
 
     drop(child);
     debug!("Forked Child {}", pid);
-    handle_parent(hooks, pid, parent)
+    cancel.watch(0, pid.id());
+    ctx.chan = Some(pchan);
+    ctx.child = Some(pid);
+    handle_parent(hooks, ctx, parent, cgroup, signals)
+}
+
+/// Move the calling process into a new user namespace, as `uid` and `gid` there, which
+/// begin the `uids` and `gids` of the parent namespace.  eg. as uid 0.  For a privileged
+/// process (eg. from `ContainerHooks::setup_priv()`) to run a command without privilege
+/// on the host.  Capabilities are afterwards only those of the new namespace, and
+/// supplementary groups are cleared.
+///
+/// Needs `CAP_SETUID` and `CAP_SETGID`, and a single threaded caller.
+pub fn enter_user_ns(uid: u32, gid: u32, uids: util::IdRange, gids: util::IdRange) -> Result<()> {
+    // the helper writes the maps of this process through a directory fd,
+    // whichever PID namespace the current /proc shows
+    let this = File::open("/proc/self").map_err(|e| err::Error::file("open", "/proc/self", e))?;
+    let dir = format!("/proc/self/fd/{}", this.as_raw_fd());
+    let (ready, wait) = util::socketpair()?;
+    let mut helper = fork(|| -> Result<()> {
+        let mut buf = [0u8; 1];
+        (&wait).read_exact(&mut buf)?;
+        for (name, id, ids) in [("uid_map", uid, uids), ("gid_map", gid, gids)] {
+            util::overwrite_file(
+                format!("{}/{}", dir, name),
+                format!("{} {} {}\n", id, ids.start, ids.count),
+            )?;
+        }
+        Ok(())
+    })?;
+    drop(wait);
+
+    debug!(
+        "Enter user namespace as uid {} gid {}, which are {} {}",
+        uid, gid, uids.start, gids.start
+    );
+    let ret = util::unshare(libc::CLONE_NEWUSER);
+    if ret.is_ok() {
+        (&ready).write_all(b"!")?;
+    }
+    // EOF releases the helper on failure
+    drop(ready);
+    let code = helper.park()?;
+    ret?;
+    if code != 0 {
+        return Err(err::Error::UIDMap.into());
+    }
+
+    // until now, IDs of this process appear as the overflow IDs
+    util::setgroups(&[])?;
+    util::setgid(gid)?;
+    util::setuid(uid)?;
+    Ok(())
 }
 
 /// Helper for setting up UID and GID mappings for a new user namespace.
@@ -279,13 +1151,13 @@ impl IdMap {
 
         if self.isuid && caps.effective(ext::CAP_SETUID) {
             // directly write uid_map
-            util::write_file(
+            util::overwrite_file(
                 format!("/proc/{}/uid_map", self.pid),
                 self.map_file().as_bytes(),
             )?;
         } else if !self.isuid && caps.effective(ext::CAP_SETGID) {
             // directly write gid_map
-            util::write_file(
+            util::overwrite_file(
                 format!("/proc/{}/gid_map", self.pid),
                 self.map_file().as_bytes(),
             )?;
@@ -310,10 +1182,9 @@ impl IdMap {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::cell::RefCell;
-    use std::net::TcpStream;
+    use std::cell::{Cell, RefCell};
 
-    struct TestHooks(RefCell<TcpStream>);
+    struct TestHooks(RefCell<UnixStream>, Cell<libc::pid_t>);
 
     impl TestHooks {
         fn at(&self, pos: &str) {
@@ -325,23 +1196,35 @@ mod tests {
     }
 
     impl ContainerHooks for TestHooks {
-        fn at_start(&self) -> Result<()> {
+        fn at_start(&self, ctx: &StageCtx) -> Result<()> {
+            assert!(ctx.child().is_none());
             self.at("A");
             Ok(())
         }
-        fn unshare(&self) -> Result<()> {
+        fn unshare(&self, _ctx: &StageCtx) -> Result<()> {
             self.at("B");
             Ok(())
         }
-        fn set_id_map(&self, _pid: &Proc) -> Result<()> {
+        fn set_id_map(&self, ctx: &StageCtx) -> Result<()> {
+            assert!(ctx.child().is_some());
             self.at("C");
             Ok(())
         }
-        fn setup_priv(&self) -> Result<()> {
+        fn started(&self, _ctx: &StageCtx, info: &ContainerInfo) -> Result<()> {
+            // in parent process, concurrent with grandchild
+            self.1.set(info.pid());
+            Ok(())
+        }
+        fn ready(&self, _ctx: &StageCtx) -> Result<()> {
+            // in parent process, after grandchild setup()
+            self.at("F");
+            Ok(())
+        }
+        fn setup_priv(&self, _ctx: &StageCtx) -> Result<()> {
             self.at("D");
             Ok(())
         }
-        fn setup(&self) -> Result<()> {
+        fn setup(&self, _ctx: &StageCtx) -> Result<()> {
             self.at("E");
             Ok(())
         }
@@ -351,12 +1234,368 @@ mod tests {
     fn lifecycle() {
         let (mut me, dut) = util::socketpair().expect("socketpair");
 
-        runc(&TestHooks(RefCell::new(dut))).expect("runc");
+        let hooks = TestHooks(RefCell::new(dut), Cell::new(0));
+        runc(&hooks).expect("runc");
         //me.set_nonblocking(true).unwrap();
+        drop(hooks);
 
         let mut result = String::new();
         me.read_to_string(&mut result).expect("Read results");
-        assert_eq!(result, "ABCDE");
+        assert_eq!(result, "ABCDEF");
+    }
+
+    #[test]
+    fn started_pid() {
+        let (_me, dut) = util::socketpair().expect("socketpair");
+
+        let hooks = TestHooks(RefCell::new(dut), Cell::new(0));
+        runc(&hooks).expect("runc");
+
+        let pid = hooks.1.get();
+        assert!(pid > 0, "{}", pid);
+        assert!(pid != std::process::id() as libc::pid_t);
+    }
+
+    struct Forever(RefCell<Option<File>>);
+
+    impl ContainerHooks for Forever {
+        fn started(&self, _ctx: &StageCtx, info: &ContainerInfo) -> Result<()> {
+            if let Some(mut tx) = self.0.borrow_mut().take() {
+                tx.write_all(&info.pid().to_ne_bytes())?;
+            }
+            Ok(())
+        }
+        fn setup(&self, _ctx: &StageCtx) -> Result<()> {
+            loop {
+                unsafe { libc::pause() };
+            }
+        }
+    }
+
+    #[test]
+    fn watchdog() {
+        let (mut rx, tx) = util::pipe().unwrap();
+        let hooks = Forever(RefCell::new(Some(tx)));
+        let mut caller = fork(|| runc(&hooks).map(|_| ())).unwrap();
+        drop(hooks);
+
+        let mut buf = [0u8; 4];
+        rx.read_exact(&mut buf).unwrap();
+        let pid = libc::pid_t::from_ne_bytes(buf);
+
+        caller.kill().unwrap();
+        caller.park().unwrap();
+        for _ in 0..5000 {
+            match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+                Err(_) => return,
+                Ok(stat) if stat.rsplit(") ").next().unwrap_or("").starts_with('Z') => return,
+                Ok(_) => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        panic!("container process {} outlived caller", pid);
+    }
+
+    struct CmdHooks(Vec<HookCmd>, Vec<HookCmd>);
+
+    impl ContainerHooks for CmdHooks {
+        fn hook_cmds(&self, stage: Stage) -> &[HookCmd] {
+            match stage {
+                Stage::Prestart => &self.0,
+                Stage::Poststop => &self.1,
+            }
+        }
+        fn setup(&self, _ctx: &StageCtx) -> Result<()> {
+            exit(3);
+        }
+    }
+
+    #[test]
+    fn hook_commands() {
+        let tdir = crate::tempdir::TempDir::new().unwrap();
+        let out = tdir.path().join("out");
+        let sh = |script: String| HookCmd {
+            path: "/bin/sh".into(),
+            args: vec!["sh".into(), "-c".into(), script],
+            ..Default::default()
+        };
+        let hooks = CmdHooks(
+            vec![sh(format!(
+                "echo $SANDBOX_STAGE $SANDBOX_PID >> {}",
+                out.display()
+            ))],
+            vec![sh(format!(
+                "echo $SANDBOX_STAGE $SANDBOX_EXIT >> {}",
+                out.display()
+            ))],
+        );
+        assert_eq!(runc(&hooks).expect("runc"), 3);
+
+        let result = std::fs::read_to_string(&out).unwrap();
+        let lines: Vec<&str> = result.lines().collect();
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(lines[0].starts_with("prestart "), "{:?}", lines);
+        assert_eq!(lines[1], "poststop 3");
+    }
+
+    #[test]
+    fn hook_prestart_failed() {
+        let tdir = crate::tempdir::TempDir::new().unwrap();
+        let out = tdir.path().join("out");
+        let sh = |script: String| HookCmd {
+            path: "/bin/sh".into(),
+            args: vec!["sh".into(), "-c".into(), script],
+            ..Default::default()
+        };
+        let hooks = CmdHooks(
+            vec![
+                sh(format!("echo $SANDBOX_STAGE >> {}", out.display())),
+                sh("exit 1".into()),
+            ],
+            vec![sh(format!(
+                "echo $SANDBOX_STAGE $SANDBOX_EXIT >> {}",
+                out.display()
+            ))],
+        );
+        runc(&hooks).unwrap_err();
+
+        // undone, and setup() not run
+        let result = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(result, "prestart\npoststop 1\n");
+    }
+
+    struct Pause;
+
+    impl ContainerHooks for Pause {
+        fn setup(&self, _ctx: &StageCtx) -> Result<()> {
+            loop {
+                unsafe { libc::pause() };
+            }
+        }
+    }
+
+    #[test]
+    fn spawned() {
+        let mut handle = spawn(Pause).unwrap();
+        let pid = handle.pid();
+        assert!(pid > 0 && pid != std::process::id() as libc::pid_t);
+        assert_eq!(handle.try_wait().unwrap(), None);
+        handle.signal(0).unwrap();
+        handle.kill().unwrap();
+        handle.wait().unwrap();
+
+        let mut handle = spawn(CmdHooks(vec![], vec![])).unwrap();
+        let code = loop {
+            match handle.try_wait().unwrap() {
+                Some(code) => break code,
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        };
+        assert_eq!(code, 3);
+        assert!(handle.signal(0).is_err());
+
+        // killed when dropped
+        let handle = spawn(Pause).unwrap();
+        let pid = handle.pid();
+        drop(handle);
+        // reaped by init, eventually
+        if let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            assert!(stat.rsplit(") ").next().unwrap().starts_with('Z'));
+        }
+    }
+
+    #[derive(Default)]
+    struct CtxHooks(RefCell<Vec<u8>>);
+
+    impl ContainerHooks for CtxHooks {
+        fn scratch_dir(&self) -> Option<&Path> {
+            Some(Path::new("/nonexistent"))
+        }
+        fn setup_priv(&self, ctx: &StageCtx) -> Result<()> {
+            assert_eq!(ctx.scratch_dir(), Some(Path::new("/nonexistent")));
+            ctx.set_new_root("/new");
+            ctx.keep(42u32);
+            Ok(())
+        }
+        fn setup(&self, ctx: &StageCtx) -> Result<()> {
+            assert_eq!(ctx.new_root(), Some(PathBuf::from("/new")));
+            assert_eq!(ctx.kept(), 1);
+            ctx.channel().unwrap().write_all(b"hello")?;
+            Ok(())
+        }
+        fn ready(&self, ctx: &StageCtx) -> Result<()> {
+            let stats = ctx.stats();
+            for phase in [Phase::Fork, Phase::Unshare, Phase::Mounts, Phase::Total] {
+                assert!(stats.get(phase).is_some(), "{:?}", phase);
+            }
+            assert!(stats.get(Phase::Pivot).is_none());
+            let mut msg = vec![0; 5];
+            ctx.channel().unwrap().read_exact(&mut msg)?;
+            *self.0.borrow_mut() = msg;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stage_ctx() {
+        let hooks = CtxHooks::default();
+        assert_eq!(runc(&hooks).expect("runc"), 0);
+        assert_eq!(&*hooks.0.borrow(), b"hello");
+    }
+
+    struct SlowHooks;
+
+    impl ContainerHooks for SlowHooks {
+        fn setup(&self, _ctx: &StageCtx) -> Result<()> {
+            thread::sleep(Duration::from_secs(10));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn cancel() {
+        let token = CancelToken::new();
+        token.cancel();
+        let err = runc_cancel(&SlowHooks, &token).unwrap_err();
+        assert_eq!(err.to_string(), "Cancelled");
+
+        let token = CancelToken::new();
+        token.cancel_after(Duration::from_millis(100));
+        let start = std::time::Instant::now();
+        let err = runc_cancel(&SlowHooks, &token).unwrap_err();
+        assert_eq!(err.to_string(), "Cancelled");
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn interrupted() {
+        let mut pid = fork(|| -> Result<()> {
+            let err = runc(&SlowHooks).unwrap_err();
+            if !matches!(
+                err.downcast_ref(),
+                Some(err::Error::Interrupted(libc::SIGINT))
+            ) {
+                return Err(format!("unexpected {}", err).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        thread::sleep(Duration::from_millis(500));
+        let start = std::time::Instant::now();
+        pid.signal(libc::SIGINT).unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    struct CgroupHooks(Resources);
+
+    impl ContainerHooks for CgroupHooks {
+        fn cgroup(&self) -> Option<&Resources> {
+            Some(&self.0)
+        }
+        fn setup(&self, _ctx: &StageCtx) -> Result<()> {
+            let cg = std::fs::read_to_string("/proc/self/cgroup")?;
+            exit(if cg.contains("/sandbox-") { 0 } else { 3 });
+        }
+    }
+
+    #[test]
+    fn cgroup() {
+        if crate::cgroup::mount_point().is_err() {
+            return;
+        }
+        let before = std::fs::read_to_string("/proc/self/cgroup").unwrap();
+        match runc(&CgroupHooks(Resources::default())) {
+            Ok(code) => assert_eq!(code, 0),
+            // eg. not delegated
+            Err(err) => eprintln!("Skip : {}", err),
+        }
+        assert_eq!(
+            std::fs::read_to_string("/proc/self/cgroup").unwrap(),
+            before
+        );
+    }
+
+    /// In a user namespace, where the caller is mapped, or none
+    struct SeccompHooks(Filter, bool);
+
+    impl ContainerHooks for SeccompHooks {
+        fn namespaces(&self) -> libc::c_int {
+            if self.1 {
+                libc::CLONE_NEWUSER
+            } else {
+                0
+            }
+        }
+        fn set_id_map(&self, ctx: &StageCtx) -> Result<()> {
+            if let (true, Some(pid)) = (self.1, ctx.child()) {
+                let (uid, gid) = (util::getuid(), util::getgid());
+                IdMap::new_uid(pid.id()).add(uid, uid, 1).write()?;
+                IdMap::new_gid(pid.id()).add(gid, gid, 1).write()?;
+            }
+            Ok(())
+        }
+        fn seccomp(&self) -> Option<&Filter> {
+            Some(&self.0)
+        }
+        fn setup(&self, _ctx: &StageCtx) -> Result<()> {
+            // KEYCTL_GET_KEYRING_ID of KEY_SPEC_SESSION_KEYRING
+            let ret = unsafe { libc::syscall(libc::SYS_keyctl, 0, -3, 0) };
+            let errno = io::Error::last_os_error().raw_os_error();
+            exit(if ret == -1 && errno == Some(libc::EDOM) {
+                0
+            } else {
+                3
+            });
+        }
+    }
+
+    #[test]
+    fn seccomp() {
+        if !crate::seccomp::SUPPORTED {
+            return;
+        }
+        let mut filter = Filter::new();
+        filter.deny(libc::SYS_keyctl, libc::EDOM);
+        // with CAP_SYS_ADMIN in the user namespace
+        if crate::testing::require_userns() {
+            let hooks = SeccompHooks(filter.clone(), true);
+            assert_eq!(runc(&hooks).expect("runc"), 0);
+        }
+        // unless root, through no_new_privs
+        assert_eq!(runc(&SeccompHooks(filter, false)).expect("runc"), 0);
+    }
+
+    #[test]
+    fn enter_user_ns_unprivileged() {
+        if !util::Cap::current().unwrap().effective(ext::CAP_SETUID) {
+            return;
+        }
+        let mut pid = fork(|| -> Result<()> {
+            enter_user_ns(0, 0, util::NOBODY, util::NOBODY)?;
+            let map = std::fs::read_to_string("/proc/self/uid_map")?;
+            let fields: Vec<&str> = map.split_whitespace().collect();
+            // the host root is not mapped, so no other ID may be taken
+            if fields != ["0", "65534", "1"]
+                || (util::getuid(), util::getgid()) != (0, 0)
+                || util::setuid(1).is_ok()
+            {
+                return Err(format!("unexpected uid_map {:?}", map).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn ns_paths() {
+        let info = ContainerInfo { pid: 42 };
+        assert_eq!(info.ns_path("net"), PathBuf::from("/proc/42/ns/net"));
+        assert_eq!(info.namespaces().len(), NAMESPACES.len());
+        assert_eq!(
+            namespace_names(libc::CLONE_NEWNET | libc::CLONE_NEWNS | libc::CLONE_VM),
+            ["mnt", "net"]
+        );
     }
 
     #[test]
@@ -0,0 +1,692 @@
+//! OCI runtime bundles.  Run the `config.json` of a bundle with `runc()`.
+//!
+//! A subset of the runtime spec.  cf.
+//! <https://github.com/opencontainers/runtime-spec/blob/main/config.md>
+//! The root filesystem, mounts, process (args, env, cwd, user), hostname, new namespaces,
+//! uid/gid mappings, masked and read-only paths, and prestart/poststop hooks.
+//! Unknown keys are ignored.  Joining existing namespaces, `process.terminal`,
+//! and most of `linux` (eg. `resources`, `seccomp`) are not supported.
+//!
+//! ```no_run
+//! use sandbox::container::oci::Spec;
+//!
+//! let spec = Spec::load("./bundle").unwrap();
+//! let code = sandbox::runc(&spec).unwrap();
+//! ```
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, warn};
+
+use super::{ContainerHooks, IdMap, Result, StageCtx};
+use crate::config::{Document, Item, Table, Value};
+use crate::err::{self, Error};
+use crate::hook::{HookCmd, Stage};
+use crate::util;
+
+/// Mount options which are flags.  `(name, set, clear)`
+const MOUNT_FLAGS: &[(&str, libc::c_ulong, libc::c_ulong)] = &[
+    ("ro", libc::MS_RDONLY, 0),
+    ("rw", 0, libc::MS_RDONLY),
+    ("nosuid", libc::MS_NOSUID, 0),
+    ("suid", 0, libc::MS_NOSUID),
+    ("nodev", libc::MS_NODEV, 0),
+    ("dev", 0, libc::MS_NODEV),
+    ("noexec", libc::MS_NOEXEC, 0),
+    ("exec", 0, libc::MS_NOEXEC),
+    ("sync", libc::MS_SYNCHRONOUS, 0),
+    ("async", 0, libc::MS_SYNCHRONOUS),
+    ("noatime", libc::MS_NOATIME, 0),
+    ("nodiratime", libc::MS_NODIRATIME, 0),
+    ("relatime", libc::MS_RELATIME, 0),
+    ("strictatime", libc::MS_STRICTATIME, 0),
+    ("bind", libc::MS_BIND, 0),
+    ("rbind", libc::MS_BIND | libc::MS_REC, 0),
+];
+
+/// Propagation options, applied with a second call
+const PROPAGATION: &[(&str, libc::c_ulong)] = &[
+    ("private", libc::MS_PRIVATE),
+    ("rprivate", libc::MS_PRIVATE | libc::MS_REC),
+    ("slave", libc::MS_SLAVE),
+    ("rslave", libc::MS_SLAVE | libc::MS_REC),
+    ("shared", libc::MS_SHARED),
+    ("rshared", libc::MS_SHARED | libc::MS_REC),
+    ("unbindable", libc::MS_UNBINDABLE),
+    ("runbindable", libc::MS_UNBINDABLE | libc::MS_REC),
+];
+
+const NAMESPACES: &[(&str, libc::c_int)] = &[
+    ("pid", libc::CLONE_NEWPID),
+    ("network", libc::CLONE_NEWNET),
+    ("mount", libc::CLONE_NEWNS),
+    ("ipc", libc::CLONE_NEWIPC),
+    ("uts", libc::CLONE_NEWUTS),
+    ("user", libc::CLONE_NEWUSER),
+    ("cgroup", libc::CLONE_NEWCGROUP),
+];
+
+/// An entry of `mounts`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    /// In the container
+    pub destination: PathBuf,
+    /// File system type.  eg. `tmpfs`.  `bind`, or none, with a `bind` option
+    pub fstype: Option<String>,
+    pub source: Option<PathBuf>,
+    pub options: Vec<String>,
+}
+
+/// Arguments of `mount()` for `Mount::options`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MountFlags {
+    pub flags: libc::c_ulong,
+    pub propagation: libc::c_ulong,
+    /// Options passed to the file system.  eg. `size=64k,mode=755`
+    pub data: String,
+}
+
+impl Mount {
+    pub fn flags(&self) -> MountFlags {
+        let mut ret = MountFlags::default();
+        let mut data = vec![];
+        for opt in &self.options {
+            if let Some((_, set, clear)) = MOUNT_FLAGS.iter().find(|(name, _, _)| name == opt) {
+                ret.flags = (ret.flags & !clear) | set;
+            } else if let Some((_, prop)) = PROPAGATION.iter().find(|(name, _)| name == opt) {
+                ret.propagation |= prop;
+            } else {
+                data.push(opt.as_str());
+            }
+        }
+        if self.fstype.as_deref() == Some("bind") {
+            ret.flags |= libc::MS_BIND;
+        }
+        ret.data = data.join(",");
+        ret
+    }
+
+    /// Make in the new root, not yet pivoted to
+    fn apply(&self, root: &Path) -> Result<()> {
+        let target = in_root(root, &self.destination);
+        let opts = self.flags();
+        let bind = opts.flags & libc::MS_BIND != 0;
+        let source = match (&self.source, bind) {
+            (Some(source), _) => source.clone(),
+            (None, true) => return Err(format!("bind mount of {:?} without source", target).into()),
+            (None, false) => PathBuf::from("none"),
+        };
+        if !target.exists() {
+            // bind a file onto a file
+            if bind && !source.is_dir() {
+                if let Some(parent) = target.parent() {
+                    util::mkdirs(parent)?;
+                }
+                util::write_file(&target, "")?;
+            } else {
+                util::mkdirs(&target)?;
+            }
+        }
+        debug!("OCI mount {:?} on {}", self, target.display());
+        if bind {
+            util::mount(
+                &source,
+                &target,
+                "",
+                opts.flags & (libc::MS_BIND | libc::MS_REC),
+            )?;
+            // other flags of a bind mount only apply to a remount
+            let rest = opts.flags & !(libc::MS_BIND | libc::MS_REC);
+            if rest != 0 {
+                util::mount("", &target, "", libc::MS_REMOUNT | libc::MS_BIND | rest)?;
+            }
+        } else {
+            util::mount_with_data(
+                &source,
+                &target,
+                self.fstype.as_deref().unwrap_or(""),
+                opts.flags,
+                &opts.data,
+            )?;
+        }
+        if opts.propagation != 0 {
+            util::mount("", &target, "", opts.propagation)?;
+        }
+        Ok(())
+    }
+}
+
+/// An entry of `linux.uidMappings` or `linux.gidMappings`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub container_id: u32,
+    pub host_id: u32,
+    pub size: u32,
+}
+
+/// `process`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Process {
+    pub args: Vec<String>,
+    /// `NAME=value`.  The environment of the caller is not inherited
+    pub env: Vec<String>,
+    pub cwd: PathBuf,
+    pub uid: u32,
+    pub gid: u32,
+    pub additional_gids: Vec<u32>,
+    pub no_new_privileges: bool,
+}
+
+/// A parsed `config.json`.  Implements `ContainerHooks`, so may be passed to `runc()`.
+#[derive(Debug, Clone, Default)]
+pub struct Spec {
+    pub oci_version: String,
+    /// Absolute, or relative to the bundle
+    pub root: PathBuf,
+    pub readonly: bool,
+    pub hostname: Option<String>,
+    pub process: Process,
+    pub mounts: Vec<Mount>,
+    /// `CLONE_NEW*`
+    pub namespaces: libc::c_int,
+    pub uid_mappings: Vec<Mapping>,
+    pub gid_mappings: Vec<Mapping>,
+    pub masked_paths: Vec<PathBuf>,
+    pub readonly_paths: Vec<PathBuf>,
+    pub prestart: Vec<HookCmd>,
+    pub poststop: Vec<HookCmd>,
+}
+
+impl Spec {
+    /// Read `config.json` of the `bundle` directory
+    pub fn load<P: AsRef<Path>>(bundle: P) -> err::Result<Spec> {
+        let bundle = bundle.as_ref();
+        let doc = Document::load_json(bundle.join("config.json"))?;
+        Self::from_document(&doc, bundle)
+    }
+
+    /// Parse the contents of `config.json`.  A relative `root.path` is taken
+    /// relative to `bundle`
+    pub fn parse<P: AsRef<Path>>(text: &str, bundle: P) -> err::Result<Spec> {
+        let bundle = bundle.as_ref();
+        let doc = Document::parse_json(text, bundle.join("config.json"))?;
+        Self::from_document(&doc, bundle)
+    }
+
+    pub fn from_document(doc: &Document, bundle: &Path) -> err::Result<Spec> {
+        let root = &doc.root;
+        let mut ret = Spec::default();
+        let required = |table: &Table, key: &str, pos| {
+            table
+                .get(key)
+                .cloned()
+                .ok_or_else(|| doc.error(pos, format!("missing \"{}\"", key)))
+        };
+        let top = crate::config::Pos { line: 1, col: 1 };
+
+        ret.oci_version = get_str(doc, &required(root, "ociVersion", top)?)?;
+
+        let item = required(root, "root", top)?;
+        let table = get_table(doc, &item)?;
+        ret.root = bundle.join(get_str(doc, &required(table, "path", item.pos)?)?);
+        if let Some(item) = table.get("readonly") {
+            ret.readonly = get_bool(doc, item)?;
+        }
+
+        if let Some(item) = root.get("hostname") {
+            ret.hostname = Some(get_str(doc, item)?);
+        }
+
+        let item = required(root, "process", top)?;
+        let table = get_table(doc, &item)?;
+        if table.get("terminal").and_then(|i| i.value.as_bool()) == Some(true) {
+            return Err(doc.error(item.pos, "\"terminal\" is not supported"));
+        }
+        ret.process.args = get_strs(doc, &required(table, "args", item.pos)?)?;
+        if ret.process.args.is_empty() {
+            return Err(doc.error(item.pos, "empty \"args\""));
+        }
+        ret.process.cwd = get_str(doc, &required(table, "cwd", item.pos)?)?.into();
+        if !ret.process.cwd.is_absolute() {
+            return Err(doc.error(item.pos, "\"cwd\" must be absolute"));
+        }
+        if let Some(item) = table.get("env") {
+            ret.process.env = get_strs(doc, item)?;
+        }
+        if let Some(item) = table.get("noNewPrivileges") {
+            ret.process.no_new_privileges = get_bool(doc, item)?;
+        }
+        if let Some(item) = table.get("user") {
+            let user = get_table(doc, item)?;
+            ret.process.uid = get_id(doc, &required(user, "uid", item.pos)?)?;
+            ret.process.gid = get_id(doc, &required(user, "gid", item.pos)?)?;
+            if let Some(item) = user.get("additionalGids") {
+                ret.process.additional_gids = get_array(doc, item, |v| match v {
+                    Value::Int(i) => u32::try_from(*i).ok(),
+                    _ => None,
+                })?;
+            }
+        }
+
+        if let Some(item) = root.get("mounts") {
+            for mount in get_tables(doc, item)? {
+                let destination: PathBuf =
+                    get_str(doc, &required(mount, "destination", item.pos)?)?.into();
+                if !destination.is_absolute() {
+                    return Err(doc.error(item.pos, "mount \"destination\" must be absolute"));
+                }
+                let source = match mount.get("source") {
+                    Some(item) => {
+                        let source = PathBuf::from(get_str(doc, item)?);
+                        // relative to the bundle, when not a device name. eg. "tmpfs"
+                        Some(if source.is_relative() && bundle.join(&source).exists() {
+                            bundle.join(source)
+                        } else {
+                            source
+                        })
+                    }
+                    None => None,
+                };
+                ret.mounts.push(Mount {
+                    destination,
+                    fstype: match mount.get("type") {
+                        Some(item) => Some(get_str(doc, item)?),
+                        None => None,
+                    },
+                    source,
+                    options: match mount.get("options") {
+                        Some(item) => get_strs(doc, item)?,
+                        None => vec![],
+                    },
+                });
+            }
+        }
+
+        if let Some(item) = root.get("linux") {
+            let linux = get_table(doc, item)?;
+            if let Some(item) = linux.get("namespaces") {
+                for ns in get_tables(doc, item)? {
+                    let name = get_str(doc, &required(ns, "type", item.pos)?)?;
+                    if ns.contains_key("path") {
+                        return Err(doc.error(
+                            item.pos,
+                            format!("joining {} namespace is not supported", name),
+                        ));
+                    }
+                    match NAMESPACES.iter().find(|(n, _)| *n == name) {
+                        Some((_, flag)) => ret.namespaces |= flag,
+                        None => {
+                            return Err(doc.error(item.pos, format!("unknown namespace {:?}", name)))
+                        }
+                    }
+                }
+            }
+            for (key, out) in [
+                ("uidMappings", &mut ret.uid_mappings),
+                ("gidMappings", &mut ret.gid_mappings),
+            ] {
+                if let Some(item) = linux.get(key) {
+                    for map in get_tables(doc, item)? {
+                        out.push(Mapping {
+                            container_id: get_id(doc, &required(map, "containerID", item.pos)?)?,
+                            host_id: get_id(doc, &required(map, "hostID", item.pos)?)?,
+                            size: get_id(doc, &required(map, "size", item.pos)?)?,
+                        });
+                    }
+                }
+            }
+            if let Some(item) = linux.get("maskedPaths") {
+                ret.masked_paths = get_strs(doc, item)?.into_iter().map(Into::into).collect();
+            }
+            if let Some(item) = linux.get("readonlyPaths") {
+                ret.readonly_paths = get_strs(doc, item)?.into_iter().map(Into::into).collect();
+            }
+        }
+        if ret.namespaces & libc::CLONE_NEWNS == 0 {
+            return Err(doc.error(top, "a mount namespace is required"));
+        }
+        if ret.hostname.is_some() && ret.namespaces & libc::CLONE_NEWUTS == 0 {
+            return Err(doc.error(top, "\"hostname\" needs a uts namespace"));
+        }
+
+        if let Some(item) = root.get("hooks") {
+            let hooks = get_table(doc, item)?;
+            if let Some(item) = hooks.get("prestart") {
+                ret.prestart = get_hooks(doc, item)?;
+            }
+            if let Some(item) = hooks.get("poststop") {
+                ret.poststop = get_hooks(doc, item)?;
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// `path` in the container, as seen from outside of `root`
+fn in_root(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+impl ContainerHooks for Spec {
+    fn namespaces(&self) -> libc::c_int {
+        self.namespaces
+    }
+
+    fn set_id_map(&self, ctx: &StageCtx) -> Result<()> {
+        let pid = match ctx.child() {
+            Some(pid) if self.namespaces & libc::CLONE_NEWUSER != 0 => pid.id(),
+            _ => return Ok(()),
+        };
+        let mut uids = IdMap::new_uid(pid);
+        for map in &self.uid_mappings {
+            uids.add(map.host_id, map.container_id, map.size);
+        }
+        uids.write()?;
+        let mut gids = IdMap::new_gid(pid);
+        for map in &self.gid_mappings {
+            gids.add(map.host_id, map.container_id, map.size);
+        }
+        gids.write()?;
+        Ok(())
+    }
+
+    fn hook_cmds(&self, stage: Stage) -> &[HookCmd] {
+        match stage {
+            Stage::Prestart => &self.prestart,
+            Stage::Poststop => &self.poststop,
+        }
+    }
+
+    fn setup_priv(&self, _ctx: &StageCtx) -> Result<()> {
+        util::mount("", "/", "", libc::MS_REC | libc::MS_PRIVATE)?;
+        let root = self
+            .root
+            .canonicalize()
+            .map_err(|e| Error::file("canonicalize", &self.root, e))?;
+        // pivot_root() needs a mount point
+        util::mount(&root, &root, "", libc::MS_BIND | libc::MS_REC)?;
+
+        for mount in &self.mounts {
+            mount.apply(&root)?;
+        }
+        if let Some(name) = &self.hostname {
+            if unsafe { libc::sethostname(name.as_ptr() as *const libc::c_char, name.len()) } != 0 {
+                return Err(Error::last_os_error("sethostname").into());
+            }
+        }
+
+        util::mkdir(root.join(".oldroot"))?;
+        env::set_current_dir(&root)?;
+        util::pivot_root(".", ".oldroot")?;
+        env::set_current_dir("/")?;
+        util::umount_lazy("/.oldroot")?;
+        util::rmdir("/.oldroot")?;
+
+        if self.readonly {
+            util::mount(
+                "",
+                "/",
+                "",
+                libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY,
+            )?;
+        }
+        for path in &self.masked_paths {
+            util::mask_path(path)?;
+        }
+        for path in &self.readonly_paths {
+            if path.exists() {
+                util::mount(path, path, "", libc::MS_BIND | libc::MS_REC)?;
+                util::mount_setattr(path, true, util::MOUNT_ATTR_RDONLY, 0)?;
+            }
+        }
+
+        // while CAP_SETUID and CAP_SETGID are held
+        let proc = &self.process;
+        if let Err(err) = util::setgroups(&proc.additional_gids) {
+            // not permitted in an unprivileged user namespace with setgroups denied
+            let denied = std::fs::read_to_string("/proc/self/setgroups")
+                .map(|mode| mode.trim() == "deny")
+                .unwrap_or(false);
+            if !denied {
+                return Err(err.into());
+            } else if proc.additional_gids.is_empty() {
+                debug!("Keep supplementary groups : {}", err);
+            } else {
+                warn!(
+                    "Ignoring additionalGids {:?} : {}",
+                    proc.additional_gids, err
+                );
+            }
+        }
+        if util::getgid() != proc.gid {
+            util::setgid(proc.gid)?;
+        }
+        if util::getuid() != proc.uid {
+            util::setuid(proc.uid)?;
+        }
+        Ok(())
+    }
+
+    fn setup(&self, _ctx: &StageCtx) -> Result<()> {
+        let proc = &self.process;
+        env::set_current_dir(&proc.cwd).map_err(|e| Error::file("chdir", &proc.cwd, e))?;
+        if proc.no_new_privileges {
+            util::set_no_new_privs()?;
+        }
+        for (name, _) in env::vars_os() {
+            env::remove_var(name);
+        }
+        for var in &proc.env {
+            if let Some((name, value)) = var.split_once('=') {
+                env::set_var(name, value);
+            }
+        }
+        util::Exec::new(&proc.args[0])?.args(&proc.args)?.exec()?;
+        Ok(())
+    }
+}
+
+fn mismatch(doc: &Document, item: &Item, want: &str) -> Error {
+    doc.error(
+        item.pos,
+        format!("expected {} found {}", want, item.value.type_name()),
+    )
+}
+
+fn get_bool(doc: &Document, item: &Item) -> err::Result<bool> {
+    item.value
+        .as_bool()
+        .ok_or_else(|| mismatch(doc, item, "boolean"))
+}
+
+fn get_str(doc: &Document, item: &Item) -> err::Result<String> {
+    item.value
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| mismatch(doc, item, "string"))
+}
+
+fn get_id(doc: &Document, item: &Item) -> err::Result<u32> {
+    item.value
+        .as_int()
+        .and_then(|i| u32::try_from(i).ok())
+        .ok_or_else(|| mismatch(doc, item, "32-bit unsigned integer"))
+}
+
+fn get_table<'a>(doc: &Document, item: &'a Item) -> err::Result<&'a Table> {
+    item.value
+        .as_table()
+        .ok_or_else(|| mismatch(doc, item, "object"))
+}
+
+fn get_strs(doc: &Document, item: &Item) -> err::Result<Vec<String>> {
+    get_array(doc, item, |v| v.as_str().map(String::from))
+}
+
+fn get_tables<'a>(doc: &Document, item: &'a Item) -> err::Result<Vec<&'a Table>> {
+    get_array(doc, item, Value::as_table)
+}
+
+/// Each element of an array, all of which `conv` must accept
+fn get_array<'a, T, F>(doc: &Document, item: &'a Item, conv: F) -> err::Result<Vec<T>>
+where
+    F: Fn(&'a Value) -> Option<T>,
+{
+    item.value
+        .as_array()
+        .and_then(|arr| arr.iter().map(conv).collect::<Option<Vec<_>>>())
+        .ok_or_else(|| mismatch(doc, item, "array"))
+}
+
+fn get_hooks(doc: &Document, item: &Item) -> err::Result<Vec<HookCmd>> {
+    let mut ret = vec![];
+    for table in get_tables(doc, item)? {
+        let path = table
+            .get("path")
+            .ok_or_else(|| doc.error(item.pos, "hook missing \"path\""))?;
+        let mut cmd = HookCmd::new(get_str(doc, path)?);
+        if let Some(item) = table.get("args") {
+            cmd.args = get_strs(doc, item)?;
+        }
+        if let Some(item) = table.get("env") {
+            cmd.env = get_strs(doc, item)?;
+        }
+        if let Some(item) = table.get("timeout") {
+            let secs = item
+                .value
+                .as_int()
+                .filter(|i| *i > 0)
+                .ok_or_else(|| mismatch(doc, item, "positive integer"))?;
+            cmd.timeout = Some(Duration::from_secs(secs as u64));
+        }
+        ret.push(cmd);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+    use crate::testing;
+
+    const CONFIG: &str = r#"{
+        "ociVersion": "1.0.2",
+        "root": {"path": "rootfs", "readonly": true},
+        "hostname": "oci",
+        "process": {
+            "terminal": false,
+            "user": {"uid": 0, "gid": 0, "additionalGids": [5]},
+            "args": ["sh", "-c", "test \"$(hostname)\" = oci && test \"$FOO\" = bar"],
+            "env": ["PATH=/usr/bin:/bin", "FOO=bar"],
+            "cwd": "/"
+        },
+        "mounts": [
+            {"destination": "/proc", "type": "proc", "source": "proc"},
+            {"destination": "/tmp", "type": "tmpfs", "source": "tmpfs",
+             "options": ["nosuid", "nodev", "mode=1777", "size=65536k"]},
+            {"destination": "/usr", "type": "bind", "source": "/usr", "options": ["rbind", "ro"]}
+        ],
+        "linux": {
+            "namespaces": [{"type": "pid"}, {"type": "mount"}, {"type": "uts"}],
+            "uidMappings": [{"containerID": 0, "hostID": 1000, "size": 1}],
+            "maskedPaths": ["/proc/kcore"],
+            "readonlyPaths": ["/proc/sys"]
+        },
+        "hooks": {"prestart": [{"path": "/bin/true", "timeout": 5}]}
+    }"#;
+
+    #[test]
+    fn parse() {
+        let spec = Spec::parse(CONFIG, "/bundle").unwrap();
+        assert_eq!(spec.oci_version, "1.0.2");
+        assert_eq!(spec.root, Path::new("/bundle/rootfs"));
+        assert!(spec.readonly);
+        assert_eq!(spec.hostname.as_deref(), Some("oci"));
+        assert_eq!(spec.process.args[0], "sh");
+        assert_eq!(spec.process.additional_gids, vec![5]);
+        assert_eq!(
+            spec.namespaces,
+            libc::CLONE_NEWPID | libc::CLONE_NEWNS | libc::CLONE_NEWUTS
+        );
+        assert_eq!(
+            spec.uid_mappings,
+            vec![Mapping {
+                container_id: 0,
+                host_id: 1000,
+                size: 1
+            }]
+        );
+        assert_eq!(spec.mounts.len(), 3);
+        assert_eq!(spec.prestart[0].timeout, Some(Duration::from_secs(5)));
+
+        let tmp = spec.mounts[1].flags();
+        assert_eq!(tmp.flags, libc::MS_NOSUID | libc::MS_NODEV);
+        assert_eq!(tmp.data, "mode=1777,size=65536k");
+        let usr = spec.mounts[2].flags();
+        assert_eq!(usr.flags, libc::MS_BIND | libc::MS_REC | libc::MS_RDONLY);
+
+        let bad = CONFIG.replace(r#"{"type": "uts"}"#, r#"{"type": "uts", "path": "/x"}"#);
+        let err = Spec::parse(&bad, "/bundle").unwrap_err();
+        assert!(err.to_string().contains("not supported"), "{}", err);
+        let bad = CONFIG.replace(r#""cwd": "/""#, r#""cwd": "rel""#);
+        Spec::parse(&bad, "/bundle").unwrap_err();
+        let bad = CONFIG.replace(r#""ociVersion": "1.0.2","#, "");
+        Spec::parse(&bad, "/bundle").unwrap_err();
+    }
+
+    #[test]
+    fn run() {
+        if !testing::require_userns() {
+            return;
+        }
+        let bundle = TempDir::new().unwrap();
+        for dir in ["rootfs/proc", "rootfs/tmp", "rootfs/usr"] {
+            util::mkdirs(bundle.path().join(dir)).unwrap();
+        }
+        // merged /usr, or the host directories themselves
+        let mut config = CONFIG.to_string();
+        for dir in ["bin", "lib", "lib64"] {
+            let host = Path::new("/").join(dir);
+            match std::fs::read_link(&host) {
+                Ok(target) => {
+                    std::os::unix::fs::symlink(target, bundle.path().join("rootfs").join(dir))
+                        .unwrap()
+                }
+                Err(_) if host.is_dir() => {
+                    config = config.replace(
+                        r#""options": ["rbind", "ro"]}"#,
+                        &format!(
+                            r#""options": ["rbind", "ro"]}},
+                            {{"destination": "/{0}", "type": "bind", "source": "/{0}",
+                              "options": ["rbind", "ro"]}}"#,
+                            dir
+                        ),
+                    )
+                }
+                Err(_) => (),
+            }
+        }
+        let uid = util::getuid();
+        let gid = util::getgid();
+        if uid != 0 {
+            config = config
+                .replace(r#"{"type": "pid"}"#, r#"{"type": "pid"}, {"type": "user"}"#)
+                .replace(r#""hostID": 1000"#, &format!(r#""hostID": {}"#, uid))
+                .replace(
+                    r#""maskedPaths""#,
+                    &format!(
+                        r#""gidMappings": [{{"containerID": 0, "hostID": {}, "size": 1}}],
+                        "maskedPaths""#,
+                        gid
+                    ),
+                );
+        }
+        util::write_file(bundle.path().join("config.json"), config).unwrap();
+
+        let spec = Spec::load(bundle.path()).unwrap();
+        assert_eq!(crate::runc(&spec).unwrap(), 0);
+    }
+}
@@ -0,0 +1,179 @@
+//! Core dump policy inside a sandbox.
+//!
+//! `kernel.core_pattern` is not per namespace, so it can not be changed for
+//! only a sandbox.  Instead, when the host pattern names a file, the directory
+//! it names is replaced with a bind mount inside the sandbox.
+//! Cores piped to a host handler (eg. `systemd-coredump`) can only be disabled.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::{debug, warn};
+
+use super::err::{Error, Result};
+use super::util;
+
+const CORE_PATTERN: &str = "/proc/sys/kernel/core_pattern";
+
+/// What happens when a sandboxed process dumps core
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorePolicy {
+    /// No core dumps.  `RLIMIT_CORE` is zero.
+    Off,
+    /// Write core dumps to this host directory
+    Dir(PathBuf),
+}
+
+impl FromStr for CorePolicy {
+    type Err = Error;
+    /// `off` or `dir:<path>`
+    fn from_str(s: &str) -> Result<CorePolicy> {
+        match s.split_once(':') {
+            None if s == "off" => Ok(CorePolicy::Off),
+            Some(("dir", dir)) if !dir.is_empty() => Ok(CorePolicy::Dir(dir.into())),
+            _ => Err(Error::os(
+                format!("Expected \"off\" or \"dir:<path>\", not {:?}", s),
+                io::Error::from(io::ErrorKind::InvalidInput),
+            )),
+        }
+    }
+}
+
+/// Interpretation of `kernel.core_pattern`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pattern {
+    /// Piped to a host program
+    Pipe(String),
+    /// Written to an absolute path
+    File(PathBuf),
+    /// Written relative to the working directory of the crashing process
+    Relative(String),
+}
+
+impl Pattern {
+    pub fn parse(text: &str) -> Pattern {
+        let text = text.trim_end_matches('\n');
+        if let Some(cmd) = text.strip_prefix('|') {
+            Pattern::Pipe(cmd.to_string())
+        } else if text.starts_with('/') {
+            Pattern::File(text.into())
+        } else {
+            Pattern::Relative(text.to_string())
+        }
+    }
+
+    /// The current host pattern
+    pub fn current() -> Result<Pattern> {
+        let text = std::fs::read_to_string(CORE_PATTERN)
+            .map_err(|e| Error::file("read", CORE_PATTERN, e))?;
+        Ok(Self::parse(&text))
+    }
+}
+
+/// Set `RLIMIT_CORE` of the current process.  `None` for unlimited
+pub fn set_core_limit(limit: Option<u64>) -> Result<()> {
+    let lim = limit.unwrap_or(libc::RLIM_INFINITY);
+    let mut rlim = libc::rlimit {
+        rlim_cur: lim,
+        rlim_max: lim,
+    };
+    if lim == libc::RLIM_INFINITY {
+        // an unprivileged process may not raise the hard limit
+        unsafe {
+            libc::getrlimit(libc::RLIMIT_CORE, &mut rlim);
+        }
+        rlim.rlim_cur = rlim.rlim_max;
+    }
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &rlim) } != 0 {
+        return Err(Error::last_os_error("setrlimit(RLIMIT_CORE)"));
+    }
+    Ok(())
+}
+
+impl CorePolicy {
+    /// With privilege, before `pivot_root()` to `new_root`.
+    /// Redirect cores written by the host pattern.
+    pub fn prepare(&self, new_root: &Path) -> Result<()> {
+        let dir = match self {
+            CorePolicy::Off => return Ok(()),
+            CorePolicy::Dir(dir) => dir,
+        };
+        match Pattern::current()? {
+            Pattern::Pipe(cmd) => {
+                warn!("Cores are piped to \"{}\", and can not be redirected", cmd);
+            }
+            Pattern::File(file) => {
+                let parent = file.parent().unwrap_or_else(|| Path::new("/"));
+                let target = new_root.join(parent.strip_prefix("/").unwrap_or(parent));
+                if parent == Path::new("/") || !target.is_dir() {
+                    warn!("Unable to redirect cores written to {}", parent.display());
+                } else {
+                    debug!("Cores from {} to {}", parent.display(), dir.display());
+                    util::mount(dir, &target, "", libc::MS_BIND)?;
+                }
+            }
+            Pattern::Relative(_) => {
+                warn!("Cores are written to the working directory of the crashing process");
+            }
+        }
+        Ok(())
+    }
+
+    /// Before executing the command
+    pub fn apply(&self) -> Result<()> {
+        match self {
+            CorePolicy::Off => set_core_limit(Some(0)),
+            CorePolicy::Dir(_) => set_core_limit(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proc::fork;
+
+    #[test]
+    fn parse() {
+        assert_eq!("off".parse::<CorePolicy>().unwrap(), CorePolicy::Off);
+        assert_eq!(
+            "dir:/tmp/cores".parse::<CorePolicy>().unwrap(),
+            CorePolicy::Dir("/tmp/cores".into())
+        );
+        for bad in ["on", "dir:", "file:/x"] {
+            bad.parse::<CorePolicy>().unwrap_err();
+        }
+
+        assert_eq!(
+            Pattern::parse("|/lib/systemd/systemd-coredump %P %u\n"),
+            Pattern::Pipe("/lib/systemd/systemd-coredump %P %u".into())
+        );
+        assert_eq!(
+            Pattern::parse("/var/crash/core.%e\n"),
+            Pattern::File("/var/crash/core.%e".into())
+        );
+        assert_eq!(Pattern::parse("core"), Pattern::Relative("core".into()));
+    }
+
+    #[test]
+    fn limit() {
+        let mut pid = fork::<_, Error>(|| {
+            CorePolicy::Off.apply()?;
+            let mut rlim = libc::rlimit {
+                rlim_cur: 1,
+                rlim_max: 1,
+            };
+            unsafe {
+                libc::getrlimit(libc::RLIMIT_CORE, &mut rlim);
+            }
+            std::process::exit(if rlim.rlim_cur == 0 && rlim.rlim_max == 0 {
+                0
+            } else {
+                2
+            });
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+}
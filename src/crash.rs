@@ -0,0 +1,311 @@
+//! Stack trace capture when a sandboxed command crashes.
+//!
+//! Instead of executing the command directly, sandbox process 1 remains as a
+//! supervisor which forks the command and traces it with `PTRACE_SEIZE`.
+//! When a crash signal would kill the command, the supervisor detaches, leaving
+//! the command stopped, and runs a debugger command against it.
+//! The output is saved to a file, then the crash signal is delivered.
+//!
+//! Only the command itself is traced, not any processes it creates.
+//! Signals with a handler installed by the command (eg. a JVM catches `SIGSEGV`)
+//! are passed through without a trace.
+//! As with any traced process, executing a set-UID program will not change privileges.
+
+use std::fs;
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{env, process};
+
+use libc::c_int;
+use signal_hook;
+use signal_hook::iterator::Signals;
+
+use log::{debug, error, info, warn};
+
+use super::err::{Error, Result};
+use super::hook::HookCmd;
+use super::util;
+
+/// Signals which trigger a trace, when they would kill the command
+pub const CRASH_SIGNALS: [c_int; 5] = [
+    libc::SIGSEGV,
+    libc::SIGABRT,
+    libc::SIGBUS,
+    libc::SIGILL,
+    libc::SIGFPE,
+];
+
+/// Run through `/bin/sh -c`, with `{pid}` replaced
+pub const DEFAULT_DEBUGGER: &str = "gdb -p {pid} -batch -ex 'thread apply all bt'";
+
+const DEBUGGER_TIMEOUT: Duration = Duration::from_secs(60);
+
+// not yet defined by libc for glibc targets
+const PTRACE_EVENT_STOP: c_int = 128;
+
+fn signal_name(sig: c_int) -> String {
+    match sig {
+        libc::SIGSEGV => "SIGSEGV".into(),
+        libc::SIGABRT => "SIGABRT".into(),
+        libc::SIGBUS => "SIGBUS".into(),
+        libc::SIGILL => "SIGILL".into(),
+        libc::SIGFPE => "SIGFPE".into(),
+        _ => format!("SIG{}", sig),
+    }
+}
+
+/// Has process `pid` installed a handler for `sig`?
+fn catches(pid: libc::pid_t, sig: c_int) -> bool {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    let mask = status
+        .lines()
+        .find_map(|l| l.strip_prefix("SigCgt:"))
+        .and_then(|m| u64::from_str_radix(m.trim(), 16).ok())
+        .unwrap_or(0);
+    mask & (1 << (sig - 1)) != 0
+}
+
+/// Close descriptors marked close-on-exec, except `keep`.
+/// As `execve()` would have done if the supervisor had executed the command.
+fn close_cloexec(keep: c_int) {
+    let fds: Vec<c_int> = match fs::read_dir("/proc/self/fd") {
+        Ok(dir) => dir
+            .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+            .collect(),
+        Err(err) => {
+            warn!("Unable to list /proc/self/fd : {}", err);
+            return;
+        }
+    };
+    for fd in fds {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if fd != keep && flags >= 0 && flags & libc::FD_CLOEXEC != 0 {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+fn ptrace(req: libc::c_uint, pid: libc::pid_t, data: usize) -> Result<()> {
+    let null = std::ptr::null_mut::<libc::c_void>();
+    if unsafe { libc::ptrace(req, pid, null, data as *mut libc::c_void) } != 0 {
+        return Err(Error::last_os_error(format!("ptrace({}, {})", req, pid)));
+    }
+    Ok(())
+}
+
+/// Capture stack traces of a crashing command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashTrace {
+    /// Where trace files are written.  Must be writable from inside the sandbox.
+    pub dir: PathBuf,
+    /// Shell command.  `{pid}` is replaced with the PID of the crashed process.
+    pub debugger: String,
+}
+
+impl CrashTrace {
+    /// With `DEFAULT_DEBUGGER`
+    pub fn new<P: Into<PathBuf>>(dir: P) -> CrashTrace {
+        CrashTrace {
+            dir: dir.into(),
+            debugger: DEFAULT_DEBUGGER.to_string(),
+        }
+    }
+
+    /// Replace the debugger command
+    pub fn debugger<S: Into<String>>(mut self, cmd: S) -> CrashTrace {
+        self.debugger = cmd.into();
+        self
+    }
+
+    fn command(&self, pid: libc::pid_t) -> String {
+        self.debugger.replace("{pid}", &pid.to_string())
+    }
+
+    fn trace_file(&self, pid: libc::pid_t, sig: c_int) -> PathBuf {
+        self.dir
+            .join(format!("crash-{}-{}.txt", pid, signal_name(sig)))
+    }
+
+    /// From `ContainerHooks::setup()`, in place of executing the command.
+    /// `exec` is called in a child process.  Anything moved into it is dropped by the
+    /// calling process once forked.  cf. `util::init_shim()`
+    /// Returns the exit code of the command, or 128 + signal number.
+    pub fn supervise<F>(&self, exec: F) -> Result<i32>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        // child waits until it is traced
+        let (rx, tx) = util::pipe()?;
+
+        // the child is traced before exec(), so must be dumpable.  The supervisor need not be.
+        let dumpable = unsafe { libc::prctl(libc::PR_GET_DUMPABLE, 0, 0, 0, 0) } == 1;
+        util::set_dumpable(true)?;
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(Error::last_os_error("fork"));
+        } else if pid == 0 {
+            drop(tx);
+            // with Yama ptrace_scope=1, allow the debugger, a child of our parent
+            unsafe {
+                libc::prctl(
+                    libc::PR_SET_PTRACER,
+                    libc::getppid() as libc::c_ulong,
+                    0,
+                    0,
+                    0,
+                );
+            }
+            let _ = (&rx).read(&mut [0u8; 1]);
+            drop(rx);
+            let code = match exec() {
+                Ok(()) => 0,
+                Err(err) => {
+                    error!("*child error: {}", err);
+                    1
+                }
+            };
+            process::exit(code);
+        }
+        drop(rx);
+        drop(exec);
+        if !dumpable {
+            util::set_dumpable(false)?;
+        }
+        debug!("Supervise PID {}", pid);
+
+        close_cloexec(tx.as_raw_fd());
+
+        let mut signals = Signals::new([
+            signal_hook::consts::SIGTERM,
+            signal_hook::consts::SIGINT,
+            signal_hook::consts::SIGQUIT,
+            signal_hook::consts::SIGHUP,
+            signal_hook::consts::SIGCHLD,
+        ])
+        .map_err(|e| Error::os("Install signal handler", e))?;
+        let mut isig = signals.forever();
+
+        if let Err(err) = ptrace(libc::PTRACE_SEIZE, pid, libc::PTRACE_O_EXITKILL as usize) {
+            warn!("Crashes will not be traced.  {}", err);
+        }
+        drop(tx);
+
+        // also reap orphans re-parented to this process
+        let mut reaper = util::Reaper::new(pid, util::OnExit::Kill);
+        loop {
+            if let Some(code) = reaper.reap(|pid, sts| self.stopped(pid, sts)) {
+                return Ok(code);
+            }
+
+            match isig.next() {
+                Some(signal_hook::consts::SIGCHLD) => (),
+                Some(sig) => reaper.relay(sig),
+                None => unreachable!(),
+            }
+        }
+    }
+
+    /// Traced process has stopped
+    fn stopped(&self, pid: libc::pid_t, sts: c_int) {
+        let sig = libc::WSTOPSIG(sts);
+        let ret = if sts >> 16 == PTRACE_EVENT_STOP {
+            // group-stop.  eg. SIGTSTP.  remain stopped until SIGCONT
+            ptrace(libc::PTRACE_LISTEN, pid, 0)
+        } else if CRASH_SIGNALS.contains(&sig) && !catches(pid, sig) {
+            self.capture(pid, sig)
+        } else {
+            ptrace(libc::PTRACE_CONT, pid, sig as usize)
+        };
+        if let Err(err) = ret {
+            warn!("{}", err);
+        }
+    }
+
+    /// Run the debugger against a stopped process, then deliver `sig`
+    fn capture(&self, pid: libc::pid_t, sig: c_int) -> Result<()> {
+        // the debugger attaches in our place
+        ptrace(libc::PTRACE_DETACH, pid, libc::SIGSTOP as usize)?;
+        // SIGSTOP is delivered asynchronously.  Leave the status to be reaped later.
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let flags = libc::WSTOPPED | libc::WEXITED | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, flags) } != 0 {
+            return Err(Error::last_os_error(format!("waitid({})", pid)));
+        } else if info.si_code != libc::CLD_STOPPED {
+            debug!("PID {} gone before trace", pid);
+            return Ok(());
+        }
+
+        let cmd = self.command(pid);
+        let file = self.trace_file(pid, sig);
+        let hook = HookCmd {
+            path: "/bin/sh".into(),
+            args: vec!["sh".into(), "-c".into(), cmd.clone()],
+            env: env::var("PATH")
+                .map(|p| vec![format!("PATH={}", p)])
+                .unwrap_or_default(),
+            timeout: Some(DEBUGGER_TIMEOUT),
+        };
+        match hook.run(&[]) {
+            Ok(out) => {
+                let text = format!(
+                    "# PID {} {}\n# {}\n{}{}",
+                    pid,
+                    signal_name(sig),
+                    cmd,
+                    out.stdout,
+                    out.stderr
+                );
+                match fs::write(&file, text) {
+                    Ok(()) => info!("Crash trace written to {}", file.display()),
+                    Err(err) => warn!("Unable to write {} : {}", file.display(), err),
+                }
+            }
+            Err(err) => warn!("Crash trace failed : {}", err),
+        }
+
+        unsafe {
+            libc::kill(pid, sig);
+            libc::kill(pid, libc::SIGCONT);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command() {
+        let trace = CrashTrace::new("/build").debugger("eu-stack -p {pid} # {pid}");
+        assert_eq!(trace.command(42), "eu-stack -p 42 # 42");
+        assert_eq!(
+            trace.trace_file(42, libc::SIGSEGV),
+            PathBuf::from("/build/crash-42-SIGSEGV.txt")
+        );
+        assert_eq!(signal_name(libc::SIGUSR1), format!("SIG{}", libc::SIGUSR1));
+    }
+
+    #[test]
+    fn crash() {
+        let tdir = crate::tempdir::TempDir::new().unwrap();
+        let trace = CrashTrace::new(tdir.path()).debugger("echo traced {pid}");
+        let mut pid = crate::proc::fork::<_, Error>(|| {
+            let code = trace.supervise(|| {
+                unsafe { libc::raise(libc::SIGABRT) };
+                Ok(())
+            })?;
+            process::exit(code);
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 128 + libc::SIGABRT);
+
+        let files: Vec<_> = fs::read_dir(tdir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let text = fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        assert!(text.contains("SIGABRT"), "{}", text);
+        assert!(text.contains("\ntraced "), "{}", text);
+    }
+}
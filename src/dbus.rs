@@ -0,0 +1,594 @@
+//! Minimal D-Bus client.  Only what is needed to make a few method calls.
+//!
+//! cf. https://dbus.freedesktop.org/doc/dbus-specification.html
+
+use std::env;
+use std::io::{Read, Write};
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use log::debug;
+
+use super::err::{Error, Result};
+use super::util;
+
+const SYSTEM_BUS: &str = "/run/dbus/system_bus_socket";
+
+pub const METHOD_CALL: u8 = 1;
+pub const METHOD_RETURN: u8 = 2;
+pub const ERROR: u8 = 3;
+pub const SIGNAL: u8 = 4;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SIGNATURE: u8 = 8;
+const FIELD_UNIX_FDS: u8 = 9;
+
+fn proto<S: AsRef<str>>(msg: S) -> Error {
+    Error::DBus(msg.as_ref().to_string())
+}
+
+/// Marshal values in little endian order
+#[derive(Default)]
+pub struct Writer {
+    pub buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn align(&mut self, n: usize) -> &mut Self {
+        let pad = (n - self.buf.len() % n) % n;
+        self.buf.resize(self.buf.len() + pad, 0);
+        self
+    }
+
+    pub fn byte(&mut self, v: u8) -> &mut Self {
+        self.buf.push(v);
+        self
+    }
+
+    pub fn u32(&mut self, v: u32) -> &mut Self {
+        self.align(4);
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    /// Also used for object paths
+    pub fn str(&mut self, v: &str) -> &mut Self {
+        self.u32(v.len() as u32);
+        self.buf.extend_from_slice(v.as_bytes());
+        self.buf.push(0);
+        self
+    }
+
+    pub fn sig(&mut self, v: &str) -> &mut Self {
+        self.buf.push(v.len() as u8);
+        self.buf.extend_from_slice(v.as_bytes());
+        self.buf.push(0);
+        self
+    }
+
+    /// Array with elements aligned to `elem_align`.  eg. 8 for an array of struct.
+    pub fn array<F: FnOnce(&mut Self)>(&mut self, elem_align: usize, elems: F) -> &mut Self {
+        self.u32(0);
+        let lenpos = self.buf.len() - 4;
+        // padding before first element is not counted
+        self.align(elem_align);
+        let start = self.buf.len();
+        elems(self);
+        let len = (self.buf.len() - start) as u32;
+        self.buf[lenpos..lenpos + 4].copy_from_slice(&len.to_le_bytes());
+        self
+    }
+}
+
+/// Unmarshal little endian values
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.pos + n > self.buf.len() {
+            return Err(proto("truncated message"));
+        }
+        let ret = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(ret)
+    }
+
+    pub fn align(&mut self, n: usize) -> Result<()> {
+        let pad = (n - self.pos % n) % n;
+        self.take(pad).map(|_| ())
+    }
+
+    pub fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        self.align(4)?;
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn str(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        let ret = String::from_utf8_lossy(self.take(len)?).to_string();
+        self.take(1)?;
+        Ok(ret)
+    }
+
+    pub fn sig(&mut self) -> Result<String> {
+        let len = self.byte()? as usize;
+        let ret = String::from_utf8_lossy(self.take(len)?).to_string();
+        self.take(1)?;
+        Ok(ret)
+    }
+
+    /// Array of bytes.  eg. a file name
+    pub fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    /// Array of strings
+    pub fn strs(&mut self) -> Result<Vec<String>> {
+        let len = self.u32()? as usize;
+        let end = self.pos + len;
+        let mut ret = vec![];
+        while self.pos < end {
+            ret.push(self.str()?);
+        }
+        Ok(ret)
+    }
+
+    /// Dictionary of variants ("a{sv}").  `value` is called with each key, and value signature,
+    /// and must read or `skip()` the value.
+    pub fn vardict<F>(&mut self, mut value: F) -> Result<()>
+    where
+        F: FnMut(&str, &str, &mut Self) -> Result<()>,
+    {
+        let len = self.u32()? as usize;
+        self.align(8)?;
+        let end = self.pos + len;
+        while self.pos < end {
+            self.align(8)?;
+            let key = self.str()?;
+            let sig = self.sig()?;
+            value(&key, &sig, self)?;
+        }
+        Ok(())
+    }
+
+    /// Skip over one value of each complete type in `sig`
+    pub fn skip(&mut self, sig: &str) -> Result<()> {
+        let mut sig = sig.as_bytes();
+        while !sig.is_empty() {
+            let n = type_len(sig)?;
+            self.skip_one(&sig[..n])?;
+            sig = &sig[n..];
+        }
+        Ok(())
+    }
+
+    fn skip_one(&mut self, sig: &[u8]) -> Result<()> {
+        match sig[0] {
+            b'y' => self.take(1).map(|_| ()),
+            b'n' | b'q' => self.align(2).and_then(|_| self.take(2)).map(|_| ()),
+            b'b' | b'i' | b'u' | b'h' => self.u32().map(|_| ()),
+            b'x' | b't' | b'd' => self.align(8).and_then(|_| self.take(8)).map(|_| ()),
+            b's' | b'o' => self.str().map(|_| ()),
+            b'g' => self.sig().map(|_| ()),
+            b'v' => {
+                let inner = self.sig()?;
+                self.skip(&inner)
+            }
+            b'a' => {
+                let len = self.u32()? as usize;
+                self.align(type_align(sig[1]))?;
+                self.take(len).map(|_| ())
+            }
+            b'(' | b'{' => {
+                self.align(8)?;
+                self.skip(std::str::from_utf8(&sig[1..sig.len() - 1]).unwrap())
+            }
+            other => Err(proto(format!("unsupported type {:?}", other as char))),
+        }
+    }
+}
+
+fn type_align(code: u8) -> usize {
+    match code {
+        b'y' | b'g' | b'v' => 1,
+        b'n' | b'q' => 2,
+        b'x' | b't' | b'd' | b'(' | b'{' => 8,
+        _ => 4,
+    }
+}
+
+/// Length of the first complete type in a signature.  eg. 5 for "a{sv}s"
+fn type_len(sig: &[u8]) -> Result<usize> {
+    match sig.first() {
+        None => Err(proto("incomplete signature")),
+        Some(b'a') => Ok(1 + type_len(&sig[1..])?),
+        Some(&open @ (b'(' | b'{')) => {
+            let close = if open == b'(' { b')' } else { b'}' };
+            let mut n = 1;
+            while sig.get(n) != Some(&close) {
+                n += type_len(&sig[n..])?;
+            }
+            Ok(n + 1)
+        }
+        Some(b')' | b'}') => Err(proto("unbalanced signature")),
+        Some(_) => Ok(1),
+    }
+}
+
+/// A received message.  Only the header fields we care about are kept.
+#[derive(Debug, Default)]
+pub struct Message {
+    pub mtype: u8,
+    pub serial: u32,
+    pub path: String,
+    pub interface: String,
+    pub member: String,
+    pub error_name: String,
+    pub reply_serial: u32,
+    pub signature: String,
+    pub body: Vec<u8>,
+}
+
+impl Message {
+    /// Parse a complete message
+    pub fn parse(buf: &[u8]) -> Result<Message> {
+        if buf.first() != Some(&b'l') {
+            return Err(proto("only little endian supported"));
+        }
+        let mut r = Reader::new(buf);
+        r.byte()?;
+        let mut msg = Message {
+            mtype: r.byte()?,
+            ..Default::default()
+        };
+        let _flags = r.byte()?;
+        let _version = r.byte()?;
+        let body_len = r.u32()? as usize;
+        msg.serial = r.u32()?;
+
+        let fields_len = r.u32()? as usize;
+        let fields_end = r.pos + fields_len;
+        while r.pos < fields_end {
+            r.align(8)?;
+            let code = r.byte()?;
+            let sig = r.sig()?;
+            match sig.as_str() {
+                "s" | "o" => {
+                    let v = r.str()?;
+                    match code {
+                        FIELD_PATH => msg.path = v,
+                        FIELD_INTERFACE => msg.interface = v,
+                        FIELD_MEMBER => msg.member = v,
+                        FIELD_ERROR_NAME => msg.error_name = v,
+                        _ => (),
+                    }
+                }
+                "g" => {
+                    let v = r.sig()?;
+                    if code == FIELD_SIGNATURE {
+                        msg.signature = v;
+                    }
+                }
+                "u" => {
+                    let v = r.u32()?;
+                    if code == FIELD_REPLY_SERIAL {
+                        msg.reply_serial = v;
+                    }
+                }
+                other => return Err(proto(format!("unexpected header field type {other:?}"))),
+            }
+        }
+        r.align(8)?;
+        msg.body = r.take(body_len)?.to_vec();
+        Ok(msg)
+    }
+
+    pub fn body(&self) -> Reader<'_> {
+        Reader::new(&self.body)
+    }
+}
+
+/// Marshal a method call, which will be accompanied by `nfds` file descriptors (usually 0)
+#[allow(clippy::too_many_arguments)]
+pub fn method_call(
+    serial: u32,
+    dest: &str,
+    path: &str,
+    iface: &str,
+    member: &str,
+    sig: &str,
+    body: &[u8],
+    nfds: u32,
+) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.byte(b'l')
+        .byte(METHOD_CALL)
+        .byte(0)
+        .byte(1)
+        .u32(body.len() as u32)
+        .u32(serial);
+    w.array(8, |w| {
+        for (code, vsig, val) in [
+            (FIELD_PATH, "o", path),
+            (FIELD_INTERFACE, "s", iface),
+            (FIELD_MEMBER, "s", member),
+            (FIELD_DESTINATION, "s", dest),
+        ] {
+            w.align(8).byte(code).sig(vsig).str(val);
+        }
+        if !sig.is_empty() {
+            w.align(8).byte(FIELD_SIGNATURE).sig("g").sig(sig);
+        }
+        if nfds > 0 {
+            w.align(8).byte(FIELD_UNIX_FDS).sig("u").u32(nfds);
+        }
+    });
+    w.align(8);
+    w.buf.extend_from_slice(body);
+    w.buf
+}
+
+/// Connection to a message bus
+#[derive(Debug)]
+pub struct Bus {
+    sock: UnixStream,
+    serial: u32,
+    /// File descriptors may be passed
+    unix_fds: bool,
+    /// Messages received while waiting for something else
+    pub pending: Vec<Message>,
+}
+
+fn auth_line(sock: &mut UnixStream) -> Result<Vec<u8>> {
+    let mut line = vec![];
+    let mut b = [0u8];
+    while b[0] != b'\n' {
+        sock.read_exact(&mut b)
+            .map_err(|e| Error::os("D-Bus auth", e))?;
+        line.push(b[0]);
+    }
+    Ok(line)
+}
+
+fn bus_path(user: bool) -> Result<PathBuf> {
+    let (var, default) = if user {
+        let rundir = env::var("XDG_RUNTIME_DIR").map_err(|_| proto("$XDG_RUNTIME_DIR not set"))?;
+        ("DBUS_SESSION_BUS_ADDRESS", format!("{rundir}/bus"))
+    } else {
+        ("DBUS_SYSTEM_BUS_ADDRESS", SYSTEM_BUS.to_string())
+    };
+    // only handles the common "unix:path=..." form
+    let path = env::var(var)
+        .ok()
+        .and_then(|addr| {
+            addr.split(';')
+                .filter_map(|a| a.strip_prefix("unix:"))
+                .flat_map(|a| a.split(','))
+                .find_map(|kv| kv.strip_prefix("path=").map(String::from))
+        })
+        .unwrap_or(default);
+    Ok(PathBuf::from(path))
+}
+
+impl Bus {
+    /// Connect to the system bus, or the session bus of the calling user
+    pub fn connect(user: bool) -> Result<Bus> {
+        let path = bus_path(user)?;
+        debug!("D-Bus connect {}", path.display());
+        let mut sock = UnixStream::connect(&path).map_err(|e| Error::file("connect", &path, e))?;
+
+        // peer credentials are checked against the claimed UID
+        let uid = util::geteuid().to_string();
+        let hexuid: String = uid.bytes().map(|b| format!("{:02x}", b)).collect();
+        sock.write_all(format!("\0AUTH EXTERNAL {hexuid}\r\n").as_bytes())
+            .map_err(|e| Error::os("D-Bus auth", e))?;
+
+        let line = auth_line(&mut sock)?;
+        if !line.starts_with(b"OK ") {
+            return Err(proto(format!(
+                "auth rejected: {}",
+                String::from_utf8_lossy(&line).trim()
+            )));
+        }
+        sock.write_all(b"NEGOTIATE_UNIX_FD\r\n")
+            .map_err(|e| Error::os("D-Bus auth", e))?;
+        let unix_fds = auth_line(&mut sock)?.starts_with(b"AGREE_UNIX_FD");
+        sock.write_all(b"BEGIN\r\n")
+            .map_err(|e| Error::os("D-Bus auth", e))?;
+
+        let mut bus = Bus {
+            sock,
+            serial: 0,
+            unix_fds,
+            pending: vec![],
+        };
+        bus.call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "Hello",
+            "",
+            &[],
+        )?;
+        Ok(bus)
+    }
+
+    fn recv(&mut self) -> Result<Message> {
+        let mut buf = vec![0; 16];
+        self.sock
+            .read_exact(&mut buf)
+            .map_err(|e| Error::os("D-Bus recv", e))?;
+        let word = |n: usize| u32::from_le_bytes([buf[n], buf[n + 1], buf[n + 2], buf[n + 3]]);
+        let body_len = word(4) as usize;
+        let fields_len = word(12) as usize;
+        // header is padded to 8 bytes
+        let header_len = 16 + fields_len + (8 - fields_len % 8) % 8;
+        buf.resize(header_len + body_len, 0);
+        self.sock
+            .read_exact(&mut buf[16..])
+            .map_err(|e| Error::os("D-Bus recv", e))?;
+        Message::parse(&buf)
+    }
+
+    /// Make a method call and wait for the reply.  Other messages received are queued in `pending`.
+    pub fn call(
+        &mut self,
+        dest: &str,
+        path: &str,
+        iface: &str,
+        member: &str,
+        sig: &str,
+        body: &[u8],
+    ) -> Result<Message> {
+        self.call_fds(dest, path, iface, member, sig, body, &[])
+    }
+
+    /// As `call()`, also passing file descriptors.  Referenced from the body by index ("h").
+    #[allow(clippy::too_many_arguments)]
+    pub fn call_fds(
+        &mut self,
+        dest: &str,
+        path: &str,
+        iface: &str,
+        member: &str,
+        sig: &str,
+        body: &[u8],
+        fds: &[RawFd],
+    ) -> Result<Message> {
+        self.serial += 1;
+        let serial = self.serial;
+        debug!("D-Bus call {dest} {path} {iface}.{member}({sig})");
+        let msg = method_call(
+            serial,
+            dest,
+            path,
+            iface,
+            member,
+            sig,
+            body,
+            fds.len() as u32,
+        );
+        if fds.is_empty() {
+            self.sock
+                .write_all(&msg)
+                .map_err(|e| Error::os("D-Bus send", e))?;
+        } else if self.unix_fds {
+            util::send_fds(&self.sock, &msg, fds)?;
+        } else {
+            return Err(proto("bus does not support passing file descriptors"));
+        }
+
+        loop {
+            let reply = self.recv()?;
+            match reply.mtype {
+                METHOD_RETURN if reply.reply_serial == serial => return Ok(reply),
+                ERROR if reply.reply_serial == serial => {
+                    let detail = reply.body().str().unwrap_or_default();
+                    return Err(proto(format!("{}: {}", reply.error_name, detail)));
+                }
+                _ => self.pending.push(reply),
+            }
+        }
+    }
+
+    /// Wait for the next signal
+    pub fn next_signal(&mut self) -> Result<Message> {
+        if let Some(idx) = self.pending.iter().position(|m| m.mtype == SIGNAL) {
+            return Ok(self.pending.remove(idx));
+        }
+        loop {
+            let msg = self.recv()?;
+            if msg.mtype == SIGNAL {
+                return Ok(msg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_roundtrip() {
+        let mut body = Writer::default();
+        body.str("hello").array(8, |w| {
+            w.align(8).str("key").sig("u").u32(42);
+        });
+        let raw = method_call(7, "a.b", "/a/b", "a.b.C", "Do", "sa(sv)", &body.buf, 0);
+
+        let msg = Message::parse(&raw).unwrap();
+        assert_eq!(msg.mtype, METHOD_CALL);
+        assert_eq!(msg.serial, 7);
+        assert_eq!(msg.path, "/a/b");
+        assert_eq!(msg.interface, "a.b.C");
+        assert_eq!(msg.member, "Do");
+        assert_eq!(msg.signature, "sa(sv)");
+
+        let mut r = msg.body();
+        assert_eq!(r.str().unwrap(), "hello");
+        assert_eq!(r.u32().unwrap(), 16);
+        r.align(8).unwrap();
+        assert_eq!(r.str().unwrap(), "key");
+        assert_eq!(r.sig().unwrap(), "u");
+        assert_eq!(r.u32().unwrap(), 42);
+    }
+
+    #[test]
+    fn skip() {
+        assert_eq!(type_len(b"a{sv}s").unwrap(), 5);
+        assert_eq!(type_len(b"(ua(ss))").unwrap(), 8);
+        type_len(b"a").unwrap_err();
+        type_len(b"(u").unwrap_err();
+
+        let mut w = Writer::default();
+        w.u32(1).array(8, |w| {
+            w.align(8).str("multiple").sig("b").u32(1);
+            w.align(8).str("uris").sig("as").array(4, |w| {
+                w.str("file:///a").str("file:///b");
+            });
+        });
+        w.str("end");
+        let mut r = Reader::new(&w.buf);
+        r.skip("ua{sv}").unwrap();
+        assert_eq!(r.str().unwrap(), "end");
+
+        let mut r = Reader::new(&w.buf);
+        r.u32().unwrap();
+        let mut uris = vec![];
+        r.vardict(|key, sig, r| match (key, sig) {
+            ("uris", "as") => r.strs().map(|v| uris = v),
+            _ => r.skip(sig),
+        })
+        .unwrap();
+        assert_eq!(uris, ["file:///a", "file:///b"]);
+        assert_eq!(r.str().unwrap(), "end");
+    }
+
+    #[test]
+    fn empty_array() {
+        let mut w = Writer::default();
+        w.byte(1).array(8, |_| {});
+        // length excludes padding to first element
+        assert_eq!(w.buf, [1, 0, 0, 0, 0, 0, 0, 0]);
+    }
+}
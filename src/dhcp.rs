@@ -0,0 +1,383 @@
+//! Minimal DHCPv4 client.  For a sandbox attached to a host bridge.
+//!
+//! Makes one DISCOVER, REQUEST, ACK exchange (RFC 2131), and applies the lease.
+//! A lease is never renewed.  So a sandbox may outlive its lease, which most
+//! servers tolerate when the pool is not exhausted.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+
+use super::err::{Error, Result};
+use super::net;
+
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const MAGIC: [u8; 4] = [99, 130, 83, 99];
+/// Ask that replies be broadcast, since we have no address yet
+const FLAG_BROADCAST: u16 = 0x8000;
+/// op through options, without any option
+const HEADER_LEN: usize = 240;
+
+// options
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETERS: u8 = 55;
+const OPT_END: u8 = 255;
+
+// message types
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+/// Configuration from a DHCP server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub address: Ipv4Addr,
+    /// Prefix length of the subnet.  eg. 24
+    pub prefix: u8,
+    /// Default gateway
+    pub router: Option<Ipv4Addr>,
+    /// Name servers
+    pub dns: Vec<Ipv4Addr>,
+    /// Seconds
+    pub lease_time: Option<u32>,
+    pub server: Ipv4Addr,
+}
+
+impl Lease {
+    /// Add the address, and default route, to `ifname`
+    pub fn apply<S: AsRef<str>>(&self, ifname: S) -> Result<()> {
+        let ifname = ifname.as_ref();
+        net::add_address(ifname, self.address, self.prefix)?;
+        if let Some(router) = self.router {
+            net::add_default_route(ifname, router)?;
+        }
+        Ok(())
+    }
+
+    /// Content for `/etc/resolv.conf`.  None without name servers.
+    pub fn resolv_conf(&self) -> Option<String> {
+        if self.dns.is_empty() {
+            return None;
+        }
+        let mut ret = format!("# from DHCP server {}\n", self.server);
+        for server in &self.dns {
+            ret.push_str(&format!("nameserver {}\n", server));
+        }
+        Some(ret)
+    }
+}
+
+/// A reply from a server
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Reply {
+    mtype: u8,
+    yiaddr: Ipv4Addr,
+    server: Option<Ipv4Addr>,
+    mask: Option<Ipv4Addr>,
+    router: Option<Ipv4Addr>,
+    dns: Vec<Ipv4Addr>,
+    lease_time: Option<u32>,
+}
+
+impl Reply {
+    fn lease(&self, server: Ipv4Addr) -> Lease {
+        let prefix = self
+            .mask
+            .map(|mask| u32::from(mask).leading_ones() as u8)
+            .unwrap_or(24);
+        Lease {
+            address: self.yiaddr,
+            prefix,
+            router: self.router,
+            dns: self.dns.clone(),
+            lease_time: self.lease_time,
+            server,
+        }
+    }
+}
+
+/// Encode a DISCOVER, or with `request = Some((address, server))` a REQUEST
+fn encode(xid: u32, hwaddr: &[u8], request: Option<(Ipv4Addr, Ipv4Addr)>) -> Vec<u8> {
+    let mut msg = vec![0u8; HEADER_LEN];
+    msg[0] = BOOTREQUEST;
+    // htype ethernet, unless some other link type
+    msg[1] = if hwaddr.len() == 6 { 1 } else { 0 };
+    msg[2] = hwaddr.len().min(16) as u8;
+    msg[4..8].copy_from_slice(&xid.to_be_bytes());
+    msg[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    let hlen = msg[2] as usize;
+    msg[28..28 + hlen].copy_from_slice(&hwaddr[..hlen]);
+    msg[236..240].copy_from_slice(&MAGIC);
+
+    let mut option = |code: u8, data: &[u8]| {
+        msg.push(code);
+        msg.push(data.len() as u8);
+        msg.extend_from_slice(data);
+    };
+    match request {
+        None => option(OPT_MESSAGE_TYPE, &[DHCPDISCOVER]),
+        Some((address, server)) => {
+            option(OPT_MESSAGE_TYPE, &[DHCPREQUEST]);
+            option(OPT_REQUESTED_IP, &address.octets());
+            option(OPT_SERVER_ID, &server.octets());
+        }
+    }
+    option(
+        OPT_PARAMETERS,
+        &[OPT_SUBNET_MASK, OPT_ROUTER, OPT_DNS, OPT_LEASE_TIME],
+    );
+    msg.push(OPT_END);
+    // some servers ignore a BOOTP message shorter than 300 bytes
+    if msg.len() < 300 {
+        msg.resize(300, OPT_PAD);
+    }
+    msg
+}
+
+fn addr(data: &[u8]) -> Option<Ipv4Addr> {
+    let b: [u8; 4] = data.get(..4)?.try_into().ok()?;
+    Some(Ipv4Addr::from(b))
+}
+
+/// Parse a reply to transaction `xid`.  None if not a reply to us, or malformed.
+fn parse(msg: &[u8], xid: u32) -> Option<Reply> {
+    if msg.len() < HEADER_LEN
+        || msg[0] != BOOTREPLY
+        || msg[4..8] != xid.to_be_bytes()
+        || msg[236..240] != MAGIC
+    {
+        return None;
+    }
+    let mut ret = Reply {
+        mtype: 0,
+        yiaddr: addr(&msg[16..20])?,
+        server: None,
+        mask: None,
+        router: None,
+        dns: vec![],
+        lease_time: None,
+    };
+
+    let mut opts = &msg[HEADER_LEN..];
+    while let Some((&code, rest)) = opts.split_first() {
+        match code {
+            OPT_PAD => {
+                opts = rest;
+                continue;
+            }
+            OPT_END => break,
+            _ => (),
+        }
+        let len = *rest.first()? as usize;
+        let data = rest.get(1..1 + len)?;
+        match code {
+            OPT_MESSAGE_TYPE => ret.mtype = *data.first()?,
+            OPT_SERVER_ID => ret.server = addr(data),
+            OPT_SUBNET_MASK => ret.mask = addr(data),
+            OPT_ROUTER => ret.router = addr(data),
+            OPT_DNS => ret.dns = data.chunks_exact(4).filter_map(addr).collect(),
+            OPT_LEASE_TIME => ret.lease_time = data.try_into().ok().map(u32::from_be_bytes),
+            _ => (),
+        }
+        opts = &rest[1 + len..];
+    }
+    if ret.mtype == 0 {
+        None
+    } else {
+        Some(ret)
+    }
+}
+
+/// Transaction ID.  Need not be secret, only unlikely to collide.
+fn new_xid() -> u32 {
+    let mut buf = [0u8; 4];
+    let ret = unsafe { libc::getrandom(buf.as_mut_ptr() as *mut _, buf.len(), 0) };
+    if ret == buf.len() as isize {
+        u32::from_ne_bytes(buf)
+    } else {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        std::process::id() ^ nanos
+    }
+}
+
+fn socket(ifname: &str) -> Result<UdpSocket> {
+    let sock = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, CLIENT_PORT))
+        .map_err(|e| Error::os("bind dhcp client", e))?;
+    sock.set_broadcast(true)
+        .map_err(|e| Error::os("SO_BROADCAST", e))?;
+    let ret = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            ifname.as_ptr() as *const _,
+            ifname.len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error("SO_BINDTODEVICE"));
+    }
+    Ok(sock)
+}
+
+/// Send `msg`, and wait for a reply of type `expect` until `deadline`.  Resent each second.
+fn exchange(
+    sock: &UdpSocket,
+    msg: &[u8],
+    xid: u32,
+    expect: u8,
+    deadline: Instant,
+) -> Result<Reply> {
+    let dest = SocketAddrV4::new(Ipv4Addr::BROADCAST, SERVER_PORT);
+    let mut buf = vec![0u8; 1500];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::os("dhcp", io::ErrorKind::TimedOut.into()));
+        }
+        sock.send_to(msg, dest)
+            .map_err(|e| Error::os("send dhcp", e))?;
+        let resend = (now + Duration::from_secs(1)).min(deadline);
+        loop {
+            let now = Instant::now();
+            if now >= resend {
+                break;
+            }
+            sock.set_read_timeout(Some(resend - now))
+                .map_err(|e| Error::os("SO_RCVTIMEO", e))?;
+            let len = match sock.recv(&mut buf) {
+                Ok(len) => len,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue
+                }
+                Err(err) => return Err(Error::os("recv dhcp", err)),
+            };
+            match parse(&buf[..len], xid) {
+                Some(reply) if reply.mtype == expect => return Ok(reply),
+                Some(reply) if reply.mtype == DHCPNAK => {
+                    return Err(Error::os(
+                        "dhcp NAK",
+                        io::ErrorKind::ConnectionRefused.into(),
+                    ))
+                }
+                _ => debug!("Ignore DHCP message"),
+            }
+        }
+    }
+}
+
+/// Obtain a lease for `ifname`, which must be up.  Gives up after `timeout`.
+pub fn discover<S: AsRef<str>>(ifname: S, timeout: Duration) -> Result<Lease> {
+    let ifname = ifname.as_ref();
+    let deadline = Instant::now() + timeout;
+    let hwaddr = net::interfaces()?
+        .into_iter()
+        .find(|iface| iface.name == ifname)
+        .map(|iface| iface.hwaddr)
+        .unwrap_or_default();
+    let sock = socket(ifname)?;
+    let xid = new_xid();
+
+    debug!("DHCP discover on {} xid={:08x}", ifname, xid);
+    let offer = exchange(&sock, &encode(xid, &hwaddr, None), xid, DHCPOFFER, deadline)?;
+    let server = offer
+        .server
+        .ok_or_else(|| Error::os("dhcp offer", io::ErrorKind::InvalidData.into()))?;
+    debug!("DHCP offer {} from {}", offer.yiaddr, server);
+
+    let request = encode(xid, &hwaddr, Some((offer.yiaddr, server)));
+    let ack = exchange(&sock, &request, xid, DHCPACK, deadline)?;
+    let lease = ack.lease(server);
+    debug!("DHCP lease {:?}", lease);
+    Ok(lease)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A reply as a server would send it
+    fn reply(xid: u32, mtype: u8) -> Vec<u8> {
+        let mut msg = vec![0u8; HEADER_LEN];
+        msg[0] = BOOTREPLY;
+        msg[4..8].copy_from_slice(&xid.to_be_bytes());
+        msg[16..20].copy_from_slice(&[10, 0, 0, 42]);
+        msg[236..240].copy_from_slice(&MAGIC);
+        msg.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, mtype, OPT_PAD]);
+        msg.extend_from_slice(&[OPT_SERVER_ID, 4, 10, 0, 0, 1]);
+        msg.extend_from_slice(&[OPT_SUBNET_MASK, 4, 255, 255, 240, 0]);
+        msg.extend_from_slice(&[OPT_ROUTER, 4, 10, 0, 0, 1]);
+        msg.extend_from_slice(&[OPT_DNS, 8, 10, 0, 0, 2, 10, 0, 0, 3]);
+        msg.extend_from_slice(&[OPT_LEASE_TIME, 4, 0, 0, 0x0e, 0x10]);
+        msg.push(OPT_END);
+        msg
+    }
+
+    #[test]
+    fn messages() {
+        let hwaddr = [2, 0, 0, 0, 0, 1];
+        let msg = encode(
+            0x1234,
+            &hwaddr,
+            Some(([10, 0, 0, 42].into(), [10, 0, 0, 1].into())),
+        );
+        assert_eq!(msg.len(), 300);
+        assert_eq!(msg[0], BOOTREQUEST);
+        assert_eq!(&msg[4..8], &[0, 0, 0x12, 0x34]);
+        assert_eq!(&msg[28..34], &hwaddr);
+        assert_eq!(&msg[240..243], &[OPT_MESSAGE_TYPE, 1, DHCPREQUEST]);
+        assert_eq!(&msg[243..249], &[OPT_REQUESTED_IP, 4, 10, 0, 0, 42]);
+
+        let ack = parse(&reply(0x1234, DHCPACK), 0x1234).unwrap();
+        assert_eq!(ack.mtype, DHCPACK);
+        let lease = ack.lease(ack.server.unwrap());
+        assert_eq!(
+            lease,
+            Lease {
+                address: [10, 0, 0, 42].into(),
+                prefix: 20,
+                router: Some([10, 0, 0, 1].into()),
+                dns: vec![[10, 0, 0, 2].into(), [10, 0, 0, 3].into()],
+                lease_time: Some(3600),
+                server: [10, 0, 0, 1].into(),
+            }
+        );
+        assert_eq!(
+            lease.resolv_conf().unwrap(),
+            "# from DHCP server 10.0.0.1\nnameserver 10.0.0.2\nnameserver 10.0.0.3\n"
+        );
+
+        // another transaction
+        assert!(parse(&reply(0x1234, DHCPACK), 0x4321).is_none());
+        // truncated option
+        let mut msg = reply(0x1234, DHCPACK);
+        msg.truncate(msg.len() - 4);
+        assert!(parse(&msg, 0x1234).is_none());
+    }
+}
@@ -0,0 +1,100 @@
+//! Deterministic randomness, for reproducing failures which depend on random values.
+//! A testing feature.  Never use it where randomness matters for security.
+//!
+//! `/dev/urandom` and `/dev/random` are replaced by a file of bytes generated from a seed,
+//! and `getrandom()` fails with `ENOSYS`, so that most libraries fall back to reading
+//! `/dev/urandom`.  Each open reads the same bytes from the start, regardless of the order
+//! in which processes run.  A reader of more than `SIZE` bytes sees end of file.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use super::container::Result;
+use super::err::Error;
+use super::seccomp::Filter;
+use super::{path, util};
+
+/// Length of the replacement file
+pub const SIZE: usize = 1 << 20;
+
+/// Device files replaced
+const DEVICES: &[&str] = &["dev/urandom", "dev/random"];
+
+/// SplitMix64.  Not cryptographic.  Only reproducible.
+#[derive(Debug, Clone)]
+pub struct Seeded {
+    state: u64,
+}
+
+impl Seeded {
+    pub fn new(seed: u64) -> Self {
+        Seeded { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+/// Refuse `getrandom()`, so that callers fall back to `/dev/urandom`
+pub fn deny_getrandom(filter: &mut Filter) {
+    filter.deny(libc::SYS_getrandom, libc::ENOSYS);
+}
+
+/// With privilege, before `pivot_root()` to `new_root`.  Write `SIZE` bytes generated
+/// from `seed` under `tdir`, then bind over the random devices of `new_root`.
+pub fn prepare(seed: u64, tdir: &Path, new_root: &Path) -> Result<()> {
+    let name = path!(tdir, "entropy");
+    let file = File::create(&name).map_err(|e| Error::file("create", &name, e))?;
+    let mut out = BufWriter::new(file);
+    let mut gen = Seeded::new(seed);
+    let mut buf = [0; 4096];
+    for _ in 0..SIZE / buf.len() {
+        gen.fill(&mut buf);
+        out.write_all(&buf)?;
+    }
+    out.flush()?;
+    fs::set_permissions(&name, fs::Permissions::from_mode(0o444))?;
+
+    for dev in DEVICES {
+        let target = new_root.join(dev);
+        if target.exists() {
+            debug!("Seeded {} with {}", target.display(), seed);
+            util::mount(&name, &target, "", libc::MS_BIND)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded() {
+        let mut a = [0u8; 21];
+        let mut b = [0u8; 21];
+        Seeded::new(42).fill(&mut a);
+        Seeded::new(42).fill(&mut b);
+        assert_eq!(a, b);
+        Seeded::new(43).fill(&mut b);
+        assert_ne!(a, b);
+        // reference value of SplitMix64
+        assert_eq!(Seeded::new(0).next_u64(), 0xe220_a839_7b1d_cdaf);
+    }
+}
@@ -0,0 +1,148 @@
+//! Environment variables passed through to a sandboxed command.  For isolate --env-preset.
+//!
+//! By default, the whole environment of the caller is inherited.  A preset instead
+//! passes only a curated list, so that tokens, agent sockets, and the like are not
+//! leaked by accident.  cf. `util::Exec::env_policy()`
+
+use std::fmt;
+
+use super::err::{Error, Result};
+
+/// A curated allow-list of variables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// Locale, terminal, and `$PATH`
+    Minimal,
+    /// `Minimal`, and variables understood by build tools.  eg. `$MAKEFLAGS`
+    Ci,
+    /// `Minimal`, and the display.  eg. `$WAYLAND_DISPLAY`
+    Desktop,
+}
+
+pub const PRESETS: &[Preset] = &[Preset::Ci, Preset::Minimal, Preset::Desktop];
+
+/// A trailing `*` matches any suffix
+const MINIMAL: &[&str] = &[
+    "HOME", "LANG", "LANGUAGE", "LC_*", "LOGNAME", "PATH", "TERM", "TZ", "USER",
+];
+
+const CI: &[&str] = &[
+    "CARGO_HOME",
+    "CARGO_TARGET_DIR",
+    "CC",
+    "CFLAGS",
+    "CI",
+    "CPPFLAGS",
+    "CXX",
+    "CXXFLAGS",
+    "LDFLAGS",
+    "MAKEFLAGS",
+    "MFLAGS",
+    "NO_COLOR",
+    "RUSTFLAGS",
+    "RUSTUP_HOME",
+    "RUSTUP_TOOLCHAIN",
+    "SOURCE_DATE_EPOCH",
+];
+
+const DESKTOP: &[&str] = &[
+    "COLORTERM",
+    "DISPLAY",
+    "WAYLAND_DISPLAY",
+    "XAUTHORITY",
+    "XDG_CURRENT_DESKTOP",
+    "XDG_RUNTIME_DIR",
+    "XDG_SESSION_TYPE",
+];
+
+impl Preset {
+    /// Names of the variables passed through
+    pub fn allowed(self) -> Vec<&'static str> {
+        let extra = match self {
+            Preset::Minimal => &[][..],
+            Preset::Ci => CI,
+            Preset::Desktop => DESKTOP,
+        };
+        let mut ret: Vec<&str> = MINIMAL.iter().chain(extra).copied().collect();
+        ret.sort_unstable();
+        ret
+    }
+
+    /// Is variable `name` passed through?
+    pub fn allows(self, name: &str) -> bool {
+        self.allowed()
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == *pattern,
+            })
+    }
+
+    /// Those of `vars` which are passed through
+    pub fn filter<I, K, V>(self, vars: I) -> Vec<(K, V)>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+    {
+        vars.into_iter()
+            .filter(|(name, _)| self.allows(name.as_ref()))
+            .collect()
+    }
+}
+
+impl std::str::FromStr for Preset {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Preset> {
+        match s {
+            "ci" => Ok(Preset::Ci),
+            "minimal" => Ok(Preset::Minimal),
+            "desktop" => Ok(Preset::Desktop),
+            _ => Err(Error::os(
+                format!("Unknown environment preset {:?}", s),
+                std::io::ErrorKind::InvalidInput.into(),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Preset::Ci => write!(f, "ci"),
+            Preset::Minimal => write!(f, "minimal"),
+            Preset::Desktop => write!(f, "desktop"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets() {
+        for preset in PRESETS {
+            assert_eq!(preset.to_string().parse::<Preset>().unwrap(), *preset);
+        }
+        "none".parse::<Preset>().unwrap_err();
+
+        assert!(Preset::Minimal.allows("PATH"));
+        assert!(Preset::Minimal.allows("LC_ALL"));
+        assert!(!Preset::Minimal.allows("DISPLAY"));
+        assert!(!Preset::Minimal.allows("SSH_AUTH_SOCK"));
+        assert!(Preset::Desktop.allows("WAYLAND_DISPLAY"));
+        assert!(Preset::Desktop.allows("TERM"));
+        assert!(Preset::Ci.allows("MAKEFLAGS"));
+        assert!(!Preset::Ci.allows("GITHUB_TOKEN"));
+
+        let vars = vec![
+            ("TERM", "xterm"),
+            ("AWS_SECRET_ACCESS_KEY", "x"),
+            ("LANG", "C"),
+        ];
+        assert_eq!(
+            Preset::Minimal.filter(vars),
+            vec![("TERM", "xterm"), ("LANG", "C")]
+        );
+    }
+}
@@ -23,10 +23,64 @@ pub enum Error {
         name: PathBuf,
     },
     MissingMount,
+    DBus(String),
+    Wayland(String),
+    Hook {
+        name: PathBuf,
+        msg: String,
+    },
+    Cancelled,
+    /// By this signal, before the container command was started
+    Interrupted(libc::c_int),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+macro_rules! errnos {
+    ($($name:ident),*) => {
+        /// `errno` of a failed syscall.  Only some are named.
+        #[allow(clippy::upper_case_acronyms)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Errno {
+            $($name,)*
+            Other(i32),
+        }
+
+        impl From<i32> for Errno {
+            fn from(raw: i32) -> Errno {
+                match raw {
+                    $(libc::$name => Errno::$name,)*
+                    raw => Errno::Other(raw),
+                }
+            }
+        }
+
+        impl Errno {
+            /// Numeric value.  eg. `libc::EBUSY`
+            pub fn raw(self) -> i32 {
+                match self {
+                    $(Errno::$name => libc::$name,)*
+                    Errno::Other(raw) => raw,
+                }
+            }
+        }
+
+        impl fmt::Display for Errno {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $(Errno::$name => write!(f, stringify!($name)),)*
+                    Errno::Other(raw) => write!(f, "errno {}", raw),
+                }
+            }
+        }
+    };
+}
+
+errnos!(
+    EPERM, ENOENT, ESRCH, EINTR, EIO, EBADF, ECHILD, EAGAIN, ENOMEM, EACCES, EBUSY, EEXIST, EXDEV,
+    ENODEV, ENOTDIR, EISDIR, EINVAL, ENOSPC, EROFS, ENOSYS, ELOOP, EOVERFLOW, EOPNOTSUPP
+);
+
 impl Error {
     /// Annotate I/O error
     pub fn file<S: AsRef<str>, P: AsRef<Path>>(desc: S, path: P, err: io::Error) -> Self {
@@ -61,6 +115,20 @@ impl Error {
         }
     }
 
+    /// The `errno` of a failed syscall, if any
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Self::File { io, .. } => io.raw_os_error(),
+            Self::OS { io, .. } => io.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    /// The `errno` of a failed syscall, if any.  eg. to branch on `Errno::EBUSY`
+    pub fn errno(&self) -> Option<Errno> {
+        self.raw_os_error().map(Errno::from)
+    }
+
     pub fn is_io_error(&self, kind: io::ErrorKind) -> bool {
         match self {
             Self::File { io, .. } => io.kind() == kind,
@@ -95,6 +163,11 @@ impl fmt::Display for Error {
                 write!(f, "Error: {} while parsing {}", msg, name.display())
             }
             Self::MissingMount => write!(f, "Missing mount point info"),
+            Self::DBus(msg) => write!(f, "D-Bus: {}", msg),
+            Self::Wayland(msg) => write!(f, "Wayland: {}", msg),
+            Self::Hook { name, msg } => write!(f, "Hook {} {}", name.display(), msg),
+            Self::Cancelled => write!(f, "Cancelled"),
+            Self::Interrupted(sig) => write!(f, "Interrupted during setup by signal {}", sig),
         }
     }
 }
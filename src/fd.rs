@@ -0,0 +1,113 @@
+//! File descriptor flags, and duplication
+
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use super::err::{Error, Result};
+
+/// Descriptor flags.  eg. `libc::FD_CLOEXEC`
+pub fn get_fd_flags<F: AsRawFd>(fd: F) -> Result<libc::c_int> {
+    let ret = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+    if ret < 0 {
+        return Err(Error::last_os_error("F_GETFD"));
+    }
+    Ok(ret)
+}
+
+/// Maniplate the `FD_CLOEXEC` bit on the provided file descriptor.
+pub fn set_cloexec<F: AsRawFd>(fd: F, v: bool) -> Result<()> {
+    let fdn = fd.as_raw_fd();
+    let mut cur = get_fd_flags(fdn)?;
+    if v {
+        cur |= libc::FD_CLOEXEC;
+    } else {
+        cur &= !libc::FD_CLOEXEC;
+    }
+    let err = unsafe { libc::fcntl(fdn, libc::F_SETFD, cur) };
+    if err < 0 {
+        return Err(Error::last_os_error("F_SETFD"));
+    }
+    Ok(())
+}
+
+/// Maniplate the `O_NONBLOCK` status flag.  Shared by all duplicates of the descriptor.
+pub fn set_nonblocking<F: AsRawFd>(fd: F, v: bool) -> Result<()> {
+    let fdn = fd.as_raw_fd();
+    let mut cur = unsafe { libc::fcntl(fdn, libc::F_GETFL) };
+    if cur < 0 {
+        return Err(Error::last_os_error("F_GETFL"));
+    }
+    if v {
+        cur |= libc::O_NONBLOCK;
+    } else {
+        cur &= !libc::O_NONBLOCK;
+    }
+    let err = unsafe { libc::fcntl(fdn, libc::F_SETFL, cur) };
+    if err < 0 {
+        return Err(Error::last_os_error("F_SETFL"));
+    }
+    Ok(())
+}
+
+/// Wraps `dup2()`.  Replace descriptor `dst` with a duplicate of `src`.
+/// `FD_CLOEXEC` of `dst` is cleared, even if `src` and `dst` are the same.
+pub fn dup_over<F: AsRawFd>(src: F, dst: RawFd) -> Result<()> {
+    let src = src.as_raw_fd();
+    if src == dst {
+        // dup2() would do nothing
+        return set_cloexec(dst, false);
+    }
+    if unsafe { libc::dup2(src, dst) } < 0 {
+        return Err(Error::last_os_error(format!("dup2({}, {})", src, dst)));
+    }
+    Ok(())
+}
+
+/// Is `fd` an open file descriptor?
+pub fn is_valid(fd: RawFd) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) >= 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn flags() {
+        let (rx, tx) = crate::util::pipe().unwrap();
+        set_cloexec(rx.as_raw_fd(), true).unwrap();
+        assert_eq!(get_fd_flags(rx.as_raw_fd()).unwrap(), libc::FD_CLOEXEC);
+        set_cloexec(rx.as_raw_fd(), false).unwrap();
+        assert_eq!(get_fd_flags(rx.as_raw_fd()).unwrap(), 0);
+
+        set_nonblocking(rx.as_raw_fd(), true).unwrap();
+        let err = (&rx).read(&mut [0u8; 1]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        set_nonblocking(rx.as_raw_fd(), false).unwrap();
+
+        assert!(is_valid(tx.as_raw_fd()));
+        assert!(!is_valid(-1));
+        get_fd_flags(-1).unwrap_err();
+    }
+
+    #[test]
+    fn dup() {
+        let (mut rx, tx) = crate::util::pipe().unwrap();
+        let (rx2, tx2) = crate::util::pipe().unwrap();
+        set_cloexec(tx2.as_raw_fd(), true).unwrap();
+        // tx2 now refers to the first pipe
+        dup_over(tx.as_raw_fd(), tx2.as_raw_fd()).unwrap();
+        assert_eq!(get_fd_flags(tx2.as_raw_fd()).unwrap(), 0);
+        drop(rx2);
+        drop(tx);
+        (&tx2).write_all(b"x").unwrap();
+        drop(tx2);
+        let mut buf = String::new();
+        rx.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "x");
+
+        set_cloexec(rx.as_raw_fd(), true).unwrap();
+        dup_over(rx.as_raw_fd(), rx.as_raw_fd()).unwrap();
+        assert_eq!(get_fd_flags(rx.as_raw_fd()).unwrap(), 0);
+    }
+}
@@ -0,0 +1,250 @@
+//! Fork-server.  A sandbox prepared once, where each request runs in a fresh copy of it.
+//!
+//! For workloads which start many short commands (eg. fuzzers, or per-test isolation),
+//! where creating namespaces and mounts for each would dominate.  cf. `test::Isolated::serve()`
+//!
+//! ```no_run
+//! use sandbox::forkserver;
+//!
+//! let code = forkserver::request("/tmp/sandbox-server.sock", &["make", "check"]).unwrap();
+//! ```
+//!
+//! Each copy has its own PID namespace, where the command is process 1, and its own mount
+//! namespace, with new instances of `/proc`, `/tmp`, and `/var/tmp`.  Copies share the
+//! network namespace, and all other mounts, of the server.  Including those under `/tmp`.
+//! eg. of the working directory.
+//!
+//! A request passes stdin, stdout, and stderr of the client, then the working directory
+//! and arguments.  The reply is the exit code of the command.
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsFd, AsRawFd, OwnedFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use super::container::Result;
+use super::fs::Mounts;
+use super::proc::{fork, Proc};
+use super::{err, fd, util};
+
+/// Set inside the sandbox, to the descriptor of the listening socket
+pub const ENV_LISTEN_FD: &str = "SANDBOX_SERVER_FD";
+
+/// Longest request accepted.  Working directory and arguments.
+const MAX_REQUEST: usize = 1 << 20;
+
+/// Create the socket of a server.  Only connectable by the calling user.
+pub fn bind<P: AsRef<Path>>(socket: P) -> Result<UnixListener> {
+    // created 0600, rather than changed after, when another user could already connect
+    let mask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket);
+    unsafe { libc::umask(mask) };
+    Ok(listener?)
+}
+
+/// Serve requests from `listener`, until killed.  Called inside the prepared sandbox,
+/// with `CAP_SYS_ADMIN`, which each copy drops before running its command.
+pub fn run(listener: UnixListener) -> Result<()> {
+    // process 1 of the sandbox, which ignores signals without a handler
+    for sig in [libc::SIGINT, libc::SIGTERM] {
+        unsafe { signal_hook::low_level::register(sig, move || libc::_exit(128 + sig))? };
+    }
+    let mut copies: Vec<Proc> = vec![];
+    loop {
+        let (conn, _) = listener.accept()?;
+        // the socket may since have been made accessible to others
+        match util::peer_cred(&conn) {
+            Ok(cred) if cred.uid == util::getuid() => (),
+            Ok(cred) => {
+                warn!("Refuse request from uid {} (pid {})", cred.uid, cred.pid);
+                continue;
+            }
+            Err(err) => {
+                warn!("Refuse request : {}", err);
+                continue;
+            }
+        }
+        copies.retain_mut(|copy| matches!(copy.try_wait(), Ok(None)));
+        match fork(|| copy(conn)) {
+            Ok(copy) => copies.push(copy),
+            Err(err) => warn!("Unable to fork for request : {}", err),
+        }
+    }
+}
+
+/// Run the command of one request, in new PID and mount namespaces
+fn copy(mut conn: UnixStream) -> Result<()> {
+    let mut stdio = vec![];
+    for _ in 0..3 {
+        match util::recv_fd(&conn)? {
+            Some(fd) => stdio.push(fd),
+            None => return Err("Request without stdin, stdout, and stderr".into()),
+        }
+    }
+    let (cwd, args) = read_request(&mut conn)?;
+    debug!("Request {:?} in {}", args, cwd.display());
+
+    util::unshare(libc::CLONE_NEWNS | libc::CLONE_NEWPID)?;
+    util::mount("", "/", "", libc::MS_REC | libc::MS_PRIVATE)?;
+
+    let mut pid = fork(|| exec(&stdio, &cwd, &args))?;
+    drop(stdio);
+    let code = pid.park()?;
+    debug!("Request {:?} exit with {}", args, code);
+    conn.write_all(&code.to_ne_bytes())?;
+    Ok(())
+}
+
+/// As process 1 of the new PID namespace
+fn exec(stdio: &[OwnedFd], cwd: &Path, args: &[String]) -> Result<()> {
+    util::mount(
+        "proc",
+        "/proc",
+        "proc",
+        libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_NOSUID,
+    )?;
+    let mounts = Mounts::current()?;
+    for dir in ["/tmp", "/var/tmp"] {
+        if !Path::new(dir).is_dir() {
+            continue;
+        }
+        // binds of the server under it (eg. of a working directory) are made again on top
+        let mut binds = vec![];
+        for mp in mounts.visible_under(dir) {
+            if mp.mount_point != Path::new(dir) {
+                let src = File::open(&mp.mount_point)
+                    .map_err(|e| err::Error::file("open", &mp.mount_point, e))?;
+                binds.push((&mp.mount_point, src));
+            }
+        }
+        util::mount("none", dir, "tmpfs", libc::MS_NODEV | libc::MS_NOSUID)?;
+        for (point, src) in binds {
+            util::mkdirs(point)?;
+            let src = format!("/proc/self/fd/{}", src.as_raw_fd());
+            util::mount(src, point, "", libc::MS_BIND | libc::MS_REC)?;
+        }
+    }
+    for (fd, dst) in stdio.iter().zip(0..) {
+        fd::dup_over(fd.as_raw_fd(), dst)?;
+    }
+    if let Err(err) = env::set_current_dir(cwd) {
+        warn!("Unable to enter {} : {}", cwd.display(), err);
+    }
+    util::Cap::current()?.clear().update()?;
+    util::Exec::new(&args[0])?.args(args)?.exec()?;
+    Ok(())
+}
+
+/// Nil separated.  The working directory, then the arguments.
+fn encode_request(cwd: &Path, args: &[&str]) -> Vec<u8> {
+    let mut body = cwd.as_os_str().as_bytes().to_vec();
+    for arg in args {
+        body.push(0);
+        body.extend_from_slice(arg.as_bytes());
+    }
+    let mut msg = (body.len() as u32).to_ne_bytes().to_vec();
+    msg.extend_from_slice(&body);
+    msg
+}
+
+fn decode_request(body: &[u8]) -> Result<(PathBuf, Vec<String>)> {
+    let mut parts = body.split(|b| *b == 0);
+    let cwd = PathBuf::from(OsStr::from_bytes(parts.next().unwrap_or_default()));
+    let args: Vec<String> = parts
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    if args.is_empty() {
+        return Err("Request without a command".into());
+    }
+    Ok((cwd, args))
+}
+
+fn read_request(conn: &mut UnixStream) -> Result<(PathBuf, Vec<String>)> {
+    let mut len = [0; 4];
+    conn.read_exact(&mut len)?;
+    let len = u32::from_ne_bytes(len) as usize;
+    if len > MAX_REQUEST {
+        return Err(format!("Request of {} bytes is too long", len).into());
+    }
+    let mut body = vec![0; len];
+    conn.read_exact(&mut body)?;
+    decode_request(&body)
+}
+
+/// Run `args` in a fresh copy of the sandbox served at `socket`, with the stdin, stdout,
+/// and stderr, and in the working directory, of the caller.  Returns the exit code.
+/// `args[0]` is found through `$PATH` of the server.
+pub fn request<P: AsRef<Path>, S: AsRef<str>>(socket: P, args: &[S]) -> Result<i32> {
+    if args.is_empty() {
+        return Err("forkserver::request() without a command".into());
+    }
+    let args: Vec<&str> = args.iter().map(AsRef::as_ref).collect();
+    let mut conn = UnixStream::connect(socket)?;
+    for fd in [
+        io::stdin().as_fd(),
+        io::stdout().as_fd(),
+        io::stderr().as_fd(),
+    ] {
+        util::send_fd(&conn, fd)?;
+    }
+    conn.write_all(&encode_request(&env::current_dir()?, &args))?;
+    let mut code = [0; 4];
+    conn.read_exact(&mut code).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => "Server closed connection before the command exited".into(),
+        _ => Box::new(err) as super::container::Error,
+    })?;
+    Ok(i32::from_ne_bytes(code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        let msg = encode_request(Path::new("/some/dir"), &["echo", "a b", ""]);
+        assert_eq!(&msg[..4], &(msg.len() as u32 - 4).to_ne_bytes());
+        let (cwd, args) = decode_request(&msg[4..]).unwrap();
+        assert_eq!(cwd, Path::new("/some/dir"));
+        assert_eq!(args, ["echo", "a b", ""]);
+
+        assert!(decode_request(b"/some/dir").is_err());
+    }
+
+    #[test]
+    fn bind_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let tdir = crate::tempdir::TempDir::new().unwrap();
+        let socket = tdir.path().join("server.sock");
+        let _listener = bind(&socket).unwrap();
+        let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn serve() {
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let tdir = crate::tempdir::TempDir::new().unwrap();
+        let socket = tdir.path().join("server.sock");
+        let marker = tdir.path().join("marker");
+        std::fs::write(&marker, "").unwrap();
+        let listener = bind(&socket).unwrap();
+        let server = fork(|| run(listener)).unwrap();
+
+        // process 1, with a new /tmp, so the marker is hidden
+        let script = format!("test $$ = 1 && ! test -e {} && exit 3", marker.display());
+        for _ in 0..2 {
+            assert_eq!(request(&socket, &["sh", "-c", &script]).unwrap(), 3);
+        }
+        assert!(request(&socket, &["/nonexistent"]).unwrap() != 0);
+        drop(server);
+    }
+}
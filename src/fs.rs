@@ -1,14 +1,18 @@
 //! Filesystem utilities...
 
 use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
-use std::{fmt, fs};
+use std::{fmt, fs, io, mem};
+
+use std::os::unix::ffi::OsStrExt;
 
 use std::os::unix::fs::MetadataExt;
 
 use log::{debug, warn};
 
 use super::err::{Error, Result};
+use super::util;
 
 // like vec!() for a PathBuf
 #[macro_export]
@@ -60,10 +64,10 @@ pub fn find_mount_point<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
 }
 
 /// cf. `Documentation/filesystems/proc.txt` in the Linux kernel source tree.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MountInfo {
     pub id: u64,
-    // parent
+    pub parent: u64,
     // major:minor
     pub root: PathBuf,
     pub mount_point: PathBuf,
@@ -96,13 +100,48 @@ impl fmt::Display for MountInfo {
 /// A list of file system mount points
 #[derive(Debug)]
 pub struct Mounts {
+    fname: PathBuf,
     points: HashMap<PathBuf, MountInfo>,
 }
 
 impl Mounts {
-    /// Mount points in the namespace of the current process
+    /// Mount points in the namespace of the current process.
+    ///
+    /// Uses `listmount()` and `statmount()` when available, otherwise mountinfo.
     pub fn current() -> Result<Mounts> {
-        Self::create(&"self")
+        match Self::from_statmount() {
+            Ok(Some(mounts)) => Ok(mounts),
+            Ok(None) => Self::create("self"),
+            Err(err) => {
+                debug!("statmount() fails, fall back to mountinfo : {}", err);
+                Self::create("self")
+            }
+        }
+    }
+
+    /// Mount points in the namespace of the current process via. `listmount()` and `statmount()`.
+    /// Returns `None` when not supported.  (before Linux 6.11)
+    pub fn from_statmount() -> Result<Option<Mounts>> {
+        let ids = match listmount() {
+            Err(err) if err.is_io_error(io::ErrorKind::Unsupported) => return Ok(None),
+            other => other?,
+        };
+        let mut points = HashMap::new();
+        for id in ids {
+            let info = match statmount(id)? {
+                Some(info) => info,
+                // device name not provided
+                None => return Ok(None),
+            };
+            points.insert(info.mount_point.clone(), info);
+        }
+        if points.is_empty() {
+            Err(Error::MissingMount)?;
+        }
+        Ok(Some(Mounts {
+            fname: "/proc/self/mountinfo".into(),
+            points,
+        }))
     }
 
     /// Mount points in the namespace of the specified PID
@@ -119,7 +158,7 @@ impl Mounts {
         // (0)(1)(2)   (3)   (4)      (5)      (6)   (7) (8)   (9)          (10)
         // where (6) may be repeated zero or more times.
         let id = liter.next().ok_or(Error::BadStr)?.parse::<_>()?;
-        let _parent_id = liter.next().ok_or(Error::BadStr)?;
+        let parent = liter.next().ok_or(Error::BadStr)?.parse::<_>()?;
         let _dev = liter.next().ok_or(Error::BadStr)?;
         let root = liter.next().ok_or(Error::BadStr)?.into();
         let mount_point = liter.next().ok_or(Error::BadStr)?.into();
@@ -161,7 +200,7 @@ impl Mounts {
 
         Ok(MountInfo {
             id,
-            // parent id
+            parent,
             // dev
             root,
             mount_point,
@@ -180,7 +219,7 @@ impl Mounts {
         Self::parse(&contents, &fname)
     }
 
-    fn parse(contents: &str, fname: &Path) -> Result<Mounts> {
+    pub(crate) fn parse(contents: &str, fname: &Path) -> Result<Mounts> {
         let lines: Vec<&str> = contents.lines().collect();
 
         // lines like:
@@ -205,7 +244,41 @@ impl Mounts {
             Err(Error::MissingMount)?;
         }
 
-        Ok(Mounts { points: infos })
+        Ok(Mounts {
+            fname: fname.to_path_buf(),
+            points: infos,
+        })
+    }
+
+    /// Re-read the entry for one mount point, which need not already be listed.
+    ///
+    /// Only the matching line of mountinfo is parsed.
+    /// Avoids re-parsing everything after each change on systems with many mounts.
+    pub fn refresh<P: AsRef<Path>>(&mut self, mount_point: P) -> Result<&MountInfo> {
+        let mount_point = mount_point.as_ref();
+        let contents =
+            fs::read_to_string(&self.fname).map_err(|e| Error::file("open", &self.fname, e))?;
+        let target = mount_point.to_string_lossy();
+
+        // last entry is top-most
+        let line = contents
+            .lines()
+            .rev()
+            .find(|line| line.split_ascii_whitespace().nth(4) == Some(&target));
+        match line {
+            Some(line) => {
+                let info = Self::parse_line(line).map_err(|_| {
+                    Error::parse(format!("Error parsing line {:?}", line), &self.fname)
+                })?;
+                debug!("Refresh {}", info);
+                self.points.insert(mount_point.to_path_buf(), info);
+                Ok(&self.points[mount_point])
+            }
+            None => {
+                self.points.remove(mount_point);
+                Err(Error::MissingMount)
+            }
+        }
     }
 
     /// Lookup the mount point for the provided path, which need not be a mount point.
@@ -213,6 +286,350 @@ impl Mounts {
         let mp = find_mount_point(path)?;
         self.points.get(&mp).ok_or_else(|| Error::MissingMount {})
     }
+
+    /// Mount points at or below `root`, sorted so that parents come before children.
+    ///
+    /// Excludes mounts which can not be reached by path.
+    /// eg. hidden by a later mount over a parent directory.
+    pub fn visible_under<P: AsRef<Path>>(&self, root: P) -> Vec<&MountInfo> {
+        let root = root.as_ref();
+        let mut ret: Vec<&MountInfo> = self
+            .points
+            .values()
+            .filter(|mp| mp.mount_point.starts_with(root))
+            .filter(|mp| {
+                // the closest mount point above must be our parent
+                let above = mp
+                    .mount_point
+                    .ancestors()
+                    .skip(1)
+                    .find_map(|dir| self.points.get(dir));
+                match above {
+                    Some(above) => above.id == mp.parent,
+                    None => true,
+                }
+            })
+            .collect();
+        ret.sort_by(|a, b| a.mount_point.cmp(&b.mount_point));
+        ret
+    }
+}
+
+// syscall numbers added with Linux 6.8.  (not in older libc)
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+const SYS_STATMOUNT: libc::c_long = 457;
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+const SYS_LISTMOUNT: libc::c_long = 458;
+#[cfg(target_arch = "mips")]
+const SYS_STATMOUNT: libc::c_long = 4000 + 457;
+#[cfg(target_arch = "mips")]
+const SYS_LISTMOUNT: libc::c_long = 4000 + 458;
+#[cfg(target_arch = "mips64")]
+const SYS_STATMOUNT: libc::c_long = 5000 + 457;
+#[cfg(target_arch = "mips64")]
+const SYS_LISTMOUNT: libc::c_long = 5000 + 458;
+
+// cf. linux/mount.h
+const LSMT_ROOT: u64 = !0;
+const STATMOUNT_MNT_BASIC: u64 = 0x02;
+const STATMOUNT_MNT_ROOT: u64 = 0x08;
+const STATMOUNT_MNT_POINT: u64 = 0x10;
+const STATMOUNT_FS_TYPE: u64 = 0x20;
+const STATMOUNT_SB_SOURCE: u64 = 0x200;
+
+const MOUNT_ATTR_RDONLY: u64 = 0x01;
+const MOUNT_ATTR_NOSUID: u64 = 0x02;
+const MOUNT_ATTR_NODEV: u64 = 0x04;
+const MOUNT_ATTR_NOEXEC: u64 = 0x08;
+const MOUNT_ATTR__ATIME: u64 = 0x70;
+const MOUNT_ATTR_NOATIME: u64 = 0x10;
+const MOUNT_ATTR_STRICTATIME: u64 = 0x20;
+const MOUNT_ATTR_NODIRATIME: u64 = 0x80;
+
+#[repr(C)]
+#[derive(Default)]
+struct MntIdReq {
+    size: u32,
+    spare: u32,
+    mnt_id: u64,
+    param: u64,
+}
+
+impl MntIdReq {
+    fn new(mnt_id: u64, param: u64) -> MntIdReq {
+        MntIdReq {
+            size: mem::size_of::<MntIdReq>() as u32,
+            mnt_id,
+            param,
+            ..Default::default()
+        }
+    }
+}
+
+/// Leading fields of `struct statmount`.  Strings follow at offset 512.
+#[repr(C)]
+struct StatMount {
+    size: u32,
+    mnt_opts: u32,
+    mask: u64,
+    sb_dev_major: u32,
+    sb_dev_minor: u32,
+    sb_magic: u64,
+    sb_flags: u32,
+    fs_type: u32,
+    mnt_id: u64,
+    mnt_parent_id: u64,
+    mnt_id_old: u32,
+    mnt_parent_id_old: u32,
+    mnt_attr: u64,
+    mnt_propagation: u64,
+    mnt_peer_group: u64,
+    mnt_master: u64,
+    propagate_from: u64,
+    mnt_root: u32,
+    mnt_point: u32,
+    mnt_ns_id: u64,
+    fs_subtype: u32,
+    sb_source: u32,
+}
+
+const STATMOUNT_STR: usize = 512;
+
+/// Unique IDs of all mounts in the namespace of the current process.  Wraps `listmount()`
+///
+/// Fails with `io::ErrorKind::Unsupported` before Linux 6.8.
+pub fn listmount() -> Result<Vec<u64>> {
+    let mut ret = vec![];
+    let mut ids = vec![0u64; 256];
+    let mut last = 0;
+    loop {
+        let req = MntIdReq::new(LSMT_ROOT, last);
+        let n = unsafe {
+            libc::syscall(
+                SYS_LISTMOUNT,
+                &req as *const MntIdReq,
+                ids.as_mut_ptr(),
+                ids.len(),
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(Error::last_os_error("listmount"));
+        }
+        let n = n as usize;
+        ret.extend_from_slice(&ids[..n]);
+        if n < ids.len() {
+            return Ok(ret);
+        }
+        last = ids[n - 1];
+    }
+}
+
+/// Information about one mount, by unique ID.  Wraps `statmount()`
+///
+/// Returns `None` if the kernel does not provide all needed information.
+pub fn statmount(mnt_id: u64) -> Result<Option<MountInfo>> {
+    let mask = STATMOUNT_MNT_BASIC
+        | STATMOUNT_MNT_ROOT
+        | STATMOUNT_MNT_POINT
+        | STATMOUNT_FS_TYPE
+        | STATMOUNT_SB_SOURCE;
+    let req = MntIdReq::new(mnt_id, mask);
+    // u64 for alignment
+    let mut buf = vec![0u64; 512];
+    loop {
+        let ret = unsafe {
+            libc::syscall(
+                SYS_STATMOUNT,
+                &req as *const MntIdReq,
+                buf.as_mut_ptr(),
+                buf.len() * 8,
+                0,
+            )
+        };
+        if ret == 0 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EOVERFLOW) {
+            let len = buf.len();
+            buf.resize(len * 2, 0);
+            continue;
+        }
+        return Err(Error::os(format!("statmount {}", mnt_id), err));
+    }
+    let sm = unsafe { &*(buf.as_ptr() as *const StatMount) };
+    if sm.mask & mask != mask {
+        return Ok(None);
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, buf.len() * 8) };
+    let size = (sm.size as usize).min(bytes.len());
+    let strs = bytes.get(STATMOUNT_STR..size).unwrap_or(&[]);
+    let string = |off: u32| -> &OsStr {
+        let s = strs.get(off as usize..).unwrap_or(&[]);
+        let end = s.iter().position(|c| *c == 0).unwrap_or(s.len());
+        OsStr::from_bytes(&s[..end])
+    };
+
+    let attr = sm.mnt_attr;
+    let mut options = 0;
+    for (a, o) in [
+        (MOUNT_ATTR_RDONLY, libc::MS_RDONLY),
+        (MOUNT_ATTR_NOSUID, libc::MS_NOSUID),
+        (MOUNT_ATTR_NODEV, libc::MS_NODEV),
+        (MOUNT_ATTR_NOEXEC, libc::MS_NOEXEC),
+        (MOUNT_ATTR_NODIRATIME, libc::MS_NODIRATIME),
+    ] {
+        if attr & a != 0 {
+            options |= o;
+        }
+    }
+    options |= match attr & MOUNT_ATTR__ATIME {
+        MOUNT_ATTR_NOATIME => libc::MS_NOATIME,
+        MOUNT_ATTR_STRICTATIME => libc::MS_STRICTATIME,
+        _ => libc::MS_RELATIME,
+    };
+
+    Ok(Some(MountInfo {
+        id: sm.mnt_id_old as u64,
+        parent: sm.mnt_parent_id_old as u64,
+        root: string(sm.mnt_root).into(),
+        mount_point: string(sm.mnt_point).into(),
+        options,
+        fstype: string(sm.fs_type).to_string_lossy().into(),
+        source: string(sm.sb_source).to_string_lossy().into(),
+    }))
+}
+
+/// Unmount `path`, if a mount point, and all mounts below it.  Children before parents.
+///
+/// Mounts stacked over one another are removed in turn, as are any which
+/// were hidden by an unmounted parent.  Returns the number of mounts removed.
+pub fn umount_recursive<P: AsRef<Path>>(path: P) -> Result<usize> {
+    let path = path.as_ref();
+    let mut count = 0;
+    loop {
+        let mut points: Vec<PathBuf> = Mounts::current()?
+            .into_iter()
+            .map(|mp| mp.mount_point.clone())
+            .filter(|mp| mp.starts_with(path))
+            .collect();
+        if points.is_empty() {
+            break;
+        }
+        debug!(
+            "umount_recursive({:?}) {} remain",
+            path.display(),
+            points.len()
+        );
+        let before = count;
+        // deepest first
+        points.sort();
+        for mp in points.iter().rev() {
+            while util::maybe_umount_lazy(mp)? {
+                count += 1;
+            }
+        }
+        if count == before {
+            warn!(
+                "Unable to unmount {} mounts under {}",
+                points.len(),
+                path.display()
+            );
+            break;
+        }
+    }
+    Ok(count)
+}
+
+/// Cover directory `dir` with a new tmpfs, then bind each of its original entries back.
+///
+/// Afterwards, new entries may be added to `dir` without changing the underlying filesystem.
+/// Symlinks are copied.  `scratch` is an empty directory, used while moving.
+pub fn shadow_dir<A: AsRef<Path>, B: AsRef<Path>>(dir: A, scratch: B) -> Result<()> {
+    let (dir, scratch) = (dir.as_ref(), scratch.as_ref());
+    debug!("shadow {}", dir.display());
+    let meta = dir.metadata().map_err(|e| Error::file("stat", dir, e))?;
+    util::mount(dir, scratch, "", libc::MS_BIND | libc::MS_REC)?;
+    util::mount_with_data(
+        "none",
+        dir,
+        "tmpfs",
+        libc::MS_NODEV | libc::MS_NOSUID,
+        format!("mode={:o}", meta.mode() & 0o7777),
+    )?;
+    if let Err(err) = util::chown(dir, meta.uid(), meta.gid()) {
+        // eg. owner not mapped in a user namespace
+        debug!("shadow keeps owner : {}", err);
+    }
+
+    for dent in fs::read_dir(scratch).map_err(|e| Error::file("readdir", scratch, e))? {
+        let dent = dent.map_err(|e| Error::file("readdir", scratch, e))?;
+        let (from, to) = (dent.path(), dir.join(dent.file_name()));
+        let ftype = dent
+            .file_type()
+            .map_err(|e| Error::file("stat", &from, e))?;
+        if ftype.is_symlink() {
+            let target = fs::read_link(&from).map_err(|e| Error::file("readlink", &from, e))?;
+            std::os::unix::fs::symlink(&target, &to).map_err(|e| Error::file("symlink", &to, e))?;
+            continue;
+        } else if ftype.is_dir() {
+            util::mkdir(&to)?;
+        } else {
+            util::write_file(&to, "")?;
+        }
+        util::mount(&from, &to, "", libc::MS_BIND | libc::MS_REC)?;
+    }
+    util::umount_lazy(scratch)
+}
+
+/// Replaced with fresh mounts inside a sandbox
+pub const SPECIAL_MOUNTS: &[(&str, &str)] = &[
+    ("/proc", "proc"),
+    ("/tmp", "tmpfs"),
+    ("/dev/shm", "tmpfs"),
+    ("/var/tmp", "tmpfs"),
+];
+
+/// Physical and various tmpfs-like mounts are made read-only inside a sandbox
+pub fn want_readonly(mp: &MountInfo) -> bool {
+    !mp.has_option(libc::MS_RDONLY)
+        && (mp.source.starts_with("/dev/") || ["tmpfs", "ramfs"].contains(&mp.fstype.as_str()))
+}
+
+/// Plan to apply a change to mounts which `want` it, with as few calls as possible.
+///
+/// `visible` is as returned by `Mounts::visible_under()`.
+/// Returns a list of `(mount, recursive)`.  Recursive when all mounts below either
+/// `want` the change, or are `compatible` with it.  eg. are already read-only.
+pub fn plan_recursive<'a, W, C>(
+    visible: &[&'a MountInfo],
+    want: W,
+    compatible: C,
+) -> Vec<(&'a MountInfo, bool)>
+where
+    W: Fn(&MountInfo) -> bool,
+    C: Fn(&MountInfo) -> bool,
+{
+    let mut ret: Vec<(&'a MountInfo, bool)> = vec![];
+    for (i, mp) in visible.iter().enumerate() {
+        if !want(mp) {
+            continue;
+        }
+        let covered = ret
+            .iter()
+            .any(|(top, rec)| *rec && mp.mount_point.starts_with(&top.mount_point));
+        if covered {
+            continue;
+        }
+        // sorted, so children follow immediately
+        let recursive = visible[i + 1..]
+            .iter()
+            .take_while(|c| c.mount_point.starts_with(&mp.mount_point))
+            .all(|c| want(c) || compatible(c));
+        ret.push((mp, recursive));
+    }
+    ret
 }
 
 impl<'a> IntoIterator for &'a Mounts {
@@ -255,6 +672,68 @@ mod tests {
         assert_eq!(root.mount_point.display().to_string(), "/");
     }
 
+    #[test]
+    fn test_umount_recursive() {
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let tdir = crate::tempdir::TempDir::new().unwrap();
+        let top = tdir.path().canonicalize().unwrap();
+        let mut pid = crate::proc::fork(|| -> crate::container::Result<()> {
+            util::unshare(libc::CLONE_NEWNS)?;
+            util::mount("", "/", "", libc::MS_REC | libc::MS_PRIVATE)?;
+            util::mount("tmpfs", &top, "tmpfs", 0)?;
+            util::mkdir(top.join("sub"))?;
+            util::mount("tmpfs", top.join("sub"), "tmpfs", 0)?;
+            // stacked
+            util::mount("tmpfs", top.join("sub"), "tmpfs", 0)?;
+
+            let count = umount_recursive(&top)?;
+            let remain = Mounts::current()?.visible_under(&top).len();
+            if (count, remain) != (3, 0) {
+                return Err(format!("unmounted {}, {} remain", count, remain).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn test_umount_recursive_fault() {
+        use crate::Errno;
+        use util::fault::{self, Op};
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let tdir = crate::tempdir::TempDir::new().unwrap();
+        let top = tdir.path().canonicalize().unwrap();
+        let mut pid = crate::proc::fork(|| -> crate::container::Result<()> {
+            util::unshare(libc::CLONE_NEWNS)?;
+            util::mount("", "/", "", libc::MS_REC | libc::MS_PRIVATE)?;
+            util::mount("tmpfs", &top, "tmpfs", 0)?;
+            util::mkdir(top.join("sub"))?;
+            util::mount("tmpfs", top.join("sub"), "tmpfs", 0)?;
+
+            fault::fail_nth(Op::Umount, 2, Errno::EIO);
+            let err = umount_recursive(&top).unwrap_err();
+            // sub was removed before the failure
+            let remain = Mounts::current()?.visible_under(&top).len();
+            fault::clear(Op::Umount);
+            if err.errno() != Some(Errno::EIO) || remain != 1 {
+                return Err(format!("unexpected {}, {} remain", err, remain).into());
+            }
+            let count = umount_recursive(&top)?;
+            if count != 1 {
+                return Err(format!("unmounted {} after fault", count).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
     #[test]
     fn test_mountinfo_static() {
         let inp = "
@@ -266,4 +745,61 @@ mod tests {
         assert_eq!("sysfs", infos.lookup(&"/sys").unwrap().fstype);
         assert_eq!("ext4", infos.lookup(&"/").unwrap().fstype);
     }
+
+    #[test]
+    fn test_statmount() {
+        // compare with mountinfo, when available
+        if let Some(stat) = Mounts::from_statmount().unwrap() {
+            let info = Mounts::create("self").unwrap();
+            let a = stat.lookup("/").unwrap();
+            let b = info.lookup("/").unwrap();
+            assert_eq!(a.id, b.id);
+            assert_eq!(a.fstype, b.fstype);
+            assert_eq!(a.source, b.source);
+            assert_eq!(a.has_option(libc::MS_RDONLY), b.has_option(libc::MS_RDONLY));
+            assert_eq!(stat.points.len(), info.points.len());
+        }
+    }
+
+    #[test]
+    fn test_refresh() {
+        let mut infos = Mounts::current().unwrap();
+        infos.points.clear();
+        let root = infos.refresh("/").unwrap();
+        assert_eq!(root.mount_point, Path::new("/"));
+        assert!(infos.lookup("/").is_ok());
+        infos.refresh("/nonexistent").unwrap_err();
+    }
+
+    #[test]
+    fn test_plan_recursive() {
+        let inp = "
+29 1 253:1 / / rw - ext4 /dev/root rw
+30 29 0:20 / /sys rw - sysfs sysfs rw
+31 29 253:2 / /data rw - ext4 /dev/data rw
+32 31 253:3 / /data/a rw - ext4 /dev/a rw
+33 31 0:21 / /data/b ro - squashfs /dev/loop0 ro
+34 40 0:22 / /data/b/c rw - tmpfs none rw
+"
+        .trim_start();
+        let infos = Mounts::parse(inp, &PathBuf::from(&"static")).unwrap();
+
+        // /data/b/c has an unknown parent
+        let visible = infos.visible_under("/data");
+        let names: Vec<_> = visible.iter().map(|m| m.id).collect();
+        assert_eq!(names, [31, 32, 33]);
+
+        let want = |m: &MountInfo| m.source.starts_with("/dev/") && !m.has_option(libc::MS_RDONLY);
+        let ro = |m: &MountInfo| m.has_option(libc::MS_RDONLY);
+
+        let plan = plan_recursive(&visible, want, ro);
+        let plan: Vec<_> = plan.iter().map(|(m, r)| (m.id, *r)).collect();
+        assert_eq!(plan, [(31, true)]);
+
+        // sysfs is neither wanted nor compatible
+        let visible = infos.visible_under("/");
+        let plan = plan_recursive(&visible, want, ro);
+        let plan: Vec<_> = plan.iter().map(|(m, r)| (m.id, *r)).collect();
+        assert_eq!(plan, [(29, false), (31, true)]);
+    }
 }
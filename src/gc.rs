@@ -0,0 +1,344 @@
+//! Removal of leftovers from runs which ended without cleaning up.  eg. when killed.
+//!
+//! Host resources are named with the ID of the run which created them.  cf. `id`
+//! A run is live while its temporary directory, eg. `$TMPDIR/sandbox-<id>-XXXXXX`,
+//! is locked (cf. `TempDir`), or while a detached sandbox with that ID is alive.
+//! Resources of other runs are stale.
+//!
+//! - temporary directories.  Mount points under them are detached first.
+//! - registry entries of detached sandboxes which are no longer alive
+//! - host network interfaces.  eg. `vsb<id>`
+//! - empty cgroups.  eg. `sandbox-<id>-0`
+//!
+//! Only resources of the calling user are considered, unless called by root.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, warn};
+
+use super::err::{Errno, Error, Result};
+use super::registry::Registry;
+use super::tempdir::TempDir;
+use super::{cgroup, id, net, tempdir, util};
+
+/// Prefix of temporary directory names, before the ID
+const TEMPDIR_PREFIX: &str = "sandbox-";
+
+/// Prefixes of interface names, before the ID
+pub const IFNAME_PREFIXES: &[&str] = &["vsbp", "vsb", "br-", "tap-"];
+
+/// Directories modified more recently may belong to a run which has not yet locked them
+const MIN_AGE: Duration = Duration::from_secs(60);
+
+/// A stale resource
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    TempDir(PathBuf),
+    /// ID of a detached sandbox
+    Entry(String),
+    Interface(String),
+    Cgroup(PathBuf),
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Item::TempDir(path) => write!(f, "temporary directory {}", path.display()),
+            Item::Entry(id) => write!(f, "detached sandbox {}", id),
+            Item::Interface(name) => write!(f, "interface {}", name),
+            Item::Cgroup(path) => write!(f, "cgroup {}", path.display()),
+        }
+    }
+}
+
+/// ID following `prefix` in `name`.  eg. `sandbox-1a2b3c4d-XXXXXX`
+fn id_of<'a>(name: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = name.strip_prefix(prefix)?;
+    let id = rest.get(..id::LEN)?;
+    let tail = &rest[id::LEN..];
+    if id::is_valid(id) && (tail.is_empty() || tail.starts_with('-')) {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+/// Finds, and removes, stale resources
+#[derive(Debug, Clone)]
+pub struct Collector {
+    tmp: Vec<PathBuf>,
+    registry: Option<Registry>,
+    dry_run: bool,
+    uid: libc::uid_t,
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Collector {
+            tmp: tempdir::candidates(),
+            registry: Registry::new().ok(),
+            dry_run: false,
+            uid: util::getuid(),
+        }
+    }
+}
+
+impl Collector {
+    pub fn new() -> Collector {
+        Default::default()
+    }
+
+    /// Where temporary directories are found.  Default `tempdir::candidates()`
+    pub fn tmp_dir<P: Into<PathBuf>>(&mut self, dir: P) -> &mut Self {
+        self.tmp = vec![dir.into()];
+        self
+    }
+
+    /// Registry of detached sandboxes.  Default that of the calling user.
+    pub fn registry(&mut self, registry: Registry) -> &mut Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Only find stale resources.  Remove nothing.
+    pub fn dry_run(&mut self, dry_run: bool) -> &mut Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    fn owned(&self, meta: &fs::Metadata) -> bool {
+        self.uid == 0 || meta.uid() == self.uid
+    }
+
+    /// Temporary directories and registry entries.  Cheap enough to run at every startup.
+    /// Returns what was removed.  Also the IDs of live runs.
+    pub fn quick(&self) -> Result<(Vec<Item>, HashSet<String>)> {
+        let mut live = HashSet::new();
+        live.insert(id::current());
+        let mut ret = vec![];
+
+        if let Some(registry) = &self.registry {
+            for entry in registry.list()? {
+                if entry.alive() {
+                    live.insert(entry.id);
+                    continue;
+                }
+                if !self.dry_run {
+                    registry.remove(&entry.id)?;
+                }
+                ret.push(Item::Entry(entry.id));
+            }
+        }
+
+        let mut stale = vec![];
+        for tmp in &self.tmp {
+            stale.extend(self.stale_tempdirs(tmp, &mut live)?);
+        }
+
+        for (path, _lock) in stale {
+            if !self.dry_run {
+                debug!("Remove stale {}", path.display());
+                drop(TempDir::adopt(&path));
+                if path.exists() {
+                    continue;
+                }
+            }
+            ret.push(Item::TempDir(path));
+        }
+        Ok((ret, live))
+    }
+
+    /// Temporary directories in `tmp` which are unlocked, and old enough.
+    /// Held locked.  IDs of others are added to `live`.
+    fn stale_tempdirs(
+        &self,
+        tmp: &Path,
+        live: &mut HashSet<String>,
+    ) -> Result<Vec<(PathBuf, File)>> {
+        let mut stale = vec![];
+        let dir = match fs::read_dir(tmp) {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(Error::file("readdir", tmp, err)),
+        };
+        let now = SystemTime::now();
+        for dent in dir {
+            let dent = dent.map_err(|e| Error::file("readdir", tmp, e))?;
+            let name = dent.file_name().to_string_lossy().into_owned();
+            let id = match id_of(&name, TEMPDIR_PREFIX) {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+            let path = dent.path();
+            let meta = match fs::symlink_metadata(&path) {
+                Ok(meta) if meta.is_dir() && self.owned(&meta) => meta,
+                _ => continue,
+            };
+            // eg. of another user
+            let lock = match File::open(&path) {
+                Ok(lock) => lock,
+                Err(err) => {
+                    debug!("Skip {} : {}", path.display(), err);
+                    continue;
+                }
+            };
+            match util::flock(&lock, libc::LOCK_EX | libc::LOCK_NB) {
+                Ok(()) => (),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    live.insert(id);
+                    continue;
+                }
+                Err(err) => return Err(Error::file("flock", &path, err)),
+            }
+            let age = meta
+                .modified()
+                .ok()
+                .and_then(|t| now.duration_since(t).ok());
+            if !matches!(age, Some(age) if age >= MIN_AGE) {
+                live.insert(id);
+                continue;
+            }
+            stale.push((path, lock));
+        }
+
+        Ok(stale)
+    }
+
+    /// All stale resources.  Returns what was removed.
+    pub fn all(&self) -> Result<Vec<Item>> {
+        let (mut ret, live) = self.quick()?;
+        let stale = |id: Option<&str>| matches!(id, Some(id) if !live.contains(id));
+
+        match net::interfaces() {
+            Ok(ifaces) => {
+                let mut names: Vec<String> = ifaces.into_iter().map(|i| i.name).collect();
+                names.dedup();
+                for name in names {
+                    let id = IFNAME_PREFIXES.iter().find_map(|p| id_of(&name, p));
+                    if !stale(id) {
+                        continue;
+                    }
+                    if !self.dry_run {
+                        match net::delete_link(&name) {
+                            Ok(()) => (),
+                            // gone with its peer
+                            Err(err) if err.errno() == Some(Errno::ENODEV) => continue,
+                            Err(err) => {
+                                warn!("{}", err);
+                                continue;
+                            }
+                        }
+                    }
+                    ret.push(Item::Interface(name));
+                }
+            }
+            Err(err) => warn!("{}", err),
+        }
+
+        if let Ok(dir) = cgroup::sibling_dir() {
+            ret.extend(self.cgroups(&dir, stale)?);
+        }
+        Ok(ret)
+    }
+
+    /// Empty cgroups in `dir`.  Those with members are left alone.
+    fn cgroups<F>(&self, dir: &Path, stale: F) -> Result<Vec<Item>>
+    where
+        F: Fn(Option<&str>) -> bool,
+    {
+        let mut ret = vec![];
+        for dent in fs::read_dir(dir).map_err(|e| Error::file("readdir", dir, e))? {
+            let dent = dent.map_err(|e| Error::file("readdir", dir, e))?;
+            let name = dent.file_name().to_string_lossy().into_owned();
+            if !stale(id_of(&name, TEMPDIR_PREFIX)) {
+                continue;
+            }
+            let path = dent.path();
+            let procs = fs::read_to_string(path.join("cgroup.procs")).unwrap_or_default();
+            if !procs.trim().is_empty() {
+                debug!("Skip populated {}", path.display());
+                continue;
+            }
+            if !self.dry_run {
+                if let Err(err) = fs::remove_dir(&path) {
+                    debug!("Skip {} : {}", path.display(), err);
+                    continue;
+                }
+            }
+            ret.push(Item::Cgroup(path));
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(
+            id_of("sandbox-1a2b3c4d-AbC123", TEMPDIR_PREFIX),
+            Some("1a2b3c4d")
+        );
+        assert_eq!(
+            id_of("sandbox-1a2b3c4d-0", TEMPDIR_PREFIX),
+            Some("1a2b3c4d")
+        );
+        assert_eq!(id_of("vsb1a2b3c4d", "vsb"), Some("1a2b3c4d"));
+        assert_eq!(id_of("vsbp1a2b3c4d", "vsb"), None);
+        assert_eq!(id_of("sandbox-1234", TEMPDIR_PREFIX), None);
+        assert_eq!(id_of("sandbox-1a2b3c4dX", TEMPDIR_PREFIX), None);
+    }
+
+    #[test]
+    fn tempdirs() {
+        let tdir = TempDir::new().unwrap();
+        let backdate = |path: &Path| {
+            let name = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+            let times = [libc::timeval {
+                tv_sec: 1_000_000,
+                tv_usec: 0,
+            }; 2];
+            assert_eq!(unsafe { libc::utimes(name.as_ptr(), times.as_ptr()) }, 0);
+        };
+
+        // crashed
+        let stale = tdir.path().join("sandbox-00000001-abcdef");
+        fs::create_dir_all(stale.join("sub")).unwrap();
+        backdate(&stale);
+        // still running
+        let running = tdir.path().join("sandbox-00000002-abcdef");
+        fs::create_dir(&running).unwrap();
+        backdate(&running);
+        let lock = File::open(&running).unwrap();
+        util::flock(&lock, libc::LOCK_SH).unwrap();
+        // just created
+        let fresh = tdir.path().join("sandbox-00000003-abcdef");
+        fs::create_dir(&fresh).unwrap();
+        // not ours
+        let other = tdir.path().join("sandbox-other");
+        fs::create_dir(&other).unwrap();
+
+        let reg = Registry::with_dir(tdir.path().join("running"));
+        let mut collector = Collector::new();
+        collector.tmp_dir(tdir.path()).registry(reg).dry_run(true);
+        let (items, live) = collector.quick().unwrap();
+        assert_eq!(items, [Item::TempDir(stale.clone())]);
+        assert!(stale.exists());
+        assert!(live.contains("00000002"));
+        assert!(live.contains("00000003"));
+
+        collector.dry_run(false);
+        let (items, _live) = collector.quick().unwrap();
+        assert_eq!(items, [Item::TempDir(stale.clone())]);
+        assert!(!stale.exists());
+        assert!(running.exists() && fresh.exists() && other.exists());
+    }
+}
@@ -0,0 +1,271 @@
+//! External commands run at container lifecycle stages.  cf. OCI runtime hooks.
+
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use std::{fmt, thread};
+
+use std::os::unix::process::CommandExt;
+
+use log::{debug, warn};
+
+use super::err::{Error, Result};
+use super::fd;
+
+/// When a hook command is run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// From the parent, after container process 1 is created, but before the command is executed.
+    Prestart,
+    /// From the parent, after container process 1 has exited.
+    Poststop,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Prestart => write!(f, "prestart"),
+            Stage::Poststop => write!(f, "poststop"),
+        }
+    }
+}
+
+/// An external hook command
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HookCmd {
+    /// Executable.  Not searched for in `$PATH`
+    pub path: PathBuf,
+    /// Arguments, including `argv[0]`.  Defaults to `path`
+    pub args: Vec<String>,
+    /// `NAME=value` environment.  The environment of the caller is not inherited
+    pub env: Vec<String>,
+    pub timeout: Option<Duration>,
+}
+
+/// Result of a hook command
+#[derive(Debug)]
+pub struct HookOutput {
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl HookOutput {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// Output of a hook command, read without blocking
+struct Capture<R> {
+    pipe: Option<R>,
+    buf: Vec<u8>,
+}
+
+impl<R: Read + AsRawFd> Capture<R> {
+    fn new(pipe: Option<R>) -> Result<Self> {
+        if let Some(pipe) = &pipe {
+            fd::set_nonblocking(pipe.as_raw_fd(), true)?;
+        }
+        Ok(Capture { pipe, buf: vec![] })
+    }
+
+    /// Read whatever is available now
+    fn read(&mut self) {
+        let mut chunk = [0; 4096];
+        while let Some(pipe) = &mut self.pipe {
+            match pipe.read(&mut chunk) {
+                Ok(0) => self.pipe = None,
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    debug!("hook output : {}", err);
+                    self.pipe = None;
+                }
+            }
+        }
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.buf).to_string()
+    }
+}
+
+impl HookCmd {
+    pub fn new<P: Into<PathBuf>>(path: P) -> HookCmd {
+        HookCmd {
+            path: path.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Run to completion, or until timeout expires, capturing output.
+    /// `vars` are added to the environment.
+    pub fn run(&self, vars: &[(&str, String)]) -> Result<HookOutput> {
+        debug!("Run hook {:?} {:?}", self.path, self.args);
+        let mut cmd = Command::new(&self.path);
+        if let Some((arg0, args)) = self.args.split_first() {
+            cmd.arg0(arg0).args(args);
+        }
+        cmd.env_clear();
+        for var in &self.env {
+            let (name, value) = var.split_once('=').unwrap_or((var, ""));
+            cmd.env(name, value);
+        }
+        for (name, value) in vars {
+            cmd.env(name, value);
+        }
+        // a process group, so that anything it starts in the background can be killed too
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setpgid(0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::file("exec hook", &self.path, e))?;
+
+        // read as it runs so a chatty hook can not fill a pipe and stall
+        let mut stdout = Capture::new(child.stdout.take())?;
+        let mut stderr = Capture::new(child.stderr.take())?;
+
+        let start = Instant::now();
+        let status = loop {
+            stdout.read();
+            stderr.read();
+            if let Some(sts) = child
+                .try_wait()
+                .map_err(|e| Error::file("wait hook", &self.path, e))?
+            {
+                // anything written before exit.  Not waiting on what it left in the background,
+                // which may hold the pipes open.
+                stdout.read();
+                stderr.read();
+                break sts;
+            }
+            if let Some(timeout) = self.timeout {
+                if start.elapsed() >= timeout {
+                    warn!("Hook {} timeout after {:?}", self.path.display(), timeout);
+                    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+                    let _ = child.wait();
+                    return Err(Error::Hook {
+                        name: self.path.clone(),
+                        msg: format!("timeout after {:?}", timeout),
+                    });
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        let ret = HookOutput {
+            code: status.code(),
+            stdout: stdout.text(),
+            stderr: stderr.text(),
+        };
+        for line in ret.stdout.lines() {
+            debug!("hook {} : {}", self.path.display(), line);
+        }
+        for line in ret.stderr.lines() {
+            debug!("hook {} ! {}", self.path.display(), line);
+        }
+        Ok(ret)
+    }
+}
+
+/// Run hook commands in order.  Stops at the first failure.
+pub fn run_all(cmds: &[HookCmd], stage: Stage, vars: &[(&str, String)]) -> Result<()> {
+    let mut vars = vars.to_vec();
+    vars.push(("SANDBOX_STAGE", stage.to_string()));
+    for cmd in cmds {
+        let out = cmd.run(&vars)?;
+        if !out.success() {
+            return Err(Error::Hook {
+                name: cmd.path.clone(),
+                msg: format!(
+                    "{} failed with {:?} : {}",
+                    stage,
+                    out.code,
+                    out.stderr.trim()
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> HookCmd {
+        HookCmd {
+            path: "/bin/sh".into(),
+            args: vec!["sh".into(), "-c".into(), script.into()],
+            env: vec!["X=1".into()],
+            timeout: Some(Duration::from_secs(5)),
+        }
+    }
+
+    #[test]
+    fn output() {
+        let out = sh("echo $X $SANDBOX_PID; echo err >&2")
+            .run(&[("SANDBOX_PID", "42".into())])
+            .unwrap();
+        assert!(out.success());
+        assert_eq!(out.stdout, "1 42\n");
+        assert_eq!(out.stderr, "err\n");
+    }
+
+    #[test]
+    fn failure() {
+        let err = run_all(&[sh("echo oops >&2; exit 3")], Stage::Prestart, &[]).unwrap_err();
+        assert!(err.to_string().contains("oops"), "{}", err);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut cmd = sh("sleep 10");
+        cmd.timeout = Some(Duration::from_millis(50));
+        let start = Instant::now();
+        cmd.run(&[]).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn background() {
+        // keeps stdout and stderr open after the hook exits
+        let start = Instant::now();
+        let out = sh("sleep 10 & echo started").run(&[]).unwrap();
+        assert!(out.success());
+        assert_eq!(out.stdout, "started\n");
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let tdir = crate::tempdir::TempDir::new().unwrap();
+        let pidfile = tdir.path().join("pid");
+        let mut cmd = sh(&format!("sleep 10 & echo $! > {}; wait", pidfile.display()));
+        cmd.timeout = Some(Duration::from_millis(200));
+        let start = Instant::now();
+        cmd.run(&[]).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // killed with the hook
+        let pid = std::fs::read_to_string(&pidfile).unwrap();
+        let running = || {
+            let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim()));
+            matches!(stat, Ok(stat) if !stat.contains(") Z "))
+        };
+        let start = Instant::now();
+        while running() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!running(), "{}", pid);
+    }
+}
@@ -0,0 +1,73 @@
+//! Identity of one run.
+//!
+//! A short random token, eg. `1a2b3c4d`, embedded in the names of host resources
+//! created for a sandbox.  Temporary and scratch directories, network interfaces,
+//! cgroups, registry entries, and log lines.  So that concurrent runs never collide,
+//! and leftovers may be traced back to the run which created them.
+//!
+//! Inside the sandbox, the ID is `$SANDBOX_ID`, and the `id` of `info.json`.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Variable holding the ID, inside the sandbox
+pub const ENV_ID: &str = "SANDBOX_ID";
+
+/// Characters in an ID
+pub const LEN: usize = 8;
+
+/// 0 until chosen
+static CURRENT: AtomicU32 = AtomicU32::new(0);
+
+fn random() -> u32 {
+    let mut buf = [0u8; 4];
+    let ret = unsafe { libc::getrandom(buf.as_mut_ptr() as *mut _, buf.len(), 0) };
+    let val = if ret == buf.len() as isize {
+        u32::from_ne_bytes(buf)
+    } else {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        std::process::id().wrapping_mul(2654435761) ^ nanos
+    };
+    val.max(1)
+}
+
+fn format(val: u32) -> String {
+    format!("{:08x}", val)
+}
+
+/// ID of the run of this process.  Chosen on first use, and kept by child processes.
+pub fn current() -> String {
+    let mut val = CURRENT.load(Ordering::Relaxed);
+    if val == 0 {
+        // lock free, as a forked child may log while another thread would hold a lock
+        let new = random();
+        val = match CURRENT.compare_exchange(0, new, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => new,
+            Err(prev) => prev,
+        };
+    }
+    format(val)
+}
+
+/// Whether `name` could be an ID
+pub fn is_valid(name: &str) -> bool {
+    name.len() == LEN && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current_id() {
+        let id = current();
+        assert!(is_valid(&id), "{:?}", id);
+        assert_eq!(current(), id);
+        assert_eq!(format(1), "00000001");
+        assert!(!is_valid("1234567"));
+        assert!(!is_valid("1234567G"));
+    }
+}
@@ -0,0 +1,166 @@
+//! Markers inside a sandbox, and detection by sandboxed programs.
+//!
+//! Sandboxed commands find `$SANDBOX=1`, and a summary of the applied policy in
+//! `/run/sandbox/info.json`.
+//!
+//! ```json
+//! {"hardening":"default","id":"1a2b3c4d","namespaces":["mnt","net"],"network":false,
+//!  "no_new_privs":true,"readonly":["/src"],"seccomp":["ptrace"],
+//!  "tool":"isolate","version":1,"writable":["/build"]}
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use super::config::{Document, Item, Table, Value};
+use super::err::Result;
+
+/// Environment variable set to "1" inside a sandbox
+pub const ENV_MARKER: &str = "SANDBOX";
+/// Policy summary inside a sandbox
+pub const INFO_FILE: &str = "/run/sandbox/info.json";
+
+const VERSION: i64 = 1;
+
+/// Summary of the limits applied to a sandbox
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SandboxInfo {
+    /// Which executable created the sandbox.  eg. "isolate"
+    pub tool: String,
+    /// Identity of the run.  cf. `id::current()`
+    pub id: String,
+    /// Namespaces which were created.  eg. `["mnt", "net"]`
+    pub namespaces: Vec<String>,
+    /// Access to the host network
+    pub network: bool,
+    /// Directories writable from inside
+    pub writable: Vec<PathBuf>,
+    /// Directories explicitly made read-only
+    pub readonly: Vec<PathBuf>,
+    /// Hardening level.  cf. `policy::Level`
+    pub hardening: Option<String>,
+    /// Names of denied syscalls
+    pub seccomp: Vec<String>,
+    pub no_new_privs: bool,
+}
+
+fn item(value: Value) -> Item {
+    Item {
+        pos: Default::default(),
+        value,
+    }
+}
+
+fn strs<I: IntoIterator<Item = String>>(iter: I) -> Value {
+    Value::Array(iter.into_iter().map(Value::Str).collect())
+}
+
+fn paths(paths: &[PathBuf]) -> Value {
+    strs(paths.iter().map(|p| p.to_string_lossy().into_owned()))
+}
+
+fn get_strs(table: &Table, key: &str) -> Vec<String> {
+    let arr = table.get(key).and_then(|i| i.value.as_array());
+    arr.unwrap_or_default()
+        .iter()
+        .filter_map(|v| v.as_str().map(String::from))
+        .collect()
+}
+
+impl SandboxInfo {
+    pub fn to_json(&self) -> String {
+        let mut table = Table::new();
+        table.insert("version".into(), item(Value::Int(VERSION)));
+        table.insert("tool".into(), item(Value::Str(self.tool.clone())));
+        table.insert("id".into(), item(Value::Str(self.id.clone())));
+        table.insert("namespaces".into(), item(strs(self.namespaces.clone())));
+        table.insert("network".into(), item(Value::Bool(self.network)));
+        table.insert("writable".into(), item(paths(&self.writable)));
+        table.insert("readonly".into(), item(paths(&self.readonly)));
+        if let Some(level) = &self.hardening {
+            table.insert("hardening".into(), item(Value::Str(level.clone())));
+        }
+        table.insert("seccomp".into(), item(strs(self.seccomp.clone())));
+        table.insert("no_new_privs".into(), item(Value::Bool(self.no_new_privs)));
+        Value::Table(table).to_json()
+    }
+
+    /// Parse.  Unknown keys are ignored
+    pub fn from_json(text: &str, name: &Path) -> Result<SandboxInfo> {
+        let root = Document::parse_json(text, name)?.root;
+        let get_bool = |key| matches!(root.get(key), Some(i) if i.value.as_bool() == Some(true));
+        let get_str = |key| {
+            root.get(key)
+                .and_then(|i| i.value.as_str())
+                .map(String::from)
+        };
+        Ok(SandboxInfo {
+            tool: get_str("tool").unwrap_or_default(),
+            id: get_str("id").unwrap_or_default(),
+            namespaces: get_strs(&root, "namespaces"),
+            network: get_bool("network"),
+            writable: get_strs(&root, "writable")
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+            readonly: get_strs(&root, "readonly")
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+            hardening: get_str("hardening"),
+            seccomp: get_strs(&root, "seccomp"),
+            no_new_privs: get_bool("no_new_privs"),
+        })
+    }
+}
+
+/// Is this process running in a sandbox?  If so, what limits apply?
+///
+/// The summary may be empty if `INFO_FILE` could not be created or read.
+pub fn detect() -> Option<SandboxInfo> {
+    let marked = matches!(std::env::var(ENV_MARKER), Ok(v) if v == "1");
+    let text = match std::fs::read_to_string(INFO_FILE) {
+        Ok(text) => text,
+        Err(_) if marked => return Some(SandboxInfo::default()),
+        Err(_) => return None,
+    };
+    match SandboxInfo::from_json(&text, Path::new(INFO_FILE)) {
+        Ok(info) => Some(info),
+        Err(err) => {
+            debug!("{}", err);
+            Some(SandboxInfo::default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let info = SandboxInfo {
+            tool: "isolate".into(),
+            id: "1a2b3c4d".into(),
+            namespaces: vec!["mnt".into(), "net".into()],
+            network: false,
+            writable: vec!["/build".into()],
+            readonly: vec!["/src".into()],
+            hardening: Some("default".into()),
+            seccomp: vec!["ptrace".into()],
+            no_new_privs: true,
+        };
+        let text = info.to_json();
+        assert_eq!(
+            text,
+            r#"{"hardening":"default","id":"1a2b3c4d","namespaces":["mnt","net"],"network":false,"no_new_privs":true,"readonly":["/src"],"seccomp":["ptrace"],"tool":"isolate","version":1,"writable":["/build"]}"#
+        );
+        assert_eq!(
+            SandboxInfo::from_json(&text, Path::new("test")).unwrap(),
+            info
+        );
+        let partial = SandboxInfo::from_json(r#"{"tool":"x","extra":[1]}"#, Path::new("test"));
+        assert_eq!(partial.unwrap().tool, "x");
+    }
+}
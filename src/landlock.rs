@@ -0,0 +1,304 @@
+//! Path restrictions with Landlock (Linux >= 5.13).  For isolate --backend landlock.
+//!
+//! Unlike a mount namespace, needs neither privilege, nor a user namespace.
+//! Rules only allow access.  Once applied, a ruleset restricts the calling thread,
+//! and its later children, for good.  cf. `Documentation/userspace-api/landlock.rst`
+//!
+//! ```no_run
+//! use sandbox::landlock::Ruleset;
+//!
+//! let mut rules = Ruleset::new();
+//! rules.ro("/").rw("/dev/null").rw(std::env::current_dir().unwrap());
+//! let code = rules.create().unwrap().run(&["make"]).unwrap();
+//! ```
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::debug;
+
+use super::backend;
+use super::err::{Error, Result};
+use super::util;
+
+// the same on all architectures
+const SYS_CREATE_RULESET: libc::c_long = 444;
+const SYS_ADD_RULE: libc::c_long = 445;
+const SYS_RESTRICT_SELF: libc::c_long = 446;
+
+const CREATE_RULESET_VERSION: u32 = 1;
+const RULE_PATH_BENEATH: u32 = 1;
+const RULE_NET_PORT: u32 = 2;
+
+// cf. linux/landlock.h
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+/// Through `ACCESS_FS_MAKE_SYM`
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+const ACCESS_NET_BIND_TCP: u64 = 1 << 0;
+const ACCESS_NET_CONNECT_TCP: u64 = 1 << 1;
+
+/// Rights which apply to files, as opposed to directories
+const ACCESS_FILE: u64 = ACCESS_FS_EXECUTE
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_READ_FILE
+    | ACCESS_FS_TRUNCATE
+    | ACCESS_FS_IOCTL_DEV;
+
+const ACCESS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+    /// ABI >= 4
+    handled_access_net: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Version of the Landlock ABI supported by the kernel.  eg. 4 for Linux 6.7
+pub fn abi() -> Result<u32> {
+    let ret = unsafe {
+        libc::syscall(
+            SYS_CREATE_RULESET,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    if ret < 0 {
+        // ENOSYS before 5.13, or EOPNOTSUPP when disabled at boot
+        return Err(Error::last_os_error("landlock"));
+    }
+    Ok(ret as u32)
+}
+
+/// Rights handled by a ruleset with `abi`
+fn handled_fs(abi: u32) -> u64 {
+    let mut ret = ACCESS_FS_V1;
+    if abi >= 2 {
+        ret |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        ret |= ACCESS_FS_TRUNCATE;
+    }
+    if abi >= 5 {
+        ret |= ACCESS_FS_IOCTL_DEV;
+    }
+    ret
+}
+
+/// Access granted beneath a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// Paths which may be accessed.  Anything else is denied.
+#[derive(Debug, Clone, Default)]
+pub struct Ruleset {
+    rules: Vec<(PathBuf, Access)>,
+    deny_tcp: bool,
+}
+
+impl Ruleset {
+    pub fn new() -> Ruleset {
+        Default::default()
+    }
+
+    /// Allow reading, and executing, files beneath `path`
+    pub fn ro<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.rules.push((path.into(), Access::ReadOnly));
+        self
+    }
+
+    /// Allow all access beneath `path`
+    pub fn rw<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.rules.push((path.into(), Access::ReadWrite));
+        self
+    }
+
+    /// Also deny TCP `bind()` and `connect()`.  Needs ABI 4.  Other sockets are not restricted.
+    pub fn deny_tcp(&mut self) -> &mut Self {
+        self.deny_tcp = true;
+        self
+    }
+
+    pub fn rules(&self) -> &[(PathBuf, Access)] {
+        &self.rules
+    }
+
+    /// Create the kernel ruleset.  Paths which do not exist are skipped.
+    pub fn create(&self) -> Result<Prepared> {
+        let abi = abi()?;
+        if self.deny_tcp && abi < 4 {
+            return Err(Error::os(
+                format!("Landlock ABI {} can not restrict the network", abi),
+                io::Error::from_raw_os_error(libc::EOPNOTSUPP),
+            ));
+        }
+        let handled = handled_fs(abi);
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+            handled_access_net: if self.deny_tcp {
+                ACCESS_NET_BIND_TCP | ACCESS_NET_CONNECT_TCP
+            } else {
+                0
+            },
+        };
+        let size = if abi >= 4 {
+            std::mem::size_of::<RulesetAttr>()
+        } else {
+            std::mem::size_of::<u64>()
+        };
+        let fd = unsafe { libc::syscall(SYS_CREATE_RULESET, &attr as *const RulesetAttr, size, 0) };
+        if fd < 0 {
+            return Err(Error::last_os_error("landlock_create_ruleset"));
+        }
+        // with O_CLOEXEC
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        for (path, access) in &self.rules {
+            let file = match File::options()
+                .read(true)
+                .custom_flags(libc::O_PATH)
+                .open(path)
+            {
+                Ok(file) => file,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    debug!("landlock skip {}", path.display());
+                    continue;
+                }
+                Err(err) => return Err(Error::file("open", path, err)),
+            };
+            let mut allowed = match access {
+                Access::ReadOnly => ACCESS_READ,
+                Access::ReadWrite => handled,
+            };
+            if !file.metadata().map(|m| m.is_dir()).unwrap_or(false) {
+                allowed &= ACCESS_FILE;
+            }
+            let rule = PathBeneathAttr {
+                allowed_access: allowed & handled,
+                parent_fd: file.as_raw_fd(),
+            };
+            debug!("landlock {:?} {}", access, path.display());
+            let ret = unsafe {
+                libc::syscall(
+                    SYS_ADD_RULE,
+                    fd.as_raw_fd(),
+                    RULE_PATH_BENEATH,
+                    &rule as *const PathBeneathAttr,
+                    0,
+                )
+            };
+            if ret != 0 {
+                return Err(Error::file(
+                    "landlock_add_rule",
+                    path,
+                    io::Error::last_os_error(),
+                ));
+            }
+        }
+        let _ = RULE_NET_PORT; // no ports are allowed
+        Ok(Prepared { fd })
+    }
+}
+
+/// A ruleset known to the kernel, which may be applied
+#[derive(Debug)]
+pub struct Prepared {
+    fd: OwnedFd,
+}
+
+/// Apply ruleset `fd` to the calling thread.  Only makes syscalls,
+/// so may be called between `fork()` and `exec()`.
+fn restrict(fd: libc::c_int) -> io::Result<()> {
+    // needed when unprivileged
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::syscall(SYS_RESTRICT_SELF, fd, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Prepared {
+    /// Apply to the calling thread, and its later children.  Also sets `no_new_privs`.
+    pub fn restrict_self(&self) -> Result<()> {
+        restrict(self.fd.as_raw_fd()).map_err(|e| Error::os("landlock_restrict_self", e))
+    }
+
+    /// Prepare to run `cmd` with this ruleset applied.  As the calling user, when SUID.
+    pub fn command<S: AsRef<str>>(&self, cmd: &[S]) -> Command {
+        let mut ret = Command::new(cmd[0].as_ref());
+        ret.args(cmd[1..].iter().map(|a| a.as_ref()));
+        if util::geteuid() != util::getuid() {
+            ret.uid(util::getuid()).gid(util::getgid());
+        }
+        let fd = self.fd.as_raw_fd();
+        unsafe { ret.pre_exec(move || restrict(fd)) };
+        ret
+    }
+
+    /// Run `cmd`, and wait for it to exit.  Returns the exit code.
+    /// Or 128 + the signal which killed it.
+    pub fn run<S: AsRef<str>>(&self, cmd: &[S]) -> Result<i32> {
+        let status = self
+            .command(cmd)
+            .status()
+            .map_err(|e| Error::file("exec", Path::new(cmd[0].as_ref()), e))?;
+        Ok(backend::exit_code(status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn handled() {
+        assert_eq!(handled_fs(1), 0x1fff);
+        assert_eq!(handled_fs(3), 0x7fff);
+        assert_eq!(handled_fs(6), 0xffff);
+        assert_eq!(std::mem::size_of::<PathBeneathAttr>(), 12);
+    }
+
+    #[test]
+    fn restricted() {
+        if let Err(err) = abi() {
+            eprintln!("Skip : {}", err);
+            return;
+        }
+        let tdir = TempDir::new().unwrap();
+        let dir = tdir.path().to_str().unwrap().to_string();
+        let mut rules = Ruleset::new();
+        rules.ro("/").rw(&dir).rw("/dev/null");
+        let prepared = rules.create().unwrap();
+
+        let script = format!("touch {dir}/ok && ! touch {dir}/../sandbox-landlock-test");
+        let mut cmd = prepared.command(&["sh", "-c", &script]);
+        assert!(cmd.status().unwrap().success());
+        assert!(tdir.path().join("ok").exists());
+        assert!(!tdir.path().join("../sandbox-landlock-test").exists());
+        assert_eq!(prepared.run(&["sh", "-c", "exit 3"]).unwrap(), 3);
+    }
+}
@@ -4,23 +4,79 @@
 //! - isolate  - Run command with (by default) only $PWD writable, and not network access.
 //! - hidehome - Run command with (apparently) empty $HOME
 //! - nonet    - Run command with no network access
+//! - sandbox  - Check and allow profiles, manage detached sandboxes
 
 mod err;
+pub use err::Errno;
 
 mod ext;
 
 mod capability;
+mod dbus;
+mod fd;
+mod rtnl;
 
+pub mod backend;
+pub mod backup;
+pub mod cgroup;
+pub mod config;
+pub mod coredump;
+pub mod crash;
+pub mod dhcp;
+pub mod entropy;
+pub mod envpolicy;
+pub mod forkserver;
 pub mod fs;
+pub mod gc;
+pub mod hook;
+pub mod id;
+pub mod info;
+pub mod landlock;
+pub mod limits;
+pub use info::detect;
+pub mod nat;
 pub mod net;
+pub mod nft;
+pub mod notify;
+pub mod policy;
+pub mod portal;
+pub mod preview;
 mod proc;
+pub mod procfs;
+pub mod profile;
+pub mod project;
+pub mod registry;
+pub mod retry;
+pub mod scratch;
+pub mod seccomp;
+pub mod secret;
+pub mod site;
+pub mod snapshot;
+pub mod stats;
+pub mod stdio;
+pub mod systemd;
 pub mod tempdir;
+pub mod term;
+pub mod test;
+pub mod testing;
+pub mod toolchain;
 mod user;
 
 pub mod container;
 pub use container::runc;
 pub use container::ContainerHooks;
+pub use container::StageCtx;
+pub use container::{runc_cancel, CancelToken};
+pub use container::{spawn, SandboxHandle};
 pub use container::{Error, Result};
 
+pub mod builder;
+pub use builder::HooksBuilder;
+
 pub mod logging;
+pub mod msg;
+pub mod ui;
 pub mod util;
+pub mod version;
+pub use version::build_info;
+pub mod wayland;
@@ -0,0 +1,264 @@
+//! Per-user limits on sandboxes started with privilege.  eg. by a SUID isolate.
+//!
+//! Configured by the administrator in `/etc/sandbox/limits.toml`.  No limits when absent.
+//!
+//! ```toml
+//! # sandboxes of one user running at once
+//! max_concurrent = 8
+//! # sandboxes started by one user in any `period` seconds
+//! max_starts = 20
+//! period = 60
+//! ```
+//!
+//! State is kept in a root owned directory per user, `/run/sandbox/limits/<uid>/`.
+//! Each running sandbox holds a `flock()` on one slot file, so that slots
+//! of processes which exit, or crash, are released.  Recent start times are
+//! listed in `starts`.
+
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::debug;
+
+use super::config::{Document, Value};
+use super::err::{Error, Result};
+use super::util::flock;
+
+/// System configuration file
+pub const LIMITS_FILE: &str = "/etc/sandbox/limits.toml";
+
+/// Parent of the per-user state directories
+pub const STATE_DIR: &str = "/run/sandbox/limits";
+
+const DEFAULT_PERIOD: u64 = 60;
+
+const KEYS: &[&str] = &["max_concurrent", "max_starts", "period"];
+
+/// Limits applied to each user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    pub max_concurrent: Option<u32>,
+    pub max_starts: Option<u32>,
+    /// Seconds
+    pub period: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_concurrent: None,
+            max_starts: None,
+            period: DEFAULT_PERIOD,
+        }
+    }
+}
+
+/// Result of `Limits::admit()`
+#[derive(Debug)]
+pub enum Admit {
+    /// Hold until the sandbox exits
+    Allowed(Slot),
+    /// `max_concurrent` sandboxes are running
+    TooMany(u32),
+    /// `max_starts` sandboxes were started in the last `period`
+    TooFrequent(u32, u64),
+}
+
+/// One running sandbox.  Released when all copies of the descriptor are closed.
+/// Including those inherited by child processes.
+#[derive(Debug)]
+pub struct Slot {
+    path: PathBuf,
+    _lock: File,
+}
+
+impl Slot {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Limits {
+    /// Read `LIMITS_FILE`, if it exists
+    pub fn system() -> Result<Option<Limits>> {
+        Self::load(LIMITS_FILE)
+    }
+
+    /// Read and parse a configuration file, if it exists
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Limits>> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(None);
+        }
+        Self::from_document(&Document::load(path)?).map(Some)
+    }
+
+    pub fn from_document(doc: &Document) -> Result<Limits> {
+        let mut ret = Limits::default();
+        for (key, item) in &doc.root {
+            if !KEYS.contains(&key.as_str()) {
+                // a typo should not remove a limit
+                return Err(doc.error(item.pos, format!("unknown key \"{}\"", key)));
+            }
+            let value = match &item.value {
+                Value::Int(v) if *v > 0 && *v <= u32::MAX as i64 => *v as u32,
+                _ => return Err(doc.error(item.pos, "expected positive integer")),
+            };
+            match key.as_str() {
+                "max_concurrent" => ret.max_concurrent = Some(value),
+                "max_starts" => ret.max_starts = Some(value),
+                _ => ret.period = value as u64,
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Whether any limit is set
+    pub fn is_empty(&self) -> bool {
+        self.max_concurrent.is_none() && self.max_starts.is_none()
+    }
+
+    /// Check whether user `uid` may start another sandbox, and if so record the start.
+    /// `dir` is the parent of the per-user directories.  eg. `STATE_DIR`
+    pub fn admit<P: AsRef<Path>>(&self, dir: P, uid: libc::uid_t) -> Result<Admit> {
+        let dir = dir.as_ref().join(uid.to_string());
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .map_err(|e| Error::file("mkdir", &dir, e))?;
+
+        // serialize checks by this user
+        let lockname = dir.join("lock");
+        let lock = File::create(&lockname).map_err(|e| Error::file("create", &lockname, e))?;
+        flock(&lock, libc::LOCK_EX).map_err(|e| Error::file("flock", &lockname, e))?;
+
+        if let Some(max) = self.max_concurrent {
+            let mut running = 0;
+            for dent in fs::read_dir(&dir).map_err(|e| Error::file("readdir", &dir, e))? {
+                let path = dent.map_err(|e| Error::file("readdir", &dir, e))?.path();
+                let name = path.file_name().and_then(|n| n.to_str());
+                if !matches!(name, Some(n) if n.starts_with("slot-")) {
+                    continue;
+                }
+                let slot = match File::open(&path) {
+                    Ok(slot) => slot,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(Error::file("open", &path, err)),
+                };
+                match flock(&slot, libc::LOCK_EX | libc::LOCK_NB) {
+                    Ok(()) => {
+                        debug!("Remove stale {}", path.display());
+                        fs::remove_file(&path).map_err(|e| Error::file("remove", &path, e))?;
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => running += 1,
+                    Err(err) => return Err(Error::file("flock", &path, err)),
+                }
+            }
+            debug!("uid {} has {} running", uid, running);
+            if running >= max {
+                return Ok(Admit::TooMany(max));
+            }
+        }
+
+        let now = now();
+        if let Some(max) = self.max_starts {
+            let name = dir.join("starts");
+            let text = match fs::read_to_string(&name) {
+                Ok(text) => text,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+                Err(err) => return Err(Error::file("read", &name, err)),
+            };
+            let mut starts: Vec<u64> = text
+                .lines()
+                .filter_map(|line| line.parse().ok())
+                .filter(|t| t + self.period > now)
+                .collect();
+            if starts.len() >= max as usize {
+                return Ok(Admit::TooFrequent(max, self.period));
+            }
+            starts.push(now);
+            let text: String = starts.iter().map(|t| format!("{}\n", t)).collect();
+            fs::write(&name, text).map_err(|e| Error::file("write", &name, e))?;
+        }
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let path = dir.join(format!("slot-{}-{}.{}", std::process::id(), now, nanos));
+        let slot = File::options()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .map_err(|e| Error::file("create", &path, e))?;
+        flock(&slot, libc::LOCK_SH).map_err(|e| Error::file("flock", &path, e))?;
+        Ok(Admit::Allowed(Slot { path, _lock: slot }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn parse() {
+        let doc = Document::parse("max_concurrent = 2\nmax_starts = 10\n", "test").unwrap();
+        let limits = Limits::from_document(&doc).unwrap();
+        assert_eq!(limits.max_concurrent, Some(2));
+        assert_eq!(limits.max_starts, Some(10));
+        assert_eq!(limits.period, DEFAULT_PERIOD);
+
+        for bad in ["max_concurent = 2", "period = 0", "max_starts = \"10\""] {
+            let doc = Document::parse(bad, "test").unwrap();
+            Limits::from_document(&doc).unwrap_err();
+        }
+        assert!(Limits::load("/nonexistent/limits.toml").unwrap().is_none());
+    }
+
+    #[test]
+    fn admit() {
+        let tdir = TempDir::new().unwrap();
+        let limits = Limits {
+            max_concurrent: Some(2),
+            max_starts: Some(3),
+            period: 3600,
+        };
+        let allowed = |admit| match admit {
+            Admit::Allowed(slot) => slot,
+            other => panic!("{:?}", other),
+        };
+        let first = allowed(limits.admit(tdir.path(), 1000).unwrap());
+        let _second = allowed(limits.admit(tdir.path(), 1000).unwrap());
+        assert!(matches!(
+            limits.admit(tdir.path(), 1000).unwrap(),
+            Admit::TooMany(2)
+        ));
+        // other users are counted separately
+        allowed(limits.admit(tdir.path(), 1001).unwrap());
+
+        // released by exit
+        let first_path = first.path().to_path_buf();
+        drop(first);
+        let _third = allowed(limits.admit(tdir.path(), 1000).unwrap());
+        assert!(!first_path.exists());
+
+        drop(_third);
+        assert!(matches!(
+            limits.admit(tdir.path(), 1000).unwrap(),
+            Admit::TooFrequent(3, 3600)
+        ));
+    }
+}
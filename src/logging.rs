@@ -1,36 +1,255 @@
-use log::{self, LevelFilter, Log, SetLoggerError};
-/// Really simple logger
+//! Really simple logger
+//!
+//! The level is taken from `$SANDBOX_LOG`, or else `$RUST_LOG`.  Either may be a list
+//! of directives, as with env_logger.  eg. `SANDBOX_LOG=warn,fs=debug,container=info`.
+//! A bare level applies to all modules.  A module may be named with, or without,
+//! the `sandbox::` prefix.  The most specific matching name applies.
+//!
+//! Regardless of level, the last `RING_SIZE` debug records are kept in memory,
+//! and printed by `dump_recent()` when `runc()` fails.
+use log::{self, Level, LevelFilter, Log, SetLoggerError};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
-struct Logger;
+/// Number of records kept for `dump_recent()`
+pub const RING_SIZE: usize = 256;
+
+/// Most recent records, and whether each was printed
+struct Ring {
+    lines: Vec<(bool, String)>,
+    /// Oldest, once full
+    next: usize,
+}
+
+impl Ring {
+    const fn new() -> Ring {
+        Ring {
+            lines: Vec::new(),
+            next: 0,
+        }
+    }
+
+    fn push(&mut self, printed: bool, line: String) {
+        if self.lines.len() < RING_SIZE {
+            self.lines.push((printed, line));
+        } else {
+            self.lines[self.next] = (printed, line);
+            self.next = (self.next + 1) % RING_SIZE;
+        }
+    }
+
+    /// Oldest first.  Leaves empty.
+    fn take(&mut self) -> Vec<(bool, String)> {
+        let mut lines = std::mem::take(&mut self.lines);
+        lines.rotate_left(self.next);
+        self.next = 0;
+        lines
+    }
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring::new());
+
+/// Set by -v or -q.  0 when not overridden, otherwise `LevelFilter as usize + 1`
+static OVERRIDE: AtomicUsize = AtomicUsize::new(0);
+
+fn override_level() -> Option<LevelFilter> {
+    let levels = [
+        LevelFilter::Off,
+        LevelFilter::Error,
+        LevelFilter::Warn,
+        LevelFilter::Info,
+        LevelFilter::Debug,
+        LevelFilter::Trace,
+    ];
+    OVERRIDE
+        .load(Ordering::Relaxed)
+        .checked_sub(1)
+        .and_then(|i| levels.get(i).copied())
+}
+
+/// Print at `level` from all modules, overriding `$SANDBOX_LOG` and `$RUST_LOG`
+pub fn set_level(level: LevelFilter) {
+    OVERRIDE.store(level as usize + 1, Ordering::Relaxed);
+    log::set_max_level(level.max(LevelFilter::Debug));
+}
+
+/// Parsed directives
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Filter {
+    default: LevelFilter,
+    /// Module name and level
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            default: LevelFilter::Warn,
+            modules: vec![],
+        }
+    }
+}
+
+/// Whether `target` is `name`, or a sub-module of `name`
+fn module_matches(target: &str, name: &str) -> bool {
+    let within = |target: &str| match target.strip_prefix(name) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    };
+    within(target) || matches!(target.strip_prefix("sandbox::"), Some(t) if within(t))
+}
+
+impl Filter {
+    /// Invalid directives are ignored
+    fn parse(spec: &str) -> Filter {
+        let mut ret = Filter::default();
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((name, level)) => {
+                    if let Ok(level) = LevelFilter::from_str(level.trim()) {
+                        ret.modules.push((name.trim().to_string(), level));
+                    }
+                }
+                None => {
+                    if let Ok(level) = LevelFilter::from_str(directive) {
+                        ret.default = level;
+                    }
+                }
+            }
+        }
+        // most specific first
+        let specificity = |name: &str| name.strip_prefix("sandbox::").unwrap_or(name).len();
+        ret.modules
+            .sort_by_key(|(name, _)| std::cmp::Reverse(specificity(name)));
+        ret
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(name, _)| module_matches(target, name))
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    /// Most verbose of any directive
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+
+    fn enabled(&self, target: &str, level: Level) -> bool {
+        level <= self.level(target)
+    }
+}
+
+struct Logger {
+    filter: Filter,
+}
 
 impl Log for Logger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true // log::set_max_level() is sufficient
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        match override_level() {
+            Some(level) => metadata.level() <= level,
+            None => self.filter.enabled(metadata.target(), metadata.level()),
+        }
     }
 
     fn log(&self, record: &log::Record) {
-        let lvl = record.level().to_string();
+        let printed = self.enabled(record.metadata());
+        let recorded = record.level() <= Level::Debug;
+        if !printed && !recorded {
+            return;
+        }
+        let lvl = super::ui::level_label(record.level());
         let tgt = if record.target().is_empty() {
             record.module_path().unwrap_or_default()
         } else {
             record.target()
         };
+        let id = super::id::current();
+        let line = format!("{lvl} {id} [{tgt}] {}", record.args());
 
-        eprintln!("{lvl:<5} [{tgt}] {}", record.args());
+        if printed {
+            eprintln!("{line}");
+        }
+        if recorded {
+            // never wait.  eg. in a child forked while another thread held the lock
+            if let Ok(mut ring) = RING.try_lock() {
+                ring.push(printed, line);
+            }
+        }
     }
 
     fn flush(&self) {}
 }
 
 pub fn setup() -> Result<(), SetLoggerError> {
-    let lvl = std::env::var("RUST_LOG")
-        .ok()
-        .as_deref()
-        .map(LevelFilter::from_str)
-        .and_then(Result::ok)
-        .unwrap_or(LevelFilter::Warn);
-
-    log::set_max_level(lvl);
-    log::set_logger(&Logger {})
+    let filter = std::env::var("SANDBOX_LOG")
+        .or_else(|_| std::env::var("RUST_LOG"))
+        .map(|spec| Filter::parse(&spec))
+        .unwrap_or_default();
+
+    // debug records are always kept
+    log::set_max_level(filter.max().max(LevelFilter::Debug));
+    log::set_boxed_logger(Box::new(Logger { filter }))
+}
+
+/// Print recent records, if any were not already printed.  Then forget them.
+pub fn dump_recent() {
+    let lines = match RING.lock() {
+        Ok(mut ring) => ring.take(),
+        Err(_) => return,
+    };
+    if lines.iter().all(|(printed, _)| *printed) {
+        return;
+    }
+    eprintln!("--- last {} log messages ---", lines.len());
+    for (_, line) in lines {
+        eprintln!("{line}");
+    }
+    eprintln!("---");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directives() {
+        let filter = Filter::parse("info, fs=debug,sandbox::net=trace,container=bogus");
+        assert_eq!(filter.default, LevelFilter::Info);
+        assert_eq!(filter.max(), LevelFilter::Trace);
+        assert_eq!(filter.level("sandbox::fs"), LevelFilter::Debug);
+        assert_eq!(filter.level("sandbox::net::dhcp"), LevelFilter::Trace);
+        // ignored
+        assert_eq!(filter.level("sandbox::container"), LevelFilter::Info);
+        // not a prefix of a name
+        assert_eq!(filter.level("sandbox::fsx"), LevelFilter::Info);
+        assert!(filter.enabled("isolate", Level::Info));
+        assert!(!filter.enabled("isolate", Level::Debug));
+
+        let filter = Filter::parse("fs=off,sandbox::fs::sub=debug");
+        assert_eq!(filter.default, LevelFilter::Warn);
+        assert_eq!(filter.level("sandbox::fs::sub"), LevelFilter::Debug);
+        assert_eq!(filter.level("sandbox::fs"), LevelFilter::Off);
+
+        assert_eq!(Filter::parse("DEBUG").default, LevelFilter::Debug);
+    }
+
+    #[test]
+    fn ring() {
+        let mut ring = Ring::new();
+        for i in 0..RING_SIZE + 2 {
+            ring.push(false, i.to_string());
+        }
+        let lines = ring.take();
+        assert_eq!(lines.len(), RING_SIZE);
+        assert_eq!(lines[0].1, "2");
+        assert_eq!(lines[RING_SIZE - 1].1, (RING_SIZE + 1).to_string());
+        assert!(ring.take().is_empty());
+    }
 }
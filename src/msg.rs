@@ -0,0 +1,560 @@
+//! Catalog of user-facing messages of the executables.
+//!
+//! Errors, and usage text, are looked up by `Msg` in the catalog of the language
+//! selected by `$LC_ALL`, `$LC_MESSAGES`, or `$LANG`.  Falling back to English,
+//! which is complete.  Log messages are not translated.
+//!
+//! A message may contain placeholders like `{arg}`, which are replaced by `tr()`.
+//! Unknown placeholders are left as is.
+//!
+//! To add a language, add a table of `(Msg, text)` to `CATALOGS`.
+//! A partial table is allowed.
+
+use std::env;
+use std::fmt::Display;
+
+/// Message identifiers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Msg {
+    /// Label of `ui::error()`
+    Error,
+    /// Label of `ui::warning()`
+    Warning,
+    /// `{arg}`
+    UnknownArgument,
+    /// `{arg}`
+    ExpectsArgument,
+    /// hidehome and nonet.  `{execname}`
+    CmdUsage,
+    /// `{signal}`
+    SetupInterrupted,
+
+    /// `{execname}`, `{debugger}`, `{prompt}`, `{name}`
+    IsolateUsage,
+    CurdirNotAbsolute,
+    /// `{dir}`
+    CoresDirMissing,
+    /// `{dir}`
+    CrashTraceDirMissing,
+    CrashTraceNoPtrace,
+    ShellWithCommand,
+    GuiNoWayland,
+    NetRawWithNet,
+    NetRawAsRoot,
+    RunAsNeedsRoot,
+    /// `{name}`
+    NoSuchAccount,
+    /// `{name}`
+    RunAsNotMapped,
+    RunAsWithNetRaw,
+    NetBridgeWithNet,
+    NetBridgeNeedsRoot,
+    DhcpWithoutBridge,
+    Ipv6WithoutBridge,
+    /// `{option}`
+    NetNatWith,
+    PublishWithNet,
+    /// `{option}`
+    PtyWith,
+    /// `{name}`
+    NoSuchBridge,
+    DetachWithShell,
+    DetachFailed,
+    /// `{log}`
+    DetachFailedLog,
+    SnapshotNotWritable,
+    /// `{dir}`, `{path}`
+    SnapshotKept,
+    /// `{dir}`
+    RolledBack,
+    /// `{dir}`, `{path}`, `{err}`
+    RollbackFailed,
+    BackupNeedsRoot,
+    /// `{count}`, `{dir}`
+    BackupSaved,
+    /// `{path}`
+    ScratchNotAbsolute,
+    /// `{path}`
+    ScratchMissing,
+    /// `{path}`, `{host}`
+    ScratchKept,
+    /// `{option}`, `{backend}`
+    BackendUnsupported,
+    /// `{cmd}`
+    BackendMissing,
+    /// `{option}`, `{arch}`
+    SeccompUnsupported,
+    /// `{option}`
+    TempDirInMemory,
+    NoTempDir,
+    /// `{option}`
+    FootprintUnsupported,
+    FootprintLimits,
+    /// `{err}`
+    LandlockUnavailable,
+    /// `{abi}`
+    LandlockNoNetwork,
+    LandlockSiteMask,
+    /// `{option}`
+    RestartUnsupported,
+    /// `{code}`, `{count}`, `{delay}`
+    Restarting,
+    /// `{name}`
+    PickDocumentsTitle,
+    NoDocuments,
+    /// `{name}`
+    SecretInArgs,
+    ExecStdinWithShell,
+    NoPayload,
+    /// `{max}`
+    TooManySandboxes,
+    /// `{max}`, `{period}`
+    TooFrequentSandboxes,
+
+    HomeNotAbsolute,
+    UnderTmp,
+
+    /// `{execname}`, `{file}`, `{timeout}`
+    SandboxUsage,
+    /// `{file}`
+    NoProjectFile,
+    /// `{key}`
+    NoDetached,
+    /// `{id}`
+    DidNotStop,
+    /// `{id}`
+    HasExited,
+    /// `{file}`
+    Allowed,
+    /// `{file}`
+    NotAllowed,
+    /// `{item}`
+    GcRemoved,
+    /// `{item}`
+    GcWouldRemove,
+    /// `{socket}`
+    ServerListening,
+
+    CargoIsolateUsage,
+    MetadataFailed,
+    /// `{file}`
+    LockfileFailed,
+    /// `{value}`
+    RegistryValue,
+}
+
+/// Translations.  eg. `("de", &[(Msg::UnderTmp, "...")])`
+const CATALOGS: &[(&str, &[(Msg, &str)])] = &[];
+
+/// English, the default
+fn en(id: Msg) -> &'static str {
+    match id {
+        Msg::Error => "error",
+        Msg::Warning => "warning",
+        Msg::UnknownArgument => "Unknown argument: {arg}",
+        Msg::ExpectsArgument => "{arg} expects argument",
+        Msg::CmdUsage => "Usage: {execname} [-v|-q] <cmd> [args ...]",
+        Msg::SetupInterrupted => "interrupted during setup, by signal {signal}",
+
+        Msg::IsolateUsage => ISOLATE_USAGE,
+        Msg::CurdirNotAbsolute => "curdir is not absolute?!?",
+        Msg::CoresDirMissing => "--cores directory {dir} does not exist",
+        Msg::CrashTraceDirMissing => "--crash-trace directory {dir} does not exist",
+        Msg::CrashTraceNoPtrace => "--crash-trace needs ptrace(), which is denied",
+        Msg::ShellWithCommand => "--shell does not accept a command",
+        Msg::GuiNoWayland => "--gui needs a Wayland session ($WAYLAND_DISPLAY)",
+        Msg::NetRawWithNet => "--net-raw is not allowed with network access",
+        Msg::NetRawAsRoot => "--net-raw needs --as-root when run by root",
+        Msg::RunAsNeedsRoot => "--run-as is only allowed when run by root",
+        Msg::NoSuchAccount => "No user {name} in /etc/passwd",
+        Msg::RunAsNotMapped => {
+            "--run-as {name} is outside of the IDs of root in /etc/subuid and /etc/subgid"
+        }
+        Msg::RunAsWithNetRaw => "--run-as is not allowed with --net-raw",
+        Msg::NetBridgeWithNet => "--net-bridge is not allowed with host network access",
+        Msg::NetBridgeNeedsRoot => "--net-bridge needs isolate to be installed SUID root",
+        Msg::NoSuchBridge => "No bridge interface {name}",
+        Msg::DhcpWithoutBridge => "--dhcp needs --net-bridge",
+        Msg::Ipv6WithoutBridge => "--net-ipv6 needs --net-bridge",
+        Msg::NetNatWith => "--net-nat is not allowed with {option}",
+        Msg::PublishWithNet => "--publish is not allowed with host network access",
+        Msg::PtyWith => "--pty is not allowed with {option}",
+        Msg::DetachWithShell => "--detach does not support --shell",
+        Msg::DetachFailed => "Detached sandbox failed to start",
+        Msg::DetachFailedLog => "Detached sandbox failed to start.  See {log}",
+        Msg::SnapshotNotWritable => "--snapshot-before needs a writable $PWD",
+        Msg::SnapshotKept => "Snapshot of {dir} kept in {path}",
+        Msg::RolledBack => "Command failed.  Restored {dir} from snapshot",
+        Msg::RollbackFailed => "Unable to restore {dir}, snapshot kept in {path} : {err}",
+        Msg::BackupNeedsRoot => "--backup-dir needs isolate to be installed SUID root",
+        Msg::BackupSaved => "Originals of {count} modified files saved in {dir}",
+        Msg::ScratchNotAbsolute => "--scratch {path} is not an absolute path",
+        Msg::ScratchMissing => "--scratch {path} must be an existing directory, or under /tmp",
+        Msg::ScratchKept => "Scratch {path} kept in {host}",
+        Msg::BackendUnsupported => "{option} is not supported with --backend {backend}",
+        Msg::BackendMissing => "{cmd} not found in $PATH",
+        Msg::SeccompUnsupported => "{option} needs seccomp, which is not supported on {arch}",
+        Msg::TempDirInMemory => {
+            "{option} needs a usable $TMPDIR, or $XDG_RUNTIME_DIR.  Writable, not noexec, and not full"
+        }
+        Msg::NoTempDir => "No usable temporary directory, on the host or in memory",
+        Msg::FootprintUnsupported => {
+            "{option} would leave files on the host, so is not supported with --zero-footprint"
+        }
+        Msg::FootprintLimits => {
+            "--zero-footprint is not possible while the administrator limits sandboxes"
+        }
+        Msg::LandlockUnavailable => "Landlock is not available : {err}",
+        Msg::LandlockNoNetwork => {
+            "Landlock ABI {abi} can not deny network access.  Linux >= 6.7 needed, or --net"
+        }
+        Msg::LandlockSiteMask => "--backend landlock can not hide the paths masked by site policy",
+        Msg::RestartUnsupported => "{option} is not supported with --restart",
+        Msg::Restarting => "Command failed with {code}.  Restart {count} in {delay} s",
+        Msg::PickDocumentsTitle => "Choose files for {name}",
+        Msg::NoDocuments => "No files chosen",
+        Msg::SecretInArgs => "The command line contains the value of secret {name}",
+        Msg::ExecStdinWithShell => "--exec-stdin does not support --shell",
+        Msg::NoPayload => "--exec-stdin read nothing from stdin",
+        Msg::TooManySandboxes => "Limit of {max} running sandboxes reached",
+        Msg::TooFrequentSandboxes => {
+            "Limit of {max} sandboxes started per {period} seconds reached"
+        }
+
+        Msg::HomeNotAbsolute => "$HOME must be an absolute path",
+        Msg::UnderTmp => "Can't run under /tmp",
+
+        Msg::SandboxUsage => SANDBOX_USAGE,
+        Msg::NoProjectFile => "No {file} found",
+        Msg::NoDetached => "No detached sandbox {key}",
+        Msg::DidNotStop => "Sandbox {id} did not stop",
+        Msg::HasExited => "Sandbox {id} has exited",
+        Msg::Allowed => "Allowed {file}",
+        Msg::NotAllowed => "{file} was not allowed",
+        Msg::GcRemoved => "Removed {item}",
+        Msg::GcWouldRemove => "Would remove {item}",
+        Msg::ServerListening => "Serving requests at {socket}",
+
+        Msg::CargoIsolateUsage => CARGO_ISOLATE_USAGE,
+        Msg::MetadataFailed => "cargo metadata failed",
+        Msg::LockfileFailed => "Unable to generate {file}",
+        Msg::RegistryValue => "--registry expects ro or rw, not {value}",
+    }
+}
+
+/// Candidate catalog names from a locale.  eg. "pt_BR.UTF-8" -> ["pt_BR", "pt"]
+fn candidates(locale: &str) -> Vec<&str> {
+    let name = locale.split(['.', '@']).next().unwrap_or_default();
+    let mut ret = vec![];
+    if !name.is_empty() {
+        ret.push(name);
+        if let Some((lang, _)) = name.split_once('_') {
+            ret.push(lang);
+        }
+    }
+    ret
+}
+
+/// The selected locale.  eg. "de_DE.UTF-8"
+pub fn locale() -> String {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(env::var_os)
+        .map(|v| v.to_string_lossy().into_owned())
+        .find(|v| !v.is_empty())
+        .unwrap_or_default()
+}
+
+fn lookup(catalogs: &[(&str, &[(Msg, &'static str)])], locale: &str, id: Msg) -> &'static str {
+    for name in candidates(locale) {
+        let table = catalogs.iter().find(|(lang, _)| *lang == name);
+        if let Some((_, table)) = table {
+            if let Some((_, text)) = table.iter().find(|(m, _)| *m == id) {
+                return text;
+            }
+        }
+    }
+    en(id)
+}
+
+/// Text of a message, with any placeholders
+pub fn text(id: Msg) -> &'static str {
+    lookup(CATALOGS, &locale(), id)
+}
+
+fn substitute(text: &str, args: &[(&str, &dyn Display)]) -> String {
+    let mut ret = text.to_string();
+    for (key, value) in args {
+        ret = ret.replace(&format!("{{{key}}}"), &value.to_string());
+    }
+    ret
+}
+
+/// Text of a message, with placeholders replaced.
+///
+/// ```
+/// use sandbox::msg::{tr, Msg};
+/// let arg = "--bogus";
+/// assert_eq!(tr(Msg::UnknownArgument, &[("arg", &arg)]), "Unknown argument: --bogus");
+/// ```
+pub fn tr(id: Msg, args: &[(&str, &dyn Display)]) -> String {
+    substitute(text(id), args)
+}
+
+const ISOLATE_USAGE: &str = "Usage: {execname} [-h] [--version] [-v|-q] [-p|--profile <file>] [-N|--net] [-K|--keep-tmp] [--zero-footprint] [-P|--pid-file <file>]
+       [--notify-fd <N>] [--sd-notify] [--notify-proxy] [--scope] [--slice <unit>]
+       [--time-report] [--output-limit <N>] [--on-output-limit truncate|abort]
+       [--cores off|dir:<path>] [--crash-trace <dir>] [--crash-debugger <cmd>]
+       [--on-exit kill|wait] [--snapshot-before] [--rollback-on-failure] [--backup-dir <dir>]
+       [--scratch <path>] [--scratch-rm] [--restart no|on-failure[:max]]
+       [--harden <level>] [--explain-hardening] [--explain]
+       [--no-userfaultfd] [--no-io-uring] [--no-project] [--entropy-seed <N>]
+       [--name <name>] [--prompt <prefix>] [--virtualenv-compat] [--detach]
+       [--backend native|bwrap|landlock]
+       [--toolchains] [--net-raw] [--net-bridge <bridge> [--dhcp] [--net-ipv6]] [--net-nat]
+       [--publish [<addr>:]<port>:<port>] [--gui]
+       [--document <file>] [--pick-documents]
+       [--secret <name>=<file>] [--env-preset ci|minimal|desktop]
+       [--pty] [--color auto|always|never] [--as-root] [--run-as <user>]
+       [-W|--rw <dir>] [-O|--ro <dir>] <cmd> [args ...]
+       {execname} [options] --shell
+       {execname} [options] --exec-stdin [argv0 [args ...]]
+       {execname} --version [--verbose]
+
+Execute command in an isolated environment.  By default only $PWD
+will be writable, with no network access allowed.
+
+Options:
+    -h             - Show this message
+    --version      - Show the version.  With --verbose, as JSON, with the features of
+                     this build, and the kernel interfaces available.
+    -v -q          - More, or less, verbose messages.  -v may be repeated.
+    -p --profile <file> - Load options from profile.  Later options take precedence
+    --no-project   - Ignore any .sandbox.toml found in $PWD or a parent directory.
+                     Otherwise, once allowed with \"sandbox allow\", it is loaded
+                     before all other options.
+    --backend native|bwrap|landlock - Create namespaces directly (default), or through
+                     bwrap.  eg. where only a SUID bwrap may create them.  Or create
+                     none, and restrict writes with Landlock (Linux >= 5.13).
+                     eg. where user namespaces are disallowed.  Many options
+                     need the native backend.
+    -N --net       - Allow network access
+    --net-raw      - Without network access, keep CAP_NET_RAW for raw sockets
+                     on the loopback interface.  eg. traceroute.
+                     ICMP echo (ping) is always allowed.
+    --net-bridge <bridge> - Connect the sandbox to an existing host bridge.
+                     eg. virbr0.  Through a veth pair, which appears inside as eth0.
+    --dhcp         - With --net-bridge, configure eth0, the default route, and
+                     /etc/resolv.conf from a DHCP server on the bridge.
+                     The lease is not renewed.
+    --net-ipv6     - With --net-bridge, keep IPv6 on eth0.  Otherwise IPv6 is
+                     disabled on interfaces created for the sandbox, so that
+                     not even link-local packets are sent.
+    --net-nat      - Without network access, connect through user-mode NAT.
+                     TCP connections, and DNS queries, are made again by isolate
+                     on the host.  Other traffic is dropped.  Needs no privilege.
+    --publish [<addr>:]<port>:<port> - Forward connections to a host port (on
+                     127.0.0.1 unless <addr> is given) to a port on the loopback
+                     interface of the sandbox.  eg. \"8080:80\".  May be repeated.
+    -c --no-pwd    - Deny writes to $PWD  (shorthand for \"-O .\")
+    -K --keep-tmp  - Do not remove temporary directory on exit (for debugging)
+    --zero-footprint - Create nothing on the host file system.  The temporary
+                     directory is kept in memory, and stale ones are not removed.
+                     Status is only reported through stderr, and --notify-fd.
+    -P --pid-file <file> - Write host PID of the sandboxed command to file
+    --detach             - Run in the background, and print an ID once started.
+                           Output is written to a log file.  Manage with
+                           \"sandbox list|stop|exec\".  Not with --shell.
+    --notify-fd <N>      - Write \"READY\" to file descriptor N, then close,
+                           after the command has been executed
+    --sd-notify          - Send \"READY=1\" to $NOTIFY_SOCKET after the command
+                           has been executed.  eg. systemd Type=notify
+    --notify-proxy       - Relay sd_notify() messages from the command to $NOTIFY_SOCKET
+    --scope              - Run in a new transient systemd scope unit
+    --slice <unit>       - Place the new scope under this slice unit.  Implies --scope
+    --time-report        - Print the duration of each startup phase once the command
+                           has been executed
+    --output-limit <N>   - Relay stdout and stderr of the command, and stop after
+                           a combined N bytes.  The command will not see a terminal.
+    --on-output-limit truncate|abort - When the output limit is exceeded, discard
+                           further output (default), or kill the command.
+    --cores off|dir:<path> - Disable core dumps, or write them to a directory.
+                           Cores piped to a host handler can not be redirected.
+    --crash-trace <dir>  - When the command is killed by SIGSEGV, SIGABRT, SIGBUS,
+                           SIGILL, or SIGFPE, save a stack trace to <dir>, which must
+                           be writable.  Needs ptrace(), so not with --harden paranoid.
+    --crash-debugger <cmd> - Shell command run to capture a trace.  {pid} is replaced.
+                           Default: {debugger}
+    --on-exit kill|wait  - When the command exits, kill any remaining processes
+                           (default), or wait until they also exit.
+    --restart no|on-failure[:max] - Run the command again in a new sandbox when it
+                           fails.  At most max times.  After 1 s, doubled for each
+                           consecutive failure, up to 60 s.  Stopped by a signal.
+    --snapshot-before    - Reflink a copy of $PWD into $XDG_STATE_HOME/sandbox/snapshots/
+                           before running.  Needs btrfs or xfs, on the same filesystem.
+    --rollback-on-failure - Snapshot, then restore $PWD if the command fails.
+                           The snapshot is removed afterwards.
+    --backup-dir <dir>   - Before a file in a writable directory is first opened,
+                           copy it under <dir>.  Copies of unchanged files are
+                           discarded on exit.  Needs SUID root.
+    --scratch <path>     - An empty writable directory at <path>, replacing any.
+                           Kept under $XDG_STATE_HOME/sandbox/scratch/, and
+                           printed on exit.  May be repeated.
+    --scratch-rm         - Remove --scratch directories on exit.
+    --harden <level>     - Apply a set of restrictions.  minimal, default, or paranoid
+    --explain-hardening  - Print the restrictions of the --harden level, then exit
+    --explain            - Print the effective sandbox policy before running.
+                           Without a command, exit afterwards.
+    --no-userfaultfd     - Deny userfaultfd()
+    --no-io-uring        - Deny io_uring
+    --entropy-seed <N>   - For testing only.  Replace /dev/urandom and /dev/random with
+                           bytes generated from seed N, and deny getrandom(), so that
+                           random-dependent failures can be reproduced.  Not secure.
+    --shell        - Run $SHELL with a \"{prompt}\" prompt prefix, instead of <cmd>
+    --name <name>  - Set $SANDBOX_NAME for the command.  Default \"{name}\"
+    --prompt <prefix>    - Prepend to the shell prompt ($PS1)
+    --virtualenv-compat  - Also set $VIRTUAL_ENV=isolated, as older versions did
+    --gui          - Allow Wayland clients, through a security context registered
+                     with the compositor, which may then restrict privileged protocols.
+                     The compositor must support wp_security_context_manager_v1.
+    --document <file>    - Grant access to one file through xdg-document-portal,
+                           instead of binding a whole directory.  May be repeated.
+                           Paths as seen by the command are listed in $SANDBOX_DOCUMENTS.
+    --pick-documents     - Choose files to grant with the host file picker, before running.
+    --secret <name>=<file> - Read a secret from <file>, and provide it as
+                           /run/secrets/<name>, in memory, readable only by the command.
+                           Variables holding the value are removed from the environment.
+                           May be repeated.
+    --env-preset ci|minimal|desktop - Pass on only a curated list of environment
+                     variables.  $PATH, $TERM, $HOME, and the locale for minimal.
+                     Also build flags (eg. $MAKEFLAGS) for ci, or the display
+                     (eg. $WAYLAND_DISPLAY) for desktop.  See --explain.
+    --pty          - Run the command on a new pseudo-terminal, relayed to this one.
+                     $TERM and the window size are passed on, and follow resizing.
+    --color auto|always|never - Pass color escapes through.  By default, not when
+                     output is captured (--output-limit or --detach), where $TERM=dumb
+                     and $NO_COLOR are set, and escapes are removed.
+    --as-root      - When run by root, run the command as root of the host.  Otherwise
+                     as root of a user namespace, which is uid and gid 65534 of the
+                     host, or the first range of \"root\" in /etc/subuid and /etc/subgid.
+    --run-as <user> - When run by root, run the command as <user> (a name or uid in
+                     /etc/passwd), without supplementary groups, and with $HOME,
+                     $USER, and $LOGNAME of <user>.  eg. \"nobody\"
+    --exec-stdin   - Read an executable from stdin, and run it from memory, without
+                     writing it to any filesystem.  Arguments, if given, begin with argv[0].
+    -W --rw <dir>  - Allow writes to part of the directory tree
+    -O --ro <dir>  - Deny writes to part of the directory tree
+    --toolchains   - Bind well known toolchain roots outside of /usr read-only,
+                     nosuid, and nodev.  eg. /nix/store, /opt/toolchains, Linuxbrew.
+                     Lower precedence than all other directories.
+
+eg. prevent a build from accidentally changing files outside of the build directory.
+  $ isolate make
+
+";
+
+const SANDBOX_USAGE: &str = "Usage: {execname} [-h] [-v|-q] profile lint <file> [file ...]
+       {execname} profile preview <file>
+       {execname} allow|deny [file]
+       {execname} list
+       {execname} inspect <id|name>
+       {execname} stop [-t <sec>] <id|name>
+       {execname} exec <id|name> <cmd> [args ...]
+       {execname} gc [-n]
+       {execname} server [--profile <file>] [--socket <path>]
+       {execname} request <socket> <cmd> [args ...]
+       {execname} --version [--verbose]
+
+Manage sandbox configuration, and detached sandboxes (isolate --detach).
+
+Options:
+    -v, -q              - More, or less, verbose messages.  -v may be repeated.
+
+Commands:
+    profile lint <file> - Check profiles for errors and unknown keys.
+                          Exit with non-zero status if any problem is found.
+    profile preview <file> - Show which host paths would be writable, read-only,
+                          hidden, or tmpfs, with a profile in $PWD.  Nothing is run.
+    allow [file]        - Allow isolate to use the current contents of a project
+                          profile.  By default, the {file} found from $PWD.
+    deny [file]         - Stop using a project profile
+    list                - Show detached sandboxes
+    inspect <id|name>   - Show the status, open files, and mounts of process 1
+                          of a detached sandbox
+    stop <id|name>      - Send SIGTERM to a detached sandbox.  After -t seconds
+                          (default {timeout}), SIGKILL all of its processes.
+    exec <id|name> <cmd> - Run a command in the namespaces of a detached sandbox.
+                          Seccomp filters of the sandbox are not applied.
+    gc [-n]             - Remove leftovers of sandboxes which were killed.  Temporary
+                          directories, exited detached sandboxes, host interfaces,
+                          and empty cgroups.  With -n, only list them.
+    server              - Prepare a sandbox once, as by --profile.  Then run each
+                          request in a fresh copy, with its own process 1, /proc, and
+                          /tmp.  Listens on --socket, by default $TMPDIR/sandbox-server-<id>.sock
+    request <socket> <cmd> - Run a command through a server, with the stdin, stdout,
+                          stderr, and working directory of the caller.  Exit with its status.
+    --version [--verbose] - Show the version.  With --verbose, as JSON, with the
+                          features of this build, and the kernel interfaces available.
+";
+
+const CARGO_ISOLATE_USAGE: &str =
+    "Usage: cargo isolate [-h] [-v|-q] [-N|--net] [--registry ro|rw] [-p|--profile <file>]
+       [-W|--rw <dir>] [-O|--ro <dir>] <command> [args ...]
+
+Run a cargo command (eg. build or test) with isolate.  Only the target directory
+is writable.  The rest of the workspace, and the registry cache, are read-only.
+Without network access, cargo is run with $CARGO_NET_OFFLINE=true.
+A missing Cargo.lock is generated before entering the sandbox.
+
+Options:
+    -h             - Show this message
+    -v -q          - More, or less, verbose messages.  Passed to isolate
+    -N --net       - Allow network access.  Implies --registry rw
+    --registry ro|rw - Whether cargo may add to the registry and git caches
+                     under $CARGO_HOME.  Default ro, unless --net.
+    -p --profile <file> - Passed to isolate
+    -W --rw <dir>  - Allow writes to another directory
+    -O --ro <dir>  - Deny writes to another directory
+
+Defaults may be set in Cargo.toml.  Command line options take precedence.
+
+  [package.metadata.isolate]  # or [workspace.metadata.isolate]
+  net = false
+  registry = \"ro\"
+  rw = [\"generated\"]        # relative to the workspace root
+  ro = []
+  profile = \"isolate.toml\"
+
+eg.
+  $ cargo isolate test
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_fallback() {
+        const DE: &[(Msg, &str)] = &[(Msg::UnderTmp, "Nicht unter /tmp")];
+        let catalogs: &[(&str, &[(Msg, &str)])] = &[("de", DE)];
+        assert_eq!(candidates("pt_BR.UTF-8@euro"), vec!["pt_BR", "pt"]);
+        assert_eq!(candidates("C"), vec!["C"]);
+        assert_eq!(candidates(""), Vec::<&str>::new());
+
+        assert_eq!(
+            lookup(catalogs, "de_AT.UTF-8", Msg::UnderTmp),
+            "Nicht unter /tmp"
+        );
+        assert_eq!(lookup(catalogs, "de_AT", Msg::Error), "error");
+        assert_eq!(lookup(catalogs, "C", Msg::UnderTmp), "Can't run under /tmp");
+    }
+
+    #[test]
+    fn placeholders() {
+        let id = 42;
+        assert_eq!(
+            substitute("Sandbox {id} {other}", &[("id", &id)]),
+            "Sandbox 42 {other}"
+        );
+    }
+}
@@ -0,0 +1,326 @@
+//! User-mode NAT.  Network access for a sandbox, without privilege on the host.
+//!
+//! In the network namespace of the sandbox, `eth0` is one end of a veth pair, with the
+//! peer `nat0` as its gateway.  Both ends stay in that namespace.  nf_tables rules
+//! redirect outgoing TCP connections, and DNS queries, to sockets which are passed to
+//! the parent.  There a forwarder makes each connection, or query, again from the host
+//! network namespace.  Addresses are as with slirp.  eg. `10.0.2.15`
+//!
+//! Only TCP, and DNS over UDP, reach the host network.  Other traffic ends at `nat0`,
+//! as the namespace does not forward.  Host loopback services are not reachable.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::{AsFd, AsRawFd};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, warn};
+
+use super::err::{Error, Result};
+use super::nft::{self, Proto, Rule};
+use super::{id, net, util};
+
+/// Interface of the sandbox
+pub const IFNAME: &str = "eth0";
+/// veth peer of `IFNAME`, holding `GATEWAY`
+pub const PEER: &str = "nat0";
+pub const ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+pub const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+/// Queries to any name server are relayed.  This one is listed in `/etc/resolv.conf`.
+pub const NAMESERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+const PREFIX: u8 = 24;
+
+const DNS_PORT: u16 = 53;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sockets receiving the traffic redirected in the network namespace of a sandbox
+#[derive(Debug)]
+pub struct Sockets {
+    tcp: TcpListener,
+    dns: UdpSocket,
+}
+
+/// Configure the current network namespace for NAT.  With `CAP_NET_ADMIN`.
+/// eg. from `ContainerHooks::setup_priv()`, after `net::configure_lo()`.
+pub fn setup() -> Result<Sockets> {
+    net::Veth::create(IFNAME, PEER)?;
+    net::add_address(PEER, GATEWAY, PREFIX)?;
+    net::add_address(IFNAME, ADDRESS, PREFIX)?;
+    net::set_up(PEER)?;
+    net::set_up(IFNAME)?;
+    net::add_default_route(IFNAME, GATEWAY)?;
+
+    let tcp = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .map_err(|e| Error::os("bind() NAT TCP socket", e))?;
+    let dns = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .map_err(|e| Error::os("bind() NAT DNS socket", e))?;
+    let port = |addr: io::Result<SocketAddr>| {
+        addr.map(|a| a.port())
+            .map_err(|e| Error::os("NAT socket address", e))
+    };
+
+    // the namespace is removed with the sandbox
+    let mut table = nft::Table::create_detached(&id::current())?;
+    table.add(&Rule::Redirect {
+        proto: Proto::Udp,
+        dport: Some(DNS_PORT),
+        port: port(dns.local_addr())?,
+    })?;
+    table.add(&Rule::Redirect {
+        proto: Proto::Tcp,
+        dport: None,
+        port: port(tcp.local_addr())?,
+    })?;
+    debug!("NAT as {}/{} via {}", ADDRESS, PREFIX, GATEWAY);
+    Ok(Sockets { tcp, dns })
+}
+
+/// Contents of `/etc/resolv.conf` in the sandbox
+pub fn resolv_conf() -> String {
+    format!("# user-mode NAT\nnameserver {}\n", NAMESERVER)
+}
+
+/// Name servers listed in the contents of a `resolv.conf`
+pub fn nameservers(text: &str) -> Vec<SocketAddr> {
+    text.lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some("nameserver"), Some(addr)) => addr.parse::<IpAddr>().ok(),
+                _ => None,
+            }
+        })
+        .map(|addr| SocketAddr::new(addr, DNS_PORT))
+        .collect()
+}
+
+/// Name servers of the host
+pub fn host_nameservers() -> Vec<SocketAddr> {
+    match std::fs::read_to_string("/etc/resolv.conf") {
+        Ok(text) => nameservers(&text),
+        Err(err) => {
+            warn!("NAT without DNS: /etc/resolv.conf {}", err);
+            vec![]
+        }
+    }
+}
+
+impl Sockets {
+    /// Pass to another process.  eg. over `StageCtx::channel()`
+    pub fn send<S: AsFd>(&self, sock: S) -> Result<()> {
+        util::send_fd(&sock, &self.tcp)?;
+        util::send_fd(&sock, &self.dns)
+    }
+
+    /// Receive from `send()`.  Returns `None` if the peer closed the socket without sending.
+    pub fn recv<S: AsFd>(sock: S) -> Result<Option<Sockets>> {
+        let tcp = match util::recv_fd(&sock)? {
+            Some(fd) => TcpListener::from(fd),
+            None => return Ok(None),
+        };
+        let dns = match util::recv_fd(&sock)? {
+            Some(fd) => UdpSocket::from(fd),
+            None => return Ok(None),
+        };
+        Ok(Some(Sockets { tcp, dns }))
+    }
+
+    /// Forward from background threads, to the network namespace of the calling process.
+    /// DNS queries to `upstream`.  Runs until the process exits.
+    pub fn spawn(self, upstream: Vec<SocketAddr>) -> Result<()> {
+        let Sockets { tcp, dns } = self;
+        thread::Builder::new()
+            .name("nat-tcp".into())
+            .spawn(move || loop {
+                match tcp.accept() {
+                    Ok((inner, _)) => {
+                        thread::spawn(move || {
+                            if let Err(err) = relay(inner) {
+                                debug!("NAT connection fails : {}", err);
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        warn!("NAT stops : {}", err);
+                        return;
+                    }
+                }
+            })
+            .map_err(|e| Error::os("spawn NAT thread", e))?;
+        thread::Builder::new()
+            .name("nat-dns".into())
+            .spawn(move || {
+                let mut buf = vec![0; 65536];
+                loop {
+                    let (n, peer) = match dns.recv_from(&mut buf) {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            warn!("NAT DNS stops : {}", err);
+                            return;
+                        }
+                    };
+                    let (query, upstream) = (buf[..n].to_vec(), upstream.clone());
+                    let reply = match dns.try_clone() {
+                        Ok(sock) => sock,
+                        Err(err) => {
+                            warn!("NAT DNS stops : {}", err);
+                            return;
+                        }
+                    };
+                    thread::spawn(move || match resolve(&query, &upstream) {
+                        Ok(answer) => {
+                            if let Err(err) = reply.send_to(&answer, peer) {
+                                debug!("NAT DNS reply fails : {}", err);
+                            }
+                        }
+                        Err(err) => debug!("NAT DNS query fails : {}", err),
+                    });
+                }
+            })
+            .map_err(|e| Error::os("spawn NAT thread", e))?;
+        Ok(())
+    }
+}
+
+/// Destination of a connection before it was redirected.  cf. linux/netfilter_ipv4.h
+fn original_dst(sock: &TcpStream) -> io::Result<SocketAddrV4> {
+    let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of_val(&addr) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_IP,
+            libc::SO_ORIGINAL_DST,
+            &mut addr as *mut libc::sockaddr_in as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(SocketAddrV4::new(
+        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+        u16::from_be(addr.sin_port),
+    ))
+}
+
+/// Close with a TCP reset.  So that a refused connection is seen as such.
+fn reset(sock: TcpStream) {
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            std::mem::size_of_val(&linger) as libc::socklen_t,
+        );
+    }
+}
+
+/// Connect to the original destination of `inner`, and copy in both directions until closed
+fn relay(inner: TcpStream) -> io::Result<()> {
+    let dest = original_dst(&inner)?;
+    if dest.ip().is_loopback() || dest.ip().is_unspecified() {
+        reset(inner);
+        return Err(io::ErrorKind::PermissionDenied.into());
+    }
+    let outer = match TcpStream::connect_timeout(&dest.into(), CONNECT_TIMEOUT) {
+        Ok(outer) => outer,
+        Err(err) => {
+            reset(inner);
+            return Err(err);
+        }
+    };
+    debug!("NAT connect {}", dest);
+    net::splice(inner, outer)
+}
+
+/// Ask each of `upstream` in turn, until one answers
+fn resolve(query: &[u8], upstream: &[SocketAddr]) -> io::Result<Vec<u8>> {
+    let mut ret = Err(io::Error::new(io::ErrorKind::NotFound, "no name servers"));
+    for server in upstream {
+        let local: SocketAddr = if server.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let sock = UdpSocket::bind(local)?;
+        sock.set_read_timeout(Some(DNS_TIMEOUT))?;
+        sock.connect(server)?;
+        sock.send(query)?;
+        let mut buf = vec![0; 65536];
+        match sock.recv(&mut buf) {
+            Ok(n) => {
+                buf.truncate(n);
+                return Ok(buf);
+            }
+            Err(err) => ret = Err(err),
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proc;
+
+    #[test]
+    fn parse() {
+        let text = "# comment\nnameserver 127.0.0.53\nnameserver ::1\noptions edns0\nnameserver\n";
+        assert_eq!(
+            nameservers(text),
+            vec![
+                "127.0.0.53:53".parse().unwrap(),
+                "[::1]:53".parse().unwrap()
+            ]
+        );
+        assert!(resolv_conf().contains("nameserver 10.0.2.3\n"));
+    }
+
+    #[test]
+    fn redirected() {
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = proc::fork::<_, Error>(|| {
+            util::unshare(libc::CLONE_NEWNET)?;
+            net::configure_lo()?;
+            let socks = setup()?;
+
+            // TEST-NET-1
+            let dest: SocketAddr = "192.0.2.1:80".parse().unwrap();
+            let _out = TcpStream::connect(dest).map_err(|e| Error::os("connect", e))?;
+            let (inner, _) = socks.tcp.accept().map_err(|e| Error::os("accept", e))?;
+            let orig = original_dst(&inner).map_err(|e| Error::os("SO_ORIGINAL_DST", e))?;
+
+            let query = UdpSocket::bind((ADDRESS, 0)).map_err(|e| Error::os("bind", e))?;
+            query
+                .send_to(b"query", (NAMESERVER, DNS_PORT))
+                .map_err(|e| Error::os("send", e))?;
+            let mut buf = [0; 16];
+            let (n, peer) = socks
+                .dns
+                .recv_from(&mut buf)
+                .map_err(|e| Error::os("recv", e))?;
+            std::process::exit(
+                if SocketAddr::from(orig) == dest
+                    && &buf[..n] == b"query"
+                    && peer == query.local_addr().unwrap()
+                {
+                    0
+                } else {
+                    2
+                },
+            );
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+}
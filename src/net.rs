@@ -1,15 +1,22 @@
 //! Direct manipulations of network configuration.  (eg. like `/sbin/ifconfig` or `/sbin/ip`)
 
+use std::ffi::CStr;
 use std::fs::{File, OpenOptions};
-use std::io::Read;
-use std::net::{self, Ipv4Addr, UdpSocket};
+use std::io::{self, Read};
+use std::net::{
+    self, IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket,
+};
 use std::os::unix::prelude::*;
+use std::path::Path;
 use std::ptr;
+use std::thread;
 
 use log;
 
-use super::err::{Error, Result};
-use super::{ext, proc, util};
+use super::err::{Errno, Error, Result};
+use super::retry::retry;
+use super::rtnl::{self, Rtnl};
+use super::{ext, id, proc, util};
 
 pub const LOOPBACK: &str = "lo";
 
@@ -50,8 +57,16 @@ impl IfReq {
     }
 
     /// Make a `ioctl()` on the named interface
+    /// Retried while the device is busy.  eg. still being created or removed.
     unsafe fn ioctl<FD: AsRawFd>(&mut self, fd: FD, req: u32) -> Result<()> {
-        let err = ext::ioctl(fd.as_raw_fd(), req as _, &mut self.0);
+        let fd = fd.as_raw_fd();
+        retry("ioctl", &[Errno::EBUSY, Errno::EAGAIN], || {
+            self.ioctl_once(fd, req)
+        })
+    }
+
+    unsafe fn ioctl_once(&mut self, fd: RawFd, req: u32) -> Result<()> {
+        let err = ext::ioctl(fd, req as _, &mut self.0);
         if err != 0 {
             let mut raw = vec![0; ::std::mem::size_of_val(&self.0)];
             ptr::copy_nonoverlapping(
@@ -82,21 +97,84 @@ impl std::ops::DerefMut for IfReq {
 }
 
 /// Network Interface Configurator.  A (small) sub-set of `/sbin/ifconfig`
-pub struct IfConfig(UdpSocket);
+pub struct IfConfig {
+    sock: UdpSocket,
+    /// `None` when the kernel has no IPv6
+    sock6: Option<OwnedFd>,
+    /// For queries through rtnetlink.  `None` for the current network namespace.
+    netns: Option<OwnedFd>,
+}
+
+/// `struct in6_ifreq`, of linux/ipv6.h.  Which SIOCSIFADDR takes for IPv6.
+#[repr(C)]
+struct In6IfReq {
+    addr: libc::in6_addr,
+    prefixlen: u32,
+    ifindex: libc::c_int,
+}
+
+/// The "dummy" sockets of `IfConfig`
+fn ifconfig_sockets() -> Result<(UdpSocket, Option<OwnedFd>)> {
+    let sock =
+        UdpSocket::bind("127.0.0.1:0").map_err(|e| Error::os("bind() ifconfig socket", e))?;
+    let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    let sock6 = if fd >= 0 {
+        Some(unsafe { OwnedFd::from_raw_fd(fd) })
+    } else {
+        log::debug!("No IPv6 : {}", io::Error::last_os_error());
+        None
+    };
+    Ok((sock, sock6))
+}
 
 impl IfConfig {
     /// Prepare to maniplate.  (allocates a "dummy" socket)
     pub fn new() -> Result<Self> {
-        let sock =
-            UdpSocket::bind("127.0.0.1:0").map_err(|e| Error::os("bind() ifconfig socket", e))?;
-        Ok(Self(sock))
+        let (sock, sock6) = ifconfig_sockets()?;
+        Ok(Self {
+            sock,
+            sock6,
+            netns: None,
+        })
+    }
+
+    /// Configure the network namespace `netns`.  eg. an open `/proc/<pid>/ns/net`
+    ///
+    /// The socket is created by a short lived thread which joins `netns`.
+    /// A socket stays in the namespace where it was created, so the calling thread
+    /// need not, and can configure both sides of eg. a veth pair.
+    pub fn in_netns<F: AsFd>(netns: F) -> Result<Self> {
+        let netns = netns.as_fd();
+        let (sock, sock6) = std::thread::scope(|s| {
+            s.spawn(|| -> Result<(UdpSocket, Option<OwnedFd>)> {
+                util::setns(netns, libc::CLONE_NEWNET)?;
+                ifconfig_sockets()
+            })
+            .join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e))
+        })?;
+        let netns = netns
+            .try_clone_to_owned()
+            .map_err(|e| Error::os("dup netns", e))?;
+        Ok(Self {
+            sock,
+            sock6,
+            netns: Some(netns),
+        })
+    }
+
+    /// Configure the network namespace of process `pid`
+    pub fn for_pid(pid: libc::pid_t) -> Result<Self> {
+        let path = format!("/proc/{}/ns/net", pid);
+        let netns = File::open(&path).map_err(|e| Error::file("open", &path, e))?;
+        Self::in_netns(&netns)
     }
 
     /// Map network interface name to numeric index
     pub fn ifindex<S: AsRef<str>>(&self, ifname: S) -> Result<u32> {
         let mut req = IfReq::from_name(ifname.as_ref())?;
         let ret = unsafe {
-            req.ioctl(self.0.as_raw_fd(), ext::SIOCGIFINDEX)?;
+            req.ioctl(self.sock.as_raw_fd(), ext::SIOCGIFINDEX)?;
             req.ifr_ifru.ifru_ivalue as u32
         };
         log::debug!("ifindex({:?}) -> {}", ifname.as_ref(), ret);
@@ -107,7 +185,7 @@ impl IfConfig {
     pub fn ifflags<S: AsRef<str>>(&self, ifname: S) -> Result<u32> {
         let mut req = IfReq::from_name(ifname.as_ref())?;
         let ret = unsafe {
-            req.ioctl(self.0.as_raw_fd(), ext::SIOCGIFFLAGS)?;
+            req.ioctl(self.sock.as_raw_fd(), ext::SIOCGIFFLAGS)?;
             req.ifr_ifru.ifru_flags as u32
         };
         log::debug!("ifflags({:?}) -> {}", ifname.as_ref(), ret);
@@ -120,31 +198,80 @@ impl IfConfig {
         let mut req = IfReq::from_name(ifname)?;
         unsafe {
             req.ifr_ifru.ifru_flags = flags as _;
-            req.ioctl(self.0.as_raw_fd(), ext::SIOCSIFFLAGS)?;
+            req.ioctl(self.sock.as_raw_fd(), ext::SIOCSIFFLAGS)?;
             Ok(())
         }
     }
 
-    /// Find "the" IPv4 address of the named interface.
-    /// Unspecified (as in I don't know) how this behaves when more than one IPv4 address is assigned.
-    pub fn address<S: AsRef<str>>(&self, ifname: S) -> Result<net::Ipv4Addr> {
+    /// Maximum transmission unit, in bytes
+    pub fn ifmtu<S: AsRef<str>>(&self, ifname: S) -> Result<u32> {
         let mut req = IfReq::from_name(ifname.as_ref())?;
+        let ret = unsafe {
+            req.ioctl(self.sock.as_raw_fd(), ext::SIOCGIFMTU)?;
+            req.ifr_ifru.ifru_mtu as u32
+        };
+        log::debug!("ifmtu({:?}) -> {}", ifname.as_ref(), ret);
+        Ok(ret)
+    }
+
+    /// Change the maximum transmission unit.  eg. 9000 for jumbo frames.
+    /// Within the limits of the interface type.  eg. at least 68 for IPv4, 1280 for IPv6.
+    pub fn set_ifmtu<S: AsRef<str>>(&self, ifname: S, mtu: u32) -> Result<()> {
+        log::debug!("set_ifmtu({:?}, {})", ifname.as_ref(), mtu);
+        let mut req = IfReq::from_name(ifname)?;
+        unsafe {
+            req.ifr_ifru.ifru_mtu = mtu as _;
+            req.ioctl(self.sock.as_raw_fd(), ext::SIOCSIFMTU)?;
+        }
+        Ok(())
+    }
+
+    /// Ethernet (MAC) address of the named interface
+    pub fn hwaddr<S: AsRef<str>>(&self, ifname: S) -> Result<[u8; 6]> {
+        let mut req = IfReq::from_name(ifname.as_ref())?;
+        let mut ret = [0; 6];
+        unsafe {
+            req.ioctl(self.sock.as_raw_fd(), ext::SIOCGIFHWADDR)?;
+            for (b, d) in ret.iter_mut().zip(req.ifr_ifru.ifru_hwaddr.sa_data.iter()) {
+                *b = *d as u8;
+            }
+        }
+        log::debug!("hwaddr({:?}) -> {:02x?}", ifname.as_ref(), ret);
+        Ok(ret)
+    }
+
+    /// Change the Ethernet (MAC) address of the named interface.  eg. of a tap or bridge.
+    /// A locally administered unicast address has bit 1 of the first byte set,
+    /// and bit 0 clear.  eg. `02:00:00:00:00:01`
+    pub fn set_hwaddr<S: AsRef<str>>(&self, ifname: S, addr: [u8; 6]) -> Result<()> {
+        log::debug!("set_hwaddr({:?}, {:02x?})", ifname.as_ref(), addr);
+        let mut req = IfReq::from_name(ifname)?;
+        unsafe {
+            req.ifr_ifru.ifru_hwaddr.sa_family = libc::ARPHRD_ETHER;
+            for (d, b) in req.ifr_ifru.ifru_hwaddr.sa_data.iter_mut().zip(addr) {
+                *d = b as _;
+            }
+            req.ioctl(self.sock.as_raw_fd(), ext::SIOCSIFHWADDR)?;
+        }
+        Ok(())
+    }
+
+    /// An IPv4 `sockaddr` of the named interface.  eg. `SIOCGIFADDR`
+    fn get_inet(&self, ifname: &str, op: u32) -> Result<net::Ipv4Addr> {
+        let mut req = IfReq::from_name(ifname)?;
         let saddr = unsafe {
-            req.ioctl(self.0.as_raw_fd(), ext::SIOCGIFADDR)?;
+            req.ioctl(self.sock.as_raw_fd(), op)?;
             if req.ifr_ifru.ifru_addr.sa_family != libc::AF_INET as libc::sa_family_t {
                 Err(Error::NotIPv4)?;
             }
             let inaddr = &req.ifr_ifru.ifru_addr as *const _ as *const libc::sockaddr_in;
             (*inaddr).sin_addr.s_addr
         };
-        let ret = net::Ipv4Addr::from(u32::from_be(saddr));
-        log::debug!("address({:?}) -> {}", ifname.as_ref(), ret);
-        Ok(ret)
+        Ok(net::Ipv4Addr::from(u32::from_be(saddr)))
     }
 
-    /// Set "the" IPv4 address of the named interface.
-    pub fn set_address<S: AsRef<str>>(&self, ifname: S, addr: net::Ipv4Addr) -> Result<()> {
-        log::debug!("set_address({:?}, {})", ifname.as_ref(), addr);
+    /// Change an IPv4 `sockaddr` of the named interface.  eg. `SIOCSIFADDR`
+    fn set_inet(&self, ifname: &str, op: u32, addr: net::Ipv4Addr) -> Result<()> {
         let iaddr = b2u32(addr.octets());
         let mut req = IfReq::from_name(ifname)?;
         unsafe {
@@ -152,18 +279,93 @@ impl IfConfig {
             (*inaddr).sin_family = libc::AF_INET as libc::sa_family_t;
             (*inaddr).sin_port = 0;
             (*inaddr).sin_addr.s_addr = iaddr;
-            req.ioctl(self.0.as_raw_fd(), ext::SIOCSIFADDR)?;
+            req.ioctl(self.sock.as_raw_fd(), op)?;
+        }
+        Ok(())
+    }
+
+    /// Find "the" IPv4 address of the named interface.
+    /// Unspecified (as in I don't know) how this behaves when more than one IPv4 address is assigned.
+    /// cf. `address6()` for IPv6.
+    pub fn address<S: AsRef<str>>(&self, ifname: S) -> Result<net::Ipv4Addr> {
+        let ret = self.get_inet(ifname.as_ref(), ext::SIOCGIFADDR)?;
+        log::debug!("address({:?}) -> {}", ifname.as_ref(), ret);
+        Ok(ret)
+    }
+
+    /// Set "the" IPv4 address of the named interface.
+    /// The netmask is then the default of the address class.  cf. `set_netmask()`
+    pub fn set_address<S: AsRef<str>>(&self, ifname: S, addr: net::Ipv4Addr) -> Result<()> {
+        log::debug!("set_address({:?}, {})", ifname.as_ref(), addr);
+        self.set_inet(ifname.as_ref(), ext::SIOCSIFADDR, addr)
+    }
+
+    /// Netmask of "the" IPv4 address of the named interface.  eg. `255.255.255.0`
+    pub fn netmask<S: AsRef<str>>(&self, ifname: S) -> Result<net::Ipv4Addr> {
+        let ret = self.get_inet(ifname.as_ref(), ext::SIOCGIFNETMASK)?;
+        log::debug!("netmask({:?}) -> {}", ifname.as_ref(), ret);
+        Ok(ret)
+    }
+
+    /// Change the netmask of "the" IPv4 address of the named interface.
+    /// After `set_address()`, which would replace it.
+    pub fn set_netmask<S: AsRef<str>>(&self, ifname: S, mask: net::Ipv4Addr) -> Result<()> {
+        log::debug!("set_netmask({:?}, {})", ifname.as_ref(), mask);
+        self.set_inet(ifname.as_ref(), ext::SIOCSIFNETMASK, mask)
+    }
+
+    /// The IPv6 addresses of the named interface, with prefix lengths.
+    /// Through rtnetlink, as no `ioctl()` lists them.
+    pub fn address6<S: AsRef<str>>(&self, ifname: S) -> Result<Vec<(Ipv6Addr, u8)>> {
+        let index = self.ifindex(ifname.as_ref())?;
+        let mut nl = match &self.netns {
+            Some(netns) => Netlink::in_netns(netns)?,
+            None => Netlink::new()?,
+        };
+        let ret: Vec<(Ipv6Addr, u8)> = nl
+            .addresses()?
+            .into_iter()
+            .filter_map(|(idx, addr, prefix)| match addr {
+                IpAddr::V6(addr) if idx == index => Some((addr, prefix)),
+                _ => None,
+            })
+            .collect();
+        log::debug!("address6({:?}) -> {:?}", ifname.as_ref(), ret);
+        Ok(ret)
+    }
+
+    /// Add an IPv6 address to the named interface.  Beside any others.
+    pub fn set_address6<S: AsRef<str>>(&self, ifname: S, addr: Ipv6Addr, prefix: u8) -> Result<()> {
+        log::debug!("set_address6({:?}, {}/{})", ifname.as_ref(), addr, prefix);
+        let sock6 = self
+            .sock6
+            .as_ref()
+            .ok_or_else(|| Error::os("set_address6", io::ErrorKind::Unsupported.into()))?;
+        let req = In6IfReq {
+            addr: libc::in6_addr {
+                s6_addr: addr.octets(),
+            },
+            prefixlen: prefix.into(),
+            ifindex: self.ifindex(ifname)? as _,
+        };
+        if unsafe { libc::ioctl(sock6.as_raw_fd(), ext::SIOCSIFADDR as _, &req) } != 0 {
+            return Err(Error::last_os_error("ioctl(SIOCSIFADDR) IPv6"));
         }
         Ok(())
     }
 
+    /// Is IPv6 available to `set_address6()`?
+    pub fn has_ipv6(&self) -> bool {
+        self.sock6.is_some()
+    }
+
     /// Create a soft ethernet bridge
     pub fn bridge_create<B: AsRef<str>>(&self, brname: B) -> Result<()> {
         log::debug!("bridge_create({:?})", brname.as_ref());
         let mut req = IfReq::from_name(brname)?;
         unsafe {
             // only the interface name is used
-            req.ioctl(self.0.as_raw_fd(), ext::SIOCBRADDBR)?;
+            req.ioctl(self.sock.as_raw_fd(), ext::SIOCBRADDBR)?;
         }
         Ok(())
     }
@@ -180,7 +382,404 @@ impl IfConfig {
         let mut req = IfReq::from_name(brname)?;
         req.ifr_ifru.ifru_ivalue = index as _;
         unsafe {
-            req.ioctl(self.0.as_raw_fd(), ext::SIOCBRADDIF)?;
+            req.ioctl(self.sock.as_raw_fd(), ext::SIOCBRADDIF)?;
+        }
+        Ok(())
+    }
+}
+
+/// Network configuration through rtnetlink.  For what `IfConfig` can not do.
+/// eg. more than one address per interface, IPv6 addresses, routes, or creating interfaces.
+pub struct Netlink(Rtnl);
+
+/// Offsets of the fields of `struct ifinfomsg`, and its size
+const IFI_INDEX: usize = 4;
+const IFI_FLAGS: usize = 8;
+const IFINFOMSG_LEN: usize = 16;
+/// Offsets of the fields of `struct ifaddrmsg`, and its size
+const IFA_PREFIXLEN: usize = 1;
+const IFA_INDEX: usize = 4;
+const IFADDRMSG_LEN: usize = 8;
+/// Offsets of the fields of `struct rtmsg`, and its size
+const RTM_FAMILY: usize = 0;
+const RTM_DST_LEN: usize = 1;
+const RTM_TABLE: usize = 4;
+const RTM_TYPE: usize = 7;
+const RTMSG_LEN: usize = 12;
+
+impl Netlink {
+    pub fn new() -> Result<Self> {
+        Ok(Self(Rtnl::new()?))
+    }
+
+    /// Configure the network namespace `netns`.  cf. `IfConfig::in_netns()`
+    pub fn in_netns<F: AsFd>(netns: F) -> Result<Self> {
+        let netns = netns.as_fd();
+        let rtnl = std::thread::scope(|s| {
+            s.spawn(|| -> Result<Rtnl> {
+                util::setns(netns, libc::CLONE_NEWNET)?;
+                Rtnl::new()
+            })
+            .join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e))
+        })?;
+        Ok(Self(rtnl))
+    }
+
+    /// Configure the network namespace of process `pid`
+    pub fn for_pid(pid: libc::pid_t) -> Result<Self> {
+        let path = format!("/proc/{}/ns/net", pid);
+        let netns = File::open(&path).map_err(|e| Error::file("open", &path, e))?;
+        Self::in_netns(&netns)
+    }
+
+    /// Map network interface name to numeric index.  In the namespace of this socket.
+    pub fn ifindex<S: AsRef<str>>(&mut self, ifname: S) -> Result<u32> {
+        IfReq::from_name(ifname.as_ref())?;
+        let mut req = rtnl::Request::new(rtnl::RTM_GETLINK, 0);
+        req.ifinfomsg(0).str(rtnl::IFLA_IFNAME, ifname.as_ref());
+        let reply = self.0.fetch("get link", req)?;
+        reply
+            .first()
+            .and_then(|msg| msg.u32_at(IFI_INDEX))
+            .ok_or_else(|| Error::os("get link", io::ErrorKind::InvalidData.into()))
+    }
+
+    /// List the network interfaces, with their addresses, ordered by index.  cf. `interfaces()`
+    pub fn interfaces(&mut self) -> Result<Vec<Interface>> {
+        let mut req = rtnl::Request::dump(rtnl::RTM_GETLINK);
+        req.ifinfomsg(0);
+        let mut ret = vec![];
+        for msg in self.0.fetch("list links", req)? {
+            let mut iface = Interface {
+                name: String::new(),
+                index: msg.u32_at(IFI_INDEX).unwrap_or(0),
+                flags: msg.u32_at(IFI_FLAGS).unwrap_or(0),
+                addrs: vec![],
+                hwaddr: vec![],
+            };
+            for (atype, data) in msg.attrs(IFINFOMSG_LEN) {
+                match atype {
+                    rtnl::IFLA_IFNAME => {
+                        let name = data.split(|b| *b == 0).next().unwrap_or_default();
+                        iface.name = String::from_utf8_lossy(name).into_owned();
+                    }
+                    rtnl::IFLA_ADDRESS => iface.hwaddr = data.to_vec(),
+                    _ => (),
+                }
+            }
+            ret.push(iface);
+        }
+        for (index, addr, _prefix) in self.addresses()? {
+            if let Some(iface) = ret.iter_mut().find(|iface| iface.index == index) {
+                iface.addrs.push(addr);
+            }
+        }
+        ret.sort_by_key(|iface| iface.index);
+        Ok(ret)
+    }
+
+    /// All IPv4 and IPv6 addresses, as (interface index, address, prefix length)
+    pub fn addresses(&mut self) -> Result<Vec<(u32, IpAddr, u8)>> {
+        let mut req = rtnl::Request::dump(rtnl::RTM_GETADDR);
+        req.ifaddrmsg(libc::AF_UNSPEC as u8, 0, 0);
+        let mut ret = vec![];
+        for msg in self.0.fetch("list addresses", req)? {
+            let index = msg.u32_at(IFA_INDEX).unwrap_or(0);
+            let prefix = msg.body.get(IFA_PREFIXLEN).copied().unwrap_or(0);
+            let attrs = msg.attrs(IFADDRMSG_LEN);
+            // IFA_LOCAL is the address of a point-to-point interface, where IFA_ADDRESS is the peer
+            let addr = [rtnl::IFA_LOCAL, rtnl::IFA_ADDRESS]
+                .iter()
+                .find_map(|want| {
+                    attrs
+                        .iter()
+                        .filter(|(atype, _)| atype == want)
+                        .find_map(|(_, data)| ip_from(data))
+                });
+            ret.extend(addr.map(|addr| (index, addr, prefix)));
+        }
+        Ok(ret)
+    }
+
+    /// Change the interface flags of `change`.  eg. `IFF_UP`
+    pub fn set_ifflags<S: AsRef<str>>(&mut self, ifname: S, flags: u32, change: u32) -> Result<()> {
+        log::debug!(
+            "Netlink::set_ifflags({:?}, {}, {})",
+            ifname.as_ref(),
+            flags,
+            change
+        );
+        let index = self.ifindex(ifname)?;
+        let mut req = rtnl::Request::new(rtnl::RTM_NEWLINK, 0);
+        req.ifinfomsg_flags(index, flags, change);
+        self.0.request("set link flags", req)
+    }
+
+    /// Bring an interface UP
+    pub fn set_up<S: AsRef<str>>(&mut self, ifname: S) -> Result<()> {
+        self.set_ifflags(ifname, ext::IFF_UP, ext::IFF_UP)
+    }
+
+    /// Create a new interface of `kind` which needs no further parameters.  eg. "bridge" or "dummy"
+    pub fn create_link<S: AsRef<str>>(&mut self, ifname: S, kind: &str) -> Result<()> {
+        log::debug!("Netlink::create_link({:?}, {:?})", ifname.as_ref(), kind);
+        IfReq::from_name(ifname.as_ref())?;
+        let mut req = rtnl::Request::new(rtnl::RTM_NEWLINK, rtnl::NLM_F_CREATE | rtnl::NLM_F_EXCL);
+        req.ifinfomsg(0)
+            .str(rtnl::IFLA_IFNAME, ifname.as_ref())
+            .nested(rtnl::IFLA_LINKINFO, |r| {
+                r.str(rtnl::IFLA_INFO_KIND, kind);
+            });
+        self.0.request("create link", req)
+    }
+
+    /// Remove interface `ifname`.  With a veth, also removes the peer.
+    pub fn delete_link<S: AsRef<str>>(&mut self, ifname: S) -> Result<()> {
+        log::debug!("Netlink::delete_link({:?})", ifname.as_ref());
+        let index = self.ifindex(ifname)?;
+        let mut req = rtnl::Request::new(rtnl::RTM_DELLINK, 0);
+        req.ifinfomsg(index);
+        self.0.request("delete link", req)
+    }
+
+    fn address_request(
+        &mut self,
+        mtype: u16,
+        flags: u16,
+        ifname: &str,
+        addr: IpAddr,
+        prefix: u8,
+    ) -> Result<rtnl::Request> {
+        let index = self.ifindex(ifname)?;
+        let mut req = rtnl::Request::new(mtype, flags);
+        match addr {
+            IpAddr::V4(addr) => {
+                let hostmask = u32::MAX.checked_shr(prefix as u32).unwrap_or(0);
+                let broadcast = Ipv4Addr::from(u32::from(addr) | hostmask);
+                req.ifaddrmsg(libc::AF_INET as u8, prefix, index)
+                    .attr(rtnl::IFA_LOCAL, &addr.octets())
+                    .attr(rtnl::IFA_ADDRESS, &addr.octets())
+                    .attr(rtnl::IFA_BROADCAST, &broadcast.octets());
+            }
+            IpAddr::V6(addr) => {
+                req.ifaddrmsg(libc::AF_INET6 as u8, prefix, index)
+                    .attr(rtnl::IFA_LOCAL, &addr.octets())
+                    .attr(rtnl::IFA_ADDRESS, &addr.octets());
+            }
+        }
+        Ok(req)
+    }
+
+    /// Add an IPv4 or IPv6 address, with a prefix length.  Beside any others.
+    pub fn add_address<S: AsRef<str>>(
+        &mut self,
+        ifname: S,
+        addr: IpAddr,
+        prefix: u8,
+    ) -> Result<()> {
+        log::debug!(
+            "Netlink::add_address({:?}, {}/{})",
+            ifname.as_ref(),
+            addr,
+            prefix
+        );
+        let req = self.address_request(
+            rtnl::RTM_NEWADDR,
+            rtnl::NLM_F_CREATE | rtnl::NLM_F_REPLACE,
+            ifname.as_ref(),
+            addr,
+            prefix,
+        )?;
+        self.0.request("add address", req)
+    }
+
+    /// Remove an address added by `add_address()`
+    pub fn del_address<S: AsRef<str>>(
+        &mut self,
+        ifname: S,
+        addr: IpAddr,
+        prefix: u8,
+    ) -> Result<()> {
+        log::debug!(
+            "Netlink::del_address({:?}, {}/{})",
+            ifname.as_ref(),
+            addr,
+            prefix
+        );
+        let req = self.address_request(rtnl::RTM_DELADDR, 0, ifname.as_ref(), addr, prefix)?;
+        self.0.request("delete address", req)
+    }
+
+    /// Add an IPv4 default route through `gateway`, which must be reachable from `ifname`
+    pub fn add_default_route<S: AsRef<str>>(&mut self, ifname: S, gateway: Ipv4Addr) -> Result<()> {
+        self.add_route(ifname, &Route::default_via(gateway.into()))
+    }
+
+    fn route_request(
+        &mut self,
+        mtype: u16,
+        flags: u16,
+        ifname: &str,
+        route: &Route,
+    ) -> Result<rtnl::Request> {
+        let index = self.ifindex(ifname)?;
+        let family = match route.dst {
+            IpAddr::V4(_) => libc::AF_INET,
+            IpAddr::V6(_) => libc::AF_INET6,
+        };
+        let (protocol, scope) = match (mtype, route.gateway) {
+            (rtnl::RTM_DELROUTE, _) => (0, rtnl::RT_SCOPE_NOWHERE),
+            (_, Some(_)) => (rtnl::RTPROT_BOOT, rtnl::RT_SCOPE_UNIVERSE),
+            // on-link
+            (_, None) => (rtnl::RTPROT_BOOT, rtnl::RT_SCOPE_LINK),
+        };
+        let mut req = rtnl::Request::new(mtype, flags);
+        req.rtmsg(family as u8, route.prefix, protocol, scope);
+        if route.prefix > 0 {
+            req.attr(rtnl::RTA_DST, &ip_octets(route.dst));
+        }
+        if let Some(gateway) = route.gateway {
+            req.attr(rtnl::RTA_GATEWAY, &ip_octets(gateway));
+        }
+        req.u32(rtnl::RTA_OIF, index);
+        Ok(req)
+    }
+
+    /// Add a route through `ifname`.  Fails if an equivalent route exists.
+    pub fn add_route<S: AsRef<str>>(&mut self, ifname: S, route: &Route) -> Result<()> {
+        log::debug!("Netlink::add_route({:?}, {})", ifname.as_ref(), route);
+        let req = self.route_request(
+            rtnl::RTM_NEWROUTE,
+            rtnl::NLM_F_CREATE | rtnl::NLM_F_EXCL,
+            ifname.as_ref(),
+            route,
+        )?;
+        self.0.request("add route", req)
+    }
+
+    /// Remove a route added by `add_route()`, or by the kernel.  eg. of an address.
+    pub fn del_route<S: AsRef<str>>(&mut self, ifname: S, route: &Route) -> Result<()> {
+        log::debug!("Netlink::del_route({:?}, {})", ifname.as_ref(), route);
+        let req = self.route_request(rtnl::RTM_DELROUTE, 0, ifname.as_ref(), route)?;
+        self.0.request("delete route", req)
+    }
+
+    /// IPv4 and IPv6 unicast routes of the main table, as (interface index, route)
+    pub fn routes(&mut self) -> Result<Vec<(u32, Route)>> {
+        let mut req = rtnl::Request::dump(rtnl::RTM_GETROUTE);
+        req.rtmsg(libc::AF_UNSPEC as u8, 0, 0, rtnl::RT_SCOPE_UNIVERSE);
+        let mut ret = vec![];
+        for msg in self.0.fetch("list routes", req)? {
+            let field = |offset: usize| msg.body.get(offset).copied().unwrap_or(0);
+            let family = field(RTM_FAMILY) as libc::c_int;
+            let mut table = field(RTM_TABLE) as u32;
+            let mut route = Route {
+                dst: match family {
+                    libc::AF_INET => Ipv4Addr::UNSPECIFIED.into(),
+                    libc::AF_INET6 => Ipv6Addr::UNSPECIFIED.into(),
+                    _ => continue,
+                },
+                prefix: field(RTM_DST_LEN),
+                gateway: None,
+            };
+            let mut index = 0;
+            for (atype, data) in msg.attrs(RTMSG_LEN) {
+                match atype {
+                    rtnl::RTA_DST => route.dst = ip_from(data).unwrap_or(route.dst),
+                    rtnl::RTA_GATEWAY => route.gateway = ip_from(data),
+                    rtnl::RTA_OIF if data.len() == 4 => {
+                        index = u32::from_ne_bytes(data.try_into().unwrap())
+                    }
+                    // table IDs above 255
+                    rtnl::RTA_TABLE if data.len() == 4 => {
+                        table = u32::from_ne_bytes(data.try_into().unwrap())
+                    }
+                    _ => (),
+                }
+            }
+            if table == rtnl::RT_TABLE_MAIN as u32 && field(RTM_TYPE) == rtnl::RTN_UNICAST {
+                ret.push((index, route));
+            }
+        }
+        Ok(ret)
+    }
+}
+
+fn ip_octets(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+fn ip_from(data: &[u8]) -> Option<IpAddr> {
+    match data.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(data).unwrap())),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(data).unwrap())),
+        _ => None,
+    }
+}
+
+/// A route of the main table.  eg. the default route through a gateway on a veth or tap
+/// interface, or a route to a subnet reached directly on an interface.  cf. `Netlink::add_route()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    /// Destination.  The unspecified address (eg. `0.0.0.0`) for a default route.
+    pub dst: IpAddr,
+    /// Prefix length of `dst`.  Zero for a default route.
+    pub prefix: u8,
+    /// Next hop.  `None` for an on-link route.
+    pub gateway: Option<IpAddr>,
+}
+
+impl Route {
+    /// Default route through `gateway`.  IPv4 or IPv6, as is `gateway`.
+    pub fn default_via(gateway: IpAddr) -> Route {
+        let dst = match gateway {
+            IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        Route {
+            dst,
+            prefix: 0,
+            gateway: Some(gateway),
+        }
+    }
+
+    /// Route to the subnet `dst/prefix`, directly reachable on the interface.
+    /// eg. a subnet which is not that of an address of the interface.
+    pub fn subnet(dst: IpAddr, prefix: u8) -> Route {
+        Route {
+            dst,
+            prefix,
+            gateway: None,
+        }
+    }
+
+    /// This route through `gateway` instead.  eg. `Route::subnet(dst, 16).via(gw)`
+    pub fn via(self, gateway: IpAddr) -> Route {
+        Route {
+            gateway: Some(gateway),
+            ..self
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.prefix == 0
+    }
+}
+
+/// As `ip route`.  eg. `default via 10.0.2.2` or `10.1.0.0/16`
+impl std::fmt::Display for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_default() {
+            write!(f, "default")?;
+        } else {
+            write!(f, "{}/{}", self.dst, self.prefix)?;
+        }
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {}", gateway)?;
         }
         Ok(())
     }
@@ -238,6 +837,204 @@ impl TunTap {
     }
 }
 
+/// A veth pair.  Both ends are removed with either one.  eg. when the network namespace
+/// holding one end is destroyed.
+#[derive(Debug)]
+pub struct Veth {
+    name: String,
+    peer: String,
+}
+
+impl Veth {
+    /// Create a veth pair in the current network namespace
+    pub fn create<A: AsRef<str>, B: AsRef<str>>(name: A, peer: B) -> Result<Self> {
+        let (name, peer) = (name.as_ref().to_string(), peer.as_ref().to_string());
+        log::debug!("Veth::create({:?}, {:?})", name, peer);
+        // check lengths
+        IfReq::from_name(&name)?;
+        IfReq::from_name(&peer)?;
+
+        let mut req = rtnl::Request::new(rtnl::RTM_NEWLINK, rtnl::NLM_F_CREATE | rtnl::NLM_F_EXCL);
+        req.ifinfomsg(0)
+            .str(rtnl::IFLA_IFNAME, &name)
+            .nested(rtnl::IFLA_LINKINFO, |r| {
+                r.str(rtnl::IFLA_INFO_KIND, "veth")
+                    .nested(rtnl::IFLA_INFO_DATA, |r| {
+                        r.nested(rtnl::VETH_INFO_PEER, |r| {
+                            r.ifinfomsg(0).str(rtnl::IFLA_IFNAME, &peer);
+                        });
+                    });
+            });
+        Rtnl::new()?.request("create veth", req)?;
+        Ok(Self { name, peer })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// Move the peer end into network namespace `netns`.  eg. an open `/proc/<pid>/ns/net`
+    /// Renamed there to `rename`, if given.
+    pub fn move_peer<F: AsFd>(&mut self, netns: F, rename: Option<&str>) -> Result<()> {
+        log::debug!("Veth::move_peer({:?}, {:?})", self.peer, rename);
+        let fd = netns.as_fd().as_raw_fd() as u32;
+        self.move_peer_by(rtnl::IFLA_NET_NS_FD, fd, rename)
+    }
+
+    /// Move the peer end into the network namespace of process `pid`.  cf. `move_peer()`
+    pub fn move_peer_to_pid(&mut self, pid: libc::pid_t, rename: Option<&str>) -> Result<()> {
+        log::debug!(
+            "Veth::move_peer_to_pid({:?}, {}, {:?})",
+            self.peer,
+            pid,
+            rename
+        );
+        self.move_peer_by(rtnl::IFLA_NET_NS_PID, pid as u32, rename)
+    }
+
+    fn move_peer_by(&mut self, attr: u16, target: u32, rename: Option<&str>) -> Result<()> {
+        let index = IfConfig::new()?.ifindex(&self.peer)?;
+        let mut req = rtnl::Request::new(rtnl::RTM_SETLINK, 0);
+        req.ifinfomsg(index).u32(attr, target);
+        if let Some(name) = rename {
+            IfReq::from_name(name)?;
+            req.str(rtnl::IFLA_IFNAME, name);
+        }
+        Rtnl::new()?.request("move veth", req)?;
+        if let Some(name) = rename {
+            self.peer = name.to_string();
+        }
+        Ok(())
+    }
+
+    /// Remove both ends.  The peer need not be in the current network namespace.
+    pub fn delete(self) -> Result<()> {
+        log::debug!("Veth::delete({:?})", self.name);
+        delete_link(&self.name)
+    }
+}
+
+/// Remove interface `ifname`.  With a veth, also removes the peer.
+pub fn delete_link(ifname: &str) -> Result<()> {
+    Netlink::new()?.delete_link(ifname)
+}
+
+/// A network interface, as listed by `interfaces()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    pub index: u32,
+    /// eg. `IFF_UP`
+    pub flags: u32,
+    /// IPv4 and IPv6 addresses
+    pub addrs: Vec<IpAddr>,
+    /// Link layer address.  eg. ethernet MAC.  Empty if none.
+    pub hwaddr: Vec<u8>,
+}
+
+/// Link layer address from an `AF_PACKET` `sockaddr`
+unsafe fn sockaddr_hw(sa: *const libc::sockaddr) -> Option<Vec<u8>> {
+    if sa.is_null() || (*sa).sa_family as libc::c_int != libc::AF_PACKET {
+        return None;
+    }
+    let sll = &*(sa as *const libc::sockaddr_ll);
+    let len = (sll.sll_halen as usize).min(sll.sll_addr.len());
+    Some(sll.sll_addr[..len].to_vec())
+}
+
+/// IP address from a `sockaddr`, if it is one
+unsafe fn sockaddr_ip(sa: *const libc::sockaddr) -> Option<IpAddr> {
+    if sa.is_null() {
+        return None;
+    }
+    match (*sa).sa_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = &*(sa as *const libc::sockaddr_in);
+            Some(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)).into())
+        }
+        libc::AF_INET6 => {
+            let sin6 = &*(sa as *const libc::sockaddr_in6);
+            Some(Ipv6Addr::from(sin6.sin6_addr.s6_addr).into())
+        }
+        _ => None,
+    }
+}
+
+/// List the network interfaces of the current network namespace, ordered by index
+pub fn interfaces() -> Result<Vec<Interface>> {
+    let mut head = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut head) } != 0 {
+        return Err(Error::last_os_error("getifaddrs"));
+    }
+    let mut ret: Vec<Interface> = vec![];
+    let mut cur = head;
+    // one entry per address.  Also one AF_PACKET entry for each interface
+    while !cur.is_null() {
+        let ifa = unsafe { &*cur };
+        cur = ifa.ifa_next;
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) }
+            .to_string_lossy()
+            .into_owned();
+        let addr = unsafe { sockaddr_ip(ifa.ifa_addr) };
+        let hwaddr = unsafe { sockaddr_hw(ifa.ifa_addr) };
+        let iface = match ret.iter().position(|iface| iface.name == name) {
+            Some(idx) => &mut ret[idx],
+            None => {
+                ret.push(Interface {
+                    name,
+                    index: unsafe { libc::if_nametoindex(ifa.ifa_name) },
+                    flags: ifa.ifa_flags,
+                    addrs: vec![],
+                    hwaddr: vec![],
+                });
+                ret.last_mut().unwrap()
+            }
+        };
+        iface.addrs.extend(addr);
+        if let Some(hwaddr) = hwaddr {
+            iface.hwaddr = hwaddr;
+        }
+    }
+    unsafe { libc::freeifaddrs(head) };
+    ret.sort_by_key(|iface| iface.index);
+    Ok(ret)
+}
+
+/// Add an IPv4 address, with a prefix length.  eg. 24 for 255.255.255.0
+pub fn add_address<S: AsRef<str>>(ifname: S, addr: Ipv4Addr, prefix: u8) -> Result<()> {
+    Netlink::new()?.add_address(ifname, addr.into(), prefix)
+}
+
+/// Add an IPv4 default route through `gateway`, which must be reachable from `ifname`
+pub fn add_default_route<S: AsRef<str>>(ifname: S, gateway: Ipv4Addr) -> Result<()> {
+    Netlink::new()?.add_default_route(ifname, gateway)
+}
+
+/// Add a route through `ifname`.  eg. `Route::default_via(gateway)`
+pub fn add_route<S: AsRef<str>>(ifname: S, route: &Route) -> Result<()> {
+    Netlink::new()?.add_route(ifname, route)
+}
+
+/// Remove a route through `ifname`
+pub fn del_route<S: AsRef<str>>(ifname: S, route: &Route) -> Result<()> {
+    Netlink::new()?.del_route(ifname, route)
+}
+
+/// The first interface name `<prefix><N>` not already in use.  eg. "br0"
+pub fn unused_name(prefix: &str) -> Result<String> {
+    let existing = interfaces()?;
+    let name = (0..)
+        .map(|n| format!("{}{}", prefix, n))
+        .find(|name| !existing.iter().any(|iface| &iface.name == name))
+        .unwrap();
+    IfReq::from_name(&name)?;
+    Ok(name)
+}
+
 /// Bring the "lo" interface UP with 127.0.0.1
 pub fn configure_lo() -> Result<()> {
     log::debug!("Setup loopback interface");
@@ -247,15 +1044,188 @@ pub fn configure_lo() -> Result<()> {
     log::debug!("Set lo address");
     conf.set_address(LOOPBACK, Ipv4Addr::LOCALHOST)?;
 
-    let flags = conf.ifflags(LOOPBACK)?;
+    set_up(LOOPBACK)?;
+
+    // normally added by the kernel as lo comes up.  Not when IPv6 is disabled.
+    if conf.has_ipv6() {
+        let added = conf.address6(LOOPBACK).and_then(|addrs| {
+            if addrs.contains(&(Ipv6Addr::LOCALHOST, 128)) {
+                Ok(())
+            } else {
+                conf.set_address6(LOOPBACK, Ipv6Addr::LOCALHOST, 128)
+            }
+        });
+        if let Err(err) = added {
+            log::debug!("No ::1 : {err}");
+        }
+    }
+    Ok(())
+}
+
+/// Bring an interface UP, if it is not already
+pub fn set_up<S: AsRef<str>>(ifname: S) -> Result<()> {
+    let conf = IfConfig::new()?;
+    let flags = conf.ifflags(ifname.as_ref())?;
     if 0 == (flags & ext::IFF_UP) {
-        log::debug!("Bring lo UP");
-        conf.set_ifflags(LOOPBACK, ext::IFF_UP | flags)?;
+        log::debug!("Bring {} UP", ifname.as_ref());
+        conf.set_ifflags(ifname, ext::IFF_UP | flags)?;
+    }
+    Ok(())
+}
+
+/// Upper bound of `net.ipv4.ping_group_range`
+const PING_GID_MAX: u64 = i32::MAX as u64;
+
+/// The range of GIDs mapped by the contents of a `/proc/<pid>/gid_map`,
+/// as limited to `net.ipv4.ping_group_range`
+fn ping_range(gid_map: &str) -> Option<(u64, u64)> {
+    gid_map
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace().map(|p| p.parse::<u64>().ok());
+            match (parts.next()?, parts.next()?, parts.next()?) {
+                (Some(inside), Some(_), Some(count)) if count > 0 && inside <= PING_GID_MAX => {
+                    Some((inside, (inside + count - 1).min(PING_GID_MAX)))
+                }
+                _ => None,
+            }
+        })
+        .reduce(|(alow, ahigh), (blow, bhigh)| (alow.min(blow), ahigh.max(bhigh)))
+}
+
+/// Allow ICMP echo sockets (eg. `ping`) without `CAP_NET_RAW`, for all groups mapped
+/// into the current user namespace.  Only in a new network namespace,
+/// as included in `unshared`.  eg. `StageCtx::namespaces()`
+pub fn allow_ping(unshared: libc::c_int) -> Result<()> {
+    let gid_map = std::fs::read_to_string("/proc/self/gid_map")
+        .map_err(|e| Error::file("read", "/proc/self/gid_map", e))?;
+    match ping_range(&gid_map) {
+        Some((low, high)) => util::sysctl_write(
+            "net.ipv4.ping_group_range",
+            format!("{} {}", low, high),
+            unshared,
+        ),
+        None => {
+            log::debug!("No GIDs to allow ping");
+            Ok(())
+        }
+    }
+}
+
+/// Disable IPv6 on interface `ifname` of a new network namespace, as included in `unshared`.
+/// Or `"default"` for interfaces created later.  So no link-local address is assigned,
+/// and no router, or neighbor, solicitation is sent.  Nothing to do if IPv6 is disabled
+/// on the host.
+pub fn disable_ipv6(ifname: &str, unshared: libc::c_int) -> Result<()> {
+    if !Path::new("/proc/sys/net/ipv6").exists() {
+        return Ok(());
     }
+    util::sysctl_write(
+        &format!("net.ipv6.conf.{}.disable_ipv6", ifname),
+        "1",
+        unshared,
+    )
+}
 
+/// Copy between two connections, in both directions, until both directions are closed
+pub(crate) fn splice(a: TcpStream, b: TcpStream) -> io::Result<()> {
+    let copy = |mut from: TcpStream, mut to: TcpStream| {
+        let _ = io::copy(&mut from, &mut to);
+        // pass on the end of stream
+        let _ = to.shutdown(Shutdown::Write);
+    };
+    let (a2, b2) = (a.try_clone()?, b.try_clone()?);
+    let up = thread::spawn(move || copy(a2, b2));
+    copy(b, a);
+    let _ = up.join();
     Ok(())
 }
 
+/// Forwards connections to a host TCP port, into the network namespace of a sandbox.
+/// eg. to test a server running in the sandbox.
+#[derive(Debug)]
+pub struct PortForward {
+    listener: TcpListener,
+    guest: u16,
+}
+
+impl PortForward {
+    /// Listen on `host`.  Connections are forwarded to port `guest`,
+    /// on the loopback interface of the sandbox.
+    pub fn bind(host: SocketAddr, guest: u16) -> Result<PortForward> {
+        let listener =
+            TcpListener::bind(host).map_err(|e| Error::os(format!("bind() {}", host), e))?;
+        Ok(PortForward { listener, guest })
+    }
+
+    /// Parse `[ADDR:]HOST:GUEST`.  eg. "8080:80".  The host address defaults to 127.0.0.1
+    pub fn parse_spec(spec: &str) -> Result<(SocketAddr, u16)> {
+        let invalid = || {
+            Error::os(
+                format!("Expected [ADDR:]HOST:GUEST, not {:?}", spec),
+                io::ErrorKind::InvalidInput.into(),
+            )
+        };
+        let (host, guest) = spec.rsplit_once(':').ok_or_else(invalid)?;
+        let guest = guest.parse::<u16>().map_err(|_| invalid())?;
+        let host = match host.parse::<u16>() {
+            Ok(port) => SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            Err(_) => host.parse().map_err(|_| invalid())?,
+        };
+        Ok((host, guest))
+    }
+
+    /// Where connections are accepted
+    pub fn host(&self) -> Result<SocketAddr> {
+        self.listener
+            .local_addr()
+            .map_err(|e| Error::os("forwarded port", e))
+    }
+
+    pub fn guest(&self) -> u16 {
+        self.guest
+    }
+
+    /// Forward from a new process, which joins the network namespace of process `pid`.
+    /// Joining first its user namespace, if `userns`.  Otherwise needs `CAP_SYS_ADMIN`,
+    /// and privilege is dropped to the real UID once joined.
+    /// The process is killed with the caller, or when the returned `Proc` is dropped.
+    pub fn spawn(&self, pid: libc::pid_t, userns: bool) -> Result<proc::Proc> {
+        let open = |ns: &str| {
+            let path = format!("/proc/{}/ns/{}", pid, ns);
+            File::open(&path).map_err(|e| Error::file("open", &path, e))
+        };
+        let netns = open("net")?;
+        let userns = if userns { Some(open("user")?) } else { None };
+        let guest = self.guest;
+        log::debug!("Forward {:?} to {} of PID {}", self.listener, guest, pid);
+        proc::fork(|| -> Result<()> {
+            proc::set_pdeathsig(libc::SIGKILL)?;
+            if let Some(userns) = &userns {
+                util::setns(userns, libc::CLONE_NEWUSER)?;
+            }
+            util::setns(&netns, libc::CLONE_NEWNET)?;
+            if userns.is_none() {
+                util::setgid(util::getgid())?;
+                util::setuid(util::getuid())?;
+            }
+            loop {
+                let (outer, peer) = self
+                    .listener
+                    .accept()
+                    .map_err(|e| Error::os("accept() forwarded port", e))?;
+                thread::spawn(move || {
+                    let ret = TcpStream::connect((Ipv4Addr::LOCALHOST, guest))
+                        .and_then(|inner| splice(outer, inner));
+                    if let Err(err) = ret {
+                        log::debug!("Forward from {} fails : {}", peer, err);
+                    }
+                });
+            }
+        })
+    }
+}
+
 /// A "dummy" software ethernet bridge
 #[allow(dead_code)]
 pub struct Bridge(proc::Proc);
@@ -266,19 +1236,21 @@ pub fn dummy_bridge() -> Result<Bridge> {
 
     let conf = IfConfig::new()?;
 
-    conf.bridge_create("br0")?;
+    let br = format!("br-{}", id::current());
+    conf.bridge_create(&br)?;
 
-    let tun = TunTap::new("tap0")?;
+    let tun = TunTap::new(format!("tap-{}", id::current()))?;
 
-    conf.bridge_add("br0", tun.name())?;
+    conf.bridge_add(&br, tun.name())?;
 
-    let brf = conf.ifflags("br0")?;
-    conf.set_address("br0", Ipv4Addr::new(192, 168, 1, 1))?;
-    conf.set_ifflags("br0", brf | ext::IFF_UP)?;
+    let brf = conf.ifflags(&br)?;
+    conf.set_address(&br, Ipv4Addr::new(192, 168, 1, 1))?;
+    conf.set_netmask(&br, Ipv4Addr::new(255, 255, 255, 0))?;
+    conf.set_ifflags(&br, brf | ext::IFF_UP)?;
 
+    // link-local IPv6 addresses are assigned once up.  cf. disable_ipv6()
     let brf = conf.ifflags(tun.name())?;
     conf.set_ifflags(tun.name(), brf | ext::IFF_UP)?;
-    // TODO: why does tap0 have an ipv6 address?
 
     Ok(Bridge(tun.handle_ignore()?))
 }
@@ -286,6 +1258,32 @@ pub fn dummy_bridge() -> Result<Bridge> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn lo_ipv6() {
+        if !IfConfig::new().unwrap().has_ipv6() {
+            return;
+        }
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = proc::fork(|| -> crate::container::Result<()> {
+            util::unshare(libc::CLONE_NEWNET)?;
+            configure_lo()?;
+            let conf = IfConfig::new()?;
+            let doc: Ipv6Addr = "fd00::1".parse().unwrap();
+            conf.set_address6(LOOPBACK, doc, 64)?;
+            let addrs = conf.address6(LOOPBACK)?;
+            if !addrs.contains(&(Ipv6Addr::LOCALHOST, 128)) || !addrs.contains(&(doc, 64)) {
+                return Err(format!("unexpected IPv6 addresses {:?}", addrs).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
 
     #[test]
     fn lo_flags() {
@@ -301,11 +1299,405 @@ mod tests {
         assert_eq!(addr, net::Ipv4Addr::LOCALHOST);
     }
 
+    #[test]
+    fn lo_netmask() {
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = proc::fork(|| -> crate::container::Result<()> {
+            util::unshare(libc::CLONE_NEWNET)?;
+            let conf = IfConfig::new()?;
+            conf.set_address(LOOPBACK, Ipv4Addr::new(127, 0, 0, 1))?;
+            let class = conf.netmask(LOOPBACK)?;
+            conf.set_netmask(LOOPBACK, Ipv4Addr::new(255, 255, 255, 0))?;
+            let mask = conf.netmask(LOOPBACK)?;
+            let addr = conf.address(LOOPBACK)?;
+            if class != Ipv4Addr::new(255, 0, 0, 0)
+                || mask != Ipv4Addr::new(255, 255, 255, 0)
+                || addr != Ipv4Addr::new(127, 0, 0, 1)
+            {
+                return Err(format!("unexpected netmask {} then {}/{}", class, addr, mask).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn lo_mtu() {
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = proc::fork(|| -> crate::container::Result<()> {
+            util::unshare(libc::CLONE_NEWNET)?;
+            let conf = IfConfig::new()?;
+            let before = conf.ifmtu(LOOPBACK)?;
+            conf.set_ifmtu(LOOPBACK, 9000)?;
+            let jumbo = conf.ifmtu(LOOPBACK)?;
+            conf.set_ifmtu(LOOPBACK, 1280)?;
+            let after = conf.ifmtu(LOOPBACK)?;
+            if (before, jumbo, after) != (65536, 9000, 1280) {
+                return Err(format!("unexpected MTU {} {} {}", before, jumbo, after).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn bridge_hwaddr() {
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = proc::fork(|| -> crate::container::Result<()> {
+            util::unshare(libc::CLONE_NEWNET)?;
+            let conf = IfConfig::new()?;
+            conf.bridge_create("br-test")?;
+            let mac = [2, 0, 0, 0x12, 0x34, 0x56];
+            conf.set_hwaddr("br-test", mac)?;
+            let (bridge, lo) = (conf.hwaddr("br-test")?, conf.hwaddr(LOOPBACK)?);
+            if bridge != mac || lo != [0; 6] {
+                return Err(format!("unexpected hwaddr {:x?} {:x?}", bridge, lo).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn ping() {
+        assert_eq!(ping_range("0 0 4294967295\n"), Some((0, PING_GID_MAX)));
+        assert_eq!(ping_range("1000 1000 1\n"), Some((1000, 1000)));
+        assert_eq!(ping_range("0 1000 1\n1 100000 65536\n"), Some((0, 65536)));
+        assert_eq!(ping_range(""), None);
+
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = proc::fork(|| -> crate::container::Result<()> {
+            util::unshare(libc::CLONE_NEWNET)?;
+            allow_ping(libc::CLONE_NEWNET)?;
+            let range = util::sysctl_read("net.ipv4.ping_group_range")?;
+            if range != format!("0\t{}", PING_GID_MAX) {
+                return Err(format!("unexpected ping_group_range {:?}", range).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn no_ipv6() {
+        if !Path::new("/proc/sys/net/ipv6").exists() {
+            return;
+        }
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = proc::fork(|| -> crate::container::Result<()> {
+            util::unshare(libc::CLONE_NEWNET)?;
+            disable_ipv6("default", libc::CLONE_NEWNET)?;
+            let conf = IfConfig::new()?;
+            conf.bridge_create("br-test")?;
+            let off = util::sysctl_read("net.ipv6.conf.br-test.disable_ipv6")?;
+            if off != "1" {
+                return Err(format!("unexpected disable_ipv6 {:?}", off).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+        assert!(disable_ipv6(LOOPBACK, 0).is_err());
+    }
+
     #[test]
     fn lo_index() {
+        let all = interfaces().unwrap();
+        let lo = all.iter().find(|iface| iface.name == LOOPBACK).unwrap();
+        assert!((lo.flags & ext::IFF_LOOPBACK) != 0, "flags {}", lo.flags);
+        assert!(lo.addrs.contains(&Ipv4Addr::LOCALHOST.into()), "{:?}", lo);
+
         let conf = IfConfig::new().unwrap();
-        let idx = conf.ifindex(LOOPBACK).expect("address");
-        // TODO: is this actually certain?
-        assert_eq!(idx, 1);
+        assert_eq!(conf.ifindex(LOOPBACK).unwrap(), lo.index);
+    }
+
+    #[test]
+    fn other_netns() {
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let (mut parent, mut child) = UnixStream::pair().unwrap();
+        let mut pid = proc::fork(move || -> crate::container::Result<()> {
+            util::unshare(libc::CLONE_NEWNET)?;
+            child.write_all(b"!").map_err(|e| Error::os("sync", e))?;
+            // wait for parent to configure
+            child
+                .read_exact(&mut [0])
+                .map_err(|e| Error::os("sync", e))?;
+            let flags = IfConfig::new()?.ifflags(LOOPBACK)?;
+            if (flags & ext::IFF_UP) == 0 {
+                return Err(format!("{} not up, flags {:#x}", LOOPBACK, flags).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        parent.read_exact(&mut [0]).unwrap();
+
+        let conf = IfConfig::for_pid(pid.id()).unwrap();
+        let flags = conf.ifflags(LOOPBACK).unwrap();
+        assert_eq!(flags & ext::IFF_UP, 0);
+        conf.set_ifflags(LOOPBACK, flags | ext::IFF_UP).unwrap();
+        parent.write_all(b"!").unwrap();
+
+        assert_eq!(pid.park().unwrap(), 0);
+        // not in our namespace
+        assert_ne!(
+            IfConfig::new().unwrap().ifflags(LOOPBACK).unwrap() & ext::IFF_UP,
+            0
+        );
+    }
+
+    #[test]
+    fn forward() {
+        let spec = |s: &str| PortForward::parse_spec(s).map_err(|e| e.to_string());
+        assert_eq!(spec("8080:80"), Ok(("127.0.0.1:8080".parse().unwrap(), 80)));
+        assert_eq!(
+            spec("0.0.0.0:80:8000"),
+            Ok(("0.0.0.0:80".parse().unwrap(), 8000))
+        );
+        assert_eq!(spec("[::1]:80:80"), Ok(("[::1]:80".parse().unwrap(), 80)));
+        spec("80").unwrap_err();
+        spec("80:http").unwrap_err();
+
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let (mut parent, mut child) = UnixStream::pair().unwrap();
+        let mut pid = proc::fork::<_, Error>(move || {
+            util::unshare(libc::CLONE_NEWNET)?;
+            configure_lo()?;
+            // the same port as the host side, which is not in this namespace
+            let mut port = [0; 2];
+            child
+                .read_exact(&mut port)
+                .map_err(|e| Error::os("sync", e))?;
+            let server = TcpListener::bind((Ipv4Addr::LOCALHOST, u16::from_ne_bytes(port)))
+                .map_err(|e| Error::os("bind", e))?;
+            child.write_all(b"!").map_err(|e| Error::os("sync", e))?;
+            let (mut conn, _) = server.accept().map_err(|e| Error::os("accept", e))?;
+            conn.write_all(b"hello")
+                .map_err(|e| Error::os("write", e))?;
+            Ok(())
+        })
+        .unwrap();
+
+        let fwd = PortForward::bind("127.0.0.1:0".parse().unwrap(), 0).unwrap();
+        let port = fwd.host().unwrap().port();
+        let fwd = PortForward { guest: port, ..fwd };
+        parent.write_all(&port.to_ne_bytes()).unwrap();
+        parent.read_exact(&mut [0]).unwrap();
+        let _helper = fwd.spawn(pid.id(), false).unwrap();
+
+        let mut conn = TcpStream::connect(fwd.host().unwrap()).unwrap();
+        let mut msg = String::new();
+        conn.read_to_string(&mut msg).unwrap();
+        assert_eq!(msg, "hello");
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn veth() {
+        let has = |name: &str| -> Result<bool> {
+            Ok(interfaces()?.iter().any(|iface| iface.name == name))
+        };
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = proc::fork::<_, Error>(|| {
+            util::unshare(libc::CLONE_NEWNET)?;
+            let mut veth = Veth::create("vtest0", "vtest1")?;
+
+            // another namespace, held by a child
+            let (rx, tx) = util::socketpair()?;
+            let other = proc::fork::<_, Error>(|| {
+                util::unshare(libc::CLONE_NEWNET)?;
+                drop(tx);
+                std::thread::sleep(std::time::Duration::from_secs(10));
+                Ok(())
+            })?;
+            // EOF after unshare()
+            let _ = (&rx).read(&mut [0]);
+            let netns = File::open(format!("/proc/{}/ns/net", other.id()))
+                .map_err(|e| Error::os("open", e))?;
+
+            veth.move_peer(&netns, Some("eth0"))?;
+            assert!(has("vtest0")? && !has("vtest1")?);
+            IfConfig::in_netns(&netns)?.ifindex("eth0")?;
+
+            veth.delete()?;
+            assert!(!has("vtest0")?);
+            other.kill()
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn veth_pid() {
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = proc::fork(|| -> crate::container::Result<()> {
+            util::unshare(libc::CLONE_NEWNET)?;
+            let mut veth = Veth::create("vtest2", "vtest3")?;
+
+            let (ready, unshared) = util::socketpair()?;
+            let (mut moved, wait) = util::socketpair()?;
+            let mut other = proc::fork(|| -> crate::container::Result<()> {
+                util::unshare(libc::CLONE_NEWNET)?;
+                drop(unshared);
+                (&wait)
+                    .read_exact(&mut [0])
+                    .map_err(|e| Error::os("sync", e))?;
+                IfConfig::new()?.ifindex("eth1")?;
+                Ok(())
+            })?;
+            // EOF after unshare()
+            let _ = (&ready).read(&mut [0]);
+
+            veth.move_peer_to_pid(other.id(), Some("eth1"))?;
+            let peer = veth.peer().to_string();
+            let left = IfConfig::new()?.ifindex("vtest3").is_ok();
+            moved.write_all(b"!").map_err(|e| Error::os("sync", e))?;
+            let code = other.park()?;
+            if peer != "eth1" || left || code != 0 {
+                return Err(format!("unexpected peer {} left={} exit {}", peer, left, code).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn netlink() {
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = proc::fork(|| -> crate::container::Result<()> {
+            util::unshare(libc::CLONE_NEWNET)?;
+            let mut nl = Netlink::new()?;
+            nl.create_link("br-nl", "bridge")?;
+            nl.set_up("br-nl")?;
+            let index = nl.ifindex("br-nl")?;
+            let same = index == IfConfig::new()?.ifindex("br-nl")?;
+
+            // more than one address, which SIOCSIFADDR can not
+            let a: IpAddr = Ipv4Addr::new(10, 1, 2, 3).into();
+            let b: IpAddr = Ipv4Addr::new(10, 9, 0, 1).into();
+            nl.add_address("br-nl", a, 24)?;
+            nl.add_address("br-nl", b, 16)?;
+            let addrs = nl.addresses()?;
+            let listed = nl.interfaces()?;
+            let br = listed.iter().find(|iface| iface.name == "br-nl");
+
+            nl.del_address("br-nl", a, 24)?;
+            let removed = !nl.addresses()?.iter().any(|(_, addr, _)| *addr == a);
+            nl.delete_link("br-nl")?;
+
+            if !same {
+                return Err(format!("ifindex {} differs from SIOCGIFINDEX", index).into());
+            }
+            if !addrs.contains(&(index, a, 24)) || !addrs.contains(&(index, b, 16)) {
+                return Err(format!("unexpected addresses {:?}", addrs).into());
+            }
+            if !matches!(br, Some(br) if br.index == index
+                && (br.flags & ext::IFF_UP) != 0
+                && br.hwaddr.len() == 6
+                && br.addrs.starts_with(&[a, b]))
+            {
+                return Err(format!("unexpected link {:?}", br).into());
+            }
+            let gone = nl.ifindex("br-nl").is_err();
+            if !removed || !gone {
+                return Err(format!("left address {} link {}", !removed, !gone).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn routes() {
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = proc::fork(|| -> crate::container::Result<()> {
+            util::unshare(libc::CLONE_NEWNET)?;
+            let mut nl = Netlink::new()?;
+            nl.create_link("br-rt", "bridge")?;
+            nl.set_up("br-rt")?;
+            let index = nl.ifindex("br-rt")?;
+            nl.add_address("br-rt", Ipv4Addr::new(10, 1, 2, 3).into(), 24)?;
+
+            let gw: IpAddr = Ipv4Addr::new(10, 1, 2, 1).into();
+            let default = Route::default_via(gw);
+            let via = Route::subnet(Ipv4Addr::new(10, 7, 0, 0).into(), 16).via(gw);
+            let onlink = Route::subnet(Ipv4Addr::new(10, 8, 0, 0).into(), 16);
+            for route in [&default, &via, &onlink] {
+                nl.add_route("br-rt", route)?;
+            }
+            let routes = nl.routes()?;
+            let again = nl.add_route("br-rt", &default).is_err();
+
+            nl.del_route("br-rt", &default)?;
+            let after = nl.routes()?;
+            nl.delete_link("br-rt")?;
+
+            // of the address
+            let local = Route::subnet(Ipv4Addr::new(10, 1, 2, 0).into(), 24);
+            if ![default, via, onlink, local]
+                .iter()
+                .all(|route| routes.contains(&(index, *route)))
+            {
+                return Err(format!("unexpected routes {:?}", routes).into());
+            }
+            if !again {
+                return Err(format!("added {} twice", default).into());
+            }
+            if after.iter().any(|(_, route)| route.is_default()) || !after.contains(&(index, via)) {
+                return Err(format!("unexpected routes after delete {:?}", after).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn route_display() {
+        let gw: IpAddr = Ipv4Addr::new(10, 0, 2, 2).into();
+        assert_eq!(Route::default_via(gw).to_string(), "default via 10.0.2.2");
+        let net = Route::subnet(Ipv4Addr::new(10, 1, 0, 0).into(), 16);
+        assert_eq!(net.to_string(), "10.1.0.0/16");
+        assert_eq!(net.via(gw).to_string(), "10.1.0.0/16 via 10.0.2.2");
+        let gw6: IpAddr = "fd00::1".parse().unwrap();
+        assert_eq!(
+            Route::default_via(gw6).dst,
+            IpAddr::from(Ipv6Addr::UNSPECIFIED)
+        );
+    }
+
+    #[test]
+    fn unused() {
+        let name = unused_name(LOOPBACK).unwrap();
+        assert!(name.starts_with(LOOPBACK));
+        assert!(!interfaces().unwrap().iter().any(|iface| iface.name == name));
+        unused_name("much_too_long_for_an_interface").unwrap_err();
     }
 }
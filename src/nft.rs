@@ -0,0 +1,492 @@
+//! nf_tables rules, through netlink.  (eg. like `/sbin/nft`)
+//!
+//! The rules of one sandbox are kept in a dedicated `inet` table, `sandbox-<id>`,
+//! with base chains added as needed.  Rules are only added.  The table is deleted
+//! with its `Table`, or by the kernel when the owning process exits (Linux >= 5.12).
+//! Otherwise `remove()` cleans up after a process which crashed.
+//! Except a table from `Table::create_detached()`, which is left in place.
+//!
+//! cf. linux/netfilter/nf_tables.h
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use log::{debug, warn};
+
+use super::err::{Errno, Error, Result};
+use super::rtnl::{self, Request, Rtnl, NLA_F_NESTED};
+
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFPROTO_INET: u8 = 1;
+const NFPROTO_IPV4: u8 = 2;
+const NLM_F_APPEND: u16 = 0x800;
+
+// message types
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+
+// table, chain, and rule attributes
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_TABLE_FLAGS: u16 = 2;
+/// Deleted when the netlink socket which created it is closed
+const NFT_TABLE_F_OWNER: u32 = 2;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+
+// expressions
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_DATA_VERDICT: u16 = 2;
+const NFTA_VERDICT_CODE: u16 = 1;
+
+const NFT_REG_VERDICT: u32 = 0;
+const NFT_REG_1: u32 = 1;
+const NFT_REG_2: u32 = 2;
+
+const NFT_META_IIFNAME: u32 = 6;
+const NFT_META_OIFNAME: u32 = 7;
+const NFT_META_NFPROTO: u32 = 15;
+const NFT_META_L4PROTO: u32 = 16;
+
+const NFT_CMP_EQ: u32 = 0;
+const NFT_CMP_NEQ: u32 = 1;
+
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_PAYLOAD_TRANSPORT_HEADER: u32 = 2;
+
+const NFT_NAT_DNAT: u32 = 1;
+
+const LOOPBACK: &str = "lo";
+
+const NF_DROP: u32 = 0;
+const NF_ACCEPT: u32 = 1;
+
+/// Interface names are compared as this many bytes, nil padded
+const IFNAMSIZ: usize = 16;
+
+/// Name of the table of sandbox `id`
+pub fn table_name(id: &str) -> String {
+    format!("sandbox-{}", id)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Proto {
+    Tcp,
+    Udp,
+}
+
+impl Proto {
+    fn number(self) -> u8 {
+        match self {
+            Proto::Tcp => libc::IPPROTO_TCP as u8,
+            Proto::Udp => libc::IPPROTO_UDP as u8,
+        }
+    }
+}
+
+/// IPv4 only
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rule {
+    /// Masquerade traffic from `iface` as it leaves through any other interface.
+    /// For the NAT mode.
+    Masquerade { iface: String },
+    /// Forward connections to `port` of the host, arriving from elsewhere, to `to`
+    Forward {
+        proto: Proto,
+        port: u16,
+        to: SocketAddrV4,
+    },
+    /// Drop traffic from `iface` to the subnet `net/prefix`.  An egress filter.
+    Deny {
+        iface: String,
+        net: Ipv4Addr,
+        prefix: u8,
+    },
+    /// Redirect locally originated traffic to `port` of the loopback interface.
+    /// Only to destination port `dport`, if given.  Traffic already on loopback is not.
+    /// The original destination is found with `SO_ORIGINAL_DST`.  For user-mode NAT.
+    Redirect {
+        proto: Proto,
+        dport: Option<u16>,
+        port: u16,
+    },
+}
+
+/// Base chains, created as needed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chain {
+    Prerouting,
+    Forward,
+    Output,
+    Postrouting,
+}
+
+impl Chain {
+    fn name(self) -> &'static str {
+        match self {
+            Chain::Prerouting => "prerouting",
+            Chain::Forward => "forward",
+            Chain::Output => "output",
+            Chain::Postrouting => "postrouting",
+        }
+    }
+
+    /// type, hook number, and priority
+    fn hook(self) -> (&'static str, u32, i32) {
+        match self {
+            // NF_INET_PRE_ROUTING, NF_IP_PRI_NAT_DST
+            Chain::Prerouting => ("nat", 0, -100),
+            // NF_INET_FORWARD, NF_IP_PRI_FILTER
+            Chain::Forward => ("filter", 2, 0),
+            // NF_INET_LOCAL_OUT, NF_IP_PRI_NAT_DST
+            Chain::Output => ("nat", 3, -100),
+            // NF_INET_POST_ROUTING, NF_IP_PRI_NAT_SRC
+            Chain::Postrouting => ("nat", 4, 100),
+        }
+    }
+}
+
+impl Rule {
+    fn chain(&self) -> Chain {
+        match self {
+            Rule::Masquerade { .. } => Chain::Postrouting,
+            Rule::Forward { .. } => Chain::Prerouting,
+            Rule::Deny { .. } => Chain::Forward,
+            Rule::Redirect { .. } => Chain::Output,
+        }
+    }
+
+    fn encode(&self, r: &mut Request) {
+        meta_cmp(r, NFT_META_NFPROTO, NFT_CMP_EQ, &[NFPROTO_IPV4]);
+        match self {
+            Rule::Masquerade { iface } => {
+                meta_cmp(r, NFT_META_IIFNAME, NFT_CMP_EQ, &ifname(iface));
+                meta_cmp(r, NFT_META_OIFNAME, NFT_CMP_NEQ, &ifname(iface));
+                expr(r, "masq", |_| ());
+            }
+            Rule::Forward { proto, port, to } => {
+                meta_cmp(r, NFT_META_L4PROTO, NFT_CMP_EQ, &[proto.number()]);
+                // destination port
+                payload(r, NFT_PAYLOAD_TRANSPORT_HEADER, 2, 2);
+                cmp(r, NFT_CMP_EQ, &port.to_be_bytes());
+                immediate(r, NFT_REG_1, &to.ip().octets());
+                immediate(r, NFT_REG_2, &to.port().to_be_bytes());
+                expr(r, "nat", |r| {
+                    r.be32(1, NFT_NAT_DNAT) // NFTA_NAT_TYPE
+                        .be32(2, NFPROTO_IPV4 as u32) // NFTA_NAT_FAMILY
+                        .be32(3, NFT_REG_1) // NFTA_NAT_REG_ADDR_MIN
+                        .be32(5, NFT_REG_2); // NFTA_NAT_REG_PROTO_MIN
+                });
+            }
+            Rule::Deny { iface, net, prefix } => {
+                meta_cmp(r, NFT_META_IIFNAME, NFT_CMP_EQ, &ifname(iface));
+                // destination address
+                payload(r, NFT_PAYLOAD_NETWORK_HEADER, 16, 4);
+                let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                expr(r, "bitwise", |r| {
+                    r.be32(1, NFT_REG_1) // NFTA_BITWISE_SREG
+                        .be32(2, NFT_REG_1) // NFTA_BITWISE_DREG
+                        .be32(3, 4) // NFTA_BITWISE_LEN
+                        .nested(NLA_F_NESTED | 4, |r| {
+                            // NFTA_BITWISE_MASK
+                            r.attr(NFTA_DATA_VALUE, &mask.to_be_bytes());
+                        })
+                        .nested(NLA_F_NESTED | 5, |r| {
+                            // NFTA_BITWISE_XOR
+                            r.attr(NFTA_DATA_VALUE, &[0; 4]);
+                        });
+                });
+                cmp(r, NFT_CMP_EQ, &(u32::from(*net) & mask).to_be_bytes());
+                verdict(r, NF_DROP);
+            }
+            Rule::Redirect { proto, dport, port } => {
+                meta_cmp(r, NFT_META_OIFNAME, NFT_CMP_NEQ, &ifname(LOOPBACK));
+                meta_cmp(r, NFT_META_L4PROTO, NFT_CMP_EQ, &[proto.number()]);
+                if let Some(dport) = dport {
+                    payload(r, NFT_PAYLOAD_TRANSPORT_HEADER, 2, 2);
+                    cmp(r, NFT_CMP_EQ, &dport.to_be_bytes());
+                }
+                immediate(r, NFT_REG_1, &port.to_be_bytes());
+                expr(r, "redir", |r| {
+                    r.be32(1, NFT_REG_1); // NFTA_REDIR_REG_PROTO_MIN
+                });
+            }
+        }
+    }
+}
+
+fn ifname(name: &str) -> [u8; IFNAMSIZ] {
+    let mut ret = [0; IFNAMSIZ];
+    let len = name.len().min(IFNAMSIZ - 1);
+    ret[..len].copy_from_slice(&name.as_bytes()[..len]);
+    ret
+}
+
+/// One expression of a rule
+fn expr<F: FnOnce(&mut Request)>(r: &mut Request, name: &str, data: F) {
+    r.nested(NLA_F_NESTED | NFTA_LIST_ELEM, |r| {
+        r.str(NFTA_EXPR_NAME, name)
+            .nested(NLA_F_NESTED | NFTA_EXPR_DATA, data);
+    });
+}
+
+/// Compare register 1 with `value`
+fn cmp(r: &mut Request, op: u32, value: &[u8]) {
+    expr(r, "cmp", |r| {
+        r.be32(1, NFT_REG_1) // NFTA_CMP_SREG
+            .be32(2, op) // NFTA_CMP_OP
+            .nested(NLA_F_NESTED | 3, |r| {
+                // NFTA_CMP_DATA
+                r.attr(NFTA_DATA_VALUE, value);
+            });
+    });
+}
+
+/// Load meta data `key` into register 1, and compare with `value`
+fn meta_cmp(r: &mut Request, key: u32, op: u32, value: &[u8]) {
+    expr(r, "meta", |r| {
+        r.be32(1, NFT_REG_1) // NFTA_META_DREG
+            .be32(2, key); // NFTA_META_KEY
+    });
+    cmp(r, op, value);
+}
+
+/// Load packet bytes into register 1
+fn payload(r: &mut Request, base: u32, offset: u32, len: u32) {
+    expr(r, "payload", |r| {
+        r.be32(1, NFT_REG_1) // NFTA_PAYLOAD_DREG
+            .be32(2, base) // NFTA_PAYLOAD_BASE
+            .be32(3, offset) // NFTA_PAYLOAD_OFFSET
+            .be32(4, len); // NFTA_PAYLOAD_LEN
+    });
+}
+
+fn immediate(r: &mut Request, reg: u32, value: &[u8]) {
+    expr(r, "immediate", |r| {
+        r.be32(1, reg) // NFTA_IMMEDIATE_DREG
+            .nested(NLA_F_NESTED | 2, |r| {
+                // NFTA_IMMEDIATE_DATA
+                r.attr(NFTA_DATA_VALUE, value);
+            });
+    });
+}
+
+fn verdict(r: &mut Request, code: u32) {
+    expr(r, "immediate", |r| {
+        r.be32(1, NFT_REG_VERDICT).nested(NLA_F_NESTED | 2, |r| {
+            r.nested(NLA_F_NESTED | NFTA_DATA_VERDICT, |r| {
+                r.be32(NFTA_VERDICT_CODE, code);
+            });
+        });
+    });
+}
+
+fn request(mtype: u16, flags: u16) -> Request {
+    let mut req = Request::new((NFNL_SUBSYS_NFTABLES << 8) | mtype, flags);
+    req.nfgenmsg(NFPROTO_INET, 0);
+    req
+}
+
+fn del_table(nl: &mut Rtnl, name: &str) -> Result<()> {
+    let mut req = request(NFT_MSG_DELTABLE, 0);
+    req.str(NFTA_TABLE_NAME, name);
+    nl.batch("delete nft table", NFNL_SUBSYS_NFTABLES, vec![req])
+}
+
+/// Delete the table of sandbox `id`, if it exists.  eg. left by a process which crashed.
+pub fn remove(id: &str) -> Result<bool> {
+    match del_table(&mut Rtnl::netfilter()?, &table_name(id)) {
+        Ok(()) => Ok(true),
+        Err(err) if err.errno() == Some(Errno::ENOENT) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// The rules of one sandbox.  Deleted on drop.
+pub struct Table {
+    nl: Rtnl,
+    name: String,
+    chains: Vec<Chain>,
+    deleted: bool,
+}
+
+impl Table {
+    /// Create the table of sandbox `id`.  Replaces any left over.
+    pub fn create(id: &str) -> Result<Table> {
+        Self::create_flags(id, NFT_TABLE_F_OWNER)
+    }
+
+    /// Create the table of sandbox `id`, which outlives this process, and is not deleted
+    /// on drop.  eg. in the network namespace of a sandbox, which is removed with it.
+    pub fn create_detached(id: &str) -> Result<Table> {
+        let mut ret = Self::create_flags(id, 0)?;
+        // not on drop
+        ret.deleted = true;
+        Ok(ret)
+    }
+
+    fn create_flags(id: &str, flags: u32) -> Result<Table> {
+        let name = table_name(id);
+        if remove(id)? {
+            warn!("Removed stale nft table {}", name);
+        }
+        let mut nl = Rtnl::netfilter()?;
+        let new = |flags: u32| {
+            let mut req = request(NFT_MSG_NEWTABLE, rtnl::NLM_F_CREATE | rtnl::NLM_F_EXCL);
+            req.str(NFTA_TABLE_NAME, &name)
+                .be32(NFTA_TABLE_FLAGS, flags);
+            vec![req]
+        };
+        debug!("Create nft table {}", name);
+        match nl.batch("create nft table", NFNL_SUBSYS_NFTABLES, new(flags)) {
+            Err(err) if flags != 0 && err.errno() == Some(Errno::EOPNOTSUPP) => {
+                debug!("No owned nft tables");
+                nl.batch("create nft table", NFNL_SUBSYS_NFTABLES, new(0))?;
+            }
+            ret => ret?,
+        }
+        Ok(Table {
+            nl,
+            name,
+            chains: vec![],
+            deleted: false,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Append a rule, with its chain if necessary
+    pub fn add(&mut self, rule: &Rule) -> Result<()> {
+        if let Rule::Deny { prefix, .. } = rule {
+            if *prefix > 32 {
+                return Err(Error::os("nft rule", io::ErrorKind::InvalidInput.into()));
+            }
+        }
+        let chain = rule.chain();
+        let mut reqs = vec![];
+        if !self.chains.contains(&chain) {
+            let (ctype, hooknum, priority) = chain.hook();
+            let mut req = request(NFT_MSG_NEWCHAIN, rtnl::NLM_F_CREATE);
+            req.str(NFTA_CHAIN_TABLE, &self.name)
+                .str(NFTA_CHAIN_NAME, chain.name())
+                .nested(NLA_F_NESTED | NFTA_CHAIN_HOOK, |r| {
+                    r.be32(NFTA_HOOK_HOOKNUM, hooknum)
+                        .be32(NFTA_HOOK_PRIORITY, priority as u32);
+                })
+                .be32(NFTA_CHAIN_POLICY, NF_ACCEPT)
+                .str(NFTA_CHAIN_TYPE, ctype);
+            reqs.push(req);
+        }
+        let mut req = request(NFT_MSG_NEWRULE, rtnl::NLM_F_CREATE | NLM_F_APPEND);
+        req.str(NFTA_RULE_TABLE, &self.name)
+            .str(NFTA_RULE_CHAIN, chain.name())
+            .nested(NLA_F_NESTED | NFTA_RULE_EXPRESSIONS, |r| rule.encode(r));
+        reqs.push(req);
+
+        debug!("Add to nft table {} : {:?}", self.name, rule);
+        self.nl.batch("add nft rule", NFNL_SUBSYS_NFTABLES, reqs)?;
+        if !self.chains.contains(&chain) {
+            self.chains.push(chain);
+        }
+        Ok(())
+    }
+
+    /// Delete the table, and all of its rules
+    pub fn delete(mut self) -> Result<()> {
+        self.deleted = true;
+        del_table(&mut self.nl, &self.name)
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if !self.deleted {
+            if let Err(err) = del_table(&mut self.nl, &self.name) {
+                warn!("Unable to delete nft table {} : {}", self.name, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{proc, util};
+
+    #[test]
+    fn rules() {
+        assert_eq!(&ifname("eth0")[..5], b"eth0\0");
+        assert_eq!(ifname("a-very-long-interface-name")[IFNAMSIZ - 1], 0);
+
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = proc::fork::<_, Error>(|| {
+            util::unshare(libc::CLONE_NEWNET)?;
+            let mut table = Table::create("test")?;
+            assert_eq!(table.name(), "sandbox-test");
+            table.add(&Rule::Masquerade {
+                iface: "br0".into(),
+            })?;
+            table.add(&Rule::Forward {
+                proto: Proto::Tcp,
+                port: 8080,
+                to: "10.0.0.2:80".parse().unwrap(),
+            })?;
+            table.add(&Rule::Deny {
+                iface: "br0".into(),
+                net: [192, 168, 0, 0].into(),
+                prefix: 16,
+            })?;
+            // chain exists
+            table.add(&Rule::Masquerade {
+                iface: "br1".into(),
+            })?;
+            table
+                .add(&Rule::Deny {
+                    iface: "br0".into(),
+                    net: [0, 0, 0, 0].into(),
+                    prefix: 33,
+                })
+                .unwrap_err();
+
+            drop(table);
+            assert!(!remove("test")?);
+
+            let table = Table::create("other")?;
+            table.delete()?;
+            assert!(!remove("other")?);
+
+            let mut table = Table::create_detached("kept")?;
+            table.add(&Rule::Redirect {
+                proto: Proto::Udp,
+                dport: Some(53),
+                port: 5353,
+            })?;
+            table.add(&Rule::Redirect {
+                proto: Proto::Tcp,
+                dport: None,
+                port: 1080,
+            })?;
+            drop(table);
+            assert!(remove("kept")?);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+}
@@ -0,0 +1,187 @@
+//! Service readiness notification.  eg. to a supervisor which started `isolate`.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+use std::{env, mem, thread};
+
+use libc;
+
+use log::{debug, warn};
+
+use super::err::{Error, Result};
+
+/// Write a message to an inherited file descriptor, then close it.
+/// cf. the s6 `notification-fd` convention.
+pub fn notify_fd(fd: RawFd, msg: &str) -> Result<()> {
+    debug!("notify_fd({}, {:?})", fd, msg);
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(msg.as_bytes())
+        .map_err(|e| Error::os(format!("notify fd {}", fd), e))
+}
+
+/// Send a message to the service manager via `$NOTIFY_SOCKET`.  eg. `"READY=1"`
+///
+/// Returns `false` if `$NOTIFY_SOCKET` is not set.  cf. `man 3 sd_notify`
+pub fn sd_notify(msg: &str) -> Result<bool> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => {
+            send_dgram(path, msg.as_bytes())?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Send a datagram to a unix socket path.  A leading `@` selects the abstract namespace.
+pub(crate) fn send_dgram(path: OsString, msg: &[u8]) -> Result<()> {
+    debug!("send_dgram({:?}, {:?})", path, String::from_utf8_lossy(msg));
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as _;
+
+    let mut bytes = path.clone().into_vec();
+    if bytes.first() == Some(&b'@') {
+        bytes[0] = 0;
+    }
+    if bytes.is_empty() || bytes.len() >= addr.sun_path.len() {
+        return Err(Error::os(
+            format!("notify socket {:?}", path),
+            io::Error::from(io::ErrorKind::InvalidInput),
+        ));
+    }
+    for (d, s) in addr.sun_path.iter_mut().zip(bytes.iter()) {
+        *d = *s as _;
+    }
+    let alen = mem::size_of::<libc::sa_family_t>() + bytes.len();
+
+    let sock = unsafe {
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(Error::last_os_error("notify socket()"));
+        }
+        OwnedFd::from_raw_fd(fd)
+    };
+    let ret = unsafe {
+        libc::sendto(
+            sock.as_raw_fd(),
+            msg.as_ptr() as *const _,
+            msg.len(),
+            libc::MSG_NOSIGNAL,
+            &addr as *const _ as *const libc::sockaddr,
+            alen as _,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error(format!("notify sendto {:?}", path)));
+    }
+    Ok(())
+}
+
+/// Relays `sd_notify()` messages from a sandboxed service to the outer `$NOTIFY_SOCKET`
+#[derive(Debug)]
+pub struct NotifyProxy {
+    sock: UnixDatagram,
+    path: PathBuf,
+    target: OsString,
+}
+
+impl NotifyProxy {
+    /// Bind a new socket at `path`.  Returns `None` if `$NOTIFY_SOCKET` is not set.
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Option<NotifyProxy>> {
+        let target = match env::var_os("NOTIFY_SOCKET") {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        let path = path.as_ref().to_path_buf();
+        let sock = UnixDatagram::bind(&path).map_err(|e| Error::file("bind", &path, e))?;
+        debug!("Notify proxy {} -> {:?}", path.display(), target);
+        Ok(Some(NotifyProxy { sock, path, target }))
+    }
+
+    /// Location of the listening socket
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Relay messages from a background thread.  Runs until the process exits.
+    pub fn spawn(&self) -> Result<thread::JoinHandle<()>> {
+        let sock = self
+            .sock
+            .try_clone()
+            .map_err(|e| Error::file("dup", &self.path, e))?;
+        let target = self.target.clone();
+        Ok(thread::spawn(move || {
+            let mut buf = vec![0; 4096];
+            loop {
+                let n = match sock.recv(&mut buf) {
+                    Ok(n) => n,
+                    Err(err) => {
+                        warn!("Notify proxy stops : {}", err);
+                        return;
+                    }
+                };
+                if let Some(msg) = filter(&buf[..n]) {
+                    if let Err(err) = send_dgram(target.clone(), &msg) {
+                        warn!("Notify proxy unable to forward : {}", err);
+                    }
+                }
+            }
+        }))
+    }
+}
+
+/// Remove assignments which are not meaningful outside of the sandbox
+fn filter(msg: &[u8]) -> Option<Vec<u8>> {
+    let msg = String::from_utf8_lossy(msg);
+    let out: Vec<&str> = msg
+        .lines()
+        // PIDs are in a different namespace, and no file descriptors are passed
+        .filter(|line| !line.starts_with("MAINPID=") && !line.starts_with("FDSTORE="))
+        .collect();
+    if out.is_empty() {
+        None
+    } else {
+        Some(out.join("\n").into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn dgram() {
+        let tdir = TempDir::new().unwrap();
+        let path = tdir.path().join("notify");
+        let rx = UnixDatagram::bind(&path).unwrap();
+
+        send_dgram(path.into(), b"READY=1").unwrap();
+
+        let mut buf = vec![0; 16];
+        let n = rx.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+
+    #[test]
+    fn filter_mainpid() {
+        assert_eq!(
+            filter(b"READY=1\nMAINPID=1\nSTATUS=ok").unwrap(),
+            b"READY=1\nSTATUS=ok"
+        );
+        assert!(filter(b"MAINPID=1").is_none());
+    }
+
+    #[test]
+    fn fd() {
+        let (mut rx, tx) = crate::util::pipe().unwrap();
+        notify_fd(tx.into_raw_fd(), "READY\n").unwrap();
+
+        let mut buf = String::new();
+        std::io::Read::read_to_string(&mut rx, &mut buf).unwrap();
+        assert_eq!(buf, "READY\n");
+    }
+}
@@ -0,0 +1,267 @@
+//! Hardening levels.  Curated sets of restrictions applied inside a sandbox.
+
+use std::path::Path;
+use std::str::FromStr;
+use std::{fmt, io};
+
+use libc;
+
+use super::err::{Error, Result};
+use super::ext;
+use super::seccomp::Filter;
+use super::util;
+
+/// Ordered from least to most restrictive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Deny common kernel exploit primitives, and terminal injection
+    Minimal,
+    /// Also hide kernel information, deny administrative syscalls, set `no_new_privs`,
+    /// and deny attaching to processes with `ptrace()`
+    Default,
+    /// Also deny all debugging, and nested containers
+    Paranoid,
+}
+
+pub const LEVELS: &[Level] = &[Level::Minimal, Level::Default, Level::Paranoid];
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::Minimal => write!(f, "minimal"),
+            Level::Default => write!(f, "default"),
+            Level::Paranoid => write!(f, "paranoid"),
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Level> {
+        match s {
+            "minimal" => Ok(Level::Minimal),
+            "default" => Ok(Level::Default),
+            "paranoid" => Ok(Level::Paranoid),
+            _ => Err(Error::os(
+                format!("Unknown hardening level {:?}", s),
+                io::Error::from(io::ErrorKind::InvalidInput),
+            )),
+        }
+    }
+}
+
+/// Restrictions of one hardening level
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hardening {
+    pub level: Level,
+    /// Absolute paths hidden in the new root
+    pub masked_paths: Vec<&'static str>,
+    /// Syscalls which fail with `EPERM`
+    pub syscalls: Vec<(&'static str, libc::c_long)>,
+    /// `ioctl()` requests which fail with `EPERM`
+    pub ioctls: Vec<(&'static str, u32)>,
+    /// `ptrace()` requests which fail with `EPERM`.  Tracing of children
+    /// with `PTRACE_TRACEME` remains possible.  eg. "gdb ./prog"
+    pub ptrace_requests: Vec<(&'static str, u32)>,
+    pub no_new_privs: bool,
+    /// Detach from the session keyring of the caller
+    pub new_keyring: bool,
+    /// Processes of the sandbox tool itself, outside and inside the sandbox, are
+    /// not dumpable.  So can not be traced by other processes of the same user.
+    pub non_dumpable: bool,
+    /// Refuse syscalls made through compat ABIs.  eg. i386 or x32 on x86_64
+    pub native_only: bool,
+}
+
+macro_rules! sys {
+    ($($name:ident),*) => {
+        vec![$((stringify!($name), libc::$name),)*]
+    };
+}
+
+impl Hardening {
+    pub fn new(level: Level) -> Hardening {
+        let mut ret = Hardening {
+            level,
+            masked_paths: vec!["/dev/userfaultfd"],
+            syscalls: sys!(
+                SYS_userfaultfd,
+                SYS_io_uring_setup,
+                SYS_io_uring_enter,
+                SYS_io_uring_register
+            ),
+            ioctls: vec![("TIOCSTI", ext::TIOCSTI)],
+            ptrace_requests: vec![],
+            no_new_privs: false,
+            new_keyring: true,
+            non_dumpable: false,
+            native_only: false,
+        };
+        if level == Level::Minimal {
+            return ret;
+        }
+
+        ret.masked_paths.extend([
+            "/proc/acpi",
+            "/proc/kcore",
+            "/proc/keys",
+            "/proc/sched_debug",
+            "/proc/scsi",
+            "/proc/sysrq-trigger",
+            "/proc/timer_list",
+            "/sys/firmware",
+        ]);
+        ret.syscalls.extend(sys!(
+            SYS_acct,
+            SYS_add_key,
+            SYS_bpf,
+            SYS_delete_module,
+            SYS_finit_module,
+            SYS_init_module,
+            SYS_kexec_file_load,
+            SYS_kexec_load,
+            SYS_keyctl,
+            SYS_open_by_handle_at,
+            SYS_perf_event_open,
+            SYS_reboot,
+            SYS_request_key,
+            SYS_swapoff,
+            SYS_swapon
+        ));
+        ret.ioctls.push(("TIOCLINUX", ext::TIOCLINUX));
+        ret.ptrace_requests.extend([
+            ("PTRACE_ATTACH", libc::PTRACE_ATTACH),
+            ("PTRACE_SEIZE", libc::PTRACE_SEIZE),
+        ]);
+        ret.no_new_privs = true;
+        ret.non_dumpable = true;
+        if level == Level::Default {
+            return ret;
+        }
+
+        ret.masked_paths
+            .extend(["/proc/kallsyms", "/proc/modules", "/sys/kernel"]);
+        ret.syscalls.extend(sys!(
+            SYS_mount,
+            SYS_personality,
+            SYS_pivot_root,
+            SYS_process_vm_readv,
+            SYS_process_vm_writev,
+            SYS_ptrace,
+            SYS_setns,
+            SYS_umount2,
+            SYS_unshare
+        ));
+        ret.native_only = true;
+        ret
+    }
+
+    /// Syscall filter implementing this level
+    pub fn filter(&self) -> Filter {
+        let mut ret = Filter::new();
+        for (_name, nr) in &self.syscalls {
+            ret.deny(*nr, libc::EPERM);
+        }
+        for (_name, req) in &self.ioctls {
+            ret.deny_arg(libc::SYS_ioctl, 1, *req, libc::EPERM);
+        }
+        for (_name, req) in &self.ptrace_requests {
+            ret.deny_arg(libc::SYS_ptrace, 0, *req, libc::EPERM);
+        }
+        if self.native_only {
+            ret.native_only();
+        }
+        ret
+    }
+
+    /// Hide `masked_paths` under the new root
+    pub fn mask(&self, new_root: &Path) -> Result<()> {
+        for path in &self.masked_paths {
+            util::mask_path(new_root.join(path.trim_start_matches('/')))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Hardening {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes = |b: bool| if b { "yes" } else { "no" };
+        writeln!(f, "Hardening level: {}", self.level)?;
+        writeln!(f, "  no_new_privs    : {}", yes(self.no_new_privs))?;
+        writeln!(f, "  new keyring     : {}", yes(self.new_keyring))?;
+        writeln!(f, "  non-dumpable    : {}", yes(self.non_dumpable))?;
+        writeln!(f, "  native ABI only : {}", yes(self.native_only))?;
+        writeln!(f, "  masked paths    :")?;
+        for path in &self.masked_paths {
+            writeln!(f, "    {}", path)?;
+        }
+        writeln!(f, "  denied syscalls :")?;
+        for (name, _nr) in &self.syscalls {
+            writeln!(f, "    {}", name.trim_start_matches("SYS_"))?;
+        }
+        writeln!(f, "  denied ioctls   :")?;
+        for (name, _req) in &self.ioctls {
+            writeln!(f, "    {}", name)?;
+        }
+        writeln!(f, "  denied ptrace   :")?;
+        for (name, _req) in &self.ptrace_requests {
+            writeln!(f, "    {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seccomp::Arch;
+
+    #[test]
+    fn levels_nest() {
+        for pair in LEVELS.windows(2) {
+            let (lesser, greater) = (Hardening::new(pair[0]), Hardening::new(pair[1]));
+            for path in &lesser.masked_paths {
+                assert!(greater.masked_paths.contains(path), "{}", path);
+            }
+            for sys in &lesser.syscalls {
+                assert!(greater.syscalls.contains(sys), "{:?}", sys);
+            }
+            for req in &lesser.ioctls {
+                assert!(greater.ioctls.contains(req), "{:?}", req);
+            }
+            for req in &lesser.ptrace_requests {
+                assert!(greater.ptrace_requests.contains(req), "{:?}", req);
+            }
+            assert!(greater.syscalls.len() > lesser.syscalls.len());
+        }
+    }
+
+    #[test]
+    fn mapping() {
+        assert_eq!("paranoid".parse::<Level>().unwrap(), Level::Paranoid);
+        "extreme".parse::<Level>().unwrap_err();
+
+        let minimal = Hardening::new(Level::Minimal);
+        assert!(!minimal.no_new_privs);
+        assert!(Hardening::new(Level::Default).no_new_privs);
+        assert!(!minimal.non_dumpable);
+        assert!(!minimal.filter().syscalls().contains(&libc::SYS_ptrace));
+        let default = Hardening::new(Level::Default).filter().to_string();
+        assert!(
+            default.contains("ptrace arg0 == 0x10 -> EPERM\n"),
+            "{}",
+            default
+        );
+        let paranoid = Hardening::new(Level::Paranoid).filter();
+        assert!(paranoid.syscalls().contains(&libc::SYS_ptrace));
+        assert!(paranoid.syscalls().contains(&libc::SYS_ioctl));
+        assert_eq!(paranoid.abis(), vec![Arch::NATIVE]);
+        assert!(
+            Hardening::new(Level::Default).filter().abis().len() >= minimal.filter().abis().len()
+        );
+
+        let text = Hardening::new(Level::Default).to_string();
+        assert!(text.contains("Hardening level: default"), "{}", text);
+        assert!(text.contains("    kexec_load\n"), "{}", text);
+    }
+}
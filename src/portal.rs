@@ -0,0 +1,268 @@
+//! Grant access to individual files through xdg-document-portal.
+//!
+//! Files exported to the document portal appear in its FUSE mount,
+//! usually `$XDG_RUNTIME_DIR/doc/`, as `<doc id>/<file name>`.  The sub-directory
+//! `by-app/<app id>/` shows only the documents granted to one application.
+//! Binding that sub-directory over the mount point gives a sandbox access to exactly
+//! those files, wherever they are on the host.
+//!
+//! Files may be named up front, or chosen interactively with the host file picker
+//! of xdg-desktop-portal.
+//!
+//! cf. https://flatpak.github.io/xdg-desktop-portal/docs/doc-org.freedesktop.portal.Documents.html
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use super::dbus::{Bus, Writer, SIGNAL};
+use super::err::{Error, Result};
+use super::util;
+
+const DOCUMENTS: &str = "org.freedesktop.portal.Documents";
+const DOCUMENTS_PATH: &str = "/org/freedesktop/portal/documents";
+const DESKTOP: &str = "org.freedesktop.portal.Desktop";
+const DESKTOP_PATH: &str = "/org/freedesktop/portal/desktop";
+const FILE_CHOOSER: &str = "org.freedesktop.portal.FileChooser";
+const REQUEST: &str = "org.freedesktop.portal.Request";
+
+/// AddFull() flag.  Return the existing ID if a file was exported before.
+const ADD_REUSE_EXISTING: u32 = 1;
+
+/// Application ID used for a sandbox named `name`.  eg. "io.github.mdavidsaver.sandbox.isolate"
+pub fn app_id(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}.{}", super::wayland::SANDBOX_ENGINE, name)
+}
+
+/// Decode a `file://` URI, as returned by the file picker
+fn file_uri(uri: &str) -> Option<PathBuf> {
+    let rest = uri.strip_prefix("file://")?;
+    // local files only.  eg. "file:///path" or "file://localhost/path"
+    let path = rest.strip_prefix("localhost").unwrap_or(rest);
+    if !path.starts_with('/') {
+        return None;
+    }
+    let mut raw = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            raw.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            raw.push(b);
+        }
+    }
+    Some(PathBuf::from(OsStr::from_bytes(&raw)))
+}
+
+/// Session of the document portal, on behalf of one application
+#[derive(Debug)]
+pub struct Documents {
+    bus: Bus,
+    app_id: String,
+    /// Where the portal FUSE filesystem is mounted.  eg. `/run/user/1000/doc`
+    mount: PathBuf,
+}
+
+impl Documents {
+    /// Connect to the portal on the session bus of the calling user
+    pub fn connect<S: Into<String>>(app_id: S) -> Result<Documents> {
+        let mut bus = Bus::connect(true)?;
+        let reply = bus.call(
+            DOCUMENTS,
+            DOCUMENTS_PATH,
+            DOCUMENTS,
+            "GetMountPoint",
+            "",
+            &[],
+        )?;
+        let mut mount = reply.body().bytes()?;
+        // a nil terminated byte string
+        if mount.last() == Some(&0) {
+            mount.pop();
+        }
+        let mount = PathBuf::from(OsStr::from_bytes(&mount));
+        debug!("Document portal at {}", mount.display());
+        Ok(Documents {
+            bus,
+            app_id: app_id.into(),
+            mount,
+        })
+    }
+
+    /// Where the portal is mounted on the host, and where the sandbox will find its documents
+    pub fn mount_point(&self) -> &Path {
+        &self.mount
+    }
+
+    /// Documents visible to our application.  `<mount>/by-app/<app id>`
+    pub fn app_view(&self) -> PathBuf {
+        self.mount.join("by-app").join(&self.app_id)
+    }
+
+    /// Export `file`, and grant our application read and write access.
+    /// Returns the path as seen through `mount_point()`.
+    pub fn add<P: AsRef<Path>>(&mut self, file: P) -> Result<PathBuf> {
+        let file = file.as_ref();
+        let name = file.file_name().ok_or_else(|| {
+            Error::file("document", file, std::io::ErrorKind::InvalidInput.into())
+        })?;
+        // the portal checks that we can access this path
+        let handle = File::options()
+            .read(true)
+            .custom_flags(libc::O_PATH)
+            .open(file)
+            .map_err(|e| Error::file("open", file, e))?;
+
+        let mut body = Writer::default();
+        body.array(4, |w| {
+            w.u32(0);
+        })
+        .u32(ADD_REUSE_EXISTING)
+        .str(&self.app_id)
+        .array(4, |w| {
+            w.str("read").str("write");
+        });
+        let reply = self.bus.call_fds(
+            DOCUMENTS,
+            DOCUMENTS_PATH,
+            DOCUMENTS,
+            "AddFull",
+            "ahusas",
+            &body.buf,
+            &[handle.as_raw_fd()],
+        )?;
+        let id = reply.body().strs()?.pop().unwrap_or_default();
+        if id.is_empty() {
+            return Err(Error::file(
+                "document portal",
+                file,
+                std::io::ErrorKind::InvalidData.into(),
+            ));
+        }
+        debug!("Document {} -> {}", file.display(), id);
+        Ok(self.mount.join(id).join(name))
+    }
+
+    /// Show the host file picker.  Blocks until the user chooses some files, or cancels.
+    /// Returns the host paths chosen, which have not yet been exported.
+    pub fn pick(&mut self, title: &str) -> Result<Vec<PathBuf>> {
+        let mut rule = Writer::default();
+        rule.str(&format!(
+            "type='signal',sender='{DESKTOP}',interface='{REQUEST}',member='Response'"
+        ));
+        self.bus.call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "AddMatch",
+            "s",
+            &rule.buf,
+        )?;
+
+        let mut body = Writer::default();
+        // no parent window
+        body.str("").str(title).array(8, |w| {
+            w.align(8).str("multiple").sig("b").u32(1);
+        });
+        let reply = self.bus.call(
+            DESKTOP,
+            DESKTOP_PATH,
+            FILE_CHOOSER,
+            "OpenFile",
+            "ssa{sv}",
+            &body.buf,
+        )?;
+        let handle = reply.body().str()?;
+        debug!("File chooser request {handle}");
+
+        loop {
+            let signal = self.bus.next_signal()?;
+            if signal.mtype != SIGNAL || signal.path != handle || signal.member != "Response" {
+                continue;
+            }
+            let mut body = signal.body();
+            // 0 - success, 1 - cancelled, 2 - other
+            let response = body.u32()?;
+            let mut uris = vec![];
+            body.vardict(|key, sig, r| match (key, sig) {
+                ("uris", "as") => r.strs().map(|v| uris = v),
+                _ => r.skip(sig),
+            })?;
+            if response != 0 {
+                debug!("File chooser response {response}");
+                return Ok(vec![]);
+            }
+            let mut ret = vec![];
+            for uri in uris {
+                match file_uri(&uri) {
+                    Some(path) => ret.push(path),
+                    None => log::warn!("Ignore non-local document {uri}"),
+                }
+            }
+            return Ok(ret);
+        }
+    }
+
+    /// Open `app_view()` with `O_PATH`, for use with `mount()` through `/proc/self/fd/`.
+    ///
+    /// The FUSE mount is accessible only to processes whose real, effective, and saved IDs
+    /// all match the user.  Not even to root.  So the open is done by a child process,
+    /// which first drops any privilege.  eg. the saved UID 0 of a SUID executable.
+    pub fn open_app_view(&self) -> Result<OwnedFd> {
+        let view = self.app_view();
+        let (rx, tx) = util::socketpair()?;
+        let mut child = util::fork(|| -> Result<()> {
+            util::setgid(util::getgid())?;
+            util::setuid(util::getuid())?;
+            let file = File::options()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+                .open(&view)
+                .map_err(|e| Error::file("open", &view, e))?;
+            util::send_fd(&tx, file)
+        })?;
+        drop(tx);
+        let fd = util::recv_fd(&rx)?;
+        if child.park()? != 0 {
+            return Err(Error::file(
+                "open",
+                &view,
+                std::io::ErrorKind::PermissionDenied.into(),
+            ));
+        }
+        fd.ok_or_else(|| Error::file("open", &view, std::io::ErrorKind::NotFound.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uri() {
+        assert_eq!(file_uri("file:///a/b").unwrap(), Path::new("/a/b"));
+        assert_eq!(
+            file_uri("file://localhost/with%20space/%C3%A9").unwrap(),
+            Path::new("/with space/é")
+        );
+        assert_eq!(file_uri("file:///bad%2").as_deref(), None);
+        assert_eq!(file_uri("file://host/x").as_deref(), None);
+        assert_eq!(file_uri("https://example.com/").as_deref(), None);
+    }
+
+    #[test]
+    fn app() {
+        assert_eq!(app_id("my app"), "io.github.mdavidsaver.sandbox.my_app");
+    }
+}
@@ -0,0 +1,233 @@
+//! Preview how a profile would expose the host, without running anything.
+//!
+//! Resolves a profile against the live mount table, in the same order as `isolate`
+//! applies mounts.  So a later entry takes precedence over an earlier one.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use super::fs::{self, MountInfo};
+use super::profile::Profile;
+use super::site::SitePolicy;
+
+/// Not storage.  Not flagged when left writable.
+const PSEUDO_FSTYPES: &[&str] = &[
+    "autofs",
+    "binfmt_misc",
+    "bpf",
+    "cgroup",
+    "cgroup2",
+    "configfs",
+    "debugfs",
+    "devpts",
+    "devtmpfs",
+    "efivarfs",
+    "fusectl",
+    "hugetlbfs",
+    "mqueue",
+    "nsfs",
+    "proc",
+    "pstore",
+    "securityfs",
+    "sysfs",
+    "tracefs",
+];
+
+/// How a host path appears inside the sandbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Writable,
+    ReadOnly,
+    Hidden,
+    Tmpfs,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Access::Writable => "writable",
+            Access::ReadOnly => "read-only",
+            Access::Hidden => "hidden",
+            Access::Tmpfs => "tmpfs",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub access: Access,
+    /// Something which may surprise the author of the profile
+    pub warning: Option<String>,
+}
+
+impl Entry {
+    fn new<P: Into<PathBuf>>(path: P, access: Access) -> Entry {
+        Entry {
+            path: path.into(),
+            access,
+            warning: None,
+        }
+    }
+
+    fn warn<S: Into<String>>(mut self, msg: S) -> Entry {
+        self.warning = Some(msg.into());
+        self
+    }
+}
+
+/// The host mount containing `path`
+fn mount_of<'a>(visible: &[&'a MountInfo], path: &Path) -> Option<&'a MountInfo> {
+    // sorted, so the last match is the closest
+    visible
+        .iter()
+        .rev()
+        .find(|mp| path.starts_with(&mp.mount_point))
+        .copied()
+}
+
+/// A directory bound by the profile
+fn bind(visible: &[&MountInfo], path: &Path, access: Access) -> Entry {
+    let real = match path.canonicalize() {
+        Ok(real) => real,
+        Err(_) => return Entry::new(path, access).warn("does not exist, ignored"),
+    };
+    if !real.is_dir() {
+        return Entry::new(path, access).warn("not a directory, ignored");
+    }
+    let entry = Entry::new(&real, access);
+    let host_ro = matches!(mount_of(visible, &real), Some(mp) if mp.has_option(libc::MS_RDONLY));
+    if access == Access::Writable && host_ro {
+        entry.warn("host mount is read-only")
+    } else if real != path {
+        let msg = format!("resolved from {}", path.display());
+        entry.warn(msg)
+    } else {
+        entry
+    }
+}
+
+/// Resolve `profile` against the host mounts `visible`, as returned by
+/// `Mounts::visible_under("/")`.  `cwd` is always writable.
+pub fn preview(
+    profile: &Profile,
+    visible: &[&MountInfo],
+    cwd: &Path,
+    site: &SitePolicy,
+) -> Vec<Entry> {
+    let visible: Vec<&MountInfo> = visible
+        .iter()
+        .filter(|mp| {
+            !fs::SPECIAL_MOUNTS
+                .iter()
+                .any(|(dir, _)| mp.mount_point.starts_with(dir))
+        })
+        .copied()
+        .collect();
+    let mut ret = vec![];
+
+    let readonly = |mp: &MountInfo| mp.has_option(libc::MS_RDONLY);
+    let plan = fs::plan_recursive(&visible, fs::want_readonly, readonly);
+    for mp in &visible {
+        let made_ro = plan.iter().any(|(top, recursive)| {
+            top.mount_point == mp.mount_point
+                || (*recursive && mp.mount_point.starts_with(&top.mount_point))
+        });
+        if made_ro {
+            ret.push(Entry::new(&mp.mount_point, Access::ReadOnly));
+        } else if !readonly(mp) && !PSEUDO_FSTYPES.contains(&mp.fstype.as_str()) {
+            let msg = format!("{} mount is not made read-only", mp.fstype);
+            ret.push(Entry::new(&mp.mount_point, Access::Writable).warn(msg));
+        }
+    }
+
+    for (dir, fstype) in fs::SPECIAL_MOUNTS {
+        if *fstype == "tmpfs" {
+            ret.push(Entry::new(dir, Access::Tmpfs));
+        }
+    }
+
+    if let Some(toolchains) = &profile.toolchains {
+        for dir in toolchains.resolve() {
+            ret.push(Entry::new(dir, Access::ReadOnly));
+        }
+    }
+    let cwd_ro = matches!(mount_of(&visible, cwd), Some(mp) if readonly(mp));
+    let entry = Entry::new(cwd, Access::Writable);
+    ret.push(if cwd_ro {
+        entry.warn("current directory, but host mount is read-only")
+    } else {
+        entry
+    });
+    for dir in &profile.rw {
+        ret.push(bind(&visible, dir, Access::Writable));
+    }
+    for dir in &profile.ro {
+        ret.push(bind(&visible, dir, Access::ReadOnly));
+    }
+
+    for dir in &site.readonly {
+        let exposed = ret.iter().any(|e| {
+            e.access == Access::Writable
+                && e.warning.is_none()
+                && (e.path.starts_with(dir) || dir.starts_with(&e.path))
+        });
+        if exposed && dir.exists() {
+            ret.push(Entry::new(dir, Access::ReadOnly).warn("forced by site policy"));
+        }
+    }
+    for path in &site.mask {
+        ret.push(Entry::new(path, Access::Hidden).warn("masked by site policy"));
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::Mounts;
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn resolve() {
+        let inp = "
+29 1 253:1 / / rw - ext4 /dev/root rw
+30 29 0:20 / /sys rw - sysfs sysfs rw
+31 29 0:21 / /mnt/net rw - nfs4 server:/export rw
+32 29 253:2 / /mnt/ro ro - ext4 /dev/data ro
+33 29 0:22 / /tmp rw - tmpfs tmpfs rw
+"
+        .trim_start();
+        let mounts = Mounts::parse(inp, Path::new("static")).unwrap();
+        let visible = mounts.visible_under("/");
+
+        let tdir = TempDir::new().unwrap();
+        let dir = tdir.path().canonicalize().unwrap();
+        let profile = Profile {
+            rw: vec![dir.clone(), "/nonexistent".into()],
+            ..Profile::default()
+        };
+        let site = SitePolicy {
+            mask: vec!["/etc/shadow".into()],
+            ..SitePolicy::default()
+        };
+        let entries = preview(&profile, &visible, Path::new("/mnt/ro/src"), &site);
+        let find = |path: &Path| entries.iter().find(|e| e.path == path).unwrap();
+
+        assert_eq!(find(Path::new("/")).access, Access::ReadOnly);
+        // replaced, not made read-only
+        assert_eq!(find(Path::new("/tmp")).access, Access::Tmpfs);
+        let net = find(Path::new("/mnt/net"));
+        assert_eq!(net.access, Access::Writable);
+        assert!(net.warning.is_some());
+        assert!(!entries.iter().any(|e| e.path == Path::new("/sys")));
+
+        let cwd = find(Path::new("/mnt/ro/src"));
+        assert_eq!(cwd.access, Access::Writable);
+        assert!(cwd.warning.is_some());
+
+        assert_eq!(find(&dir), &Entry::new(&dir, Access::Writable));
+        assert!(find(Path::new("/nonexistent")).warning.is_some());
+        assert_eq!(find(Path::new("/etc/shadow")).access, Access::Hidden);
+    }
+}
@@ -1,6 +1,9 @@
 //! Child process creation/handling
 
 use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
 use std::{env, ffi, fmt, process};
 
 use libc;
@@ -9,6 +12,7 @@ use signal_hook::iterator::Signals;
 
 use log::{debug, error, warn};
 
+use super::envpolicy::Preset;
 use super::err::{Error, Result};
 
 /// Managed (child) process
@@ -51,15 +55,52 @@ impl Proc {
         Ok(())
     }
 
+    /// Send signal to the process group led by this process, which includes
+    /// any descendants which have not moved to another group.
+    /// Signals only the process when it is not a group leader.
+    pub fn signal_group(&self, sig: libc::c_int) -> Result<()> {
+        if self.done || unsafe { libc::getpgid(self.pid) } != self.pid {
+            return self.signal(sig);
+        }
+        debug!("signal group {} with {}", self.pid, sig);
+        if 0 != unsafe { libc::kill(-self.pid, sig) } {
+            return Err(Error::last_os_error(format!(
+                "Unable to signal group {} with {}",
+                self.pid, sig
+            )));
+        }
+        Ok(())
+    }
+
     /// Send `SIGKILL` to process
     pub fn kill(&self) -> Result<()> {
         self.signal(libc::SIGKILL)
     }
 
+    /// Exit code, if the child has exited.  Without blocking.
+    pub fn try_wait(&mut self) -> Result<Option<i32>> {
+        if !self.done {
+            match trywaitpid(self.pid)? {
+                TryWait::Busy => return Ok(None),
+                TryWait::Done(_child, sts) => {
+                    self.done = true;
+                    self.code = sts;
+                }
+            }
+        }
+        Ok(Some(self.code))
+    }
+
     /// Block current process until child exits.
     /// May be interrupted by `SIGINT`.
     /// Returns process exit code.
     pub fn park(&mut self) -> Result<i32> {
+        self.park_watch(None)
+    }
+
+    /// As `park()`.  Also `SIGKILL` the child once `watch` becomes readable.
+    /// eg. a pidfd of another process, which has exited.
+    pub fn park_watch(&mut self, watch: Option<BorrowedFd>) -> Result<i32> {
         if self.done {
             return Ok(self.code);
         }
@@ -71,9 +112,13 @@ impl Proc {
             signal_hook::consts::SIGCHLD,
         ])
         .map_err(|e| Error::os("Install signal handler", e))?;
-        let mut isig = signals.forever();
 
         let mut cnt = 0;
+        let mut watch = watch.map(|fd| libc::pollfd {
+            fd: fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        });
 
         loop {
             match trywaitpid(self.pid) {
@@ -88,21 +133,33 @@ impl Proc {
             }
             debug!("Waiting for PID {}", self.pid);
 
-            match isig.next() {
-                Some(signal_hook::consts::SIGCHLD) => {
-                    debug!("SIGCHLD");
-                    // loop around to test child
-                }
-                Some(sig) => {
-                    debug!("SIG {}", sig);
-                    // we are being interrupted.
-                    // be delicate with child at first
-                    let num = if cnt < 2 { sig } else { libc::SIGKILL };
-                    cnt += 1;
-                    self.signal(num)?;
+            let sigs: Vec<libc::c_int> = match &mut watch {
+                None => signals.forever().next().into_iter().collect(),
+                Some(pfd) => {
+                    // interrupted by any signal.  Timeout in case one arrives before poll()
+                    if unsafe { libc::poll(pfd, 1, 1000) } == 1 {
+                        warn!("Watched descriptor ready.  Kill PID {}", self.pid);
+                        self.kill()?;
+                        watch = None;
+                    }
+                    signals.pending().collect()
                 }
-                None => {
-                    unreachable!();
+            };
+
+            for sig in sigs {
+                match sig {
+                    signal_hook::consts::SIGCHLD => {
+                        debug!("SIGCHLD");
+                        // loop around to test child
+                    }
+                    sig => {
+                        debug!("SIG {}", sig);
+                        // we are being interrupted.
+                        // be delicate with child at first
+                        let num = if cnt < 2 { sig } else { libc::SIGKILL };
+                        cnt += 1;
+                        self.signal_group(num)?;
+                    }
                 }
             }
         }
@@ -145,11 +202,138 @@ pub fn trywaitpid(pid: libc::pid_t) -> Result<TryWait> {
     }
 }
 
-/// Configuration for a call to `execvpe()`
+/// Wraps `setsid()`.  Start a new session and process group, led by the calling process.
+/// Returns the new session ID.
+pub fn setsid() -> Result<libc::pid_t> {
+    let ret = unsafe { libc::setsid() };
+    if ret < 0 {
+        return Err(Error::last_os_error("setsid"));
+    }
+    Ok(ret)
+}
+
+/// Deliver `sig` to the calling process when the parent thread exits.  0 to disable.
+/// Cleared by a change of effective UID or GID.
+pub fn set_pdeathsig(sig: libc::c_int) -> Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, sig as libc::c_ulong, 0, 0, 0) } != 0 {
+        return Err(Error::last_os_error("prctl(PR_SET_PDEATHSIG)"));
+    }
+    Ok(())
+}
+
+/// Allow, or prevent, `ptrace()` of the calling process, and reading its memory through
+/// `/proc/<pid>/`, by other processes of the same user.  Also disables core dumps.
+/// Reset to dumpable by `execve()`, unless privilege is gained.
+pub fn set_dumpable(v: bool) -> Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, v as libc::c_ulong, 0, 0, 0) } != 0 {
+        return Err(Error::last_os_error("prctl(PR_SET_DUMPABLE)"));
+    }
+    Ok(())
+}
+
+/// Wraps `pidfd_open()`.  The descriptor becomes readable when the process exits.
+/// Fails with `ENOSYS` before Linux 5.3
+pub fn pidfd_open(pid: libc::pid_t) -> Result<OwnedFd> {
+    let ret = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if ret < 0 {
+        return Err(Error::last_os_error(format!("pidfd_open({})", pid)));
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(ret as RawFd) })
+}
+
+/// Replace stdout and stderr of the calling process with `file`
+pub fn redirect_output<F: AsFd>(file: F) -> Result<()> {
+    for dst in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        super::fd::dup_over(file.as_fd().as_raw_fd(), dst)?;
+    }
+    Ok(())
+}
+
+/// File descriptors open in the calling process, in ascending order
+pub fn open_fds() -> Result<Vec<RawFd>> {
+    let dir = std::fs::read_dir("/proc/self/fd")
+        .map_err(|e| Error::file("readdir", "/proc/self/fd", e))?;
+    let mut fds: Vec<RawFd> = dir
+        .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    fds.sort_unstable();
+    // the descriptor of the directory listing is closed by now
+    fds.retain(|&fd| super::fd::is_valid(fd));
+    Ok(fds)
+}
+
+/// Mark all file descriptors other than stdin, stdout, stderr, and `keep` close-on-exec.
+/// Call before `exec()` so that descriptors of the calling process are not
+/// leaked to the new program.  Those owned by objects of the calling process stay
+/// valid until the `exec()` succeeds.  eg. a pipe whose closing signals it.
+pub fn cloexec_extra_fds(keep: &[RawFd]) -> Result<()> {
+    for fd in open_fds()? {
+        if fd > libc::STDERR_FILENO && !keep.contains(&fd) {
+            debug!("cloexec fd {}", fd);
+            super::fd::set_cloexec(fd, true)?;
+        }
+    }
+    Ok(())
+}
+
+/// Result of `daemonize()`
+#[derive(Debug)]
+pub enum Daemon {
+    /// In the calling process.  Read end of a pipe, which the detached process may write.
+    Caller(File),
+    /// In the detached process.  Write end of the pipe.  Close to release the caller.
+    Detached(File),
+}
+
+/// Detach from the calling process, and any terminal, by forking twice.
+/// The calling process must be single threaded.
+///
+/// The detached process is in a new session, with stdin, stdout, and stderr
+/// opened from `/dev/null`.  It is re-parented to process 1, or a sub-reaper.
+/// The caller returns once the intermediate process has exited.
+pub fn daemonize() -> Result<Daemon> {
+    let (rx, tx) = super::util::pipe()?;
+    let mid = unsafe { libc::fork() };
+    if mid < 0 {
+        return Err(Error::last_os_error("fork"));
+    } else if mid > 0 {
+        drop(tx);
+        let mut sts = 0;
+        if unsafe { libc::waitpid(mid, &mut sts, 0) } < 0 {
+            return Err(Error::last_os_error(format!("waitpid({})", mid)));
+        }
+        return Ok(Daemon::Caller(rx));
+    }
+    drop(rx);
+
+    // no longer a process group leader, so setsid() may succeed
+    if let Err(err) = setsid() {
+        error!("*child error: {}", err);
+        process::exit(1);
+    }
+    // not a session leader, so never acquires a controlling terminal
+    match unsafe { libc::fork() } {
+        0 => (),
+        pid => process::exit(if pid < 0 { 1 } else { 0 }),
+    }
+
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(|e| Error::file("open", "/dev/null", e))?;
+    super::fd::dup_over(null.as_raw_fd(), libc::STDIN_FILENO)?;
+    redirect_output(&null)?;
+    Ok(Daemon::Detached(tx))
+}
+
+/// Configuration for a call to `execvpe()`, or `fexecve()`
 pub struct Exec {
     cmd: ffi::CString,
     args: Vec<ffi::CString>,
     env: HashMap<String, ffi::CString>,
+    /// An executable held only in memory.  cf. `from_memfd()`
+    memfd: Option<OwnedFd>,
 }
 
 impl Exec {
@@ -169,9 +353,44 @@ impl Exec {
             cmd: ffi::CString::new(cmd.as_ref())?,
             args: vec![],
             env: es,
+            memfd: None,
         })
     }
 
+    /// Setup to exec a copy of `bytes`, kept in a sealed memfd, which is not visible
+    /// through any mount.  The executable is not looked up, so `args()` must also
+    /// supply `argv[0]`.
+    ///
+    /// A script (`#!`) is run by an interpreter which opens `/dev/fd/<N>`,
+    /// so the descriptor is left open for it.
+    pub fn from_memfd(bytes: &[u8]) -> Result<Exec> {
+        let script = bytes.starts_with(b"#!");
+        let name = ffi::CString::new("payload")?;
+        let mut flags = libc::MFD_ALLOW_SEALING;
+        if !script {
+            flags |= libc::MFD_CLOEXEC;
+        }
+        let fd = unsafe {
+            let fd = libc::memfd_create(name.as_ptr(), flags);
+            if fd < 0 {
+                return Err(Error::last_os_error("memfd_create"));
+            }
+            OwnedFd::from_raw_fd(fd)
+        };
+        let mut file = File::from(fd);
+        file.write_all(bytes)
+            .map_err(|e| Error::os("memfd write", e))?;
+        let seals =
+            libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) } < 0 {
+            return Err(Error::last_os_error("memfd seal"));
+        }
+
+        let mut exec = Exec::new("memfd:payload")?;
+        exec.memfd = Some(file.into());
+        Ok(exec)
+    }
+
     /// Provide command arguments
     pub fn args<I>(&mut self, args: I) -> Result<&mut Self>
     where
@@ -195,18 +414,25 @@ impl Exec {
     where
         T: Into<&'a str>,
     {
-        self.env
-            .insert(name.into().to_string(), ffi::CString::new(value.into())?);
+        let name = name.into();
+        let entry = ffi::CString::new(format!("{}={}", name, value.into()))?;
+        self.env.insert(name.to_string(), entry);
         Ok(self)
     }
 
+    /// Keep only the environment variables passed through by `preset`.
+    pub fn env_policy(&mut self, preset: Preset) -> &mut Self {
+        self.env.retain(|name, _| preset.allows(name));
+        self
+    }
+
     /// Clear a single environment variable.
     pub fn env_remove<'a, T: Into<&'a str>>(&mut self, name: T) -> &mut Self {
         self.env.remove(name.into());
         self
     }
 
-    /// Make the `execvpe()` call.
+    /// Make the `execvpe()`, or `fexecve()`, call.
     /// On success, does not return.
     pub fn exec(&self) -> Result<()> {
         let cmd = self.cmd.as_ptr();
@@ -217,7 +443,10 @@ impl Exec {
         env.push(::std::ptr::null());
 
         Err(unsafe {
-            libc::execvpe(cmd, args.as_ptr(), env.as_ptr());
+            match &self.memfd {
+                Some(fd) => libc::fexecve(fd.as_raw_fd(), args.as_ptr(), env.as_ptr()),
+                None => libc::execvpe(cmd, args.as_ptr(), env.as_ptr()),
+            };
             // only returns on error
             Error::last_os_error(format!(
                 "exec cmd={:?} args={:?} env={:?}",
@@ -227,6 +456,194 @@ impl Exec {
     }
 }
 
+/// What process 1 of a PID namespace does when the command exits,
+/// while other processes remain.  cf. `init_shim()`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnExit {
+    /// Exit, which kills all remaining processes of the PID namespace
+    #[default]
+    Kill,
+    /// Wait until all processes of the PID namespace have exited
+    Wait,
+}
+
+impl std::str::FromStr for OnExit {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<OnExit> {
+        match s {
+            "kill" => Ok(OnExit::Kill),
+            "wait" => Ok(OnExit::Wait),
+            _ => Err(Error::os(
+                format!("Expected \"kill\" or \"wait\", not {:?}", s),
+                std::io::ErrorKind::InvalidInput.into(),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OnExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OnExit::Kill => write!(f, "kill"),
+            OnExit::Wait => write!(f, "wait"),
+        }
+    }
+}
+
+/// Child handling of process 1, which waits for the command `pid`,
+/// and reaps any orphans re-parented to it.
+#[derive(Debug)]
+pub struct Reaper {
+    pid: libc::pid_t,
+    on_exit: OnExit,
+    /// Exit code of the command, once it has exited
+    code: Option<i32>,
+    signals: usize,
+}
+
+impl Reaper {
+    pub fn new(pid: libc::pid_t, on_exit: OnExit) -> Reaper {
+        Reaper {
+            pid,
+            on_exit,
+            code: None,
+            signals: 0,
+        }
+    }
+
+    /// Reap exited children without blocking.  `stopped` is called when the command stops.
+    /// Returns the exit code of the command, or 128 + signal number, once done.
+    /// With `OnExit::Wait`, not until no children remain.
+    pub fn reap<S: FnMut(libc::pid_t, libc::c_int)>(&mut self, mut stopped: S) -> Option<i32> {
+        loop {
+            let mut sts = 0;
+            let ret = unsafe { libc::waitpid(-1, &mut sts, libc::WNOHANG | libc::__WALL) };
+            if ret == 0 {
+                return None;
+            } else if ret < 0 {
+                // ECHILD.  all gone
+                return Some(self.code.unwrap_or(1));
+            } else if ret != self.pid {
+                debug!("Reaped PID {}", ret);
+                continue;
+            }
+
+            let code = if libc::WIFEXITED(sts) {
+                libc::WEXITSTATUS(sts)
+            } else if libc::WIFSIGNALED(sts) {
+                128 + libc::WTERMSIG(sts)
+            } else {
+                if libc::WIFSTOPPED(sts) {
+                    stopped(ret, sts);
+                }
+                continue;
+            };
+            debug!("Command PID {} exits with {}", ret, code);
+            self.code = Some(code);
+            if self.on_exit == OnExit::Kill {
+                return Some(code);
+            }
+        }
+    }
+
+    /// Relay a signal to the command.  Or, once it has exited, to the remaining
+    /// children.  eg. re-parented orphans.  Repeated signals become `SIGKILL`.
+    pub fn relay(&mut self, sig: libc::c_int) {
+        let num = if self.signals < 2 { sig } else { libc::SIGKILL };
+        self.signals += 1;
+        let targets = match self.code {
+            None => vec![self.pid],
+            Some(_) => children(),
+        };
+        for pid in targets {
+            debug!("signal PID {} with {}", pid, num);
+            unsafe { libc::kill(pid, num) };
+        }
+    }
+}
+
+/// Children of the calling thread
+fn children() -> Vec<libc::pid_t> {
+    let list = std::fs::read_to_string("/proc/thread-self/children").unwrap_or_default();
+    list.split_whitespace()
+        .filter_map(|p| p.parse().ok())
+        .collect()
+}
+
+/// Orphaned descendants are re-parented to the calling process, instead of to process 1
+pub fn set_child_subreaper() -> Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } != 0 {
+        return Err(Error::last_os_error("PR_SET_CHILD_SUBREAPER"));
+    }
+    Ok(())
+}
+
+/// From process 1 of a PID namespace, run `exec` in a child process.
+/// Signals are relayed to the command, and orphans are reaped.
+/// Returns the exit code of the command, or 128 + signal number.
+///
+/// Anything moved into `exec` is dropped by the calling process once forked.
+/// eg. the readiness pipe of `StageCtx::take_ready()`
+pub fn init_shim<F>(exec: F, on_exit: OnExit) -> Result<i32>
+where
+    F: FnOnce() -> Result<()>,
+{
+    match on_exit {
+        OnExit::Kill => supervise(exec, OnExit::Kill),
+        // orphans of the command go to a sub-reaper, apart from other children
+        // of process 1.  eg. from net::dummy_bridge()
+        OnExit::Wait => supervise(
+            || {
+                set_child_subreaper()?;
+                process::exit(supervise(exec, OnExit::Wait)?);
+            },
+            OnExit::Kill,
+        ),
+    }
+}
+
+fn supervise<F>(exec: F, on_exit: OnExit) -> Result<i32>
+where
+    F: FnOnce() -> Result<()>,
+{
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(Error::last_os_error("fork"));
+    } else if pid == 0 {
+        let code = match exec() {
+            Ok(()) => 0,
+            Err(err) => {
+                error!("*child error: {}", err);
+                1
+            }
+        };
+        process::exit(code);
+    }
+    drop(exec);
+    debug!("Supervise PID {} until {}", pid, on_exit);
+
+    let mut signals = Signals::new([
+        signal_hook::consts::SIGTERM,
+        signal_hook::consts::SIGINT,
+        signal_hook::consts::SIGQUIT,
+        signal_hook::consts::SIGHUP,
+        signal_hook::consts::SIGCHLD,
+    ])
+    .map_err(|e| Error::os("Install signal handler", e))?;
+
+    let mut reaper = Reaper::new(pid, on_exit);
+    loop {
+        if let Some(code) = reaper.reap(|_, _| ()) {
+            return Ok(code);
+        }
+        for sig in signals.wait() {
+            if sig != signal_hook::consts::SIGCHLD {
+                reaper.relay(sig);
+            }
+        }
+    }
+}
+
 /// `fork()` a child with the current process address map to run `act`.
 /// Returns only to the caller (parent process) with a `Proc` to manage the new child.
 pub fn fork<F, E>(act: F) -> Result<Proc>
@@ -264,6 +681,144 @@ mod tests {
         assert_eq!(0, pid.park().unwrap());
     }
 
+    #[test]
+    fn test_memfd() {
+        let mut pid = fork::<_, Error>(|| {
+            let script = b"#!/bin/sh\nexit $1\n";
+            Exec::from_memfd(script)?.args(["payload", "3"])?.exec()
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_dumpable() {
+        let mut pid = fork::<_, Error>(|| {
+            set_dumpable(false)?;
+            let off = unsafe { libc::prctl(libc::PR_GET_DUMPABLE, 0, 0, 0, 0) };
+            set_dumpable(true)?;
+            let on = unsafe { libc::prctl(libc::PR_GET_DUMPABLE, 0, 0, 0, 0) };
+            process::exit(if off == 0 && on == 1 { 0 } else { 2 });
+        })
+        .unwrap();
+        assert_eq!(0, pid.park().unwrap());
+    }
+
+    #[test]
+    fn test_group() {
+        use std::io::{Read, Write};
+        let (mut rx, mut tx) = crate::util::pipe().unwrap();
+        let mut pid = fork::<_, Error>(|| {
+            setsid()?;
+            // a grandchild in the same group
+            let child = unsafe { libc::fork() };
+            if child == 0 {
+                loop {
+                    unsafe { libc::pause() };
+                }
+            }
+            tx.write_all(&child.to_ne_bytes())
+                .map_err(|e| Error::os("write", e))?;
+            loop {
+                unsafe { libc::pause() };
+            }
+        })
+        .unwrap();
+        drop(tx);
+        let mut buf = [0u8; 4];
+        rx.read_exact(&mut buf).unwrap();
+        let grandchild = libc::pid_t::from_ne_bytes(buf);
+
+        pid.signal_group(libc::SIGKILL).unwrap();
+        pid.park().unwrap();
+        // grandchild is also killed, but may not be reaped immediately
+        for _ in 0..1000 {
+            match std::fs::read_to_string(format!("/proc/{}/stat", grandchild)) {
+                Err(_) => return,
+                Ok(stat) if stat.rsplit(") ").next().unwrap_or("").starts_with('Z') => return,
+                Ok(_) => std::thread::sleep(std::time::Duration::from_millis(1)),
+            }
+        }
+        panic!("grandchild {} remains", grandchild);
+    }
+
+    #[test]
+    fn test_init_shim() {
+        use std::io::{Read, Write};
+        assert_eq!("wait".parse::<OnExit>().unwrap(), OnExit::Wait);
+        "other".parse::<OnExit>().unwrap_err();
+
+        // not in the test process, which would also reap children of other tests
+        let mut pid = fork::<_, Error>(|| {
+            // not waited for
+            let helper = fork::<_, Error>(|| loop {
+                unsafe { libc::pause() };
+            })?;
+            let (mut rx, mut tx) = crate::util::pipe()?;
+            let code = init_shim(
+                || {
+                    // outlives the command
+                    if unsafe { libc::fork() } == 0 {
+                        std::thread::sleep(std::time::Duration::from_millis(100));
+                        let _ = tx.write_all(b"x");
+                        process::exit(0);
+                    }
+                    process::exit(3);
+                },
+                OnExit::Wait,
+            )?;
+            drop(helper);
+            drop(tx);
+            let mut buf = String::new();
+            rx.read_to_string(&mut buf)
+                .map_err(|e| Error::os("read", e))?;
+            process::exit(if code == 3 && buf == "x" { 0 } else { 2 });
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_daemonize() {
+        use std::io::{Read, Write};
+        match daemonize().unwrap() {
+            Daemon::Detached(mut tx) => {
+                let msg = format!("{} {}", unsafe { libc::getsid(0) }, process::id());
+                let _ = tx.write_all(msg.as_bytes());
+                process::exit(0);
+            }
+            Daemon::Caller(mut rx) => {
+                let mut msg = String::new();
+                rx.read_to_string(&mut msg).unwrap();
+                let (sid, pid) = msg.split_once(' ').unwrap();
+                assert_ne!(sid, unsafe { libc::getsid(0) }.to_string());
+                assert_ne!(pid, process::id().to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn test_cloexec_extra_fds() {
+        // without FD_CLOEXEC
+        let (rx, tx) = crate::util::pipe().unwrap();
+        let (rx, tx) = unsafe { (libc::dup(rx.as_raw_fd()), libc::dup(tx.as_raw_fd())) };
+        let mut pid = fork(|| -> crate::container::Result<()> {
+            cloexec_extra_fds(&[tx])?;
+            let cloexec =
+                |fd| -> Result<bool> { Ok(crate::fd::get_fd_flags(fd)? & libc::FD_CLOEXEC != 0) };
+            if !cloexec(rx)? || cloexec(tx)? {
+                return Err(format!("FD_CLOEXEC {} {}", cloexec(rx)?, cloexec(tx)?).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        unsafe {
+            libc::close(rx);
+            libc::close(tx);
+        }
+        assert_eq!(0, pid.park().unwrap());
+    }
+
     #[test]
     fn test_exit42() {
         let mut pid = fork::<_, Error>(|| {
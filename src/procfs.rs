@@ -0,0 +1,112 @@
+//! Inspect a sandboxed process from the host side, through `/proc/<pid>/`.
+//!
+//! eg. container process 1, as found from `ContainerInfo::procfs()`, or a registry entry.
+//! Reading the descriptor list, and mount table, needs the same access as `ptrace()`.
+
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+
+use super::err::{Error, Result};
+use super::fs::{MountInfo, Mounts};
+
+/// `/proc/<pid>/` of one process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcFs {
+    pid: libc::pid_t,
+}
+
+impl ProcFs {
+    pub fn new(pid: libc::pid_t) -> ProcFs {
+        ProcFs { pid }
+    }
+
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// Path of an entry.  eg. `path("status")` -> `/proc/<pid>/status`
+    pub fn path(&self, name: &str) -> PathBuf {
+        PathBuf::from(format!("/proc/{}/{}", self.pid, name))
+    }
+
+    fn read(&self, name: &str) -> Result<String> {
+        let path = self.path(name);
+        std::fs::read_to_string(&path).map_err(|e| Error::file("read", &path, e))
+    }
+
+    /// Fields of `/proc/<pid>/status`, in order.  eg. `("VmRSS", "1234 kB")`
+    pub fn status(&self) -> Result<Vec<(String, String)>> {
+        Ok(parse_status(&self.read("status")?))
+    }
+
+    /// One field of `/proc/<pid>/status`
+    pub fn status_field(&self, key: &str) -> Result<Option<String>> {
+        let status = self.status()?;
+        Ok(status.into_iter().find(|(k, _)| k == key).map(|(_, v)| v))
+    }
+
+    /// Open descriptors, and their targets, ordered by number.  eg. `(0, "/dev/null")`
+    pub fn fds(&self) -> Result<Vec<(RawFd, PathBuf)>> {
+        let dir = self.path("fd");
+        let mut ret = vec![];
+        for dent in std::fs::read_dir(&dir).map_err(|e| Error::file("readdir", &dir, e))? {
+            let path = dent.map_err(|e| Error::file("readdir", &dir, e))?.path();
+            let fd = match path.file_name().and_then(|n| n.to_str()?.parse().ok()) {
+                Some(fd) => fd,
+                None => continue,
+            };
+            match std::fs::read_link(&path) {
+                Ok(target) => ret.push((fd, target)),
+                // closed concurrently
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                Err(err) => return Err(Error::file("readlink", &path, err)),
+            }
+        }
+        ret.sort();
+        Ok(ret)
+    }
+
+    /// Mount points of the mount namespace of the process, ordered by mount ID.
+    pub fn mounts(&self) -> Result<Vec<MountInfo>> {
+        let mounts = Mounts::from_pid(self.pid)?;
+        let mut ret: Vec<MountInfo> = mounts.into_iter().cloned().collect();
+        ret.sort_by_key(|m| m.id);
+        Ok(ret)
+    }
+}
+
+fn parse_status(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.to_string(), v.trim().to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn status() {
+        let fields = parse_status("Name:\tcat\nUmask:\t0022\nVmRSS:\t    1024 kB\n");
+        assert_eq!(fields[0], ("Name".to_string(), "cat".to_string()));
+        assert_eq!(fields[2], ("VmRSS".to_string(), "1024 kB".to_string()));
+    }
+
+    #[test]
+    fn current() {
+        let proc = ProcFs::new(std::process::id() as libc::pid_t);
+        let pid = proc.status_field("Pid").unwrap();
+        assert_eq!(pid, Some(std::process::id().to_string()));
+
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let fds = proc.fds().unwrap();
+        assert!(fds.contains(&(file.as_raw_fd(), "/dev/null".into())));
+
+        let mounts = proc.mounts().unwrap();
+        assert!(mounts
+            .iter()
+            .any(|m| m.mount_point == std::path::Path::new("/")));
+    }
+}
@@ -0,0 +1,423 @@
+//! Sandbox profiles.  Re-usable configuration for `isolate`.
+//!
+//! ```toml
+//! net = false
+//! name = "build"          # $SANDBOX_NAME
+//! prompt = "(build) "     # prepended to $PS1
+//! virtualenv = false      # also set $VIRTUAL_ENV, as older versions did
+//! rw = ["/some/dir"]
+//! ro = ["/some/dir/src"]
+//! toolchains = true       # or a list. eg. ["/opt/arm-gcc"]
+//!
+//! [[hooks.prestart]]
+//! path = "/usr/local/bin/setup-fw"
+//! args = ["setup-fw", "--add"]
+//! env = ["PATH=/usr/bin:/bin"]
+//! timeout = 5
+//!
+//! [[hooks.poststop]]
+//! path = "/usr/local/bin/setup-fw"
+//! args = ["setup-fw", "--remove"]
+//! ```
+//!
+//! Mount paths, and the values of hook `env` entries, may refer to
+//! `${HOME}`, `${PWD}`, or `${XDG_RUNTIME_DIR}`.  eg. `rw = ["${HOME}/.cache"]`.
+//! Also to the run: `${run_id}` (as `$SANDBOX_ID`), `${date}` (`YYYY-MM-DD`, UTC),
+//! and `${profile}` (the file name without extension).  eg. for unique artifact paths
+//! of recurring CI jobs, `env = ["OUT=/srv/ci/${profile}/${date}-${run_id}"]`.
+//! Use `$$` for a literal `$`.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::warn;
+
+use super::config::{self, Document, Item, Pos, Table, Value};
+use super::err::Result;
+use super::hook::{HookCmd, Stage};
+use super::toolchain::Toolchains;
+
+/// Keys known at each level of a profile
+const ROOT_KEYS: &[&str] = &[
+    "net",
+    "name",
+    "prompt",
+    "virtualenv",
+    "rw",
+    "ro",
+    "toolchains",
+    "hooks",
+];
+const HOOKS_KEYS: &[&str] = &["prestart", "poststop"];
+const HOOK_KEYS: &[&str] = &["path", "args", "env", "timeout"];
+
+/// A problem which does not prevent a profile from being used.  eg. an unknown key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub pos: Pos,
+    pub msg: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.pos, self.msg)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Profile {
+    /// Allow network access
+    pub net: Option<bool>,
+    /// `$SANDBOX_NAME` of the command
+    pub name: Option<String>,
+    /// Prepended to `$PS1` of the command
+    pub prompt: Option<String>,
+    /// Also set `$VIRTUAL_ENV`
+    pub virtualenv: Option<bool>,
+    /// Writable directories
+    pub rw: Vec<PathBuf>,
+    /// Read-only directories
+    pub ro: Vec<PathBuf>,
+    /// Toolchain roots.  `true` to detect well known roots, or a list
+    pub toolchains: Option<Toolchains>,
+    pub prestart: Vec<HookCmd>,
+    pub poststop: Vec<HookCmd>,
+}
+
+impl Profile {
+    /// Read and parse a profile file.  Warnings are logged.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Profile> {
+        Self::from_checked(&Document::load(path)?)
+    }
+
+    /// Parse profile text.  `name` is used in messages.  Warnings are logged.
+    pub fn parse<P: AsRef<Path>>(text: &str, name: P) -> Result<Profile> {
+        Self::from_checked(&Document::parse(text, name)?)
+    }
+
+    fn from_checked(doc: &Document) -> Result<Profile> {
+        for warning in Self::lint(doc) {
+            warn!("{}:{}", doc.name.display(), warning);
+        }
+        Self::from_document(doc)
+    }
+
+    /// Find keys which are not part of the profile schema.
+    /// Type errors are found by `from_document()`.
+    pub fn lint(doc: &Document) -> Vec<Warning> {
+        let mut ret = vec![];
+        unknown_keys(&doc.root, "", ROOT_KEYS, &mut ret);
+        if let Some(hooks) = doc.root.get("hooks").and_then(|i| i.value.as_table()) {
+            unknown_keys(hooks, "hooks.", HOOKS_KEYS, &mut ret);
+            for stage in HOOKS_KEYS {
+                let hooks = hooks.get(*stage).and_then(|i| i.value.as_array());
+                for hook in hooks.unwrap_or_default() {
+                    if let Value::Table(table) = hook {
+                        let prefix = format!("hooks.{}.", stage);
+                        unknown_keys(table, &prefix, HOOK_KEYS, &mut ret);
+                    }
+                }
+            }
+        }
+        ret.sort_by_key(|w| (w.pos.line, w.pos.col));
+        ret
+    }
+
+    pub fn from_document(doc: &Document) -> Result<Profile> {
+        let mut ret = Profile::default();
+        let root = &doc.root;
+
+        if let Some(item) = root.get("net") {
+            ret.net = Some(get_bool(doc, item)?);
+        }
+        if let Some(item) = root.get("name") {
+            ret.name = Some(get_str(doc, item)?);
+        }
+        if let Some(item) = root.get("prompt") {
+            ret.prompt = Some(get_str(doc, item)?);
+        }
+        if let Some(item) = root.get("virtualenv") {
+            ret.virtualenv = Some(get_bool(doc, item)?);
+        }
+        if let Some(item) = root.get("rw") {
+            ret.rw = get_paths(doc, item)?;
+        }
+        if let Some(item) = root.get("ro") {
+            ret.ro = get_paths(doc, item)?;
+        }
+        if let Some(item) = root.get("toolchains") {
+            ret.toolchains = match &item.value {
+                Value::Bool(true) => Some(Toolchains::Detect),
+                Value::Bool(false) => None,
+                Value::Array(_) => Some(Toolchains::Paths(get_paths(doc, item)?)),
+                _ => return Err(mismatch(doc, item, "boolean or array of strings")),
+            };
+        }
+        if let Some(item) = root.get("hooks") {
+            let hooks = get_table(doc, item)?;
+            if let Some(item) = hooks.get("prestart") {
+                ret.prestart = get_hooks(doc, item)?;
+            }
+            if let Some(item) = hooks.get("poststop") {
+                ret.poststop = get_hooks(doc, item)?;
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Hook commands for a lifecycle stage
+    pub fn hooks(&self, stage: Stage) -> &[HookCmd] {
+        match stage {
+            Stage::Prestart => &self.prestart,
+            Stage::Poststop => &self.poststop,
+        }
+    }
+}
+
+fn unknown_keys(table: &Table, prefix: &str, known: &[&str], out: &mut Vec<Warning>) {
+    for (key, item) in table {
+        if known.contains(&key.as_str()) {
+            continue;
+        }
+        let mut msg = format!("unknown key \"{}{}\"", prefix, key);
+        let nearest = known
+            .iter()
+            .map(|k| (distance(k, key), k))
+            .min()
+            .filter(|(d, _)| *d <= 2 && *d < key.len());
+        if let Some((_, k)) = nearest {
+            msg.push_str(&format!(", did you mean \"{}{}\"?", prefix, k));
+        }
+        out.push(Warning { pos: item.pos, msg });
+    }
+}
+
+/// Levenshtein edit distance
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let subst = prev[j] + usize::from(ca != *cb);
+            cur.push(subst.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+fn mismatch(doc: &Document, item: &Item, want: &str) -> crate::err::Error {
+    doc.error(
+        item.pos,
+        format!("expected {} found {}", want, item.value.type_name()),
+    )
+}
+
+fn get_bool(doc: &Document, item: &Item) -> Result<bool> {
+    item.value
+        .as_bool()
+        .ok_or_else(|| mismatch(doc, item, "boolean"))
+}
+
+fn get_str(doc: &Document, item: &Item) -> Result<String> {
+    item.value
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| mismatch(doc, item, "string"))
+}
+
+fn get_table<'a>(doc: &Document, item: &'a Item) -> Result<&'a Table> {
+    item.value
+        .as_table()
+        .ok_or_else(|| mismatch(doc, item, "table"))
+}
+
+fn get_strs(doc: &Document, item: &Item) -> Result<Vec<String>> {
+    item.value
+        .as_array()
+        .and_then(|arr| {
+            arr.iter()
+                .map(|v| v.as_str().map(String::from))
+                .collect::<Option<Vec<_>>>()
+        })
+        .ok_or_else(|| mismatch(doc, item, "array of strings"))
+}
+
+fn expand(doc: &Document, item: &Item, text: &str) -> Result<String> {
+    let lookup = |name: &str| match name {
+        "profile" => doc
+            .name
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned()),
+        _ => config::lookup_env(name),
+    };
+    config::interpolate(text, lookup).map_err(|msg| doc.error(item.pos, msg))
+}
+
+fn get_paths(doc: &Document, item: &Item) -> Result<Vec<PathBuf>> {
+    get_strs(doc, item)?
+        .iter()
+        .map(|s| expand(doc, item, s).map(PathBuf::from))
+        .collect()
+}
+
+/// Expand only the value of `NAME=value`, so that the name can not be changed
+fn get_env(doc: &Document, item: &Item) -> Result<Vec<String>> {
+    get_strs(doc, item)?
+        .into_iter()
+        .map(|ent| match ent.split_once('=') {
+            Some((name, value)) => Ok(format!("{}={}", name, expand(doc, item, value)?)),
+            None => Ok(ent),
+        })
+        .collect()
+}
+
+fn get_hooks(doc: &Document, item: &Item) -> Result<Vec<HookCmd>> {
+    let arr = item
+        .value
+        .as_array()
+        .ok_or_else(|| mismatch(doc, item, "array of tables"))?;
+    let mut ret = vec![];
+    for value in arr {
+        let table = match value {
+            Value::Table(table) => table,
+            _ => return Err(mismatch(doc, item, "array of tables")),
+        };
+        let path = table
+            .get("path")
+            .ok_or_else(|| doc.error(item.pos, "hook missing \"path\""))?;
+        let mut cmd = HookCmd::new(get_str(doc, path)?);
+        if let Some(item) = table.get("args") {
+            cmd.args = get_strs(doc, item)?;
+        }
+        if let Some(item) = table.get("env") {
+            cmd.env = get_env(doc, item)?;
+        }
+        if let Some(item) = table.get("timeout") {
+            let secs = item
+                .value
+                .as_int()
+                .filter(|i| *i > 0)
+                .ok_or_else(|| mismatch(doc, item, "positive integer"))?;
+            cmd.timeout = Some(Duration::from_secs(secs as u64));
+        }
+        ret.push(cmd);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let doc = Document::parse(
+            r#"
+net = true
+name = "test"
+rw = ["/a", "/b"]
+[[hooks.prestart]]
+path = "/bin/true"
+timeout = 2
+[[hooks.poststop]]
+path = "/bin/false"
+args = ["false", "x"]
+"#,
+            "test",
+        )
+        .unwrap();
+        let prof = Profile::from_document(&doc).unwrap();
+        assert_eq!(prof.net, Some(true));
+        assert_eq!(prof.name.as_deref(), Some("test"));
+        assert_eq!(prof.prompt, None);
+        assert_eq!(prof.rw, [PathBuf::from("/a"), PathBuf::from("/b")]);
+        assert!(prof.ro.is_empty());
+        assert_eq!(prof.prestart.len(), 1);
+        assert_eq!(prof.prestart[0].timeout, Some(Duration::from_secs(2)));
+        assert_eq!(prof.hooks(Stage::Poststop)[0].args, ["false", "x"]);
+        assert_eq!(prof.toolchains, None);
+
+        let doc = Document::parse("toolchains = true", "test").unwrap();
+        let prof = Profile::from_document(&doc).unwrap();
+        assert_eq!(prof.toolchains, Some(Toolchains::Detect));
+        let doc = Document::parse("toolchains = [\"/opt/gcc\"]", "test").unwrap();
+        let prof = Profile::from_document(&doc).unwrap();
+        assert_eq!(
+            prof.toolchains,
+            Some(Toolchains::Paths(vec!["/opt/gcc".into()]))
+        );
+        let doc = Document::parse("toolchains = 1", "test").unwrap();
+        Profile::from_document(&doc).unwrap_err();
+    }
+
+    #[test]
+    fn interpolate() {
+        let doc = Document::parse(
+            r#"
+ro = ["${PWD}/src", "/cost/$$5"]
+[[hooks.prestart]]
+path = "/bin/true"
+env = ["DIR=${PWD}", "PLAIN"]
+"#,
+            "test",
+        )
+        .unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        let prof = Profile::from_document(&doc).unwrap();
+        assert_eq!(prof.ro, [cwd.join("src"), PathBuf::from("/cost/$5")]);
+        assert_eq!(
+            prof.prestart[0].env,
+            [format!("DIR={}", cwd.display()), "PLAIN".to_string()]
+        );
+
+        let doc = Document::parse("rw = [\"${PATH}\"]", "test").unwrap();
+        let err = Profile::from_document(&doc).unwrap_err().to_string();
+        assert!(err.contains("${PATH} may not be expanded"), "{}", err);
+        assert!(err.contains("at 1:6"), "{}", err);
+        let doc =
+            Document::parse("rw = [\"/out/${profile}/${run_id}\"]", "ci/nightly.toml").unwrap();
+        let prof = Profile::from_document(&doc).unwrap();
+        let want = format!("/out/nightly/{}", crate::id::current());
+        assert_eq!(prof.rw, [PathBuf::from(want)]);
+    }
+
+    #[test]
+    fn lint() {
+        let doc = Document::parse(
+            r#"
+net = true
+rww = ["/a"]
+[[hooks.prestart]]
+path = "/bin/true"
+timout = 2
+[hooks.other]
+"#,
+            "test",
+        )
+        .unwrap();
+        let msgs: Vec<String> = Profile::lint(&doc).iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            msgs,
+            [
+                "3:7: unknown key \"rww\", did you mean \"rw\"?",
+                "6:10: unknown key \"hooks.prestart.timout\", did you mean \"hooks.prestart.timeout\"?",
+                "7:1: unknown key \"hooks.other\"",
+            ]
+        );
+        assert_eq!(distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn bad_type() {
+        let doc = Document::parse("net = \"yes\"", "test").unwrap();
+        let err = Profile::from_document(&doc).unwrap_err().to_string();
+        assert!(
+            err.contains("expected boolean found string at 1:7"),
+            "{}",
+            err
+        );
+    }
+}
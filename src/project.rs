@@ -0,0 +1,156 @@
+//! Per-project profiles, discovered from the working directory.
+//!
+//! A `.sandbox.toml` found in `$PWD`, or any parent directory, is only used
+//! after its current contents have been allowed by the user.  eg. with `sandbox allow`.
+//! A copy of the allowed contents is kept under `$XDG_DATA_HOME/sandbox/allow/`.
+//! Any change to the file must be allowed again.
+
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use super::err::{Error, Result};
+use super::util;
+
+/// Name of a per-project profile
+pub const PROJECT_FILE: &str = ".sandbox.toml";
+
+/// Search `dir`, then its parents, for `PROJECT_FILE`
+pub fn discover<P: AsRef<Path>>(dir: P) -> Option<PathBuf> {
+    dir.as_ref()
+        .ancestors()
+        .map(|d| d.join(PROJECT_FILE))
+        .find(|f| f.is_file())
+}
+
+/// Escape an absolute path into a single file name
+fn record_name(path: &Path) -> String {
+    let mut ret = String::new();
+    for b in path.as_os_str().as_bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'_' | b'-' => ret.push(*b as char),
+            _ => ret.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    ret
+}
+
+/// Record of project files which the user has allowed
+#[derive(Debug, Clone)]
+pub struct AllowList {
+    dir: PathBuf,
+}
+
+impl AllowList {
+    /// The allow list of the calling user
+    pub fn new() -> Result<AllowList> {
+        let data = match (std::env::var_os("XDG_DATA_HOME"), std::env::var_os("HOME")) {
+            (Some(dir), _) if !dir.is_empty() => PathBuf::from(dir),
+            (_, Some(home)) => Path::new(&home).join(".local").join("share"),
+            _ => return Err(Error::os("No $HOME", io::ErrorKind::NotFound.into())),
+        };
+        Ok(Self::with_dir(data.join("sandbox").join("allow")))
+    }
+
+    /// An allow list stored in `dir`
+    pub fn with_dir<P: Into<PathBuf>>(dir: P) -> AllowList {
+        AllowList { dir: dir.into() }
+    }
+
+    fn record(&self, file: &Path) -> Result<(PathBuf, PathBuf)> {
+        let file = file
+            .canonicalize()
+            .map_err(|e| Error::file("canonicalize", file, e))?;
+        let record = self.dir.join(record_name(&file));
+        Ok((file, record))
+    }
+
+    /// Contents of `file`, if these contents have been allowed
+    pub fn read_allowed<P: AsRef<Path>>(&self, file: P) -> Result<Option<String>> {
+        let (file, record) = self.record(file.as_ref())?;
+        let meta = match std::fs::metadata(&record) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(Error::file("stat", &record, err)),
+        };
+        if meta.uid() != util::getuid() {
+            // when SUID, do not trust records of another user
+            warn!("Ignore {} not owned by caller", record.display());
+            return Ok(None);
+        }
+        let text = std::fs::read_to_string(&file).map_err(|e| Error::file("read", &file, e))?;
+        let allowed = std::fs::read(&record).map_err(|e| Error::file("read", &record, e))?;
+        if allowed != text.as_bytes() {
+            debug!("{} changed since allowed", file.display());
+            return Ok(None);
+        }
+        Ok(Some(text))
+    }
+
+    /// Allow the current contents of `file`
+    pub fn allow<P: AsRef<Path>>(&self, file: P) -> Result<()> {
+        let (file, record) = self.record(file.as_ref())?;
+        let text = std::fs::read(&file).map_err(|e| Error::file("read", &file, e))?;
+        util::mkdirs(&self.dir)?;
+        std::fs::write(&record, text).map_err(|e| Error::file("write", &record, e))
+    }
+
+    /// Forget `file`.  Returns true if it was previously allowed.
+    pub fn deny<P: AsRef<Path>>(&self, file: P) -> Result<bool> {
+        let (_file, record) = self.record(file.as_ref())?;
+        match std::fs::remove_file(&record) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(Error::file("remove", &record, err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn discovery() {
+        let tdir = TempDir::new().unwrap();
+        let top = tdir.path().canonicalize().unwrap();
+        let deep = util::mkdirs(top.join("a").join("b")).unwrap();
+        assert_eq!(discover(&deep), None);
+
+        util::write_new_file(top.join("a").join(PROJECT_FILE), "net = false\n").unwrap();
+        assert_eq!(discover(&deep), Some(top.join("a").join(PROJECT_FILE)));
+    }
+
+    #[test]
+    fn allow() {
+        let tdir = TempDir::new().unwrap();
+        let file = tdir.path().join(PROJECT_FILE);
+        let list = AllowList::with_dir(tdir.path().join("allow"));
+        util::write_new_file(&file, "net = false\n").unwrap();
+
+        assert_eq!(list.read_allowed(&file).unwrap(), None);
+        list.allow(&file).unwrap();
+        assert_eq!(
+            list.read_allowed(&file).unwrap().as_deref(),
+            Some("net = false\n")
+        );
+
+        std::fs::write(&file, "net = true\n").unwrap();
+        assert_eq!(list.read_allowed(&file).unwrap(), None);
+
+        list.allow(&file).unwrap();
+        assert!(list.read_allowed(&file).unwrap().is_some());
+        assert!(list.deny(&file).unwrap());
+        assert!(!list.deny(&file).unwrap());
+        assert_eq!(list.read_allowed(&file).unwrap(), None);
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(record_name(Path::new("/a b/c.d")), "%2Fa%20b%2Fc.d");
+    }
+}
@@ -0,0 +1,296 @@
+//! Registry of detached sandboxes.
+//!
+//! A detached sandbox (eg. `isolate --detach`) is recorded with one JSON file,
+//! named by its ID, in a per-user directory.
+//! Normally `$XDG_RUNTIME_DIR/sandbox/running/`, or `/run/sandbox/running/` for root.
+//! Output of the sandboxed command is written beside, to `<id>.log`,
+//! which remains after the entry is removed.
+//!
+//! ```json
+//! {"args":["sleep","100"],"cwd":"/src","id":"1a2b3c4d",
+//!  "log":"/run/user/1000/sandbox/running/1a2b3c4d.log","name":"isolate","pid":1240,
+//!  "started":1700000000,"supervisor":1234,"supervisor_start":8812345}
+//! ```
+
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+
+use super::config::{Document, Item, Table, Value};
+use super::err::{Error, Result};
+
+/// A detached sandbox
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub id: String,
+    /// `$SANDBOX_NAME`
+    pub name: String,
+    /// Host PID of container process 1
+    pub pid: libc::pid_t,
+    /// Host PID of the process which launched, and waits for, the container
+    pub supervisor: libc::pid_t,
+    /// Start time of the supervisor, in clock ticks after boot.
+    /// Distinguishes a re-used PID.
+    pub supervisor_start: u64,
+    pub args: Vec<String>,
+    pub cwd: PathBuf,
+    /// Output of the command
+    pub log: PathBuf,
+    /// Seconds since the UNIX epoch
+    pub started: u64,
+}
+
+/// Start time of a process, in clock ticks after boot.  `None` if it does not exist.
+pub fn start_time(pid: libc::pid_t) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // skip "pid (comm)", which may contain spaces.  starttime is field 22
+    stat.rsplit_once(") ")?.1.split(' ').nth(19)?.parse().ok()
+}
+
+fn exists(pid: libc::pid_t) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn item(value: Value) -> Item {
+    Item {
+        pos: Default::default(),
+        value,
+    }
+}
+
+impl Entry {
+    /// A new entry for the calling process, as supervisor, started now.
+    pub fn new<S: Into<String>>(id: S, pid: libc::pid_t) -> Entry {
+        let supervisor = std::process::id() as libc::pid_t;
+        Entry {
+            id: id.into(),
+            pid,
+            supervisor,
+            supervisor_start: start_time(supervisor).unwrap_or(0),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            ..Default::default()
+        }
+    }
+
+    /// Is the supervisor still running?
+    pub fn supervised(&self) -> bool {
+        exists(self.supervisor) && start_time(self.supervisor) == Some(self.supervisor_start)
+    }
+
+    /// Is the supervisor, or container process 1, still running?
+    ///
+    /// Without a supervisor, the container PID may have been re-used.
+    pub fn alive(&self) -> bool {
+        self.supervised() || exists(self.pid)
+    }
+
+    pub fn to_json(&self) -> String {
+        let int = |v: i64| item(Value::Int(v));
+        let string = |s: &str| item(Value::Str(s.to_string()));
+        let mut table = Table::new();
+        table.insert("id".into(), string(&self.id));
+        table.insert("name".into(), string(&self.name));
+        table.insert("pid".into(), int(self.pid as i64));
+        table.insert("supervisor".into(), int(self.supervisor as i64));
+        table.insert("supervisor_start".into(), int(self.supervisor_start as i64));
+        table.insert(
+            "args".into(),
+            item(Value::Array(
+                self.args.iter().cloned().map(Value::Str).collect(),
+            )),
+        );
+        table.insert("cwd".into(), string(&self.cwd.to_string_lossy()));
+        table.insert("log".into(), string(&self.log.to_string_lossy()));
+        table.insert("started".into(), int(self.started as i64));
+        Value::Table(table).to_json()
+    }
+
+    /// Parse.  Unknown keys are ignored
+    pub fn from_json(text: &str, name: &Path) -> Result<Entry> {
+        let root = Document::parse_json(text, name)?.root;
+        let get_str = |key| {
+            root.get(key)
+                .and_then(|i| i.value.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let get_int = |key| root.get(key).and_then(|i| i.value.as_int()).unwrap_or(0);
+        let args = root.get("args").and_then(|i| i.value.as_array());
+        Ok(Entry {
+            id: get_str("id"),
+            name: get_str("name"),
+            pid: get_int("pid") as libc::pid_t,
+            supervisor: get_int("supervisor") as libc::pid_t,
+            supervisor_start: get_int("supervisor_start") as u64,
+            args: args
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|v| v.as_str().map(String::from))
+                .collect(),
+            cwd: get_str("cwd").into(),
+            log: get_str("log").into(),
+            started: get_int("started") as u64,
+        })
+    }
+}
+
+/// Directory of `Entry` files
+#[derive(Debug, Clone)]
+pub struct Registry {
+    dir: PathBuf,
+}
+
+impl Registry {
+    /// The registry of the calling user
+    pub fn new() -> Result<Registry> {
+        let base = match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("sandbox"),
+            _ if unsafe { libc::getuid() } == 0 => PathBuf::from("/run/sandbox"),
+            _ => {
+                return Err(Error::os(
+                    "No $XDG_RUNTIME_DIR",
+                    io::ErrorKind::NotFound.into(),
+                ))
+            }
+        };
+        Ok(Self::with_dir(base.join("running")))
+    }
+
+    /// A registry stored in `dir`
+    pub fn with_dir<P: Into<PathBuf>>(dir: P) -> Registry {
+        Registry { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Create the registry directory if necessary
+    pub fn create(&self) -> Result<()> {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)
+            .map_err(|e| Error::file("mkdir", &self.dir, e))
+    }
+
+    fn entry_file(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    /// Where output of sandbox `id` is written
+    pub fn log_file(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.log", id))
+    }
+
+    /// Record a detached sandbox
+    pub fn add(&self, entry: &Entry) -> Result<()> {
+        self.create()?;
+        let file = self.entry_file(&entry.id);
+        // replace atomically, so that a reader never sees a partial entry
+        let tmp = self.dir.join(format!(".{}.json.tmp", entry.id));
+        std::fs::write(&tmp, entry.to_json()).map_err(|e| Error::file("write", &tmp, e))?;
+        std::fs::rename(&tmp, &file).map_err(|e| Error::file("rename", &file, e))?;
+        debug!("Registered {}", file.display());
+        Ok(())
+    }
+
+    /// Remove the entry of sandbox `id`.  The log file remains.
+    /// Returns false if there was no entry.
+    pub fn remove(&self, id: &str) -> Result<bool> {
+        let file = self.entry_file(id);
+        match std::fs::remove_file(&file) {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(Error::file("remove", &file, err)),
+        }
+        Ok(true)
+    }
+
+    /// All entries, ordered by ID.  Including any which are no longer `alive()`.
+    pub fn list(&self) -> Result<Vec<Entry>> {
+        let dir = match std::fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(Error::file("readdir", &self.dir, err)),
+        };
+        let mut ret = vec![];
+        for dent in dir {
+            let path = dent
+                .map_err(|e| Error::file("readdir", &self.dir, e))?
+                .path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with('.') || !name.ends_with(".json") {
+                continue;
+            }
+            let entry = std::fs::read_to_string(&path)
+                .map_err(|e| Error::file("read", &path, e))
+                .and_then(|text| Entry::from_json(&text, &path));
+            match entry {
+                Ok(entry) => ret.push(entry),
+                // removed concurrently, or corrupt
+                Err(err) => warn!("{}", err),
+            }
+        }
+        ret.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(ret)
+    }
+
+    /// The entry with ID `key`, or else the only entry with name `key`
+    pub fn find(&self, key: &str) -> Result<Option<Entry>> {
+        let all = self.list()?;
+        if let Some(entry) = all.iter().find(|e| e.id == key) {
+            return Ok(Some(entry.clone()));
+        }
+        let mut named = all.into_iter().filter(|e| e.name == key);
+        match (named.next(), named.next()) {
+            (Some(_), Some(_)) => Err(Error::os(
+                format!("More than one sandbox named {:?}.  Use an ID", key),
+                io::ErrorKind::InvalidInput.into(),
+            )),
+            (entry, _) => Ok(entry),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry() {
+        let tdir = crate::tempdir::TempDir::new().unwrap();
+        let reg = Registry::with_dir(tdir.path().join("running"));
+        assert_eq!(reg.list().unwrap(), vec![]);
+
+        let mut entry = Entry::new("42", 1);
+        entry.name = "web".into();
+        entry.args = vec!["sleep".into(), "100".into()];
+        entry.cwd = "/src".into();
+        entry.log = reg.log_file("42");
+        assert!(entry.supervised());
+        reg.add(&entry).unwrap();
+
+        let mut other = Entry::new("43", 1);
+        other.name = "web".into();
+        reg.add(&other).unwrap();
+
+        assert_eq!(reg.list().unwrap(), vec![entry.clone(), other]);
+        assert_eq!(reg.find("42").unwrap(), Some(entry));
+        reg.find("web").unwrap_err();
+        assert!(reg.remove("43").unwrap());
+        assert!(!reg.remove("43").unwrap());
+        assert_eq!(reg.find("web").unwrap().unwrap().id, "42");
+        assert_eq!(reg.find("nope").unwrap(), None);
+    }
+}
@@ -0,0 +1,268 @@
+//! Bounded retries of operations which may fail transiently.
+//!
+//! eg. `umount2()` failing with `EBUSY` while a just exited process still holds a reference,
+//! or a network device which is still being torn down.
+//! Only failures with an errno known to be transient for the particular operation are retried.
+//! Each retry is logged at debug level.
+//!
+//! Also `Restart`, when a sandboxed command is run again.  eg. isolate --restart
+
+use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+
+use super::err::{Errno, Error, Result};
+
+/// How often, and how long, to retry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first.  1 disables retries.
+    pub attempts: u32,
+    /// Delay before the first retry.  Doubled for each following retry.
+    pub delay: Duration,
+    /// Upper bound of any single delay
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 4,
+            delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(200),
+        }
+    }
+}
+
+static ATTEMPTS: AtomicU32 = AtomicU32::new(0);
+static DELAY_US: AtomicU64 = AtomicU64::new(0);
+static MAX_DELAY_US: AtomicU64 = AtomicU64::new(0);
+
+/// Replace the policy used by `retry()` in this process, and any later `fork()`s.
+pub fn set_default(policy: RetryPolicy) {
+    DELAY_US.store(policy.delay.as_micros() as u64, Ordering::SeqCst);
+    MAX_DELAY_US.store(policy.max_delay.as_micros() as u64, Ordering::SeqCst);
+    // zero means not set
+    ATTEMPTS.store(policy.attempts.max(1), Ordering::SeqCst);
+}
+
+/// Randomly pick between half and all of `delay`.
+/// So that processes which failed together do not retry in lock step.
+fn jitter(delay: Duration) -> Duration {
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0)
+        ^ std::process::id().wrapping_mul(2654435761);
+    let half = delay / 2;
+    half + half.mul_f64((seed % 1000) as f64 / 1000.0)
+}
+
+impl RetryPolicy {
+    /// Never retry
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// The policy set by `set_default()`, or `RetryPolicy::default()`
+    pub fn current() -> RetryPolicy {
+        match ATTEMPTS.load(Ordering::SeqCst) {
+            0 => Default::default(),
+            attempts => RetryPolicy {
+                attempts,
+                delay: Duration::from_micros(DELAY_US.load(Ordering::SeqCst)),
+                max_delay: Duration::from_micros(MAX_DELAY_US.load(Ordering::SeqCst)),
+            },
+        }
+    }
+
+    /// Call `op` until it succeeds, fails with an errno not in `transient`,
+    /// or all attempts are used.  `what` names the operation in log messages.
+    pub fn run<T, F>(&self, what: &str, transient: &[Errno], mut op: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let mut delay = self.delay;
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Ok(ret) => {
+                    if attempt > 1 {
+                        debug!("{} succeeded after {} retries", what, attempt - 1);
+                    }
+                    return Ok(ret);
+                }
+                Err(err) if attempt < self.attempts && is_transient(&err, transient) => {
+                    let pause = jitter(delay);
+                    debug!(
+                        "{} retry {}/{} in {:?} : {}",
+                        what,
+                        attempt,
+                        self.attempts - 1,
+                        pause,
+                        err
+                    );
+                    thread::sleep(pause);
+                    delay = (delay * 2).min(self.max_delay);
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+fn is_transient(err: &Error, transient: &[Errno]) -> bool {
+    matches!(err.errno(), Some(errno) if transient.contains(&errno))
+}
+
+/// When a sandboxed command which exits is run again
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Restart {
+    #[default]
+    Never,
+    /// After a non-zero exit.  At most `max` times, if given.
+    OnFailure { max: Option<u32> },
+}
+
+impl Restart {
+    /// Delay before the first restart.  Doubled for each consecutive restart.
+    pub const DELAY: Duration = Duration::from_secs(1);
+    /// Upper bound of any delay.  A run which lasts this long resets the delay.
+    pub const MAX_DELAY: Duration = Duration::from_secs(60);
+
+    /// Whether to run again, after `restarts` so far, and an exit with `code`
+    pub fn again(&self, restarts: u32, code: i32) -> bool {
+        match self {
+            Restart::Never => false,
+            Restart::OnFailure { max: None } => code != 0,
+            Restart::OnFailure { max: Some(max) } => code != 0 && restarts < *max,
+        }
+    }
+
+    /// Delay before a restart, after `consecutive` earlier restarts
+    pub fn delay(consecutive: u32) -> Duration {
+        Self::DELAY
+            .checked_mul(1 << consecutive.min(16))
+            .unwrap_or(Self::MAX_DELAY)
+            .min(Self::MAX_DELAY)
+    }
+}
+
+impl std::str::FromStr for Restart {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Restart> {
+        let (mode, max) = match s.split_once(':') {
+            Some((mode, max)) => (mode, Some(max.parse()?)),
+            None => (s, None),
+        };
+        match (mode, max) {
+            ("no", None) => Ok(Restart::Never),
+            ("on-failure", max) => Ok(Restart::OnFailure { max }),
+            _ => Err(Error::os(
+                format!("Expected \"no\" or \"on-failure[:max]\", not {:?}", s),
+                std::io::ErrorKind::InvalidInput.into(),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Restart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Restart::Never => write!(f, "no"),
+            Restart::OnFailure { max: None } => write!(f, "on-failure"),
+            Restart::OnFailure { max: Some(max) } => write!(f, "on-failure:{}", max),
+        }
+    }
+}
+
+/// `RetryPolicy::run()` with the current default policy
+pub fn retry<T, F>(what: &str, transient: &[Errno], op: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    RetryPolicy::current().run(what, transient, op)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn busy() -> Error {
+        Error::os("test", io::Error::from_raw_os_error(libc::EBUSY))
+    }
+
+    #[test]
+    fn bounded() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+        };
+
+        let mut calls = 0;
+        let ret = policy.run("test", &[Errno::EBUSY], || {
+            calls += 1;
+            if calls < 3 {
+                Err(busy())
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(ret.unwrap(), 3);
+
+        calls = 0;
+        let ret: Result<()> = policy.run("test", &[Errno::EBUSY], || {
+            calls += 1;
+            Err(busy())
+        });
+        assert!(ret.is_err());
+        assert_eq!(calls, 3);
+
+        // not transient
+        calls = 0;
+        let ret: Result<()> = policy.run("test", &[Errno::EAGAIN], || {
+            calls += 1;
+            Err(busy())
+        });
+        assert!(ret.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn restart() {
+        assert_eq!("no".parse::<Restart>().unwrap(), Restart::Never);
+        let limited: Restart = "on-failure:2".parse().unwrap();
+        assert_eq!(limited, Restart::OnFailure { max: Some(2) });
+        assert_eq!(limited.to_string(), "on-failure:2");
+        assert!("always".parse::<Restart>().is_err());
+        assert!("on-failure:x".parse::<Restart>().is_err());
+
+        assert!(limited.again(1, 1));
+        assert!(!limited.again(2, 1));
+        assert!(!limited.again(0, 0));
+        assert!("on-failure".parse::<Restart>().unwrap().again(100, 1));
+        assert!(!Restart::Never.again(0, 1));
+
+        assert_eq!(Restart::delay(0), Duration::from_secs(1));
+        assert_eq!(Restart::delay(3), Duration::from_secs(8));
+        assert_eq!(Restart::delay(40), Restart::MAX_DELAY);
+    }
+
+    #[test]
+    fn jittered() {
+        for _ in 0..10 {
+            let pause = jitter(Duration::from_millis(10));
+            assert!(pause >= Duration::from_millis(5), "{:?}", pause);
+            assert!(pause <= Duration::from_millis(10), "{:?}", pause);
+        }
+    }
+}
@@ -0,0 +1,454 @@
+//! Minimal rtnetlink (NETLINK_ROUTE) client.  For what the `ioctl()`s of `net::IfConfig`
+//! can not do.  eg. create a veth pair, or list addresses.  Also carries nfnetlink batches
+//! for `nft`.
+//!
+//! cf. rtnetlink(7), and linux/rtnetlink.h, linux/if_link.h and linux/netfilter/nfnetlink.h
+
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+use log::debug;
+
+use super::err::{Error, Result};
+
+// netlink message types and flags
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+/// NLM_F_ROOT | NLM_F_MATCH
+const NLM_F_DUMP: u16 = 0x300;
+pub const NLM_F_REPLACE: u16 = 0x100;
+pub const NLM_F_EXCL: u16 = 0x200;
+pub const NLM_F_CREATE: u16 = 0x400;
+/// Attribute type flag, for nested attributes which are checked strictly
+pub const NLA_F_NESTED: u16 = 0x8000;
+
+// nfnetlink batches
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+
+pub const RTM_NEWLINK: u16 = 16;
+pub const RTM_DELLINK: u16 = 17;
+pub const RTM_GETLINK: u16 = 18;
+pub const RTM_SETLINK: u16 = 19;
+pub const RTM_NEWADDR: u16 = 20;
+pub const RTM_DELADDR: u16 = 21;
+pub const RTM_GETADDR: u16 = 22;
+pub const RTM_NEWROUTE: u16 = 24;
+pub const RTM_DELROUTE: u16 = 25;
+pub const RTM_GETROUTE: u16 = 26;
+
+// link attributes
+pub const IFLA_ADDRESS: u16 = 1;
+pub const IFLA_IFNAME: u16 = 3;
+pub const IFLA_LINKINFO: u16 = 18;
+pub const IFLA_NET_NS_PID: u16 = 19;
+pub const IFLA_NET_NS_FD: u16 = 28;
+pub const IFLA_INFO_KIND: u16 = 1;
+pub const IFLA_INFO_DATA: u16 = 2;
+pub const VETH_INFO_PEER: u16 = 1;
+
+// address attributes
+pub const IFA_ADDRESS: u16 = 1;
+pub const IFA_LOCAL: u16 = 2;
+pub const IFA_BROADCAST: u16 = 4;
+
+// route attributes, and values of `struct rtmsg`
+pub const RTA_DST: u16 = 1;
+pub const RTA_OIF: u16 = 4;
+pub const RTA_GATEWAY: u16 = 5;
+pub const RTA_TABLE: u16 = 15;
+pub const RT_TABLE_MAIN: u8 = 254;
+pub const RTPROT_BOOT: u8 = 3;
+pub const RT_SCOPE_UNIVERSE: u8 = 0;
+pub const RT_SCOPE_LINK: u8 = 253;
+/// When deleting, any scope
+pub const RT_SCOPE_NOWHERE: u8 = 255;
+pub const RTN_UNICAST: u8 = 1;
+
+const HEADER_LEN: usize = 16;
+
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Encode one request.  In host byte order.
+pub struct Request {
+    buf: Vec<u8>,
+}
+
+impl Request {
+    pub fn new(mtype: u16, flags: u16) -> Request {
+        Self::with_flags(mtype, flags | NLM_F_REQUEST | NLM_F_ACK)
+    }
+
+    /// Request all objects of a type.  eg. `RTM_GETADDR` for all addresses.  cf. `Rtnl::fetch()`
+    pub fn dump(mtype: u16) -> Request {
+        Self::with_flags(mtype, NLM_F_REQUEST | NLM_F_DUMP)
+    }
+
+    fn with_flags(mtype: u16, flags: u16) -> Request {
+        let mut buf = vec![0; HEADER_LEN];
+        buf[4..6].copy_from_slice(&mtype.to_ne_bytes());
+        buf[6..8].copy_from_slice(&flags.to_ne_bytes());
+        Request { buf }
+    }
+
+    /// `struct nfgenmsg`, which begins nfnetlink requests.  `res_id` is big endian.
+    pub fn nfgenmsg(&mut self, family: u8, res_id: u16) -> &mut Self {
+        // family, version (NFNETLINK_V0)
+        self.buf.extend_from_slice(&[family, 0]);
+        self.buf.extend_from_slice(&res_id.to_be_bytes());
+        self
+    }
+
+    /// `struct ifinfomsg`, which begins link requests and `VETH_INFO_PEER`
+    pub fn ifinfomsg(&mut self, index: u32) -> &mut Self {
+        self.ifinfomsg_flags(index, 0, 0)
+    }
+
+    /// `struct ifinfomsg`, changing the interface flags (eg. `IFF_UP`) of `change`
+    pub fn ifinfomsg_flags(&mut self, index: u32, flags: u32, change: u32) -> &mut Self {
+        // family, pad, type
+        self.buf.extend_from_slice(&[0; 4]);
+        self.buf.extend_from_slice(&index.to_ne_bytes());
+        self.buf.extend_from_slice(&flags.to_ne_bytes());
+        self.buf.extend_from_slice(&change.to_ne_bytes());
+        self
+    }
+
+    /// `struct ifaddrmsg`, which begins address requests
+    pub fn ifaddrmsg(&mut self, family: u8, prefix: u8, index: u32) -> &mut Self {
+        // family, prefixlen, flags, scope
+        self.buf
+            .extend_from_slice(&[family, prefix, 0, RT_SCOPE_UNIVERSE]);
+        self.buf.extend_from_slice(&index.to_ne_bytes());
+        self
+    }
+
+    /// `struct rtmsg`, which begins route requests.  A unicast route in the main table.
+    pub fn rtmsg(&mut self, family: u8, dst_len: u8, protocol: u8, scope: u8) -> &mut Self {
+        // family, dst_len, src_len, tos, table, protocol, scope, type
+        self.buf.extend_from_slice(&[
+            family,
+            dst_len,
+            0,
+            0,
+            RT_TABLE_MAIN,
+            protocol,
+            scope,
+            RTN_UNICAST,
+        ]);
+        // flags
+        self.buf.extend_from_slice(&[0; 4]);
+        self
+    }
+
+    pub fn attr(&mut self, atype: u16, data: &[u8]) -> &mut Self {
+        let len = 4 + data.len();
+        self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&atype.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align4(self.buf.len()), 0);
+        self
+    }
+
+    /// Nil terminated
+    pub fn str(&mut self, atype: u16, value: &str) -> &mut Self {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.attr(atype, &data)
+    }
+
+    pub fn u32(&mut self, atype: u16, value: u32) -> &mut Self {
+        self.attr(atype, &value.to_ne_bytes())
+    }
+
+    /// Network byte order.  As nfnetlink expects.
+    pub fn be32(&mut self, atype: u16, value: u32) -> &mut Self {
+        self.attr(atype, &value.to_be_bytes())
+    }
+
+    /// Attributes, or other content, within an attribute
+    pub fn nested<F: FnOnce(&mut Self)>(&mut self, atype: u16, f: F) -> &mut Self {
+        let start = self.buf.len();
+        self.attr(atype, &[]);
+        f(self);
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+        self
+    }
+
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// One reply to `Rtnl::fetch()`.  eg. `RTM_NEWLINK` with a `struct ifinfomsg`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub mtype: u16,
+    /// After the netlink header.  A fixed size struct, then attributes.
+    pub body: Vec<u8>,
+}
+
+impl Message {
+    /// Attributes following a fixed size struct of `offset` bytes
+    pub fn attrs(&self, offset: usize) -> Vec<(u16, &[u8])> {
+        attrs(self.body.get(offset..).unwrap_or_default())
+    }
+
+    /// `u32` in host byte order, at `offset`
+    pub fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self.body.get(offset..offset + 4)?;
+        Some(u32::from_ne_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+/// Split attributes.  Types are without `NLA_F_NESTED`.
+pub fn attrs(mut data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut ret = vec![];
+    while data.len() >= 4 {
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        let atype = u16::from_ne_bytes([data[2], data[3]]) & !NLA_F_NESTED;
+        if len < 4 || len > data.len() {
+            break;
+        }
+        ret.push((atype, &data[4..len]));
+        data = &data[align4(len).min(data.len())..];
+    }
+    ret
+}
+
+/// Split received messages into (type, sequence number, body)
+fn split<'a>(op: &str, mut msgs: &'a [u8]) -> Result<Vec<(u16, u32, &'a [u8])>> {
+    let mut ret = vec![];
+    while msgs.len() >= HEADER_LEN {
+        let len = u32::from_ne_bytes(msgs[0..4].try_into().unwrap()) as usize;
+        let mtype = u16::from_ne_bytes(msgs[4..6].try_into().unwrap());
+        let seq = u32::from_ne_bytes(msgs[8..12].try_into().unwrap());
+        if len < HEADER_LEN || len > msgs.len() {
+            return Err(Error::os(op, io::ErrorKind::InvalidData.into()));
+        }
+        ret.push((mtype, seq, &msgs[HEADER_LEN..len]));
+        msgs = &msgs[align4(len).min(msgs.len())..];
+    }
+    Ok(ret)
+}
+
+/// The (negative) error code of `NLMSG_ERROR`, or `NLMSG_DONE`
+fn error_code(body: &[u8]) -> i32 {
+    match body.get(0..4) {
+        Some(code) => i32::from_ne_bytes(code.try_into().unwrap()),
+        None => 0,
+    }
+}
+
+/// A NETLINK_ROUTE socket, in the network namespace where it was created
+pub struct Rtnl {
+    sock: OwnedFd,
+    seq: u32,
+}
+
+impl Rtnl {
+    pub fn new() -> Result<Rtnl> {
+        Self::open(libc::NETLINK_ROUTE, "socket(NETLINK_ROUTE)")
+    }
+
+    /// For nfnetlink.  eg. nf_tables
+    pub fn netfilter() -> Result<Rtnl> {
+        Self::open(libc::NETLINK_NETFILTER, "socket(NETLINK_NETFILTER)")
+    }
+
+    fn open(protocol: libc::c_int, op: &str) -> Result<Rtnl> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                protocol,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error(op));
+        }
+        Ok(Rtnl {
+            sock: unsafe { OwnedFd::from_raw_fd(fd) },
+            seq: 0,
+        })
+    }
+
+    fn next_seq(&mut self) -> u32 {
+        self.seq += 1;
+        self.seq
+    }
+
+    /// Send one request, and wait for the acknowledgement
+    pub fn request(&mut self, op: &str, req: Request) -> Result<()> {
+        let seq = self.next_seq();
+        let msg = req.finish(seq);
+        debug!("rtnetlink {} ({} bytes)", op, msg.len());
+        self.send(op, &msg)?;
+        self.wait_ack(op, seq, seq)
+    }
+
+    /// Send one request, and collect the replies.  eg. for a `Request::dump()`,
+    /// or a `RTM_GETLINK` of one interface.
+    pub fn fetch(&mut self, op: &str, req: Request) -> Result<Vec<Message>> {
+        let seq = self.next_seq();
+        let msg = req.finish(seq);
+        debug!("rtnetlink {} ({} bytes)", op, msg.len());
+        self.send(op, &msg)?;
+        let mut ret = vec![];
+        // large enough for a page of dump
+        let mut buf = vec![0u8; 32768];
+        loop {
+            let len = self.recv(op, &mut buf)?;
+            for (mtype, rseq, body) in split(op, &buf[..len])? {
+                if rseq != seq {
+                    continue;
+                }
+                match (mtype, error_code(body)) {
+                    (NLMSG_ERROR | NLMSG_DONE, code) if code < 0 => {
+                        return Err(Error::os(op, io::Error::from_raw_os_error(-code)));
+                    }
+                    // acknowledgement, or end of dump
+                    (NLMSG_ERROR | NLMSG_DONE, _) => return Ok(ret),
+                    _ => ret.push(Message {
+                        mtype,
+                        body: body.to_vec(),
+                    }),
+                }
+            }
+        }
+    }
+
+    /// Send nfnetlink requests to subsystem `subsys`, as one batch.  Which is applied
+    /// entirely, or not at all.  Waits for the acknowledgement of each.
+    pub fn batch(&mut self, op: &str, subsys: u16, reqs: Vec<Request>) -> Result<()> {
+        if reqs.is_empty() {
+            return Ok(());
+        }
+        let mut msg = vec![];
+        let marker = |rtnl: &mut Self, mtype: u16| {
+            let mut req = Request::with_flags(mtype, NLM_F_REQUEST);
+            req.nfgenmsg(libc::AF_UNSPEC as u8, subsys);
+            req.finish(rtnl.next_seq())
+        };
+        msg.extend(marker(self, NFNL_MSG_BATCH_BEGIN));
+        let first = self.seq + 1;
+        for req in reqs {
+            let seq = self.next_seq();
+            msg.extend(req.finish(seq));
+        }
+        let last = self.seq;
+        msg.extend(marker(self, NFNL_MSG_BATCH_END));
+        debug!("nfnetlink {} ({} bytes)", op, msg.len());
+        self.send(op, &msg)?;
+        self.wait_ack(op, first, last)
+    }
+
+    fn send(&self, op: &str, msg: &[u8]) -> Result<()> {
+        let ret = unsafe {
+            libc::send(
+                self.sock.as_raw_fd(),
+                msg.as_ptr() as *const _,
+                msg.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::last_os_error(op));
+        }
+        Ok(())
+    }
+
+    fn recv(&self, op: &str, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let ret = unsafe {
+                libc::recv(
+                    self.sock.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut _,
+                    buf.len(),
+                    0,
+                )
+            };
+            if ret >= 0 {
+                return Ok(ret as usize);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(Error::os(op, err));
+            }
+        }
+    }
+
+    /// Wait for acknowledgements of requests `first` through `last`.  Returns the first error.
+    /// Requests are handled, and so acknowledged, in order.
+    fn wait_ack(&self, op: &str, first: u32, last: u32) -> Result<()> {
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let len = self.recv(op, &mut buf)?;
+            for (mtype, rseq, body) in split(op, &buf[..len])? {
+                if mtype == NLMSG_ERROR && (first..=last).contains(&rseq) && body.len() >= 4 {
+                    let code = error_code(body);
+                    if code != 0 {
+                        return Err(Error::os(op, io::Error::from_raw_os_error(-code)));
+                    } else if rseq == last {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let mut req = Request::new(RTM_NEWLINK, NLM_F_CREATE);
+        req.ifinfomsg(0)
+            .str(IFLA_IFNAME, "a")
+            .nested(IFLA_LINKINFO, |r| {
+                r.str(IFLA_INFO_KIND, "veth");
+            });
+        let msg = req.finish(7);
+        // header, ifinfomsg, "a\0" padded, nest of "veth\0" padded
+        assert_eq!(msg.len(), 16 + 16 + 8 + 4 + 12);
+        assert_eq!(u32::from_ne_bytes(msg[0..4].try_into().unwrap()), 56);
+        assert_eq!(u32::from_ne_bytes(msg[8..12].try_into().unwrap()), 7);
+        let u16_at = |i: usize| u16::from_ne_bytes(msg[i..i + 2].try_into().unwrap());
+        assert_eq!((u16_at(32), u16_at(34)), (6, IFLA_IFNAME));
+        assert_eq!(&msg[36..40], b"a\0\0\0");
+        assert_eq!((u16_at(40), u16_at(42)), (16, IFLA_LINKINFO));
+        assert_eq!((u16_at(44), u16_at(46)), (9, IFLA_INFO_KIND));
+
+        let reply = Message {
+            mtype: RTM_NEWLINK,
+            body: msg[16..].to_vec(),
+        };
+        assert_eq!(reply.u32_at(4), Some(0));
+        let found = reply.attrs(16);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0], (IFLA_IFNAME, &b"a\0"[..]));
+        assert_eq!(found[1].0, IFLA_LINKINFO);
+        assert_eq!(attrs(found[1].1), [(IFLA_INFO_KIND, &b"veth\0"[..])]);
+    }
+
+    #[test]
+    fn dump_lo() {
+        let mut req = Request::dump(RTM_GETLINK);
+        req.ifinfomsg(0);
+        let links = Rtnl::new().unwrap().fetch("dump links", req).unwrap();
+        let lo = links
+            .iter()
+            .find(|msg| msg.attrs(16).contains(&(IFLA_IFNAME, &b"lo\0"[..])));
+        assert_eq!(lo.unwrap().mtype, RTM_NEWLINK);
+    }
+}
@@ -0,0 +1,118 @@
+//! Empty writable directories, which outlive a sandbox.  For --scratch.
+//!
+//! Each run has a directory under `$XDG_STATE_HOME/sandbox/scratch/`, with one
+//! sub-directory for each path in the sandbox.  eg. `/out/bin` is kept in `out_bin`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::debug;
+
+use super::err::{Error, Result};
+use super::id;
+
+/// Scratch directories of one run
+#[derive(Debug)]
+pub struct Scratch {
+    dir: PathBuf,
+    /// Path in the sandbox, and on the host
+    dirs: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Name of the host directory for `path`, in the sandbox
+fn host_name(path: &Path) -> String {
+    let name: Vec<_> = path
+        .components()
+        .filter_map(|c| match c {
+            std::path::Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    if name.is_empty() {
+        "root".to_string()
+    } else {
+        name.join("_")
+    }
+}
+
+impl Scratch {
+    /// Create empty directories for `paths` under `state`.  eg. `snapshot::state_dir()`
+    pub fn create<S: AsRef<Path>>(state: S, paths: &[PathBuf]) -> Result<Scratch> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let dir = state
+            .as_ref()
+            .join("scratch")
+            .join(format!("{}-{}", now, id::current()));
+        fs::create_dir_all(&dir).map_err(|e| Error::file("mkdir", &dir, e))?;
+
+        let mut dirs: Vec<(PathBuf, PathBuf)> = vec![];
+        for path in paths {
+            let name = host_name(path);
+            let mut host = dir.join(&name);
+            // eg. /a_b and /a/b
+            let mut n = 1;
+            while dirs.iter().any(|(_, h)| h == &host) {
+                n += 1;
+                host = dir.join(format!("{}-{}", name, n));
+            }
+            fs::create_dir(&host).map_err(|e| Error::file("mkdir", &host, e))?;
+            debug!("Scratch {} in {}", path.display(), host.display());
+            dirs.push((path.clone(), host));
+        }
+        Ok(Scratch { dir, dirs })
+    }
+
+    /// Parent of the host directories
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path in the sandbox, and on the host
+    pub fn dirs(&self) -> &[(PathBuf, PathBuf)] {
+        &self.dirs
+    }
+
+    /// Remove the host directories, and any contents
+    pub fn remove(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir).map_err(|e| Error::file("remove", &self.dir, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn create() {
+        assert_eq!(host_name(Path::new("/out/bin/")), "out_bin");
+        assert_eq!(host_name(Path::new("/")), "root");
+
+        let tdir = TempDir::new().unwrap();
+        let paths = [
+            PathBuf::from("/out"),
+            PathBuf::from("/a/b"),
+            PathBuf::from("/a_b"),
+        ];
+        let scratch = Scratch::create(tdir.path(), &paths).unwrap();
+        let hosts: Vec<_> = scratch
+            .dirs()
+            .iter()
+            .map(|(_, host)| host.strip_prefix(scratch.dir()).unwrap())
+            .collect();
+        assert_eq!(
+            hosts,
+            [Path::new("out"), Path::new("a_b"), Path::new("a_b-2")]
+        );
+        assert!(scratch.dirs().iter().all(|(_, host)| host.is_dir()));
+
+        let dir = scratch.dir().to_path_buf();
+        assert!(dir.starts_with(tdir.path().join("scratch")));
+        scratch.remove().unwrap();
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,927 @@
+//! Syscall filtering with seccomp BPF programs.
+//!
+//! Programs are assembled directly, without libseccomp.
+//! Installed in a container through `ContainerHooks::seccomp()`.
+//!
+//! Rules are given with native syscall numbers, and applied to each ABI which a
+//! process may use.  eg. i386 and x32 programs on x86_64, with their own numbering.
+//! Socket syscalls are also refused through `socketcall()`, where it exists.
+//! An ABI for which some rule can not be translated is refused entirely.  cf. `Arch`
+//!
+//! ```no_run
+//! use sandbox::{runc, seccomp::Filter, util, HooksBuilder};
+//!
+//! let mut filter = Filter::new();
+//! for nr in [libc::SYS_ptrace, libc::SYS_mount, libc::SYS_keyctl] {
+//!     filter.deny(nr, libc::EPERM);
+//! }
+//! let mut hooks = HooksBuilder::new();
+//! hooks
+//!     .namespaces(libc::CLONE_NEWUSER)
+//!     .seccomp(filter)
+//!     .on_setup(|_ctx| Ok(util::Exec::new("id")?.args(["id"])?.exec()?));
+//! let code = runc(&hooks).unwrap();
+//! ```
+
+use std::{fmt, fs, io};
+
+use libc;
+
+use log::debug;
+
+use super::err::{Error, Result};
+
+// cf. linux/filter.h and linux/bpf_common.h
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JA: u16 = 0x05;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+
+// cf. linux/seccomp.h
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+
+/// Offsets into `struct seccomp_data`
+const OFF_NR: u32 = 0;
+const OFF_ARCH: u32 = 4;
+
+/// Offset of the low 32 bits of an argument in `struct seccomp_data`
+fn off_arg(idx: u32) -> u32 {
+    let off = 16 + 8 * idx;
+    if cfg!(target_endian = "big") {
+        off + 4
+    } else {
+        off
+    }
+}
+
+/// x32 syscalls on x86_64 are numbered from here
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Multiplexer of socket syscalls, where present.  The same number on all.
+const SYS_SOCKETCALL: u32 = 102;
+
+/// Can filters be installed on this architecture?  cf. `Arch::Unknown`
+pub const SUPPORTED: bool = !matches!(Arch::NATIVE, Arch::Unknown);
+
+/// A syscall ABI.  Identified by `AUDIT_ARCH_*`, and for x32 also by syscall number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    X32,
+    X86,
+    Aarch64,
+    Arm,
+    Riscv64,
+    Ppc64le,
+    S390x,
+    /// Any other.  eg. mips64el, or loong64.  Filters can not be installed.
+    Unknown,
+}
+
+impl Arch {
+    #[cfg(target_arch = "x86_64")]
+    pub const NATIVE: Arch = Arch::X86_64;
+    #[cfg(target_arch = "x86")]
+    pub const NATIVE: Arch = Arch::X86;
+    #[cfg(target_arch = "aarch64")]
+    pub const NATIVE: Arch = Arch::Aarch64;
+    #[cfg(target_arch = "arm")]
+    pub const NATIVE: Arch = Arch::Arm;
+    #[cfg(target_arch = "riscv64")]
+    pub const NATIVE: Arch = Arch::Riscv64;
+    #[cfg(all(target_arch = "powerpc64", target_endian = "little"))]
+    pub const NATIVE: Arch = Arch::Ppc64le;
+    #[cfg(target_arch = "s390x")]
+    pub const NATIVE: Arch = Arch::S390x;
+    #[cfg(not(any(
+        target_arch = "x86_64",
+        target_arch = "x86",
+        target_arch = "aarch64",
+        target_arch = "arm",
+        target_arch = "riscv64",
+        all(target_arch = "powerpc64", target_endian = "little"),
+        target_arch = "s390x"
+    )))]
+    pub const NATIVE: Arch = Arch::Unknown;
+
+    /// ABIs which a process of this architecture may use.  This one first.
+    pub fn abis(self) -> &'static [Arch] {
+        match self {
+            Arch::X86_64 => &[Arch::X86_64, Arch::X32, Arch::X86],
+            Arch::Aarch64 => &[Arch::Aarch64, Arch::Arm],
+            Arch::X32 => &[Arch::X32],
+            Arch::X86 => &[Arch::X86],
+            Arch::Arm => &[Arch::Arm],
+            Arch::Riscv64 => &[Arch::Riscv64],
+            Arch::Ppc64le => &[Arch::Ppc64le],
+            Arch::S390x => &[Arch::S390x],
+            Arch::Unknown => &[],
+        }
+    }
+
+    /// `AUDIT_ARCH_*`.  cf. linux/audit.h  Zero for `Unknown`.
+    pub fn audit(self) -> u32 {
+        match self {
+            Arch::X86_64 | Arch::X32 => 0xc000_003e,
+            Arch::X86 => 0x4000_0003,
+            Arch::Aarch64 => 0xc000_00b7,
+            Arch::Arm => 0x4000_0028,
+            Arch::Riscv64 => 0xc000_00f3,
+            Arch::Ppc64le => 0xc000_0015,
+            Arch::S390x => 0x8000_0016,
+            Arch::Unknown => 0,
+        }
+    }
+
+    fn has_socketcall(self) -> bool {
+        matches!(self, Arch::X86 | Arch::Ppc64le | Arch::S390x)
+    }
+
+    /// Number of syscall `name` in this ABI, when not native.
+    /// `Some(None)` if absent from this ABI.  `None` if not known.
+    fn foreign_nr(self, name: &str) -> Option<Option<u32>> {
+        let known = |nr: i32| Some(u32::try_from(nr).ok());
+        match self {
+            Arch::X86 => COMPAT_NRS
+                .iter()
+                .find(|(n, _, _)| *n == name)
+                .and_then(|(_, nr, _)| known(*nr)),
+            Arch::Arm => COMPAT_NRS
+                .iter()
+                .find(|(n, _, _)| *n == name)
+                .and_then(|(_, _, nr)| known(*nr)),
+            _ => None,
+        }
+    }
+
+    /// Numbers of native syscall `nr` in this ABI, each with the first argument
+    /// which selects it through a multiplexer.  eg. `socketcall(SYS_CONNECT, ...)`
+    /// Empty if absent from this ABI.  `None` if not known.
+    fn translate(self, nr: libc::c_long) -> Option<Vec<(u32, Option<u32>)>> {
+        if self == Arch::Unknown {
+            return None;
+        }
+        let name = syscall_name(nr);
+        let mut ret = if self == Arch::NATIVE {
+            vec![(nr as u32, None)]
+        } else if self == Arch::X32 && Arch::NATIVE == Arch::X86_64 {
+            let mut ret = vec![(X32_SYSCALL_BIT | nr as u32, None)];
+            // some have distinct numbers for x32.  cf. arch/x86/entry/syscalls/syscall_64.tbl
+            if let Some((_, x32)) = X32_NRS.iter().find(|(n, _)| Some(*n) == name) {
+                ret.push((X32_SYSCALL_BIT | x32, None));
+            }
+            ret
+        } else {
+            self.foreign_nr(name?)?
+                .map(|nr| (nr, None))
+                .into_iter()
+                .collect()
+        };
+        if self.has_socketcall() {
+            if let Some((_, calls)) = SOCKETCALLS.iter().find(|(n, _)| Some(*n) == name) {
+                ret.extend(calls.iter().map(|call| (SYS_SOCKETCALL, Some(*call))));
+            }
+        }
+        Some(ret)
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Arch::X86_64 => "x86_64",
+            Arch::X32 => "x32",
+            Arch::X86 => "i386",
+            Arch::Aarch64 => "aarch64",
+            Arch::Arm => "arm",
+            Arch::Riscv64 => "riscv64",
+            Arch::Ppc64le => "ppc64le",
+            Arch::S390x => "s390x",
+            Arch::Unknown => std::env::consts::ARCH,
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Numbers of known syscalls for the i386, and arm EABI, compat ABIs.  -1 if absent.
+/// cf. arch/x86/entry/syscalls/syscall_32.tbl and arch/arm/tools/syscall.tbl
+const COMPAT_NRS: &[(&str, i32, i32)] = &[
+    ("acct", 51, 51),
+    ("add_key", 286, 309),
+    ("bpf", 357, 386),
+    ("delete_module", 129, 129),
+    ("finit_module", 350, 379),
+    ("init_module", 128, 128),
+    ("io_uring_enter", 426, 426),
+    ("io_uring_register", 427, 427),
+    ("io_uring_setup", 425, 425),
+    ("ioctl", 54, 54),
+    ("kexec_file_load", -1, 401),
+    ("kexec_load", 283, 347),
+    ("keyctl", 288, 311),
+    ("mount", 21, 21),
+    ("open_by_handle_at", 342, 371),
+    ("perf_event_open", 336, 364),
+    ("personality", 136, 136),
+    ("pivot_root", 217, 218),
+    ("prctl", 172, 172),
+    ("process_vm_readv", 347, 376),
+    ("process_vm_writev", 348, 377),
+    ("ptrace", 26, 26),
+    ("reboot", 88, 88),
+    ("request_key", 287, 310),
+    ("setns", 346, 375),
+    ("swapoff", 115, 115),
+    ("swapon", 87, 87),
+    ("umount2", 52, 52),
+    ("unshare", 310, 337),
+    ("userfaultfd", 374, 388),
+    ("socket", 359, 281),
+    ("bind", 361, 282),
+    ("connect", 362, 283),
+    ("listen", 363, 284),
+    ("accept4", 364, 366),
+    ("getsockname", 367, 286),
+    ("getpeername", 368, 287),
+    ("socketpair", 360, 288),
+    ("sendto", 369, 290),
+    ("recvfrom", 371, 292),
+    ("shutdown", 373, 293),
+    ("setsockopt", 366, 294),
+    ("getsockopt", 365, 295),
+    ("sendmsg", 370, 296),
+    ("recvmsg", 372, 297),
+    ("recvmmsg", 337, 365),
+    ("sendmmsg", 345, 374),
+];
+
+/// x32 numbers distinct from those of x86_64, before `X32_SYSCALL_BIT`
+const X32_NRS: &[(&str, u32)] = &[
+    ("ioctl", 514),
+    ("recvfrom", 517),
+    ("sendmsg", 518),
+    ("recvmsg", 519),
+    ("ptrace", 521),
+    ("kexec_load", 528),
+    ("recvmmsg", 537),
+    ("sendmmsg", 538),
+    ("process_vm_readv", 539),
+    ("process_vm_writev", 540),
+    ("setsockopt", 541),
+    ("getsockopt", 542),
+];
+
+/// `socketcall()` calls equivalent to a socket syscall.  cf. linux/net.h
+/// eg. `SYS_SEND` is `sendto()` without an address.
+const SOCKETCALLS: &[(&str, &[u32])] = &[
+    ("socket", &[1]),
+    ("bind", &[2]),
+    ("connect", &[3]),
+    ("listen", &[4]),
+    ("getsockname", &[6]),
+    ("getpeername", &[7]),
+    ("socketpair", &[8]),
+    ("sendto", &[9, 11]),
+    ("recvfrom", &[10, 12]),
+    ("shutdown", &[13]),
+    ("setsockopt", &[14]),
+    ("getsockopt", &[15]),
+    ("sendmsg", &[16]),
+    ("recvmsg", &[17]),
+    ("accept4", &[5, 18]),
+    ("recvmmsg", &[19]),
+    ("sendmmsg", &[20]),
+];
+
+macro_rules! names {
+    ($($name:ident),*) => {
+        &[$((stringify!($name), libc::$name),)*]
+    };
+}
+
+/// Syscalls which may be named when describing a filter
+const SYSCALL_NAMES: &[(&str, libc::c_long)] = names!(
+    SYS_accept4,
+    SYS_acct,
+    SYS_add_key,
+    SYS_bind,
+    SYS_bpf,
+    SYS_connect,
+    SYS_delete_module,
+    SYS_finit_module,
+    SYS_getpeername,
+    SYS_getsockname,
+    SYS_getsockopt,
+    SYS_init_module,
+    SYS_io_uring_enter,
+    SYS_io_uring_register,
+    SYS_io_uring_setup,
+    SYS_ioctl,
+    SYS_kexec_file_load,
+    SYS_kexec_load,
+    SYS_keyctl,
+    SYS_listen,
+    SYS_mount,
+    SYS_open_by_handle_at,
+    SYS_perf_event_open,
+    SYS_personality,
+    SYS_pivot_root,
+    SYS_prctl,
+    SYS_process_vm_readv,
+    SYS_process_vm_writev,
+    SYS_ptrace,
+    SYS_reboot,
+    SYS_recvfrom,
+    SYS_recvmmsg,
+    SYS_recvmsg,
+    SYS_request_key,
+    SYS_sendmmsg,
+    SYS_sendmsg,
+    SYS_sendto,
+    SYS_setns,
+    SYS_setsockopt,
+    SYS_shutdown,
+    SYS_socket,
+    SYS_socketpair,
+    SYS_swapoff,
+    SYS_swapon,
+    SYS_umount2,
+    SYS_unshare,
+    SYS_userfaultfd
+);
+
+/// Name of a syscall number.  eg. `Some("ptrace")`.  Only some syscalls are known.
+pub fn syscall_name(nr: libc::c_long) -> Option<&'static str> {
+    SYSCALL_NAMES
+        .iter()
+        .find(|(_, n)| *n == nr)
+        .map(|(name, _)| name.trim_start_matches("SYS_"))
+}
+
+fn errno_name(errno: i32) -> String {
+    match errno {
+        libc::EPERM => "EPERM".into(),
+        libc::EACCES => "EACCES".into(),
+        libc::ENOSYS => "ENOSYS".into(),
+        _ => format!("errno {}", errno),
+    }
+}
+
+fn stmt(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    nr: libc::c_long,
+    /// Only when argument number `.0` (low 32 bits) equals `.1`
+    arg: Option<(u32, u32)>,
+    errno: i32,
+}
+
+/// A rule translated for one ABI.  (nr, (arg, value), errno)
+type AbiRule = (u32, Option<(u32, u32)>, i32);
+
+/// A list of syscalls to be refused
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    deny: Vec<Rule>,
+    native_only: bool,
+}
+
+impl Filter {
+    pub fn new() -> Filter {
+        Default::default()
+    }
+
+    /// Fail syscall number `nr` (eg. `libc::SYS_ptrace`) with `errno`
+    pub fn deny(&mut self, nr: libc::c_long, errno: i32) -> &mut Self {
+        self.add(Rule {
+            nr,
+            arg: None,
+            errno,
+        })
+    }
+
+    /// Fail syscall number `nr` with `errno` only when the low 32 bits of argument `arg`
+    /// equal `value`.  eg. deny `ioctl()` request `TIOCSTI`
+    pub fn deny_arg(&mut self, nr: libc::c_long, arg: u32, value: u32, errno: i32) -> &mut Self {
+        self.add(Rule {
+            nr,
+            arg: Some((arg, value)),
+            errno,
+        })
+    }
+
+    fn add(&mut self, rule: Rule) -> &mut Self {
+        if !self.deny.contains(&rule) {
+            self.deny.push(rule);
+        }
+        self
+    }
+
+    /// Add all rules of another filter
+    pub fn extend(&mut self, other: &Filter) -> &mut Self {
+        for rule in &other.deny {
+            self.add(rule.clone());
+        }
+        self.native_only |= other.native_only;
+        self
+    }
+
+    /// Refuse syscalls of all but the native ABI with `ENOSYS`.  eg. i386 on x86_64
+    pub fn native_only(&mut self) -> &mut Self {
+        self.native_only = true;
+        self
+    }
+
+    /// ABIs which may be used.  Syscalls of others are refused with `ENOSYS`.
+    pub fn abis(&self) -> Vec<Arch> {
+        Arch::NATIVE
+            .abis()
+            .iter()
+            .copied()
+            .filter(|abi| *abi == Arch::NATIVE || !self.native_only)
+            .filter(|abi| self.rules_for(*abi).is_some())
+            .collect()
+    }
+
+    /// Rules translated for `abi`.  `None` if some can not be.
+    fn rules_for(&self, abi: Arch) -> Option<Vec<AbiRule>> {
+        let mut ret = vec![];
+        for rule in &self.deny {
+            let nrs = match abi.translate(rule.nr) {
+                Some(nrs) => nrs,
+                None => {
+                    debug!("seccomp can not translate syscall {} for {}", rule.nr, abi);
+                    return None;
+                }
+            };
+            for (nr, call) in nrs {
+                // arguments of socketcall() are in memory, so can not be inspected
+                let arg = match call {
+                    Some(call) => Some((0, call)),
+                    None => rule.arg,
+                };
+                ret.push((nr, arg, rule.errno));
+            }
+        }
+        Some(ret)
+    }
+
+    /// Syscall numbers with at least one rule
+    pub fn syscalls(&self) -> Vec<libc::c_long> {
+        let mut ret: Vec<_> = self.deny.iter().map(|r| r.nr).collect();
+        ret.sort_unstable();
+        ret.dedup();
+        ret
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deny.is_empty()
+    }
+
+    /// Assemble BPF program.
+    ///
+    /// Rules are applied to each of `abis()`.  Syscalls made through another ABI
+    /// are refused with `ENOSYS`.
+    pub fn program(&self) -> Vec<libc::sock_filter> {
+        let mut prog = vec![];
+        for abi in self.abis() {
+            let rules = self.rules_for(abi).expect("translated");
+            let mut block = vec![stmt(BPF_LD_W_ABS, OFF_NR)];
+            // x86_64 and x32 share AUDIT_ARCH_X86_64
+            match abi {
+                Arch::X86_64 => block.push(jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1)),
+                Arch::X32 => block.push(jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 1, 0)),
+                _ => (),
+            }
+            let skip = block.len();
+            if skip > 1 {
+                block.push(stmt(BPF_JMP_JA, 0));
+            }
+            for (nr, arg, errno) in rules {
+                let ret = stmt(BPF_RET_K, SECCOMP_RET_ERRNO | (errno as u32 & 0xffff));
+                match arg {
+                    None => {
+                        block.push(jump(BPF_JMP_JEQ_K, nr, 0, 1));
+                        block.push(ret);
+                    }
+                    Some((arg, value)) => {
+                        block.push(jump(BPF_JMP_JEQ_K, nr, 0, 4));
+                        block.push(stmt(BPF_LD_W_ABS, off_arg(arg)));
+                        block.push(jump(BPF_JMP_JEQ_K, value, 0, 1));
+                        block.push(ret);
+                        // restore for the following rules
+                        block.push(stmt(BPF_LD_W_ABS, OFF_NR));
+                    }
+                }
+            }
+            block.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+            if skip > 1 {
+                // to the next ABI
+                block[skip].k = (block.len() - skip - 1) as u32;
+            }
+
+            prog.push(stmt(BPF_LD_W_ABS, OFF_ARCH));
+            prog.push(jump(BPF_JMP_JEQ_K, abi.audit(), 1, 0));
+            prog.push(stmt(BPF_JMP_JA, block.len() as u32));
+            prog.extend(block);
+        }
+        prog.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32));
+        prog
+    }
+
+    /// Install filter for the current thread and all future children.
+    ///
+    /// Requires either `CAP_SYS_ADMIN` in the current user namespace,
+    /// or that `no_new_privs` was previously set.
+    /// Fails with `ErrorKind::Unsupported` on an architecture without `SUPPORTED`.
+    pub fn install(&self) -> Result<()> {
+        debug!("seccomp deny {:?}", self.syscalls());
+        if !SUPPORTED {
+            let msg = format!("no seccomp ABI known for {}", Arch::NATIVE);
+            return Err(Error::os(
+                "seccomp",
+                io::Error::new(io::ErrorKind::Unsupported, msg),
+            ));
+        }
+        let mut prog = self.program();
+        let fprog = libc::sock_fprog {
+            len: prog.len() as u16,
+            filter: prog.as_mut_ptr(),
+        };
+        let ret = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &fprog as *const libc::sock_fprog,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::os("seccomp", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+}
+
+/// One rule per line.  eg. `ptrace -> EPERM`
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for rule in &self.deny {
+            match syscall_name(rule.nr) {
+                Some(name) => write!(f, "{}", name)?,
+                None => write!(f, "syscall {}", rule.nr)?,
+            }
+            if let Some((arg, value)) = rule.arg {
+                write!(f, " arg{} == {:#x}", arg, value)?;
+            }
+            writeln!(f, " -> {}", errno_name(rule.errno))?;
+        }
+        Ok(())
+    }
+}
+
+/// Refuse `userfaultfd()`.  A common kernel exploit primitive.
+pub fn deny_userfaultfd(filter: &mut Filter) {
+    filter.deny(libc::SYS_userfaultfd, libc::EPERM);
+}
+
+/// Refuse all `io_uring_*()` syscalls.  A common kernel exploit primitive.
+pub fn deny_io_uring(filter: &mut Filter) {
+    filter
+        .deny(libc::SYS_io_uring_setup, libc::EPERM)
+        .deny(libc::SYS_io_uring_enter, libc::EPERM)
+        .deny(libc::SYS_io_uring_register, libc::EPERM);
+}
+
+fn sysctl_at_least(name: &str, min: u32) -> bool {
+    let val = fs::read_to_string(name)
+        .ok()
+        .and_then(|v| v.trim().parse::<u32>().ok());
+    matches!(val, Some(v) if v >= min)
+}
+
+/// Does the host already prevent unprivileged use of `userfaultfd()`?
+/// (`vm.unprivileged_userfaultfd = 0`)  Not per namespace, so only the host may change.
+pub fn host_blocks_userfaultfd() -> bool {
+    let val = fs::read_to_string("/proc/sys/vm/unprivileged_userfaultfd");
+    matches!(val, Ok(v) if v.trim() == "0")
+}
+
+/// Does the host disable `io_uring` entirely?  (`kernel.io_uring_disabled = 2`)
+/// Not per namespace, so only the host may change.
+pub fn host_blocks_io_uring() -> bool {
+    sysctl_at_least("/proc/sys/kernel/io_uring_disabled", 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proc::fork;
+
+    #[test]
+    fn program() {
+        if !SUPPORTED {
+            return;
+        }
+        let mut filter = Filter::new();
+        deny_io_uring(&mut filter);
+        deny_io_uring(&mut filter);
+        filter.native_only();
+        let prog = filter.program();
+        let last = prog.last().unwrap();
+        assert_eq!(
+            (last.code, last.k),
+            (BPF_RET_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32)
+        );
+        // arch check, then one per syscall
+        assert_eq!(prog.iter().filter(|i| i.code == BPF_JMP_JEQ_K).count(), 4);
+        let allow = prog
+            .iter()
+            .filter(|i| i.code == BPF_RET_K && i.k == SECCOMP_RET_ALLOW);
+        assert_eq!(allow.count(), 1);
+    }
+
+    /// Run `prog` on a syscall, as the kernel would.  Returns the action.
+    fn eval(prog: &[libc::sock_filter], arch: Arch, nr: u32, args: &[u32]) -> u32 {
+        let mut acc = 0;
+        let mut pc = 0;
+        loop {
+            let insn = prog[pc];
+            pc += 1;
+            match insn.code {
+                BPF_LD_W_ABS => {
+                    acc = match insn.k {
+                        OFF_NR => nr,
+                        OFF_ARCH => arch.audit(),
+                        off => args.get((off as usize - 16) / 8).copied().unwrap_or(0),
+                    }
+                }
+                BPF_JMP_JA => pc += insn.k as usize,
+                BPF_JMP_JEQ_K | BPF_JMP_JGE_K => {
+                    let taken = if insn.code == BPF_JMP_JEQ_K {
+                        acc == insn.k
+                    } else {
+                        acc >= insn.k
+                    };
+                    pc += if taken { insn.jt } else { insn.jf } as usize;
+                }
+                BPF_RET_K => return insn.k,
+                code => panic!("unexpected {:#x}", code),
+            }
+        }
+    }
+
+    #[test]
+    fn translate() {
+        assert_eq!(
+            Arch::X86.translate(libc::SYS_ptrace),
+            Some(vec![(26, None)])
+        );
+        assert_eq!(Arch::X86.translate(libc::SYS_kexec_file_load), Some(vec![]));
+        assert_eq!(
+            Arch::X86.translate(libc::SYS_connect),
+            Some(vec![(362, None), (102, Some(3))])
+        );
+        assert_eq!(
+            Arch::Arm.translate(libc::SYS_unshare),
+            Some(vec![(337, None)])
+        );
+        assert_eq!(
+            Arch::Arm.translate(libc::SYS_connect),
+            Some(vec![(283, None)])
+        );
+        assert_eq!(Arch::X86.translate(libc::SYS_getpid), None);
+        assert_eq!(Arch::Unknown.translate(libc::SYS_getpid), None);
+        assert!(Arch::Unknown.abis().is_empty());
+        if SUPPORTED {
+            assert_eq!(
+                Arch::NATIVE.translate(libc::SYS_getpid),
+                Some(vec![(libc::SYS_getpid as u32, None)])
+            );
+        }
+        for (name, _, _) in COMPAT_NRS {
+            assert!(
+                SYSCALL_NAMES
+                    .iter()
+                    .any(|(n, _)| n.trim_start_matches("SYS_") == *name),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn vectors_x86_64() {
+        const ALLOW: u32 = SECCOMP_RET_ALLOW;
+        const EPERM: u32 = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        const ENOSYS: u32 = SECCOMP_RET_ERRNO | libc::ENOSYS as u32;
+        let x32 = |nr| X32_SYSCALL_BIT | nr;
+
+        let mut filter = Filter::new();
+        filter
+            .deny(libc::SYS_ptrace, libc::EPERM)
+            .deny(libc::SYS_socket, libc::EPERM)
+            .deny_arg(libc::SYS_ioctl, 1, 0x5412, libc::EPERM);
+        assert_eq!(filter.abis(), vec![Arch::X86_64, Arch::X32, Arch::X86]);
+        let prog = filter.program();
+
+        // (ABI, nr, args, expected)
+        let vectors: &[(Arch, u32, &[u32], u32)] = &[
+            (Arch::X86_64, 101, &[], EPERM),
+            (Arch::X86_64, 41, &[], EPERM),
+            (Arch::X86_64, 16, &[3, 0x5412], EPERM),
+            (Arch::X86_64, 16, &[3, 0x5413], ALLOW),
+            (Arch::X86_64, 39, &[], ALLOW),
+            (Arch::X32, x32(101), &[], EPERM),
+            (Arch::X32, x32(521), &[], EPERM),
+            (Arch::X32, x32(41), &[], EPERM),
+            (Arch::X32, x32(514), &[3, 0x5412], EPERM),
+            (Arch::X32, x32(514), &[3, 0x5413], ALLOW),
+            (Arch::X32, x32(39), &[], ALLOW),
+            (Arch::X86, 26, &[], EPERM),
+            (Arch::X86, 359, &[], EPERM),
+            // socketcall(SYS_SOCKET, ...)
+            (Arch::X86, 102, &[1], EPERM),
+            // socketcall(SYS_CONNECT, ...)
+            (Arch::X86, 102, &[3], ALLOW),
+            (Arch::X86, 54, &[3, 0x5412], EPERM),
+            (Arch::X86, 54, &[3, 0x5413], ALLOW),
+            (Arch::X86, 20, &[], ALLOW),
+            (Arch::Aarch64, 117, &[], ENOSYS),
+        ];
+        for (abi, nr, args, expect) in vectors {
+            assert_eq!(eval(&prog, *abi, *nr, args), *expect, "{} {}", abi, nr);
+        }
+
+        filter.native_only();
+        let prog = filter.program();
+        assert_eq!(eval(&prog, Arch::X86_64, 101, &[]), EPERM);
+        assert_eq!(eval(&prog, Arch::X86_64, 39, &[]), ALLOW);
+        assert_eq!(eval(&prog, Arch::X86_64, x32(39), &[]), ENOSYS);
+        assert_eq!(eval(&prog, Arch::X86, 20, &[]), ENOSYS);
+
+        // not known for i386, so refused
+        let mut filter = Filter::new();
+        filter.deny(libc::SYS_getpid, libc::EPERM);
+        assert_eq!(filter.abis(), vec![Arch::X86_64, Arch::X32]);
+        let prog = filter.program();
+        assert_eq!(eval(&prog, Arch::X86, 20, &[]), ENOSYS);
+        assert_eq!(eval(&prog, Arch::X32, x32(39), &[]), EPERM);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn vectors_aarch64() {
+        const ALLOW: u32 = SECCOMP_RET_ALLOW;
+        const EPERM: u32 = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+        let mut filter = Filter::new();
+        filter
+            .deny(libc::SYS_unshare, libc::EPERM)
+            .deny(libc::SYS_socket, libc::EPERM);
+        assert_eq!(filter.abis(), vec![Arch::Aarch64, Arch::Arm]);
+        let prog = filter.program();
+
+        let vectors: &[(Arch, u32, &[u32], u32)] = &[
+            (Arch::Aarch64, 97, &[], EPERM),
+            (Arch::Aarch64, 198, &[], EPERM),
+            (Arch::Aarch64, 172, &[], ALLOW),
+            (Arch::Arm, 337, &[], EPERM),
+            (Arch::Arm, 281, &[], EPERM),
+            (Arch::Arm, 20, &[], ALLOW),
+        ];
+        for (abi, nr, args, expect) in vectors {
+            assert_eq!(eval(&prog, *abi, *nr, args), *expect, "{} {}", abi, nr);
+        }
+    }
+
+    /// i386 syscalls through `int 0x80`.  Arguments must be 32-bit.
+    #[cfg(target_arch = "x86_64")]
+    fn int80(nr: u32, arg0: u64) -> i32 {
+        let ret: i32;
+        unsafe {
+            // rbx is reserved
+            std::arch::asm!(
+                "xchg {arg0}, rbx",
+                "int 0x80",
+                "xchg {arg0}, rbx",
+                arg0 = inout(reg) arg0 => _,
+                inlateout("eax") nr as i32 => ret,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn install_compat() {
+        // i386 getpid()
+        if int80(20, 0) < 0 {
+            eprintln!("Skip : no IA32 emulation");
+            return;
+        }
+        let mut pid = fork::<_, Error>(|| {
+            unsafe {
+                libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0);
+            }
+            let mut filter = Filter::new();
+            deny_userfaultfd(&mut filter);
+            filter.deny(libc::SYS_socket, libc::EPERM);
+            filter.install()?;
+            // i386 userfaultfd()
+            let uffd = int80(374, 0);
+            // socketcall(SYS_SOCKET, NULL) is EFAULT when allowed
+            let socket = int80(102, 1);
+            std::process::exit(if uffd == -libc::EPERM && socket == -libc::EPERM {
+                0
+            } else {
+                2
+            });
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn display() {
+        let mut filter = Filter::new();
+        filter
+            .deny(libc::SYS_ptrace, libc::EPERM)
+            .deny_arg(libc::SYS_ioctl, 1, 0x5412, libc::EPERM)
+            .deny(-42, 95);
+        assert_eq!(
+            filter.to_string(),
+            "ptrace -> EPERM\nioctl arg1 == 0x5412 -> EPERM\nsyscall -42 -> errno 95\n"
+        );
+    }
+
+    #[test]
+    fn install() {
+        if !SUPPORTED {
+            let err = Filter::new().install().unwrap_err();
+            assert!(err.is_io_error(io::ErrorKind::Unsupported), "{}", err);
+            return;
+        }
+        let mut pid = fork::<_, Error>(|| {
+            unsafe {
+                libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0);
+            }
+            let mut filter = Filter::new();
+            deny_userfaultfd(&mut filter);
+            filter.install()?;
+            let ret = unsafe { libc::syscall(libc::SYS_userfaultfd, 0) };
+            let err = io::Error::last_os_error().raw_os_error();
+            std::process::exit(if ret < 0 && err == Some(libc::EPERM) {
+                0
+            } else {
+                2
+            });
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn install_arg() {
+        if !SUPPORTED {
+            return;
+        }
+        let mut pid = fork::<_, Error>(|| {
+            unsafe {
+                libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0);
+            }
+            let mut filter = Filter::new();
+            filter.deny_arg(
+                libc::SYS_prctl,
+                0,
+                libc::PR_GET_DUMPABLE as u32,
+                libc::EACCES,
+            );
+            filter.install()?;
+            let denied = unsafe { libc::prctl(libc::PR_GET_DUMPABLE, 0, 0, 0, 0) };
+            let err = io::Error::last_os_error().raw_os_error();
+            let allowed = unsafe { libc::prctl(libc::PR_GET_NO_NEW_PRIVS, 0, 0, 0, 0) };
+            std::process::exit(if denied < 0 && err == Some(libc::EACCES) && allowed == 1 {
+                0
+            } else {
+                2
+            });
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+}
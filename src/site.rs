@@ -0,0 +1,239 @@
+//! Site policy.  Minimums set by the administrator, which users can not relax.
+//!
+//! Read from `/etc/sandbox/policy.toml`, and applied after all options and profiles.
+//! Top level keys apply to all users.  Tables `[group.<name>]` apply additionally
+//! to members of a group.
+//!
+//! ```toml
+//! harden = "minimal"
+//! mask = ["/etc/shadow"]
+//!
+//! [group.students]
+//! net = false
+//! readonly = ["/srv/datasets"]
+//! ```
+//!
+//! - `net = false` - Network access is always denied.
+//! - `ipv6 = false` - IPv6 is always disabled on interfaces created for a sandbox.
+//! - `harden` - The lowest `--harden` level.
+//! - `mask` - Paths always hidden.
+//! - `readonly` - Paths never writable.
+
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::{mem, ptr};
+
+use super::config::{Document, Table, Value};
+use super::err::{Error, Result};
+use super::policy::Level;
+use super::util;
+
+/// System configuration file
+pub const POLICY_FILE: &str = "/etc/sandbox/policy.toml";
+
+const KEYS: &[&str] = &["net", "ipv6", "harden", "mask", "readonly"];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SitePolicy {
+    /// Network access is denied
+    pub deny_net: bool,
+    /// `isolate --net-ipv6` is denied
+    pub deny_ipv6: bool,
+    /// Lowest hardening level
+    pub harden: Option<Level>,
+    /// Hidden in the new root
+    pub mask: Vec<PathBuf>,
+    /// Never writable
+    pub readonly: Vec<PathBuf>,
+}
+
+impl SitePolicy {
+    /// Read `POLICY_FILE`, if it exists, as it applies to the calling user
+    pub fn system() -> Result<SitePolicy> {
+        Self::load(POLICY_FILE, in_group)
+    }
+
+    /// Read and parse a policy file, if it exists.  Empty otherwise.
+    pub fn load<P, G>(path: P, member: G) -> Result<SitePolicy>
+    where
+        P: AsRef<Path>,
+        G: Fn(&str) -> bool,
+    {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(SitePolicy::default());
+        }
+        Self::from_document(&Document::load(path)?, member)
+    }
+
+    /// Policy for a user who is a `member` of some groups
+    pub fn from_document<G: Fn(&str) -> bool>(doc: &Document, member: G) -> Result<SitePolicy> {
+        let mut ret = SitePolicy::default();
+        ret.merge(doc, &doc.root, true)?;
+        if let Some(item) = doc.root.get("group") {
+            let groups = item
+                .value
+                .as_table()
+                .ok_or_else(|| doc.error(item.pos, "expected table"))?;
+            for (name, item) in groups {
+                let table = item
+                    .value
+                    .as_table()
+                    .ok_or_else(|| doc.error(item.pos, "expected table"))?;
+                // checked even when not a member
+                let mut group = SitePolicy::default();
+                group.merge(doc, table, false)?;
+                if member(name) {
+                    ret.merge(doc, table, false)?;
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Add restrictions.  Any unknown key is an error, so that a typo does not
+    /// silently remove a restriction.
+    fn merge(&mut self, doc: &Document, table: &Table, top: bool) -> Result<()> {
+        for (key, item) in table {
+            match (key.as_str(), &item.value) {
+                ("group", _) if top => (),
+                ("net", Value::Bool(net)) => self.deny_net |= !net,
+                ("ipv6", Value::Bool(ipv6)) => self.deny_ipv6 |= !ipv6,
+                ("harden", Value::Str(level)) => {
+                    let level: Level = level
+                        .parse()
+                        .map_err(|e: Error| doc.error(item.pos, e.to_string()))?;
+                    self.harden = self.harden.max(Some(level));
+                }
+                ("mask" | "readonly", Value::Array(arr)) => {
+                    let mut paths = vec![];
+                    for value in arr {
+                        match value.as_str() {
+                            Some(path) if path.starts_with('/') => paths.push(PathBuf::from(path)),
+                            _ => return Err(doc.error(item.pos, "expected absolute paths")),
+                        }
+                    }
+                    if key == "mask" {
+                        self.mask.extend(paths);
+                    } else {
+                        self.readonly.extend(paths);
+                    }
+                }
+                (key, _) if KEYS.contains(&key) => {
+                    return Err(doc.error(
+                        item.pos,
+                        format!("unexpected {} for \"{}\"", item.value.type_name(), key),
+                    ))
+                }
+                (key, _) => return Err(doc.error(item.pos, format!("unknown key \"{}\"", key))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any restriction is set
+    pub fn is_empty(&self) -> bool {
+        self == &SitePolicy::default()
+    }
+}
+
+fn group_id(name: &str) -> Option<libc::gid_t> {
+    let cname = CString::new(name).ok()?;
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 64 * 1024];
+    let mut result = ptr::null_mut();
+    let ret = unsafe {
+        libc::getgrnam_r(
+            cname.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        None
+    } else {
+        Some(grp.gr_gid)
+    }
+}
+
+/// Whether the calling process is a member of group `name`.  By real, or supplementary, GID
+pub fn in_group(name: &str) -> bool {
+    let gid = match group_id(name) {
+        Some(gid) => gid,
+        None => return false,
+    };
+    if util::getgid() == gid {
+        return true;
+    }
+    let count = unsafe { libc::getgroups(0, ptr::null_mut()) };
+    let mut groups = vec![0; count.max(0) as usize];
+    let count = unsafe { libc::getgroups(groups.len() as _, groups.as_mut_ptr()) };
+    groups.truncate(count.max(0) as usize);
+    groups.contains(&gid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = r#"
+harden = "minimal"
+mask = ["/etc/shadow"]
+
+[group.students]
+net = false
+ipv6 = false
+harden = "paranoid"
+readonly = ["/srv/datasets"]
+
+[group.staff]
+mask = ["/srv/exams"]
+"#;
+
+    #[test]
+    fn parse() {
+        let doc = Document::parse(EXAMPLE, "policy.toml").unwrap();
+
+        let policy = SitePolicy::from_document(&doc, |_| false).unwrap();
+        assert!(!policy.deny_net);
+        assert!(!policy.deny_ipv6);
+        assert_eq!(policy.harden, Some(Level::Minimal));
+        assert_eq!(policy.mask, [Path::new("/etc/shadow")]);
+        assert!(policy.readonly.is_empty());
+
+        let policy = SitePolicy::from_document(&doc, |g| g == "students").unwrap();
+        assert!(policy.deny_net);
+        assert!(policy.deny_ipv6);
+        assert_eq!(policy.harden, Some(Level::Paranoid));
+        assert_eq!(policy.mask, [Path::new("/etc/shadow")]);
+        assert_eq!(policy.readonly, [Path::new("/srv/datasets")]);
+
+        assert!(SitePolicy::load("/nonexistent", |_| true)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn strict() {
+        for bad in [
+            "net = \"no\"",
+            "mask = [\"relative\"]",
+            "harden = \"extreme\"",
+            "[group.x]\nmsak = []",
+            "[group.x]\ngroup = {}",
+        ] {
+            let doc = Document::parse(bad, "policy.toml").unwrap();
+            SitePolicy::from_document(&doc, |_| false).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn groups() {
+        assert!(!in_group("no-such-group-hopefully"));
+        if util::getgid() == 0 && group_id("root") == Some(0) {
+            assert!(in_group("root"));
+        }
+    }
+}
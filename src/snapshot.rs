@@ -0,0 +1,233 @@
+//! Copy-on-write snapshot of a directory tree.
+//!
+//! Regular files are cloned with the `FICLONE` ioctl, so creating a snapshot
+//! is nearly instant, and uses no additional space until files are changed.
+//! Which needs a filesystem supporting reflinks, eg. btrfs or xfs,
+//! with the snapshot on the same filesystem as the source.
+//! There is no fallback to a full copy.
+//!
+//! Snapshots are kept under `$XDG_STATE_HOME/sandbox/snapshots/`.
+
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use super::err::{Errno, Error, Result};
+use super::{id, util};
+
+/// `_IOW(0x94, 9, int)` from linux/fs.h
+const FICLONE: libc::c_ulong = 0x40049409;
+
+/// State directory of the calling user.  `$XDG_STATE_HOME/sandbox`
+pub fn state_dir() -> Result<PathBuf> {
+    let state = match (std::env::var_os("XDG_STATE_HOME"), std::env::var_os("HOME")) {
+        (Some(dir), _) if !dir.is_empty() => PathBuf::from(dir),
+        (_, Some(home)) => Path::new(&home).join(".local").join("state"),
+        _ => return Err(Error::os("No $HOME", io::ErrorKind::NotFound.into())),
+    };
+    Ok(state.join("sandbox"))
+}
+
+/// Share the data blocks of `src` with `dst`, replacing any contents of `dst`.
+pub fn reflink(src: &File, dst: &File) -> Result<()> {
+    if unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) } < 0 {
+        return Err(Error::last_os_error("FICLONE"));
+    }
+    Ok(())
+}
+
+pub(crate) fn set_times(dst: &File, meta: &fs::Metadata) -> Result<()> {
+    let times = [
+        libc::timespec {
+            tv_sec: meta.atime() as _,
+            tv_nsec: meta.atime_nsec() as _,
+        },
+        libc::timespec {
+            tv_sec: meta.mtime() as _,
+            tv_nsec: meta.mtime_nsec() as _,
+        },
+    ];
+    if unsafe { libc::futimens(dst.as_raw_fd(), times.as_ptr()) } < 0 {
+        return Err(Error::last_os_error("futimens"));
+    }
+    Ok(())
+}
+
+fn copy_file(src: &Path, dst: &Path, meta: &fs::Metadata) -> Result<()> {
+    let input = File::open(src).map_err(|e| Error::file("open", src, e))?;
+    let output = File::create(dst).map_err(|e| Error::file("create", dst, e))?;
+    reflink(&input, &output).map_err(|e| match e.errno() {
+        Some(Errno::EXDEV | Errno::EOPNOTSUPP | Errno::EINVAL) => Error::file(
+            "reflink (needs btrfs or xfs, on the same filesystem)",
+            dst,
+            io::Error::from_raw_os_error(e.raw_os_error().unwrap()),
+        ),
+        _ => e,
+    })?;
+    output
+        .set_permissions(meta.permissions())
+        .map_err(|e| Error::file("chmod", dst, e))?;
+    set_times(&output, meta)
+}
+
+/// Recursively copy the contents of directory `src` into the existing directory `dst`.
+/// Regular files are reflinked, and symlinks recreated.  Other special files are skipped,
+/// as is `dst` if found under `src`.
+pub fn copy_tree<S: AsRef<Path>, D: AsRef<Path>>(src: S, dst: D) -> Result<()> {
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    copy_dir(src, dst, dst)
+}
+
+fn copy_dir(src: &Path, dst: &Path, skip: &Path) -> Result<()> {
+    for dent in fs::read_dir(src).map_err(|e| Error::file("readdir", src, e))? {
+        let dent = dent.map_err(|e| Error::file("readdir", src, e))?;
+        let (from, to) = (dent.path(), dst.join(dent.file_name()));
+        if from == skip {
+            continue;
+        }
+        let meta = fs::symlink_metadata(&from).map_err(|e| Error::file("stat", &from, e))?;
+        let ftype = meta.file_type();
+        if ftype.is_dir() {
+            fs::create_dir(&to).map_err(|e| Error::file("mkdir", &to, e))?;
+            copy_dir(&from, &to, skip)?;
+            fs::set_permissions(&to, meta.permissions())
+                .map_err(|e| Error::file("chmod", &to, e))?;
+            let dir = File::open(&to).map_err(|e| Error::file("open", &to, e))?;
+            set_times(&dir, &meta)?;
+        } else if ftype.is_file() {
+            copy_file(&from, &to, &meta)?;
+        } else if ftype.is_symlink() {
+            let target = fs::read_link(&from).map_err(|e| Error::file("readlink", &from, e))?;
+            std::os::unix::fs::symlink(&target, &to).map_err(|e| Error::file("symlink", &to, e))?;
+        } else {
+            debug!("Snapshot skips special file {}", from.display());
+        }
+    }
+    Ok(())
+}
+
+/// Remove everything inside `dir`, but not `dir` itself
+fn clear_dir(dir: &Path) -> Result<()> {
+    for dent in fs::read_dir(dir).map_err(|e| Error::file("readdir", dir, e))? {
+        let path = dent.map_err(|e| Error::file("readdir", dir, e))?.path();
+        let meta = fs::symlink_metadata(&path).map_err(|e| Error::file("stat", &path, e))?;
+        let ret = if meta.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        ret.map_err(|e| Error::file("remove", &path, e))?;
+    }
+    Ok(())
+}
+
+/// A snapshot of a directory, which may be restored.
+///
+/// Not removed when dropped.  cf. `remove()`
+#[derive(Debug)]
+pub struct Snapshot {
+    source: PathBuf,
+    path: PathBuf,
+}
+
+impl Snapshot {
+    /// Snapshot `source` into a new directory under `<dir>/snapshots/`
+    pub fn create<S: Into<PathBuf>, D: AsRef<Path>>(source: S, dir: D) -> Result<Snapshot> {
+        let source = source.into();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let parent = util::mkdirs(dir.as_ref().join("snapshots"))?;
+        let path = parent.join(format!("{}-{}", now.as_secs(), id::current()));
+        fs::create_dir(&path).map_err(|e| Error::file("mkdir", &path, e))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o700))
+            .map_err(|e| Error::file("chmod", &path, e))?;
+        debug!("Snapshot {} -> {}", source.display(), path.display());
+
+        let snap = Snapshot { source, path };
+        if let Err(err) = copy_tree(&snap.source, &snap.path) {
+            // not useful when incomplete
+            let _ = snap.remove();
+            return Err(err);
+        }
+        Ok(snap)
+    }
+
+    /// The directory which was copied
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    /// Where the copy is kept
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Discard all changes to the source directory since the snapshot was created.
+    /// The snapshot is kept, and may be restored again.
+    pub fn restore(&self) -> Result<()> {
+        debug!(
+            "Restore {} <- {}",
+            self.source.display(),
+            self.path.display()
+        );
+        clear_dir(&self.source)?;
+        copy_dir(&self.path, &self.source, &self.path)
+    }
+
+    /// Delete the copy
+    pub fn remove(self) -> Result<()> {
+        fs::remove_dir_all(&self.path).map_err(|e| Error::file("rm -rf", &self.path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn state() {
+        std::env::set_var("XDG_STATE_HOME", "/somewhere");
+        assert_eq!(state_dir().unwrap(), Path::new("/somewhere/sandbox"));
+        std::env::remove_var("XDG_STATE_HOME");
+    }
+
+    #[test]
+    fn roundtrip() {
+        let tdir = TempDir::new().unwrap();
+        let src = util::mkdirs(tdir.path().join("src")).unwrap();
+        util::mkdirs(src.join("sub")).unwrap();
+        fs::write(src.join("sub").join("file"), "original").unwrap();
+        std::os::unix::fs::symlink("sub/file", src.join("link")).unwrap();
+
+        let snap = match Snapshot::create(&src, tdir.path()) {
+            Ok(snap) => snap,
+            // eg. ext4 or tmpfs
+            Err(err) if err.errno() == Some(Errno::EOPNOTSUPP) => return,
+            Err(err) if err.errno() == Some(Errno::EINVAL) => return,
+            Err(err) => panic!("{err}"),
+        };
+        assert!(snap.path().starts_with(tdir.path().join("snapshots")));
+
+        fs::write(src.join("sub").join("file"), "changed").unwrap();
+        fs::write(src.join("new"), "").unwrap();
+        snap.restore().unwrap();
+
+        let text = fs::read_to_string(src.join("sub").join("file")).unwrap();
+        assert_eq!(text, "original");
+        assert!(!src.join("new").exists());
+        assert_eq!(
+            fs::read_link(src.join("link")).unwrap(),
+            Path::new("sub/file")
+        );
+
+        let path = snap.path().to_path_buf();
+        snap.remove().unwrap();
+        assert!(!path.exists());
+    }
+}
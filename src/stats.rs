@@ -0,0 +1,251 @@
+//! Startup latency instrumentation.
+//!
+//! Each phase is timed by the process which performs it.
+//! Results are collected through memory shared by all processes of a container.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use std::{fmt, mem, ptr};
+
+use libc;
+
+use log::debug;
+
+use super::err::{Error, Result};
+
+/// A timed phase of container startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// `fork()` of the child process, from the parent
+    Fork,
+    /// Creation of namespaces, from the child
+    Unshare,
+    /// `ContainerHooks::set_id_map()`, from the parent
+    IdMap,
+    /// `ContainerHooks::setup_priv()`, from the grandchild.  eg. mount setup
+    Mounts,
+    /// `pivot_root()` into the new root.  Recorded by hooks which do so.
+    Pivot,
+    /// `ContainerHooks::setup()` until the command is executed
+    Exec,
+    /// `runc()` until the command is executed
+    Total,
+}
+
+pub const PHASES: &[Phase] = &[
+    Phase::Fork,
+    Phase::Unshare,
+    Phase::IdMap,
+    Phase::Mounts,
+    Phase::Pivot,
+    Phase::Exec,
+    Phase::Total,
+];
+
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Fork => "fork",
+            Phase::Unshare => "unshare",
+            Phase::IdMap => "idmap",
+            Phase::Mounts => "mounts",
+            Phase::Pivot => "pivot",
+            Phase::Exec => "exec",
+            Phase::Total => "total",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Durations of startup phases.  Phases which did not happen are `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    times: [Option<Duration>; 7],
+}
+
+impl Stats {
+    pub fn get(&self, phase: Phase) -> Option<Duration> {
+        self.times[phase as usize]
+    }
+
+    /// Phases which happened, in order
+    pub fn iter(&self) -> impl Iterator<Item = (Phase, Duration)> + '_ {
+        PHASES
+            .iter()
+            .filter_map(move |p| self.get(*p).map(|d| (*p, d)))
+    }
+
+    /// As a JSON object of microseconds.  eg. `{"fork":120,"total":5300}`
+    pub fn to_json(&self) -> String {
+        let fields: Vec<String> = self
+            .iter()
+            .map(|(p, d)| format!("\"{}\":{}", p, d.as_micros()))
+            .collect();
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (phase, dur) in self.iter() {
+            writeln!(
+                f,
+                "{:>8} {:>10.3} ms",
+                phase.name(),
+                dur.as_secs_f64() * 1e3
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Resource usage of waited for child processes, and their descendants.  cf. `getrusage()`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub user: Duration,
+    pub system: Duration,
+    /// Largest resident set of any one process, in KiB
+    pub max_rss: u64,
+}
+
+fn timeval(tv: libc::timeval) -> Duration {
+    Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+}
+
+impl Usage {
+    /// Of all children of the calling process so far
+    pub fn children() -> Result<Usage> {
+        let mut ru: libc::rusage = unsafe { mem::zeroed() };
+        if unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut ru) } != 0 {
+            return Err(Error::last_os_error("getrusage"));
+        }
+        Ok(Usage {
+            user: timeval(ru.ru_utime),
+            system: timeval(ru.ru_stime),
+            max_rss: ru.ru_maxrss as u64,
+        })
+    }
+
+    /// Times since `before`.  The largest resident set is not a sum, so is kept.
+    pub fn since(&self, before: &Usage) -> Usage {
+        Usage {
+            user: self.user.saturating_sub(before.user),
+            system: self.system.saturating_sub(before.system),
+            max_rss: self.max_rss,
+        }
+    }
+}
+
+/// Stats in a `MAP_SHARED` mapping, which remains shared after `fork()`
+pub(crate) struct SharedStats {
+    slots: *mut AtomicU64,
+}
+
+impl SharedStats {
+    pub(crate) fn new() -> Result<SharedStats> {
+        let len = PHASES.len() * mem::size_of::<AtomicU64>();
+        let slots = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if slots == libc::MAP_FAILED {
+            return Err(Error::last_os_error("mmap stats"));
+        }
+        // anonymous mapping is zero filled
+        Ok(SharedStats {
+            slots: slots as *mut AtomicU64,
+        })
+    }
+
+    fn slot(&self, phase: Phase) -> &AtomicU64 {
+        unsafe { &*self.slots.add(phase as usize) }
+    }
+
+    pub(crate) fn record(&self, phase: Phase, dur: Duration) {
+        debug!("Phase {} took {:?}", phase, dur);
+        // zero means not recorded
+        let ns = (dur.as_nanos() as u64).max(1);
+        self.slot(phase).store(ns, Ordering::SeqCst);
+    }
+
+    pub(crate) fn get(&self) -> Stats {
+        let mut ret = Stats::default();
+        for phase in PHASES {
+            let ns = self.slot(*phase).load(Ordering::SeqCst);
+            if ns != 0 {
+                ret.times[*phase as usize] = Some(Duration::from_nanos(ns));
+            }
+        }
+        ret
+    }
+}
+
+impl Drop for SharedStats {
+    fn drop(&mut self) {
+        let len = PHASES.len() * mem::size_of::<AtomicU64>();
+        unsafe {
+            libc::munmap(self.slots as *mut _, len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proc::fork;
+
+    #[test]
+    fn shared() {
+        let stats = SharedStats::new().unwrap();
+        stats.record(Phase::Fork, Duration::from_micros(5));
+
+        let mut pid = fork::<_, Error>(|| {
+            stats.record(Phase::Mounts, Duration::from_millis(2));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+
+        let result = stats.get();
+        assert_eq!(result.get(Phase::Fork), Some(Duration::from_micros(5)));
+        assert_eq!(result.get(Phase::Mounts), Some(Duration::from_millis(2)));
+        assert_eq!(result.get(Phase::Exec), None);
+        assert_eq!(result.to_json(), "{\"fork\":5,\"mounts\":2000}");
+    }
+
+    #[test]
+    fn usage() {
+        let before = Usage::children().unwrap();
+        let mut pid = fork::<_, Error>(|| {
+            // CPU time, which lags wall time on a busy host
+            let mut now = libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            };
+            while now.tv_sec == 0 && now.tv_nsec < 20_000_000 {
+                unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut now) };
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+        let used = Usage::children().unwrap().since(&before);
+        assert!(
+            used.user + used.system >= Duration::from_millis(10),
+            "{:?}",
+            used
+        );
+        assert!(used.max_rss > 0);
+    }
+}
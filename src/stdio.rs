@@ -0,0 +1,244 @@
+//! Proxy stdout/stderr of a sandboxed command through pipes.
+//!
+//! Allows output to be limited.  eg. to protect CI log storage from runaway output.
+//! The command no longer sees a terminal.  Color escapes may be removed.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use log::{debug, warn};
+
+use super::container::CancelToken;
+use super::err::{Error, Result};
+use super::term::StripColor;
+use super::util;
+
+/// What to do once the output limit is exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitAction {
+    /// Discard further output.  The command continues.
+    Truncate,
+    /// Kill the command
+    Abort,
+}
+
+impl FromStr for LimitAction {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<LimitAction> {
+        match s {
+            "truncate" => Ok(LimitAction::Truncate),
+            "abort" => Ok(LimitAction::Abort),
+            _ => Err(Error::os(
+                format!("Unknown output limit action {:?}", s),
+                io::Error::from(io::ErrorKind::InvalidInput),
+            )),
+        }
+    }
+}
+
+/// State shared by the relay threads
+struct Shared {
+    limit: u64,
+    action: LimitAction,
+    total: AtomicU64,
+    exceeded: AtomicBool,
+    cancel: Option<CancelToken>,
+}
+
+/// Copy from `src` to `dst` until end of file, or an error.
+/// Once the combined total exceeds the limit, output is discarded.
+fn relay<W: Write>(mut src: File, dst: &mut W, shared: &Shared, mut strip: Option<StripColor>) {
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = match src.read(&mut buf) {
+            Ok(0) => {
+                if let Some(strip) = &mut strip {
+                    let _ = dst.write_all(&strip.finish()).and(dst.flush());
+                }
+                break;
+            }
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                debug!("stdio relay error: {}", err);
+                break;
+            }
+        };
+        let before = shared.total.fetch_add(n as u64, Ordering::SeqCst);
+        let allowed = shared.limit.saturating_sub(before).min(n as u64) as usize;
+        if allowed > 0 {
+            let ret = match &mut strip {
+                Some(strip) => dst.write_all(&strip.filter(&buf[..allowed])),
+                None => dst.write_all(&buf[..allowed]),
+            };
+            if ret.and(dst.flush()).is_err() {
+                break;
+            }
+        }
+        if allowed < n && !shared.exceeded.swap(true, Ordering::SeqCst) {
+            warn!("Output limit of {} bytes exceeded", shared.limit);
+            if let (LimitAction::Abort, Some(cancel)) = (shared.action, &shared.cancel) {
+                cancel.cancel();
+            }
+        }
+    }
+}
+
+/// Relays stdout and stderr of a sandboxed command, with a combined size limit.
+///
+/// Create before `runc()`.  Call `redirect()` from `ContainerHooks::setup()`,
+/// and `start()` from `ContainerHooks::started()`.
+pub struct OutputProxy {
+    shared: Arc<Shared>,
+    /// write ends of stdout and stderr pipes
+    writers: RefCell<Option<(File, File)>>,
+    /// read ends
+    readers: RefCell<Option<(File, File)>>,
+    threads: RefCell<Vec<thread::JoinHandle<()>>>,
+    strip: bool,
+}
+
+impl OutputProxy {
+    /// `cancel` is used to kill the command with `LimitAction::Abort`
+    pub fn new(
+        limit: u64,
+        action: LimitAction,
+        cancel: Option<CancelToken>,
+    ) -> Result<OutputProxy> {
+        let (out_rx, out_tx) = util::pipe()?;
+        let (err_rx, err_tx) = util::pipe()?;
+        Ok(OutputProxy {
+            shared: Arc::new(Shared {
+                limit,
+                action,
+                total: AtomicU64::new(0),
+                exceeded: AtomicBool::new(false),
+                cancel,
+            }),
+            writers: RefCell::new(Some((out_tx, err_tx))),
+            readers: RefCell::new(Some((out_rx, err_rx))),
+            threads: RefCell::new(vec![]),
+            strip: false,
+        })
+    }
+
+    /// Remove color escapes from output.  The limit applies before.
+    pub fn strip_color(&mut self, strip: bool) {
+        self.strip = strip;
+    }
+
+    /// In the sandboxed process.  Replace stdout and stderr with the proxy pipes
+    pub fn redirect(&self) -> Result<()> {
+        let writers = self.writers.borrow();
+        let (out, err) = writers.as_ref().expect("redirect() after start()");
+        for (src, dst) in [(out, libc::STDOUT_FILENO), (err, libc::STDERR_FILENO)] {
+            util::dup_over(src.as_raw_fd(), dst)?;
+        }
+        Ok(())
+    }
+
+    /// In the parent, after the sandboxed process is started.  Begin relaying.
+    pub fn start(&self) -> Result<()> {
+        // parent must not hold the write ends, or relaying would never end
+        drop(self.writers.borrow_mut().take());
+        let (out, err) = match self.readers.borrow_mut().take() {
+            Some(readers) => readers,
+            None => return Ok(()),
+        };
+        let mut threads = self.threads.borrow_mut();
+        let strip = || self.strip.then(StripColor::new);
+        let (shared, filter) = (self.shared.clone(), strip());
+        threads.push(thread::spawn(move || {
+            relay(out, &mut io::stdout(), &shared, filter)
+        }));
+        let (shared, filter) = (self.shared.clone(), strip());
+        threads.push(thread::spawn(move || {
+            relay(err, &mut io::stderr(), &shared, filter)
+        }));
+        Ok(())
+    }
+
+    /// Wait until all output has been relayed.  Returns the total size of output,
+    /// including any which was discarded.
+    pub fn finish(&self) -> u64 {
+        for th in self.threads.borrow_mut().drain(..) {
+            let _ = th.join();
+        }
+        self.shared.total.load(Ordering::SeqCst)
+    }
+
+    /// Was the limit exceeded?
+    pub fn exceeded(&self) -> bool {
+        self.shared.exceeded.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared(limit: u64, action: LimitAction, cancel: Option<CancelToken>) -> Shared {
+        Shared {
+            limit,
+            action,
+            total: AtomicU64::new(0),
+            exceeded: AtomicBool::new(false),
+            cancel,
+        }
+    }
+
+    #[test]
+    fn truncate() {
+        let (rx, mut tx) = util::pipe().unwrap();
+        tx.write_all(b"hello world").unwrap();
+        drop(tx);
+
+        let limits = shared(5, LimitAction::Truncate, None);
+        let mut out = vec![];
+        relay(rx, &mut out, &limits, None);
+        assert_eq!(out, b"hello");
+        assert_eq!(limits.total.load(Ordering::SeqCst), 11);
+        assert!(limits.exceeded.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn abort() {
+        let (rx, mut tx) = util::pipe().unwrap();
+        tx.write_all(b"1234").unwrap();
+        drop(tx);
+
+        let cancel = CancelToken::new();
+        let limits = shared(4, LimitAction::Abort, Some(cancel.clone()));
+        let mut out = vec![];
+        relay(rx, &mut out, &limits, None);
+        assert_eq!(out, b"1234");
+        // exactly at the limit is allowed
+        assert!(!cancel.is_cancelled());
+
+        let (rx, mut tx) = util::pipe().unwrap();
+        tx.write_all(b"5").unwrap();
+        drop(tx);
+        relay(rx, &mut out, &limits, None);
+        assert_eq!(out, b"1234");
+        assert!(cancel.is_cancelled());
+    }
+
+    #[test]
+    fn strip() {
+        let (rx, mut tx) = util::pipe().unwrap();
+        tx.write_all(b"\x1b[32mok\x1b[0m\n").unwrap();
+        drop(tx);
+
+        let limits = shared(100, LimitAction::Truncate, None);
+        let mut out = vec![];
+        relay(rx, &mut out, &limits, Some(StripColor::new()));
+        assert_eq!(out, b"ok\n");
+        assert_eq!(limits.total.load(Ordering::SeqCst), 12);
+    }
+}
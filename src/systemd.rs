@@ -0,0 +1,120 @@
+//! Integration with the systemd service manager
+
+use log::debug;
+
+use super::dbus::{Bus, Writer};
+use super::err::{Error, Result};
+
+const SYSTEMD: &str = "org.freedesktop.systemd1";
+const SYSTEMD_PATH: &str = "/org/freedesktop/systemd1";
+const MANAGER: &str = "org.freedesktop.systemd1.Manager";
+
+/// A transient scope unit.  cf. `systemd-run --scope`
+#[derive(Debug)]
+pub struct Scope {
+    name: String,
+    description: String,
+    slice: Option<String>,
+    user: bool,
+}
+
+impl Scope {
+    /// Prepare a scope with unit name.  eg. "isolate-1234.scope"
+    pub fn new<S: Into<String>>(name: S) -> Scope {
+        let name = name.into();
+        Scope {
+            description: name.clone(),
+            name,
+            slice: None,
+            user: false,
+        }
+    }
+
+    /// Human readable description shown by `systemctl status`
+    pub fn description<S: Into<String>>(&mut self, desc: S) -> &mut Self {
+        self.description = desc.into();
+        self
+    }
+
+    /// Place the scope under a slice unit other than the default.  eg. "user.slice"
+    pub fn slice<S: Into<String>>(&mut self, slice: S) -> &mut Self {
+        self.slice = Some(slice.into());
+        self
+    }
+
+    /// Use the per-user service manager instead of the system manager
+    pub fn user(&mut self, user: bool) -> &mut Self {
+        self.user = user;
+        self
+    }
+
+    /// Create the scope unit, moving `pids` into it.  Blocks until the start job completes.
+    pub fn start(&self, pids: &[u32]) -> Result<()> {
+        debug!("Start scope {:?} with {:?}", self, pids);
+        let mut bus = Bus::connect(self.user)?;
+
+        // subscribe before starting to avoid missing the job completion
+        let mut rule = Writer::default();
+        rule.str(&format!(
+            "type='signal',sender='{SYSTEMD}',path='{SYSTEMD_PATH}',interface='{MANAGER}',member='JobRemoved'"
+        ));
+        bus.call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "AddMatch",
+            "s",
+            &rule.buf,
+        )?;
+
+        let mut body = Writer::default();
+        body.str(&self.name).str("fail").array(8, |w| {
+            w.align(8)
+                .str("Description")
+                .sig("s")
+                .str(&self.description);
+            w.align(8).str("PIDs").sig("au").array(4, |w| {
+                for pid in pids {
+                    w.u32(*pid);
+                }
+            });
+            if let Some(slice) = &self.slice {
+                w.align(8).str("Slice").sig("s").str(slice);
+            }
+        });
+        // no auxiliary units
+        body.array(8, |_| {});
+
+        let reply = bus.call(
+            SYSTEMD,
+            SYSTEMD_PATH,
+            MANAGER,
+            "StartTransientUnit",
+            "ssa(sv)a(sa(sv))",
+            &body.buf,
+        )?;
+        let job = reply.body().str()?;
+        debug!("Scope job {}", job);
+
+        loop {
+            let sig = bus.next_signal()?;
+            if sig.member != "JobRemoved" {
+                continue;
+            }
+            // (u id, o job, s unit, s result)
+            let mut args = sig.body();
+            let _id = args.u32()?;
+            if args.str()? != job {
+                continue;
+            }
+            let _unit = args.str()?;
+            let result = args.str()?;
+            debug!("Scope job {} -> {}", job, result);
+            return if result == "done" {
+                Ok(())
+            } else {
+                Err(Error::DBus(format!("start {} : {}", self.name, result)))
+            };
+        }
+    }
+}
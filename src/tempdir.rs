@@ -1,24 +1,122 @@
 //! Manage a temporary directory
+//!
+//! Created under `$TMPDIR`, or `$XDG_RUNTIME_DIR` where `/tmp` is unsuitable.
+//! eg. `noexec`, or nearly full.  Or, failing both, in memory.  cf. `probe()`
 
 use libc;
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
-use log::{debug, error};
+use log::{debug, error, info, warn};
 
 use super::err::{Error, Result};
-use super::path;
+use super::fs::Mounts;
+use super::{id, path, util};
+
+/// Free space needed for a host directory to be used.  bytes
+pub const MIN_FREE: u64 = 16 << 20;
+
+/// Where temporary directories are created
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    /// A host directory.  eg. `/tmp`
+    Dir(PathBuf),
+    /// Nowhere visible to the host.  A tmpfs mounted inside a new mount namespace.
+    /// cf. `mount_memory()`
+    Memory,
+}
+
+/// Host directories which may hold temporary directories.  In order of preference.
+/// `$TMPDIR` (default `/tmp`), then `$XDG_RUNTIME_DIR`.
+pub fn candidates() -> Vec<PathBuf> {
+    let mut ret = vec![std::env::temp_dir()];
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        let dir = PathBuf::from(dir);
+        if dir.is_absolute() && !ret.contains(&dir) {
+            ret.push(dir);
+        }
+    }
+    ret
+}
+
+/// Whether `dir` is writable by the real user, not `noexec`, and has `MIN_FREE` available
+fn usable(dir: &Path) -> bool {
+    let name = match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(name) => name,
+        Err(_) => return false,
+    };
+    // access() checks the real UID.  As wanted when SUID.
+    if unsafe { libc::access(name.as_ptr(), libc::W_OK | libc::X_OK) } != 0 {
+        debug!("{} not writable", dir.display());
+        return false;
+    }
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(name.as_ptr(), &mut st) } != 0 {
+        return false;
+    }
+    let free = st.f_bavail as u64 * st.f_frsize as u64;
+    if st.f_flag & libc::ST_NOEXEC != 0 || free < MIN_FREE {
+        debug!("{} noexec, or {} bytes free", dir.display(), free);
+        return false;
+    }
+    true
+}
+
+/// Choose where to create temporary directories.  The first usable of `candidates()`,
+/// otherwise `Location::Memory`.
+pub fn probe() -> Location {
+    match candidates().into_iter().find(|dir| usable(dir)) {
+        Some(dir) => Location::Dir(dir),
+        None => Location::Memory,
+    }
+}
+
+/// Path of the directory created by `mount_memory(mount_point)`
+pub fn memory_path<P: AsRef<Path>>(mount_point: P) -> PathBuf {
+    path!(mount_point.as_ref(), format!("sandbox-{}", id::current()))
+}
+
+/// Mount a tmpfs over `mount_point`, and create `memory_path(mount_point)` in it.
+/// Only in a private mount namespace, where nothing is visible to the host.
+/// Nothing is left to clean up once the namespace is gone.
+pub fn mount_memory<P: AsRef<Path>>(mount_point: P) -> Result<PathBuf> {
+    let mount_point = mount_point.as_ref();
+    util::mount_with_data(
+        "tmpfs",
+        mount_point,
+        "tmpfs",
+        libc::MS_NODEV | libc::MS_NOSUID,
+        "mode=0700",
+    )?;
+    debug!("Temp dir in memory: {}", mount_point.display());
+    util::mkdir(memory_path(mount_point))
+}
 
 /// A temporary directory which will be `rm -rf` when dropped.
+///
+/// Any mount points found under the directory are lazily unmounted first.
+/// A shared `flock()` is held on the directory, also by any forked child processes,
+/// so that a stale directory can be recognized.  cf. `gc`
 #[derive(Debug)]
 pub struct TempDir {
     name: PathBuf,
+    keep: bool,
+    _lock: Option<File>,
 }
 
 impl TempDir {
-    /// Create a new temporary directory
+    /// Create a new temporary directory.  eg. `/tmp/sandbox-<id>-XXXXXX`
     pub fn new() -> Result<TempDir> {
-        let template = path!(std::env::temp_dir(), "sandbox-XXXXXX");
-        let template = std::ffi::CString::new(template.to_str().unwrap())?;
+        Self::new_in(std::env::temp_dir())
+    }
+
+    /// Create a new temporary directory in `dir`.  eg. from `probe()`
+    pub fn new_in<P: AsRef<Path>>(dir: P) -> Result<TempDir> {
+        let name = format!("sandbox-{}-XXXXXX", id::current());
+        let template = path!(dir.as_ref(), name);
+        let template = CString::new(template.to_str().unwrap())?;
         unsafe {
             let temp = template.as_ptr();
             let ret = libc::mkdtemp(temp as *mut libc::c_char); // modifies template
@@ -28,17 +126,99 @@ impl TempDir {
         }
         let name = PathBuf::from(template.into_string()?);
         debug!("Temp dir: {}", name.display());
-        Ok(TempDir { name })
+        let lock = File::open(&name).map_err(|e| Error::file("open", &name, e))?;
+        util::flock(&lock, libc::LOCK_SH).map_err(|e| Error::file("flock", &name, e))?;
+        Ok(TempDir {
+            name,
+            keep: false,
+            _lock: Some(lock),
+        })
+    }
+
+    /// Take over an existing directory, which will be removed when dropped.
+    /// eg. one left by a crashed run.
+    pub fn adopt<P: Into<PathBuf>>(name: P) -> TempDir {
+        TempDir {
+            name: name.into(),
+            keep: false,
+            _lock: None,
+        }
     }
 
     /// Where is it?
     pub fn path(&self) -> &Path {
         &self.name
     }
+
+    /// Leave the directory in place when dropped.  eg. for post-mortem debugging.
+    pub fn keep(&mut self) -> &mut Self {
+        self.keep = true;
+        self
+    }
+
+    /// Mount points under this directory
+    fn mount_points(&self) -> Result<Vec<PathBuf>> {
+        Ok(Mounts::current()?
+            .into_iter()
+            .filter(|mp| mp.mount_point.starts_with(&self.name))
+            .map(|mp| mp.mount_point.clone())
+            .collect())
+    }
+
+    /// Lazily unmount anything mounted under this directory, leaf first.
+    /// Returns the number of mount points removed.
+    /// Each is attempted, even after a failure.  Then the first error is returned.
+    pub fn umount_all(&self) -> Result<usize> {
+        let mut points = self.mount_points()?;
+        // deepest first, so that children are detached before parents
+        points.sort_by_key(|p| std::cmp::Reverse(p.components().count()));
+
+        let mut count = 0;
+        let mut failed = None;
+        for mp in points {
+            match util::maybe_umount_lazy(&mp) {
+                Ok(true) => count += 1,
+                Ok(false) => (),
+                Err(err) => {
+                    debug!("Unable to unmount {} : {}", mp.display(), err);
+                    failed.get_or_insert(err);
+                }
+            }
+        }
+        match failed {
+            Some(err) => Err(err),
+            None => Ok(count),
+        }
+    }
 }
 
 impl Drop for TempDir {
     fn drop(&mut self) {
+        if self.keep {
+            info!("Keeping temporary directory: {}", self.name.display());
+            return;
+        }
+        let unmounted = match self.umount_all() {
+            Ok(n) => {
+                if n > 0 {
+                    debug!("Unmounted {} under {}", n, self.name.display());
+                }
+                // eg. mounted again meanwhile
+                matches!(self.mount_points(), Ok(points) if points.is_empty())
+            }
+            Err(err) => {
+                warn!("Unable to unmount under {} : {}", self.name.display(), err);
+                false
+            }
+        };
+        if !unmounted {
+            // removing would recurse into binds of host directories.  eg. of $PWD
+            error!(
+                "Leaving temporary directory with mount points: {}",
+                self.name.display()
+            );
+            return;
+        }
         if let Err(err) = std::fs::remove_dir_all(&self.name) {
             error!(
                 "Unable to remove temporary directory: {} : {}",
@@ -54,7 +234,7 @@ impl Drop for TempDir {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::util::write_file;
+    use crate::util::write_new_file;
 
     #[test]
     fn test_tempdir() {
@@ -65,11 +245,81 @@ mod tests {
 
         let tfile = dir.join("test.txt");
         assert!(!tfile.is_file());
-        write_file(&tfile, "Hello world").unwrap();
+        write_new_file(&tfile, "Hello world").unwrap();
         assert!(tfile.is_file());
 
         drop(tdir);
         assert!(!tfile.is_file());
         assert!(!dir.is_dir());
     }
+
+    #[test]
+    fn test_tempdir_keep() {
+        let mut tdir = TempDir::new().unwrap();
+        let dir = tdir.path().to_path_buf();
+        tdir.keep();
+
+        drop(tdir);
+        assert!(dir.is_dir());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn test_umount_fault() {
+        use crate::util::fault::{self, Op};
+        use crate::Errno;
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = crate::proc::fork(|| -> crate::container::Result<()> {
+            util::unshare(libc::CLONE_NEWNS)?;
+            util::mount("", "/", "", libc::MS_REC | libc::MS_PRIVATE)?;
+            let tdir = TempDir::new()?;
+            let dir = tdir.path().to_path_buf();
+            for sub in ["a", "b"] {
+                let sub = util::mkdir(dir.join(sub))?;
+                util::mount("tmpfs", &sub, "tmpfs", 0)?;
+                write_new_file(sub.join("keep"), "")?;
+            }
+
+            // the other is still attempted
+            fault::fail_nth(Op::Umount, 1, Errno::EPERM);
+            let err = tdir.umount_all().unwrap_err();
+            let remain = tdir.mount_points()?;
+            if err.errno() != Some(Errno::EPERM) || remain.len() != 1 {
+                return Err(format!("unexpected {} with {:?}", err, remain).into());
+            }
+
+            // not removed through the remaining mount
+            fault::fail_nth(Op::Umount, 1, Errno::EPERM);
+            drop(tdir);
+            fault::clear(Op::Umount);
+            if !remain[0].join("keep").exists() {
+                return Err(format!("removed under {}", remain[0].display()).into());
+            }
+            drop(TempDir::adopt(&dir));
+            if dir.exists() {
+                return Err(format!("{} not removed", dir.display()).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_probe() {
+        let tdir = TempDir::new().unwrap();
+        assert!(usable(tdir.path()));
+        assert!(!usable(Path::new("/nonexistent")));
+        assert!(!usable(Path::new("/proc/self")));
+        let sub = TempDir::new_in(tdir.path()).unwrap();
+        assert_eq!(sub.path().parent(), Some(tdir.path()));
+        assert_eq!(candidates()[0], std::env::temp_dir());
+        assert_eq!(
+            memory_path("/mnt"),
+            PathBuf::from(format!("/mnt/sandbox-{}", id::current()))
+        );
+    }
 }
@@ -0,0 +1,461 @@
+//! Terminal of a sandboxed command.  For isolate --pty and --color.
+//!
+//! A command may share the terminal of the caller, have its own pseudo-terminal
+//! relayed to the caller, or have its output captured.  eg. by `stdio::OutputProxy`.
+//! The environment is adjusted to match, so that interactive tools render correctly,
+//! and captured output is free of color escapes.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, OsStr};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::thread;
+
+use log::debug;
+use signal_hook::iterator::{Handle, Signals};
+
+use super::err::{Error, Result};
+use super::util;
+
+/// Used when the caller has no `$TERM`
+const DEFAULT_TERM: &str = "xterm";
+
+/// Size of a terminal window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WinSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+/// Size of the terminal `fd`, if it is one
+pub fn window_size<F: AsRawFd>(fd: F) -> Option<WinSize> {
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCGWINSZ, &mut ws) } != 0 || ws.ws_col == 0 {
+        return None;
+    }
+    Some(WinSize {
+        cols: ws.ws_col,
+        rows: ws.ws_row,
+    })
+}
+
+/// Resize terminal `fd`.  Its foreground process group is sent `SIGWINCH`.
+pub fn set_window_size<F: AsRawFd>(fd: F, size: WinSize) -> Result<()> {
+    let ws = libc::winsize {
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCSWINSZ, &ws) } != 0 {
+        return Err(Error::last_os_error("TIOCSWINSZ"));
+    }
+    Ok(())
+}
+
+/// Is `fd` a terminal?
+pub fn isatty<F: AsRawFd>(fd: F) -> bool {
+    unsafe { libc::isatty(fd.as_raw_fd()) == 1 }
+}
+
+/// When color escapes are passed through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    /// Unless output is captured
+    Auto,
+    Always,
+    Never,
+}
+
+impl std::str::FromStr for Color {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Color> {
+        match s {
+            "auto" => Ok(Color::Auto),
+            "always" => Ok(Color::Always),
+            "never" => Ok(Color::Never),
+            _ => Err(Error::os(
+                format!("Unknown color mode {:?}", s),
+                io::ErrorKind::InvalidInput.into(),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Color::Auto => write!(f, "auto"),
+            Color::Always => write!(f, "always"),
+            Color::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// How output of a command reaches the caller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// Shares the terminal, or whatever else, of the caller
+    Inherited,
+    /// Through a new pseudo-terminal.  cf. `Pty`
+    Pty,
+    /// Through pipes, or to a file
+    Captured,
+}
+
+impl Output {
+    /// Are color escapes passed through?
+    pub fn colored(self, color: Color) -> bool {
+        match color {
+            Color::Auto => self != Output::Captured,
+            Color::Always => true,
+            Color::Never => false,
+        }
+    }
+
+    /// Changes to the environment of the command.  `None` to remove a variable.
+    /// `size` of the terminal of the caller, if any.  `term` is its `$TERM`.
+    pub fn env(
+        self,
+        color: Color,
+        size: Option<WinSize>,
+        term: Option<&str>,
+    ) -> Vec<(&'static str, Option<String>)> {
+        let mut ret = vec![];
+        match self {
+            Output::Inherited => (),
+            Output::Pty => {
+                ret.push(("TERM", Some(term.unwrap_or(DEFAULT_TERM).to_string())));
+                // would override the size of the pty, which follows the caller
+                ret.push(("COLUMNS", None));
+                ret.push(("LINES", None));
+            }
+            Output::Captured => {
+                if color != Color::Always {
+                    ret.push(("TERM", Some("dumb".to_string())));
+                }
+                // formatted as if for the terminal where it will most likely be read
+                match size {
+                    Some(size) => {
+                        ret.push(("COLUMNS", Some(size.cols.to_string())));
+                        ret.push(("LINES", Some(size.rows.to_string())));
+                    }
+                    None => {
+                        ret.push(("COLUMNS", None));
+                        ret.push(("LINES", None));
+                    }
+                }
+            }
+        }
+        if !self.colored(color) {
+            ret.push(("NO_COLOR", Some("1".to_string())));
+        }
+        ret
+    }
+}
+
+/// Removes color escapes (SGR) from a stream.  Other escapes are passed through.
+#[derive(Debug, Default)]
+pub struct StripColor {
+    /// A possible escape, split between writes
+    pending: Vec<u8>,
+}
+
+/// Longer escapes are passed through, rather than buffered without end
+const MAX_ESCAPE: usize = 64;
+
+impl StripColor {
+    pub fn new() -> StripColor {
+        StripColor::default()
+    }
+
+    /// Filter the next part of the stream
+    pub fn filter(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len());
+        for &b in input {
+            if self.pending.is_empty() {
+                if b == 0x1b {
+                    self.pending.push(b);
+                } else {
+                    out.push(b);
+                }
+                continue;
+            }
+            self.pending.push(b);
+            match (self.pending.len(), b) {
+                (2, b'[') => (),
+                // not CSI
+                (2, _) => out.append(&mut self.pending),
+                // parameter and intermediate bytes
+                (_, 0x20..=0x3f) if self.pending.len() < MAX_ESCAPE => (),
+                (_, b'm') => self.pending.clear(),
+                _ => out.append(&mut self.pending),
+            }
+        }
+        out
+    }
+
+    /// End of stream.  Any incomplete escape.
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Terminal mode of `fd` before `make_raw()`
+struct SavedMode {
+    fd: RawFd,
+    termios: libc::termios,
+}
+
+impl SavedMode {
+    /// Pass through all input, including line editing and signal keys
+    fn make_raw(fd: RawFd) -> Result<SavedMode> {
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+            return Err(Error::last_os_error("tcgetattr"));
+        }
+        let mut raw = termios;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(fd, libc::TCSADRAIN, &raw) } != 0 {
+            return Err(Error::last_os_error("tcsetattr"));
+        }
+        Ok(SavedMode { fd, termios })
+    }
+}
+
+impl Drop for SavedMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.fd, libc::TCSADRAIN, &self.termios) };
+    }
+}
+
+/// A pseudo-terminal for a sandboxed command, relayed to stdin and stdout of the caller.
+/// The command can not reach the terminal of the caller.  eg. with `TIOCSTI`.
+///
+/// Create before `runc()`.  Call `attach()` from `ContainerHooks::setup()`,
+/// `start()` from `ContainerHooks::started()`, and `finish()` after the command exits.
+pub struct Pty {
+    master: File,
+    /// Until `start()`
+    slave: RefCell<Option<File>>,
+    /// Of stdin, while relaying
+    saved: RefCell<Option<SavedMode>>,
+    output: RefCell<Option<thread::JoinHandle<()>>>,
+    winch: RefCell<Option<Handle>>,
+}
+
+impl Pty {
+    /// Open a new pseudo-terminal, sized as stdin, if it is a terminal.
+    pub fn open() -> Result<Pty> {
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_os_error("posix_openpt"));
+        }
+        let master = unsafe { File::from_raw_fd(fd) };
+        if unsafe { libc::grantpt(fd) } != 0 {
+            return Err(Error::last_os_error("grantpt"));
+        }
+        if unsafe { libc::unlockpt(fd) } != 0 {
+            return Err(Error::last_os_error("unlockpt"));
+        }
+        let mut name = [0 as libc::c_char; 64];
+        let ret = unsafe { libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) };
+        if ret != 0 {
+            return Err(Error::os("ptsname", io::Error::from_raw_os_error(ret)));
+        }
+        let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+        let path = Path::new(OsStr::from_bytes(name.to_bytes()));
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)
+            .map_err(|e| Error::file("open", path, e))?;
+        if let Some(size) = window_size(io::stdin()) {
+            set_window_size(slave.as_raw_fd(), size)?;
+        }
+        debug!("Allocate {}", path.display());
+        Ok(Pty {
+            master,
+            slave: RefCell::new(Some(slave)),
+            saved: RefCell::new(None),
+            output: RefCell::new(None),
+            winch: RefCell::new(None),
+        })
+    }
+
+    /// In the sandboxed process.  Make the pty the controlling terminal,
+    /// and stdin, stdout, and stderr.
+    pub fn attach(&self) -> Result<()> {
+        let slave = self.slave.borrow();
+        let slave = slave.as_ref().expect("attach() after start()");
+        // a session leader without a controlling terminal may already have been made
+        if let Err(err) = util::setsid() {
+            debug!("Keep session : {}", err);
+        }
+        if unsafe { libc::ioctl(slave.as_raw_fd(), libc::TIOCSCTTY, 0) } != 0 {
+            return Err(Error::last_os_error("TIOCSCTTY"));
+        }
+        for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            util::dup_over(slave.as_raw_fd(), fd)?;
+        }
+        Ok(())
+    }
+
+    /// In the parent, after the sandboxed process is started.  Begin relaying input,
+    /// output, and changes to the window size.
+    pub fn start(&self) -> Result<()> {
+        // parent must not hold the slave, or output would never end
+        if self.slave.borrow_mut().take().is_none() {
+            return Ok(());
+        }
+        let stdin = io::stdin();
+        if isatty(stdin.as_raw_fd()) {
+            *self.saved.borrow_mut() = Some(SavedMode::make_raw(stdin.as_raw_fd())?);
+
+            let mut signals =
+                Signals::new([libc::SIGWINCH]).map_err(|e| Error::os("register SIGWINCH", e))?;
+            *self.winch.borrow_mut() = Some(signals.handle());
+            let master = self.clone_master()?;
+            thread::spawn(move || {
+                for _ in signals.forever() {
+                    if let Some(size) = window_size(io::stdin()) {
+                        if let Err(err) = set_window_size(master.as_raw_fd(), size) {
+                            debug!("pty resize fails : {}", err);
+                        }
+                    }
+                }
+            });
+        }
+
+        // not joined, as reading stdin may block indefinitely
+        let mut master = self.clone_master()?;
+        thread::spawn(move || {
+            let _ = io::copy(&mut io::stdin().lock(), &mut master);
+        });
+
+        let mut master = self.clone_master()?;
+        *self.output.borrow_mut() = Some(thread::spawn(move || {
+            let mut stdout = io::stdout();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = match master.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    // EIO once all slave ends are closed
+                    Err(_) => break,
+                };
+                if stdout.write_all(&buf[..n]).and(stdout.flush()).is_err() {
+                    break;
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    /// Wait until all output has been relayed, then restore the terminal of the caller
+    pub fn finish(&self) {
+        if let Some(th) = self.output.borrow_mut().take() {
+            let _ = th.join();
+        }
+        if let Some(handle) = self.winch.borrow_mut().take() {
+            handle.close();
+        }
+        drop(self.saved.borrow_mut().take());
+    }
+
+    fn clone_master(&self) -> Result<File> {
+        self.master.try_clone().map_err(|e| Error::os("dup pty", e))
+    }
+}
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proc;
+
+    #[test]
+    fn strip() {
+        let mut strip = StripColor::new();
+        assert_eq!(
+            strip.filter(b"\x1b[1;31mred\x1b[0m plain \x1b[2K"),
+            b"red plain \x1b[2K"
+        );
+        // split between writes
+        assert_eq!(strip.filter(b"a\x1b[3"), b"a");
+        assert_eq!(strip.filter(b"2mb\x1b"), b"b");
+        assert_eq!(strip.filter(b"c"), b"\x1bc");
+        assert_eq!(strip.filter(b"\x1b["), b"");
+        assert_eq!(strip.finish(), b"\x1b[");
+    }
+
+    #[test]
+    fn env() {
+        let size = Some(WinSize { cols: 80, rows: 24 });
+        assert_eq!(
+            Output::Inherited.env(Color::Auto, size, Some("xterm")),
+            vec![]
+        );
+        assert_eq!(
+            Output::Pty.env(Color::Auto, size, None),
+            vec![
+                ("TERM", Some("xterm".to_string())),
+                ("COLUMNS", None),
+                ("LINES", None)
+            ]
+        );
+        assert_eq!(
+            Output::Captured.env(Color::Auto, size, Some("xterm")),
+            vec![
+                ("TERM", Some("dumb".to_string())),
+                ("COLUMNS", Some("80".to_string())),
+                ("LINES", Some("24".to_string())),
+                ("NO_COLOR", Some("1".to_string())),
+            ]
+        );
+        assert_eq!(
+            Output::Captured.env(Color::Always, None, Some("xterm")),
+            vec![("COLUMNS", None), ("LINES", None)]
+        );
+        assert_eq!(
+            Output::Inherited.env(Color::Never, None, None),
+            vec![("NO_COLOR", Some("1".to_string()))]
+        );
+        for color in [Color::Auto, Color::Always, Color::Never] {
+            assert_eq!(color.to_string().parse::<Color>().unwrap(), color);
+        }
+    }
+
+    #[test]
+    fn pty() {
+        let pty = Pty::open().unwrap();
+        let size = WinSize {
+            cols: 100,
+            rows: 40,
+        };
+        set_window_size(pty.slave.borrow().as_ref().unwrap().as_raw_fd(), size).unwrap();
+
+        let mut pid = proc::fork(|| -> crate::container::Result<()> {
+            pty.attach()?;
+            let seen = window_size(io::stdout());
+            if !isatty(io::stdout()) || seen != Some(size) {
+                return Err(format!("unexpected window size {:?}", seen).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+}
@@ -0,0 +1,396 @@
+//! Run individual tests of a test suite in a sandbox.
+//!
+//! ```no_run
+//! #[test]
+//! fn no_side_effects() {
+//!     sandbox::test::run_isolated(|| {
+//!         assert!(std::fs::write("/etc/example", "").is_err());
+//!         assert!(std::net::TcpStream::connect("192.0.2.1:80").is_err());
+//!     });
+//! }
+//! ```
+//!
+//! The test binary is executed again, inside a sandbox, to run only the calling test.
+//! Where `run_isolated()` calls the closure directly.
+//! The calling test is identified by the name of the current thread,
+//! as set by the default test harness.
+//!
+//! By default, the sandbox has no network access, and a private `/tmp`.
+//! All else is read-only, except the working directory.
+//! eg. the package directory during `cargo test`.
+//!
+//! `Isolated::command()` runs some other command in the same sandbox.
+//! `Isolated::serve()` prepares it once, then runs commands in copies.  cf. `forkserver`
+
+use std::cell::Cell;
+use std::env;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use super::container::{runc, ContainerHooks, IdMap, Result, StageCtx};
+use super::fs::Mounts;
+use super::stats::{Stats, Usage};
+use super::{err, fd, forkserver, net, util};
+
+/// Set inside the sandbox, to the name of the test
+pub const ENV_INNER: &str = "SANDBOX_TEST";
+
+const NOOPT: libc::c_ulong = libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_NOSUID | libc::MS_RELATIME;
+
+/// Change the read-only flag of one mount
+fn set_readonly(mounts: &mut Mounts, path: &Path, ro: bool) -> err::Result<()> {
+    let (set, clear) = if ro {
+        (util::MOUNT_ATTR_RDONLY, 0)
+    } else {
+        (0, util::MOUNT_ATTR_RDONLY)
+    };
+    match util::mount_setattr(path, false, set, clear) {
+        Err(err) if err.is_io_error(std::io::ErrorKind::Unsupported) => {
+            let mut opts = mounts.refresh(path)?.options & !libc::MS_RDONLY;
+            if ro {
+                opts |= libc::MS_RDONLY;
+            }
+            util::mount("", path, "", opts | libc::MS_REMOUNT | libc::MS_BIND)
+        }
+        other => other,
+    }
+}
+
+/// Outcome of `Isolated::command()`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Report {
+    /// Exit code of the command
+    pub code: i32,
+    /// Until the command exited
+    pub elapsed: Duration,
+    /// Durations of startup phases
+    pub startup: Stats,
+    /// Of the command, and any processes it waited for
+    pub usage: Usage,
+}
+
+/// What runs in the sandbox
+#[derive(Debug)]
+enum Target {
+    /// Name of a test of the current executable
+    Test(String),
+    /// `argv`, with `argv[0]` found through `$PATH`
+    Command(Vec<String>),
+    /// The current executable, again.  To serve requests from a listening socket.
+    Server(RawFd),
+}
+
+/// Kept by a fork-server, to create the namespaces and mounts of each copy
+const SERVER_CAPS: &[u32] = &[util::CAP_SYS_ADMIN];
+
+/// Configuration of the sandbox for one test
+#[derive(Debug, Clone)]
+pub struct Isolated {
+    name: Option<String>,
+    net: bool,
+    rw: Vec<PathBuf>,
+    ro: Vec<PathBuf>,
+}
+
+impl Default for Isolated {
+    fn default() -> Self {
+        Isolated {
+            name: None,
+            net: false,
+            rw: env::current_dir().into_iter().collect(),
+            ro: vec![],
+        }
+    }
+}
+
+impl Isolated {
+    pub fn new() -> Isolated {
+        Default::default()
+    }
+
+    /// Name of the test, as accepted by `--exact`.  Default is the name of the current thread.
+    pub fn name<S: Into<String>>(&mut self, test: S) -> &mut Self {
+        self.name = Some(test.into());
+        self
+    }
+
+    /// Allow network access.  Default false
+    pub fn net(&mut self, allow: bool) -> &mut Self {
+        self.net = allow;
+        self
+    }
+
+    /// Allow writes under `dir`
+    pub fn rw<P: Into<PathBuf>>(&mut self, dir: P) -> &mut Self {
+        self.rw.push(dir.into());
+        self
+    }
+
+    /// Deny writes under `dir`, even within a writable directory
+    pub fn ro<P: Into<PathBuf>>(&mut self, dir: P) -> &mut Self {
+        self.ro.push(dir.into());
+        self
+    }
+
+    /// Run `f` in the sandbox.  Panics if `f` panics, or if the sandbox can not be created.
+    pub fn run<F: FnOnce()>(&self, f: F) {
+        if env::var_os(ENV_INNER).is_some() {
+            f();
+            return;
+        }
+        let name = match (&self.name, thread::current().name()) {
+            (Some(name), _) => name.clone(),
+            (None, Some(name)) if name != "main" => name.to_string(),
+            _ => panic!("Unable to identify the current test.  Use Isolated::name()"),
+        };
+        let hooks = Hooks::new(self, Target::Test(name.clone()));
+        match runc(&hooks) {
+            Ok(0) => (),
+            Ok(code) => panic!("Isolated test {} failed with {}", name, code),
+            Err(err) => panic!("Unable to isolate test {} : {}", name, err),
+        }
+    }
+
+    /// Run `args` in the sandbox, instead of a test, and wait for it to exit.
+    /// `args[0]` is found through `$PATH`.
+    ///
+    /// Capabilities of the calling thread are dropped.  cf. `runc()`
+    pub fn command<S: AsRef<str>>(&self, args: &[S]) -> Result<Report> {
+        if args.is_empty() {
+            return Err("Isolated::command() without a command".into());
+        }
+        let args = args.iter().map(|a| a.as_ref().to_string()).collect();
+        let hooks = Hooks::new(self, Target::Command(args));
+        let before = Usage::children()?;
+        let start = Instant::now();
+        let code = runc(&hooks)?;
+        Ok(Report {
+            code,
+            elapsed: start.elapsed(),
+            startup: hooks.startup.get(),
+            usage: Usage::children()?.since(&before),
+        })
+    }
+
+    /// Prepare the sandbox once, then serve requests to run commands in fresh copies of it,
+    /// through a Unix socket created at `socket`.  cf. `forkserver::request()`
+    ///
+    /// The current executable is run again, inside the sandbox, with the same arguments.
+    /// There `serve()` is reached again, and serves until killed.
+    /// Returns the exit code of the server, after removing `socket`.
+    pub fn serve<P: AsRef<Path>>(&self, socket: P) -> Result<i32> {
+        if let Some(listen) = env::var_os(forkserver::ENV_LISTEN_FD) {
+            let listen: RawFd = listen.to_string_lossy().parse()?;
+            env::remove_var(forkserver::ENV_LISTEN_FD);
+            forkserver::run(unsafe { UnixListener::from_raw_fd(listen) })?;
+            return Ok(0);
+        }
+        let socket = socket.as_ref();
+        let listener = forkserver::bind(socket)?;
+        fd::set_cloexec(listener.as_raw_fd(), false)?;
+        let hooks = Hooks::new(self, Target::Server(listener.as_raw_fd()));
+        let ret = runc(&hooks);
+        if let Err(err) = std::fs::remove_file(socket) {
+            debug!("Unable to remove {} : {}", socket.display(), err);
+        }
+        ret
+    }
+}
+
+/// Run `f` in a sandbox with the default configuration.  cf. `Isolated`
+pub fn run_isolated<F: FnOnce()>(f: F) {
+    Isolated::new().run(f)
+}
+
+struct Hooks {
+    isuser: bool,
+    config: Isolated,
+    target: Target,
+    /// Once the command has been executed
+    startup: Cell<Stats>,
+}
+
+impl Hooks {
+    fn new(config: &Isolated, target: Target) -> Hooks {
+        Hooks {
+            isuser: !util::Cap::current()
+                .map(|c| c.effective(util::CAP_SYS_ADMIN))
+                .unwrap_or(false),
+            config: config.clone(),
+            target,
+            startup: Cell::new(Stats::default()),
+        }
+    }
+}
+
+impl ContainerHooks for Hooks {
+    fn namespaces(&self) -> libc::c_int {
+        let mut flags = libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWIPC;
+        if !self.config.net {
+            flags |= libc::CLONE_NEWNET;
+        }
+        if self.isuser {
+            flags |= libc::CLONE_NEWUSER;
+        }
+        flags
+    }
+
+    fn set_id_map(&self, ctx: &StageCtx) -> Result<()> {
+        if let (true, Some(pid)) = (self.isuser, ctx.child()) {
+            let uid = util::getuid();
+            let gid = util::getgid();
+            IdMap::new_uid(pid.id()).add(uid, uid, 1).write()?;
+            IdMap::new_gid(pid.id()).add(gid, gid, 1).write()?;
+        }
+        Ok(())
+    }
+
+    fn setup_priv(&self, _ctx: &StageCtx) -> Result<()> {
+        if !self.config.net {
+            net::configure_lo()?;
+        }
+        util::mount("", "/", "", libc::MS_REC | libc::MS_PRIVATE)?;
+
+        let mut mounts = Mounts::current()?;
+        match util::mount_setattr("/", true, util::MOUNT_ATTR_RDONLY, 0) {
+            Err(err) if err.is_io_error(std::io::ErrorKind::Unsupported) => {
+                let points: Vec<PathBuf> = mounts
+                    .visible_under("/")
+                    .iter()
+                    .map(|mp| mp.mount_point.clone())
+                    .collect();
+                for point in points {
+                    // some may not be accessible.  eg. under /root
+                    if let Err(err) = set_readonly(&mut mounts, &point, true) {
+                        debug!("{}", err);
+                    }
+                }
+            }
+            other => other?,
+        }
+
+        // opened before /tmp is replaced, which may hide some.  eg. $PWD under /tmp
+        let mut binds = vec![];
+        let dirs = self.config.rw.iter().map(|d| (d, false));
+        for (dir, ro) in dirs.chain(self.config.ro.iter().map(|d| (d, true))) {
+            let src = File::open(dir).map_err(|e| err::Error::file("open", dir, e))?;
+            binds.push((dir, ro, src));
+        }
+
+        util::mount("proc", "/proc", "proc", NOOPT)?;
+        for dir in ["/tmp", "/var/tmp"] {
+            if Path::new(dir).is_dir() {
+                util::mount("none", dir, "tmpfs", libc::MS_NODEV | libc::MS_NOSUID)?;
+            }
+        }
+
+        for (dir, ro, src) in binds {
+            debug!("Bind {} ro={}", dir.display(), ro);
+            if !dir.exists() {
+                util::mkdirs(dir)?;
+            }
+            let src = format!("/proc/self/fd/{}", src.as_raw_fd());
+            util::mount(src, dir, "", libc::MS_BIND)?;
+            set_readonly(&mut mounts, dir, ro)?;
+        }
+        Ok(())
+    }
+
+    fn ready(&self, ctx: &StageCtx) -> Result<()> {
+        self.startup.set(ctx.stats());
+        Ok(())
+    }
+
+    fn keep_caps(&self) -> &[u32] {
+        match self.target {
+            Target::Server(_) => SERVER_CAPS,
+            _ => &[],
+        }
+    }
+
+    fn setup(&self, _ctx: &StageCtx) -> Result<()> {
+        // re-enter any bind of the working directory
+        env::set_current_dir(env::current_dir()?)?;
+        match &self.target {
+            Target::Test(name) => {
+                let exe = env::current_exe()?;
+                debug!("Isolated test {}", name);
+                util::Exec::new(exe.to_string_lossy())?
+                    .args([
+                        exe.to_string_lossy().as_ref(),
+                        name.as_str(),
+                        "--exact",
+                        "--nocapture",
+                        "--test-threads=1",
+                        "--quiet",
+                    ])?
+                    .env(ENV_INNER, name.as_str())?
+                    .exec()?;
+            }
+            Target::Command(args) => {
+                debug!("Isolated command {:?}", args);
+                util::Exec::new(&args[0])?.args(args)?.exec()?;
+            }
+            Target::Server(listen) => {
+                let args: Vec<String> = env::args().collect();
+                debug!("Fork-server {:?}", args);
+                // even if hidden.  eg. under /tmp
+                util::Exec::new("/proc/self/exe")?
+                    .args(&args)?
+                    .env(forkserver::ENV_LISTEN_FD, listen.to_string().as_str())?
+                    .exec()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isolated() {
+        // also reached inside, where the namespaces exist
+        if env::var_os(ENV_INNER).is_none() && !crate::testing::require_userns() {
+            return;
+        }
+        run_isolated(|| {
+            // re-executed as process 1 of a new PID namespace
+            assert_eq!(std::process::id(), 1);
+            assert!(env::var_os(ENV_INNER).is_some());
+            std::fs::write("/tmp/isolated", "").unwrap();
+            assert!(std::fs::write("/etc/isolated", "").is_err());
+            assert!(std::net::TcpStream::connect("192.0.2.1:80").is_err());
+        });
+        // also reached inside
+        if env::var_os(ENV_INNER).is_none() {
+            assert!(!Path::new("/tmp/isolated").exists());
+        }
+    }
+
+    #[test]
+    fn command() {
+        if !crate::testing::require_userns() {
+            return;
+        }
+        // runc() drops the capabilities of the calling thread
+        let report = thread::spawn(|| {
+            Isolated::new()
+                .command(&["sh", "-c", "! touch /etc/isolated && exit 3"])
+                .map_err(|e| e.to_string())
+        })
+        .join()
+        .unwrap()
+        .unwrap();
+        assert_eq!(report.code, 3);
+        let total = report.startup.get(crate::stats::Phase::Total).unwrap();
+        assert!(report.elapsed >= total);
+    }
+}
@@ -0,0 +1,334 @@
+//! Helpers for end-to-end tests which create namespaces.  For this crate, and downstream.
+//!
+//! ```no_run
+//! use sandbox::testing::{require_userns, RootFs};
+//!
+//! #[test]
+//! fn hostname() {
+//!     if !require_userns() {
+//!         return;
+//!     }
+//!     let mut root = RootFs::new().unwrap();
+//!     root.with_host_usr().unwrap()
+//!         .file("etc/hostname", "fixture\n").unwrap();
+//!     assert_eq!(root.run(&["grep", "-q", "fixture", "/etc/hostname"]).unwrap(), 0);
+//! }
+//! ```
+//!
+//! Where namespaces can not be created, eg. in some CI containers, `require_userns()`
+//! returns false so that a test can skip itself.  With `$SANDBOX_TEST_REQUIRE_USERNS`
+//! set, it panics instead.  So that a skip is not mistaken for a pass.
+//! Tests which need privilege on the host, eg. to create a network namespace directly,
+//! skip themselves through `require_privilege()` when not root.
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use log::debug;
+
+use super::backend::which;
+use super::container::{runc, ContainerHooks, IdMap, Result, StageCtx};
+use super::err::{self, Error};
+use super::tempdir::TempDir;
+use super::{net, path, proc, util};
+
+/// When set, `require_userns()` panics instead of skipping
+pub const ENV_REQUIRE: &str = "SANDBOX_TEST_REQUIRE_USERNS";
+
+/// Host directories visible through `RootFs::with_host_usr()`
+const HOST_USR: &[&str] = &[
+    "/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/libx32",
+];
+
+const NOOPT: libc::c_ulong = libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_NOSUID | libc::MS_RELATIME;
+
+/// Namespaces created by `RootFs::run()`
+fn namespaces(isuser: bool) -> libc::c_int {
+    let flags = libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWNET | libc::CLONE_NEWIPC;
+    if isuser {
+        flags | libc::CLONE_NEWUSER
+    } else {
+        flags
+    }
+}
+
+fn is_user() -> bool {
+    !util::Cap::current()
+        .map(|c| c.effective(util::CAP_SYS_ADMIN))
+        .unwrap_or(false)
+}
+
+/// Whether the calling process can create the namespaces of a sandbox.
+/// With a user namespace, unless already privileged.
+pub fn userns_available() -> bool {
+    let isuser = is_user();
+    // IDs are mapped through these
+    if isuser
+        && ["newuidmap", "newgidmap"]
+            .iter()
+            .any(|cmd| which(cmd).is_none())
+    {
+        debug!("newuidmap or newgidmap not found");
+        return false;
+    }
+    let flags = namespaces(isuser);
+    let child = proc::fork::<_, Error>(|| {
+        util::unshare(flags)?;
+        Ok(())
+    });
+    match child.and_then(|mut child| child.park()) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(err) => {
+            debug!("fork() : {}", err);
+            false
+        }
+    }
+}
+
+/// Begin a test which creates namespaces.  Returns false if the test should be skipped.
+pub fn require_userns() -> bool {
+    if userns_available() {
+        return true;
+    }
+    if env::var_os(ENV_REQUIRE).is_some() {
+        panic!("Unable to create namespaces, and ${} is set", ENV_REQUIRE);
+    }
+    eprintln!("skipped: unable to create namespaces");
+    false
+}
+
+/// Begin a test which needs privilege on the host.  eg. to create a network namespace
+/// without a user namespace.  Returns false, if the test should be skipped, unless
+/// the caller has `CAP_SYS_ADMIN`.  eg. as root.
+pub fn require_privilege() -> bool {
+    if !is_user() {
+        return true;
+    }
+    eprintln!("skipped: needs CAP_SYS_ADMIN");
+    false
+}
+
+/// Fixture of a root filesystem.  Built in a temporary directory, which is removed on drop.
+/// Begins with empty `/dev`, `/proc` and `/tmp`.
+#[derive(Debug)]
+pub struct RootFs {
+    _dir: TempDir,
+    root: PathBuf,
+    /// Host directories, bound read-only
+    host: Vec<PathBuf>,
+}
+
+impl RootFs {
+    pub fn new() -> err::Result<RootFs> {
+        let dir = TempDir::new()?;
+        let root = util::mkdir(path!(dir.path(), "root"))?;
+        for name in ["dev", "proc", "tmp"] {
+            util::mkdir(path!(&root, name))?;
+        }
+        Ok(RootFs {
+            _dir: dir,
+            root,
+            host: vec![],
+        })
+    }
+
+    /// The root directory, on the host
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Host path of `path` in the fixture
+    fn target<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let path = path.as_ref();
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    /// Create a directory, and any parents
+    pub fn dir<P: AsRef<Path>>(&mut self, path: P) -> err::Result<&mut Self> {
+        util::mkdirs(self.target(path))?;
+        Ok(self)
+    }
+
+    /// Create a file, and any parent directories
+    pub fn file<P: AsRef<Path>, C: AsRef<[u8]>>(
+        &mut self,
+        path: P,
+        contents: C,
+    ) -> err::Result<&mut Self> {
+        let target = self.target(path);
+        if let Some(parent) = target.parent() {
+            util::mkdirs(parent)?;
+        }
+        util::write_file(&target, contents)?;
+        Ok(self)
+    }
+
+    pub fn symlink<P: AsRef<Path>, T: AsRef<Path>>(
+        &mut self,
+        path: P,
+        target: T,
+    ) -> err::Result<&mut Self> {
+        let link = self.target(path);
+        if let Some(parent) = link.parent() {
+            util::mkdirs(parent)?;
+        }
+        symlink(target.as_ref(), &link).map_err(|e| Error::file("symlink", &link, e))?;
+        Ok(self)
+    }
+
+    /// Make host directory `path` visible, read-only, at the same location.
+    /// A symlink is copied instead.  eg. `/bin -> usr/bin`
+    pub fn host<P: AsRef<Path>>(&mut self, path: P) -> err::Result<&mut Self> {
+        let path = path.as_ref();
+        let meta = fs::symlink_metadata(path).map_err(|e| Error::file("stat", path, e))?;
+        if meta.file_type().is_symlink() {
+            let dest = fs::read_link(path).map_err(|e| Error::file("readlink", path, e))?;
+            return self.symlink(path, dest);
+        }
+        self.dir(path)?;
+        self.host.push(path.to_path_buf());
+        Ok(self)
+    }
+
+    /// Those of `/usr`, `/bin`, `/sbin`, and `/lib*` which exist on the host.
+    /// And a copy of `/etc/ld.so.cache`.  Enough to run most host executables.
+    pub fn with_host_usr(&mut self) -> err::Result<&mut Self> {
+        for dir in HOST_USR {
+            if fs::symlink_metadata(dir).is_ok() {
+                self.host(dir)?;
+            }
+        }
+        let cache = Path::new("/etc/ld.so.cache");
+        if cache.is_file() {
+            let contents = fs::read(cache).map_err(|e| Error::file("read", cache, e))?;
+            self.file(cache, contents)?;
+        }
+        Ok(self)
+    }
+
+    /// Run a command with the fixture as `/`, in new namespaces.  Without network access.
+    /// `args[0]` is found through `$PATH`.  Returns the exit code.
+    pub fn run<S: AsRef<str>>(&self, args: &[S]) -> Result<i32> {
+        let hooks = Hooks {
+            isuser: is_user(),
+            rootfs: self,
+            args: args.iter().map(|a| a.as_ref().to_string()).collect(),
+        };
+        // runc() drops the capabilities of the calling thread.  Keep those of the caller,
+        // so that it may run again.
+        thread::scope(|s| {
+            s.spawn(|| runc(&hooks).map_err(|e| e.to_string()))
+                .join()
+                .unwrap_or_else(|_| Err("RootFs::run() panicked".to_string()))
+        })
+        .map_err(Into::into)
+    }
+}
+
+struct Hooks<'a> {
+    isuser: bool,
+    rootfs: &'a RootFs,
+    args: Vec<String>,
+}
+
+impl<'a> ContainerHooks for Hooks<'a> {
+    fn namespaces(&self) -> libc::c_int {
+        namespaces(self.isuser)
+    }
+
+    fn set_id_map(&self, ctx: &StageCtx) -> Result<()> {
+        if let (true, Some(pid)) = (self.isuser, ctx.child()) {
+            let uid = util::getuid();
+            let gid = util::getgid();
+            IdMap::new_uid(pid.id()).add(uid, uid, 1).write()?;
+            IdMap::new_gid(pid.id()).add(gid, gid, 1).write()?;
+        }
+        Ok(())
+    }
+
+    fn setup_priv(&self, _ctx: &StageCtx) -> Result<()> {
+        net::configure_lo()?;
+        util::mount("", "/", "", libc::MS_REC | libc::MS_PRIVATE)?;
+
+        let root = self.rootfs.path();
+        // pivot_root() needs a mount point
+        util::mount(root, root, "", libc::MS_BIND)?;
+
+        for dir in &self.rootfs.host {
+            let target = self.rootfs.target(dir);
+            debug!("Bind {}", dir.display());
+            util::mount(dir, &target, "", libc::MS_BIND | libc::MS_REC)?;
+            match util::mount_setattr(&target, true, util::MOUNT_ATTR_RDONLY, 0) {
+                Err(err) if err.is_io_error(std::io::ErrorKind::Unsupported) => {
+                    let flags = libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY;
+                    util::mount("", &target, "", flags)?;
+                }
+                other => other?,
+            }
+        }
+        // for /dev/null and the like
+        util::mount("/dev", path!(root, "dev"), "", libc::MS_BIND | libc::MS_REC)?;
+        util::mount("none", path!(root, "proc"), "proc", NOOPT)?;
+        util::mount(
+            "none",
+            path!(root, "tmp"),
+            "tmpfs",
+            libc::MS_NODEV | libc::MS_NOSUID,
+        )?;
+
+        util::mkdir(path!(root, "tmp", "oldroot"))?;
+        env::set_current_dir(root)?;
+        util::pivot_root(".", "tmp/oldroot")?;
+        env::set_current_dir("/")?;
+        util::umount_lazy("/tmp/oldroot")?;
+        util::rmdir("/tmp/oldroot")?;
+        Ok(())
+    }
+
+    fn setup(&self, _ctx: &StageCtx) -> Result<()> {
+        let cmd = self.args.first().ok_or("RootFs::run() without a command")?;
+        util::Exec::new(cmd)?.args(&self.args)?.exec()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixture() {
+        let mut root = RootFs::new().unwrap();
+        root.file("/etc/hostname", "fixture\n")
+            .unwrap()
+            .symlink("etc/alias", "hostname")
+            .unwrap();
+        assert!(root.path().join("proc").is_dir());
+        assert_eq!(
+            fs::read_to_string(root.path().join("etc/alias")).unwrap(),
+            "fixture\n"
+        );
+
+        let dir = root.path().to_path_buf();
+        drop(root);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn run() {
+        if !require_userns() {
+            return;
+        }
+        let mut root = RootFs::new().unwrap();
+        root.with_host_usr().unwrap();
+        let code = root
+            .run(&["sh", "-c", "test -d /usr/bin && ! test -e /etc/passwd"])
+            .unwrap();
+        assert_eq!(code, 0);
+        assert_ne!(root.run(&["sh", "-c", "touch /usr/x"]).unwrap(), 0);
+    }
+}
@@ -0,0 +1,132 @@
+//! Toolchain roots which live outside of `/usr`.
+//!
+//! eg. the Nix store, `/opt/toolchains`, or Linuxbrew.  These are bound read-only
+//! into a sandbox, even where the surrounding tree is hidden (eg. by `hidehome`),
+//! so that builds can still find compilers.
+//!
+//! Toolchain binds are always `nosuid` and `nodev`.  Other options of the host
+//! mount (eg. the `ro,nosuid` of a Nix store) are kept.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use super::err::Result;
+use super::fs::Mounts;
+use super::util;
+
+/// Well known toolchain roots
+pub const KNOWN_ROOTS: &[&str] = &[
+    "/nix/store",
+    "/nix/var/nix/profiles",
+    "/opt/toolchains",
+    "/home/linuxbrew/.linuxbrew",
+];
+
+/// Well known toolchain roots, relative to `$HOME`
+pub const KNOWN_HOME_ROOTS: &[&str] = &[".linuxbrew"];
+
+/// Which toolchain roots to bind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Toolchains {
+    /// Those of `KNOWN_ROOTS` and `KNOWN_HOME_ROOTS` which exist
+    Detect,
+    /// Only these
+    Paths(Vec<PathBuf>),
+}
+
+impl Toolchains {
+    /// Existing directories, canonicalized, without duplicates
+    pub fn resolve(&self) -> Vec<PathBuf> {
+        let candidates = match self {
+            Toolchains::Detect => {
+                let home = std::env::var_os("HOME").map(PathBuf::from);
+                let mut ret: Vec<PathBuf> = KNOWN_ROOTS.iter().map(PathBuf::from).collect();
+                if let Some(home) = home.filter(|h| h.is_absolute()) {
+                    ret.extend(KNOWN_HOME_ROOTS.iter().map(|d| home.join(d)));
+                }
+                ret
+            }
+            Toolchains::Paths(paths) => paths.clone(),
+        };
+        let mut seen = HashSet::new();
+        candidates
+            .iter()
+            .filter(|d| d.is_dir())
+            .filter_map(|d| d.canonicalize().ok())
+            .filter(|d| seen.insert(d.clone()))
+            .collect()
+    }
+}
+
+/// Bind directory `src` onto `target` as read-only, `nosuid`, and `nodev`.
+/// Other options of the new mount are kept.
+pub fn bind_ro<A: AsRef<Path>, B: AsRef<Path>>(
+    mounts: &mut Mounts,
+    src: A,
+    target: B,
+) -> Result<()> {
+    let target = target.as_ref();
+    debug!(
+        "Toolchain {} -> {}",
+        src.as_ref().display(),
+        target.display()
+    );
+    util::mount(src, target, "", libc::MS_BIND)?;
+    // a new bind mount has the options of the source mount.
+    // When unprivileged, locked options must be repeated.
+    let opts = mounts.refresh(target)?.options;
+    util::mount(
+        "",
+        target,
+        "",
+        opts | libc::MS_REMOUNT
+            | libc::MS_BIND
+            | libc::MS_RDONLY
+            | libc::MS_NOSUID
+            | libc::MS_NODEV,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve() {
+        let tdir = crate::tempdir::TempDir::new().unwrap();
+        let a = util::mkdir(tdir.path().join("a")).unwrap();
+        let link = tdir.path().join("link");
+        std::os::unix::fs::symlink(&a, &link).unwrap();
+
+        let tc = Toolchains::Paths(vec![a.clone(), tdir.path().join("missing"), link]);
+        assert_eq!(tc.resolve(), vec![a.canonicalize().unwrap()]);
+    }
+
+    #[test]
+    fn bind() {
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let tdir = crate::tempdir::TempDir::new().unwrap();
+        let top = tdir.path().canonicalize().unwrap();
+        let mut pid = crate::proc::fork(|| -> crate::container::Result<()> {
+            util::unshare(libc::CLONE_NEWNS)?;
+            util::mount("", "/", "", libc::MS_REC | libc::MS_PRIVATE)?;
+            util::mount("tmpfs", &top, "tmpfs", libc::MS_NOEXEC)?;
+            let src = util::mkdir(top.join("src"))?;
+            let dst = util::mkdir(top.join("dst"))?;
+            bind_ro(&mut Mounts::current()?, &src, &dst)?;
+
+            let want = libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+            let opts = Mounts::current()?.refresh(&dst)?.options;
+            if opts & want != want {
+                return Err(format!("unexpected mount options {:#x}", opts).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+}
@@ -0,0 +1,164 @@
+//! Messages from the executables to the user.
+//!
+//! Errors and warnings are written to stderr as `error: ...` and `warning: ...`,
+//! with labels from the message catalog.  cf. `msg`
+//! The label is colored when stderr is a terminal, unless `$NO_COLOR` is set.
+//!
+//! Verbosity is adjusted by `-v` (repeatable) and `-q`, which also set
+//! the level of `log` messages.  eg. `-vv` shows debug messages.
+
+use std::fmt::Display;
+use std::process;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use log::LevelFilter;
+
+use super::err::Error;
+use super::msg::{text, tr, Msg};
+
+static VERBOSITY: AtomicI32 = AtomicI32::new(0);
+
+const RED: &str = "1;31";
+const YELLOW: &str = "1;33";
+
+/// Change in verbosity, if `arg` is a verbosity option.  eg. `-vv` is `Some(2)`
+pub fn verbosity_arg(arg: &str) -> Option<i32> {
+    match arg {
+        "--verbose" => Some(1),
+        "-q" | "--quiet" => Some(-1),
+        _ if arg.len() > 1 && arg.starts_with('-') && arg[1..].bytes().all(|c| c == b'v') => {
+            Some(arg.len() as i32 - 1)
+        }
+        _ => None,
+    }
+}
+
+/// Consume leading verbosity options.  Returns the remaining arguments.
+pub fn take_verbosity(args: &[String]) -> &[String] {
+    let mut n = 0;
+    while let Some(change) = args.get(n).and_then(|a| verbosity_arg(a)) {
+        set_verbosity(verbosity() + change);
+        n += 1;
+    }
+    &args[n..]
+}
+
+/// Negative is quiet, positive is verbose.  When non-zero, also overrides
+/// the log levels from `$SANDBOX_LOG` or `$RUST_LOG`.
+pub fn set_verbosity(level: i32) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+    let filter = match level {
+        0 => return,
+        i32::MIN..=-1 => LevelFilter::Error,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    super::logging::set_level(filter);
+}
+
+pub fn verbosity() -> i32 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+/// Print the version of `tool`.  When `verbose`, with the features of the build,
+/// and kernel interfaces, as JSON.  cf. `version::build_info()`
+pub fn version(tool: &str, verbose: bool) {
+    let info = super::version::build_info();
+    if verbose {
+        println!("{}", info.to_json());
+    } else {
+        println!("{} {}", tool, info.short());
+    }
+}
+
+/// Color stderr?
+pub fn color() -> bool {
+    let nocolor = std::env::var_os("NO_COLOR").filter(|v| !v.is_empty());
+    nocolor.is_none() && unsafe { libc::isatty(libc::STDERR_FILENO) } == 1
+}
+
+/// Wrap `text` with an ANSI SGR sequence
+fn paint(text: &str, sgr: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{sgr}m{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
+
+fn format_msg<D: Display>(label: &str, sgr: &str, color: bool, msg: D) -> String {
+    format!("{}: {msg}", paint(label, sgr, color))
+}
+
+/// Label of a log level, colored when `color()`
+pub(crate) fn level_label(level: log::Level) -> String {
+    let text = format!("{:<5}", level);
+    match level {
+        log::Level::Error => paint(&text, RED, color()),
+        log::Level::Warn => paint(&text, YELLOW, color()),
+        _ => text,
+    }
+}
+
+/// Print `error: <msg>`
+pub fn error<D: Display>(msg: D) {
+    eprintln!("{}", format_msg(text(Msg::Error), RED, color(), msg));
+}
+
+/// Print `warning: <msg>`, unless quiet
+pub fn warning<D: Display>(msg: D) {
+    if verbosity() >= 0 {
+        eprintln!("{}", format_msg(text(Msg::Warning), YELLOW, color(), msg));
+    }
+}
+
+/// Print an informational message, unless quiet
+pub fn info<D: Display>(msg: D) {
+    if verbosity() >= 0 {
+        eprintln!("{msg}");
+    }
+}
+
+/// Print `error: <msg>`, then exit with status 1
+pub fn fatal<D: Display>(msg: D) -> ! {
+    error(msg);
+    process::exit(1);
+}
+
+/// Run the body of `main()`.  An error is printed as `error: <msg>`, with exit status 1.
+/// Or, when a signal interrupted setup, with status 128 plus the signal number, as a shell.
+pub fn main<F: FnOnce() -> Result<(), crate::Error>>(f: F) {
+    if let Err(err) = f() {
+        if let Some(Error::Interrupted(sig)) = err.downcast_ref() {
+            error(tr(Msg::SetupInterrupted, &[("signal", sig)]));
+            process::exit(128 + sig);
+        }
+        fatal(err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn args() {
+        assert_eq!(verbosity_arg("-v"), Some(1));
+        assert_eq!(verbosity_arg("-vvv"), Some(3));
+        assert_eq!(verbosity_arg("--verbose"), Some(1));
+        assert_eq!(verbosity_arg("-q"), Some(-1));
+        assert_eq!(verbosity_arg("-"), None);
+        assert_eq!(verbosity_arg("-vx"), None);
+        assert_eq!(verbosity_arg("ls"), None);
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(format_msg("error", RED, false, "oops"), "error: oops");
+        assert_eq!(
+            format_msg("error", RED, true, 42),
+            "\x1b[1;31merror\x1b[0m: 42"
+        );
+    }
+}
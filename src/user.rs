@@ -1,5 +1,8 @@
 //! Wrappers for UID and GID syscalls
 
+use std::fs;
+use std::path::PathBuf;
+
 use libc;
 
 use super::err::{Error, Result};
@@ -55,3 +58,126 @@ pub fn setegid(id: libc::gid_t) -> Result<()> {
     }
     Ok(())
 }
+
+pub fn setgroups(ids: &[libc::gid_t]) -> Result<()> {
+    unsafe {
+        if 0 != libc::setgroups(ids.len(), ids.as_ptr()) {
+            return Err(Error::last_os_error("setgroups"));
+        }
+    }
+    Ok(())
+}
+
+/// A range of host IDs.  eg. delegated by `/etc/subuid`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    pub start: u32,
+    pub count: u32,
+}
+
+/// The overflow user and group, `nobody` and `nogroup`
+pub const NOBODY: IdRange = IdRange {
+    start: 65534,
+    count: 1,
+};
+
+/// First range delegated to user `name`, or `id`, in the format of subuid(5) and subgid(5)
+pub fn parse_subids(text: &str, name: &str, id: u32) -> Option<IdRange> {
+    let id = id.to_string();
+    text.lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.trim().split(':');
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(owner), Some(start), Some(count), None) if owner == name || owner == id => {
+                    Some(IdRange {
+                        start: start.parse().ok()?,
+                        count: count.parse().ok()?,
+                    })
+                }
+                _ => None,
+            }
+        })
+        .find(|range| range.count > 0)
+}
+
+/// IDs delegated to user `name`, or `id`, by `/etc/subuid` or `/etc/subgid`
+pub fn subids(file: &str, name: &str, id: u32) -> Option<IdRange> {
+    parse_subids(&fs::read_to_string(file).ok()?, name, id)
+}
+
+/// An entry of `/etc/passwd`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub name: String,
+    pub uid: u32,
+    /// Primary group
+    pub gid: u32,
+    pub home: PathBuf,
+}
+
+/// Find user `name`, or a numeric uid, in the format of passwd(5)
+pub fn parse_passwd(text: &str, name: &str) -> Option<Account> {
+    text.lines().find_map(|line| {
+        let fields: Vec<&str> = line.trim().split(':').collect();
+        match fields[..] {
+            [user, _, uid, gid, _, home, ..] if user == name || uid == name => Some(Account {
+                name: user.to_string(),
+                uid: uid.parse().ok()?,
+                gid: gid.parse().ok()?,
+                home: PathBuf::from(home),
+            }),
+            _ => None,
+        }
+    })
+}
+
+/// Find user `name` in `/etc/passwd`.  A numeric uid which is not listed is taken as
+/// that uid, and a group of the same number, with home `/`.
+pub fn account(name: &str) -> Option<Account> {
+    let text = fs::read_to_string("/etc/passwd").unwrap_or_default();
+    parse_passwd(&text, name).or_else(|| {
+        let id = name.parse().ok()?;
+        Some(Account {
+            name: name.to_string(),
+            uid: id,
+            gid: id,
+            home: PathBuf::from("/"),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subids() {
+        let text = "# comment\nalice:100000:65536\n0:200000:0\n0:300000:1000\nroot:400000:1\n";
+        assert_eq!(
+            parse_subids(text, "root", 0),
+            Some(IdRange {
+                start: 300000,
+                count: 1000
+            })
+        );
+        assert_eq!(
+            parse_subids(text, "alice", 1000).map(|r| r.start),
+            Some(100000)
+        );
+        assert_eq!(parse_subids(text, "bob", 1001), None);
+        assert_eq!(parse_subids("bob:x:1\n", "bob", 1001), None);
+    }
+
+    #[test]
+    fn passwd() {
+        let text = "root:x:0:0:root:/root:/bin/bash\n\
+                    nobody:x:65534:65534:nobody:/nonexistent:/usr/sbin/nologin\n";
+        let nobody = parse_passwd(text, "nobody").unwrap();
+        assert_eq!((nobody.uid, nobody.gid), (65534, 65534));
+        assert_eq!(nobody.home, PathBuf::from("/nonexistent"));
+        assert_eq!(parse_passwd(text, "0").unwrap().name, "root");
+        assert_eq!(parse_passwd(text, "alice"), None);
+        assert_eq!(parse_passwd("bad:x:y:0::/:\n", "bad"), None);
+    }
+}
@@ -1,20 +1,24 @@
 use std::ffi::CString;
 use std::fs;
-use std::io::Write;
-use std::net::TcpStream;
+use std::io::{self, Write};
 use std::os::unix::prelude::*;
 use std::path::{Path, PathBuf};
+use std::{mem, ptr};
 
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixStream;
 
 use libc;
 
 use log::debug;
 
 pub use super::capability::*;
-use super::err::{Error, Result};
+use super::err::{Errno, Error, Result};
+pub use super::ext::{MOUNT_ATTR_NODEV, MOUNT_ATTR_NOEXEC, MOUNT_ATTR_NOSUID, MOUNT_ATTR_RDONLY};
+pub use super::fd::*;
 pub use super::proc::*;
+use super::retry::retry;
 pub use super::user::*;
 
 /// Allocate a `CString` from the given path.
@@ -28,16 +32,50 @@ fn path2cstr<P: AsRef<Path>>(path: P) -> Result<CString> {
     str2cstr(path.as_ref().to_string_lossy())
 }
 
-/// Create a file, and write the provided bytes
+/// Open with `O_NOFOLLOW`, and write all of `buf` with one `write_all()`
+fn write_with(name: &Path, opts: &mut fs::OpenOptions, buf: &[u8]) -> Result<()> {
+    opts.custom_flags(libc::O_NOFOLLOW)
+        .open(name)
+        .map_err(|e| Error::file("open", name, e))?
+        .write_all(buf)
+        .map_err(|e| Error::file("write", name, e))
+}
+
+/// Create or truncate a file, and write the provided bytes.
+/// Fails if `name` is a symbolic link.
 pub fn write_file<P: AsRef<Path>, S: AsRef<[u8]>>(name: P, buf: S) -> Result<()> {
     debug!("write_file({:?}, ...)", name.as_ref().display());
-    fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(name.as_ref())
-        .map_err(|e| Error::file("open", name.as_ref(), e))?
-        .write_all(buf.as_ref())
-        .map_err(|e| Error::file("write", name.as_ref(), e))
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    write_with(name.as_ref(), &mut opts, buf.as_ref())
+}
+
+/// Create a file which must not already exist, and write the provided bytes.
+/// A symbolic link is never followed.
+pub fn write_new_file<P: AsRef<Path>, S: AsRef<[u8]>>(name: P, buf: S) -> Result<()> {
+    debug!("write_new_file({:?}, ...)", name.as_ref().display());
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    write_with(name.as_ref(), &mut opts, buf.as_ref())
+}
+
+/// Replace the contents of a file which must already exist.
+/// eg. `/proc/<pid>/uid_map` or `/proc/sys/...`.
+/// Fails if `name` is a symbolic link.
+pub fn overwrite_file<P: AsRef<Path>, S: AsRef<[u8]>>(name: P, buf: S) -> Result<()> {
+    debug!("overwrite_file({:?}, ...)", name.as_ref().display());
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).truncate(true);
+    write_with(name.as_ref(), &mut opts, buf.as_ref())
+}
+
+/// Append the provided bytes to a file, which is created if necessary.
+/// Fails if `name` is a symbolic link.
+pub fn append_file<P: AsRef<Path>, S: AsRef<[u8]>>(name: P, buf: S) -> Result<()> {
+    debug!("append_file({:?}, ...)", name.as_ref().display());
+    let mut opts = fs::OpenOptions::new();
+    opts.append(true).create(true);
+    write_with(name.as_ref(), &mut opts, buf.as_ref())
 }
 
 /// Wraps `mkdir()`.  Only attempts to create the leaf
@@ -54,6 +92,19 @@ pub fn mkdirs<S: AsRef<Path>>(name: S) -> Result<PathBuf> {
     Ok(name.as_ref().to_path_buf())
 }
 
+/// Wraps `flock()`.  eg. `libc::LOCK_EX | libc::LOCK_NB`.  Retried when interrupted.
+pub fn flock<F: AsRawFd>(file: &F, op: libc::c_int) -> io::Result<()> {
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), op) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
 /// Create directory /B/A with same ownership and permissions as /A
 pub fn clonedirs<A: AsRef<Path>, B: AsRef<Path>>(src: A, target: B) -> Result<()> {
     assert!(src.as_ref().is_absolute(), "{:?}", src.as_ref());
@@ -104,20 +155,145 @@ pub fn chmod<S: AsRef<Path>>(path: S, mode: u32) -> Result<()> {
     }
 }
 
-/// Create a pair of connected stream sockets.  Will be `SOCK_STREAM`.  May not actually be `AF_INET` or `AF_INET6`.
-pub fn socketpair() -> Result<(TcpStream, TcpStream)> {
-    let mut fds = vec![0, 2];
+fn socketpair_type(stype: libc::c_int) -> Result<(UnixStream, UnixStream)> {
+    let mut fds = [-1; 2];
     unsafe {
-        if 0 != libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) {
+        if 0 != libc::socketpair(
+            libc::AF_UNIX,
+            stype | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        ) {
             return Err(Error::last_os_error("socketpair"));
         }
         Ok((
-            TcpStream::from_raw_fd(fds[0]),
-            TcpStream::from_raw_fd(fds[1]),
+            UnixStream::from_raw_fd(fds[0]),
+            UnixStream::from_raw_fd(fds[1]),
         ))
     }
 }
 
+/// Create a pair of connected `AF_UNIX`, `SOCK_STREAM` sockets.  Both ends have `SOCK_CLOEXEC` set.
+pub fn socketpair() -> Result<(UnixStream, UnixStream)> {
+    socketpair_type(libc::SOCK_STREAM)
+}
+
+/// Create a pair of connected `AF_UNIX`, `SOCK_SEQPACKET` sockets.  Both ends have `SOCK_CLOEXEC` set.
+///
+/// Message boundaries are kept.  Each `write()` sends one message,
+/// and each `read()` receives at most one.  Any excess of a message is discarded.
+pub fn seqpacket_pair() -> Result<(UnixStream, UnixStream)> {
+    socketpair_type(libc::SOCK_SEQPACKET)
+}
+
+/// Create a pipe.  Both ends have `O_CLOEXEC` set.  Returns `(read, write)`.
+pub fn pipe() -> Result<(fs::File, fs::File)> {
+    let mut fds = [-1; 2];
+    unsafe {
+        if 0 != libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) {
+            return Err(Error::last_os_error("pipe2"));
+        }
+        Ok((fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1])))
+    }
+}
+
+/// Pass a copy of file descriptor `fd` over a unix socket (`SCM_RIGHTS`)
+pub fn send_fd<S: AsFd, F: AsFd>(sock: S, fd: F) -> Result<()> {
+    send_fds(sock, b".", &[fd.as_fd().as_raw_fd()])
+}
+
+/// Send `data`, with copies of file descriptors `fds`, over a unix socket, in one message.
+pub fn send_fds<S: AsFd>(sock: S, data: &[u8], fds: &[RawFd]) -> Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut _,
+        iov_len: data.len(),
+    };
+    let len = mem::size_of_val(fds);
+    let space = unsafe { libc::CMSG_SPACE(len as _) } as usize;
+    // u64 for cmsghdr alignment
+    let mut cbuf = vec![0u64; space / 8 + 1];
+    let ret = unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cbuf.as_mut_ptr() as *mut _;
+        msg.msg_controllen = space as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(len as _) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+
+        libc::sendmsg(sock.as_fd().as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error("sendmsg SCM_RIGHTS"));
+    } else if ret as usize != data.len() {
+        return Err(Error::os(
+            "sendmsg SCM_RIGHTS",
+            io::ErrorKind::WriteZero.into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Receive a file descriptor sent with `send_fd()`.  The new descriptor has `O_CLOEXEC` set.
+/// Returns `None` if the peer closed the socket without sending.
+pub fn recv_fd<S: AsFd>(sock: S) -> Result<Option<OwnedFd>> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut _,
+        iov_len: byte.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as _) } as usize;
+    let mut cbuf = vec![0u64; space / 8 + 1];
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cbuf.as_mut_ptr() as *mut _;
+        msg.msg_controllen = space as _;
+
+        let ret = libc::recvmsg(sock.as_fd().as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+        if ret < 0 {
+            return Err(Error::last_os_error("recvmsg SCM_RIGHTS"));
+        } else if ret == 0 {
+            return Ok(None);
+        }
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Ok(None);
+        }
+        let fd = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd);
+        Ok(Some(OwnedFd::from_raw_fd(fd)))
+    }
+}
+
+/// Credentials of the process which connected the peer of a unix socket (`SO_PEERCRED`).
+/// IDs are as seen in the user namespace of the caller.
+pub fn peer_cred<S: AsFd>(sock: S) -> Result<libc::ucred> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            sock.as_fd().as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut _,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error("getsockopt SO_PEERCRED"));
+    }
+    Ok(cred)
+}
+
 /// Wraps `unshare()`
 pub fn unshare(flags: libc::c_int) -> Result<()> {
     debug!("unshare(0x{:x})", flags);
@@ -127,6 +303,128 @@ pub fn unshare(flags: libc::c_int) -> Result<()> {
     Ok(())
 }
 
+/// Wraps `setns()`.  `nstype` may be 0 to allow any type of namespace.
+pub fn setns<F: AsFd>(fd: F, nstype: libc::c_int) -> Result<()> {
+    if unsafe { libc::setns(fd.as_fd().as_raw_fd(), nstype) } != 0 {
+        return Err(Error::last_os_error("setns"));
+    }
+    Ok(())
+}
+
+/// Fault injection into `mount()` and `umount2()`.  To exercise error, and cleanup, paths.
+///
+/// A fault is armed in the calling process, and inherited by those it forks.
+/// eg. fail the second `mount()` of a child with `EACCES`.
+///
+/// ```no_run
+/// use sandbox::util::fault::{self, Op};
+/// use sandbox::Errno;
+///
+/// fault::fail_nth(Op::Mount, 2, Errno::EACCES);
+/// ```
+#[cfg(feature = "fault-injection")]
+pub mod fault {
+    use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+
+    use log::debug;
+
+    use super::Errno;
+
+    /// Which wrapper to fail
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Op {
+        /// `mount()` and `mount_with_data()`
+        Mount,
+        /// `umount_lazy()` and `maybe_umount_lazy()`
+        Umount,
+    }
+
+    struct Fault {
+        /// Calls since armed
+        calls: AtomicU32,
+        /// Call to fail, counting from 1.  0 when not armed.
+        nth: AtomicU32,
+        errno: AtomicI32,
+    }
+
+    impl Fault {
+        const fn new() -> Fault {
+            Fault {
+                calls: AtomicU32::new(0),
+                nth: AtomicU32::new(0),
+                errno: AtomicI32::new(0),
+            }
+        }
+    }
+
+    static MOUNT: Fault = Fault::new();
+    static UMOUNT: Fault = Fault::new();
+
+    fn fault(op: Op) -> &'static Fault {
+        match op {
+            Op::Mount => &MOUNT,
+            Op::Umount => &UMOUNT,
+        }
+    }
+
+    /// Fail the `nth` call of `op` from now, counting from 1, with `errno`.
+    /// Only that one.  Each retry of a transient failure is counted as a call.
+    pub fn fail_nth(op: Op, nth: u32, errno: Errno) {
+        let f = fault(op);
+        f.nth.store(0, Ordering::SeqCst);
+        f.calls.store(0, Ordering::SeqCst);
+        f.errno.store(errno.raw(), Ordering::SeqCst);
+        f.nth.store(nth, Ordering::SeqCst);
+    }
+
+    /// Disarm any fault of `op`
+    pub fn clear(op: Op) {
+        fault(op).nth.store(0, Ordering::SeqCst);
+    }
+
+    /// Number of calls of `op` since armed by `fail_nth()`
+    pub fn calls(op: Op) -> u32 {
+        fault(op).calls.load(Ordering::SeqCst)
+    }
+
+    /// Count a call of `op`.  Returns the errno to fail it with, if any.
+    pub(super) fn take(op: Op) -> Option<Errno> {
+        let f = fault(op);
+        let nth = f.nth.load(Ordering::SeqCst);
+        if nth == 0 {
+            return None;
+        }
+        let n = f.calls.fetch_add(1, Ordering::SeqCst) + 1;
+        if n != nth {
+            return None;
+        }
+        let errno = Errno::from(f.errno.load(Ordering::SeqCst));
+        debug!("Inject {} into {:?} #{}", errno, op, n);
+        Some(errno)
+    }
+}
+
+/// Evaluate syscall `$call`, unless `fault` fails it with -1 and `errno`
+#[cfg(feature = "fault-injection")]
+macro_rules! inject {
+    ($op:ident, $call:expr) => {
+        match fault::take(fault::Op::$op) {
+            Some(errno) => {
+                unsafe { *libc::__errno_location() = errno.raw() };
+                -1
+            }
+            None => $call,
+        }
+    };
+}
+
+#[cfg(not(feature = "fault-injection"))]
+macro_rules! inject {
+    ($op:ident, $call:expr) => {
+        $call
+    };
+}
+
 /// Wraps `mount()`
 pub fn mount<A, B, C>(src: A, target: B, fstype: C, flags: libc::c_ulong) -> Result<()>
 where
@@ -159,53 +457,138 @@ where
         flags,
         data.as_ref()
     );
-    if 0 != unsafe {
-        libc::mount(
-            path2cstr(&src)?.as_ptr(),
-            path2cstr(&target)?.as_ptr(),
-            str2cstr(&fstype)?.as_ptr() as *const _,
-            flags,
-            str2cstr(&data)?.as_ptr() as *const _,
-        )
-    } {
-        Err(Error::last_os_error(format!(
-            "mount src={:?} target={:?} fs={:?} flags=0x{:x} data=",
-            src.as_ref(),
-            target.as_ref(),
-            fstype.as_ref(),
-            flags
-        )))?;
-    }
-    Ok(())
+    let (csrc, ctarget) = (path2cstr(&src)?, path2cstr(&target)?);
+    let (cfstype, cdata) = (str2cstr(&fstype)?, str2cstr(&data)?);
+    retry("mount", &[Errno::EBUSY], || {
+        if 0 != inject!(Mount, unsafe {
+            libc::mount(
+                csrc.as_ptr(),
+                ctarget.as_ptr(),
+                cfstype.as_ptr() as *const _,
+                flags,
+                cdata.as_ptr() as *const _,
+            )
+        }) {
+            Err(Error::last_os_error(format!(
+                "mount src={:?} target={:?} fs={:?} flags=0x{:x} data=",
+                src.as_ref(),
+                target.as_ref(),
+                fstype.as_ref(),
+                flags
+            )))?;
+        }
+        Ok(())
+    })
 }
 
 /// Wraps `umount2(..., MNT_DETACH)` to remove a mount from the current namespace,
 /// but not necessarily from others.
 pub fn umount_lazy<P: AsRef<Path>>(path: P) -> Result<()> {
     debug!("umount({:?})", path.as_ref().display());
-    let ret = unsafe { libc::umount2(path2cstr(&path)?.as_ptr(), libc::MNT_DETACH) };
-    if ret == 0 {
-        Ok(())
-    } else {
-        Err(Error::last_file_error("umount2", path))
-    }
+    let cpath = path2cstr(&path)?;
+    retry("umount2", &[Errno::EBUSY], || {
+        let ret = inject!(Umount, unsafe {
+            libc::umount2(cpath.as_ptr(), libc::MNT_DETACH)
+        });
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(Error::last_file_error("umount2", &path))
+        }
+    })
 }
 
 /// Try to `umount_lazy()`
 pub fn maybe_umount_lazy<P: AsRef<Path>>(path: P) -> Result<bool> {
-    debug!("umount({:?})", path.as_ref().display());
-    let ret = unsafe { libc::umount2(path2cstr(&path)?.as_ptr(), libc::MNT_DETACH) };
+    match umount_lazy(path) {
+        Ok(()) => {
+            debug!("  Success");
+            Ok(true)
+        }
+        Err(err) if err.errno() == Some(Errno::EINVAL) => {
+            debug!("  Nope");
+            Ok(false)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Apply to all mounts below `path` as well.  (not in older libc)
+const AT_RECURSIVE: libc::c_uint = 0x8000;
+
+/// Wraps `mount_setattr()`.  Set and clear `MOUNT_ATTR_*` flags of the mount at `path`,
+/// and when `recursive` all mounts below it.  A recursive change applies to all, or none.
+///
+/// Fails with `ENOSYS` (`io::ErrorKind::Unsupported`) before Linux 5.12.
+pub fn mount_setattr<P: AsRef<Path>>(path: P, recursive: bool, set: u32, clear: u32) -> Result<()> {
+    debug!(
+        "mount_setattr({:?}, {}, 0x{:x}, 0x{:x})",
+        path.as_ref().display(),
+        recursive,
+        set,
+        clear
+    );
+    let mut attr = super::ext::mount_attr {
+        attr_set: set as _,
+        attr_clr: clear as _,
+        ..Default::default()
+    };
+    let flags = if recursive { AT_RECURSIVE } else { 0 };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            path2cstr(&path)?.as_ptr(),
+            flags,
+            &mut attr as *mut super::ext::mount_attr,
+            mem::size_of::<super::ext::mount_attr>(),
+        )
+    };
     if ret == 0 {
-        debug!("  Success");
-        Ok(true)
-    } else if std::io::Error::last_os_error().raw_os_error().unwrap() == libc::EINVAL {
-        debug!("  Nope");
-        Ok(false)
+        Ok(())
     } else {
-        Err(Error::last_file_error("umount2", path))
+        Err(Error::last_file_error("mount_setattr", path))
     }
 }
 
+/// Hide a file or directory.  Files are covered by `/dev/null`, directories by an empty,
+/// read-only tmpfs.  Does nothing if `path` does not exist.
+pub fn mask_path<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => mount(
+            "none",
+            path,
+            "tmpfs",
+            libc::MS_RDONLY | libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_NOSUID,
+        ),
+        Ok(meta) if meta.file_type().is_symlink() => Ok(()),
+        Ok(_) => mount("/dev/null", path, "", libc::MS_BIND),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Set `no_new_privs`, after which `execve()` can not grant privileges.
+/// eg. through SUID executables or file capabilities.
+pub fn set_no_new_privs() -> Result<()> {
+    debug!("no_new_privs");
+    if 0 != unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } {
+        return Err(Error::last_os_error("PR_SET_NO_NEW_PRIVS"));
+    }
+    Ok(())
+}
+
+/// Join a new anonymous session keyring, detaching from the keys of the caller's session.
+pub fn join_session_keyring() -> Result<()> {
+    debug!("new session keyring");
+    // KEYCTL_JOIN_SESSION_KEYRING with NULL name
+    let ret = unsafe { libc::syscall(libc::SYS_keyctl, 1, ptr::null::<libc::c_char>()) };
+    if ret < 0 {
+        return Err(Error::last_os_error("keyctl(JOIN_SESSION_KEYRING)"));
+    }
+    Ok(())
+}
+
 unsafe fn sys_pivot_root(
     new_root: *const libc::c_char,
     old_root: *const libc::c_char,
@@ -234,23 +617,74 @@ pub fn pivot_root<A: AsRef<Path>, B: AsRef<Path>>(new_root: A, old_root: B) -> R
     }
 }
 
-/// Maniplate the `O_CLOEXEC` bit on the provided file descriptor.
-pub fn set_cloexec<F: AsRawFd>(fd: F, v: bool) -> Result<()> {
-    let fdn = fd.as_raw_fd();
-    let mut cur = unsafe { libc::fcntl(fdn, libc::F_GETFD) };
-    if cur < 0 {
-        return Err(Error::last_os_error("F_GETFD"));
-    }
-    if v {
-        cur |= libc::O_CLOEXEC;
-    } else {
-        cur &= !libc::O_CLOEXEC;
+/// Prefixes of sysctl names scoped to a namespace, and its `CLONE_NEW*` flag
+const SYSCTL_NAMESPACES: &[(&str, libc::c_int)] = &[
+    ("net.", libc::CLONE_NEWNET),
+    ("kernel.shm", libc::CLONE_NEWIPC),
+    ("kernel.msg", libc::CLONE_NEWIPC),
+    ("kernel.sem", libc::CLONE_NEWIPC),
+    ("fs.mqueue.", libc::CLONE_NEWIPC),
+    ("kernel.hostname", libc::CLONE_NEWUTS),
+    ("kernel.domainname", libc::CLONE_NEWUTS),
+    ("user.", libc::CLONE_NEWUSER),
+];
+
+/// The `CLONE_NEW*` namespace type which scopes sysctl `name`.
+/// eg. `CLONE_NEWNET` for `net.ipv4.ip_forward`.  `None` if global, or unknown.
+pub fn sysctl_namespace(name: &str) -> Option<libc::c_int> {
+    SYSCTL_NAMESPACES
+        .iter()
+        .find(|(prefix, _)| name.starts_with(prefix))
+        .map(|(_, ns)| *ns)
+}
+
+/// Location under `/proc/sys` of sysctl `name`.  eg. `net.ipv4.ip_forward`
+fn sysctl_path(name: &str) -> Result<PathBuf> {
+    let parts: Vec<&str> = name.split('.').collect();
+    if parts.iter().any(|p| p.is_empty() || p.contains('/')) {
+        return Err(Error::os(
+            format!("Invalid sysctl name {:?}", name),
+            io::Error::from_raw_os_error(libc::EINVAL),
+        ));
     }
-    let err = unsafe { libc::fcntl(fdn, libc::F_SETFD, cur) };
-    if err < 0 {
-        return Err(Error::last_os_error("F_SETFD"));
+    Ok(parts
+        .iter()
+        .fold(PathBuf::from("/proc/sys"), |p, c| p.join(c)))
+}
+
+/// Write sysctl `name`, which must be scoped to one of the `unshared` namespaces.
+/// eg. `StageCtx::namespaces()` from `ContainerHooks::setup_priv()`.
+/// So that the host is never changed.
+///
+/// ```no_run
+/// # use sandbox::util;
+/// // allow ICMP echo sockets, in place of CAP_NET_RAW
+/// util::sysctl_write("net.ipv4.ping_group_range", "0 2147483647", libc::CLONE_NEWNET).unwrap();
+/// ```
+pub fn sysctl_write<S: AsRef<[u8]>>(name: &str, value: S, unshared: libc::c_int) -> Result<()> {
+    match sysctl_namespace(name) {
+        Some(ns) if unshared & ns != 0 => (),
+        _ => {
+            return Err(Error::os(
+                format!("sysctl {} is not scoped to a new namespace", name),
+                io::Error::from_raw_os_error(libc::EPERM),
+            ))
+        }
     }
-    Ok(())
+    let path = sysctl_path(name)?;
+    debug!(
+        "sysctl {} = {:?}",
+        name,
+        String::from_utf8_lossy(value.as_ref())
+    );
+    overwrite_file(path, value)
+}
+
+/// Read sysctl `name`, without any trailing newline
+pub fn sysctl_read(name: &str) -> Result<String> {
+    let path = sysctl_path(name)?;
+    let value = fs::read_to_string(&path).map_err(|e| Error::file("read", &path, e))?;
+    Ok(value.trim_end_matches('\n').to_string())
 }
 
 #[cfg(test)]
@@ -263,8 +697,8 @@ mod tests {
         let (mut a, mut b) = socketpair().expect("socketpair");
         a.set_nonblocking(true).unwrap();
         b.set_nonblocking(true).unwrap();
-        set_cloexec(a.as_raw_fd(), true).unwrap();
-        set_cloexec(b.as_raw_fd(), true).unwrap();
+        assert_eq!(get_fd_flags(a.as_raw_fd()).unwrap(), libc::FD_CLOEXEC);
+        assert_eq!(get_fd_flags(b.as_raw_fd()).unwrap(), libc::FD_CLOEXEC);
 
         a.write_all("msg".as_bytes()).unwrap();
         let mut buf = vec![0; 4];
@@ -273,6 +707,164 @@ mod tests {
         assert_eq!(&buf[0..3], "msg".as_bytes());
     }
 
+    #[test]
+    fn test_write_file() {
+        let tdir = crate::tempdir::TempDir::new().unwrap();
+        let file = tdir.path().join("file");
+        let link = tdir.path().join("link");
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+
+        overwrite_file(&file, "x").unwrap_err();
+        write_new_file(&file, "long").unwrap();
+        write_new_file(&file, "again").unwrap_err();
+        write_file(&file, "abc").unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "abc");
+        overwrite_file(&file, "de").unwrap();
+        append_file(&file, "f").unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "def");
+
+        write_file(&link, "link").unwrap_err();
+        write_new_file(&link, "link").unwrap_err();
+        overwrite_file(&link, "link").unwrap_err();
+        append_file(&link, "link").unwrap_err();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "def");
+    }
+
+    #[test]
+    fn test_sysctl() {
+        assert_eq!(
+            sysctl_namespace("net.ipv4.ip_forward"),
+            Some(libc::CLONE_NEWNET)
+        );
+        assert_eq!(sysctl_namespace("kernel.shmmax"), Some(libc::CLONE_NEWIPC));
+        assert_eq!(sysctl_namespace("kernel.panic"), None);
+        assert_eq!(
+            sysctl_path("net.ipv4.ping_group_range").unwrap(),
+            PathBuf::from("/proc/sys/net/ipv4/ping_group_range")
+        );
+        sysctl_path("net..x").unwrap_err();
+        sysctl_path("net/../kernel.panic").unwrap_err();
+
+        // never the host
+        sysctl_write("kernel.panic", "0", !0).unwrap_err();
+        sysctl_write("net.ipv4.ip_forward", "1", libc::CLONE_NEWNS).unwrap_err();
+
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = fork(|| -> crate::container::Result<()> {
+            unshare(libc::CLONE_NEWNET)?;
+            sysctl_write("net.ipv4.ping_group_range", "0 0", libc::CLONE_NEWNET)?;
+            let value = sysctl_read("net.ipv4.ping_group_range")?;
+            if !value.split_whitespace().eq(["0", "0"]) {
+                return Err(format!("unexpected ping_group_range {:?}", value).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_seqpacket() {
+        let (mut a, mut b) = seqpacket_pair().expect("socketpair");
+        a.write_all(b"one").unwrap();
+        a.write_all(b"three").unwrap();
+        drop(a);
+        let mut buf = [0; 4];
+        assert_eq!(b.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"one");
+        // truncated
+        assert_eq!(b.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"thre");
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_errno() {
+        assert_eq!(Errno::from(libc::EBUSY), Errno::EBUSY);
+        assert_eq!(Errno::EINVAL.raw(), libc::EINVAL);
+        assert_eq!(Errno::from(4095), Errno::Other(4095));
+        assert_eq!(Errno::from(4095).raw(), 4095);
+        assert_eq!(Errno::EPERM.to_string(), "EPERM");
+        assert_eq!(mkdir("/").unwrap_err().errno(), Some(Errno::EEXIST));
+
+        // otherwise EPERM
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        // not a mount point
+        let err = umount_lazy("/proc/self").unwrap_err();
+        assert_eq!(err.errno(), Some(Errno::EINVAL));
+        assert!(!maybe_umount_lazy("/proc/self").unwrap());
+        assert_eq!(unshare(-1).unwrap_err().errno(), Some(Errno::EINVAL));
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn test_fault() {
+        use fault::Op;
+        let tdir = crate::tempdir::TempDir::new().unwrap();
+        let top = tdir.path().to_path_buf();
+        if !crate::testing::require_privilege() {
+            return;
+        }
+        let mut pid = crate::proc::fork(|| -> crate::container::Result<()> {
+            unshare(libc::CLONE_NEWNS)?;
+            mount("", "/", "", libc::MS_REC | libc::MS_PRIVATE)?;
+            fault::fail_nth(Op::Mount, 2, Errno::EACCES);
+            fault::fail_nth(Op::Umount, 1, Errno::EPERM);
+            mount("tmpfs", &top, "tmpfs", 0)?;
+            let second = mount("tmpfs", &top, "tmpfs", 0).unwrap_err().errno();
+            mount("tmpfs", &top, "tmpfs", 0)?;
+            let first = maybe_umount_lazy(&top).unwrap_err().errno();
+            let calls = fault::calls(Op::Mount);
+            if second != Some(Errno::EACCES) || first != Some(Errno::EPERM) || calls != 3 {
+                return Err(format!("unexpected {:?} {:?} {}", second, first, calls).into());
+            }
+            let umounted = [
+                maybe_umount_lazy(&top)?,
+                maybe_umount_lazy(&top)?,
+                maybe_umount_lazy(&top)?,
+            ];
+            if umounted != [true, true, false] {
+                return Err(format!("unexpected umount {:?}", umounted).into());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pid.park().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_peer_cred() {
+        let (a, _b) = socketpair().unwrap();
+        let cred = peer_cred(&a).unwrap();
+        assert_eq!(
+            (cred.pid, cred.uid),
+            (std::process::id() as libc::pid_t, getuid())
+        );
+    }
+
+    #[test]
+    fn test_pass_fd() {
+        let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+        let (rx, mut tx) = pipe().unwrap();
+
+        send_fd(&a, &rx).unwrap();
+        drop(rx);
+        let rx = recv_fd(&b).unwrap().expect("fd");
+
+        tx.write_all(b"hello").unwrap();
+        drop(tx);
+        let mut buf = String::new();
+        fs::File::from(rx).read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "hello");
+
+        drop(a);
+        assert!(recv_fd(&b).unwrap().is_none());
+    }
+
     #[test]
     fn test_cstr() {
         let cstr = path2cstr("/some/path").unwrap();
@@ -0,0 +1,166 @@
+//! What this build supports, and what the running kernel provides.
+//! For `--version --verbose`, so that bug reports and orchestration can adapt.
+//!
+//! ```json
+//! {"arch":"x86_64","features":{"criu":false,"landlock":true,"seccomp":true,"slirp":true},
+//!  "interfaces":{"cgroup2":true,"landlock_abi":4,"listmount":false,"mount_setattr":true,
+//!  "pidfd":true,"seccomp":true,"user_namespaces":true},"kernel":"6.1.0-18-amd64",
+//!  "revision":"0123456789ab","version":"1.0.0"}
+//! ```
+
+use std::path::Path;
+
+use super::config::{Item, Table, Value};
+use super::{fs, landlock, seccomp};
+
+/// Features which a build may, or may not, include
+pub const FEATURES: &[(&str, bool)] = &[
+    ("seccomp", seccomp::SUPPORTED),
+    ("landlock", true),
+    // isolate --net-nat.  slirp compatible addressing, without slirp4netns
+    ("slirp", true),
+    // checkpoint/restore is not implemented
+    ("criu", false),
+    ("fault-injection", cfg!(feature = "fault-injection")),
+];
+
+/// Details of this build, and of the running kernel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// Crate version
+    pub version: &'static str,
+    /// Git revision built from, if known
+    pub revision: Option<&'static str>,
+    pub arch: &'static str,
+    /// Compiled in, or not.  cf. `FEATURES`
+    pub features: Vec<(&'static str, bool)>,
+    /// Release of the running kernel.  eg. `6.1.0-18-amd64`
+    pub kernel: String,
+    /// Kernel interfaces, and whether usable by this process
+    pub interfaces: Vec<(&'static str, bool)>,
+    /// `None` when Landlock is unavailable.  cf. `landlock::abi()`
+    pub landlock_abi: Option<u32>,
+}
+
+/// Does the kernel implement syscall `nr`?  Called with invalid arguments.
+fn has_syscall(nr: libc::c_long) -> bool {
+    let ret = unsafe { libc::syscall(nr, -1, 0, 0, 0, 0) };
+    ret >= 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::ENOSYS)
+}
+
+fn kernel_release() -> String {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return String::new();
+    }
+    let release = unsafe { std::ffi::CStr::from_ptr(uts.release.as_ptr()) };
+    release.to_string_lossy().into_owned()
+}
+
+/// Are new user namespaces permitted?  Without creating one.
+fn user_namespaces() -> bool {
+    let limit = |path: &str| {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+    };
+    // the sysctl of Debian and Ubuntu kernels is absent elsewhere
+    limit("/proc/sys/user/max_user_namespaces") != Some(0)
+        && limit("/proc/sys/kernel/unprivileged_userns_clone") != Some(0)
+}
+
+/// Probe the running kernel
+pub fn build_info() -> BuildInfo {
+    let listmount = !matches!(
+        fs::listmount(),
+        Err(err) if err.is_io_error(std::io::ErrorKind::Unsupported)
+    );
+    let interfaces = vec![
+        ("user_namespaces", user_namespaces()),
+        // EINVAL when not configured
+        ("seccomp", unsafe { libc::prctl(libc::PR_GET_SECCOMP) } >= 0),
+        ("pidfd", has_syscall(libc::SYS_pidfd_open)),
+        ("mount_setattr", has_syscall(libc::SYS_mount_setattr)),
+        ("listmount", listmount),
+        (
+            "cgroup2",
+            Path::new("/sys/fs/cgroup/cgroup.controllers").exists(),
+        ),
+    ];
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        revision: option_env!("SANDBOX_GIT_REV"),
+        arch: std::env::consts::ARCH,
+        features: FEATURES.to_vec(),
+        kernel: kernel_release(),
+        interfaces,
+        landlock_abi: landlock::abi().ok(),
+    }
+}
+
+fn item(value: Value) -> Item {
+    Item {
+        pos: Default::default(),
+        value,
+    }
+}
+
+fn flags(flags: &[(&str, bool)]) -> Table {
+    flags
+        .iter()
+        .map(|(name, on)| (name.to_string(), item(Value::Bool(*on))))
+        .collect()
+}
+
+impl BuildInfo {
+    /// One line.  eg. `1.0.0 (0123456789ab)`
+    pub fn short(&self) -> String {
+        match self.revision {
+            Some(rev) => format!("{} ({})", self.version, rev),
+            None => self.version.to_string(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut table = Table::new();
+        table.insert("version".into(), item(Value::Str(self.version.into())));
+        if let Some(rev) = self.revision {
+            table.insert("revision".into(), item(Value::Str(rev.into())));
+        }
+        table.insert("arch".into(), item(Value::Str(self.arch.into())));
+        table.insert("features".into(), item(Value::Table(flags(&self.features))));
+        table.insert("kernel".into(), item(Value::Str(self.kernel.clone())));
+        let mut interfaces = flags(&self.interfaces);
+        if let Some(abi) = self.landlock_abi {
+            interfaces.insert("landlock_abi".into(), item(Value::Int(abi.into())));
+        }
+        table.insert("interfaces".into(), item(Value::Table(interfaces)));
+        Value::Table(table).to_json()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Document;
+
+    #[test]
+    fn json() {
+        let info = build_info();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.kernel.is_empty());
+        assert!(has_syscall(libc::SYS_getpid));
+        assert!(!has_syscall(100000));
+
+        let doc = Document::parse_json(&info.to_json(), "test").unwrap();
+        let features = doc.root["features"].value.as_table().unwrap();
+        assert_eq!(
+            features["seccomp"].value.as_bool(),
+            Some(crate::seccomp::SUPPORTED)
+        );
+        assert_eq!(features["criu"].value.as_bool(), Some(false));
+        let interfaces = doc.root["interfaces"].value.as_table().unwrap();
+        assert_eq!(interfaces["pidfd"].value.as_bool(), Some(true));
+        assert!(info.short().starts_with(info.version));
+    }
+}
@@ -0,0 +1,333 @@
+//! Minimal Wayland client.  Only what is needed to register a security context.
+//!
+//! A security context is a listening socket created by us, and handed to the compositor.
+//! Clients which connect through it are known to be sandboxed, and the compositor
+//! may deny them privileged protocols.  eg. screen capture or input injection.
+//!
+//! cf. https://wayland.freedesktop.org/docs/html/ch04.html
+//! and staging/security-context/security-context-v1.xml from wayland-protocols
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use super::err::{Error, Result};
+use super::util;
+
+/// Reported to the compositor with each security context
+pub const SANDBOX_ENGINE: &str = "io.github.mdavidsaver.sandbox";
+pub const MANAGER_INTERFACE: &str = "wp_security_context_manager_v1";
+
+const DISPLAY: u32 = 1;
+// wl_display requests
+const DISPLAY_SYNC: u16 = 0;
+const DISPLAY_GET_REGISTRY: u16 = 1;
+// wl_display events
+const DISPLAY_ERROR: u16 = 0;
+// wl_registry
+const REGISTRY_BIND: u16 = 0;
+const REGISTRY_GLOBAL: u16 = 0;
+// wl_callback
+const CALLBACK_DONE: u16 = 0;
+// wp_security_context_manager_v1
+const MANAGER_CREATE_LISTENER: u16 = 1;
+// wp_security_context_v1
+const CONTEXT_SET_SANDBOX_ENGINE: u16 = 1;
+const CONTEXT_SET_APP_ID: u16 = 2;
+const CONTEXT_SET_INSTANCE_ID: u16 = 3;
+const CONTEXT_COMMIT: u16 = 4;
+
+fn proto<S: AsRef<str>>(msg: S) -> Error {
+    Error::Wayland(msg.as_ref().to_string())
+}
+
+/// Socket of the compositor.  `$WAYLAND_DISPLAY`, relative to `$XDG_RUNTIME_DIR`
+pub fn display_path() -> Result<PathBuf> {
+    let display =
+        env::var_os("WAYLAND_DISPLAY").ok_or_else(|| proto("$WAYLAND_DISPLAY not set"))?;
+    let display = Path::new(&display);
+    if display.is_absolute() {
+        return Ok(display.to_path_buf());
+    }
+    let rundir = env::var_os("XDG_RUNTIME_DIR").ok_or_else(|| proto("$XDG_RUNTIME_DIR not set"))?;
+    Ok(Path::new(&rundir).join(display))
+}
+
+/// Encode one request, in host byte order
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn new(id: u32, opcode: u16) -> Writer {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&id.to_ne_bytes());
+        buf.extend_from_slice(&(opcode as u32).to_ne_bytes());
+        Writer { buf }
+    }
+
+    /// Also used for new_id and object arguments
+    fn uint(mut self, v: u32) -> Self {
+        self.buf.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    /// Length includes the nil, then padded to 4 bytes
+    fn string(mut self, v: &str) -> Self {
+        self = self.uint(v.len() as u32 + 1);
+        self.buf.extend_from_slice(v.as_bytes());
+        self.buf.push(0);
+        let pad = (4 - self.buf.len() % 4) % 4;
+        self.buf.resize(self.buf.len() + pad, 0);
+        self
+    }
+
+    /// Fill in the message size
+    fn finish(mut self) -> Vec<u8> {
+        let word =
+            (self.buf.len() as u32) << 16 | u32::from_ne_bytes(self.buf[4..8].try_into().unwrap());
+        self.buf[4..8].copy_from_slice(&word.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// Arguments of one event
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn uint(&mut self) -> Result<u32> {
+        if self.buf.len() < 4 {
+            return Err(proto("truncated event"));
+        }
+        let (word, rest) = self.buf.split_at(4);
+        self.buf = rest;
+        Ok(u32::from_ne_bytes(word.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.uint()? as usize;
+        let padded = (len + 3) & !3;
+        if len == 0 || self.buf.len() < padded {
+            return Err(proto("truncated event"));
+        }
+        let ret = String::from_utf8_lossy(&self.buf[..len - 1]).into_owned();
+        self.buf = &self.buf[padded..];
+        Ok(ret)
+    }
+}
+
+/// A received event
+struct Event {
+    id: u32,
+    opcode: u16,
+    args: Vec<u8>,
+}
+
+impl Event {
+    fn args(&self) -> Reader<'_> {
+        Reader { buf: &self.args }
+    }
+}
+
+struct Connection {
+    sock: UnixStream,
+    next_id: u32,
+}
+
+impl Connection {
+    fn connect(path: &Path) -> Result<Connection> {
+        debug!("Wayland connect {}", path.display());
+        let sock = UnixStream::connect(path).map_err(|e| Error::file("connect", path, e))?;
+        Ok(Connection {
+            sock,
+            next_id: DISPLAY + 1,
+        })
+    }
+
+    fn new_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// Send one request, with any file descriptor arguments
+    fn send(&mut self, msg: &[u8], fds: &[RawFd]) -> Result<()> {
+        if fds.is_empty() {
+            return self
+                .sock
+                .write_all(msg)
+                .map_err(|e| Error::os("Wayland send", e));
+        }
+        util::send_fds(&self.sock, msg, fds)
+    }
+
+    fn recv(&mut self) -> Result<Event> {
+        let mut header = [0u8; 8];
+        self.sock
+            .read_exact(&mut header)
+            .map_err(|e| Error::os("Wayland recv", e))?;
+        let id = u32::from_ne_bytes(header[..4].try_into().unwrap());
+        let word = u32::from_ne_bytes(header[4..].try_into().unwrap());
+        let size = (word >> 16) as usize;
+        if size < header.len() {
+            return Err(proto("invalid event size"));
+        }
+        let mut args = vec![0; size - header.len()];
+        self.sock
+            .read_exact(&mut args)
+            .map_err(|e| Error::os("Wayland recv", e))?;
+        Ok(Event {
+            id,
+            opcode: word as u16,
+            args,
+        })
+    }
+
+    /// Wait until all previous requests have been processed.
+    /// Other events are passed to `handle`.
+    fn roundtrip<F: FnMut(&Event) -> Result<()>>(&mut self, mut handle: F) -> Result<()> {
+        let callback = self.new_id();
+        self.send(
+            &Writer::new(DISPLAY, DISPLAY_SYNC).uint(callback).finish(),
+            &[],
+        )?;
+        loop {
+            let event = self.recv()?;
+            if event.id == callback && event.opcode == CALLBACK_DONE {
+                return Ok(());
+            } else if event.id == DISPLAY && event.opcode == DISPLAY_ERROR {
+                let mut args = event.args();
+                let (object, code) = (args.uint()?, args.uint()?);
+                let msg = args.string()?;
+                return Err(proto(format!("error {code} on object {object} : {msg}")));
+            }
+            handle(&event)?;
+        }
+    }
+}
+
+/// A listening socket registered with the compositor as a security context.
+///
+/// The compositor stops listening when this is dropped.
+#[derive(Debug)]
+pub struct SecurityContext {
+    path: PathBuf,
+    /// The compositor watches the other end
+    _close: File,
+}
+
+impl SecurityContext {
+    /// Create a socket at `path`, and register with the compositor of `$WAYLAND_DISPLAY`.
+    pub fn new<P: Into<PathBuf>>(
+        path: P,
+        app_id: &str,
+        instance_id: &str,
+    ) -> Result<SecurityContext> {
+        let path = path.into();
+        let mut conn = Connection::connect(&display_path()?)?;
+
+        let registry = conn.new_id();
+        conn.send(
+            &Writer::new(DISPLAY, DISPLAY_GET_REGISTRY)
+                .uint(registry)
+                .finish(),
+            &[],
+        )?;
+        let mut global = None;
+        conn.roundtrip(|event| {
+            if event.id == registry && event.opcode == REGISTRY_GLOBAL {
+                let mut args = event.args();
+                let (name, interface) = (args.uint()?, args.string()?);
+                if interface == MANAGER_INTERFACE {
+                    global = Some(name);
+                }
+            }
+            Ok(())
+        })?;
+        let global =
+            global.ok_or_else(|| proto(format!("compositor lacks {MANAGER_INTERFACE}")))?;
+
+        let manager = conn.new_id();
+        conn.send(
+            &Writer::new(registry, REGISTRY_BIND)
+                .uint(global)
+                .string(MANAGER_INTERFACE)
+                .uint(1)
+                .uint(manager)
+                .finish(),
+            &[],
+        )?;
+
+        let listener = UnixListener::bind(&path).map_err(|e| Error::file("bind", &path, e))?;
+        let (close_rx, close_tx) = util::pipe()?;
+        let context = conn.new_id();
+        conn.send(
+            &Writer::new(manager, MANAGER_CREATE_LISTENER)
+                .uint(context)
+                .finish(),
+            &[listener.as_raw_fd(), close_rx.as_raw_fd()],
+        )?;
+        for (opcode, value) in [
+            (CONTEXT_SET_SANDBOX_ENGINE, SANDBOX_ENGINE),
+            (CONTEXT_SET_APP_ID, app_id),
+            (CONTEXT_SET_INSTANCE_ID, instance_id),
+        ] {
+            conn.send(&Writer::new(context, opcode).string(value).finish(), &[])?;
+        }
+        conn.send(&Writer::new(context, CONTEXT_COMMIT).finish(), &[])?;
+        // any error is reported before the sync completes
+        conn.roundtrip(|_| Ok(()))?;
+        debug!("Wayland security context {}", path.display());
+
+        Ok(SecurityContext {
+            path,
+            _close: close_tx,
+        })
+    }
+
+    /// Where clients connect
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let msg = Writer::new(2, 0)
+            .uint(7)
+            .string("wl_shm")
+            .uint(1)
+            .uint(3)
+            .finish();
+        assert_eq!(msg.len(), 8 + 4 + 4 + 8 + 4 + 4);
+        let word = u32::from_ne_bytes(msg[4..8].try_into().unwrap());
+        assert_eq!(word, (msg.len() as u32) << 16);
+        assert_eq!(&msg[16..24], b"wl_shm\0\0");
+
+        let mut args = Reader { buf: &msg[8..] };
+        assert_eq!(args.uint().unwrap(), 7);
+        assert_eq!(args.string().unwrap(), "wl_shm");
+        assert_eq!(args.uint().unwrap(), 1);
+        assert_eq!(args.uint().unwrap(), 3);
+        args.uint().unwrap_err();
+    }
+
+    #[test]
+    fn display() {
+        env::set_var("WAYLAND_DISPLAY", "/run/other/wayland-1");
+        assert_eq!(display_path().unwrap(), Path::new("/run/other/wayland-1"));
+        env::remove_var("WAYLAND_DISPLAY");
+        display_path().unwrap_err();
+    }
+}
@@ -0,0 +1,152 @@
+//! End-to-end tests of the isolate executable.  Skipped where namespaces are unavailable.
+
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+use sandbox::tempdir::TempDir;
+use sandbox::testing::{require_userns, RootFs};
+use sandbox::util;
+
+/// Run `sh -c <script>` through isolate, from `cwd`
+fn isolate(cwd: &Path, args: &[&str], script: &str) -> Output {
+    if util::getuid() == 0 {
+        // run by root, the command is root of a user namespace, but not of the host
+        util::chmod(cwd, 0o777).unwrap();
+    }
+    Command::new(env!("CARGO_BIN_EXE_isolate"))
+        .current_dir(cwd)
+        .args(args)
+        .args(["sh", "-c", script])
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn writable_cwd_only() {
+    if !require_userns() {
+        return;
+    }
+    let tdir = TempDir::new().unwrap();
+    let out = isolate(
+        tdir.path(),
+        &[],
+        "touch inside && ! touch /etc/inside && touch /tmp/private",
+    );
+    assert!(out.status.success(), "{:?}", out);
+    assert!(tdir.path().join("inside").exists());
+    assert!(!Path::new("/etc/inside").exists());
+    assert!(!Path::new("/tmp/private").exists());
+}
+
+#[test]
+fn no_network() {
+    if !require_userns() {
+        return;
+    }
+    let tdir = TempDir::new().unwrap();
+    // no default route
+    let script = "test -z \"$(awk '$2 == \"00000000\"' /proc/net/route)\"";
+    let out = isolate(tdir.path(), &[], script);
+    assert!(out.status.success(), "{:?}", out);
+}
+
+#[test]
+fn exit_code() {
+    if !require_userns() {
+        return;
+    }
+    let tdir = TempDir::new().unwrap();
+    let out = isolate(tdir.path(), &[], "exit 3");
+    assert_eq!(out.status.code(), Some(3));
+}
+
+#[test]
+fn fixture_root() {
+    if !require_userns() {
+        return;
+    }
+    let mut root = RootFs::new().unwrap();
+    root.with_host_usr()
+        .unwrap()
+        .file("etc/hostname", "fixture\n")
+        .unwrap();
+    let code = root
+        .run(&["grep", "-q", "fixture", "/etc/hostname"])
+        .unwrap();
+    assert_eq!(code, 0);
+}
+
+#[test]
+fn notify_fd_after_exec() {
+    if !require_userns() {
+        return;
+    }
+    let tdir = TempDir::new().unwrap();
+    util::chmod(tdir.path(), 0o777).unwrap();
+    let notify = |cmd: &str| {
+        let out = Command::new("sh")
+            .current_dir(tdir.path())
+            .args(["-c", "exec \"$0\" --notify-fd 3 \"$1\" 3>notify"])
+            .args([env!("CARGO_BIN_EXE_isolate"), cmd])
+            .output()
+            .unwrap();
+        let ready = std::fs::read_to_string(tdir.path().join("notify")).unwrap();
+        (out, ready)
+    };
+    let (out, ready) = notify("true");
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(ready, "READY\n");
+    // readiness is the exec() of the command
+    let (out, ready) = notify("/nonexistent-cmd");
+    assert!(!out.status.success(), "{:?}", out);
+    assert_eq!(ready, "", "{:?}", out);
+}
+
+#[test]
+fn notify_fd_on_exit_wait() {
+    if !require_userns() {
+        return;
+    }
+    let tdir = TempDir::new().unwrap();
+    util::chmod(tdir.path(), 0o777).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_isolate"))
+        .current_dir(tdir.path())
+        .args(["--on-exit", "wait", "--notify-fd", "1", "sleep", "30"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let start = Instant::now();
+    let mut ready = String::new();
+    BufReader::new(child.stdout.take().unwrap())
+        .read_line(&mut ready)
+        .unwrap();
+    // while process 1 remains to wait for the command.  Not once it exits.
+    let elapsed = start.elapsed();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert_eq!(ready, "READY\n");
+    assert!(elapsed < Duration::from_secs(20), "{:?}", elapsed);
+}
+
+#[test]
+fn explain_net_raw() {
+    let explain = |args: &[&str]| {
+        let out = Command::new(env!("CARGO_BIN_EXE_isolate"))
+            .args(args)
+            .arg("--explain")
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "{:?}", out);
+        String::from_utf8(out.stdout).unwrap()
+    };
+    assert!(explain(&[]).contains("Capabilities: none."));
+    // when run by root, --net-raw also needs --as-root
+    let mut args = vec!["--net-raw"];
+    if util::getuid() == 0 {
+        args.push("--as-root");
+    }
+    let text = explain(&args);
+    assert!(text.contains("Capabilities: CAP_NET_RAW."), "{}", text);
+}